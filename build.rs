@@ -1,18 +1,237 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+const SCHEMA: &str = "flatbuffers/messages.fbs";
+
+struct Field {
+    name: String,
+    field_type: String,
+    required: bool,
+}
+
+// The parts of the schema needed to verify requests
+struct Schema {
+    // Name -> (underlying type, largest value)
+    enums: HashMap<String, (String, i64)>,
+    // Name -> size in bytes
+    structs: HashMap<String, usize>,
+    tables: HashMap<String, Vec<Field>>,
+    requests: Vec<String>,
+}
+
+fn scalar_size(name: &str) -> Option<usize> {
+    match name {
+        "bool" | "byte" | "ubyte" => Some(1),
+        "short" | "ushort" => Some(2),
+        "int" | "uint" | "float" => Some(4),
+        "long" | "ulong" | "double" => Some(8),
+        _ => None,
+    }
+}
+
+fn strip_comments(schema: &str) -> String {
+    schema
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Returns the name and body of every declaration of the given kind, like "table"
+fn declarations<'a>(schema: &'a str, kind: &str) -> Vec<(&'a str, &'a str)> {
+    let mut result = vec![];
+    let keyword = format!("{} ", kind);
+    for (start, _) in schema.match_indices(&keyword) {
+        if start > 0 && !schema[..start].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let rest = &schema[start + keyword.len()..];
+        let open = rest.find('{').unwrap();
+        let close = rest.find('}').unwrap();
+        let name = rest[..open].split(':').next().unwrap().trim();
+        result.push((name, &rest[open + 1..close]));
+    }
+
+    result
+}
+
+fn parse_schema(schema: &str) -> Schema {
+    let schema = strip_comments(schema);
+
+    let mut enums = HashMap::new();
+    for (start, _) in schema.match_indices("enum ") {
+        let rest = &schema[start + "enum ".len()..];
+        let header = &rest[..rest.find('{').unwrap()];
+        let mut parts = header.split(':');
+        let name = parts.next().unwrap().trim().to_string();
+        let underlying = parts.next().unwrap().trim().to_string();
+        let body = &rest[rest.find('{').unwrap() + 1..rest.find('}').unwrap()];
+        let mut next = 0;
+        let mut max = 0;
+        for value in body.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            if let Some(index) = value.find('=') {
+                next = value[index + 1..].trim().parse().unwrap();
+            }
+            max = next;
+            next += 1;
+        }
+        enums.insert(name, (underlying, max));
+    }
+
+    let mut structs = HashMap::new();
+    for (name, body) in declarations(&schema, "struct") {
+        let mut size = 0;
+        let mut alignment = 1;
+        for field in body.split(';').map(str::trim).filter(|x| !x.is_empty()) {
+            let field_type = field.split(':').nth(1).unwrap().trim();
+            let field_size = scalar_size(field_type)
+                .unwrap_or_else(|| panic!("Unsupported type {} in struct {}", field_type, name));
+            size = (size + field_size - 1) / field_size * field_size + field_size;
+            alignment = alignment.max(field_size);
+        }
+        structs.insert(
+            name.to_string(),
+            (size + alignment - 1) / alignment * alignment,
+        );
+    }
+
+    let mut tables = HashMap::new();
+    for (name, body) in declarations(&schema, "table") {
+        let mut fields = vec![];
+        for field in body.split(';').map(str::trim).filter(|x| !x.is_empty()) {
+            let mut parts = field.splitn(2, ':');
+            let field_name = parts.next().unwrap().trim().to_string();
+            let declaration = parts.next().unwrap();
+            let field_type = declaration
+                .split(|c| c == '(' || c == '=')
+                .next()
+                .unwrap()
+                .trim()
+                .to_string();
+            fields.push(Field {
+                name: field_name,
+                field_type,
+                required: declaration.contains("required"),
+            });
+        }
+        tables.insert(name.to_string(), fields);
+    }
+
+    let requests = declarations(&schema, "union")
+        .into_iter()
+        .find(|(name, _)| *name == "RequestType")
+        .unwrap()
+        .1
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .collect();
+
+    Schema {
+        enums,
+        structs,
+        tables,
+        requests,
+    }
+}
+
+fn constant_name(table: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in table.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+
+    name
+}
+
+// Returns the FieldLayout of field, and adds the tables it refers to to pending
+fn field_layout(schema: &Schema, table: &str, field: &Field, pending: &mut Vec<String>) -> String {
+    let unsupported = || panic!("Unsupported type of {}.{}", table, field.name);
+    let field_type = field.field_type.as_str();
+    if field_type == "bool" {
+        return "FieldLayout::Bool".to_string();
+    }
+    if let Some(size) = scalar_size(field_type).or_else(|| schema.structs.get(field_type).cloned())
+    {
+        return format!(
+            "FieldLayout::Inline {{ size: {}, required: {} }}",
+            size, field.required
+        );
+    }
+    if let Some((underlying, max)) = schema.enums.get(field_type) {
+        if underlying != "ubyte" {
+            unsupported();
+        }
+        return format!("FieldLayout::Enum {{ max: {} }}", max);
+    }
+    if field_type == "string" {
+        return format!("FieldLayout::String {{ required: {} }}", field.required);
+    }
+    if field_type.starts_with('[') && field_type.ends_with(']') {
+        let element = &field_type[1..field_type.len() - 1];
+        if element != "bool" {
+            if let Some(size) =
+                scalar_size(element).or_else(|| schema.structs.get(element).cloned())
+            {
+                return format!(
+                    "FieldLayout::Vector {{ element_size: {}, required: {} }}",
+                    size, field.required
+                );
+            }
+        }
+        if schema.tables.contains_key(element) {
+            pending.push(element.to_string());
+            return format!(
+                "FieldLayout::TableVector {{ fields: {}, required: {} }}",
+                constant_name(element),
+                field.required
+            );
+        }
+    }
+
+    unsupported()
+}
+
+// Generates the layout of every request table, indexed by RequestType, so that requests can be verified before
+// they're read
+fn write_request_layouts(schema_path: &str, path: &Path) {
+    let schema = parse_schema(&fs::read_to_string(schema_path).unwrap());
+    let mut pending = schema.requests.clone();
+    let mut generated = vec![];
+    let mut f = File::create(path).unwrap();
+    writeln!(f, "// Generated by build.rs from {}", schema_path).unwrap();
+    while let Some(table) = pending.pop() {
+        if generated.contains(&table) {
+            continue;
+        }
+        writeln!(f, "const {}: &[FieldLayout] = &[", constant_name(&table)).unwrap();
+        for field in schema.tables[&table].iter() {
+            let layout = field_layout(&schema, &table, field, &mut pending);
+            writeln!(f, "    {},", layout).unwrap();
+        }
+        writeln!(f, "];").unwrap();
+        generated.push(table);
+    }
+    writeln!(f, "const REQUEST_LAYOUTS: &[&[FieldLayout]] = &[").unwrap();
+    // RequestType::NONE
+    writeln!(f, "    &[],").unwrap();
+    for request in schema.requests.iter() {
+        writeln!(f, "    {},", constant_name(request)).unwrap();
+    }
+    writeln!(f, "];").unwrap();
+}
+
 fn main() {
     let out = PathBuf::from(env::var("OUT_DIR").unwrap());
     let handle = Command::new("flatc")
-        .args(&[
-            "--rust",
-            "-o",
-            out.to_str().unwrap(),
-            "flatbuffers/messages.fbs",
-        ])
+        .args(&["--rust", "-o", out.to_str().unwrap(), SCHEMA])
         .spawn()
         .expect("flatc failed");
 
@@ -37,4 +256,6 @@ fn main() {
         mod_path.to_string_lossy()
     )
     .expect("flatc mod failed");
+
+    write_request_layouts(SCHEMA, &out.join("request_layouts.rs"));
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A pattern is only reported once this many consecutive reads of the stream followed it
+const CONFIRMING_READS: u32 = 2;
+// Streams which weren't read for this long are dropped, once there are more than MAX_STREAMS
+const STREAM_IDLE: Duration = Duration::from_secs(30);
+const MAX_STREAMS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessPattern {
+    // Too few reads to tell
+    Unknown,
+    // Each read starts where the previous one ended
+    Sequential,
+    // Reads of the same size, each starting this many bytes after the start of the previous one, such as a
+    // database scanning every nth page
+    Strided(u64),
+    Random,
+}
+
+struct Stream {
+    last_offset: u64,
+    last_size: u32,
+    // Pattern of the latest reads, and how many consecutive reads followed it
+    candidate: AccessPattern,
+    confirmations: u32,
+    last_read: Instant,
+}
+
+// Classifies the reads of each (process, inode) stream, so that the prefetch of each stream can suit how it's read
+#[derive(Default)]
+pub struct AccessPatternTracker {
+    streams: Mutex<HashMap<(u32, u64), Stream>>,
+}
+
+impl AccessPatternTracker {
+    // Records a read of the stream, and returns the pattern its reads follow
+    pub fn record(&self, pid: u32, inode: u64, offset: u64, size: u32) -> AccessPattern {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() > MAX_STREAMS {
+            streams.retain(|_, stream| stream.last_read.elapsed() < STREAM_IDLE);
+        }
+        let stream = match streams.get_mut(&(pid, inode)) {
+            Some(stream) => stream,
+            None => {
+                streams.insert(
+                    (pid, inode),
+                    Stream {
+                        last_offset: offset,
+                        last_size: size,
+                        candidate: AccessPattern::Unknown,
+                        confirmations: 0,
+                        last_read: Instant::now(),
+                    },
+                );
+                return AccessPattern::Unknown;
+            }
+        };
+
+        let observed = if offset == stream.last_offset + u64::from(stream.last_size) {
+            AccessPattern::Sequential
+        } else if offset > stream.last_offset && size == stream.last_size {
+            AccessPattern::Strided(offset - stream.last_offset)
+        } else {
+            AccessPattern::Random
+        };
+        if observed == stream.candidate {
+            stream.confirmations += 1;
+        } else {
+            stream.candidate = observed;
+            stream.confirmations = 1;
+        }
+        stream.last_offset = offset;
+        stream.last_size = size;
+        stream.last_read = Instant::now();
+
+        if stream.candidate == AccessPattern::Random || stream.confirmations >= CONFIRMING_READS {
+            stream.candidate
+        } else {
+            AccessPattern::Unknown
+        }
+    }
+
+    // Called when the file is closed
+    pub fn forget(&self, inode: u64) {
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|(_, stream_inode), _| *stream_inode != inode);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Buckets of sessions which haven't sent a request for this long are dropped, once there are MAX_IDLE_SESSIONS
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_IDLE_SESSIONS: usize = 1024;
+
+struct Limits {
+    // Zero is unlimited
+    global_bytes_per_second: u64,
+    peer_bytes_per_second: u64,
+    // Time at which the bytes reserved so far will have been sent, at the limit
+    global_sent_at: Instant,
+    peer_sent_at: HashMap<u64, Instant>,
+}
+
+fn transfer_time(bytes: u64, bytes_per_second: u64) -> Duration {
+    Duration::from_micros(bytes * 1_000_000 / bytes_per_second)
+}
+
+// Paces data sent to other nodes, such as snapshots, so that recovering a node doesn't saturate the network.
+// Limits apply to the total sent, and to each peer separately, and can be changed while the node is running
+#[derive(Clone)]
+pub struct BandwidthLimiter {
+    limits: Arc<Mutex<Limits>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(global_bytes_per_second: u64, peer_bytes_per_second: u64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            limits: Arc::new(Mutex::new(Limits {
+                global_bytes_per_second,
+                peer_bytes_per_second,
+                global_sent_at: Instant::now(),
+                peer_sent_at: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn set_limits(&self, global_bytes_per_second: u64, peer_bytes_per_second: u64) {
+        let mut limits = self.limits.lock().unwrap();
+        limits.global_bytes_per_second = global_bytes_per_second;
+        limits.peer_bytes_per_second = peer_bytes_per_second;
+    }
+
+    // Returns (global, per peer)
+    pub fn limits(&self) -> (u64, u64) {
+        let limits = self.limits.lock().unwrap();
+        (limits.global_bytes_per_second, limits.peer_bytes_per_second)
+    }
+
+    // Reserves bytes to send to peer, and returns how long to wait before sending them
+    pub fn reserve(&self, peer: u64, bytes: u64) -> Duration {
+        let mut limits = self.limits.lock().unwrap();
+        let now = Instant::now();
+        let mut wait = Duration::from_secs(0);
+
+        let global_bytes_per_second = limits.global_bytes_per_second;
+        if global_bytes_per_second > 0 {
+            let start = limits.global_sent_at.max(now);
+            wait = start - now;
+            limits.global_sent_at = start + transfer_time(bytes, global_bytes_per_second);
+        }
+        let peer_bytes_per_second = limits.peer_bytes_per_second;
+        if peer_bytes_per_second > 0 {
+            let sent_at = limits.peer_sent_at.entry(peer).or_insert(now);
+            let start = (*sent_at).max(now);
+            wait = wait.max(start - now);
+            *sent_at = start + transfer_time(bytes, peer_bytes_per_second);
+        }
+
+        wait
+    }
+}
+
+// Allows bursts of up to one second at the rate
+struct TokenBucket {
+    per_second: u64,
+    // Negative once more than the burst has been used
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_second: u64) -> TokenBucket {
+        TokenBucket {
+            per_second,
+            tokens: per_second as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes amount tokens, and returns how long to wait until they would have been available
+    fn take(&mut self, amount: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.refilled_at;
+        self.refilled_at = now;
+        if self.per_second == 0 {
+            return Duration::from_secs(0);
+        }
+        let rate = self.per_second as f64;
+        self.tokens = (self.tokens + elapsed.as_micros() as f64 * rate / 1_000_000.0).min(rate);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_micros((-self.tokens * 1_000_000.0 / rate) as u64)
+        }
+    }
+}
+
+// Zero is unlimited
+#[derive(Clone, Copy, PartialEq)]
+pub struct SessionLimits {
+    pub iops: u64,
+    pub bytes_per_second: u64,
+}
+
+struct SessionBuckets {
+    limits: SessionLimits,
+    operations: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl SessionBuckets {
+    fn new(limits: SessionLimits) -> SessionBuckets {
+        SessionBuckets {
+            limits,
+            operations: TokenBucket::new(limits.iops),
+            bytes: TokenBucket::new(limits.bytes_per_second),
+        }
+    }
+}
+
+struct SessionState {
+    default_limits: SessionLimits,
+    // Sessions which have their own limits, instead of the default
+    overrides: HashMap<u64, SessionLimits>,
+    buckets: HashMap<u64, SessionBuckets>,
+}
+
+// Rate limits the requests of each client session, so that one client can't monopolize the node
+#[derive(Clone)]
+pub struct SessionLimiter {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl SessionLimiter {
+    pub fn new(default_limits: SessionLimits) -> SessionLimiter {
+        SessionLimiter {
+            state: Arc::new(Mutex::new(SessionState {
+                default_limits,
+                overrides: HashMap::new(),
+                buckets: HashMap::new(),
+            })),
+        }
+    }
+
+    // Session 0 sets the default limits, of sessions which don't have their own
+    pub fn set_limits(&self, session_id: u64, limits: SessionLimits) {
+        let mut state = self.state.lock().unwrap();
+        if session_id == 0 {
+            state.default_limits = limits;
+        } else {
+            state.overrides.insert(session_id, limits);
+        }
+    }
+
+    // Accounts for a request of session_id which transfers bytes, and returns how long to delay it
+    pub fn reserve(&self, session_id: u64, bytes: u64) -> Duration {
+        // Requests which aren't part of a session come from other nodes
+        if session_id == 0 {
+            return Duration::from_secs(0);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.buckets.len() > MAX_IDLE_SESSIONS {
+            state.buckets.retain(|_, buckets| {
+                buckets.operations.refilled_at.elapsed() < SESSION_IDLE_TIMEOUT
+            });
+        }
+        let limits = state
+            .overrides
+            .get(&session_id)
+            .cloned()
+            .unwrap_or(state.default_limits);
+        let buckets = state
+            .buckets
+            .entry(session_id)
+            .or_insert_with(|| SessionBuckets::new(limits));
+        if buckets.limits != limits {
+            *buckets = SessionBuckets::new(limits);
+        }
+
+        buckets.operations.take(1).max(buckets.bytes.take(bytes))
+    }
+}
@@ -1,28 +1,112 @@
 use std::cell::{RefCell, RefMut};
+use std::collections::{BTreeSet, HashMap};
 use std::ffi::OsString;
+use std::io::{ErrorKind, Write};
 use std::net::SocketAddr;
 
-use flatbuffers::FlatBufferBuilder;
+use flatbuffers::{FlatBufferBuilder, UnionWIPOffset, Vector, WIPOffset};
 use thread_local::CachedThreadLocal;
 
+use crate::client_registry::ClientStatus;
 use crate::generated::*;
+use crate::request_stats::NodeStats;
+use crate::storage::access_stats::FileAccess;
+use crate::storage::block_cache::BlockCacheStats;
 use crate::storage::data_storage::BLOCK_SIZE;
+use crate::storage::file_storage::MAX_BATCH_GETATTR_INODES;
+use crate::storage::lock_manager::{ByteRangeLock, LockEntry, LockOwner};
+use crate::storage::metadata_storage::{default_limits, FindQuery};
+use crate::storage::snapshot::SnapshotInfo;
 use crate::storage::ROOT_INODE;
-use crate::tcp_client::TcpClient;
-use crate::utils::{decode_fast_read_response_inplace, finalize_request, response_or_error};
+use crate::tcp_client::{Keepalive, TcpClient};
+use crate::utils::{
+    decode_fast_read_response_inplace, finalize_client_request, finalize_request,
+    finalize_session_request, into_error_code, parse_log_records, response_or_error,
+    LOG_RECORD_HEADER_SIZE,
+};
+use byteorder::{ByteOrder, LittleEndian};
 use fuse::FileAttr;
+use log::{info, warn};
+use rand::Rng;
+use std::cmp::min;
 use std::ops::Add;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
 
 fn to_fuse_file_type(file_type: FileKind) -> fuse::FileType {
     match file_type {
         FileKind::File => fuse::FileType::RegularFile,
         FileKind::Directory => fuse::FileType::Directory,
         FileKind::Symlink => fuse::FileType::Symlink,
+        FileKind::SharedLog => fuse::FileType::RegularFile,
         FileKind::DefaultValueNotAType => unreachable!(),
     }
 }
 
+// Size of the writes used to upload a file
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+// Size of the reads used to follow a shared log
+const LOG_READ_SIZE: u32 = 1024 * 1024;
+// Number of times a request is resent on a new connection, after the connection it was sent on fails
+const MAX_RESENDS: u32 = 3;
+// Longest wait between resends, while the server is unreachable
+const MAX_RESEND_BACKOFF: Duration = Duration::from_secs(1);
+
+// What requests do while the server can't be reached
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnreachablePolicy {
+    // Resend until the server answers
+    Hang,
+    // Fail with EIO once the server has been unreachable for this long. Requests sent after that fail right away,
+    // until the server is reachable again
+    FailAfter(Duration),
+    // Fail after a few resends, so that the mount can answer from what it has cached
+    ServeStale,
+}
+
+pub type DirectoryEntryTuple = (u64, OsString, fuse::FileType);
+
+// The attributes of a file which a fuse FileAttr can't represent
+#[derive(Clone, Copy)]
+pub struct FileDetails {
+    pub kind: FileKind,
+    // Changes whenever the file's data does
+    pub data_version: u64,
+}
+
+// Data of a write, copied straight into the request which will carry it, so that the caller's buffer can be
+// reused without the data being copied twice
+pub struct EncodedWrite {
+    builder: FlatBufferBuilder<'static>,
+    data_offset: WIPOffset<Vector<'static, u8>>,
+    length: usize,
+}
+
+impl EncodedWrite {
+    pub fn new(data: &[u8]) -> EncodedWrite {
+        let mut builder = FlatBufferBuilder::new_with_capacity(data.len() + 256);
+        let data_offset = builder.create_vector_direct(data);
+        EncodedWrite {
+            builder,
+            data_offset,
+            length: data.len(),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        // The vector is the only thing in the builder so far, and starts with its length
+        &self.builder.unfinished_data()[4..4 + self.length]
+    }
+}
+
+pub enum DirectoryListing {
+    Full(Vec<DirectoryEntryTuple>),
+    // Entries added or replaced, and names removed, since the requested version
+    Delta(Vec<DirectoryEntryTuple>, Vec<OsString>),
+}
+
 fn metadata_to_fuse_fileattr(metadata: &FileMetadataResponse) -> FileAttr {
     FileAttr {
         ino: metadata.inode(),
@@ -40,7 +124,10 @@ fn metadata_to_fuse_fileattr(metadata: &FileMetadataResponse) -> FileAttr {
             metadata.last_metadata_modified_time().seconds() as u64,
             metadata.last_metadata_modified_time().nanos() as u32,
         )),
-        crtime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH.add(Duration::new(
+            metadata.creation_time().seconds() as u64,
+            metadata.creation_time().nanos() as u32,
+        )),
         kind: to_fuse_file_type(metadata.kind()),
         perm: metadata.mode(),
         nlink: metadata.hard_links(),
@@ -53,21 +140,336 @@ fn metadata_to_fuse_fileattr(metadata: &FileMetadataResponse) -> FileAttr {
     }
 }
 
+fn decode_locks(response: LocksResponse) -> Vec<LockEntry> {
+    let locks = response.locks();
+    (0..locks.len())
+        .map(|i| {
+            let entry = locks.get(i);
+            LockEntry {
+                inode: entry.inode(),
+                lock: ByteRangeLock {
+                    owner: LockOwner {
+                        session_id: entry.session_id(),
+                        owner: entry.owner(),
+                    },
+                    pid: entry.pid(),
+                    start: entry.start(),
+                    end: entry.end(),
+                    exclusive: entry.kind() == LockKind::Exclusive,
+                    fencing_token: entry.fencing_token(),
+                },
+                waiting: entry.waiting(),
+            }
+        })
+        .collect()
+}
+
+// Space available to the filesystem. Sizes are in blocks of block_size
+pub struct FilesystemInformation {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    // Free blocks excluding the space reserved for root
+    pub available_blocks: u64,
+    pub reserved_blocks: u64,
+    pub files: u64,
+    pub free_files: u64,
+    pub limits: FilesystemLimits,
+}
+
+pub struct RaftLogEntryInfo {
+    pub index: u64,
+    pub term: u64,
+    pub size: u32,
+    pub description: String,
+}
+
+pub struct RaftPeerInfo {
+    pub node_id: u64,
+    pub matched: u64,
+    pub next_index: u64,
+    pub state: String,
+    pub recent_active: bool,
+}
+
+// Raft state of a node
+pub struct RaftDebugInfo {
+    pub node_id: u64,
+    pub term: u64,
+    pub role: String,
+    pub leader_id: u64,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub first_index: u64,
+    pub last_index: u64,
+    pub log_tail: Vec<RaftLogEntryInfo>,
+    // Only populated on the leader
+    pub progress: Vec<RaftPeerInfo>,
+}
+
+// A request which a node is executing
+pub struct InflightRequestInfo {
+    pub request_type: String,
+    // 0 if the request isn't for an inode
+    pub inode: u64,
+    pub age: Duration,
+    // 0 for requests from other nodes
+    pub session_id: u64,
+    pub client: String,
+    // What the request is waiting for
+    pub state: String,
+}
+
+// Identity of a node, which its peers check before they start
+pub struct NodeInfo {
+    pub cluster_name: String,
+    pub protocol_version: u32,
+    pub time: SystemTime,
+}
+
+pub struct BackgroundTaskInfo {
+    pub name: String,
+    pub runs: u64,
+    pub deferred: u64,
+    pub bytes: u64,
+}
+
+// Status of the background maintenance tasks of a node
+pub struct BackgroundStatus {
+    pub paused: bool,
+    // Zero is unlimited
+    pub bytes_per_second: u64,
+    pub tasks: Vec<BackgroundTaskInfo>,
+}
+
+// Checksum of the copy of a file stored on each node
+pub struct FileVerification {
+    pub mirrored: bool,
+    // (node ID, checksum)
+    pub checksums: Vec<(u64, Vec<u8>)>,
+}
+
+impl FileVerification {
+    // Nodes whose copy differs from the majority's. If there's no majority, all the nodes are returned.
+    // Striped files store different blocks on each node, so they never diverge
+    pub fn divergent_nodes(&self) -> Vec<u64> {
+        if !self.mirrored {
+            return vec![];
+        }
+        let mut counts: HashMap<&[u8], usize> = HashMap::new();
+        for (_, checksum) in self.checksums.iter() {
+            *counts.entry(checksum.as_slice()).or_insert(0) += 1;
+        }
+        let majority = counts
+            .iter()
+            .find(|(_, count)| **count * 2 > self.checksums.len())
+            .map(|(checksum, _)| *checksum);
+
+        return self
+            .checksums
+            .iter()
+            .filter(|(_, checksum)| Some(checksum.as_slice()) != majority)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+    }
+}
+
 pub struct NodeClient {
     tcp_client: TcpClient,
     response_buffer: CachedThreadLocal<RefCell<Vec<u8>>>,
     request_builder: CachedThreadLocal<RefCell<FlatBufferBuilder<'static>>>,
+    // Highest Raft term seen in any response. Sent with requests as a fencing token, so that a leader which has been
+    // superseded rejects them, instead of answering
+    highest_term: AtomicU64,
+    // Requests are numbered within the session, so that the server can detect retried writes
+    session_id: u64,
+    next_sequence_number: AtomicU64,
+    // Sequence numbers of the requests which haven't been answered yet. The server forgets the responses to the
+    // requests before the first of them
+    unanswered: Mutex<BTreeSet<u64>>,
+    unreachable_policy: UnreachablePolicy,
+    // When the server stopped answering, if it currently isn't
+    unreachable_since: Mutex<Option<Instant>>,
+    // Node which relaxed metadata reads are sent to, instead of the server, because it's closer
+    read_replica: Mutex<Option<Arc<TcpClient>>>,
+    // Fencing token sent with the writes to each inode, while an exclusive lock on it is held
+    fencing_tokens: Mutex<HashMap<u64, u64>>,
+    // Cluster-wide ID of the mount, assigned when it first registers. 0 until then
+    client_id: AtomicU64,
 }
 
 impl NodeClient {
     pub fn new(server_ip_port: SocketAddr) -> NodeClient {
+        NodeClient::with_tcp_client(TcpClient::new(server_ip_port))
+    }
+
+    // Sends up to connections requests to the server at once, for clients which are used by several threads.
+    // With keepalive, the server is pinged whenever a connection is idle, so that dead connections are detected
+    // in seconds
+    pub fn with_connections(
+        server_ip_port: SocketAddr,
+        connections: usize,
+        keepalive: Option<Keepalive>,
+    ) -> NodeClient {
+        let keepalive = match keepalive {
+            Some(keepalive) => keepalive,
+            None => {
+                return NodeClient::with_tcp_client(TcpClient::with_connections(
+                    server_ip_port,
+                    connections,
+                ))
+            }
+        };
+        let mut builder = FlatBufferBuilder::new();
+        let request_builder = PingRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
+        let ping_request = builder.finished_data().to_vec();
+
+        NodeClient::with_tcp_client(TcpClient::with_keepalive(
+            server_ip_port,
+            connections,
+            keepalive,
+            ping_request,
+        ))
+    }
+
+    fn with_tcp_client(tcp_client: TcpClient) -> NodeClient {
         NodeClient {
-            tcp_client: TcpClient::new(server_ip_port),
+            tcp_client,
             response_buffer: CachedThreadLocal::new(),
             request_builder: CachedThreadLocal::new(),
+            highest_term: AtomicU64::new(0),
+            // 0 is reserved for requests that are not part of a session
+            session_id: rand::thread_rng().gen_range(1, u64::max_value()),
+            next_sequence_number: AtomicU64::new(1),
+            unanswered: Mutex::new(BTreeSet::new()),
+            unreachable_policy: UnreachablePolicy::FailAfter(Duration::from_secs(0)),
+            unreachable_since: Mutex::new(None),
+            read_replica: Mutex::new(None),
+            fencing_tokens: Mutex::new(HashMap::new()),
+            client_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id.load(Ordering::SeqCst)
+    }
+
+    pub fn set_unreachable_policy(&mut self, policy: UnreachablePolicy) {
+        self.unreachable_policy = policy;
+    }
+
+    // Must not be larger than the servers' limit. Larger reads and writes are split into several requests
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.tcp_client.set_max_frame_length(max_frame_length);
+    }
+
+    // Largest amount of data sent or received in one request, leaving room for the rest of the message
+    fn max_chunk_size(&self) -> usize {
+        self.tcp_client.max_frame_length() / 2
+    }
+
+    // Whether the last request failed to reach the server
+    pub fn is_unreachable(&self) -> bool {
+        self.unreachable_since
+            .lock()
+            .expect("unreachable_since lock is poisoned")
+            .is_some()
+    }
+
+    // Returns when the server became unreachable
+    fn mark_unreachable(&self, error: &std::io::Error) -> Instant {
+        let mut unreachable_since = self
+            .unreachable_since
+            .lock()
+            .expect("unreachable_since lock is poisoned");
+        if unreachable_since.is_none() {
+            warn!(
+                "Server is unreachable ({:?}). Policy is {:?}",
+                error, self.unreachable_policy
+            );
+            *unreachable_since = Some(Instant::now());
+        }
+        return unreachable_since.unwrap();
+    }
+
+    fn mark_reachable(&self) {
+        let mut unreachable_since = self
+            .unreachable_since
+            .lock()
+            .expect("unreachable_since lock is poisoned");
+        if let Some(since) = unreachable_since.take() {
+            info!(
+                "Server is reachable again, after {} seconds",
+                since.elapsed().as_secs()
+            );
+        }
+    }
+
+    fn finalize_request(
+        &self,
+        builder: &mut FlatBufferBuilder,
+        request_type: RequestType,
+        finish_offset: WIPOffset<UnionWIPOffset>,
+    ) {
+        // Numbered while holding the lock, so that no earlier request is missing from unanswered
+        let mut unanswered = self.unanswered.lock().expect("unanswered lock is poisoned");
+        let sequence_number = self.next_sequence_number.fetch_add(1, Ordering::SeqCst);
+        unanswered.insert(sequence_number);
+        let acknowledged = *unanswered.iter().next().unwrap();
+        finalize_client_request(
+            builder,
+            request_type,
+            finish_offset,
+            self.session_id,
+            sequence_number,
+            acknowledged,
+            self.highest_term.load(Ordering::SeqCst),
+        );
+    }
+
+    // Like finalize_request(), except that the request isn't fenced, so that a read replica which hasn't caught up
+    // with the latest term still answers it. Reads are never applied, so they don't need a sequence number
+    fn finalize_relaxed_request(
+        &self,
+        builder: &mut FlatBufferBuilder,
+        request_type: RequestType,
+        finish_offset: WIPOffset<UnionWIPOffset>,
+    ) {
+        finalize_session_request(builder, request_type, finish_offset, self.session_id, 0);
+    }
+
+    // Records the term of a response. Responses are never rejected for their term: the request was fenced when it
+    // was sent, and a write may have been committed in an older term than the latest one
+    fn observe_term(&self, term: u64) {
+        let mut highest = self.highest_term.load(Ordering::SeqCst);
+        while term > highest {
+            match self.highest_term.compare_exchange(
+                highest,
+                term,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return,
+                Err(current) => highest = current,
+            }
         }
     }
 
+    fn check_response<'b>(
+        &self,
+        buffer: &'b mut Vec<u8>,
+    ) -> Result<GenericResponse<'b>, ErrorCode> {
+        let term = flatbuffers::get_root::<GenericResponse>(buffer).term();
+        self.observe_term(term);
+        return response_or_error(buffer);
+    }
+
     fn get_or_create_builder(&self) -> RefMut<FlatBufferBuilder<'static>> {
         let mut builder = self
             .request_builder
@@ -84,14 +486,90 @@ impl NodeClient {
             .borrow_mut();
     }
 
+    // Sends the request, and resends it on a new connection if the connection fails before the response is received.
+    // Requests are still applied at most once, since the server remembers the response to every request of the
+    // session which hasn't been acknowledged
+    fn send_with_resend(&self, request: &[u8], buffer: &mut Vec<u8>) -> Result<(), ErrorCode> {
+        buffer.clear();
+        return self.send_with_resend_appended(request, buffer);
+    }
+
+    // Like send_with_resend, except that the response is appended to buffer
+    fn send_with_resend_appended(
+        &self,
+        request: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ErrorCode> {
+        let result = self.resend_until_answered(request, buffer);
+        // Whether it was answered or given up on, the server can forget its response once later requests
+        // acknowledge it
+        let sequence_number = get_root_as_generic_request(&request[4..]).sequence_number();
+        self.unanswered
+            .lock()
+            .expect("unanswered lock is poisoned")
+            .remove(&sequence_number);
+        return result;
+    }
+
+    fn resend_until_answered(&self, request: &[u8], buffer: &mut Vec<u8>) -> Result<(), ErrorCode> {
+        // The server would reject it without reading it
+        if request.len() - 4 > self.tcp_client.max_frame_length() {
+            return Err(ErrorCode::RequestTooLarge);
+        }
+        // Skip the size prefix
+        let generic_request = get_root_as_generic_request(&request[4..]);
+
+        let sent_at = Instant::now();
+        let mut resends = 0;
+        loop {
+            match self.tcp_client.send_and_receive_appended(request, buffer) {
+                Ok(_) => {
+                    self.mark_reachable();
+                    return Ok(());
+                }
+                // The server answered, but the response was too large to read
+                Err(ref error) if error.kind() == ErrorKind::InvalidData => {
+                    self.mark_reachable();
+                    return Err(ErrorCode::BadResponse);
+                }
+                Err(error) => {
+                    let unreachable_since = self.mark_unreachable(&error);
+                    match self.unreachable_policy {
+                        UnreachablePolicy::Hang => {}
+                        UnreachablePolicy::FailAfter(timeout) => {
+                            if unreachable_since.elapsed() >= timeout
+                                && (resends >= MAX_RESENDS || unreachable_since < sent_at)
+                            {
+                                return Err(ErrorCode::Uncategorized);
+                            }
+                        }
+                        UnreachablePolicy::ServeStale => {
+                            if resends >= MAX_RESENDS {
+                                return Err(into_error_code(error));
+                            }
+                        }
+                    }
+                    resends += 1;
+                    warn!(
+                        "Resending {:?} after connection failure: {:?}",
+                        generic_request.request_type(),
+                        error
+                    );
+                    sleep(min(
+                        Duration::from_millis(100 * u64::from(resends)),
+                        MAX_RESEND_BACKOFF,
+                    ));
+                }
+            }
+        }
+    }
+
     fn send_receive_raw<'b>(
         &self,
         request: &[u8],
         buffer: &'b mut Vec<u8>,
     ) -> Result<&'b mut Vec<u8>, ErrorCode> {
-        self.tcp_client
-            .send_and_receive_length_prefixed(request, buffer.as_mut())
-            .map_err(|_| ErrorCode::Uncategorized)?;
+        self.send_with_resend(request, buffer)?;
         Ok(buffer)
     }
 
@@ -100,36 +578,107 @@ impl NodeClient {
         request: &[u8],
         buffer: &'b mut Vec<u8>,
     ) -> Result<GenericResponse<'b>, ErrorCode> {
-        self.tcp_client
-            .send_and_receive_length_prefixed(request, buffer.as_mut())
-            .map_err(|_| ErrorCode::Uncategorized)?;
-        return response_or_error(buffer);
+        self.send_with_resend(request, buffer)?;
+        return self.check_response(buffer);
     }
 
-    pub fn leader_id(&self) -> Result<u64, ErrorCode> {
-        let mut builder = self.get_or_create_builder();
-        let request_builder = GetLeaderRequestBuilder::new(&mut builder);
-        let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::GetLeaderRequest, finish_offset);
+    // Sends getattr and xattr reads to the read replica, if one was chosen, and otherwise like send(). They may not
+    // see the latest writes made through the server, if the replica hasn't applied them yet
+    fn send_relaxed<'b>(
+        &self,
+        request: &[u8],
+        buffer: &'b mut Vec<u8>,
+    ) -> Result<GenericResponse<'b>, ErrorCode> {
+        let replica = self
+            .read_replica
+            .lock()
+            .expect("read_replica lock is poisoned")
+            .clone();
+        let sent_to_replica = match replica {
+            Some(replica) => match replica.send_and_receive_length_prefixed(request, buffer) {
+                Ok(_) => true,
+                Err(error) => {
+                    info!(
+                        "Read replica failed, reading from the server instead: {:?}",
+                        error
+                    );
+                    *self
+                        .read_replica
+                        .lock()
+                        .expect("read_replica lock is poisoned") = None;
+                    false
+                }
+            },
+            None => false,
+        };
+        if !sent_to_replica {
+            self.send_with_resend(request, buffer)?;
+        }
+        return self.check_response(buffer);
+    }
 
-        let mut buffer = self.get_or_create_buffer();
-        let response = self.send(builder.finished_data(), &mut buffer)?;
-        let node_id_response = response
-            .response_as_node_id_response()
-            .ok_or(ErrorCode::BadResponse)?;
+    // Measures the round trip time to every node, and chooses the closest one as the read replica. Nodes in zone
+    // are preferred, if any of them are reachable. The server itself is chosen if it's the closest, so that reads
+    // only leave it when that's faster
+    pub fn choose_read_replica(&self, zone: Option<&str>) -> Result<(), ErrorCode> {
+        let start = Instant::now();
+        self.ping()?;
+        let server_round_trip = start.elapsed();
+
+        let mut candidates = vec![];
+        for node in self.cluster_stats()? {
+            // The node with no address is the server
+            if node.address.is_empty() {
+                candidates.push((node.zone, server_round_trip, None));
+                continue;
+            }
+            if !node.reachable {
+                continue;
+            }
+            let address: SocketAddr = match node.address.parse() {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
+            let client = NodeClient::new(address);
+            // The first ping includes connecting
+            if client.ping().is_err() {
+                continue;
+            }
+            let start = Instant::now();
+            if client.ping().is_ok() {
+                candidates.push((node.zone, start.elapsed(), Some(address)));
+            }
+        }
 
-        return Ok(node_id_response.node_id());
+        let in_zone = candidates
+            .iter()
+            .any(|(node_zone, _, _)| Some(node_zone.as_str()) == zone);
+        let closest = candidates
+            .into_iter()
+            .filter(|(node_zone, _, _)| !in_zone || Some(node_zone.as_str()) == zone)
+            .min_by_key(|(_, round_trip, _)| *round_trip);
+        let replica = match closest {
+            Some((_, round_trip, Some(address))) => {
+                info!("Reading metadata from {} ({:?} away)", address, round_trip);
+                let mut tcp_client = TcpClient::new(address);
+                tcp_client.set_max_frame_length(self.tcp_client.max_frame_length());
+                Some(Arc::new(tcp_client))
+            }
+            _ => None,
+        };
+        *self
+            .read_replica
+            .lock()
+            .expect("read_replica lock is poisoned") = replica;
+
+        Ok(())
     }
 
-    pub fn fsck(&self) -> Result<(), ErrorCode> {
+    pub fn ping(&self) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let request_builder = FilesystemCheckRequestBuilder::new(&mut builder);
+        let request_builder = PingRequestBuilder::new(&mut builder);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(
-            &mut builder,
-            RequestType::FilesystemCheckRequest,
-            finish_offset,
-        );
+        self.finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -140,151 +689,140 @@ impl NodeClient {
         Ok(())
     }
 
-    pub fn mkdir(
-        &self,
-        parent: u64,
-        name: &str,
-        uid: u32,
-        gid: u32,
-        mode: u16,
-    ) -> Result<FileAttr, ErrorCode> {
+    // Sets the log level of module on the node, or of all modules which don't have their own if module is None
+    pub fn set_log_level(&self, module: Option<&str>, level: LogLevel) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_name = builder.create_string(name);
-        let mut request_builder = MkdirRequestBuilder::new(&mut builder);
-        request_builder.add_parent(parent);
-        request_builder.add_name(builder_name);
-        request_builder.add_uid(uid);
-        request_builder.add_gid(gid);
-        request_builder.add_mode(mode);
+        let module_offset = module.map(|module| builder.create_string(module));
+        let mut request_builder = SetLogLevelRequestBuilder::new(&mut builder);
+        if let Some(offset) = module_offset {
+            request_builder.add_module(offset);
+        }
+        request_builder.add_level(level);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::MkdirRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::SetLogLevelRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let metadata = response
-            .response_as_file_metadata_response()
+        response
+            .response_as_empty_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(metadata_to_fuse_fileattr(&metadata));
+        Ok(())
     }
 
-    pub fn lookup(&self, parent: u64, name: &str, context: UserContext) -> Result<u64, ErrorCode> {
+    // Zero is unlimited
+    pub fn set_replication_bandwidth(
+        &self,
+        global_bytes_per_second: u64,
+        peer_bytes_per_second: u64,
+    ) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_name = builder.create_string(name);
-        let mut request_builder = LookupRequestBuilder::new(&mut builder);
-        request_builder.add_parent(parent);
-        request_builder.add_name(builder_name);
-        request_builder.add_context(&context);
+        let mut request_builder = SetReplicationBandwidthRequestBuilder::new(&mut builder);
+        request_builder.add_global_bytes_per_second(global_bytes_per_second);
+        request_builder.add_peer_bytes_per_second(peer_bytes_per_second);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::LookupRequest, finish_offset);
+        self.finalize_request(
+            &mut builder,
+            RequestType::SetReplicationBandwidthRequest,
+            finish_offset,
+        );
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let inode_response = response
-            .response_as_inode_response()
+        response
+            .response_as_empty_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(inode_response.inode());
+        Ok(())
     }
 
-    pub fn create(
+    // Session 0 sets the default limits. Zero is unlimited
+    pub fn set_session_limits(
         &self,
-        parent: u64,
-        name: &str,
-        uid: u32,
-        gid: u32,
-        mode: u16,
-        kind: FileKind,
-    ) -> Result<FileAttr, ErrorCode> {
+        session_id: u64,
+        iops: u64,
+        bytes_per_second: u64,
+    ) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_name = builder.create_string(name);
-        let mut request_builder = CreateRequestBuilder::new(&mut builder);
-        request_builder.add_parent(parent);
-        request_builder.add_name(builder_name);
-        request_builder.add_uid(uid);
-        request_builder.add_gid(gid);
-        request_builder.add_mode(mode);
-        request_builder.add_kind(kind);
+        let mut request_builder = SetSessionLimitsRequestBuilder::new(&mut builder);
+        request_builder.add_target_session_id(session_id);
+        request_builder.add_iops(iops);
+        request_builder.add_bytes_per_second(bytes_per_second);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::CreateRequest, finish_offset);
+        self.finalize_request(
+            &mut builder,
+            RequestType::SetSessionLimitsRequest,
+            finish_offset,
+        );
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let metadata = response
-            .response_as_file_metadata_response()
+        response
+            .response_as_empty_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(metadata_to_fuse_fileattr(&metadata));
+        Ok(())
     }
 
-    pub fn getattr(&self, inode: u64) -> Result<FileAttr, ErrorCode> {
+    pub fn set_limits(&self, limits: FilesystemLimits) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = GetattrRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
+        let mut request_builder = SetLimitsRequestBuilder::new(&mut builder);
+        request_builder.add_limits(&limits);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::GetattrRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::SetLimitsRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let metadata = response
-            .response_as_file_metadata_response()
+        response
+            .response_as_empty_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(metadata_to_fuse_fileattr(&metadata));
+        Ok(())
     }
 
-    pub fn getxattr(&self, inode: u64, key: &str) -> Result<Vec<u8>, ErrorCode> {
+    pub fn freeze(&self, inode: u64, timeout_seconds: u32) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_key = builder.create_string(key);
-        let mut request_builder = GetXattrRequestBuilder::new(&mut builder);
+        let mut request_builder = FreezeRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_key(builder_key);
+        request_builder.add_timeout_seconds(timeout_seconds);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::GetXattrRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::FreezeRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let data = response
-            .response_as_read_response()
-            .ok_or(ErrorCode::BadResponse)?
-            .data();
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(data.to_vec());
+        Ok(())
     }
 
-    pub fn listxattr(&self, inode: u64) -> Result<Vec<String>, ErrorCode> {
+    pub fn thaw(&self, inode: u64) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ListXattrsRequestBuilder::new(&mut builder);
+        let mut request_builder = ThawRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ListXattrsRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::ThawRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let xattrs_response = response
-            .response_as_xattrs_response()
+        response
+            .response_as_empty_response()
             .ok_or(ErrorCode::BadResponse)?;
-        let xattrs = xattrs_response.xattrs();
-
-        let mut attrs = vec![];
-        for i in 0..xattrs.len() {
-            let attr = xattrs.get(i);
-            attrs.push(attr.to_string());
-        }
 
-        return Ok(attrs);
+        Ok(())
     }
 
-    pub fn setxattr(&self, inode: u64, key: &str, value: &[u8]) -> Result<(), ErrorCode> {
+    pub fn set_request_dumping(&self, enabled: bool) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_key = builder.create_string(key);
-        let builder_value = builder.create_vector_direct(value);
-        let mut request_builder = SetXattrRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
-        request_builder.add_key(builder_key);
-        request_builder.add_value(builder_value);
+        let mut request_builder = SetRequestDumpingRequestBuilder::new(&mut builder);
+        request_builder.add_enabled(enabled);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::SetXattrRequest, finish_offset);
+        self.finalize_request(
+            &mut builder,
+            RequestType::SetRequestDumpingRequest,
+            finish_offset,
+        );
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -295,45 +833,1596 @@ impl NodeClient {
         Ok(())
     }
 
-    pub fn removexattr(&self, inode: u64, key: &str) -> Result<(), ErrorCode> {
+    pub fn leader_id(&self) -> Result<u64, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = GetLeaderRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::GetLeaderRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let node_id_response = response
+            .response_as_node_id_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(node_id_response.node_id());
+    }
+
+    // Makes the node this client is connected to the only member of its cluster. Returns the IDs of the nodes
+    // which were removed, and the number of striped files which lost blocks
+    pub fn force_new_cluster(&self, dry_run: bool) -> Result<(Vec<u64>, u64), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ForceNewClusterRequestBuilder::new(&mut builder);
+        request_builder.add_dry_run(dry_run);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::ForceNewClusterRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let force_response = response
+            .response_as_force_new_cluster_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let removed = force_response.removed_nodes();
+        let removed_nodes = (0..removed.len()).map(|i| removed.get(i)).collect();
+
+        return Ok((removed_nodes, force_response.striped_files()));
+    }
+
+    // Returns the Raft state of the node this client is connected to
+    pub fn raft_debug(&self, log_tail: u32) -> Result<RaftDebugInfo, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = RaftDebugRequestBuilder::new(&mut builder);
+        request_builder.add_log_tail(log_tail);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::RaftDebugRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let debug_response = response
+            .response_as_raft_debug_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let entries = debug_response.log_tail();
+        let log_tail = (0..entries.len())
+            .map(|i| {
+                let entry = entries.get(i);
+                RaftLogEntryInfo {
+                    index: entry.index(),
+                    term: entry.term(),
+                    size: entry.size(),
+                    description: entry.description().to_string(),
+                }
+            })
+            .collect();
+        let progress_entries = debug_response.progress();
+        let progress = (0..progress_entries.len())
+            .map(|i| {
+                let progress = progress_entries.get(i);
+                RaftPeerInfo {
+                    node_id: progress.node_id(),
+                    matched: progress.matched(),
+                    next_index: progress.next_index(),
+                    state: progress.state().to_string(),
+                    recent_active: progress.recent_active(),
+                }
+            })
+            .collect();
+
+        return Ok(RaftDebugInfo {
+            node_id: debug_response.node_id(),
+            term: debug_response.term(),
+            role: debug_response.role().to_string(),
+            leader_id: debug_response.leader_id(),
+            commit_index: debug_response.commit_index(),
+            applied_index: debug_response.applied_index(),
+            first_index: debug_response.first_index(),
+            last_index: debug_response.last_index(),
+            log_tail,
+            progress,
+        });
+    }
+
+    // Returns the block cache statistics of the node this client is connected to
+    pub fn block_cache_stats(&self) -> Result<BlockCacheStats, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = BlockCacheStatsRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::BlockCacheStatsRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let stats_response = response
+            .response_as_block_cache_stats_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(BlockCacheStats {
+            capacity_bytes: stats_response.capacity_bytes(),
+            cached_bytes: stats_response.cached_bytes(),
+            hits: stats_response.hits(),
+            misses: stats_response.misses(),
+            rejected: stats_response.rejected(),
+            evictions: stats_response.evictions(),
+        });
+    }
+
+    pub fn node_info(&self) -> Result<NodeInfo, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = NodeInfoRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::NodeInfoRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let info_response = response
+            .response_as_node_info_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let time = info_response.time();
+
+        return Ok(NodeInfo {
+            cluster_name: info_response.cluster_name().to_string(),
+            protocol_version: info_response.protocol_version(),
+            time: SystemTime::UNIX_EPOCH
+                .add(Duration::new(time.seconds() as u64, time.nanos() as u32)),
+        });
+    }
+
+    // Returns the requests which the node this client is connected to is executing, oldest first
+    pub fn inflight_requests(&self) -> Result<Vec<InflightRequestInfo>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = InflightRequestsRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::InflightRequestsRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let entries = response
+            .response_as_inflight_requests_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .requests();
+
+        return Ok((0..entries.len())
+            .map(|i| {
+                let entry = entries.get(i);
+                InflightRequestInfo {
+                    request_type: entry.request_type().to_string(),
+                    inode: entry.inode(),
+                    age: Duration::from_millis(entry.age_ms()),
+                    session_id: entry.session_id(),
+                    client: entry.client().to_string(),
+                    state: entry.state().to_string(),
+                }
+            })
+            .collect());
+    }
+
+    // Returns the statistics of every node in the cluster, sorted by node id
+    pub fn cluster_stats(&self) -> Result<Vec<NodeStats>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = ClusterStatsRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::ClusterStatsRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let nodes = response
+            .response_as_cluster_stats_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .nodes();
+
+        return Ok((0..nodes.len())
+            .map(|i| NodeStats::from_status(&nodes.get(i)))
+            .collect());
+    }
+
+    // Returns the (inode, path, accesses) of the limit hottest files, hottest first. The path is empty if the file
+    // has none
+    pub fn heatmap(&self, limit: u32) -> Result<Vec<(u64, String, FileAccess)>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = HeatmapRequestBuilder::new(&mut builder);
+        request_builder.add_limit(limit);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::HeatmapRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let entries = response
+            .response_as_heatmap_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .files();
+        let mut files = vec![];
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            let access = FileAccess {
+                reads: entry.reads(),
+                writes: entry.writes(),
+                read_bytes: entry.read_bytes(),
+                written_bytes: entry.written_bytes(),
+                last_access: entry.last_access(),
+            };
+            files.push((entry.inode(), entry.path().to_string(), access));
+        }
+
+        return Ok(files);
+    }
+
+    // Pauses or resumes the background tasks of the node this client is connected to
+    pub fn background_control(
+        &self,
+        action: BackgroundAction,
+    ) -> Result<BackgroundStatus, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = BackgroundControlRequestBuilder::new(&mut builder);
+        request_builder.add_action(action);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::BackgroundControlRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let status_response = response
+            .response_as_background_status_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let task_entries = status_response.tasks();
+        let tasks = (0..task_entries.len())
+            .map(|i| {
+                let task = task_entries.get(i);
+                BackgroundTaskInfo {
+                    name: task.name().to_string(),
+                    runs: task.runs(),
+                    deferred: task.deferred(),
+                    bytes: task.bytes(),
+                }
+            })
+            .collect();
+
+        return Ok(BackgroundStatus {
+            paused: status_response.paused(),
+            bytes_per_second: status_response.bytes_per_second(),
+            tasks,
+        });
+    }
+
+    // resume_index is the snapshot which is partially downloaded, or 0
+    pub fn create_snapshot(&self, resume_index: u64) -> Result<SnapshotInfo, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = CreateSnapshotRequestBuilder::new(&mut builder);
+        request_builder.add_resume_index(resume_index);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::CreateSnapshotRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let info_response = response
+            .response_as_snapshot_info_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(SnapshotInfo {
+            index: info_response.index(),
+            term: info_response.term(),
+            size: info_response.size(),
+            checksum: info_response.checksum().to_vec(),
+            checksum_algorithm: info_response.checksum_algorithm(),
+        });
+    }
+
+    // node_id is the ID of the node which the snapshot is being fetched for
+    pub fn read_snapshot(
+        &self,
+        index: u64,
+        offset: u64,
+        size: u32,
+        node_id: u64,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ReadSnapshotRequestBuilder::new(&mut builder);
+        request_builder.add_index(index);
+        request_builder.add_offset(offset);
+        request_builder.add_read_size(size);
+        request_builder.add_node_id(node_id);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::ReadSnapshotRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let read_response = response
+            .response_as_read_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(read_response.data().to_vec());
+    }
+
+    // Used by the nodes, to fetch the data of a staged write which wasn't pushed to them. Sent without a session, since
+    // the request is only accepted from other nodes
+    pub fn fetch_staged_data(&self, staged_id: u64) -> Result<Vec<u8>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = FetchStagedDataRequestBuilder::new(&mut builder);
+        request_builder.add_staged_id(staged_id);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(
+            &mut builder,
+            RequestType::FetchStagedDataRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let read_response = response
+            .response_as_read_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(read_response.data().to_vec());
+    }
+
+    // Returns whether a checksum is running, and its (total_files, hashed_files, hashed_bytes)
+    pub fn checksum_progress(&self) -> Result<(bool, u64, u64, u64), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = FilesystemChecksumProgressRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::FilesystemChecksumProgressRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let progress_response = response
+            .response_as_checksum_progress_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok((
+            progress_response.running(),
+            progress_response.total_files(),
+            progress_response.hashed_files(),
+            progress_response.hashed_bytes(),
+        ));
+    }
+
+    // Returns the problems which were repaired
+    pub fn fsck_repair(&self) -> Result<Vec<String>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = FilesystemRepairRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::FilesystemRepairRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let repair_response = response
+            .response_as_filesystem_repair_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let problems = repair_response.problems();
+
+        Ok((0..problems.len())
+            .map(|i| problems.get(i).to_string())
+            .collect())
+    }
+
+    pub fn filesystem_information(&self) -> Result<FilesystemInformation, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = FilesystemInformationRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::FilesystemInformationRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let information_response = response
+            .response_as_filesystem_information_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(FilesystemInformation {
+            block_size: information_response.block_size(),
+            total_blocks: information_response.total_blocks(),
+            free_blocks: information_response.free_blocks(),
+            available_blocks: information_response.available_blocks(),
+            reserved_blocks: information_response.reserved_blocks(),
+            files: information_response.files(),
+            free_files: information_response.free_files(),
+            limits: information_response
+                .limits()
+                .cloned()
+                .unwrap_or_else(default_limits),
+        });
+    }
+
+    pub fn fsck(&self) -> Result<(), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let request_builder = FilesystemCheckRequestBuilder::new(&mut builder);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::FilesystemCheckRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(())
+    }
+
+    pub fn mkdir(
+        &self,
+        parent: u64,
+        name: &str,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+    ) -> Result<FileAttr, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_name = builder.create_string(name);
+        let mut request_builder = MkdirRequestBuilder::new(&mut builder);
+        request_builder.add_parent(parent);
+        request_builder.add_name(builder_name);
+        request_builder.add_uid(uid);
+        request_builder.add_gid(gid);
+        request_builder.add_mode(mode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::MkdirRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let metadata = response
+            .response_as_file_metadata_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(metadata_to_fuse_fileattr(&metadata));
+    }
+
+    pub fn lookup(&self, parent: u64, name: &str, context: UserContext) -> Result<u64, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_name = builder.create_string(name);
+        let mut request_builder = LookupRequestBuilder::new(&mut builder);
+        request_builder.add_parent(parent);
+        request_builder.add_name(builder_name);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::LookupRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let inode_response = response
+            .response_as_inode_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(inode_response.inode());
+    }
+
+    pub fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        kind: FileKind,
+    ) -> Result<FileAttr, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_name = builder.create_string(name);
+        let mut request_builder = CreateRequestBuilder::new(&mut builder);
+        request_builder.add_parent(parent);
+        request_builder.add_name(builder_name);
+        request_builder.add_uid(uid);
+        request_builder.add_gid(gid);
+        request_builder.add_mode(mode);
+        request_builder.add_kind(kind);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::CreateRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let metadata = response
+            .response_as_file_metadata_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(metadata_to_fuse_fileattr(&metadata));
+    }
+
+    // Creates a file which isn't linked into any directory. Link it with hardlink(), or it's deleted once every
+    // handle of it is released, or the lease of the sessions holding them expires
+    pub fn create_temporary(
+        &self,
+        parent: u64,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+    ) -> Result<FileAttr, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = CreateTemporaryRequestBuilder::new(&mut builder);
+        request_builder.add_parent(parent);
+        request_builder.add_uid(uid);
+        request_builder.add_gid(gid);
+        request_builder.add_mode(mode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::CreateTemporaryRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let metadata = response
+            .response_as_file_metadata_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(metadata_to_fuse_fileattr(&metadata));
+    }
+
+    pub fn getattr(&self, inode: u64) -> Result<FileAttr, ErrorCode> {
+        return Ok(self.getattr_details(inode)?.0);
+    }
+
+    // Returns the attributes of inode, including those which a fuse FileAttr can't represent
+    pub fn getattr_details(&self, inode: u64) -> Result<(FileAttr, FileDetails), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = GetattrRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_relaxed_request(&mut builder, RequestType::GetattrRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send_relaxed(builder.finished_data(), &mut buffer)?;
+        let metadata = response
+            .response_as_file_metadata_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        let details = FileDetails {
+            kind: metadata.kind(),
+            data_version: metadata.data_version(),
+        };
+        return Ok((metadata_to_fuse_fileattr(&metadata), details));
+    }
+
+    // Returns the attributes of each inode, in the same order, or the error reading them. Sent in batches of at
+    // most MAX_BATCH_GETATTR_INODES, instead of one request per inode
+    pub fn getattr_many(
+        &self,
+        inodes: &[u64],
+    ) -> Result<Vec<Result<FileAttr, ErrorCode>>, ErrorCode> {
+        let mut result = vec![];
+        for batch in inodes.chunks(MAX_BATCH_GETATTR_INODES) {
+            let mut builder = self.get_or_create_builder();
+            let batch_inodes = builder.create_vector(batch);
+            let mut request_builder = BatchGetattrRequestBuilder::new(&mut builder);
+            request_builder.add_inodes(batch_inodes);
+            let finish_offset = request_builder.finish().as_union_value();
+            self.finalize_relaxed_request(
+                &mut builder,
+                RequestType::BatchGetattrRequest,
+                finish_offset,
+            );
+
+            let mut buffer = self.get_or_create_buffer();
+            let response = self.send_relaxed(builder.finished_data(), &mut buffer)?;
+            let entries = response
+                .response_as_batch_getattr_response()
+                .ok_or(ErrorCode::BadResponse)?
+                .entries();
+            if entries.len() != batch.len() {
+                return Err(ErrorCode::BadResponse);
+            }
+            for i in 0..entries.len() {
+                let entry = entries.get(i);
+                result.push(match entry.attributes() {
+                    Some(metadata) => Ok(metadata_to_fuse_fileattr(&metadata)),
+                    None => Err(entry.error_code()),
+                });
+            }
+        }
+
+        return Ok(result);
+    }
+
+    pub fn getxattr(
+        &self,
+        inode: u64,
+        key: &str,
+        context: UserContext,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_key = builder.create_string(key);
+        let mut request_builder = GetXattrRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_key(builder_key);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_relaxed_request(&mut builder, RequestType::GetXattrRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send_relaxed(builder.finished_data(), &mut buffer)?;
+        let data = response
+            .response_as_read_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .data();
+
+        return Ok(data.to_vec());
+    }
+
+    pub fn listxattr(&self, inode: u64) -> Result<Vec<String>, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ListXattrsRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_relaxed_request(&mut builder, RequestType::ListXattrsRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send_relaxed(builder.finished_data(), &mut buffer)?;
+        let xattrs_response = response
+            .response_as_xattrs_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let xattrs = xattrs_response.xattrs();
+
+        let mut attrs = vec![];
+        for i in 0..xattrs.len() {
+            let attr = xattrs.get(i);
+            attrs.push(attr.to_string());
+        }
+
+        return Ok(attrs);
+    }
+
+    pub fn setxattr(
+        &self,
+        inode: u64,
+        key: &str,
+        value: &[u8],
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_key = builder.create_string(key);
+        let builder_value = builder.create_vector_direct(value);
+        let mut request_builder = SetXattrRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_key(builder_key);
+        request_builder.add_value(builder_value);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::SetXattrRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(())
+    }
+
+    pub fn removexattr(
+        &self,
+        inode: u64,
+        key: &str,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_key = builder.create_string(key);
+        let mut request_builder = RemoveXattrRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_key(builder_key);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::RemoveXattrRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(())
+    }
+
+    pub fn utimens(
+        &self,
+        inode: u64,
+        atime: Option<Timestamp>,
+        mtime: Option<Timestamp>,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = UtimensRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        if let Some(ref atime) = atime {
+            request_builder.add_atime(atime);
+        }
+        if let Some(ref mtime) = mtime {
+            request_builder.add_mtime(mtime);
+        }
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::UtimensRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
+    }
+
+    pub fn chmod(&self, inode: u64, mode: u32, context: UserContext) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ChmodRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_mode(mode);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ChmodRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
+    }
+
+    pub fn chown(
+        &self,
+        inode: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ChownRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        let uid_struct;
+        let gid_struct;
+        if let Some(uid) = uid {
+            uid_struct = OptionalUInt::new(uid);
+            request_builder.add_uid(&uid_struct);
+        }
+        if let Some(gid) = gid {
+            gid_struct = OptionalUInt::new(gid);
+            request_builder.add_gid(&gid_struct);
+        }
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ChownRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
+    }
+
+    pub fn hardlink(
+        &self,
+        inode: u64,
+        new_parent: u64,
+        new_name: &str,
+        context: UserContext,
+    ) -> Result<FileAttr, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let builder_new_name = builder.create_string(new_name);
+        let mut request_builder = HardlinkRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_new_parent(new_parent);
+        request_builder.add_new_name(builder_new_name);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::HardlinkRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let metadata = response
+            .response_as_file_metadata_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(metadata_to_fuse_fileattr(&metadata));
+    }
+
+    pub fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_name = builder.create_string(name);
+        let builder_new_name = builder.create_string(new_name);
+        let mut request_builder = RenameRequestBuilder::new(&mut builder);
+        request_builder.add_parent(parent);
+        request_builder.add_name(builder_name);
+        request_builder.add_new_parent(new_parent);
+        request_builder.add_new_name(builder_new_name);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::RenameRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
+    }
+
+    pub fn readlink(&self, inode: u64, context: UserContext) -> Result<Vec<u8>, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ReadRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(0);
+        request_builder.add_read_size(BLOCK_SIZE as u32);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send_receive_raw(builder.finished_data(), &mut buffer)?;
+        decode_fast_read_response_inplace(response).map(Clone::clone)
+    }
+
+    pub fn read<F: FnOnce(Result<&[u8], ErrorCode>) -> ()>(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: u32,
+        context: UserContext,
+        atime_mode: AtimeMode,
+        callback: F,
+    ) {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ReadRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(offset);
+        request_builder.add_read_size(size);
+        request_builder.add_context(&context);
+        request_builder.add_atime_mode(atime_mode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        match self.send_receive_raw(builder.finished_data(), &mut buffer) {
+            Ok(response) => match decode_fast_read_response_inplace(response) {
+                Ok(data) => {
+                    callback(Ok(data));
+                    return;
+                }
+                Err(e) => {
+                    callback(Err(e));
+                    return;
+                }
+            },
+            Err(e) => {
+                callback(Err(e));
+                return;
+            }
+        };
+    }
+
+    pub fn read_to_vec(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: u32,
+        context: UserContext,
+        atime_mode: AtimeMode,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let chunk_size = self.max_chunk_size() as u32;
+        if size <= chunk_size {
+            return self.read_chunk(inode, offset, size, context, atime_mode);
+        }
+        // Each chunk is read straight into the result. One more byte is reserved for the error code of the last one
+        let mut data = Vec::with_capacity(size as usize + 1);
+        while (data.len() as u32) < size {
+            let remaining = size - data.len() as u32;
+            let chunk_start = data.len();
+            self.read_chunk_into(
+                inode,
+                offset + chunk_start as u64,
+                min(remaining, chunk_size),
+                context,
+                atime_mode,
+                &mut data,
+            )?;
+            let end_of_file = ((data.len() - chunk_start) as u32) < min(remaining, chunk_size);
+            if end_of_file {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn read_chunk(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: u32,
+        context: UserContext,
+        atime_mode: AtimeMode,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let mut buffer = Vec::with_capacity((size + 1) as usize);
+        self.read_chunk_into(inode, offset, size, context, atime_mode, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    // Appends the data read to buffer
+    fn read_chunk_into(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: u32,
+        context: UserContext,
+        atime_mode: AtimeMode,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ReadRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(offset);
+        request_builder.add_read_size(size);
+        request_builder.add_context(&context);
+        request_builder.add_atime_mode(atime_mode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+
+        let start = buffer.len();
+        self.send_with_resend_appended(builder.finished_data(), buffer)?;
+        // The error code is the last byte of the response
+        if let Err(error_code) = decode_fast_read_response_inplace(buffer) {
+            buffer.truncate(start);
+            return Err(error_code);
+        }
+
+        Ok(())
+    }
+
+    pub fn readdir(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> Result<Vec<DirectoryEntryTuple>, ErrorCode> {
+        match self.readdir_since(inode, None, context)? {
+            (_, DirectoryListing::Full(entries)) => Ok(entries),
+            _ => Err(ErrorCode::BadResponse),
+        }
+    }
+
+    // Returns the version of the directory, and its listing. If since_version is set, the listing may only
+    // contain the changes since that version
+    pub fn readdir_since(
+        &self,
+        inode: u64,
+        since_version: Option<u64>,
+        context: UserContext,
+    ) -> Result<(u64, DirectoryListing), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = ReaddirRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        if let Some(version) = since_version {
+            request_builder.add_since_version(&OptionalULong::new(version));
+        }
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ReaddirRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+
+        let mut result = vec![];
+        let listing_response = response
+            .response_as_directory_listing_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let entries = listing_response.entries();
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            result.push((
+                entry.inode(),
+                OsString::from(entry.name()),
+                to_fuse_file_type(entry.kind()),
+            ));
+        }
+        if !listing_response.delta() {
+            return Ok((listing_response.version(), DirectoryListing::Full(result)));
+        }
+
+        let mut removed = vec![];
+        if let Some(names) = listing_response.removed() {
+            for i in 0..names.len() {
+                removed.push(OsString::from(names.get(i)));
+            }
+        }
+
+        return Ok((
+            listing_response.version(),
+            DirectoryListing::Delta(result, removed),
+        ));
+    }
+
+    // Resolves a path, relative to the root of the filesystem, to an inode
+    pub fn lookup_path(&self, path: &str, context: UserContext) -> Result<u64, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_path = builder.create_string(path);
+        let mut request_builder = ResolvePathRequestBuilder::new(&mut builder);
+        request_builder.add_path(builder_path);
+        request_builder.add_follow_symlinks(true);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::ResolvePathRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let inode_response = response
+            .response_as_inode_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(inode_response.inode());
+    }
+
+    // Writes data to a new file at path. The data is written to a temporary file, which is only linked at path
+    // once all of it was written, so that readers never see a partially written file
+    pub fn upload(&self, data: &[u8], path: &str, context: UserContext) -> Result<(), ErrorCode> {
+        let (parent_path, name) = match path.trim_end_matches('/').rfind('/') {
+            Some(index) => (&path[..index], &path[(index + 1)..]),
+            None => ("", path),
+        };
+        let parent = self.lookup_path(parent_path, context)?;
+
+        return self.upload_into(data, parent, name, context);
+    }
+
+    // Like upload(), but copies data to the entry name in the directory parent
+    pub fn upload_into(
+        &self,
+        data: &[u8],
+        parent: u64,
+        name: &str,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let attributes = self.create_temporary(parent, context.uid(), context.gid(), 0o644)?;
+        let mut offset = 0;
+        for chunk in data.chunks(UPLOAD_CHUNK_SIZE) {
+            if let Err(error_code) = self.write(attributes.ino, chunk, offset, context) {
+                self.release(attributes.ino)?;
+                return Err(error_code);
+            }
+            offset += chunk.len() as u64;
+        }
+        let linked = self.hardlink(attributes.ino, parent, name, context);
+        self.release(attributes.ino)?;
+
+        return linked.map(|_| ());
+    }
+
+    // Creates an empty shared log at path
+    pub fn create_log(&self, path: &str, context: UserContext) -> Result<FileAttr, ErrorCode> {
+        let (parent_path, name) = match path.trim_end_matches('/').rfind('/') {
+            Some(index) => (&path[..index], &path[(index + 1)..]),
+            None => ("", path),
+        };
+        let parent = self.lookup_path(parent_path, context)?;
+
+        return self.create(
+            parent,
+            name,
+            context.uid(),
+            context.gid(),
+            0o644,
+            FileKind::SharedLog,
+        );
+    }
+
+    pub fn verify_file(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> Result<FileVerification, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = VerifyFileRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::VerifyFileRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let verify_response = response
+            .response_as_verify_file_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let entries = verify_response.checksums();
+        let checksums = (0..entries.len())
+            .map(|i| (entries.get(i).node_id(), entries.get(i).checksum().to_vec()))
+            .collect();
+
+        return Ok(FileVerification {
+            mirrored: verify_response.mirrored(),
+            checksums,
+        });
+    }
+
+    // Loads inode, or the files below it, into the block caches of the nodes. Returns the number of files and bytes
+    // loaded
+    pub fn prefetch(&self, inode: u64, context: UserContext) -> Result<(u64, u64), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = PrefetchRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::PrefetchRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let prefetch_response = response
+            .response_as_prefetch_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok((prefetch_response.files(), prefetch_response.bytes()));
+    }
+
+    // Returns one page of (inode, path) results, and whether there are more results after it
+    // Returns a page of the paths which match pattern, with their attributes, and whether there are more after it
+    pub fn glob(
+        &self,
+        pattern: &str,
+        start_after: Option<&str>,
+        context: UserContext,
+    ) -> Result<(Vec<(String, FileAttr)>, bool), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let pattern = builder.create_string(pattern);
+        let start_after = start_after.map(|x| builder.create_string(x));
+        let mut request_builder = GlobRequestBuilder::new(&mut builder);
+        request_builder.add_pattern(pattern);
+        if let Some(offset) = start_after {
+            request_builder.add_start_after(offset);
+        }
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::GlobRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let find_response = response
+            .response_as_find_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        let mut result = vec![];
+        let entries = find_response.entries();
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            let attributes = entry.attributes().ok_or(ErrorCode::BadResponse)?;
+            result.push((
+                entry.path().to_string(),
+                metadata_to_fuse_fileattr(&attributes),
+            ));
+        }
+
+        return Ok((result, find_response.truncated()));
+    }
+
+    // Writes a tar archive of the entries below inode to output. It's assembled by the server, a page at a time
+    pub fn export_archive<W: Write>(
+        &self,
+        inode: u64,
+        context: UserContext,
+        output: &mut W,
+    ) -> Result<(), ErrorCode> {
+        let mut resume_path: Option<String> = None;
+        let mut resume_offset = 0;
+        loop {
+            let mut builder = self.get_or_create_builder();
+            let path = resume_path.as_ref().map(|x| builder.create_string(x));
+            let mut request_builder = ExportArchiveRequestBuilder::new(&mut builder);
+            request_builder.add_inode(inode);
+            if let Some(path) = path {
+                request_builder.add_resume_path(path);
+            }
+            request_builder.add_resume_offset(resume_offset);
+            request_builder.add_max_bytes(self.max_chunk_size() as u32);
+            request_builder.add_context(&context);
+            let finish_offset = request_builder.finish().as_union_value();
+            self.finalize_request(
+                &mut builder,
+                RequestType::ExportArchiveRequest,
+                finish_offset,
+            );
+
+            let mut buffer = self.get_or_create_buffer();
+            let response = self.send(builder.finished_data(), &mut buffer)?;
+            let export_response = response
+                .response_as_export_archive_response()
+                .ok_or(ErrorCode::BadResponse)?;
+            output
+                .write_all(export_response.data())
+                .map_err(into_error_code)?;
+            if export_response.done() {
+                return Ok(());
+            }
+            resume_path = export_response.resume_path().map(ToString::to_string);
+            resume_offset = export_response.resume_offset();
+        }
+    }
+
+    pub fn find(
+        &self,
+        inode: u64,
+        query: &FindQuery,
+        start_after: Option<&str>,
+        context: UserContext,
+    ) -> Result<(Vec<(u64, String)>, bool), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let name_glob = query.name_glob.as_ref().map(|x| builder.create_string(x));
+        let xattr_key = query.xattr_key.as_ref().map(|x| builder.create_string(x));
+        let xattr_value = query
+            .xattr_value
+            .as_ref()
+            .map(|x| builder.create_vector_direct(x));
+        let start_after = start_after.map(|x| builder.create_string(x));
+        let mut request_builder = FindRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        if let Some(offset) = name_glob {
+            request_builder.add_name_glob(offset);
+        }
+        if let Some(min_size) = query.min_size {
+            request_builder.add_min_size(&OptionalULong::new(min_size));
+        }
+        if let Some(max_size) = query.max_size {
+            request_builder.add_max_size(&OptionalULong::new(max_size));
+        }
+        if let Some(ref modified_after) = query.modified_after {
+            request_builder.add_modified_after(modified_after);
+        }
+        if let Some(ref modified_before) = query.modified_before {
+            request_builder.add_modified_before(modified_before);
+        }
+        if let Some(offset) = xattr_key {
+            request_builder.add_xattr_key(offset);
+        }
+        if let Some(offset) = xattr_value {
+            request_builder.add_xattr_value(offset);
+        }
+        if let Some(offset) = start_after {
+            request_builder.add_start_after(offset);
+        }
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::FindRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let find_response = response
+            .response_as_find_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        let mut result = vec![];
+        let entries = find_response.entries();
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            result.push((entry.inode(), entry.path().to_string()));
+        }
+
+        return Ok((result, find_response.truncated()));
+    }
+
+    // Returns (inode, path) of files whose contents may contain query, and whether there were more results
+    pub fn search(
+        &self,
+        query: &str,
+        context: UserContext,
+    ) -> Result<(Vec<(u64, String)>, bool), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let builder_query = builder.create_string(query);
+        let mut request_builder = SearchRequestBuilder::new(&mut builder);
+        request_builder.add_query(builder_query);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::SearchRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let find_response = response
+            .response_as_find_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        let mut result = vec![];
+        let entries = find_response.entries();
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            result.push((entry.inode(), entry.path().to_string()));
+        }
+
+        return Ok((result, find_response.truncated()));
+    }
+
+    // Returns the recursive (bytes, inodes) used below the given inode
+    pub fn get_tree_usage(&self, inode: u64) -> Result<(u64, u64), ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = GetTreeUsageRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::GetTreeUsageRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let usage_response = response
+            .response_as_tree_usage_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok((usage_response.bytes(), usage_response.inodes()));
+    }
+
+    pub fn truncate(&self, inode: u64, length: u64, context: UserContext) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut builder = self.get_or_create_builder();
+        let mut request_builder = TruncateRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_new_length(length);
+        request_builder.add_context(&context);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(&mut builder, RequestType::TruncateRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
+    }
+
+    pub fn write(
+        &self,
+        inode: u64,
+        data: &[u8],
+        offset: u64,
+        context: UserContext,
+    ) -> Result<u32, ErrorCode> {
+        let chunk_size = self.max_chunk_size();
+        if data.len() <= chunk_size {
+            return self.write_chunk(inode, data, offset, context);
+        }
+        let mut written = 0;
+        for chunk in data.chunks(chunk_size) {
+            let chunk_written =
+                self.write_chunk(inode, chunk, offset + u64::from(written), context)?;
+            written += chunk_written;
+            if chunk_written < chunk.len() as u32 {
+                break;
+            }
+        }
+
+        return Ok(written);
+    }
+
+    fn write_chunk(
+        &self,
+        inode: u64,
+        data: &[u8],
+        offset: u64,
+        context: UserContext,
+    ) -> Result<u32, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let data_offset = builder.create_vector_direct(data);
+        return self.finish_write(&mut builder, data_offset, inode, offset, context);
+    }
+
+    // Writes data which was already copied into its request. It must fit in one request
+    pub fn write_encoded(
+        &self,
+        inode: u64,
+        mut encoded: EncodedWrite,
+        offset: u64,
+        context: UserContext,
+    ) -> Result<u32, ErrorCode> {
+        if encoded.length > self.max_chunk_size() {
+            return self.write(inode, encoded.data(), offset, context);
+        }
+        let data_offset = encoded.data_offset;
+        return self.finish_write(&mut encoded.builder, data_offset, inode, offset, context);
+    }
+
+    fn finish_write(
+        &self,
+        builder: &mut FlatBufferBuilder<'static>,
+        data_offset: WIPOffset<Vector<'static, u8>>,
+        inode: u64,
+        offset: u64,
+        context: UserContext,
+    ) -> Result<u32, ErrorCode> {
+        let fencing_token = self
+            .fencing_tokens
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .cloned()
+            .unwrap_or(0);
+        let mut request_builder = WriteRequestBuilder::new(builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(offset);
+        request_builder.add_data(data_offset);
+        request_builder.add_context(&context);
+        request_builder.add_fencing_token(fencing_token);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(builder, RequestType::WriteRequest, finish_offset);
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        return Ok(response
+            .response_as_written_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .bytes_written());
+    }
+
+    // Fails with VersionMismatch if the data version of the file is no longer expected_version
+    pub fn write_conditional(
+        &self,
+        inode: u64,
+        data: &[u8],
+        offset: u64,
+        expected_version: u64,
+        context: UserContext,
+    ) -> Result<u32, ErrorCode> {
+        let mut builder = self.get_or_create_builder();
+        let data_offset = builder.create_vector_direct(data);
+        let mut request_builder = WriteConditionalRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(offset);
+        request_builder.add_data(data_offset);
+        request_builder.add_context(&context);
+        request_builder.add_expected_version(expected_version);
+        let finish_offset = request_builder.finish().as_union_value();
+        self.finalize_request(
+            &mut builder,
+            RequestType::WriteConditionalRequest,
+            finish_offset,
+        );
+
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        return Ok(response
+            .response_as_written_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .bytes_written());
+    }
+
+    // Returns the offset the data was written at
+    pub fn append(&self, inode: u64, data: &[u8], context: UserContext) -> Result<u64, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_key = builder.create_string(key);
-        let mut request_builder = RemoveXattrRequestBuilder::new(&mut builder);
+        let data_offset = builder.create_vector_direct(data);
+        let mut request_builder = AppendRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_key(builder_key);
+        request_builder.add_data(data_offset);
+        request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::RemoveXattrRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::AppendRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        response
-            .response_as_empty_response()
-            .ok_or(ErrorCode::BadResponse)?;
+        return Ok(response
+            .response_as_written_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .offset());
+    }
 
-        Ok(())
+    // Returns the complete records of the shared log at offset, and the offset after them
+    pub fn read_log(
+        &self,
+        inode: u64,
+        offset: u64,
+        max_bytes: u32,
+        context: UserContext,
+    ) -> Result<(Vec<(u64, Vec<u8>)>, u64), ErrorCode> {
+        let data = self.read_to_vec(inode, offset, max_bytes, context, AtimeMode::VolumeDefault)?;
+        let (records, next_offset) = parse_log_records(&data, offset);
+        if records.is_empty() && data.len() >= LOG_RECORD_HEADER_SIZE {
+            // The next record is larger than max_bytes, so read all of it
+            let length = LOG_RECORD_HEADER_SIZE + LittleEndian::read_u32(&data) as usize;
+            if length > data.len() && length > max_bytes as usize {
+                let data = self.read_to_vec(
+                    inode,
+                    offset,
+                    length as u32,
+                    context,
+                    AtimeMode::VolumeDefault,
+                )?;
+                return Ok(parse_log_records(&data, offset));
+            }
+        }
+
+        return Ok((records, next_offset));
     }
 
-    pub fn utimens(
+    // Calls callback with each record of the shared log from offset on, and then with each record appended to
+    // it, until callback returns false. The log is read from the start again, if it's truncated
+    pub fn follow_log<F: FnMut(u64, &[u8]) -> bool>(
         &self,
         inode: u64,
-        atime: Option<Timestamp>,
-        mtime: Option<Timestamp>,
+        mut offset: u64,
         context: UserContext,
+        poll_interval: Duration,
+        mut callback: F,
     ) -> Result<(), ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
+        loop {
+            let (records, next_offset) = self.read_log(inode, offset, LOG_READ_SIZE, context)?;
+            for (record_offset, data) in records.iter() {
+                if !callback(*record_offset, data) {
+                    return Ok(());
+                }
+            }
+            if next_offset == offset {
+                if self.getattr(inode)?.size < offset {
+                    offset = 0;
+                    continue;
+                }
+                sleep(poll_interval);
+            }
+            offset = next_offset;
+        }
+    }
 
+    pub fn fsync(&self, inode: u64) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = UtimensRequestBuilder::new(&mut builder);
+        let mut request_builder = FsyncRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        if let Some(ref atime) = atime {
-            request_builder.add_atime(atime);
-        }
-        if let Some(ref mtime) = mtime {
-            request_builder.add_mtime(mtime);
-        }
-        request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::UtimensRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::FsyncRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -344,276 +2433,248 @@ impl NodeClient {
         return Ok(());
     }
 
-    pub fn chmod(&self, inode: u64, mode: u32, context: UserContext) -> Result<(), ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
-
+    // Acquires, converts, or releases a byte range lock for the owner, and returns its fencing token. If wait is
+    // set, the lock is registered as waited for, and the request should be retried while it fails with LockConflict
+    #[allow(clippy::too_many_arguments)]
+    pub fn lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+        wait: bool,
+    ) -> Result<u64, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ChmodRequestBuilder::new(&mut builder);
+        let mut request_builder = LockRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_mode(mode);
-        request_builder.add_context(&context);
+        request_builder.add_owner(owner);
+        request_builder.add_pid(pid);
+        request_builder.add_start(start);
+        request_builder.add_end(end);
+        request_builder.add_kind(kind);
+        request_builder.add_wait(wait);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ChmodRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::LockRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        response
-            .response_as_empty_response()
+        let granted = response
+            .response_as_lock_granted_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(());
+        return Ok(granted.fencing_token());
     }
 
-    pub fn chown(
-        &self,
-        inode: u64,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        context: UserContext,
-    ) -> Result<(), ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
+    // Writes to the inode are sent with the token, so that they're rejected once the lock it was issued for is
+    // broken. 0 stops sending one
+    pub fn set_fencing_token(&self, inode: u64, fencing_token: u64) {
+        let mut tokens = self.fencing_tokens.lock().unwrap();
+        if fencing_token == 0 {
+            tokens.remove(&inode);
+        } else {
+            tokens.insert(inode, fencing_token);
+        }
+    }
 
+    // Returns once every write acknowledged before the call is flushed to disk on a quorum of the nodes, with the
+    // index of the Raft log they're flushed up to
+    pub fn flush_epoch(&self) -> Result<u64, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ChownRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
-        let uid_struct;
-        let gid_struct;
-        if let Some(uid) = uid {
-            uid_struct = OptionalUInt::new(uid);
-            request_builder.add_uid(&uid_struct);
-        }
-        if let Some(gid) = gid {
-            gid_struct = OptionalUInt::new(gid);
-            request_builder.add_gid(&gid_struct);
-        }
-        request_builder.add_context(&context);
+        let request_builder = FlushEpochRequestBuilder::new(&mut builder);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ChownRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::FlushEpochRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        response
-            .response_as_empty_response()
+        let flushed = response
+            .response_as_flush_epoch_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(());
+        return Ok(flushed.epoch());
     }
 
-    pub fn hardlink(
+    // Reports the mount to the server, which assigns it a client ID the first time. Returns the limit on open
+    // handles, which is 0 if there's none
+    pub fn register_client(
         &self,
-        inode: u64,
-        new_parent: u64,
-        new_name: &str,
-        context: UserContext,
-    ) -> Result<FileAttr, ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
-
+        hostname: &str,
+        mount_point: &str,
+        open_handles: u32,
+    ) -> Result<u32, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_new_name = builder.create_string(new_name);
-        let mut request_builder = HardlinkRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
-        request_builder.add_new_parent(new_parent);
-        request_builder.add_new_name(builder_new_name);
-        request_builder.add_context(&context);
+        let hostname_offset = builder.create_string(hostname);
+        let mount_point_offset = builder.create_string(mount_point);
+        let mut request_builder = RegisterClientRequestBuilder::new(&mut builder);
+        request_builder.add_client_id(self.client_id());
+        request_builder.add_hostname(hostname_offset);
+        request_builder.add_mount_point(mount_point_offset);
+        request_builder.add_open_handles(open_handles);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::HardlinkRequest, finish_offset);
+        self.finalize_request(
+            &mut builder,
+            RequestType::RegisterClientRequest,
+            finish_offset,
+        );
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        let metadata = response
-            .response_as_file_metadata_response()
+        let registered = response
+            .response_as_client_registered_response()
             .ok_or(ErrorCode::BadResponse)?;
+        self.client_id
+            .store(registered.client_id(), Ordering::SeqCst);
 
-        return Ok(metadata_to_fuse_fileattr(&metadata));
+        return Ok(registered.max_open_handles());
     }
 
-    pub fn rename(
-        &self,
-        parent: u64,
-        name: &str,
-        new_parent: u64,
-        new_name: &str,
-        context: UserContext,
-    ) -> Result<(), ErrorCode> {
+    // Returns the client sessions connected to each node of the cluster
+    pub fn list_clients(&self) -> Result<Vec<ClientStatus>, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let builder_name = builder.create_string(name);
-        let builder_new_name = builder.create_string(new_name);
-        let mut request_builder = RenameRequestBuilder::new(&mut builder);
-        request_builder.add_parent(parent);
-        request_builder.add_name(builder_name);
-        request_builder.add_new_parent(new_parent);
-        request_builder.add_new_name(builder_new_name);
-        request_builder.add_context(&context);
+        let request_builder = ListClientsRequestBuilder::new(&mut builder);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::RenameRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::ListClientsRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        response
-            .response_as_empty_response()
-            .ok_or(ErrorCode::BadResponse)?;
+        let entries = response
+            .response_as_clients_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .clients();
 
-        return Ok(());
+        return Ok((0..entries.len())
+            .map(|i| ClientStatus::from_entry(&entries.get(i)))
+            .collect());
     }
 
-    pub fn readlink(&self, inode: u64, context: UserContext) -> Result<Vec<u8>, ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
-
+    // Evicts a client session from the cluster, and returns its entries on the nodes it was connected to
+    pub fn evict_client(&self, session_id: u64) -> Result<Vec<ClientStatus>, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ReadRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
-        request_builder.add_offset(0);
-        request_builder.add_read_size(BLOCK_SIZE as u32);
-        request_builder.add_context(&context);
+        let mut request_builder = EvictClientRequestBuilder::new(&mut builder);
+        request_builder.add_session_id(session_id);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::EvictClientRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
-        let response = self.send_receive_raw(builder.finished_data(), &mut buffer)?;
-        decode_fast_read_response_inplace(response).map(Clone::clone)
-    }
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let entries = response
+            .response_as_clients_response()
+            .ok_or(ErrorCode::BadResponse)?
+            .clients();
 
-    pub fn read<F: FnOnce(Result<&[u8], ErrorCode>) -> ()>(
-        &self,
-        inode: u64,
-        offset: u64,
-        size: u32,
-        context: UserContext,
-        callback: F,
-    ) {
-        assert_ne!(inode, ROOT_INODE);
+        return Ok((0..entries.len())
+            .map(|i| ClientStatus::from_entry(&entries.get(i)))
+            .collect());
+    }
 
+    // Renews the lease of this session. Its locks are released, and its temporary files deleted, if it makes no
+    // writes and isn't renewed for 30 seconds
+    pub fn renew_locks(&self) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ReadRequestBuilder::new(&mut builder);
-        request_builder.add_inode(inode);
-        request_builder.add_offset(offset);
-        request_builder.add_read_size(size);
-        request_builder.add_context(&context);
+        let request_builder = RenewLocksRequestBuilder::new(&mut builder);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::RenewLocksRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
-        match self.send_receive_raw(builder.finished_data(), &mut buffer) {
-            Ok(response) => match decode_fast_read_response_inplace(response) {
-                Ok(data) => {
-                    callback(Ok(data));
-                    return;
-                }
-                Err(e) => {
-                    callback(Err(e));
-                    return;
-                }
-            },
-            Err(e) => {
-                callback(Err(e));
-                return;
-            }
-        };
-    }
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
 
-    pub fn read_to_vec(
-        &self,
-        inode: u64,
-        offset: u64,
-        size: u32,
-        context: UserContext,
-    ) -> Result<Vec<u8>, ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
+        return Ok(());
+    }
 
+    // Forcibly releases the locks of the inode and/or session, where 0 matches any, and returns them
+    pub fn break_locks(&self, inode: u64, session_id: u64) -> Result<Vec<LockEntry>, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ReadRequestBuilder::new(&mut builder);
+        let mut request_builder = BreakLocksRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_offset(offset);
-        request_builder.add_read_size(size);
-        request_builder.add_context(&context);
+        request_builder.add_session_id(session_id);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::BreakLocksRequest, finish_offset);
 
-        let mut buffer = Vec::with_capacity((size + 1) as usize);
-        self.send_receive_raw(builder.finished_data(), &mut buffer)?;
-        decode_fast_read_response_inplace(&mut buffer)?;
+        let mut buffer = self.get_or_create_buffer();
+        let response = self.send(builder.finished_data(), &mut buffer)?;
+        let locks = response
+            .response_as_locks_response()
+            .ok_or(ErrorCode::BadResponse)?;
 
-        Ok(buffer)
+        return Ok(decode_locks(locks));
     }
 
-    pub fn readdir(&self, inode: u64) -> Result<Vec<(u64, OsString, fuse::FileType)>, ErrorCode> {
+    // Returns a lock which conflicts with the given one, if there is any
+    pub fn test_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+    ) -> Result<Option<ByteRangeLock>, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = ReaddirRequestBuilder::new(&mut builder);
+        let mut request_builder = TestLockRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
+        request_builder.add_owner(owner);
+        request_builder.add_start(start);
+        request_builder.add_end(end);
+        request_builder.add_kind(kind);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::ReaddirRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::TestLockRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-
-        let mut result = vec![];
-        let listing_response = response
-            .response_as_directory_listing_response()
+        let locks = response
+            .response_as_locks_response()
             .ok_or(ErrorCode::BadResponse)?;
-        let entries = listing_response.entries();
-        for i in 0..entries.len() {
-            let entry = entries.get(i);
-            result.push((
-                entry.inode(),
-                OsString::from(entry.name()),
-                to_fuse_file_type(entry.kind()),
-            ));
-        }
 
-        return Ok(result);
+        return Ok(decode_locks(locks).pop().map(|x| x.lock));
     }
 
-    pub fn truncate(&self, inode: u64, length: u64, context: UserContext) -> Result<(), ErrorCode> {
-        assert_ne!(inode, ROOT_INODE);
-
+    // Returns the held and waited for locks of the inode, or of every inode if it's 0, sorted by inode
+    pub fn lock_status(&self, inode: u64) -> Result<Vec<LockEntry>, ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = TruncateRequestBuilder::new(&mut builder);
+        let mut request_builder = LockStatusRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_new_length(length);
-        request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::TruncateRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::LockStatusRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        response
-            .response_as_empty_response()
+        let locks = response
+            .response_as_locks_response()
             .ok_or(ErrorCode::BadResponse)?;
 
-        return Ok(());
+        return Ok(decode_locks(locks));
     }
 
-    pub fn write(
-        &self,
-        inode: u64,
-        data: &[u8],
-        offset: u64,
-        context: UserContext,
-    ) -> Result<u32, ErrorCode> {
+    // Registers another handle of this session on a temporary file, which must be released with release()
+    pub fn open_temporary(&self, inode: u64) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let data_offset = builder.create_vector_direct(data);
-        let mut request_builder = WriteRequestBuilder::new(&mut builder);
+        let mut request_builder = OpenTemporaryRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
-        request_builder.add_offset(offset);
-        request_builder.add_data(data_offset);
-        request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::WriteRequest, finish_offset);
+        self.finalize_request(
+            &mut builder,
+            RequestType::OpenTemporaryRequest,
+            finish_offset,
+        );
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
-        return Ok(response
-            .response_as_written_response()
-            .ok_or(ErrorCode::BadResponse)?
-            .bytes_written());
+        response
+            .response_as_empty_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        return Ok(());
     }
 
-    pub fn fsync(&self, inode: u64) -> Result<(), ErrorCode> {
+    pub fn release(&self, inode: u64) -> Result<(), ErrorCode> {
         let mut builder = self.get_or_create_builder();
-        let mut request_builder = FsyncRequestBuilder::new(&mut builder);
+        let mut request_builder = ReleaseRequestBuilder::new(&mut builder);
         request_builder.add_inode(inode);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::FsyncRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::ReleaseRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -632,7 +2693,7 @@ impl NodeClient {
         request_builder.add_name(builder_name);
         request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::UnlinkRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::UnlinkRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -651,7 +2712,7 @@ impl NodeClient {
         request_builder.add_name(builder_name);
         request_builder.add_context(&context);
         let finish_offset = request_builder.finish().as_union_value();
-        finalize_request(&mut builder, RequestType::RmdirRequest, finish_offset);
+        self.finalize_request(&mut builder, RequestType::RmdirRequest, finish_offset);
 
         let mut buffer = self.get_or_create_buffer();
         let response = self.send(builder.finished_data(), &mut buffer)?;
@@ -662,3 +2723,77 @@ impl NodeClient {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::client::NodeClient;
+    use crate::generated::*;
+    use crate::utils::{empty_response, finalize_response};
+    use flatbuffers::FlatBufferBuilder;
+
+    fn response_in_term(term: u64) -> Vec<u8> {
+        let (mut builder, response_type, offset) =
+            empty_response(FlatBufferBuilder::new()).unwrap();
+        finalize_response(&mut builder, response_type, offset, term);
+        // Skip the size prefix, like the client does when it receives a response
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn request_term(client: &NodeClient) -> u64 {
+        let mut builder = FlatBufferBuilder::new();
+        let finish_offset = PingRequestBuilder::new(&mut builder)
+            .finish()
+            .as_union_value();
+        client.finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
+        return get_root_as_generic_request(&builder.finished_data()[4..]).term();
+    }
+
+    #[test]
+    fn leader_change_between_propose_and_commit() {
+        let client = NodeClient::new("127.0.0.1:1".parse().unwrap());
+        assert_eq!(request_term(&client), 0);
+
+        // A read answered by the new leader, elected in term 5
+        let mut response = response_in_term(5);
+        assert!(client.check_response(&mut response).is_ok());
+        assert_eq!(request_term(&client), 5);
+
+        // A write which was proposed in term 4, and committed after the election, was still applied
+        let mut response = response_in_term(4);
+        assert!(client.check_response(&mut response).is_ok());
+        // Later requests stay fenced with the newest term, so that the old leader rejects them
+        assert_eq!(request_term(&client), 5);
+    }
+
+    #[test]
+    fn acknowledged() {
+        let client = NodeClient::new("127.0.0.1:1".parse().unwrap());
+        let mut builder = FlatBufferBuilder::new();
+        let finish_offset = PingRequestBuilder::new(&mut builder)
+            .finish()
+            .as_union_value();
+        client.finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
+        let first = builder.finished_data().to_vec();
+
+        // The first request hasn't been answered yet
+        let mut builder = FlatBufferBuilder::new();
+        let finish_offset = PingRequestBuilder::new(&mut builder)
+            .finish()
+            .as_union_value();
+        client.finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
+        let second = get_root_as_generic_request(&builder.finished_data()[4..]);
+        assert_eq!(second.sequence_number(), 2);
+        assert_eq!(second.acknowledged(), 1);
+
+        // Nothing listens on port 1, so it's given up on, and acknowledged by the next request
+        let mut buffer = vec![];
+        assert!(client.send_with_resend(&first, &mut buffer).is_err());
+        let mut builder = FlatBufferBuilder::new();
+        let finish_offset = PingRequestBuilder::new(&mut builder)
+            .finish()
+            .as_union_value();
+        client.finalize_request(&mut builder, RequestType::PingRequest, finish_offset);
+        let third = get_root_as_generic_request(&builder.finished_data()[4..]);
+        assert_eq!(third.acknowledged(), 2);
+    }
+}
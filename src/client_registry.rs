@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use flatbuffers::{FlatBufferBuilder, WIPOffset};
+use futures::sync::oneshot;
+
+use crate::generated::*;
+
+// Limits of each client session on a node. Zero is unlimited
+#[derive(Clone, Copy, Default)]
+pub struct ClientLimits {
+    pub max_connections: u32,
+    // Enforced by the mount, which learns it when it registers, since nodes aren't told when files are opened
+    pub max_open_handles: u32,
+}
+
+struct Client {
+    client_id: u64,
+    hostname: String,
+    mount_point: String,
+    open_handles: u32,
+    address: String,
+    connections: u32,
+    requests: u64,
+    connected: Instant,
+    last_request: Instant,
+    // Of the connections which only carry requests of this session
+    closers: Vec<Arc<Closer>>,
+}
+
+// Closes a connection, when it's evicted
+struct Closer {
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    // Connections from other nodes carry the requests of many sessions, which aren't closed
+    shared: Mutex<bool>,
+}
+
+impl Closer {
+    fn close(&self) {
+        if *self.shared.lock().unwrap() {
+            return;
+        }
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            sender.send(()).ok();
+        }
+    }
+}
+
+// A client session connected to a node, as listed by ListClientsRequest
+#[derive(Clone, Default)]
+pub struct ClientStatus {
+    // 0 if the session didn't register
+    pub client_id: u64,
+    pub session_id: u64,
+    pub node_id: u64,
+    pub hostname: String,
+    pub mount_point: String,
+    pub address: String,
+    pub connections: u32,
+    pub open_handles: u32,
+    pub requests: u64,
+    pub connected_seconds: u64,
+    pub idle_seconds: u64,
+}
+
+impl ClientStatus {
+    pub fn from_entry(entry: &ClientEntry) -> ClientStatus {
+        ClientStatus {
+            client_id: entry.client_id(),
+            session_id: entry.session_id(),
+            node_id: entry.node_id(),
+            hostname: entry.hostname().unwrap_or_default().to_string(),
+            mount_point: entry.mount_point().unwrap_or_default().to_string(),
+            address: entry.address().unwrap_or_default().to_string(),
+            connections: entry.connections(),
+            open_handles: entry.open_handles(),
+            requests: entry.requests(),
+            connected_seconds: entry.connected_seconds(),
+            idle_seconds: entry.idle_seconds(),
+        }
+    }
+
+    pub fn to_entry<'a>(&self, builder: &mut FlatBufferBuilder<'a>) -> WIPOffset<ClientEntry<'a>> {
+        let hostname = builder.create_string(&self.hostname);
+        let mount_point = builder.create_string(&self.mount_point);
+        let address = builder.create_string(&self.address);
+        let mut entry_builder = ClientEntryBuilder::new(builder);
+        entry_builder.add_client_id(self.client_id);
+        entry_builder.add_session_id(self.session_id);
+        entry_builder.add_node_id(self.node_id);
+        entry_builder.add_hostname(hostname);
+        entry_builder.add_mount_point(mount_point);
+        entry_builder.add_address(address);
+        entry_builder.add_connections(self.connections);
+        entry_builder.add_open_handles(self.open_handles);
+        entry_builder.add_requests(self.requests);
+        entry_builder.add_connected_seconds(self.connected_seconds);
+        entry_builder.add_idle_seconds(self.idle_seconds);
+        entry_builder.finish()
+    }
+}
+
+// Client sessions connected to a node. A session is listed from its first request until its last connection is
+// closed
+#[derive(Default)]
+pub struct ClientRegistry {
+    limits: ClientLimits,
+    clients: Mutex<HashMap<u64, Client>>,
+    // Sessions whose requests are rejected. Forgotten when the node restarts
+    evicted: Mutex<HashSet<u64>>,
+}
+
+impl ClientRegistry {
+    pub fn new(limits: ClientLimits) -> ClientRegistry {
+        ClientRegistry {
+            limits,
+            clients: Mutex::new(HashMap::new()),
+            evicted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn limits(&self) -> ClientLimits {
+        self.limits
+    }
+
+    // 0 if the session hasn't registered
+    pub fn client_id(&self, session_id: u64) -> u64 {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .map_or(0, |x| x.client_id)
+    }
+
+    // Records what the client reported when it registered. Ignored if it disconnected meanwhile
+    pub fn register(
+        &self,
+        session_id: u64,
+        client_id: u64,
+        hostname: &str,
+        mount_point: &str,
+        open_handles: u32,
+    ) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&session_id) {
+            client.client_id = client_id;
+            client.hostname = hostname.to_string();
+            client.mount_point = mount_point.to_string();
+            client.open_handles = open_handles;
+        }
+    }
+
+    // Sorted by client ID
+    pub fn list(&self, node_id: u64) -> Vec<ClientStatus> {
+        let clients = self.clients.lock().unwrap();
+        let mut listed: Vec<ClientStatus> = clients
+            .iter()
+            .map(|(session_id, client)| client.status(*session_id, node_id))
+            .collect();
+        listed.sort_by_key(|x| (x.client_id, x.session_id));
+
+        listed
+    }
+
+    // Rejects the later requests of the session, and closes its connections. Returns its entry, if it was connected
+    pub fn evict(&self, session_id: u64, node_id: u64) -> Option<ClientStatus> {
+        self.evicted.lock().unwrap().insert(session_id);
+        let client = self.clients.lock().unwrap().remove(&session_id)?;
+        for closer in client.closers.iter() {
+            closer.close();
+        }
+
+        Some(client.status(session_id, node_id))
+    }
+}
+
+impl Client {
+    fn status(&self, session_id: u64, node_id: u64) -> ClientStatus {
+        ClientStatus {
+            client_id: self.client_id,
+            session_id,
+            node_id,
+            hostname: self.hostname.clone(),
+            mount_point: self.mount_point.clone(),
+            address: self.address.clone(),
+            connections: self.connections,
+            open_handles: self.open_handles,
+            requests: self.requests,
+            connected_seconds: self.connected.elapsed().as_secs(),
+            idle_seconds: self.last_request.elapsed().as_secs(),
+        }
+    }
+}
+
+// Counts a connection against the limit of each session which sends requests on it, until it's dropped
+pub struct ClientConnection {
+    registry: Arc<ClientRegistry>,
+    address: String,
+    sessions: Vec<u64>,
+    closer: Arc<Closer>,
+}
+
+impl ClientConnection {
+    // The receiver completes when the connection should be closed, because its session was evicted
+    pub fn new(
+        registry: &Arc<ClientRegistry>,
+        address: String,
+    ) -> (ClientConnection, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let connection = ClientConnection {
+            registry: registry.clone(),
+            address,
+            sessions: vec![],
+            closer: Arc::new(Closer {
+                sender: Mutex::new(Some(sender)),
+                shared: Mutex::new(false),
+            }),
+        };
+
+        (connection, receiver)
+    }
+
+    // Accounts for a request received on the connection. The first request of a session fails with
+    // TooManyConnections if the session already has max_connections other connections. Requests of evicted
+    // sessions fail with ClientEvicted
+    pub fn record_request(&mut self, session_id: u64) -> Result<(), ErrorCode> {
+        // Requests without a session are from other nodes
+        if session_id == 0 {
+            *self.closer.shared.lock().unwrap() = true;
+            return Ok(());
+        }
+        if self.registry.evicted.lock().unwrap().contains(&session_id) {
+            return Err(ErrorCode::ClientEvicted);
+        }
+        let mut clients = self.registry.clients.lock().unwrap();
+        let address = &self.address;
+        let client = clients.entry(session_id).or_insert_with(|| Client {
+            client_id: 0,
+            hostname: String::new(),
+            mount_point: String::new(),
+            open_handles: 0,
+            address: address.clone(),
+            connections: 0,
+            requests: 0,
+            connected: Instant::now(),
+            last_request: Instant::now(),
+            closers: vec![],
+        });
+        if !self.sessions.contains(&session_id) {
+            let max_connections = self.registry.limits.max_connections;
+            if max_connections > 0 && client.connections >= max_connections {
+                return Err(ErrorCode::TooManyConnections);
+            }
+            client.connections += 1;
+            client.closers.push(self.closer.clone());
+            self.sessions.push(session_id);
+            if self.sessions.len() > 1 {
+                *self.closer.shared.lock().unwrap() = true;
+            }
+        }
+        client.requests += 1;
+        client.last_request = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        let mut clients = self.registry.clients.lock().unwrap();
+        for session_id in self.sessions.iter() {
+            let disconnected = match clients.get_mut(session_id) {
+                Some(client) => {
+                    client.connections -= 1;
+                    client.connections == 0
+                }
+                None => false,
+            };
+            if disconnected {
+                clients.remove(session_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_registry::{ClientConnection, ClientLimits, ClientRegistry};
+    use crate::generated::ErrorCode;
+    use futures::Future;
+    use std::sync::Arc;
+
+    #[test]
+    fn connection_limit() {
+        let registry = Arc::new(ClientRegistry::new(ClientLimits {
+            max_connections: 2,
+            max_open_handles: 0,
+        }));
+        let (mut first, _) = ClientConnection::new(&registry, "client:1".into());
+        let (mut second, _) = ClientConnection::new(&registry, "client:2".into());
+        let (mut third, _) = ClientConnection::new(&registry, "client:3".into());
+        first.record_request(5).unwrap();
+        first.record_request(5).unwrap();
+        second.record_request(5).unwrap();
+        assert_eq!(
+            third.record_request(5).err(),
+            Some(ErrorCode::TooManyConnections)
+        );
+        // Other sessions and nodes have their own limits
+        third.record_request(6).unwrap();
+        third.record_request(0).unwrap();
+
+        registry.register(5, 11, "host", "/mnt", 3);
+        let listed = registry.list(1);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1].client_id, 11);
+        assert_eq!(listed[1].connections, 2);
+        assert_eq!(listed[1].requests, 3);
+
+        drop(first);
+        third.record_request(5).unwrap();
+        assert_eq!(registry.client_id(5), 11);
+        drop(second);
+        drop(third);
+        assert!(registry.list(1).is_empty());
+    }
+
+    #[test]
+    fn eviction() {
+        let registry = Arc::new(ClientRegistry::new(ClientLimits::default()));
+        let (mut client, client_closed) = ClientConnection::new(&registry, "client:1".into());
+        let (mut peer, mut peer_closed) = ClientConnection::new(&registry, "peer:1".into());
+        client.record_request(5).unwrap();
+        peer.record_request(5).unwrap();
+        peer.record_request(6).unwrap();
+
+        let evicted = registry.evict(5, 1).unwrap();
+        assert_eq!(evicted.connections, 2);
+        // Only the connection which carries nothing but the evicted session is closed
+        client_closed.wait().unwrap();
+        assert!(peer_closed.try_recv().unwrap().is_none());
+        assert_eq!(
+            client.record_request(5).err(),
+            Some(ErrorCode::ClientEvicted)
+        );
+        peer.record_request(6).unwrap();
+        assert!(registry.evict(5, 1).is_none());
+        assert_eq!(registry.list(1).len(), 1);
+    }
+}
@@ -0,0 +1,421 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use fuse::FileType;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::client::DirectoryEntryTuple;
+
+// Files are cached in blocks of this size, so that reads at any offset can be served from them
+pub const CACHE_BLOCK_SIZE: u64 = 128 * 1024;
+const VALIDATOR_FILE: &str = "validator";
+// Directory listings are stored in this subdirectory, one file per directory
+const LISTINGS_DIR: &str = "listings";
+// Once this many listings are stored, they're all dropped
+const MAX_STORED_LISTINGS: usize = 16 * 1024;
+
+// Returns the block aligned range which covers size bytes at offset
+pub fn aligned_range(offset: u64, size: u32) -> (u64, u32) {
+    let start = offset - offset % CACHE_BLOCK_SIZE;
+    let end = offset + u64::from(size);
+    let end = (end + CACHE_BLOCK_SIZE - 1) / CACHE_BLOCK_SIZE * CACHE_BLOCK_SIZE;
+    (start, (end - start) as u32)
+}
+
+// Identifies the version of a file which its cached blocks were read from
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Validator {
+    data_version: u64,
+    size: u64,
+}
+
+impl Validator {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        data.write_u64::<LittleEndian>(self.data_version).unwrap();
+        data.write_u64::<LittleEndian>(self.size).unwrap();
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Validator> {
+        if data.len() != 16 {
+            return None;
+        }
+        Some(Validator {
+            data_version: LittleEndian::read_u64(&data[0..8]),
+            size: LittleEndian::read_u64(&data[8..16]),
+        })
+    }
+}
+
+fn file_type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::NamedPipe => 0,
+        FileType::CharDevice => 1,
+        FileType::BlockDevice => 2,
+        FileType::Directory => 3,
+        FileType::RegularFile => 4,
+        FileType::Symlink => 5,
+        FileType::Socket => 6,
+    }
+}
+
+fn file_type_from_code(code: u8) -> Option<FileType> {
+    match code {
+        0 => Some(FileType::NamedPipe),
+        1 => Some(FileType::CharDevice),
+        2 => Some(FileType::BlockDevice),
+        3 => Some(FileType::Directory),
+        4 => Some(FileType::RegularFile),
+        5 => Some(FileType::Symlink),
+        6 => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+// The directory version, followed by the inode, type, name length and name of each entry
+fn encode_listing(version: u64, entries: &[DirectoryEntryTuple]) -> Vec<u8> {
+    let mut data = vec![];
+    data.write_u64::<LittleEndian>(version).unwrap();
+    for (inode, name, file_type) in entries.iter() {
+        data.write_u64::<LittleEndian>(*inode).unwrap();
+        data.push(file_type_code(*file_type));
+        data.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+        data.extend_from_slice(name.as_bytes());
+    }
+    data
+}
+
+fn decode_listing(data: &[u8]) -> Option<(u64, Vec<DirectoryEntryTuple>)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let version = LittleEndian::read_u64(&data[0..8]);
+    let mut entries = vec![];
+    let mut position = 8;
+    while position < data.len() {
+        if data.len() < position + 13 {
+            return None;
+        }
+        let inode = LittleEndian::read_u64(&data[position..position + 8]);
+        let file_type = file_type_from_code(data[position + 8])?;
+        let name_length = LittleEndian::read_u32(&data[position + 9..position + 13]) as usize;
+        position += 13;
+        if data.len() < position + name_length {
+            return None;
+        }
+        let name = OsStr::from_bytes(&data[position..position + name_length]).to_os_string();
+        position += name_length;
+        entries.push((inode, name, file_type));
+    }
+    Some((version, entries))
+}
+
+struct CachedBlock {
+    size: u64,
+    last_used: u64,
+}
+
+struct DiskCacheState {
+    blocks: HashMap<(u64, u64), CachedBlock>,
+    validators: HashMap<u64, Validator>,
+    // Files whose validator was checked against the server by this mount. Only their blocks are served
+    validated: HashSet<u64>,
+    total_bytes: u64,
+    // Incremented on every access, to find the least recently used blocks
+    clock: u64,
+    // Directories whose listing is stored
+    listings: HashSet<u64>,
+}
+
+// Blocks of files read through the mount, kept on local disk so that they survive remounts. A file's blocks are
+// only served after its data version has been checked against the server, when it's opened. Reads
+// served from the cache don't update the access time on the server.
+// Directory listings are kept too, as the second tier of the mount's in-memory listings, so that a listing which
+// was evicted from memory, or read by an earlier mount, only needs the changes since its version to be fetched
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<DiskCacheState>,
+}
+
+impl DiskCache {
+    pub fn new(dir: &str, max_bytes: u64) -> io::Result<DiskCache> {
+        let dir = Path::new(dir).to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut validators = HashMap::new();
+        let mut found = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let inode_dir = entry?.path();
+            let inode: u64 = match inode_dir.file_name().and_then(|x| x.to_str()?.parse().ok()) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            match fs::read(inode_dir.join(VALIDATOR_FILE))
+                .ok()
+                .and_then(|x| Validator::decode(&x))
+            {
+                Some(validator) => validators.insert(inode, validator),
+                None => {
+                    fs::remove_dir_all(&inode_dir)?;
+                    continue;
+                }
+            };
+            for block in fs::read_dir(&inode_dir)? {
+                let block = block?;
+                let index: u64 = match block.file_name().to_str().and_then(|x| x.parse().ok()) {
+                    Some(index) => index,
+                    // Validator, or an incomplete block
+                    None => continue,
+                };
+                let metadata = block.metadata()?;
+                found.push((metadata.modified()?, inode, index, metadata.len()));
+            }
+        }
+
+        // The order the blocks were written in is the best estimate of which were used least recently
+        found.sort();
+        let mut blocks = HashMap::new();
+        let mut total_bytes = 0;
+        for (clock, (_, inode, index, size)) in found.into_iter().enumerate() {
+            total_bytes += size;
+            blocks.insert(
+                (inode, index),
+                CachedBlock {
+                    size,
+                    last_used: clock as u64,
+                },
+            );
+        }
+        let clock = blocks.len() as u64;
+
+        let mut listings = HashSet::new();
+        let listings_dir = dir.join(LISTINGS_DIR);
+        if listings_dir.exists() {
+            for entry in fs::read_dir(&listings_dir)? {
+                // Incomplete listings have an extension, and are skipped
+                if let Some(inode) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+                    listings.insert(inode);
+                }
+            }
+        }
+
+        let cache = DiskCache {
+            dir,
+            max_bytes,
+            state: Mutex::new(DiskCacheState {
+                blocks,
+                validators,
+                validated: HashSet::new(),
+                total_bytes,
+                clock,
+                listings,
+            }),
+        };
+        cache.evict(&mut cache.state.lock().unwrap());
+
+        Ok(cache)
+    }
+
+    fn inode_dir(&self, inode: u64) -> PathBuf {
+        self.dir.join(inode.to_string())
+    }
+
+    fn block_path(&self, inode: u64, index: u64) -> PathBuf {
+        self.inode_dir(inode).join(index.to_string())
+    }
+
+    fn listing_path(&self, inode: u64) -> PathBuf {
+        self.dir.join(LISTINGS_DIR).join(inode.to_string())
+    }
+
+    fn remove_inode(&self, state: &mut DiskCacheState, inode: u64) {
+        let mut freed = 0;
+        state.blocks.retain(|(block_inode, _), block| {
+            if *block_inode == inode {
+                freed += block.size;
+                false
+            } else {
+                true
+            }
+        });
+        state.total_bytes -= freed;
+        state.validators.remove(&inode);
+        state.validated.remove(&inode);
+        if let Err(error) = fs::remove_dir_all(self.inode_dir(inode)) {
+            if error.kind() != io::ErrorKind::NotFound {
+                warn!(
+                    "Failed to remove cached blocks of inode {}: {:?}",
+                    inode, error
+                );
+            }
+        }
+    }
+
+    // Removes the least recently used blocks, until the cache fits in max_bytes
+    fn evict(&self, state: &mut DiskCacheState) {
+        if state.total_bytes <= self.max_bytes {
+            return;
+        }
+        let mut blocks: Vec<(u64, (u64, u64), u64)> = state
+            .blocks
+            .iter()
+            .map(|(key, block)| (block.last_used, *key, block.size))
+            .collect();
+        blocks.sort();
+        for (_, (inode, index), size) in blocks {
+            if state.total_bytes <= self.max_bytes {
+                break;
+            }
+            state.blocks.remove(&(inode, index));
+            state.total_bytes -= size;
+            fs::remove_file(self.block_path(inode, index)).ok();
+        }
+    }
+
+    // Checks the cached blocks of inode against its current attributes, and drops them if the file has changed
+    pub fn validate(&self, inode: u64, data_version: u64, size: u64) {
+        let validator = Validator { data_version, size };
+        let mut state = self.state.lock().unwrap();
+        if state.validators.get(&inode) == Some(&validator) {
+            state.validated.insert(inode);
+            return;
+        }
+        self.remove_inode(&mut state, inode);
+        let inode_dir = self.inode_dir(inode);
+        let stored = fs::create_dir_all(&inode_dir)
+            .and_then(|_| fs::write(inode_dir.join(VALIDATOR_FILE), validator.encode()));
+        if let Err(error) = stored {
+            warn!("Failed to store validator of inode {}: {:?}", inode, error);
+            return;
+        }
+        state.validators.insert(inode, validator);
+        state.validated.insert(inode);
+    }
+
+    // Drops the cached blocks of inode, because it was modified through this mount
+    pub fn invalidate(&self, inode: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.validators.contains_key(&inode) {
+            self.remove_inode(&mut state, inode);
+        }
+    }
+
+    // Returns the data, if every block it's in is cached
+    pub fn read(&self, inode: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let end = {
+            let mut state = self.state.lock().unwrap();
+            if !state.validated.contains(&inode) {
+                return None;
+            }
+            let file_size = state.validators[&inode].size;
+            let end = file_size.min(offset + u64::from(size));
+            if offset >= end {
+                return Some(vec![]);
+            }
+            state.clock += 1;
+            let clock = state.clock;
+            for index in offset / CACHE_BLOCK_SIZE..=(end - 1) / CACHE_BLOCK_SIZE {
+                state.blocks.get_mut(&(inode, index))?.last_used = clock;
+            }
+            end
+        };
+
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        for index in offset / CACHE_BLOCK_SIZE..=(end - 1) / CACHE_BLOCK_SIZE {
+            let block = fs::read(self.block_path(inode, index)).ok()?;
+            let block_start = index * CACHE_BLOCK_SIZE;
+            let start = (offset.max(block_start) - block_start) as usize;
+            let stop = (end.min(block_start + CACHE_BLOCK_SIZE) - block_start) as usize;
+            if block.len() < stop {
+                return None;
+            }
+            data.extend_from_slice(&block[start..stop]);
+        }
+
+        Some(data)
+    }
+
+    // Caches data read from the server at offset, which must be block aligned. Only complete blocks, and the last
+    // block of the file, are kept
+    pub fn insert(&self, inode: u64, offset: u64, data: &[u8]) {
+        assert_eq!(offset % CACHE_BLOCK_SIZE, 0);
+        let validator = match self.state.lock().unwrap().validators.get(&inode) {
+            Some(validator) => *validator,
+            None => return,
+        };
+        for (i, block) in data.chunks(CACHE_BLOCK_SIZE as usize).enumerate() {
+            let index = offset / CACHE_BLOCK_SIZE + i as u64;
+            let block_end = index * CACHE_BLOCK_SIZE + block.len() as u64;
+            if block.len() as u64 != CACHE_BLOCK_SIZE && block_end != validator.size {
+                continue;
+            }
+            let path = self.block_path(inode, index);
+            let temp_path = path.with_extension("tmp");
+            let stored = fs::write(&temp_path, block).and_then(|_| fs::rename(&temp_path, &path));
+            let mut state = self.state.lock().unwrap();
+            // The file may have been invalidated while the block was written
+            if !state.validated.contains(&inode) || state.validators.get(&inode) != Some(&validator)
+            {
+                fs::remove_file(&path).ok();
+                return;
+            }
+            if let Err(error) = stored {
+                warn!(
+                    "Failed to cache block {} of inode {}: {:?}",
+                    index, inode, error
+                );
+                return;
+            }
+            state.clock += 1;
+            let last_used = state.clock;
+            let previous = state.blocks.insert(
+                (inode, index),
+                CachedBlock {
+                    size: block.len() as u64,
+                    last_used,
+                },
+            );
+            state.total_bytes += block.len() as u64;
+            if let Some(previous) = previous {
+                state.total_bytes -= previous.size;
+            }
+            self.evict(&mut state);
+        }
+    }
+
+    // Returns the version and entries of the stored listing of directory inode. It may be out of date, so it must
+    // be checked against the server's version of the directory before it's served
+    pub fn read_listing(&self, inode: u64) -> Option<(u64, Vec<DirectoryEntryTuple>)> {
+        if !self.state.lock().unwrap().listings.contains(&inode) {
+            return None;
+        }
+        fs::read(self.listing_path(inode))
+            .ok()
+            .and_then(|data| decode_listing(&data))
+    }
+
+    pub fn store_listing(&self, inode: u64, version: u64, entries: &[DirectoryEntryTuple]) {
+        let mut state = self.state.lock().unwrap();
+        if state.listings.len() >= MAX_STORED_LISTINGS && !state.listings.contains(&inode) {
+            fs::remove_dir_all(self.dir.join(LISTINGS_DIR)).ok();
+            state.listings.clear();
+        }
+        let path = self.listing_path(inode);
+        let temp_path = path.with_extension("tmp");
+        let stored = fs::create_dir_all(self.dir.join(LISTINGS_DIR))
+            .and_then(|_| fs::write(&temp_path, encode_listing(version, entries)))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(error) = stored {
+            warn!("Failed to store listing of inode {}: {:?}", inode, error);
+            return;
+        }
+        state.listings.insert(inode);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+// Each delivery is tried this many times, waiting RETRY_DELAY after the first failure, and twice as long after each
+// later one
+const DELIVERY_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the delivery thread waits for events, when none are pending
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    // A file which was open for writing was closed
+    FileWritten,
+    DirectoryCreated,
+    // A change was rejected because the filesystem, or a node, is full
+    NoSpace,
+}
+
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::FileWritten => "file_written",
+            EventKind::DirectoryCreated => "directory_created",
+            EventKind::NoSpace => "no_space",
+        }
+    }
+}
+
+struct Event {
+    kind: EventKind,
+    inode: u64,
+    path: String,
+    time: SystemTime,
+}
+
+impl Event {
+    fn to_json(&self) -> String {
+        let seconds = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        format!(
+            "{{\"event\":\"{}\",\"inode\":{},\"path\":{},\"time\":{}}}",
+            self.kind.name(),
+            self.inode,
+            json_string(&self.path),
+            seconds
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[derive(Clone)]
+pub struct HookConfig {
+    // Events are POSTed as JSON to each of these http:// URLs
+    pub urls: Vec<String>,
+    // Each of these is executed with the event as JSON on stdin, and its kind and path in FLEETFS_EVENT and
+    // FLEETFS_PATH
+    pub commands: Vec<String>,
+    // Events of the same kind for the same inode are only delivered once they stop for this long, so that a burst
+    // of them is delivered once
+    pub debounce: Duration,
+}
+
+// Delivers events about changes to the filesystem to external endpoints, so that pipelines can react to new data.
+// Delivery happens on a background thread, so that slow endpoints never delay the changes. Each event is only
+// delivered by one node: changes by the leader when they're applied, and rejected requests by the node which
+// received them
+#[derive(Clone)]
+pub struct EventHooks {
+    sender: Option<Arc<Mutex<Sender<Event>>>>,
+}
+
+impl EventHooks {
+    pub fn new(config: HookConfig) -> EventHooks {
+        if config.urls.is_empty() && config.commands.is_empty() {
+            return EventHooks { sender: None };
+        }
+
+        let (sender, receiver) = channel();
+        thread::spawn(move || deliver_events(&config, receiver));
+        EventHooks {
+            sender: Some(Arc::new(Mutex::new(sender))),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    pub fn notify(&self, kind: EventKind, inode: u64, path: String) {
+        if let Some(ref sender) = self.sender {
+            let event = Event {
+                kind,
+                inode,
+                path,
+                time: SystemTime::now(),
+            };
+            sender.lock().unwrap().send(event).ok();
+        }
+    }
+}
+
+fn deliver_events(config: &HookConfig, receiver: Receiver<Event>) {
+    // Latest event of each kind for each inode, and when it's delivered
+    let mut pending: HashMap<(EventKind, u64), (Event, Instant)> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        let wait = pending
+            .values()
+            .map(|(_, due)| {
+                if *due > now {
+                    *due - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            })
+            .min()
+            .unwrap_or(IDLE_WAIT);
+        match receiver.recv_timeout(wait) {
+            Ok(event) => {
+                let due = Instant::now() + config.debounce;
+                pending.insert((event.kind, event.inode), (event, due));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let due: Vec<(EventKind, u64)> = pending
+            .iter()
+            .filter(|(_, (_, due))| *due <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in due {
+            let (event, _) = pending.remove(&key).unwrap();
+            deliver(config, &event);
+        }
+    }
+}
+
+fn deliver(config: &HookConfig, event: &Event) {
+    let body = event.to_json();
+    for url in config.urls.iter() {
+        with_retries(&format!("POST to {}", url), || post(url, &body));
+    }
+    for command in config.commands.iter() {
+        with_retries(command, || run_command(command, event, &body));
+    }
+}
+
+fn with_retries<F: Fn() -> io::Result<()>>(hook: &str, attempt: F) {
+    let mut delay = RETRY_DELAY;
+    for i in 1..=DELIVERY_ATTEMPTS {
+        match attempt() {
+            Ok(()) => return,
+            Err(error) if i < DELIVERY_ATTEMPTS => {
+                info!("Hook {} failed, retrying in {:?}: {}", hook, delay, error);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(error) => warn!(
+                "Hook {} failed {} times. Dropping the event: {}",
+                hook, DELIVERY_ATTEMPTS, error
+            ),
+        }
+    }
+}
+
+fn post(url: &str, body: &str) -> io::Result<()> {
+    if !url.starts_with("http://") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Only http:// URLs are supported",
+        ));
+    }
+    let location = &url["http://".len()..];
+    let (host, path) = match location.find('/') {
+        Some(i) => (&location[..i], &location[i..]),
+        None => (location, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Host has no addresses"))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    // Like "HTTP/1.1 200 OK"
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Unexpected response: {}", status_line.trim()),
+        )),
+    }
+}
+
+fn run_command(command: &str, event: &Event, body: &str) -> io::Result<()> {
+    let mut child = Command::new(command)
+        .env("FLEETFS_EVENT", event.kind.name())
+        .env("FLEETFS_PATH", &event.path)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may not read its input
+        stdin.write_all(body.as_bytes()).ok();
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Exited with {}", status),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_hooks::{Event, EventKind};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn event_json() {
+        let event = Event {
+            kind: EventKind::FileWritten,
+            inode: 5,
+            path: "/new \"data\"\n".to_string(),
+            time: UNIX_EPOCH + Duration::from_secs(100),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"file_written","inode":5,"path":"/new \"data\"\u000a","time":100}"#
+        );
+    }
+}
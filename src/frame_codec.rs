@@ -0,0 +1,107 @@
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::BytesMut;
+use std::io;
+use tokio::codec::Decoder;
+
+// Used unless --max-frame-size is given
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+pub enum Frame {
+    Request(BytesMut),
+    // A frame longer than the maximum. Its data is skipped without being buffered, so that the following frames
+    // on the connection can still be read
+    Oversized(usize),
+}
+
+// Splits the stream from a client into length prefixed frames, like length_delimited does, except that frames
+// longer than the maximum are reported instead of closing the connection
+pub struct RequestFrameCodec {
+    max_frame_length: usize,
+    // Remaining bytes of an oversized frame
+    skipping: usize,
+}
+
+impl RequestFrameCodec {
+    pub fn new(max_frame_length: usize) -> RequestFrameCodec {
+        RequestFrameCodec {
+            max_frame_length,
+            skipping: 0,
+        }
+    }
+}
+
+impl Decoder for RequestFrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if self.skipping > 0 {
+            let skipped = self.skipping.min(buffer.len());
+            buffer.advance(skipped);
+            self.skipping -= skipped;
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+        if buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let length = LittleEndian::read_u32(&buffer[..LENGTH_PREFIX_SIZE]) as usize;
+        if length > self.max_frame_length {
+            buffer.advance(LENGTH_PREFIX_SIZE);
+            self.skipping = length;
+            return Ok(Some(Frame::Oversized(length)));
+        }
+        if buffer.len() < LENGTH_PREFIX_SIZE + length {
+            buffer.reserve(LENGTH_PREFIX_SIZE + length - buffer.len());
+            return Ok(None);
+        }
+        buffer.advance(LENGTH_PREFIX_SIZE);
+
+        Ok(Some(Frame::Request(buffer.split_to(length))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame_codec::{Frame, RequestFrameCodec};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use bytes::BytesMut;
+    use tokio::codec::Decoder;
+
+    fn length_prefixed(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        frame.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped() {
+        let mut codec = RequestFrameCodec::new(8);
+        let mut stream = length_prefixed(b"first");
+        stream.extend(length_prefixed(&[1; 20]));
+        stream.extend(length_prefixed(b"third"));
+
+        // Fed a few bytes at a time, like a slow connection
+        let mut buffer = BytesMut::new();
+        let mut frames = vec![];
+        for chunk in stream.chunks(3) {
+            buffer.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                frames.push(match frame {
+                    Frame::Request(data) => Ok(data.to_vec()),
+                    Frame::Oversized(length) => Err(length),
+                });
+            }
+        }
+
+        assert_eq!(
+            frames,
+            vec![Ok(b"first".to_vec()), Err(20), Ok(b"third".to_vec())]
+        );
+        assert!(buffer.is_empty());
+    }
+}
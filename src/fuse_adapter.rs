@@ -1,28 +1,41 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::mem::size_of;
 use std::net::SocketAddr;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use libc;
 use log::debug;
 use log::error;
 use log::warn;
 
-use crate::client::NodeClient;
-use crate::generated::{ErrorCode, FileKind, Timestamp, UserContext};
-use crate::storage::metadata_storage::MAX_NAME_LENGTH;
-use crate::utils::check_access;
+use crate::access_pattern::{AccessPattern, AccessPatternTracker};
+use crate::client::{
+    DirectoryEntryTuple, DirectoryListing, EncodedWrite, FileDetails, NodeClient, UnreachablePolicy,
+};
+use crate::disk_cache::{aligned_range, DiskCache};
+use crate::generated::{
+    AtimeMode, ErrorCode, FileKind, FilesystemLimits, LockKind, Timestamp, UserContext,
+};
+use crate::memory_budget::{CacheKind, MemoryBudget, CLIENT_MEMORY_XATTR};
+use crate::offline_store::{OfflineStore, MAX_OFFLINE_FILE_SIZE, SYNC_STATUS_XATTR};
+use crate::read_coalescer::{ReadCoalescer, MAX_COALESCED_READ};
+use crate::storage::metadata_storage::default_limits;
+use crate::tcp_client::Keepalive;
+use crate::utils::{check_access, LOG_RECORD_HEADER_SIZE};
+use crate::worker_pool::WorkerPool;
 use bytes::Bytes;
 use fuse::{
-    Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
 use libc::ENOSYS;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::os::raw::c_int;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const READ_AHEAD_CACHE_TTL_MS: u64 = 1;
@@ -31,10 +44,61 @@ const FUSE_MAX_READ_SIZE: u32 = 128 * 1024;
 // TODO: should dynamically size this, based on prediction of what client process will read
 // TODO: should also track wasted read aheads
 const SPECULATIVE_READ_SIZE: u32 = 8 * FUSE_MAX_READ_SIZE;
+// Strided streams prefetch up to this many of their next reads, within SPECULATIVE_READ_SIZE
+const STRIDED_PREFETCH_READS: u64 = 8;
+// Directory listings are kept, so that listing them again only transfers the changes
+const MAX_CACHED_LISTINGS: usize = 1024;
+// Tells the kernel not to cache the data of the file
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+// Attributes and directory entries kept for UnreachablePolicy::ServeStale
+const MAX_STALE_ENTRIES: usize = 100_000;
+// How often writes made while disconnected are checked for, to replay them
+const OFFLINE_REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+// How often the round trip time to each node is measured again, to choose the read replica
+const READ_REPLICA_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+// Locks which are waited for are retried after this long, doubling up to LOCK_RETRY_MAX_DELAY, which must be shorter
+// than the time after which the leader forgets a waiter
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+const LOCK_RETRY_MAX_DELAY: Duration = Duration::from_secs(1);
+// How often the lease of the mount's session is renewed. A third of the lease, so that a renewal can
+// fail without losing the locks
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+// How often the mount reports itself to the node it's connected to, which lists it as a client
+const CLIENT_REGISTRATION_INTERVAL: Duration = Duration::from_secs(10);
+// Reading it from any file returns once every write acknowledged to the mount, or any other client, before the read
+// is flushed to disk on a quorum of the nodes. Its value is the index of the Raft log they're flushed up to. Writes
+// journaled while disconnected aren't included, since they haven't been acknowledged by the cluster
+pub const FLUSH_EPOCH_XATTR: &str = "fleetfs.flush_epoch";
 
 struct FileHandleAttributes {
     read: bool,
     write: bool,
+    // Opened with O_APPEND, so writes go to the end of the file, wherever that is on the server
+    append: bool,
+    // Set for shared logs, which are read one record per read, starting at this offset
+    log_cursor: Option<u64>,
+    // Registered with the cluster, because the file is temporary, so it's released even if it isn't writable
+    temporary: bool,
+}
+
+struct CachedListing {
+    version: u64,
+    entries: Vec<DirectoryEntryTuple>,
+}
+
+// Data of a write from the kernel, which is handed to a worker
+enum WriteData {
+    Copied(Vec<u8>),
+    Encoded(EncodedWrite),
+}
+
+impl WriteData {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            WriteData::Copied(data) => data,
+            WriteData::Encoded(encoded) => encoded.data(),
+        }
+    }
 }
 
 struct CachedRead {
@@ -44,34 +108,616 @@ struct CachedRead {
     read_at: Instant,
 }
 
-pub struct FleetFUSE {
+// The parts of a fuse Request which are needed once it's been handed to a worker
+#[derive(Clone, Copy)]
+struct Caller {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Caller {
+    fn new(req: &Request) -> Caller {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+// Metadata last read from the server, which is served read-only while it's unreachable
+#[derive(Default)]
+struct StaleCache {
+    attributes: HashMap<u64, FileAttr>,
+    entries: HashMap<(u64, OsString), u64>,
+}
+
+struct FuseState {
     client: NodeClient,
     next_file_handle: AtomicU64,
     file_handles: Mutex<HashMap<u64, FileHandleAttributes>>,
     read_ahead_cache: Mutex<HashMap<u64, CachedRead>>,
+    // How each process reads each file, which decides what's read ahead
+    access_patterns: AccessPatternTracker,
+    directory_listings: Mutex<HashMap<u64, CachedListing>>,
+    // Shared by the read-ahead and directory listing caches
+    memory_budget: MemoryBudget,
+    // Merges small reads which arrive close together, if a coalescing window was given
+    read_coalescer: Option<ReadCoalescer>,
+    // Listing of each open directory handle, taken when it's read from the start. Later reads of the handle are
+    // served from it, so that a paginated listing doesn't skip or repeat entries which are renamed meanwhile
+    open_listings: Mutex<HashMap<u64, Arc<Vec<DirectoryEntryTuple>>>>,
+    // Only kept with UnreachablePolicy::ServeStale
+    stale_cache: Option<Mutex<StaleCache>>,
+    // Local copies of files and writes made while disconnected, if an offline directory was given
+    offline: Option<OfflineStore>,
+    // Blocks of files read through the mount, if a disk cache directory was given
+    disk_cache: Option<DiskCache>,
+    atime_mode: AtimeMode,
+    // Limits of the filesystem, last read by init or statfs. Requests which exceed them are rejected without
+    // being sent to the server
+    limits: Mutex<FilesystemLimits>,
+    // (inode, lock owner) of the locks taken through the mount, which are released when the owner closes the file,
+    // and the fencing token of the owner's latest exclusive lock on the inode, or 0
+    lock_owners: Mutex<HashMap<(u64, u64), u64>>,
+    // Limit on the handles open at once, learned when the mount registers. Zero is unlimited
+    max_open_handles: AtomicU32,
+}
+
+// Bytes to read for a read of size bytes, so that the following reads of its stream are served from the read-ahead
+// cache. Streams which are read at random aren't read ahead, and those which aren't classified yet are only read
+// ahead for reads of the largest size the kernel sends, which are likely sequential
+fn prefetch_size(pattern: AccessPattern, size: u32) -> u32 {
+    let prefetch_size = match pattern {
+        AccessPattern::Sequential => SPECULATIVE_READ_SIZE,
+        AccessPattern::Strided(stride) => {
+            let span = stride.saturating_mul(STRIDED_PREFETCH_READS);
+            // Only worth it if at least two more reads fit
+            if stride.saturating_mul(2) + u64::from(size) <= u64::from(SPECULATIVE_READ_SIZE) {
+                span.min(u64::from(SPECULATIVE_READ_SIZE)) as u32
+            } else {
+                size
+            }
+        }
+        AccessPattern::Random => size,
+        AccessPattern::Unknown if size >= FUSE_MAX_READ_SIZE => SPECULATIVE_READ_SIZE,
+        AccessPattern::Unknown => size,
+    };
+
+    prefetch_size.max(size)
+}
+
+// Replays the writes made while disconnected, once the server is reachable, until the mount is dropped
+fn replay_offline_writes(state: Weak<FuseState>) {
+    loop {
+        thread::sleep(OFFLINE_REPLAY_INTERVAL);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        if let Some(ref offline) = state.offline {
+            if offline.has_journaled_writes() {
+                if let Err(error_code) = offline.replay(&state.client) {
+                    debug!("Failed to replay offline writes: {:?}", error_code);
+                }
+            }
+        }
+    }
+}
+
+// Renews the lease of the mount's session, so that the leader only releases its locks and temporary files if the
+// mount dies, until the mount is dropped
+fn renew_lock_leases(state: Weak<FuseState>) {
+    loop {
+        thread::sleep(LOCK_RENEW_INTERVAL);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        if let Err(error_code) = state.client.renew_locks() {
+            warn!("Failed to renew lease: {:?}", error_code);
+        }
+    }
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let length = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+    return String::from_utf8_lossy(&buffer[..length]).to_string();
+}
+
+// Registers the mount with the cluster, and then keeps its entry up to date, until the mount is dropped
+fn register_client(state: Weak<FuseState>, mount_point: String) {
+    let hostname = hostname();
+    loop {
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        let open_handles = state
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned")
+            .len() as u32;
+        match state
+            .client
+            .register_client(&hostname, &mount_point, open_handles)
+        {
+            Ok(max_open_handles) => state
+                .max_open_handles
+                .store(max_open_handles, Ordering::SeqCst),
+            Err(error_code) => debug!("Failed to register client: {:?}", error_code),
+        }
+        drop(state);
+        thread::sleep(CLIENT_REGISTRATION_INTERVAL);
+    }
+}
+
+// Keeps the closest node chosen as the read replica, until the mount is dropped
+fn probe_read_replicas(state: Weak<FuseState>, zone: Option<String>) {
+    loop {
+        match state.upgrade() {
+            Some(state) => {
+                if let Err(error_code) = state
+                    .client
+                    .choose_read_replica(zone.as_ref().map(String::as_str))
+                {
+                    debug!("Failed to choose read replica: {:?}", error_code);
+                }
+            }
+            None => return,
+        }
+        thread::sleep(READ_REPLICA_PROBE_INTERVAL);
+    }
+}
+
+#[derive(Clone)]
+pub struct MountConfig {
+    pub server_ip_port: SocketAddr,
+    // Reported to the cluster, which lists the mount as a client
+    pub mount_point: String,
+    pub atime_mode: AtimeMode,
+    pub keepalive: Option<Keepalive>,
+    // Number of FUSE requests which are processed concurrently
+    pub workers: usize,
+    pub unreachable_policy: UnreachablePolicy,
+    // Directory of the offline store, which keeps a copy of the open files
+    pub offline_dir: Option<String>,
+    // Directory of the disk cache, and its size in bytes
+    pub disk_cache: Option<(String, u64)>,
+    pub max_frame_length: usize,
+    pub memory_budget_bytes: u64,
+    // If set, getattr and xattr reads are sent to the closest node, preferring those in the given zone
+    pub nearest_reads: Option<Option<String>>,
+    // Zero disables read coalescing
+    pub read_coalescing_window: Duration,
+}
+
+// Requests from the kernel are handed to a pool of workers, so that a slow response from the server only holds
+// up the requests which are waiting for it
+pub struct FleetFUSE {
+    state: Arc<FuseState>,
+    workers: WorkerPool,
 }
 
 impl FleetFUSE {
-    pub fn new(server_ip_port: SocketAddr) -> FleetFUSE {
-        FleetFUSE {
-            client: NodeClient::new(server_ip_port),
-            next_file_handle: AtomicU64::new(1),
-            file_handles: Mutex::new(HashMap::new()),
-            read_ahead_cache: Mutex::new(HashMap::new()),
+    pub fn new(config: &MountConfig) -> FleetFUSE {
+        // One connection per worker, so that they don't wait for each other's requests
+        let mut client =
+            NodeClient::with_connections(config.server_ip_port, config.workers, config.keepalive);
+        client.set_unreachable_policy(config.unreachable_policy);
+        client.set_max_frame_length(config.max_frame_length);
+        let offline = config
+            .offline_dir
+            .as_ref()
+            .map(|dir| OfflineStore::new(dir).expect("Failed to open offline directory"));
+        let disk_cache = config.disk_cache.as_ref().map(|(dir, size)| {
+            DiskCache::new(dir, *size).expect("Failed to open disk cache directory")
+        });
+        let read_coalescing_window = config.read_coalescing_window;
+        let stale_cache = if config.unreachable_policy == UnreachablePolicy::ServeStale {
+            Some(Mutex::new(StaleCache::default()))
+        } else {
+            None
+        };
+        let replay = offline.is_some();
+        let fuse = FleetFUSE {
+            state: Arc::new(FuseState {
+                client,
+                next_file_handle: AtomicU64::new(1),
+                file_handles: Mutex::new(HashMap::new()),
+                read_ahead_cache: Mutex::new(HashMap::new()),
+                access_patterns: AccessPatternTracker::default(),
+                directory_listings: Mutex::new(HashMap::new()),
+                memory_budget: MemoryBudget::new(config.memory_budget_bytes),
+                read_coalescer: if read_coalescing_window > Duration::from_secs(0) {
+                    Some(ReadCoalescer::new(read_coalescing_window))
+                } else {
+                    None
+                },
+                open_listings: Mutex::new(HashMap::new()),
+                stale_cache,
+                offline,
+                disk_cache,
+                atime_mode: config.atime_mode,
+                limits: Mutex::new(default_limits()),
+                lock_owners: Mutex::new(HashMap::new()),
+                max_open_handles: AtomicU32::new(0),
+            }),
+            workers: WorkerPool::new("fuse-worker", config.workers),
+        };
+        if replay {
+            let state = Arc::downgrade(&fuse.state);
+            thread::Builder::new()
+                .name("offline-replay".to_string())
+                .spawn(move || replay_offline_writes(state))
+                .expect("Failed to spawn offline replay thread");
+        }
+        if let Some(zone) = config.nearest_reads.clone() {
+            let state = Arc::downgrade(&fuse.state);
+            thread::Builder::new()
+                .name("read-replica-probe".to_string())
+                .spawn(move || probe_read_replicas(state, zone))
+                .expect("Failed to spawn read replica probe thread");
+        }
+        let state = Arc::downgrade(&fuse.state);
+        thread::Builder::new()
+            .name("lock-lease-renewal".to_string())
+            .spawn(move || renew_lock_leases(state))
+            .expect("Failed to spawn lock lease renewal thread");
+        let state = Arc::downgrade(&fuse.state);
+        let mount_point = config.mount_point.clone();
+        thread::Builder::new()
+            .name("client-registration".to_string())
+            .spawn(move || register_client(state, mount_point))
+            .expect("Failed to spawn client registration thread");
+
+        fuse
+    }
+
+    // Identifies this mount to the storage nodes, for example to set its rate limits
+    pub fn session_id(&self) -> u64 {
+        self.state.client.session_id()
+    }
+
+    // Runs operation with the state on a worker
+    fn dispatch<F: FnOnce(&FuseState) + Send + 'static>(&self, operation: F) {
+        let state = self.state.clone();
+        self.workers.execute(move || operation(&state));
+    }
+}
+
+impl FuseState {
+    fn limits(&self) -> FilesystemLimits {
+        *self.limits.lock().expect("limits lock is poisoned")
+    }
+
+    fn refresh_limits(&self) {
+        match self.client.filesystem_information() {
+            Ok(information) => {
+                *self.limits.lock().expect("limits lock is poisoned") = information.limits
+            }
+            Err(error_code) => debug!("Failed to read filesystem limits: {:?}", error_code),
+        }
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), ErrorCode> {
+        if name.len() > self.limits().max_name_length() as usize {
+            return Err(ErrorCode::NameTooLong);
+        }
+
+        Ok(())
+    }
+
+    // Whether requests are answered from the stale cache, because the server is unreachable
+    fn serve_stale(&self) -> bool {
+        self.stale_cache.is_some() && self.client.is_unreachable()
+    }
+
+    // Whether inode can be read and written while the server is unreachable
+    fn available_offline(&self, inode: u64) -> bool {
+        self.offline.as_ref().map_or(false, |x| x.is_cached(inode))
+    }
+
+    // Fetches a copy of the file for use while disconnected, unless the copy already kept is up to date
+    fn cache_offline(&self, req: &Caller, inode: u64, attr: &FileAttr, data_version: u64) {
+        let offline = match self.offline {
+            Some(ref offline) => offline,
+            None => return,
+        };
+        if attr.kind != FileType::RegularFile
+            || attr.size > MAX_OFFLINE_FILE_SIZE
+            || offline.is_current(inode, data_version)
+        {
+            return;
+        }
+        let mut data = vec![];
+        while (data.len() as u64) < attr.size {
+            match self.client.read_to_vec(
+                inode,
+                data.len() as u64,
+                SPECULATIVE_READ_SIZE,
+                UserContext::new(req.uid(), req.gid()),
+                AtimeMode::NoAtime,
+            ) {
+                Ok(ref chunk) if chunk.is_empty() => break,
+                Ok(chunk) => data.extend(chunk),
+                Err(error_code) => {
+                    debug!("Failed to cache inode {} offline: {:?}", inode, error_code);
+                    return;
+                }
+            }
+        }
+        if let Err(error) = offline.store(inode, data_version, &data) {
+            warn!("Failed to cache inode {} offline: {:?}", inode, error);
+        }
+    }
+
+    fn stale_cache(&self) -> Option<MutexGuard<StaleCache>> {
+        self.stale_cache
+            .as_ref()
+            .map(|x| x.lock().expect("stale_cache lock is poisoned"))
+    }
+
+    // Falls back to the last attributes received, if the server is unreachable
+    fn getattr_or_stale(&self, inode: u64) -> Result<FileAttr, ErrorCode> {
+        self.getattr_details_or_stale(inode).map(|(attr, _)| attr)
+    }
+
+    // Like getattr_or_stale(), but also returns the details, unless the attributes are stale
+    fn getattr_details_or_stale(
+        &self,
+        inode: u64,
+    ) -> Result<(FileAttr, Option<FileDetails>), ErrorCode> {
+        match self.client.getattr_details(inode) {
+            Ok((attr, details)) => {
+                if let Some(mut cache) = self.stale_cache() {
+                    if cache.attributes.len() >= MAX_STALE_ENTRIES {
+                        cache.attributes.clear();
+                    }
+                    cache.attributes.insert(inode, attr);
+                }
+                Ok((attr, Some(details)))
+            }
+            Err(error_code) => {
+                if self.serve_stale() {
+                    if let Some(attr) = self
+                        .stale_cache()
+                        .and_then(|x| x.attributes.get(&inode).cloned())
+                    {
+                        return Ok((attr, None));
+                    }
+                }
+                Err(error_code)
+            }
+        }
+    }
+
+    // Falls back to the last result of the lookup, if the server is unreachable
+    fn lookup_or_stale(&self, req: &Caller, parent: u64, name: &str) -> Result<u64, ErrorCode> {
+        let key = (parent, OsString::from(name));
+        match self
+            .client
+            .lookup(parent, name, UserContext::new(req.uid(), req.gid()))
+        {
+            Ok(inode) => {
+                if let Some(mut cache) = self.stale_cache() {
+                    if cache.entries.len() >= MAX_STALE_ENTRIES {
+                        cache.entries.clear();
+                    }
+                    cache.entries.insert(key, inode);
+                }
+                Ok(inode)
+            }
+            Err(error_code) => {
+                if !self.serve_stale() {
+                    return Err(error_code);
+                }
+                let cache = self.stale_cache().ok_or(error_code)?;
+                let parent_attr = cache.attributes.get(&parent).ok_or(error_code)?;
+                if !check_access(
+                    parent_attr.uid,
+                    parent_attr.gid,
+                    parent_attr.perm,
+                    req.uid(),
+                    req.gid(),
+                    libc::X_OK as u32,
+                ) {
+                    return Err(ErrorCode::AccessDenied);
+                }
+                cache.entries.get(&key).cloned().ok_or(error_code)
+            }
+        }
+    }
+
+    fn list_directory(
+        &self,
+        req: &Caller,
+        inode: u64,
+    ) -> Result<Vec<DirectoryEntryTuple>, ErrorCode> {
+        let mut listings = self
+            .directory_listings
+            .lock()
+            .expect("directory_listings lock is poisoned");
+        if listings.len() >= MAX_CACHED_LISTINGS && !listings.contains_key(&inode) {
+            for cached_inode in listings.keys() {
+                self.memory_budget
+                    .release(CacheKind::DirectoryListing, *cached_inode);
+            }
+            listings.clear();
+        }
+        // Listings which were evicted from memory, or read by an earlier mount, may be in the disk cache
+        if !listings.contains_key(&inode) {
+            if let Some((version, entries)) = self
+                .disk_cache
+                .as_ref()
+                .and_then(|cache| cache.read_listing(inode))
+            {
+                listings.insert(inode, CachedListing { version, entries });
+            }
+        }
+        let since_version = listings.get(&inode).map(|x| x.version);
+        let context = UserContext::new(req.uid(), req.gid());
+        let (version, listing) = match self.client.readdir_since(inode, since_version, context) {
+            Ok(result) => result,
+            Err(error_code) => {
+                if self.serve_stale() {
+                    if let Some(cached) = listings.get(&inode) {
+                        return Ok(cached.entries.clone());
+                    }
+                }
+                return Err(error_code);
+            }
+        };
+        let entries = match listing {
+            DirectoryListing::Full(entries) => entries,
+            DirectoryListing::Delta(added, removed) => {
+                let mut entries = listings
+                    .remove(&inode)
+                    .map(|x| x.entries)
+                    .ok_or(ErrorCode::BadResponse)?;
+                let changed: HashSet<&OsString> = added
+                    .iter()
+                    .map(|(_, name, _)| name)
+                    .chain(removed.iter())
+                    .collect();
+                entries.retain(|(_, name, _)| !changed.contains(name));
+                entries.extend(added.iter().cloned());
+                entries
+            }
+        };
+
+        if since_version != Some(version) {
+            if let Some(ref cache) = self.disk_cache {
+                cache.store_listing(inode, version, &entries);
+            }
+        }
+        listings.insert(
+            inode,
+            CachedListing {
+                version,
+                entries: entries.clone(),
+            },
+        );
+        drop(listings);
+        self.charge_cache(CacheKind::DirectoryListing, inode, listing_bytes(&entries));
+
+        Ok(entries)
+    }
+
+    // Charges an entry of one of the caches against the memory budget, and evicts the least recently used entries
+    // of every cache until they fit. The locks of the caches must not be held
+    fn charge_cache(&self, kind: CacheKind, key: u64, bytes: u64) {
+        for (kind, key) in self.memory_budget.charge(kind, key, bytes) {
+            match kind {
+                CacheKind::ReadAhead => {
+                    self.read_ahead_cache
+                        .lock()
+                        .expect("read_ahead_cache lock is poisoned")
+                        .remove(&key);
+                }
+                CacheKind::DirectoryListing => {
+                    self.directory_listings
+                        .lock()
+                        .expect("directory_listings lock is poisoned")
+                        .remove(&key);
+                }
+            }
         }
     }
 
-    fn allocate_file_handle(&self, read: bool, write: bool) -> u64 {
+    // Whether the mount has as many handles open as the cluster allows each client
+    fn handles_exhausted(&self) -> bool {
+        let max_open_handles = self.max_open_handles.load(Ordering::SeqCst);
+        max_open_handles > 0
+            && self
+                .file_handles
+                .lock()
+                .expect("file_handles lock is poisoned")
+                .len()
+                >= max_open_handles as usize
+    }
+
+    fn allocate_file_handle(&self, read: bool, write: bool, append: bool) -> u64 {
         let handle = self.next_file_handle.fetch_add(1, Ordering::SeqCst);
         let mut handles = self
             .file_handles
             .lock()
             .expect("file_handles lock is poisoned");
-        handles.insert(handle, FileHandleAttributes { read, write });
+        handles.insert(
+            handle,
+            FileHandleAttributes {
+                read,
+                write,
+                append,
+                log_cursor: None,
+                temporary: false,
+            },
+        );
 
         handle
     }
 
+    // Reads of the handle return the records of the shared log, like a character device
+    fn read_as_log(&self, handle: u64) {
+        let mut handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        if let Some(attributes) = handles.get_mut(&handle) {
+            attributes.append = true;
+            attributes.log_cursor = Some(0);
+        }
+    }
+
+    // Keeps a temporary file, which isn't linked into any directory, until the handle is released
+    fn register_temporary(&self, inode: u64, handle: u64) -> Result<(), ErrorCode> {
+        self.client.open_temporary(inode)?;
+        let mut handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        if let Some(attributes) = handles.get_mut(&handle) {
+            attributes.temporary = true;
+        }
+
+        Ok(())
+    }
+
+    fn is_temporary(&self, handle: u64) -> bool {
+        let handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        handles.get(&handle).map_or(false, |x| x.temporary)
+    }
+
+    fn log_cursor(&self, handle: u64) -> Option<u64> {
+        let handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        handles.get(&handle).and_then(|x| x.log_cursor)
+    }
+
     fn deallocate_file_handle(&self, handle: u64) {
         let mut handles = self
             .file_handles
@@ -83,6 +729,12 @@ impl FleetFUSE {
             .lock()
             .expect("read_ahead_cache lock is poisoned");
         read_cache.remove(&handle);
+        self.memory_budget.release(CacheKind::ReadAhead, handle);
+        let mut open_listings = self
+            .open_listings
+            .lock()
+            .expect("open_listings lock is poisoned");
+        open_listings.remove(&handle);
     }
 
     fn check_read(&self, handle: u64) -> bool {
@@ -98,6 +750,14 @@ impl FleetFUSE {
         }
     }
 
+    fn is_append(&self, handle: u64) -> bool {
+        let handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        handles.get(&handle).map_or(false, |x| x.append)
+    }
+
     fn check_write(&self, handle: u64) -> bool {
         let handles = self
             .file_handles
@@ -112,6 +772,16 @@ impl FleetFUSE {
     }
 }
 
+// F_RDLCK, F_WRLCK or F_UNLCK
+fn to_lock_kind(typ: u32) -> Option<LockKind> {
+    match typ as c_int {
+        libc::F_RDLCK => Some(LockKind::Shared),
+        libc::F_WRLCK => Some(LockKind::Exclusive),
+        libc::F_UNLCK => Some(LockKind::Unlock),
+        _ => None,
+    }
+}
+
 fn into_fuse_error(error: ErrorCode) -> c_int {
     match error {
         ErrorCode::DoesNotExist => libc::ENOENT,
@@ -128,10 +798,39 @@ fn into_fuse_error(error: ErrorCode) -> c_int {
         ErrorCode::NotEmpty => libc::ENOTEMPTY,
         ErrorCode::MissingXattrKey => libc::ENODATA,
         ErrorCode::AlreadyExists => libc::EEXIST,
+        ErrorCode::NotSupported => libc::ENOSYS,
+        ErrorCode::NoSpace => libc::ENOSPC,
+        ErrorCode::NotADirectory => libc::ENOTDIR,
+        ErrorCode::IsADirectory => libc::EISDIR,
+        ErrorCode::InvalidArgument => libc::EINVAL,
+        ErrorCode::TooManyLinks => libc::EMLINK,
+        ErrorCode::TimedOut => libc::ETIMEDOUT,
+        ErrorCode::ReadOnly => libc::EROFS,
+        ErrorCode::Interrupted => libc::EINTR,
+        ErrorCode::TooManySymlinks => libc::ELOOP,
+        ErrorCode::VersionMismatch => libc::ESTALE,
+        ErrorCode::RequestTooLarge => libc::EMSGSIZE,
+        ErrorCode::XattrTooLarge => libc::E2BIG,
+        ErrorCode::LockConflict => libc::EAGAIN,
+        ErrorCode::Deadlock => libc::EDEADLK,
+        ErrorCode::StaleFencingToken => libc::ESTALE,
+        ErrorCode::Recovering => libc::EAGAIN,
+        ErrorCode::TooManyConnections => libc::EAGAIN,
+        // Every later operation of the mount fails, until it's remounted
+        ErrorCode::ClientEvicted => libc::ESTALE,
+        ErrorCode::InvalidXattr => libc::EINVAL,
         ErrorCode::DefaultValueNotAnError => unreachable!(),
     }
 }
 
+// Approximate memory used by a cached listing
+fn listing_bytes(entries: &[DirectoryEntryTuple]) -> u64 {
+    entries
+        .iter()
+        .map(|(_, name, _)| (size_of::<DirectoryEntryTuple>() + name.len()) as u64)
+        .sum()
+}
+
 fn as_file_kind(mode: u32) -> FileKind {
     if mode & libc::S_IFREG != 0 {
         return FileKind::File;
@@ -144,14 +843,9 @@ fn as_file_kind(mode: u32) -> FileKind {
     }
 }
 
-impl Filesystem for FleetFUSE {
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        Ok(())
-    }
-
-    fn destroy(&mut self, _req: &Request) {}
-
-    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+// The operations which are run on the worker pool. They block on requests to the server
+impl FuseState {
+    fn lookup(&self, req: &Caller, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let name = if let Some(value) = name.to_str() {
             value
         } else {
@@ -160,11 +854,8 @@ impl Filesystem for FleetFUSE {
             return;
         };
         // TODO: avoid this double lookup
-        match self
-            .client
-            .lookup(parent, name, UserContext::new(req.uid(), req.gid()))
-        {
-            Ok(inode) => match self.client.getattr(inode) {
+        match self.lookup_or_stale(req, parent, name) {
+            Ok(inode) => match self.getattr_or_stale(inode) {
                 Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
                 Err(error_code) => reply.error(into_fuse_error(error_code)),
             },
@@ -172,19 +863,18 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
-
-    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
+    fn getattr(&self, inode: u64, reply: ReplyAttr) {
         debug!("getattr() called with {:?}", inode);
-        match self.client.getattr(inode) {
+        match self.getattr_or_stale(inode) {
             Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
             Err(error_code) => reply.error(into_fuse_error(error_code)),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn setattr(
-        &mut self,
-        req: &Request,
+        &self,
+        req: &Caller,
         inode: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -195,10 +885,6 @@ impl Filesystem for FleetFUSE {
         mtime: Option<SystemTime>,
         mtime_now: bool,
         fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
         if let Some(mode) = mode {
@@ -232,6 +918,10 @@ impl Filesystem for FleetFUSE {
 
         if let Some(size) = size {
             debug!("truncate() called with {:?}", inode);
+            if size > self.limits().max_file_size() {
+                reply.error(libc::EFBIG);
+                return;
+            }
             if let Some(handle) = fh {
                 // If the file handle is available, check access locally.
                 // This is important as it preserves the semantic that a file handle opened
@@ -255,6 +945,9 @@ impl Filesystem for FleetFUSE {
                 reply.error(into_fuse_error(error_code));
                 return;
             }
+            if let Some(ref cache) = self.disk_cache {
+                cache.invalidate(inode);
+            }
         }
 
         if atime.is_some() || mtime.is_some() {
@@ -293,7 +986,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn readlink(&mut self, req: &Request, inode: u64, reply: ReplyData) {
+    fn readlink(&self, req: &Caller, inode: u64, reply: ReplyData) {
         debug!("readlink() called on {:?}", inode);
         match self
             .client
@@ -304,15 +997,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn mknod(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
+    fn mknod(&self, req: &Caller, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
         let name = if let Some(value) = name.to_str() {
             value
         } else {
@@ -320,6 +1005,10 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
+        if let Err(error_code) = self.check_name(name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
         if (mode & (libc::S_IFREG | libc::S_IFLNK)) == 0 {
             // TODO
             warn!("mknod() implementation is incomplete. Only supports regular files and symlinks. Got {:o}", mode);
@@ -339,7 +1028,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+    fn mkdir(&self, req: &Caller, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
         debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
         let name = if let Some(value) = name.to_str() {
             value
@@ -348,8 +1037,12 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
-        match self
-            .client
+        if let Err(error_code) = self.check_name(name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
+        match self
+            .client
             .mkdir(parent, name, req.uid(), req.gid(), mode as u16)
         {
             Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
@@ -357,7 +1050,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&self, req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("unlink() called with {:?} {:?}", parent, name);
         let name = if let Some(value) = name.to_str() {
             value
@@ -376,7 +1069,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&self, req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("rmdir() called with {:?} {:?}", parent, name);
         let name = if let Some(value) = name.to_str() {
             value
@@ -395,14 +1088,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn symlink(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        link: &Path,
-        reply: ReplyEntry,
-    ) {
+    fn symlink(&self, req: &Caller, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
         debug!("symlink() called with {:?} {:?} {:?}", parent, name, link);
         let name = if let Some(value) = name.to_str() {
             value
@@ -419,6 +1105,10 @@ impl Filesystem for FleetFUSE {
             return;
         };
 
+        if let Err(error_code) = self.check_name(name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
         match self
             .client
             .create(parent, name, req.uid(), req.gid(), 0o755, FileKind::Symlink)
@@ -448,8 +1138,8 @@ impl Filesystem for FleetFUSE {
     }
 
     fn rename(
-        &mut self,
-        req: &Request,
+        &self,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
         new_parent: u64,
@@ -470,6 +1160,10 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
+        if let Err(error_code) = self.check_name(new_name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
         if let Err(error_code) = self.client.rename(
             parent,
             name,
@@ -483,14 +1177,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn link(
-        &mut self,
-        req: &Request,
-        inode: u64,
-        new_parent: u64,
-        new_name: &OsStr,
-        reply: ReplyEntry,
-    ) {
+    fn link(&self, req: &Caller, inode: u64, new_parent: u64, new_name: &OsStr, reply: ReplyEntry) {
         debug!(
             "link() called for {}, {}, {:?}",
             inode, new_parent, new_name
@@ -502,6 +1189,10 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
+        if let Err(error_code) = self.check_name(new_name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
         match self.client.hardlink(
             inode,
             new_parent,
@@ -513,8 +1204,12 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn open(&mut self, req: &Request, inode: u64, flags: u32, reply: ReplyOpen) {
+    fn open(&self, req: &Caller, inode: u64, flags: u32, reply: ReplyOpen) {
         debug!("open() called for {:?}", inode);
+        if self.handles_exhausted() {
+            reply.error(libc::EMFILE);
+            return;
+        }
         let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 // Behavior is undefined, but most filesystems return EACCES
@@ -532,9 +1227,13 @@ impl Filesystem for FleetFUSE {
                 return;
             }
         };
+        if write && self.serve_stale() && !self.available_offline(inode) {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-        match self.client.getattr(inode) {
-            Ok(attr) => {
+        match self.getattr_details_or_stale(inode) {
+            Ok((attr, details)) => {
                 if check_access(
                     attr.uid,
                     attr.gid,
@@ -543,7 +1242,31 @@ impl Filesystem for FleetFUSE {
                     req.gid(),
                     access_mask as u32,
                 ) {
-                    reply.opened(self.allocate_file_handle(read, write), 0);
+                    let handle =
+                        self.allocate_file_handle(read, write, flags as i32 & libc::O_APPEND != 0);
+                    // The details are only known if the attributes aren't stale
+                    match details {
+                        Some(details) if details.kind == FileKind::SharedLog => {
+                            self.read_as_log(handle);
+                            reply.opened(handle, FOPEN_DIRECT_IO);
+                            return;
+                        }
+                        Some(_) if attr.nlink == 0 => {
+                            if let Err(error_code) = self.register_temporary(inode, handle) {
+                                self.deallocate_file_handle(handle);
+                                reply.error(into_fuse_error(error_code));
+                                return;
+                            }
+                        }
+                        Some(details) => {
+                            self.cache_offline(req, inode, &attr, details.data_version);
+                            if let Some(ref cache) = self.disk_cache {
+                                cache.validate(inode, details.data_version, attr.size);
+                            }
+                        }
+                        None => {}
+                    }
+                    reply.opened(handle, 0);
                     return;
                 } else {
                     reply.error(libc::EACCES);
@@ -554,21 +1277,16 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn read(
-        &mut self,
-        req: &Request,
-        inode: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        reply: ReplyData,
-    ) {
+    fn read(&self, req: &Caller, inode: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
         debug!("read() called on {:?}", inode);
         assert!(offset >= 0);
         if !self.check_read(fh) {
             reply.error(libc::EACCES);
             return;
         }
+        let pattern = self
+            .access_patterns
+            .record(req.pid(), inode, offset as u64, size);
 
         {
             let mut read_cache = self
@@ -576,34 +1294,73 @@ impl Filesystem for FleetFUSE {
                 .lock()
                 .expect("read_ahead_cache lock is poisoned");
             if let Some(cached) = read_cache.get_mut(&fh) {
-                if cached.file_offset == offset as u64
+                // Strided reads skip the data between them
+                let skipped = (offset as u64).wrapping_sub(cached.file_offset);
+                if offset as u64 >= cached.file_offset
                     && req.pid() == cached.process_id
-                    && size <= cached.data.len() as u32
+                    && skipped + u64::from(size) <= cached.data.len() as u64
                     && cached.read_at.elapsed() < Duration::from_millis(READ_AHEAD_CACHE_TTL_MS)
                 {
+                    cached.data.advance(skipped as usize);
                     reply.data(&cached.data[0..size as usize]);
                     cached.data.advance(size as usize);
-                    cached.file_offset += u64::from(size);
-                    // TODO: should drop cached reads that have been fully consumed
+                    cached.file_offset = offset as u64 + u64::from(size);
+                    if cached.data.is_empty() {
+                        read_cache.remove(&fh);
+                        self.memory_budget.release(CacheKind::ReadAhead, fh);
+                    } else {
+                        self.memory_budget.touch(CacheKind::ReadAhead, fh);
+                    }
                     return;
                 }
             }
         }
 
-        if size >= FUSE_MAX_READ_SIZE {
+        if self.serve_stale() {
+            if let Some(ref offline) = self.offline {
+                match offline.read(inode, offset as u64, size) {
+                    Ok(Some(data)) => {
+                        reply.data(&data);
+                        return;
+                    }
+                    Ok(None) => {}
+                    Err(error) => {
+                        warn!("Failed to read inode {} offline: {:?}", inode, error);
+                        reply.error(libc::EIO);
+                        return;
+                    }
+                }
+            }
+        }
+
+        if let Some(cursor) = self.log_cursor(fh) {
+            self.read_log_record(req, inode, fh, cursor, size, reply);
+            return;
+        }
+
+        if let Some(ref cache) = self.disk_cache {
+            self.read_through_disk_cache(cache, req, inode, offset as u64, size, reply);
+            return;
+        }
+
+        let prefetch_size = prefetch_size(pattern, size);
+        if prefetch_size > size {
             match self.client.read_to_vec(
                 inode,
                 offset as u64,
-                SPECULATIVE_READ_SIZE,
+                prefetch_size,
                 UserContext::new(req.uid(), req.gid()),
+                self.atime_mode,
             ) {
                 Ok(data) => {
                     let mut read_cache = self
                         .read_ahead_cache
                         .lock()
                         .expect("read_ahead_cache lock is poisoned");
-                    reply.data(&data[0..size as usize]);
+                    reply.data(&data[0..data.len().min(size as usize)]);
                     if data.len() > size as usize {
+                        // The whole buffer is kept until the rest of it has been read
+                        let bytes = data.len() as u64;
                         let mut data_bytes = Bytes::from(data);
                         data_bytes.advance(size as usize);
                         let cached = CachedRead {
@@ -614,16 +1371,32 @@ impl Filesystem for FleetFUSE {
                         };
 
                         read_cache.insert(fh, cached);
+                        drop(read_cache);
+                        self.charge_cache(CacheKind::ReadAhead, fh, bytes);
                     }
                 }
                 Err(error_code) => reply.error(into_fuse_error(error_code)),
             }
+        } else if let (Some(ref coalescer), true) =
+            (&self.read_coalescer, size <= MAX_COALESCED_READ)
+        {
+            let context = UserContext::new(req.uid(), req.gid());
+            let key = (inode, req.uid(), req.gid());
+            let read = coalescer.read(key, offset as u64, size, |offset, size| {
+                self.client
+                    .read_to_vec(inode, offset, size, context, self.atime_mode)
+            });
+            match read {
+                Ok(data) => reply.data(&data),
+                Err(error_code) => reply.error(into_fuse_error(error_code)),
+            }
         } else {
             self.client.read(
                 inode,
                 offset as u64,
                 size,
                 UserContext::new(req.uid(), req.gid()),
+                self.atime_mode,
                 move |result| match result {
                     Ok(data) => reply.data(data),
                     Err(error_code) => reply.error(into_fuse_error(error_code)),
@@ -632,14 +1405,86 @@ impl Filesystem for FleetFUSE {
         }
     }
 
+    // Returns the next record of the shared log, or no data if there isn't one yet
+    fn read_log_record(
+        &self,
+        req: &Caller,
+        inode: u64,
+        fh: u64,
+        cursor: u64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let context = UserContext::new(req.uid(), req.gid());
+        let (records, _) = match self.client.read_log(inode, cursor, size, context) {
+            Ok(result) => result,
+            Err(error_code) => {
+                reply.error(into_fuse_error(error_code));
+                return;
+            }
+        };
+        let (record_offset, data) = match records.first() {
+            Some(record) => record,
+            None => {
+                reply.data(&[]);
+                return;
+            }
+        };
+        // Like /dev/kmsg, records which don't fit in the buffer aren't split
+        if data.len() > size as usize {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let mut handles = self
+            .file_handles
+            .lock()
+            .expect("file_handles lock is poisoned");
+        if let Some(attributes) = handles.get_mut(&fh) {
+            attributes.log_cursor =
+                Some(record_offset + (LOG_RECORD_HEADER_SIZE + data.len()) as u64);
+        }
+        reply.data(data);
+    }
+
+    // Reads the blocks which aren't cached from the server, and caches them
+    fn read_through_disk_cache(
+        &self,
+        cache: &DiskCache,
+        req: &Caller,
+        inode: u64,
+        offset: u64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        if let Some(data) = cache.read(inode, offset, size) {
+            reply.data(&data);
+            return;
+        }
+        let (aligned_offset, aligned_size) = aligned_range(offset, size);
+        match self.client.read_to_vec(
+            inode,
+            aligned_offset,
+            aligned_size,
+            UserContext::new(req.uid(), req.gid()),
+            self.atime_mode,
+        ) {
+            Ok(data) => {
+                cache.insert(inode, aligned_offset, &data);
+                let start = ((offset - aligned_offset) as usize).min(data.len());
+                let end = data.len().min(start + size as usize);
+                reply.data(&data[start..end]);
+            }
+            Err(error_code) => reply.error(into_fuse_error(error_code)),
+        }
+    }
+
     fn write(
-        &mut self,
-        req: &Request,
+        &self,
+        req: &Caller,
         inode: u64,
         fh: u64,
         offset: i64,
-        data: &[u8],
-        _flags: u32,
+        data: WriteData,
         reply: ReplyWrite,
     ) {
         debug!("write() called with {:?}", inode);
@@ -648,38 +1493,223 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EACCES);
             return;
         }
-        match self.client.write(
-            inode,
-            &data,
-            offset as u64,
-            UserContext::new(req.uid(), req.gid()),
-        ) {
-            Ok(written) => reply.written(written),
+        let length = data.bytes().len();
+        if !self.is_append(fh) && offset as u64 + length as u64 > self.limits().max_file_size() {
+            reply.error(libc::EFBIG);
+            return;
+        }
+        let context = UserContext::new(req.uid(), req.gid());
+        if self.serve_stale() {
+            self.write_offline(inode, offset as u64, data.bytes(), context, reply);
+            return;
+        }
+        // The offset from the kernel may be stale, if another client has appended since
+        let written = if self.is_append(fh) {
+            self.client
+                .append(inode, data.bytes(), context)
+                .map(|_| length as u32)
+        } else {
+            match data {
+                WriteData::Copied(data) => self.client.write(inode, &data, offset as u64, context),
+                WriteData::Encoded(encoded) => {
+                    self.client
+                        .write_encoded(inode, encoded, offset as u64, context)
+                }
+            }
+        };
+        match written {
+            Ok(written) => {
+                // The local copy no longer matches, so it'll be fetched again on the next open
+                if let Some(ref offline) = self.offline {
+                    offline.evict(inode);
+                }
+                if let Some(ref cache) = self.disk_cache {
+                    cache.invalidate(inode);
+                }
+                reply.written(written);
+            }
             Err(error_code) => reply.error(into_fuse_error(error_code)),
         }
     }
 
-    fn flush(&mut self, _req: &Request, inode: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        debug!("flush() called on {:?}", inode);
-        reply.error(ENOSYS);
+    // "file <bytes> <age>" and "mount <bytes> <age>" lines, with the bytes written to inode and to the mount which
+    // aren't on the cluster yet, and the age in milliseconds of the oldest such write
+    fn sync_status(&self, inode: u64) -> String {
+        let (file, mount) = match self.offline {
+            Some(ref offline) => (
+                offline.pending_writes(Some(inode)),
+                offline.pending_writes(None),
+            ),
+            None => Default::default(),
+        };
+        format!(
+            "file {} {}\nmount {} {}\n",
+            file.bytes,
+            file.oldest_age().as_millis(),
+            mount.bytes,
+            mount.oldest_age().as_millis()
+        )
     }
 
-    fn release(
-        &mut self,
-        _req: &Request,
+    // Journals the write, to replay it once the server is reachable
+    fn write_offline(
+        &self,
         inode: u64,
-        fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
-        reply: ReplyEmpty,
+        offset: u64,
+        data: &[u8],
+        context: UserContext,
+        reply: ReplyWrite,
     ) {
+        let offline = match self.offline {
+            Some(ref offline) if offline.is_cached(inode) => offline,
+            _ => {
+                reply.error(libc::EROFS);
+                return;
+            }
+        };
+        if let Err(error) = offline.write(inode, offset, data, context) {
+            warn!("Failed to journal write to inode {}: {:?}", inode, error);
+            reply.error(libc::EIO);
+            return;
+        }
+        if let Some(mut cache) = self.stale_cache() {
+            if let Some(attr) = cache.attributes.get_mut(&inode) {
+                attr.size = attr.size.max(offset + data.len() as u64);
+                attr.mtime = SystemTime::now();
+            }
+        }
+        reply.written(data.len() as u32);
+    }
+
+    fn release(&self, inode: u64, fh: u64, reply: ReplyEmpty) {
         debug!("release() called on {:?} {}", inode, fh);
+        self.access_patterns.forget(inode);
+        if let Some(ref coalescer) = self.read_coalescer {
+            coalescer.forget(inode);
+        }
+        let released = if self.check_write(fh) || self.is_temporary(fh) {
+            self.client.release(inode)
+        } else {
+            Ok(())
+        };
         self.deallocate_file_handle(fh);
+        if let Err(error_code) = released {
+            reply.error(into_fuse_error(error_code));
+        } else {
+            reply.ok();
+        }
+    }
+
+    // Writes to the inode carry the latest fencing token of any of its lock owners
+    fn update_fencing_token(&self, lock_owners: &HashMap<(u64, u64), u64>, inode: u64) {
+        let fencing_token = lock_owners
+            .iter()
+            .filter(|((locked_inode, _), _)| *locked_inode == inode)
+            .map(|(_, token)| *token)
+            .max()
+            .unwrap_or(0);
+        self.client.set_fencing_token(inode, fencing_token);
+    }
+
+    // Closing a file releases the locks its owner holds on it
+    fn flush(&self, inode: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush() called on {:?}", inode);
+        let locked = {
+            let mut lock_owners = self
+                .lock_owners
+                .lock()
+                .expect("lock_owners lock is poisoned");
+            let locked = lock_owners.remove(&(inode, lock_owner)).is_some();
+            self.update_fencing_token(&lock_owners, inode);
+            locked
+        };
+        if locked {
+            if let Err(error_code) =
+                self.client
+                    .lock(inode, lock_owner, 0, 0, u64::MAX, LockKind::Unlock, false)
+            {
+                reply.error(into_fuse_error(error_code));
+                return;
+            }
+        }
         reply.ok();
     }
 
-    fn fsync(&mut self, _req: &Request, inode: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+    fn getlk(&self, inode: u64, lock_owner: u64, start: u64, end: u64, typ: u32, reply: ReplyLock) {
+        debug!("getlk() called on {:?} {}-{}", inode, start, end);
+        let kind = match to_lock_kind(typ) {
+            Some(kind) => kind,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        match self.client.test_lock(inode, lock_owner, start, end, kind) {
+            Ok(Some(lock)) => {
+                let typ = if lock.exclusive {
+                    libc::F_WRLCK
+                } else {
+                    libc::F_RDLCK
+                };
+                reply.locked(lock.start, lock.end, typ as u32, lock.pid);
+            }
+            Ok(None) => reply.locked(start, end, libc::F_UNLCK as u32, 0),
+            Err(error_code) => reply.error(into_fuse_error(error_code)),
+        }
+    }
+
+    // If wait is set, retries until the lock is granted, or the leader reports that waiting would deadlock
+    #[allow(clippy::too_many_arguments)]
+    fn setlk(
+        &self,
+        inode: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        wait: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!("setlk() called on {:?} {}-{}", inode, start, end);
+        let kind = match to_lock_kind(typ) {
+            Some(kind) => kind,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let mut delay = LOCK_RETRY_DELAY;
+        let fencing_token = loop {
+            match self
+                .client
+                .lock(inode, lock_owner, pid, start, end, kind, wait)
+            {
+                Ok(fencing_token) => break fencing_token,
+                Err(ErrorCode::LockConflict) if wait => {
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(LOCK_RETRY_MAX_DELAY);
+                }
+                Err(error_code) => {
+                    reply.error(into_fuse_error(error_code));
+                    return;
+                }
+            }
+        };
+        if kind != LockKind::Unlock {
+            let mut lock_owners = self
+                .lock_owners
+                .lock()
+                .expect("lock_owners lock is poisoned");
+            // Tokens only grow, so a shared lock keeps the token of the owner's exclusive locks
+            let token = lock_owners.entry((inode, lock_owner)).or_insert(0);
+            *token = (*token).max(fencing_token);
+            self.update_fencing_token(&lock_owners, inode);
+        }
+        reply.ok();
+    }
+
+    fn fsync(&self, inode: u64, reply: ReplyEmpty) {
         debug!("fsync() called with {:?}", inode);
         if let Err(error_code) = self.client.fsync(inode) {
             reply.error(into_fuse_error(error_code));
@@ -688,8 +1718,12 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn opendir(&mut self, req: &Request, inode: u64, flags: u32, reply: ReplyOpen) {
+    fn opendir(&self, req: &Caller, inode: u64, flags: u32, reply: ReplyOpen) {
         debug!("opendir() called on {:?}", inode);
+        if self.handles_exhausted() {
+            reply.error(libc::EMFILE);
+            return;
+        }
         let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 // Behavior is undefined, but most filesystems return EACCES
@@ -707,8 +1741,12 @@ impl Filesystem for FleetFUSE {
                 return;
             }
         };
+        if write && self.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
 
-        match self.client.getattr(inode) {
+        match self.getattr_or_stale(inode) {
             Ok(attr) => {
                 if check_access(
                     attr.uid,
@@ -718,7 +1756,7 @@ impl Filesystem for FleetFUSE {
                     req.gid(),
                     access_mask as u32,
                 ) {
-                    reply.opened(self.allocate_file_handle(read, write), 0);
+                    reply.opened(self.allocate_file_handle(read, write, false), 0);
                     return;
                 } else {
                     reply.error(libc::EACCES);
@@ -729,18 +1767,75 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    // TODO: send offset to server and do pagination
+    // Fetches the attributes of every entry in one request, like readdirplus, so that a listing which is
+    // followed by a stat of each entry can still be served if the server becomes unreachable
+    fn cache_listed_attributes(&self, parent: u64, entries: &[DirectoryEntryTuple]) {
+        if self.stale_cache.is_none() || self.serve_stale() {
+            return;
+        }
+        let inodes: Vec<u64> = entries.iter().map(|(inode, _, _)| *inode).collect();
+        let attributes = match self.client.getattr_many(&inodes) {
+            Ok(attributes) => attributes,
+            Err(error_code) => {
+                debug!("Failed to read attributes of listing: {:?}", error_code);
+                return;
+            }
+        };
+        if let Some(mut cache) = self.stale_cache() {
+            if cache.attributes.len() + entries.len() > MAX_STALE_ENTRIES {
+                cache.attributes.clear();
+            }
+            if cache.entries.len() + entries.len() > MAX_STALE_ENTRIES {
+                cache.entries.clear();
+            }
+            for ((inode, name, _), attr) in entries.iter().zip(attributes) {
+                if let Ok(attr) = attr {
+                    cache.attributes.insert(*inode, attr);
+                    cache.entries.insert((parent, name.clone()), *inode);
+                }
+            }
+        }
+    }
+
+    // Reading from offset 0, including after rewinddir(), takes a new listing
+    fn open_listing(
+        &self,
+        req: &Caller,
+        inode: u64,
+        handle: u64,
+        offset: i64,
+    ) -> Result<Arc<Vec<DirectoryEntryTuple>>, ErrorCode> {
+        if offset > 0 {
+            let open_listings = self
+                .open_listings
+                .lock()
+                .expect("open_listings lock is poisoned");
+            if let Some(entries) = open_listings.get(&handle) {
+                return Ok(entries.clone());
+            }
+        }
+        let entries = Arc::new(self.list_directory(req, inode)?);
+        self.cache_listed_attributes(inode, &entries);
+        let mut open_listings = self
+            .open_listings
+            .lock()
+            .expect("open_listings lock is poisoned");
+        open_listings.insert(handle, entries.clone());
+
+        Ok(entries)
+    }
+
     fn readdir(
-        &mut self,
-        _req: &Request,
+        &self,
+        req: &Caller,
         inode: u64,
-        _fh: u64,
+        handle: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
         debug!("readdir() called with {:?}", inode);
         assert!(offset >= 0);
-        match self.client.readdir(inode) {
+        match self.open_listing(req, inode, handle, offset) {
             Ok(entries) => {
                 for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
                     let (inode, name, file_type) = entry;
@@ -759,20 +1854,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn releasedir(&mut self, _req: &Request, inode: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
-        debug!("releasedir() called on {:?} {}", inode, fh);
-        self.deallocate_file_handle(fh);
-        reply.ok();
-    }
-
-    fn fsyncdir(
-        &mut self,
-        _req: &Request,
-        inode: u64,
-        _fh: u64,
-        _datasync: bool,
-        reply: ReplyEmpty,
-    ) {
+    fn fsyncdir(&self, inode: u64, reply: ReplyEmpty) {
         debug!("fsyncdir() called with {:?}", inode);
         if let Err(error_code) = self.client.fsync(inode) {
             reply.error(into_fuse_error(error_code));
@@ -781,22 +1863,27 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        warn!("statfs() implementation is a stub");
-        // TODO: real implementation of this
-        reply.statfs(10, 10, 10, 1, 10, 4096, MAX_NAME_LENGTH, 4096);
+    fn statfs(&self, reply: ReplyStatfs) {
+        debug!("statfs() called");
+        match self.client.filesystem_information() {
+            Ok(information) => {
+                *self.limits.lock().expect("limits lock is poisoned") = information.limits;
+                reply.statfs(
+                    information.total_blocks,
+                    information.free_blocks,
+                    information.available_blocks,
+                    information.files,
+                    information.free_files,
+                    information.block_size,
+                    information.limits.max_name_length(),
+                    information.block_size,
+                )
+            }
+            Err(error_code) => reply.error(into_fuse_error(error_code)),
+        }
     }
 
-    fn setxattr(
-        &mut self,
-        _req: &Request,
-        inode: u64,
-        name: &OsStr,
-        value: &[u8],
-        _flags: u32,
-        _position: u32,
-        reply: ReplyEmpty,
-    ) {
+    fn setxattr(&self, req: &Caller, inode: u64, name: &OsStr, value: &[u8], reply: ReplyEmpty) {
         debug!("setxattr() called with {:?} {:?} {:?}", inode, name, value);
         let name = if let Some(value) = name.to_str() {
             value
@@ -805,14 +1892,21 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
-        if let Err(error_code) = self.client.setxattr(inode, name, value) {
+        if value.len() > self.limits().max_xattr_size() as usize {
+            reply.error(libc::E2BIG);
+            return;
+        }
+        if let Err(error_code) =
+            self.client
+                .setxattr(inode, name, value, UserContext::new(req.uid(), req.gid()))
+        {
             reply.error(into_fuse_error(error_code));
         } else {
             reply.ok();
         }
     }
 
-    fn getxattr(&mut self, _req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+    fn getxattr(&self, req: &Caller, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         debug!("getxattr() called with {:?} {:?}", inode, name);
         let name = if let Some(value) = name.to_str() {
             value
@@ -821,7 +1915,19 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
-        match self.client.getxattr(inode, name) {
+        let value = if name == CLIENT_MEMORY_XATTR {
+            Ok(self.memory_budget.report().into_bytes())
+        } else if name == FLUSH_EPOCH_XATTR {
+            self.client
+                .flush_epoch()
+                .map(|epoch| epoch.to_string().into_bytes())
+        } else if name == SYNC_STATUS_XATTR {
+            Ok(self.sync_status(inode).into_bytes())
+        } else {
+            self.client
+                .getxattr(inode, name, UserContext::new(req.uid(), req.gid()))
+        };
+        match value {
             Ok(data) => {
                 if size == 0 {
                     reply.size(data.len() as u32);
@@ -835,7 +1941,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn listxattr(&mut self, _req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
+    fn listxattr(&self, inode: u64, size: u32, reply: ReplyXattr) {
         debug!("listxattr() called with {:?}", inode);
         match self.client.listxattr(inode).map(|xattrs| {
             let mut bytes = vec![];
@@ -859,7 +1965,7 @@ impl Filesystem for FleetFUSE {
         }
     }
 
-    fn removexattr(&mut self, _req: &Request, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn removexattr(&self, req: &Caller, inode: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("removexattr() called with {:?} {:?}", inode, name);
         let name = if let Some(value) = name.to_str() {
             value
@@ -868,16 +1974,17 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
-        if let Err(error_code) = self.client.removexattr(inode, name) {
+        let context = UserContext::new(req.uid(), req.gid());
+        if let Err(error_code) = self.client.removexattr(inode, name, context) {
             reply.error(into_fuse_error(error_code));
         } else {
             reply.ok();
         }
     }
 
-    fn access(&mut self, req: &Request, inode: u64, mask: u32, reply: ReplyEmpty) {
+    fn access(&self, req: &Caller, inode: u64, mask: u32, reply: ReplyEmpty) {
         debug!("access() called with {:?} {:?}", inode, mask);
-        match self.client.getattr(inode) {
+        match self.getattr_or_stale(inode) {
             Ok(attr) => {
                 if check_access(attr.uid, attr.gid, attr.perm, req.uid(), req.gid(), mask) {
                     reply.ok();
@@ -890,8 +1997,8 @@ impl Filesystem for FleetFUSE {
     }
 
     fn create(
-        &mut self,
-        req: &Request,
+        &self,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -899,6 +2006,10 @@ impl Filesystem for FleetFUSE {
         reply: ReplyCreate,
     ) {
         debug!("create() called with {:?} {:?}", parent, name);
+        if self.handles_exhausted() {
+            reply.error(libc::EMFILE);
+            return;
+        }
         let name = if let Some(value) = name.to_str() {
             value
         } else {
@@ -906,6 +2017,10 @@ impl Filesystem for FleetFUSE {
             reply.error(libc::EINVAL);
             return;
         };
+        if let Err(error_code) = self.check_name(name) {
+            reply.error(into_fuse_error(error_code));
+            return;
+        }
         let (read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => (true, false),
             libc::O_WRONLY => (false, true),
@@ -930,43 +2045,374 @@ impl Filesystem for FleetFUSE {
                     &Duration::new(0, 0),
                     &attr,
                     0,
-                    self.allocate_file_handle(read, write),
+                    self.allocate_file_handle(read, write, flags as i32 & libc::O_APPEND != 0),
                     0,
                 )
             }
             Err(error_code) => reply.error(into_fuse_error(error_code)),
         }
     }
+}
+
+impl Filesystem for FleetFUSE {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        self.state.refresh_limits();
+        Ok(())
+    }
+
+    fn destroy(&mut self, _req: &Request) {}
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.lookup(&caller, parent, &name, reply));
+    }
+
+    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
+
+    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
+        self.dispatch(move |state| state.getattr(inode, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        atime_now: bool,
+        mtime: Option<SystemTime>,
+        mtime_now: bool,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // The stale cache is read-only
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        self.dispatch(move |state| {
+            state.setattr(
+                &caller, inode, mode, uid, gid, size, atime, atime_now, mtime, mtime_now, fh, reply,
+            )
+        });
+    }
+
+    fn readlink(&mut self, req: &Request, inode: u64, reply: ReplyData) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.readlink(&caller, inode, reply));
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.mknod(&caller, parent, &name, mode, reply));
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.mkdir(&caller, parent, &name, mode, reply));
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.unlink(&caller, parent, &name, reply));
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.rmdir(&caller, parent, &name, reply));
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        let link = link.to_path_buf();
+        self.dispatch(move |state| state.symlink(&caller, parent, &name, &link, reply));
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        let new_name = new_name.to_os_string();
+        self.dispatch(move |state| {
+            state.rename(&caller, parent, &name, new_parent, &new_name, reply)
+        });
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let new_name = new_name.to_os_string();
+        self.dispatch(move |state| state.link(&caller, inode, new_parent, &new_name, reply));
+    }
+
+    fn open(&mut self, req: &Request, inode: u64, flags: u32, reply: ReplyOpen) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.open(&caller, inode, flags, reply));
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.read(&caller, inode, fh, offset, size, reply));
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        // Writes to files cached for offline use are journaled while the server is unreachable
+        if self.state.serve_stale() && !self.state.available_offline(inode) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        // Writes at an offset are copied straight into their request, instead of into a buffer for the worker
+        let data = if self.state.is_append(fh) {
+            WriteData::Copied(data.to_vec())
+        } else {
+            WriteData::Encoded(EncodedWrite::new(data))
+        };
+        self.dispatch(move |state| state.write(&caller, inode, fh, offset, data, reply));
+    }
+
+    fn flush(&mut self, _req: &Request, inode: u64, _fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.dispatch(move |state| state.flush(inode, lock_owner, reply));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        inode: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.dispatch(move |state| state.release(inode, fh, reply));
+    }
+
+    fn fsync(&mut self, _req: &Request, inode: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.dispatch(move |state| state.fsync(inode, reply));
+    }
+
+    fn opendir(&mut self, req: &Request, inode: u64, flags: u32, reply: ReplyOpen) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.opendir(&caller, inode, flags, reply));
+    }
+
+    fn readdir(&mut self, req: &Request, inode: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.readdir(&caller, inode, fh, offset, reply));
+    }
+
+    fn releasedir(&mut self, _req: &Request, inode: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        debug!("releasedir() called on {:?} {}", inode, fh);
+        self.state.deallocate_file_handle(fh);
+        reply.ok();
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request,
+        inode: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.dispatch(move |state| state.fsyncdir(inode, reply));
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.dispatch(move |state| state.statfs(reply));
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        let value = value.to_vec();
+        self.dispatch(move |state| state.setxattr(&caller, inode, &name, &value, reply));
+    }
+
+    fn getxattr(&mut self, req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.getxattr(&caller, inode, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, _req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
+        self.dispatch(move |state| state.listxattr(inode, size, reply));
+    }
+
+    fn removexattr(&mut self, req: &Request, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.removexattr(&caller, inode, &name, reply));
+    }
+
+    fn access(&mut self, req: &Request, inode: u64, mask: u32, reply: ReplyEmpty) {
+        let caller = Caller::new(req);
+        self.dispatch(move |state| state.access(&caller, inode, mask, reply));
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        if self.state.serve_stale() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let caller = Caller::new(req);
+        let name = name.to_os_string();
+        self.dispatch(move |state| state.create(&caller, parent, &name, mode, flags, reply));
+    }
 
     fn getlk(
         &mut self,
         _req: &Request,
-        _ino: u64,
+        inode: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
         _pid: u32,
         reply: ReplyLock,
     ) {
-        reply.error(ENOSYS);
+        self.dispatch(move |state| state.getlk(inode, lock_owner, start, end, typ, reply));
     }
 
     fn setlk(
         &mut self,
         _req: &Request,
-        _ino: u64,
+        inode: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
-        _pid: u32,
-        _sleep: bool,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
         reply: ReplyEmpty,
     ) {
-        reply.error(ENOSYS);
+        if sleep {
+            // Waiting can take arbitrarily long, so it gets its own thread, instead of holding a worker which the
+            // request that releases the lock may need
+            let state = self.state.clone();
+            thread::spawn(move || {
+                state.setlk(inode, lock_owner, start, end, typ, pid, true, reply)
+            });
+        } else {
+            self.dispatch(move |state| {
+                state.setlk(inode, lock_owner, start, end, typ, pid, false, reply)
+            });
+        }
     }
 
     fn bmap(&mut self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
@@ -0,0 +1,320 @@
+use crate::generated::*;
+use crate::storage::ROOT_INODE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Lookup,
+    Read,
+    Write,
+    Truncate,
+    Create,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Rename,
+    Hardlink,
+    Chmod,
+    Chown,
+    Utimens,
+    Find,
+    Search,
+    Readdir,
+    GetXattr,
+    SetXattr,
+    RemoveXattr,
+}
+
+// Policy consulted for every request made on behalf of a user, in addition to the UNIX permission checks.
+// It runs on the node which received the request, before the request is proposed, so that policy decisions
+// are made exactly once and can't cause the replicas to diverge.
+// inode is the file operated on, or the parent directory for operations on directory entries.
+// session_id is 0 if the client did not open a session
+pub trait Authorizer: Send + Sync {
+    fn authorize(
+        &self,
+        inode: u64,
+        operation: Operation,
+        context: UserContext,
+        session_id: u64,
+    ) -> bool;
+}
+
+// Default policy, which only relies on the UNIX permission checks
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _: u64, _: Operation, _: UserContext, _: u64) -> bool {
+        return true;
+    }
+}
+
+// Whether authorizer allows request. Requests which aren't subject to authorization are always allowed
+pub fn is_authorized(authorizer: &Authorizer, request: &GenericRequest) -> bool {
+    if let Some((inode, operation, context)) = authorization_target(request) {
+        return authorizer.authorize(inode, operation, context, request.session_id());
+    }
+
+    return true;
+}
+
+// Returns the inode, operation, and user of requests which are subject to authorization
+pub fn authorization_target(request: &GenericRequest) -> Option<(u64, Operation, UserContext)> {
+    match request.request_type() {
+        RequestType::LookupRequest => request
+            .request_as_lookup_request()
+            .map(|x| (x.parent(), Operation::Lookup, *x.context())),
+        // Each component is checked by its directory's permissions as it's resolved
+        RequestType::ResolvePathRequest => request
+            .request_as_resolve_path_request()
+            .map(|x| (ROOT_INODE, Operation::Lookup, *x.context())),
+        RequestType::ReadRequest => request
+            .request_as_read_request()
+            .map(|x| (x.inode(), Operation::Read, *x.context())),
+        RequestType::WriteRequest => request
+            .request_as_write_request()
+            .map(|x| (x.inode(), Operation::Write, *x.context())),
+        RequestType::VerifyFileRequest => request
+            .request_as_verify_file_request()
+            .map(|x| (x.inode(), Operation::Read, *x.context())),
+        RequestType::PrefetchRequest => request
+            .request_as_prefetch_request()
+            .map(|x| (x.inode(), Operation::Read, *x.context())),
+        RequestType::FileBlockHashesRequest => request
+            .request_as_file_block_hashes_request()
+            .map(|x| (x.inode(), Operation::Read, *x.context())),
+        RequestType::WritePatchRequest => request
+            .request_as_write_patch_request()
+            .map(|x| (x.inode(), Operation::Write, *x.context())),
+        RequestType::WriteConditionalRequest => request
+            .request_as_write_conditional_request()
+            .map(|x| (x.inode(), Operation::Write, *x.context())),
+        RequestType::AppendRequest => request
+            .request_as_append_request()
+            .map(|x| (x.inode(), Operation::Write, *x.context())),
+        RequestType::TruncateRequest => request
+            .request_as_truncate_request()
+            .map(|x| (x.inode(), Operation::Truncate, *x.context())),
+        RequestType::CreateRequest => request.request_as_create_request().map(|x| {
+            let context = UserContext::new(x.uid(), x.gid());
+            (x.parent(), Operation::Create, context)
+        }),
+        RequestType::CreateTemporaryRequest => {
+            request.request_as_create_temporary_request().map(|x| {
+                let context = UserContext::new(x.uid(), x.gid());
+                (x.parent(), Operation::Create, context)
+            })
+        }
+        RequestType::MkdirRequest => request.request_as_mkdir_request().map(|x| {
+            let context = UserContext::new(x.uid(), x.gid());
+            (x.parent(), Operation::Mkdir, context)
+        }),
+        RequestType::UnlinkRequest => request
+            .request_as_unlink_request()
+            .map(|x| (x.parent(), Operation::Unlink, *x.context())),
+        RequestType::RmdirRequest => request
+            .request_as_rmdir_request()
+            .map(|x| (x.parent(), Operation::Rmdir, *x.context())),
+        RequestType::RenameRequest => request
+            .request_as_rename_request()
+            .map(|x| (x.parent(), Operation::Rename, *x.context())),
+        RequestType::HardlinkRequest => request
+            .request_as_hardlink_request()
+            .map(|x| (x.inode(), Operation::Hardlink, *x.context())),
+        RequestType::ChmodRequest => request
+            .request_as_chmod_request()
+            .map(|x| (x.inode(), Operation::Chmod, *x.context())),
+        RequestType::ChownRequest => request
+            .request_as_chown_request()
+            .map(|x| (x.inode(), Operation::Chown, *x.context())),
+        RequestType::UtimensRequest => request
+            .request_as_utimens_request()
+            .map(|x| (x.inode(), Operation::Utimens, *x.context())),
+        RequestType::FindRequest => request
+            .request_as_find_request()
+            .map(|x| (x.inode(), Operation::Find, *x.context())),
+        RequestType::ExportArchiveRequest => request
+            .request_as_export_archive_request()
+            .map(|x| (x.inode(), Operation::Find, *x.context())),
+        // Each directory is checked by its permissions as the pattern is expanded
+        RequestType::GlobRequest => request
+            .request_as_glob_request()
+            .map(|x| (ROOT_INODE, Operation::Find, *x.context())),
+        RequestType::SearchRequest => request
+            .request_as_search_request()
+            .map(|x| (ROOT_INODE, Operation::Search, *x.context())),
+        RequestType::ReaddirRequest => request
+            .request_as_readdir_request()
+            .map(|x| (x.inode(), Operation::Readdir, *x.context())),
+        RequestType::GetXattrRequest => request
+            .request_as_get_xattr_request()
+            .map(|x| (x.inode(), Operation::GetXattr, *x.context())),
+        RequestType::SetXattrRequest => request
+            .request_as_set_xattr_request()
+            .map(|x| (x.inode(), Operation::SetXattr, *x.context())),
+        RequestType::RemoveXattrRequest => request
+            .request_as_remove_xattr_request()
+            .map(|x| (x.inode(), Operation::RemoveXattr, *x.context())),
+        // Not subject to authorization, like stat() and listxattr() which need no permission on the file itself:
+        // GetattrRequest, BatchGetattrRequest, and ListXattrsRequest. Node to node requests, and requests which
+        // only act on a handle or lock the caller already holds, aren't either
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::*;
+    use crate::handlers::authorization::{is_authorized, AllowAll, Authorizer, Operation};
+    use crate::utils::{finalize_request, finalize_session_request};
+    use flatbuffers::FlatBufferBuilder;
+    use std::sync::Mutex;
+
+    // Denies the given operations, and records every decision it's asked to make
+    struct DenyOperations {
+        denied: Vec<Operation>,
+        calls: Mutex<Vec<(u64, Operation, u32, u64)>>,
+    }
+
+    impl DenyOperations {
+        fn new(denied: Vec<Operation>) -> DenyOperations {
+            DenyOperations {
+                denied,
+                calls: Mutex::new(vec![]),
+            }
+        }
+
+        fn calls(&self) -> Vec<(u64, Operation, u32, u64)> {
+            self.calls.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    impl Authorizer for DenyOperations {
+        fn authorize(
+            &self,
+            inode: u64,
+            operation: Operation,
+            context: UserContext,
+            session_id: u64,
+        ) -> bool {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((inode, operation, context.uid(), session_id));
+            return !self.denied.contains(&operation);
+        }
+    }
+
+    fn rename_request(session_id: u64) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("name");
+        let new_name = builder.create_string("new_name");
+        let mut request_builder = RenameRequestBuilder::new(&mut builder);
+        request_builder.add_parent(2);
+        request_builder.add_name(name);
+        request_builder.add_new_parent(3);
+        request_builder.add_new_name(new_name);
+        request_builder.add_context(&UserContext::new(1000, 1000));
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_session_request(
+            &mut builder,
+            RequestType::RenameRequest,
+            finish_offset,
+            session_id,
+            1,
+        );
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn unlink_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("name");
+        let mut request_builder = UnlinkRequestBuilder::new(&mut builder);
+        request_builder.add_parent(2);
+        request_builder.add_name(name);
+        request_builder.add_context(&UserContext::new(1000, 1000));
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::UnlinkRequest, finish_offset);
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn create_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("file");
+        let mut request_builder = CreateRequestBuilder::new(&mut builder);
+        request_builder.add_parent(2);
+        request_builder.add_name(name);
+        request_builder.add_uid(1001);
+        request_builder.add_gid(1001);
+        request_builder.add_mode(0o644);
+        request_builder.add_kind(FileKind::File);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::CreateRequest, finish_offset);
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn readdir_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = ReaddirRequestBuilder::new(&mut builder);
+        request_builder.add_inode(4);
+        request_builder.add_context(&UserContext::new(1000, 1000));
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::ReaddirRequest, finish_offset);
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn getattr_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = GetattrRequestBuilder::new(&mut builder);
+        request_builder.add_inode(4);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::GetattrRequest, finish_offset);
+        return builder.finished_data()[4..].to_vec();
+    }
+
+    fn authorized(authorizer: &Authorizer, data: &[u8]) -> bool {
+        return is_authorized(authorizer, &get_root_as_generic_request(data));
+    }
+
+    #[test]
+    fn denied_operations() {
+        let authorizer = DenyOperations::new(vec![Operation::Rename, Operation::Readdir]);
+
+        assert!(!authorized(&authorizer, &rename_request(7)));
+        // Operations on directory entries are authorized against the parent
+        assert_eq!(authorizer.calls(), vec![(2, Operation::Rename, 1000, 7)]);
+        assert!(!authorized(&authorizer, &readdir_request()));
+        assert_eq!(authorizer.calls(), vec![(4, Operation::Readdir, 1000, 0)]);
+
+        assert!(authorized(&authorizer, &unlink_request()));
+        assert_eq!(authorizer.calls(), vec![(2, Operation::Unlink, 1000, 0)]);
+        // Creates carry the user in their uid and gid, instead of a context
+        assert!(authorized(&authorizer, &create_request()));
+        assert_eq!(authorizer.calls(), vec![(2, Operation::Create, 1001, 0)]);
+    }
+
+    #[test]
+    fn requests_without_authorization() {
+        let authorizer = DenyOperations::new(vec![
+            Operation::Lookup,
+            Operation::Read,
+            Operation::Readdir,
+            Operation::GetXattr,
+        ]);
+        // Like stat(), getattr needs no permission on the file itself
+        assert!(authorized(&authorizer, &getattr_request()));
+        assert!(authorizer.calls().is_empty());
+    }
+
+    #[test]
+    fn allow_all() {
+        for request in vec![
+            rename_request(7),
+            unlink_request(),
+            create_request(),
+            readdir_request(),
+            getattr_request(),
+        ] {
+            assert!(authorized(&AllowAll, &request));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::client_registry::ClientStatus;
+use crate::generated::*;
+use crate::inflight_requests::{InflightHandle, RequestState};
+use crate::peer_client::PeerClient;
+use crate::storage::raft_manager::RaftManager;
+use crate::utils::{finalize_request, response_or_error, FlatBufferResponse, FutureResultResponse};
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{err, join_all, ok, Either};
+use futures::Future;
+use log::warn;
+use std::sync::Arc;
+
+// Assigns a client ID through Raft, so that it's unique in the cluster
+fn allocate_client_id(raft: &RaftManager) -> impl Future<Item = u64, Error = ErrorCode> {
+    let mut builder = FlatBufferBuilder::new();
+    let request_builder = AllocateClientIdRequestBuilder::new(&mut builder);
+    let finish_offset = request_builder.finish().as_union_value();
+    finalize_request(
+        &mut builder,
+        RequestType::AllocateClientIdRequest,
+        finish_offset,
+    );
+
+    // Skip the size prefix, like the frames received from clients
+    let request = get_root_as_generic_request(&builder.finished_data()[4..]);
+    raft.propose(request, FlatBufferBuilder::new())
+        .and_then(|committed| {
+            let response = response_or_error(&committed.as_ref()[4..])?;
+            let registered = response
+                .response_as_client_registered_response()
+                .ok_or(ErrorCode::BadResponse)?;
+            Ok(registered.client_id())
+        })
+}
+
+// Records the mount in the registry of this node, and returns its client ID, which is assigned on its first
+// registration
+pub fn register_client(
+    request: &GenericRequest,
+    raft: Arc<RaftManager>,
+    mut builder: FlatBufferBuilder<'static>,
+    inflight: &InflightHandle,
+) -> Box<FutureResultResponse<'static>> {
+    let register_request = match request.request_as_register_client_request() {
+        Some(register_request) => register_request,
+        None => return Box::new(err(ErrorCode::BadRequest)),
+    };
+    let session_id = request.session_id();
+    if session_id == 0 {
+        return Box::new(err(ErrorCode::InvalidArgument));
+    }
+    let hostname = register_request.hostname().to_string();
+    let mount_point = register_request.mount_point().to_string();
+    let open_handles = register_request.open_handles();
+    let registry = raft.local_context().clients.clone();
+
+    let known = match register_request.client_id() {
+        0 => registry.client_id(session_id),
+        client_id => client_id,
+    };
+    let client_id = if known != 0 {
+        Either::A(ok(known))
+    } else {
+        inflight.set_state(RequestState::WaitingForRaft);
+        Either::B(allocate_client_id(&raft))
+    };
+
+    Box::new(client_id.map(move |client_id| {
+        registry.register(session_id, client_id, &hostname, &mount_point, open_handles);
+        let mut response_builder = ClientRegisteredResponseBuilder::new(&mut builder);
+        response_builder.add_client_id(client_id);
+        response_builder.add_max_open_handles(registry.limits().max_open_handles);
+        let response_offset = response_builder.finish().as_union_value();
+
+        (
+            builder,
+            ResponseType::ClientRegisteredResponse,
+            response_offset,
+        )
+    }))
+}
+
+// Lists the client sessions connected to this node, and unless local is set, to every other node. Nodes which don't
+// respond are skipped
+pub fn list_clients<'a>(
+    raft: Arc<RaftManager>,
+    local: bool,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    let context = raft.local_context();
+    let mut gathered = vec![Either::A(ok(context.clients.list(context.node_id)))];
+    if !local {
+        for peer in context.peers.iter().chain(context.observers.iter()) {
+            let client = PeerClient::new(*peer);
+            gathered.push(Either::B(
+                client
+                    .list_clients()
+                    .then(|clients| Ok(clients.unwrap_or_default())),
+            ));
+        }
+    }
+
+    join_all(gathered).map(move |clients| {
+        let mut clients: Vec<ClientStatus> = clients.into_iter().flatten().collect();
+        clients.sort_by_key(|x| (x.client_id, x.node_id, x.session_id));
+        let mut entries = vec![];
+        for client in clients.iter() {
+            entries.push(client.to_entry(&mut builder));
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = ClientsResponseBuilder::new(&mut builder);
+        response_builder.add_clients(entries);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (builder, ResponseType::ClientsResponse, response_offset);
+    })
+}
+
+// Evicts the session from this node, and unless local is set, releases its locks, ends it, and evicts it from every
+// other node. Must be called on the leader, unless local is set
+pub fn evict_client<'a>(
+    raft: Arc<RaftManager>,
+    session_id: u64,
+    local: bool,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    if session_id == 0 {
+        return Either::A(err(ErrorCode::InvalidArgument));
+    }
+    let context = raft.local_context();
+    let mut evicted = vec![Either::A(ok(context
+        .clients
+        .evict(session_id, context.node_id)
+        .into_iter()
+        .collect::<Vec<ClientStatus>>()))];
+    if !local {
+        let broken = raft
+            .locks()
+            .break_locks(raft.current_term(), None, Some(session_id));
+        warn!(
+            "Evicted session {}, and released its {} locks",
+            session_id,
+            broken.len()
+        );
+        raft.end_session(session_id);
+        for peer in context.peers.iter().chain(context.observers.iter()) {
+            let peer = *peer;
+            let client = PeerClient::new(peer);
+            evicted.push(Either::B(client.evict_client(session_id).then(
+                move |clients| match clients {
+                    Ok(clients) => Ok(clients),
+                    Err(error_code) => {
+                        warn!(
+                            "Failed to evict session {} from {}: {:?}",
+                            session_id, peer, error_code
+                        );
+                        Ok(vec![])
+                    }
+                },
+            )));
+        }
+    }
+
+    Either::B(join_all(evicted).map(move |clients| {
+        let clients: Vec<ClientStatus> = clients.into_iter().flatten().collect();
+        let mut entries = vec![];
+        for client in clients.iter() {
+            entries.push(client.to_entry(&mut builder));
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = ClientsResponseBuilder::new(&mut builder);
+        response_builder.add_clients(entries);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (builder, ResponseType::ClientsResponse, response_offset);
+    }))
+}
@@ -1,57 +1,49 @@
 use crate::generated::*;
 use crate::peer_client::PeerClient;
+use crate::storage::file_storage::FileStorage;
 use crate::storage_node::LocalContext;
-use crate::utils::{empty_response, into_error_code, FlatBufferResponse, ResultResponse};
+use crate::utils::{
+    empty_response, into_error_code, node_id_from_address, FlatBufferResponse, ResultResponse,
+};
 use flatbuffers::FlatBufferBuilder;
-use futures::future::result;
+use futures::future::{err, result, Either};
 use futures::Future;
-use sha2::{Digest, Sha256};
-use std::io;
-use std::io::Write;
-use walkdir::WalkDir;
-
-fn checksum(data_dir: &str) -> io::Result<Vec<u8>> {
-    let mut hasher = Sha256::new();
-    for entry in WalkDir::new(data_dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            // TODO hash the data and file attributes too
-            let path_bytes = entry
-                .path()
-                .to_str()
-                .unwrap()
-                .trim_start_matches(data_dir)
-                .as_bytes();
-            hasher.write_all(path_bytes).unwrap();
-        }
-        // TODO handle other file types
-    }
-    return Ok(hasher.result().to_vec());
-}
+use log::warn;
+use std::sync::atomic::Ordering;
 
+// Checks that all the nodes store the same data, and that metadata_problems, found by checking the metadata, is empty
 pub fn fsck<'a>(
     context: &LocalContext,
+    file_storage: &FileStorage,
+    metadata_problems: Result<Vec<String>, ErrorCode>,
     mut builder: FlatBufferBuilder<'a>,
 ) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
-    let future_checksum = result(checksum(&context.data_dir).map_err(into_error_code));
+    // Peers are asked to use this node's algorithm, so that the checksums are comparable
+    let algorithm = context.checksums.fsck;
+    let future_checksum = file_storage.data_checksum(&context.data_dir, algorithm);
     let mut peer_futures = vec![];
     for peer in context.peers.iter() {
         let client = PeerClient::new(*peer);
-        peer_futures.push(client.filesystem_checksum().map_err(into_error_code));
+        peer_futures.push(
+            client
+                .filesystem_checksum(algorithm)
+                .map_err(into_error_code),
+        );
     }
 
     futures::future::join_all(peer_futures)
         .join(future_checksum)
-        .map(move |(peer_checksums, checksum)| {
-            for peer_checksum in peer_checksums {
-                if checksum != peer_checksum {
-                    let args = ErrorResponseArgs {
-                        error_code: ErrorCode::Corrupted,
-                    };
-                    let response_offset =
-                        ErrorResponse::create(&mut builder, &args).as_union_value();
-                    return (builder, ResponseType::ErrorResponse, response_offset);
-                }
+        .join(result(metadata_problems))
+        .map(move |((peer_checksums, checksum), metadata_problems)| {
+            for problem in metadata_problems.iter() {
+                warn!("Metadata inconsistency: {}", problem);
+            }
+            if !metadata_problems.is_empty() || peer_checksums.iter().any(|x| *x != checksum) {
+                let args = ErrorResponseArgs {
+                    error_code: ErrorCode::Corrupted,
+                };
+                let response_offset = ErrorResponse::create(&mut builder, &args).as_union_value();
+                return (builder, ResponseType::ErrorResponse, response_offset);
             }
 
             return empty_response(builder).unwrap();
@@ -60,13 +52,97 @@ pub fn fsck<'a>(
 
 pub fn checksum_request<'a>(
     local_context: &LocalContext,
+    file_storage: &FileStorage,
+    algorithm: ChecksumAlgorithm,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    file_storage
+        .data_checksum(&local_context.data_dir, algorithm)
+        .map(move |checksum| {
+            let data_offset = builder.create_vector_direct(&checksum);
+            let mut response_builder = ReadResponseBuilder::new(&mut builder);
+            response_builder.add_data(data_offset);
+            let response_offset = response_builder.finish().as_union_value();
+
+            return (builder, ResponseType::ReadResponse, response_offset);
+        })
+}
+
+// Checksums the copy of inode stored on every node. Done by this node, rather than the client, since the client may
+// not be able to reach every node
+pub fn verify_file<'a>(
+    context: &LocalContext,
+    file_storage: &FileStorage,
+    inode: u64,
+    user_context: UserContext,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    // Observers don't store any data
+    if context.observer {
+        return Either::A(err(ErrorCode::NotSupported));
+    }
+    let mirrored = match file_storage.verify_target(inode, user_context) {
+        Ok(mirrored) => mirrored,
+        Err(error_code) => return Either::A(err(error_code)),
+    };
+    let algorithm = context.checksums.fsck;
+    let node_id = context.node_id;
+    let mut checksum_futures = vec![];
+    for peer in context.peers.iter() {
+        let peer_id = node_id_from_address(peer);
+        let client = PeerClient::new(*peer);
+        checksum_futures.push(Either::A(
+            client
+                .local_file_checksum(inode, algorithm)
+                .map(move |checksum| (peer_id, checksum)),
+        ));
+    }
+    checksum_futures.push(Either::B(
+        file_storage
+            .local_file_checksum(inode, algorithm)
+            .map(move |checksum| (node_id, checksum)),
+    ));
+
+    Either::B(
+        futures::future::join_all(checksum_futures).map(move |mut checksums| {
+            checksums.sort();
+            let mut entries = vec![];
+            for (node_id, checksum) in checksums {
+                let checksum = builder.create_vector_direct(&checksum);
+                entries.push(NodeChecksum::create(
+                    &mut builder,
+                    &NodeChecksumArgs {
+                        node_id,
+                        checksum: Some(checksum),
+                    },
+                ));
+            }
+            let entries = builder.create_vector(&entries);
+            let mut response_builder = VerifyFileResponseBuilder::new(&mut builder);
+            response_builder.add_mirrored(mirrored);
+            response_builder.add_checksums(entries);
+            let response_offset = response_builder.finish().as_union_value();
+
+            return (builder, ResponseType::VerifyFileResponse, response_offset);
+        }),
+    )
+}
+
+pub fn checksum_progress_request<'a>(
+    file_storage: &FileStorage,
     mut builder: FlatBufferBuilder<'a>,
 ) -> ResultResponse<'a> {
-    let checksum = checksum(&local_context.data_dir).map_err(|_| ErrorCode::Uncategorized)?;
-    let data_offset = builder.create_vector_direct(&checksum);
-    let mut response_builder = ReadResponseBuilder::new(&mut builder);
-    response_builder.add_data(data_offset);
+    let progress = file_storage.checksum_progress();
+    let mut response_builder = ChecksumProgressResponseBuilder::new(&mut builder);
+    response_builder.add_running(progress.running.load(Ordering::SeqCst) > 0);
+    response_builder.add_total_files(progress.total_files.load(Ordering::SeqCst));
+    response_builder.add_hashed_files(progress.hashed_files.load(Ordering::SeqCst));
+    response_builder.add_hashed_bytes(progress.hashed_bytes.load(Ordering::SeqCst));
     let response_offset = response_builder.finish().as_union_value();
 
-    return Ok((builder, ResponseType::ReadResponse, response_offset));
+    return Ok((
+        builder,
+        ResponseType::ChecksumProgressResponse,
+        response_offset,
+    ));
 }
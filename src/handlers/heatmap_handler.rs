@@ -0,0 +1,77 @@
+use crate::generated::*;
+use crate::peer_client::PeerClient;
+use crate::storage::access_stats::FileAccess;
+use crate::storage::raft_manager::RaftManager;
+use crate::utils::FlatBufferResponse;
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{join_all, ok, Either};
+use futures::Future;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Every node applies every write, so writes are counted once, but reads are served by a single node and are summed
+fn merge(files: &mut HashMap<u64, FileAccess>, inode: u64, access: FileAccess) {
+    let merged = files.entry(inode).or_insert_with(FileAccess::default);
+    merged.reads += access.reads;
+    merged.read_bytes += access.read_bytes;
+    merged.writes = merged.writes.max(access.writes);
+    merged.written_bytes = merged.written_bytes.max(access.written_bytes);
+    merged.last_access = merged.last_access.max(access.last_access);
+}
+
+// Returns the limit hottest files, counting the accesses of this node, and unless local is set, of every other node.
+// Paths are only resolved by the node which receives the request
+pub fn heatmap<'a>(
+    raft: Arc<RaftManager>,
+    limit: u32,
+    local: bool,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    let mut heatmaps = vec![Either::A(ok(raft
+        .file_storage()
+        .hottest_files(limit as usize)))];
+    if !local {
+        for peer in raft.local_context().peers.iter() {
+            let client = PeerClient::new(*peer);
+            heatmaps.push(Either::B(client.heatmap(limit)));
+        }
+    }
+
+    join_all(heatmaps).map(move |heatmaps| {
+        let mut files = HashMap::new();
+        for (inode, access) in heatmaps.into_iter().flatten() {
+            merge(&mut files, inode, access);
+        }
+        let mut files: Vec<(u64, FileAccess)> = files.into_iter().collect();
+        files.sort_by_key(|(inode, access)| {
+            (Reverse(access.heat()), Reverse(access.last_access), *inode)
+        });
+        files.truncate(limit as usize);
+
+        let mut entries = vec![];
+        for (inode, access) in files {
+            let path = if local {
+                String::new()
+            } else {
+                raft.file_storage().path_of(inode).unwrap_or_default()
+            };
+            let path = builder.create_string(&path);
+            let mut entry_builder = FileHeatBuilder::new(&mut builder);
+            entry_builder.add_inode(inode);
+            entry_builder.add_path(path);
+            entry_builder.add_reads(access.reads);
+            entry_builder.add_writes(access.writes);
+            entry_builder.add_read_bytes(access.read_bytes);
+            entry_builder.add_written_bytes(access.written_bytes);
+            entry_builder.add_last_access(access.last_access);
+            entries.push(entry_builder.finish());
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = HeatmapResponseBuilder::new(&mut builder);
+        response_builder.add_files(entries);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (builder, ResponseType::HeatmapResponse, response_offset);
+    })
+}
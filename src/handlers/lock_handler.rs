@@ -0,0 +1,340 @@
+use crate::generated::*;
+use crate::inflight_requests::{InflightHandle, RequestState};
+use crate::storage::lock_manager::{ByteRangeLock, LockEntry, LockOwner};
+use crate::storage::raft_manager::RaftManager;
+use crate::storage_node::MandatoryLocking;
+use crate::utils::{
+    empty_response, finalize_session_request, response_or_error, FlatBufferResponse, ResultResponse,
+};
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{err, loop_fn, ok, Either, Loop};
+use futures::Future;
+use log::warn;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+// Reads and writes blocked by mandatory locks check them again after this long, doubling up to
+// MANDATORY_LOCK_MAX_DELAY
+const MANDATORY_LOCK_DELAY: Duration = Duration::from_millis(10);
+const MANDATORY_LOCK_MAX_DELAY: Duration = Duration::from_secs(1);
+
+// Must only be called on the leader
+pub fn lock<'a>(
+    request: &GenericRequest,
+    raft: &RaftManager,
+    mut builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    let lock_request = request
+        .request_as_lock_request()
+        .ok_or(ErrorCode::BadRequest)?;
+    let lock = ByteRangeLock {
+        owner: LockOwner {
+            session_id: request.session_id(),
+            owner: lock_request.owner(),
+        },
+        pid: lock_request.pid(),
+        start: lock_request.start(),
+        end: lock_request.end(),
+        exclusive: lock_request.kind() == LockKind::Exclusive,
+        fencing_token: 0,
+    };
+    if lock.start > lock.end {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    let fencing_token = raft.locks().lock(
+        raft.current_term(),
+        lock_request.inode(),
+        lock,
+        lock_request.kind(),
+        lock_request.wait(),
+    )?;
+
+    let mut response_builder = LockGrantedResponseBuilder::new(&mut builder);
+    response_builder.add_fencing_token(fencing_token);
+    let response_offset = response_builder.finish().as_union_value();
+
+    return Ok((builder, ResponseType::LockGrantedResponse, response_offset));
+}
+
+// Must only be called on the leader
+pub fn renew_locks<'a>(
+    request: &GenericRequest,
+    raft: &RaftManager,
+    builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    raft.locks()
+        .renew(raft.current_term(), request.session_id());
+
+    return empty_response(builder);
+}
+
+// Must only be called on the leader
+pub fn break_locks<'a>(
+    request: &GenericRequest,
+    raft: &RaftManager,
+    builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    let break_request = request
+        .request_as_break_locks_request()
+        .ok_or(ErrorCode::BadRequest)?;
+    let inode = match break_request.inode() {
+        0 => None,
+        inode => Some(inode),
+    };
+    let session_id = match break_request.session_id() {
+        0 => None,
+        session_id => Some(session_id),
+    };
+    if inode.is_none() && session_id.is_none() {
+        return Err(ErrorCode::InvalidArgument);
+    }
+    let broken = raft
+        .locks()
+        .break_locks(raft.current_term(), inode, session_id);
+    // Logged for auditing, since the holders' writes will fail
+    for entry in broken.iter() {
+        warn!(
+            "Session {} broke {} lock of session {} (pid {}) on inode {}, bytes {}..={}",
+            request.session_id(),
+            if entry.waiting { "waiting" } else { "held" },
+            entry.lock.owner.session_id,
+            entry.lock.pid,
+            entry.inode,
+            entry.lock.start,
+            entry.lock.end
+        );
+    }
+
+    return Ok(to_locks_response(builder, broken.iter()));
+}
+
+// Must only be called on the leader
+pub fn test_lock<'a>(
+    request: &GenericRequest,
+    raft: &RaftManager,
+    builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    let test_request = request
+        .request_as_test_lock_request()
+        .ok_or(ErrorCode::BadRequest)?;
+    let inode = test_request.inode();
+    let lock = ByteRangeLock {
+        owner: LockOwner {
+            session_id: request.session_id(),
+            owner: test_request.owner(),
+        },
+        pid: 0,
+        start: test_request.start(),
+        end: test_request.end(),
+        exclusive: test_request.kind() == LockKind::Exclusive,
+        fencing_token: 0,
+    };
+    let conflicting = if test_request.mandatory() {
+        raft.locks().conflicting_access(
+            raft.current_term(),
+            inode,
+            lock.owner.session_id,
+            lock.start,
+            lock.end,
+            lock.exclusive,
+        )
+    } else {
+        raft.locks().test(raft.current_term(), inode, &lock)
+    };
+    let conflicting = conflicting.map(|lock| LockEntry {
+        inode,
+        lock,
+        waiting: false,
+    });
+
+    return Ok(to_locks_response(builder, conflicting.iter()));
+}
+
+// Must only be called on the leader
+pub fn lock_status<'a>(
+    request: &GenericRequest,
+    raft: &RaftManager,
+    builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    let status_request = request
+        .request_as_lock_status_request()
+        .ok_or(ErrorCode::BadRequest)?;
+    let inode = match status_request.inode() {
+        0 => None,
+        inode => Some(inode),
+    };
+    let entries = raft.locks().list(raft.current_term(), inode);
+
+    return Ok(to_locks_response(builder, entries.iter()));
+}
+
+// (inode, start, end, write) of the bytes a client request reads or writes. end is inclusive
+fn accessed_range(request: &GenericRequest) -> Option<(u64, u64, u64, bool)> {
+    let (inode, start, length, write) = match request.request_type() {
+        RequestType::ReadRequest => {
+            let read_request = request.request_as_read_request()?;
+            (
+                read_request.inode(),
+                read_request.offset(),
+                u64::from(read_request.read_size()),
+                false,
+            )
+        }
+        RequestType::WriteRequest => {
+            let write_request = request.request_as_write_request()?;
+            (
+                write_request.inode(),
+                write_request.offset(),
+                write_request.data().len() as u64,
+                true,
+            )
+        }
+        RequestType::WriteConditionalRequest => {
+            let write_request = request.request_as_write_conditional_request()?;
+            (
+                write_request.inode(),
+                write_request.offset(),
+                write_request.data().len() as u64,
+                true,
+            )
+        }
+        RequestType::TruncateRequest => {
+            let truncate_request = request.request_as_truncate_request()?;
+            // Truncation changes everything past the new length
+            (
+                truncate_request.inode(),
+                truncate_request.new_length(),
+                u64::MAX - truncate_request.new_length(),
+                true,
+            )
+        }
+        _ => return None,
+    };
+    if length == 0 {
+        return None;
+    }
+
+    Some((inode, start, start.saturating_add(length - 1), write))
+}
+
+// Whether a lock of another session conflicts with the access. Asks the leader, which holds the locks
+fn access_conflicts(
+    raft: &Arc<RaftManager>,
+    inode: u64,
+    session_id: u64,
+    range: (u64, u64, bool),
+) -> impl Future<Item = bool, Error = ErrorCode> {
+    let (start, end, write) = range;
+    if raft.is_leader() {
+        let conflicting = raft.locks().conflicting_access(
+            raft.current_term(),
+            inode,
+            session_id,
+            start,
+            end,
+            write,
+        );
+        return Either::A(ok(conflicting.is_some()));
+    }
+
+    let mut builder = FlatBufferBuilder::new();
+    let mut request_builder = TestLockRequestBuilder::new(&mut builder);
+    request_builder.add_inode(inode);
+    request_builder.add_start(start);
+    request_builder.add_end(end);
+    request_builder.add_kind(if write {
+        LockKind::Exclusive
+    } else {
+        LockKind::Shared
+    });
+    request_builder.add_mandatory(true);
+    let finish_offset = request_builder.finish().as_union_value();
+    finalize_session_request(
+        &mut builder,
+        RequestType::TestLockRequest,
+        finish_offset,
+        session_id,
+        0,
+    );
+    Either::B(
+        raft.forward_to_leader(builder.finished_data())
+            .and_then(|response| {
+                let response = response_or_error(response.bytes())?;
+                let locks = response
+                    .response_as_locks_response()
+                    .ok_or(ErrorCode::BadResponse)?;
+                Ok(locks.locks().len() > 0)
+            }),
+    )
+}
+
+// Under mandatory locking, a client's read or write which conflicts with the byte range locks of another session
+// fails, or waits until they're released, depending on the mode. Locks of the client's own session never block it,
+// and requests from other nodes aren't checked
+pub fn check_mandatory_locks(
+    request: &GenericRequest,
+    raft: &Arc<RaftManager>,
+    inflight: &InflightHandle,
+) -> Box<Future<Item = (), Error = ErrorCode> + Send> {
+    let mode = raft.local_context().mandatory_locking;
+    if mode == MandatoryLocking::Off || request.session_id() == 0 {
+        return Box::new(ok(()));
+    }
+    let (inode, start, end, write) = match accessed_range(request) {
+        Some(range) => range,
+        None => return Box::new(ok(())),
+    };
+    let session_id = request.session_id();
+    let raft = raft.clone();
+    let inflight = inflight.clone();
+
+    Box::new(loop_fn(MANDATORY_LOCK_DELAY, move |delay| {
+        let inflight = inflight.clone();
+        access_conflicts(&raft, inode, session_id, (start, end, write)).and_then(move |conflicts| {
+            if !conflicts {
+                return Either::A(ok(Loop::Break(())));
+            }
+            if mode == MandatoryLocking::Fail {
+                return Either::A(err(ErrorCode::LockConflict));
+            }
+            inflight.set_state(RequestState::WaitingForLock);
+            Either::B(
+                Delay::new(Instant::now() + delay)
+                    .map_err(|_| ErrorCode::Uncategorized)
+                    .map(move |_| Loop::Continue((delay * 2).min(MANDATORY_LOCK_MAX_DELAY))),
+            )
+        })
+    }))
+}
+
+fn to_locks_response<'a, 'b, T: Iterator<Item = &'b LockEntry>>(
+    mut builder: FlatBufferBuilder<'a>,
+    entries: T,
+) -> FlatBufferResponse<'a> {
+    let mut offsets = vec![];
+    for entry in entries {
+        let lock = &entry.lock;
+        let mut entry_builder = LockEntryBuilder::new(&mut builder);
+        entry_builder.add_inode(entry.inode);
+        entry_builder.add_session_id(lock.owner.session_id);
+        entry_builder.add_owner(lock.owner.owner);
+        entry_builder.add_pid(lock.pid);
+        entry_builder.add_start(lock.start);
+        entry_builder.add_end(lock.end);
+        entry_builder.add_kind(if lock.exclusive {
+            LockKind::Exclusive
+        } else {
+            LockKind::Shared
+        });
+        entry_builder.add_waiting(entry.waiting);
+        entry_builder.add_fencing_token(lock.fencing_token);
+        offsets.push(entry_builder.finish());
+    }
+    let locks = builder.create_vector(&offsets);
+    let mut response_builder = LocksResponseBuilder::new(&mut builder);
+    response_builder.add_locks(locks);
+    let response_offset = response_builder.finish().as_union_value();
+
+    (builder, ResponseType::LocksResponse, response_offset)
+}
@@ -1,4 +1,12 @@
+pub mod authorization;
+mod client_handler;
 mod fsck_handler;
+mod heatmap_handler;
+mod lock_handler;
+mod path_handler;
+mod prefetch_handler;
 mod router;
+mod stats_handler;
 
-pub use router::request_router;
+pub use lock_handler::check_mandatory_locks;
+pub use router::{request_inode, request_router, transferred_bytes};
@@ -0,0 +1,89 @@
+use crate::generated::*;
+use crate::storage::metadata_storage::MAX_PATH_LENGTH;
+use crate::storage::raft_manager::RaftManager;
+use crate::storage::ROOT_INODE;
+use crate::utils::{to_inode_response, FlatBufferResponse};
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{err, loop_fn, ok, Either, Loop};
+use futures::Future;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Same limit as Linux, so that symlink loops are detected
+const MAX_SYMLINK_FOLLOWS: u32 = 40;
+
+struct Resolution {
+    inode: u64,
+    // Components of the path which haven't been resolved yet
+    remaining: VecDeque<String>,
+    symlinks_followed: u32,
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = String> + '_ {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .map(ToString::to_string)
+}
+
+// Resolves path, starting from the root, to an inode. Symlinks in the middle of the path are always followed,
+// and the last component only if follow_symlinks is set
+pub fn resolve_path<'a>(
+    raft: Arc<RaftManager>,
+    path: &str,
+    follow_symlinks: bool,
+    context: UserContext,
+    builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Either::A(err(ErrorCode::NameTooLong));
+    }
+
+    let start = Resolution {
+        inode: ROOT_INODE,
+        remaining: split_path(path).collect(),
+        symlinks_followed: 0,
+    };
+    let resolved = loop_fn(start, move |mut resolution| {
+        let name = match resolution.remaining.pop_front() {
+            Some(name) => name,
+            None => return Either::A(ok(Loop::Break(resolution.inode))),
+        };
+        let file_storage = raft.file_storage();
+        let (inode, kind) = match file_storage.resolve(resolution.inode, &name, context) {
+            Ok(entry) => entry,
+            Err(error_code) => return Either::A(err(error_code)),
+        };
+        if kind != FileKind::Symlink || (resolution.remaining.is_empty() && !follow_symlinks) {
+            resolution.inode = inode;
+            return Either::A(ok(Loop::Continue(resolution)));
+        }
+
+        resolution.symlinks_followed += 1;
+        if resolution.symlinks_followed > MAX_SYMLINK_FOLLOWS {
+            return Either::A(err(ErrorCode::TooManySymlinks));
+        }
+        Either::B(
+            file_storage
+                .read_symlink(inode, context)
+                .and_then(move |target| {
+                    let target = String::from_utf8(target).map_err(|_| ErrorCode::Corrupted)?;
+                    let remaining_length: usize =
+                        resolution.remaining.iter().map(|name| name.len() + 1).sum();
+                    if target.len() + remaining_length > MAX_PATH_LENGTH {
+                        return Err(ErrorCode::NameTooLong);
+                    }
+                    // Relative targets are resolved from the directory containing the symlink
+                    if target.starts_with('/') {
+                        resolution.inode = ROOT_INODE;
+                    }
+                    let target_names: Vec<String> = split_path(&target).collect();
+                    for name in target_names.into_iter().rev() {
+                        resolution.remaining.push_front(name);
+                    }
+                    Ok(Loop::Continue(resolution))
+                }),
+        )
+    });
+
+    Either::B(resolved.and_then(move |inode| to_inode_response(builder, inode)))
+}
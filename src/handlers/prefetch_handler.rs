@@ -0,0 +1,42 @@
+use crate::generated::*;
+use crate::peer_client::PeerClient;
+use crate::storage::file_storage::FileStorage;
+use crate::storage_node::LocalContext;
+use crate::utils::FlatBufferResponse;
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{err, join_all, Either};
+use futures::Future;
+
+// Loads the data of inode, or of the files below it, into the block cache of this node, and unless local is set,
+// of every other node
+pub fn prefetch<'a>(
+    context: &LocalContext,
+    file_storage: &FileStorage,
+    inode: u64,
+    user_context: UserContext,
+    local: bool,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    // Observers don't store any data
+    if context.observer {
+        return Either::A(err(ErrorCode::NotSupported));
+    }
+    let mut prefetches = vec![Either::A(file_storage.prefetch_local(inode, user_context))];
+    if !local {
+        for peer in context.peers.iter() {
+            let client = PeerClient::new(*peer);
+            prefetches.push(Either::B(client.prefetch(inode, user_context)));
+        }
+    }
+
+    Either::B(join_all(prefetches).map(move |loaded| {
+        let files = loaded.iter().map(|(files, _)| *files).max().unwrap_or(0);
+        let bytes = loaded.iter().map(|(_, bytes)| *bytes).sum();
+        let mut response_builder = PrefetchResponseBuilder::new(&mut builder);
+        response_builder.add_files(files);
+        response_builder.add_bytes(bytes);
+        let response_offset = response_builder.finish().as_union_value();
+
+        (builder, ResponseType::PrefetchResponse, response_offset)
+    }))
+}
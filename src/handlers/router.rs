@@ -1,56 +1,575 @@
+use crate::bandwidth_limiter::SessionLimits;
+use crate::event_hooks::EventKind;
 use crate::generated::*;
-use crate::handlers::fsck_handler::{checksum_request, fsck};
+use crate::handlers::authorization::{authorization_target, is_authorized};
+use crate::handlers::client_handler::{evict_client, list_clients, register_client};
+use crate::handlers::fsck_handler::{
+    checksum_progress_request, checksum_request, fsck, verify_file,
+};
+use crate::handlers::heatmap_handler::heatmap;
+use crate::handlers::lock_handler::{break_locks, lock, lock_status, renew_locks, test_lock};
+use crate::handlers::path_handler::resolve_path;
+use crate::handlers::prefetch_handler::prefetch;
+use crate::handlers::stats_handler::{cluster_stats, inflight_requests};
+use crate::inflight_requests::{InflightHandle, RequestState};
+use crate::logging::to_level_filter;
+use crate::preflight::node_info;
+use crate::storage::file_storage::MAX_ARCHIVE_PAGE_BYTES;
+use crate::storage::metadata_storage::{FindQuery, CHECKSUM_XATTR};
 use crate::storage::raft_manager::RaftManager;
+use crate::storage::snapshot::{read_snapshot_chunk, snapshot_directory};
 use crate::utils::{
-    empty_response, finalize_response, FlatBufferWithResponse, FutureResultResponse,
+    empty_response, fast_read_succeeded, finalize_request, finalize_response,
+    finalize_session_request, into_error_code, response_or_error, to_error_response,
+    to_read_response, FlatBufferWithResponse, FutureResultResponse, ResultResponse,
 };
 use flatbuffers::FlatBufferBuilder;
 use futures::future::{err, ok, result, Either};
-use futures::Future;
+use futures::stream::futures_unordered;
+use futures::{Future, Stream};
+use log::{info, warn};
 use protobuf::Message as ProtobufMessage;
 use raft::prelude::Message;
+use rand::Rng;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::timer::Delay;
+
+// Limit on the number of entries in a single page of FindRequest and GlobRequest results
+const MAX_FIND_RESULTS: usize = 10_000;
+// Writes of at least this many bytes have their data pushed directly to every node, instead of through Raft
+const STAGED_WRITE_THRESHOLD: usize = 128 * 1024;
+
+type CommittedResponse =
+    Box<Future<Item = FlatBufferWithResponse<'static>, Error = std::io::Error> + Send>;
 
 // Sync to ensure replicas serve latest data
-fn sync_with_leader(raft: &Arc<RaftManager>) -> impl Future<Item = (), Error = ErrorCode> {
+fn sync_with_leader(
+    raft: &Arc<RaftManager>,
+    inflight: &InflightHandle,
+) -> impl Future<Item = (), Error = ErrorCode> {
+    // Observers serve eventually consistent reads, from whatever they have applied so far
+    if raft.local_context().observer {
+        return Either::A(ok(()));
+    }
+    inflight.set_state(RequestState::WaitingForLeaderSync);
     let cloned_raft = raft.clone();
-    raft.get_latest_commit_from_leader()
-        .map(move |latest_commit| cloned_raft.sync(latest_commit))
-        .flatten()
-        .map_err(|_| ErrorCode::Uncategorized)
+    let inflight = inflight.clone();
+    Either::B(
+        raft.get_latest_commit_from_leader()
+            .map(move |latest_commit| cloned_raft.sync(latest_commit))
+            .flatten()
+            .map(move |_| inflight.set_state(RequestState::Executing))
+            .map_err(|_| ErrorCode::Uncategorized),
+    )
+}
+
+// Reads of attributes and xattrs skip the sync if the node is configured for relaxed metadata reads. They're still
+// consistent with the changes made through this node, since a change is applied before it's acknowledged.
+// Lookups always sync, since they're used for permission checks
+fn sync_for_metadata_read(
+    raft: &Arc<RaftManager>,
+    inflight: &InflightHandle,
+) -> impl Future<Item = (), Error = ErrorCode> {
+    if raft.local_context().relaxed_metadata_reads {
+        return Either::A(ok(()));
+    }
+    Either::B(sync_with_leader(raft, inflight))
+}
+
+fn authorized(request: &GenericRequest, raft: &RaftManager) -> bool {
+    return is_authorized(&*raft.local_context().authorizer, request);
+}
+
+// Writes of file data from users other than root fail once only the reserved space is left on a node which
+// stores part of them, so that metadata operations and deletions can still be made
+fn has_space(request: &GenericRequest, raft: &RaftManager) -> bool {
+    let (uid, inode, writes) = match request.request_type() {
+        RequestType::WriteRequest => match request.request_as_write_request() {
+            Some(x) => (
+                x.context().uid(),
+                x.inode(),
+                vec![(Some(x.offset()), x.data().len() as u64)],
+            ),
+            None => return true,
+        },
+        RequestType::WriteConditionalRequest => {
+            match request.request_as_write_conditional_request() {
+                Some(x) => (
+                    x.context().uid(),
+                    x.inode(),
+                    vec![(Some(x.offset()), x.data().len() as u64)],
+                ),
+                None => return true,
+            }
+        }
+        RequestType::AppendRequest => match request.request_as_append_request() {
+            Some(x) => (
+                x.context().uid(),
+                x.inode(),
+                vec![(None, x.data().len() as u64)],
+            ),
+            None => return true,
+        },
+        RequestType::WritePatchRequest => match request.request_as_write_patch_request() {
+            Some(x) => (
+                x.context().uid(),
+                x.inode(),
+                x.patches()
+                    .iter()
+                    .map(|patch| (Some(patch.offset()), patch.data().len() as u64))
+                    .collect(),
+            ),
+            None => return true,
+        },
+        _ => return true,
+    };
+
+    if raft.file_storage().has_space_for_write(uid, inode, &writes) {
+        return true;
+    }
+    let hooks = &raft.local_context().hooks;
+    if hooks.enabled() {
+        let path = raft.file_storage().path_of(inode).unwrap_or_default();
+        hooks.notify(EventKind::NoSpace, inode, path);
+    }
+
+    return false;
+}
+
+// Writes are finalized with the term they were committed in
+fn propose_write(
+    request: GenericRequest,
+    raft: Arc<RaftManager>,
+    builder: FlatBufferBuilder<'static>,
+    inflight: &InflightHandle,
+) -> CommittedResponse {
+    inflight.set_state(RequestState::WaitingForRaft);
+    Box::new(raft.propose(request, builder).or_else(move |error_code| {
+        Ok(FlatBufferWithResponse::new(to_error_response(
+            error_code,
+            raft.current_term(),
+        )))
+    }))
+}
+
+// Requests which only the leader serves, such as for locks, are forwarded to it by the other nodes
+fn forward_to_leader(
+    request: GenericRequest,
+    raft: Arc<RaftManager>,
+    builder: FlatBufferBuilder<'static>,
+    inflight: &InflightHandle,
+) -> CommittedResponse {
+    inflight.set_state(RequestState::WaitingForPeers);
+    Box::new(
+        raft.forward_to_leader(request._tab.buf)
+            .then(move |forwarded| match forwarded {
+                Ok(response) => Ok(FlatBufferWithResponse::with_separate_response(
+                    builder, response,
+                )),
+                Err(error_code) => Ok(FlatBufferWithResponse::new(to_error_response(
+                    error_code,
+                    raft.current_term(),
+                ))),
+            }),
+    )
+}
+
+// Builds the StagedWriteRequest which is proposed instead of write_request, once its data is held by the holders
+fn to_staged_write_request(
+    request: &GenericRequest,
+    write_request: &WriteRequest,
+    staged_id: u64,
+    holders: &[u64],
+) -> Vec<u8> {
+    let mut staged_builder = FlatBufferBuilder::new();
+    let holders_offset = staged_builder.create_vector(holders);
+    let mut request_builder = StagedWriteRequestBuilder::new(&mut staged_builder);
+    request_builder.add_inode(write_request.inode());
+    request_builder.add_offset(write_request.offset());
+    request_builder.add_staged_id(staged_id);
+    request_builder.add_context(write_request.context());
+    request_builder.add_fencing_token(write_request.fencing_token());
+    request_builder.add_holders(holders_offset);
+    let finish_offset = request_builder.finish().as_union_value();
+    // Keep the session of the original request, so that retries are still deduplicated
+    finalize_session_request(
+        &mut staged_builder,
+        RequestType::StagedWriteRequest,
+        finish_offset,
+        request.session_id(),
+        request.sequence_number(),
+    );
+
+    // Skip the size prefix, like the frames received from clients
+    staged_builder.finished_data()[4..].to_vec()
+}
+
+// Pushes the data of a large write to the other nodes, and then only proposes a small StagedWriteRequest, to keep the
+// data out of the Raft log. If a quorum of the voters can't store the data, the write is proposed with it instead
+fn stage_and_propose_write(
+    request: GenericRequest,
+    write_request: WriteRequest,
+    raft: Arc<RaftManager>,
+    builder: FlatBufferBuilder<'static>,
+    inflight: &InflightHandle,
+) -> CommittedResponse {
+    let staged_id: u64 = rand::thread_rng().gen();
+    let staged_data = raft.file_storage().staged_data();
+    if staged_data.stage(staged_id, write_request.data()).is_err() {
+        return propose_write(request, raft, builder, inflight);
+    }
+
+    // Kept in case the write has to be proposed with its data
+    let original_request = request._tab.buf.to_vec();
+    inflight.set_state(RequestState::WaitingForPeers);
+    let inflight = inflight.clone();
+    Box::new(
+        raft.stage_on_peers(staged_id, write_request.data())
+            .then(move |holders| {
+                let request = get_root_as_generic_request(&original_request);
+                match holders {
+                    Ok(holders) => {
+                        let write_request = request.request_as_write_request().unwrap();
+                        let staged_request =
+                            to_staged_write_request(&request, &write_request, staged_id, &holders);
+                        propose_write(
+                            get_root_as_generic_request(&staged_request),
+                            raft,
+                            builder,
+                            &inflight,
+                        )
+                    }
+                    Err(_) => {
+                        let inode = request.request_as_write_request().unwrap().inode();
+                        warn!("Proposing write to inode {} with its data", inode);
+                        propose_write(request, raft, builder, &inflight)
+                    }
+                }
+            }),
+    )
+}
+
+fn to_flush_epoch_response(
+    mut builder: FlatBufferBuilder<'static>,
+    epoch: u64,
+) -> ResultResponse<'static> {
+    let mut response_builder = FlushEpochResponseBuilder::new(&mut builder);
+    response_builder.add_epoch(epoch);
+    let response_offset = response_builder.finish().as_union_value();
+
+    return Ok((builder, ResponseType::FlushEpochResponse, response_offset));
+}
+
+// On the leader, flushes its data, and waits for a quorum of the voting nodes to flush theirs up to its applied index,
+// which every acknowledged write was applied before. Elsewhere, flushes the data once the log is applied up to epoch
+fn flush_epoch(
+    epoch: u64,
+    raft: Arc<RaftManager>,
+    builder: FlatBufferBuilder<'static>,
+    inflight: &InflightHandle,
+) -> Box<FutureResultResponse<'static>> {
+    if epoch != 0 {
+        let cloned_raft = raft.clone();
+        return Box::new(
+            raft.sync(epoch)
+                .map_err(|_| ErrorCode::Uncategorized)
+                .and_then(move |_| cloned_raft.file_storage().sync_all())
+                .and_then(move |_| to_flush_epoch_response(builder, epoch)),
+        );
+    }
+
+    let epoch = raft.get_latest_local_commit();
+    if let Err(error_code) = raft.file_storage().sync_all() {
+        return Box::new(err(error_code));
+    }
+    let mut peer_builder = FlatBufferBuilder::new();
+    let mut request_builder = FlushEpochRequestBuilder::new(&mut peer_builder);
+    request_builder.add_epoch(epoch);
+    let finish_offset = request_builder.finish().as_union_value();
+    finalize_request(
+        &mut peer_builder,
+        RequestType::FlushEpochRequest,
+        finish_offset,
+    );
+    // This node is one of the quorum
+    let needed = (raft.local_context().peers.len() + 1) / 2;
+    inflight.set_state(RequestState::WaitingForPeers);
+
+    Box::new(
+        futures_unordered(raft.send_to_voters(peer_builder.finished_data()))
+            .then(|response| {
+                Ok::<bool, ErrorCode>(
+                    response.map_or(false, |x| response_or_error(x.bytes()).is_ok()),
+                )
+            })
+            .filter(|flushed| *flushed)
+            .take(needed as u64)
+            .collect()
+            .and_then(move |flushed| {
+                if flushed.len() < needed {
+                    return Err(ErrorCode::RaftFailure);
+                }
+                to_flush_epoch_response(builder, epoch)
+            }),
+    )
+}
+
+// Bytes counted against the session's bandwidth limit
+pub fn transferred_bytes(request: &GenericRequest, frame_length: usize) -> u64 {
+    let read_size = match request.request_type() {
+        RequestType::ReadRequest => request.request_as_read_request().map(|x| x.read_size()),
+        RequestType::ReadRawRequest => request.request_as_read_raw_request().map(|x| x.read_size()),
+        RequestType::ExportArchiveRequest => {
+            request
+                .request_as_export_archive_request()
+                .map(|x| match x.max_bytes() {
+                    0 => MAX_ARCHIVE_PAGE_BYTES,
+                    max_bytes => max_bytes.min(MAX_ARCHIVE_PAGE_BYTES),
+                })
+        }
+        _ => None,
+    };
+
+    return read_size.map(u64::from).unwrap_or(frame_length as u64);
+}
+
+// Inode the request is for, or 0, so that it can be shown among the inflight requests
+pub fn request_inode(request: &GenericRequest) -> u64 {
+    if let Some((inode, _, _)) = authorization_target(request) {
+        return inode;
+    }
+    let inode = match request.request_type() {
+        RequestType::GetattrRequest => request.request_as_getattr_request().map(|x| x.inode()),
+        RequestType::ListXattrsRequest => {
+            request.request_as_list_xattrs_request().map(|x| x.inode())
+        }
+        RequestType::ReleaseRequest => request.request_as_release_request().map(|x| x.inode()),
+        RequestType::OpenTemporaryRequest => request
+            .request_as_open_temporary_request()
+            .map(|x| x.inode()),
+        RequestType::FsyncRequest => request.request_as_fsync_request().map(|x| x.inode()),
+        RequestType::ReadRawRequest => request.request_as_read_raw_request().map(|x| x.inode()),
+        RequestType::GetTreeUsageRequest => request
+            .request_as_get_tree_usage_request()
+            .map(|x| x.inode()),
+        RequestType::LockRequest => request.request_as_lock_request().map(|x| x.inode()),
+        RequestType::TestLockRequest => request.request_as_test_lock_request().map(|x| x.inode()),
+        _ => None,
+    };
+
+    return inode.unwrap_or(0);
 }
 
 pub fn request_router(
     request: GenericRequest,
     raft: Arc<RaftManager>,
     mut builder: FlatBufferBuilder<'static>,
+    inflight: InflightHandle,
 ) -> impl Future<Item = FlatBufferWithResponse<'static>, Error = std::io::Error> {
     let response: Box<FutureResultResponse<'static>>;
+    let raft_for_term = raft.clone();
+    if raft.local_context().log_control.dump_requests() {
+        info!(
+            "Received {:?} (session {}, sequence number {}) for {:?}",
+            request.request_type(),
+            request.session_id(),
+            request.sequence_number(),
+            authorization_target(&request).map(|(inode, operation, _)| (inode, operation))
+        );
+    }
 
     match request.request_type() {
+        // The client has seen a newer term, so this node may have been superseded as leader, and can't be
+        // trusted to answer. Nothing was applied, so the client can send the request again
+        _ if request.term() > raft.current_term() => {
+            response = Box::new(err(ErrorCode::RaftFailure));
+        }
+        _ if !authorized(&request, &raft) => {
+            response = Box::new(err(ErrorCode::AccessDenied));
+        }
+        _ if !has_space(&request, &raft) => {
+            response = Box::new(err(ErrorCode::NoSpace));
+        }
         RequestType::FilesystemCheckRequest => {
-            let after_sync = sync_with_leader(&raft);
+            let after_sync = sync_with_leader(&raft, &inflight);
             let response_after_sync = after_sync
-                .map(move |_| fsck(raft.local_context(), builder))
+                .map(move |_| {
+                    let metadata_problems = raft.file_storage().check_metadata();
+                    fsck(
+                        raft.local_context(),
+                        raft.file_storage(),
+                        metadata_problems,
+                        builder,
+                    )
+                })
                 .flatten();
             response = Box::new(response_after_sync);
         }
         RequestType::FilesystemChecksumRequest => {
-            response = Box::new(result(checksum_request(raft.local_context(), builder)));
+            if let Some(checksum_request_args) = request.request_as_filesystem_checksum_request() {
+                response = Box::new(checksum_request(
+                    raft.local_context(),
+                    raft.file_storage(),
+                    checksum_request_args.algorithm(),
+                    builder,
+                ));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::LocalFileChecksumRequest => {
+            if let Some(checksum_request) = request.request_as_local_file_checksum_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = checksum_request.inode();
+                let algorithm = checksum_request.algorithm();
+                let response_after_sync = after_sync
+                    .and_then(move |_| raft.file_storage().local_file_checksum(inode, algorithm))
+                    .and_then(move |checksum| to_read_response(builder, &checksum));
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::VerifyFileRequest => {
+            if let Some(verify_request) = request.request_as_verify_file_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = verify_request.inode();
+                let user_context = *verify_request.context();
+                let response_after_sync = after_sync.and_then(move |_| {
+                    verify_file(
+                        raft.local_context(),
+                        raft.file_storage(),
+                        inode,
+                        user_context,
+                        builder,
+                    )
+                });
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::PrefetchRequest => {
+            if let Some(prefetch_request) = request.request_as_prefetch_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = prefetch_request.inode();
+                let user_context = *prefetch_request.context();
+                let local = prefetch_request.local();
+                let response_after_sync = after_sync.and_then(move |_| {
+                    prefetch(
+                        raft.local_context(),
+                        raft.file_storage(),
+                        inode,
+                        user_context,
+                        local,
+                        builder,
+                    )
+                });
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::SetLogLevelRequest => {
+            if let Some(log_level_request) = request.request_as_set_log_level_request() {
+                let level = to_level_filter(log_level_request.level());
+                info!(
+                    "Setting log level of {} to {}",
+                    log_level_request.module().unwrap_or("all modules"),
+                    level
+                );
+                raft.local_context()
+                    .log_control
+                    .set_level(log_level_request.module(), level);
+                response = Box::new(result(empty_response(builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::SetReplicationBandwidthRequest => {
+            if let Some(bandwidth_request) = request.request_as_set_replication_bandwidth_request()
+            {
+                let global = bandwidth_request.global_bytes_per_second();
+                let peer = bandwidth_request.peer_bytes_per_second();
+                info!(
+                    "Setting replication bandwidth to {} bytes/sec, and {} per peer",
+                    global, peer
+                );
+                raft.local_context()
+                    .replication_limiter
+                    .set_limits(global, peer);
+                response = Box::new(result(empty_response(builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::SetSessionLimitsRequest => {
+            if let Some(limits_request) = request.request_as_set_session_limits_request() {
+                let limits = SessionLimits {
+                    iops: limits_request.iops(),
+                    bytes_per_second: limits_request.bytes_per_second(),
+                };
+                info!(
+                    "Setting limits of session {} to {} IOPS and {} bytes/sec",
+                    limits_request.target_session_id(),
+                    limits.iops,
+                    limits.bytes_per_second
+                );
+                raft.local_context()
+                    .session_limiter
+                    .set_limits(limits_request.target_session_id(), limits);
+                response = Box::new(result(empty_response(builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::SetRequestDumpingRequest => {
+            if let Some(dumping_request) = request.request_as_set_request_dumping_request() {
+                raft.local_context()
+                    .log_control
+                    .set_dump_requests(dumping_request.enabled());
+                response = Box::new(result(empty_response(builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::PingRequest => {
+            response = Box::new(result(empty_response(builder)));
+        }
+        RequestType::NodeInfoRequest => {
+            response = Box::new(result(node_info(raft.local_context(), builder)));
+        }
+        RequestType::FilesystemInformationRequest => {
+            response = Box::new(result(raft.file_storage().filesystem_information(builder)));
+        }
+        RequestType::FilesystemChecksumProgressRequest => {
+            response = Box::new(result(checksum_progress_request(
+                raft.file_storage(),
+                builder,
+            )));
         }
         RequestType::ReadRequest => {
             if let Some(read_request) = request.request_as_read_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_with_leader(&raft, &inflight);
                 let inode = read_request.inode();
                 let offset = read_request.offset();
                 let read_size = read_request.read_size();
                 let user_context = *read_request.context();
+                let atime_mode = read_request.atime_mode();
+                let raft_for_access = raft.clone();
                 let response_after_sync = after_sync
                     .map(move |_| {
                         raft.file_storage()
                             .read(inode, offset, read_size, user_context, builder)
                     })
-                    .flatten();
+                    .flatten()
+                    .map(move |response| {
+                        if fast_read_succeeded(&response) {
+                            raft_for_access.record_access(inode, atime_mode);
+                        }
+                        response
+                    });
                 return Either::A(Either::A(
                     response_after_sync
                         .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other)),
@@ -75,7 +594,6 @@ pub fn request_router(
         | RequestType::RemoveXattrRequest
         | RequestType::UnlinkRequest
         | RequestType::RmdirRequest
-        | RequestType::WriteRequest
         | RequestType::UtimensRequest
         | RequestType::HardlinkRequest
         | RequestType::RenameRequest
@@ -84,12 +602,133 @@ pub fn request_router(
         | RequestType::ChownRequest
         | RequestType::TruncateRequest
         | RequestType::FsyncRequest
-        | RequestType::CreateRequest => {
-            response = Box::new(raft.propose(request, builder));
+        | RequestType::UpdateAtimeRequest
+        | RequestType::WritePatchRequest
+        | RequestType::WriteConditionalRequest
+        | RequestType::AppendRequest
+        | RequestType::FilesystemRepairRequest
+        | RequestType::SetLimitsRequest
+        | RequestType::FreezeRequest
+        | RequestType::ThawRequest
+        | RequestType::AllocateClientIdRequest
+        | RequestType::CreateRequest
+        | RequestType::CreateTemporaryRequest
+        | RequestType::OpenTemporaryRequest => {
+            return Either::B(Either::A(propose_write(request, raft, builder, &inflight)));
+        }
+        RequestType::EndSessionRequest => {
+            // Requests without a session are from other nodes
+            if request.session_id() != 0 {
+                response = Box::new(err(ErrorCode::OperationNotPermitted));
+            } else {
+                return Either::B(Either::A(propose_write(request, raft, builder, &inflight)));
+            }
+        }
+        RequestType::WriteRequest => {
+            if let Some(write_request) = request.request_as_write_request() {
+                if write_request.data().len() >= STAGED_WRITE_THRESHOLD {
+                    return Either::B(Either::A(stage_and_propose_write(
+                        request,
+                        write_request,
+                        raft,
+                        builder,
+                        &inflight,
+                    )));
+                }
+            }
+            return Either::B(Either::A(propose_write(request, raft, builder, &inflight)));
+        }
+        RequestType::CreateSnapshotRequest => {
+            let resume_index = request
+                .request_as_create_snapshot_request()
+                .map_or(0, |x| x.resume_index());
+            response = Box::new(result(raft.latest_snapshot(resume_index).map(|info| {
+                let checksum_offset = builder.create_vector_direct(&info.checksum);
+                let mut response_builder = SnapshotInfoResponseBuilder::new(&mut builder);
+                response_builder.add_index(info.index);
+                response_builder.add_term(info.term);
+                response_builder.add_size(info.size);
+                response_builder.add_checksum(checksum_offset);
+                response_builder.add_checksum_algorithm(info.checksum_algorithm);
+                let response_offset = response_builder.finish().as_union_value();
+                (builder, ResponseType::SnapshotInfoResponse, response_offset)
+            })));
+        }
+        RequestType::ReadSnapshotRequest => {
+            if let Some(read_request) = request.request_as_read_snapshot_request() {
+                let wait = raft
+                    .local_context()
+                    .replication_limiter
+                    .reserve(read_request.node_id(), u64::from(read_request.read_size()));
+                let directory = snapshot_directory(&raft.local_context().data_dir);
+                let index = read_request.index();
+                let offset = read_request.offset();
+                let read_size = read_request.read_size();
+                let chunk = Delay::new(Instant::now() + wait)
+                    .map_err(|_| ErrorCode::Uncategorized)
+                    .and_then(move |_| {
+                        read_snapshot_chunk(&directory, index, offset, read_size)
+                            .map_err(into_error_code)
+                            .and_then(|data| to_read_response(builder, &data))
+                    });
+                response = Box::new(chunk);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::StageDataRequest => {
+            // Requests without a session are from other nodes
+            if request.session_id() != 0 {
+                response = Box::new(err(ErrorCode::OperationNotPermitted));
+            } else if let Some(stage_request) = request.request_as_stage_data_request() {
+                let staged = raft
+                    .file_storage()
+                    .staged_data()
+                    .stage(stage_request.staged_id(), stage_request.data());
+                response = Box::new(result(staged.and_then(|_| empty_response(builder))));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::FetchStagedDataRequest => {
+            if request.session_id() != 0 {
+                response = Box::new(err(ErrorCode::OperationNotPermitted));
+            } else if let Some(fetch_request) = request.request_as_fetch_staged_data_request() {
+                let staged = raft
+                    .file_storage()
+                    .staged_data()
+                    .get(fetch_request.staged_id())
+                    .ok_or(ErrorCode::DoesNotExist);
+                response = Box::new(result(
+                    staged.and_then(|data| to_read_response(builder, &data)),
+                ));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::ReleaseRequest => {
+            if let Some(release_request) = request.request_as_release_request() {
+                // Most files aren't retained or temporary, so skip the round trip through raft for them
+                let inode = release_request.inode();
+                let file_storage = raft.file_storage();
+                match (
+                    file_storage.has_pending_retention(inode),
+                    file_storage.is_temporary(inode),
+                ) {
+                    (Ok(false), Ok(false)) => response = Box::new(result(empty_response(builder))),
+                    _ => {
+                        return Either::B(Either::A(propose_write(
+                            request, raft, builder, &inflight,
+                        )))
+                    }
+                }
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
         }
         RequestType::LookupRequest => {
             if let Some(lookup_request) = request.request_as_lookup_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_with_leader(&raft, &inflight);
                 let parent = lookup_request.parent();
                 let name = lookup_request.name().to_string();
                 let user_context = *lookup_request.context();
@@ -104,13 +743,35 @@ pub fn request_router(
                 response = Box::new(err(ErrorCode::BadRequest));
             }
         }
+        RequestType::ResolvePathRequest => {
+            if let Some(resolve_request) = request.request_as_resolve_path_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let path = resolve_request.path().to_string();
+                let follow_symlinks = resolve_request.follow_symlinks();
+                let user_context = *resolve_request.context();
+                let response_after_sync = after_sync
+                    .map(move |_| resolve_path(raft, &path, follow_symlinks, user_context, builder))
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
         RequestType::GetXattrRequest => {
             if let Some(get_xattr_request) = request.request_as_get_xattr_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_for_metadata_read(&raft, &inflight);
                 let inode = get_xattr_request.inode();
                 let key = get_xattr_request.key().to_string();
                 let response_after_sync = after_sync
-                    .map(move |_| raft.file_storage().get_xattr(inode, &key, builder))
+                    .map(move |_| {
+                        let file_storage = raft.file_storage();
+                        // The checksum may have to be computed, by reading the whole file
+                        if key == CHECKSUM_XATTR {
+                            Either::A(file_storage.get_checksum_xattr(inode, builder))
+                        } else {
+                            Either::B(result(file_storage.get_xattr(inode, &key, builder)))
+                        }
+                    })
                     .flatten();
                 response = Box::new(response_after_sync);
             } else {
@@ -119,7 +780,7 @@ pub fn request_router(
         }
         RequestType::ListXattrsRequest => {
             if let Some(list_xattrs_request) = request.request_as_list_xattrs_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_for_metadata_read(&raft, &inflight);
                 let inode = list_xattrs_request.inode();
                 let response_after_sync = after_sync
                     .map(move |_| raft.file_storage().list_xattrs(inode, builder))
@@ -131,10 +792,144 @@ pub fn request_router(
         }
         RequestType::ReaddirRequest => {
             if let Some(readdir_request) = request.request_as_readdir_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_with_leader(&raft, &inflight);
                 let inode = readdir_request.inode();
+                let since_version = readdir_request.since_version().map(|x| x.value());
+                let response_after_sync = after_sync
+                    .map(move |_| raft.file_storage().readdir(inode, since_version, builder))
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::FindRequest => {
+            if let Some(find_request) = request.request_as_find_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = find_request.inode();
+                let query = FindQuery::from_request(&find_request);
+                let start_after = find_request.start_after().map(ToString::to_string);
+                let mut max_results = find_request.max_results() as usize;
+                if max_results == 0 || max_results > MAX_FIND_RESULTS {
+                    max_results = MAX_FIND_RESULTS;
+                }
+                let user_context = *find_request.context();
+                let response_after_sync = after_sync
+                    .map(move |_| {
+                        raft.file_storage().find(
+                            inode,
+                            &query,
+                            start_after.as_ref().map(String::as_str),
+                            max_results,
+                            user_context,
+                            builder,
+                        )
+                    })
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::GlobRequest => {
+            if let Some(glob_request) = request.request_as_glob_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let pattern = glob_request.pattern().to_string();
+                let start_after = glob_request.start_after().map(ToString::to_string);
+                let mut max_results = glob_request.max_results() as usize;
+                if max_results == 0 || max_results > MAX_FIND_RESULTS {
+                    max_results = MAX_FIND_RESULTS;
+                }
+                let user_context = *glob_request.context();
+                let response_after_sync = after_sync
+                    .map(move |_| {
+                        raft.file_storage().glob(
+                            &pattern,
+                            start_after.as_ref().map(String::as_str),
+                            max_results,
+                            user_context,
+                            builder,
+                        )
+                    })
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::SearchRequest => {
+            if let Some(search_request) = request.request_as_search_request() {
+                // The content index is maintained asynchronously, so there's no need to sync
+                let mut max_results = search_request.max_results() as usize;
+                if max_results == 0 || max_results > MAX_FIND_RESULTS {
+                    max_results = MAX_FIND_RESULTS;
+                }
+                response = Box::new(raft.file_storage().search(
+                    search_request.query(),
+                    max_results,
+                    *search_request.context(),
+                    builder,
+                ));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::GetTreeUsageRequest => {
+            if let Some(tree_usage_request) = request.request_as_get_tree_usage_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = tree_usage_request.inode();
+                let response_after_sync = after_sync
+                    .map(move |_| raft.file_storage().get_tree_usage(inode, builder))
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::FileBlockHashesRequest => {
+            if let Some(hashes_request) = request.request_as_file_block_hashes_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = hashes_request.inode();
+                let block_size = hashes_request.block_size();
+                let start_block = hashes_request.start_block();
+                let user_context = *hashes_request.context();
+                let algorithm = hashes_request.algorithm();
+                let response_after_sync = after_sync
+                    .map(move |_| {
+                        raft.file_storage().block_hashes(
+                            inode,
+                            block_size,
+                            start_block,
+                            algorithm,
+                            user_context,
+                            builder,
+                        )
+                    })
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::ExportArchiveRequest => {
+            if let Some(export_request) = request.request_as_export_archive_request() {
+                let after_sync = sync_with_leader(&raft, &inflight);
+                let inode = export_request.inode();
+                let resume_path = export_request.resume_path().map(ToString::to_string);
+                let resume_offset = export_request.resume_offset();
+                let max_bytes = export_request.max_bytes();
+                let user_context = *export_request.context();
                 let response_after_sync = after_sync
-                    .map(move |_| raft.file_storage().readdir(inode, builder))
+                    .map(move |_| {
+                        raft.file_storage().export_archive(
+                            inode,
+                            resume_path.as_ref().map(String::as_str),
+                            resume_offset,
+                            max_bytes,
+                            user_context,
+                            builder,
+                        )
+                    })
                     .flatten();
                 response = Box::new(response_after_sync);
             } else {
@@ -143,7 +938,7 @@ pub fn request_router(
         }
         RequestType::GetattrRequest => {
             if let Some(getattr_request) = request.request_as_getattr_request() {
-                let after_sync = sync_with_leader(&raft);
+                let after_sync = sync_for_metadata_read(&raft, &inflight);
                 let inode = getattr_request.inode();
                 let response_after_sync = after_sync
                     .map(move |_| raft.file_storage().getattr(inode, builder))
@@ -153,14 +948,29 @@ pub fn request_router(
                 response = Box::new(err(ErrorCode::BadRequest));
             }
         }
+        RequestType::BatchGetattrRequest => {
+            if let Some(batch_getattr_request) = request.request_as_batch_getattr_request() {
+                let after_sync = sync_for_metadata_read(&raft, &inflight);
+                let inodes: Vec<u64> = batch_getattr_request.inodes().iter().collect();
+                let response_after_sync = after_sync
+                    .map(move |_| raft.file_storage().batch_getattr(&inodes, builder))
+                    .flatten();
+                response = Box::new(response_after_sync);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
         RequestType::RaftRequest => {
             if let Some(raft_request) = request.request_as_raft_request() {
                 let mut deserialized_message = Message::new();
-                deserialized_message
+                let applied = deserialized_message
                     .merge_from_bytes(raft_request.message())
-                    .unwrap();
-                raft.apply_messages(&[deserialized_message]).unwrap();
-                response = Box::new(result(empty_response(builder)));
+                    .map_err(|_| ErrorCode::BadRequest)
+                    .and_then(|_| {
+                        raft.apply_messages(&[deserialized_message])
+                            .map_err(|_| ErrorCode::BadRequest)
+                    });
+                response = Box::new(result(applied.and_then(|_| empty_response(builder))));
             } else {
                 response = Box::new(err(ErrorCode::BadRequest));
             }
@@ -176,6 +986,56 @@ pub fn request_router(
                 response_offset,
             )));
         }
+        RequestType::ForceNewClusterRequest => {
+            if let Some(force_request) = request.request_as_force_new_cluster_request() {
+                let dry_run = force_request.dry_run();
+                response = Box::new(result(raft.force_new_cluster(dry_run, builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::BlockCacheStatsRequest => {
+            response = Box::new(ok(raft.file_storage().block_cache_stats(builder)));
+        }
+        RequestType::ClusterStatsRequest => {
+            if let Some(stats_request) = request.request_as_cluster_stats_request() {
+                let local = stats_request.local();
+                if !local {
+                    inflight.set_state(RequestState::WaitingForPeers);
+                }
+                response = Box::new(cluster_stats(raft.clone(), local, builder));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::HeatmapRequest => {
+            if let Some(heatmap_request) = request.request_as_heatmap_request() {
+                let limit = heatmap_request.limit();
+                let local = heatmap_request.local();
+                if !local {
+                    inflight.set_state(RequestState::WaitingForPeers);
+                }
+                response = Box::new(heatmap(raft.clone(), limit, local, builder));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::BackgroundControlRequest => {
+            if let Some(control_request) = request.request_as_background_control_request() {
+                let action = control_request.action();
+                response = Box::new(ok(raft.file_storage().background_control(action, builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::RaftDebugRequest => {
+            if let Some(debug_request) = request.request_as_raft_debug_request() {
+                let log_tail = debug_request.log_tail();
+                response = Box::new(ok(raft.debug_info(log_tail, builder)));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
         RequestType::GetLeaderRequest => {
             let leader_future = raft
                 .get_leader()
@@ -189,23 +1049,86 @@ pub fn request_router(
 
             response = Box::new(leader_future);
         }
-        RequestType::NONE => unreachable!(),
+        RequestType::LockRequest
+        | RequestType::TestLockRequest
+        | RequestType::LockStatusRequest
+        | RequestType::RenewLocksRequest
+        | RequestType::BreakLocksRequest => {
+            if !raft.is_leader() {
+                return Either::B(Either::A(forward_to_leader(
+                    request, raft, builder, &inflight,
+                )));
+            }
+            response = Box::new(result(match request.request_type() {
+                RequestType::LockRequest => lock(&request, &raft, builder),
+                RequestType::TestLockRequest => test_lock(&request, &raft, builder),
+                RequestType::RenewLocksRequest => renew_locks(&request, &raft, builder),
+                RequestType::BreakLocksRequest => break_locks(&request, &raft, builder),
+                _ => lock_status(&request, &raft, builder),
+            }));
+        }
+        RequestType::InflightRequestsRequest => {
+            response = Box::new(ok(inflight_requests(raft.local_context(), builder)));
+        }
+        RequestType::FlushEpochRequest => {
+            if let Some(flush_request) = request.request_as_flush_epoch_request() {
+                let epoch = flush_request.epoch();
+                if epoch == 0 && !raft.is_leader() {
+                    return Either::B(Either::A(forward_to_leader(
+                        request, raft, builder, &inflight,
+                    )));
+                }
+                response = flush_epoch(epoch, raft.clone(), builder, &inflight);
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::RegisterClientRequest => {
+            response = register_client(&request, raft.clone(), builder, &inflight);
+        }
+        RequestType::ListClientsRequest => {
+            if let Some(list_request) = request.request_as_list_clients_request() {
+                let local = list_request.local();
+                if !local {
+                    inflight.set_state(RequestState::WaitingForPeers);
+                }
+                response = Box::new(list_clients(raft.clone(), local, builder));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        RequestType::EvictClientRequest => {
+            if let Some(evict_request) = request.request_as_evict_client_request() {
+                let session_id = evict_request.session_id();
+                let local = evict_request.local();
+                // The leader holds the locks
+                if !local && !raft.is_leader() {
+                    return Either::B(Either::A(forward_to_leader(
+                        request, raft, builder, &inflight,
+                    )));
+                }
+                if !local {
+                    inflight.set_state(RequestState::WaitingForPeers);
+                }
+                response = Box::new(evict_client(raft.clone(), session_id, local, builder));
+            } else {
+                response = Box::new(err(ErrorCode::BadRequest));
+            }
+        }
+        // Rejected by verify_request(), before the request is routed
+        RequestType::NONE => {
+            response = Box::new(err(ErrorCode::BadRequest));
+        }
     }
 
-    Either::B(
+    let term = raft_for_term.current_term();
+    Either::B(Either::B(
         response
-            .map(|(mut builder, response_type, response_offset)| {
-                finalize_response(&mut builder, response_type, response_offset);
+            .map(move |(mut builder, response_type, response_offset)| {
+                finalize_response(&mut builder, response_type, response_offset, term);
                 builder
             })
-            .or_else(|error_code| {
-                let mut builder = FlatBufferBuilder::new();
-                let args = ErrorResponseArgs { error_code };
-                let response_offset = ErrorResponse::create(&mut builder, &args).as_union_value();
-                finalize_response(&mut builder, ResponseType::ErrorResponse, response_offset);
-
-                Ok(builder)
-            })
+            .or_else(move |error_code| Ok(to_error_response(error_code, term)))
             .map(FlatBufferWithResponse::new),
-    )
+    ))
 }
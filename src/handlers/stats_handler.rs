@@ -0,0 +1,115 @@
+use crate::generated::*;
+use crate::peer_client::PeerClient;
+use crate::request_stats::NodeStats;
+use crate::storage::raft_manager::RaftManager;
+use crate::storage_node::LocalContext;
+use crate::utils::{node_id_from_address, FlatBufferResponse};
+use flatbuffers::FlatBufferBuilder;
+use futures::future::{join_all, ok, Either};
+use futures::Future;
+use std::sync::Arc;
+
+fn local_stats(raft: &RaftManager) -> NodeStats {
+    let context = raft.local_context();
+    let (role, leader_id, commit_index, applied_index) = raft.raft_status();
+    let mut stats = NodeStats {
+        node_id: context.node_id,
+        reachable: true,
+        role,
+        leader_id,
+        commit_index,
+        applied_index,
+        uptime_seconds: context.request_stats.uptime_seconds(),
+        counts: context.request_stats.counts(),
+        zone: context.zone.clone(),
+        ..NodeStats::default()
+    };
+    if let Ok(space) = raft.file_storage().disk_space() {
+        stats.total_bytes = space.total_blocks * space.block_size;
+        stats.available_bytes = space.available_blocks * space.block_size;
+    }
+    let cache = raft.file_storage().local_cache_stats();
+    stats.cache_hits = cache.hits;
+    stats.cache_misses = cache.misses;
+    stats.cached_bytes = cache.cached_bytes;
+
+    stats
+}
+
+// Gathers the statistics of this node, and unless local is set, of every other node. Nodes which don't respond are
+// included, but marked unreachable
+pub fn cluster_stats<'a>(
+    raft: Arc<RaftManager>,
+    local: bool,
+    mut builder: FlatBufferBuilder<'a>,
+) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+    let mut gathered = vec![Either::A(ok(local_stats(&raft)))];
+    if !local {
+        let context = raft.local_context();
+        for peer in context.peers.iter().chain(context.observers.iter()) {
+            let node_id = node_id_from_address(peer);
+            let client = PeerClient::new(*peer);
+            let address = peer.to_string();
+            gathered.push(Either::B(client.node_stats().then(move |stats| {
+                let mut stats = stats.unwrap_or_else(|_| NodeStats {
+                    node_id,
+                    ..NodeStats::default()
+                });
+                stats.address = address;
+                Ok(stats)
+            })));
+        }
+    }
+
+    join_all(gathered).map(move |mut nodes| {
+        nodes.sort_by_key(|x| x.node_id);
+        let latest_commit = nodes.iter().map(|x| x.commit_index).max().unwrap_or(0);
+        let mut entries = vec![];
+        for node in nodes.iter_mut() {
+            if node.reachable {
+                node.raft_lag = latest_commit.saturating_sub(node.applied_index);
+            }
+            entries.push(node.to_status(&mut builder));
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = ClusterStatsResponseBuilder::new(&mut builder);
+        response_builder.add_nodes(entries);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (builder, ResponseType::ClusterStatsResponse, response_offset);
+    })
+}
+
+// Lists the requests this node is executing, oldest first, excluding the request which asked for them
+pub fn inflight_requests<'a>(
+    context: &LocalContext,
+    mut builder: FlatBufferBuilder<'a>,
+) -> FlatBufferResponse<'a> {
+    let mut entries = vec![];
+    for (request, age) in context.inflight_requests.list() {
+        if request.request_type == RequestType::InflightRequestsRequest {
+            continue;
+        }
+        let request_type = builder.create_string(&format!("{:?}", request.request_type));
+        let client = builder.create_string(&request.client);
+        let state = builder.create_string(request.state.name());
+        let mut entry_builder = InflightRequestEntryBuilder::new(&mut builder);
+        entry_builder.add_request_type(request_type);
+        entry_builder.add_inode(request.inode);
+        entry_builder.add_age_ms(age.as_millis() as u64);
+        entry_builder.add_session_id(request.session_id);
+        entry_builder.add_client(client);
+        entry_builder.add_state(state);
+        entries.push(entry_builder.finish());
+    }
+    let entries = builder.create_vector(&entries);
+    let mut response_builder = InflightRequestsResponseBuilder::new(&mut builder);
+    response_builder.add_requests(entries);
+    let response_offset = response_builder.finish().as_union_value();
+
+    return (
+        builder,
+        ResponseType::InflightRequestsResponse,
+        response_offset,
+    );
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::generated::RequestType;
+
+// What an executing request is waiting for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestState {
+    // Held back by the rate limit of its session
+    RateLimited,
+    // Waiting for the node to apply the latest commit of the leader, before a read
+    WaitingForLeaderSync,
+    // Proposed, and waiting to be committed and applied
+    WaitingForRaft,
+    // Waiting for other nodes, such as for the data of a staged write to be pushed to them
+    WaitingForPeers,
+    // Waiting for a byte range lock of another session to be released, under mandatory locking
+    WaitingForLock,
+    // Reading or writing storage
+    Executing,
+}
+
+impl RequestState {
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestState::RateLimited => "rate_limited",
+            RequestState::WaitingForLeaderSync => "waiting_for_leader_sync",
+            RequestState::WaitingForRaft => "waiting_for_raft",
+            RequestState::WaitingForPeers => "waiting_for_peers",
+            RequestState::WaitingForLock => "waiting_for_lock",
+            RequestState::Executing => "executing",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct InflightRequest {
+    pub request_type: RequestType,
+    // 0 if the request isn't for an inode
+    pub inode: u64,
+    // 0 for requests from other nodes
+    pub session_id: u64,
+    // Address of the connection the request was received on
+    pub client: String,
+    pub started: Instant,
+    pub state: RequestState,
+}
+
+// Requests a node is currently executing, so that a hang can be diagnosed by asking the node what it's waiting for
+#[derive(Default)]
+pub struct InflightRequests {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, InflightRequest>>,
+}
+
+impl InflightRequests {
+    // The request is listed until the returned handle, and all its clones, are dropped
+    pub fn start(
+        registry: &Arc<InflightRequests>,
+        request_type: RequestType,
+        inode: u64,
+        session_id: u64,
+        client: String,
+    ) -> InflightHandle {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = InflightRequest {
+            request_type,
+            inode,
+            session_id,
+            client,
+            started: Instant::now(),
+            state: RequestState::Executing,
+        };
+        registry.requests.lock().unwrap().insert(id, request);
+
+        InflightHandle {
+            registration: Arc::new(Registration {
+                registry: registry.clone(),
+                id,
+            }),
+        }
+    }
+
+    // (request, age) of each request, oldest first
+    pub fn list(&self) -> Vec<(InflightRequest, Duration)> {
+        let requests = self.requests.lock().unwrap();
+        let mut listed: Vec<(InflightRequest, Duration)> = requests
+            .values()
+            .map(|x| (x.clone(), x.started.elapsed()))
+            .collect();
+        listed.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+
+        listed
+    }
+}
+
+// Updates the state of one inflight request. Clones share the request, which is removed from the registry when the
+// last of them is dropped
+#[derive(Clone)]
+pub struct InflightHandle {
+    registration: Arc<Registration>,
+}
+
+impl InflightHandle {
+    pub fn set_state(&self, state: RequestState) {
+        let registration = &self.registration;
+        if let Some(request) = registration
+            .registry
+            .requests
+            .lock()
+            .unwrap()
+            .get_mut(&registration.id)
+        {
+            request.state = state;
+        }
+    }
+}
+
+struct Registration {
+    registry: Arc<InflightRequests>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::RequestType;
+    use crate::inflight_requests::{InflightRequests, RequestState};
+    use std::sync::Arc;
+
+    #[test]
+    fn listed_until_dropped() {
+        let registry = Arc::new(InflightRequests::default());
+        let handle = InflightRequests::start(
+            &registry,
+            RequestType::ReaddirRequest,
+            5,
+            7,
+            "client".into(),
+        );
+        handle.set_state(RequestState::WaitingForLeaderSync);
+        let listed = registry.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0.inode, 5);
+        assert_eq!(listed[0].0.state, RequestState::WaitingForLeaderSync);
+
+        let cloned = handle.clone();
+        drop(handle);
+        assert_eq!(registry.list().len(), 1);
+        drop(cloned);
+        assert!(registry.list().is_empty());
+    }
+}
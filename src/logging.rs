@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::generated::LogLevel;
+
+struct Levels {
+    // Level of modules which don't have their own
+    default: LevelFilter,
+    // Module paths, like fleetfs::storage, and the level of them and their submodules
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn level(&self, target: &str) -> LevelFilter {
+        // The most specific module takes precedence
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+struct DynamicLogger {
+    inner: env_logger::Logger,
+    levels: Arc<RwLock<Levels>>,
+}
+
+impl Log for DynamicLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Controls the logging of the process while it's running, so that debugging can be turned on for a node
+// without restarting it
+#[derive(Clone)]
+pub struct LogControl {
+    levels: Arc<RwLock<Levels>>,
+    dump_requests: Arc<AtomicBool>,
+}
+
+impl LogControl {
+    // Installs the logger. Must only be called once
+    pub fn init(default: LevelFilter) -> LogControl {
+        let levels = Arc::new(RwLock::new(Levels {
+            default,
+            modules: vec![],
+        }));
+        let inner = env_logger::Builder::new()
+            .default_format_timestamp_nanos(true)
+            .filter_level(LevelFilter::Trace)
+            .build();
+        log::set_boxed_logger(Box::new(DynamicLogger {
+            inner,
+            levels: levels.clone(),
+        }))
+        .expect("Logger was already initialized");
+        log::set_max_level(default);
+
+        LogControl {
+            levels,
+            dump_requests: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Sets the level of module and its submodules, or of all modules which don't have their own if module is None
+    pub fn set_level(&self, module: Option<&str>, level: LevelFilter) {
+        let mut levels = self.levels.write().unwrap();
+        match module {
+            Some(module) => {
+                levels.modules.retain(|(existing, _)| existing != module);
+                levels.modules.push((module.to_string(), level));
+            }
+            None => levels.default = level,
+        }
+        log::set_max_level(levels.max());
+    }
+
+    pub fn set_dump_requests(&self, enabled: bool) {
+        self.dump_requests.store(enabled, Ordering::SeqCst);
+    }
+
+    // Whether every request received by the node is logged
+    pub fn dump_requests(&self) -> bool {
+        self.dump_requests.load(Ordering::SeqCst)
+    }
+}
+
+pub fn to_level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+pub fn to_log_level(level: LevelFilter) -> LogLevel {
+    match level {
+        LevelFilter::Off => LogLevel::Off,
+        LevelFilter::Error => LogLevel::Error,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Info,
+        LevelFilter::Debug => LogLevel::Debug,
+        LevelFilter::Trace => LogLevel::Trace,
+    }
+}
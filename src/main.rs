@@ -3,199 +3,1486 @@
 use clap::crate_version;
 use clap::App;
 use clap::Arg;
+use clap::ArgMatches;
+use clap::SubCommand;
 
-use crate::client::NodeClient;
-use crate::fuse_adapter::FleetFUSE;
-use crate::storage_node::Node;
+use crate::bandwidth_limiter::{BandwidthLimiter, SessionLimiter, SessionLimits};
+use crate::client::{NodeClient, UnreachablePolicy};
+use crate::client_registry::ClientLimits;
+use crate::event_hooks::HookConfig;
+use crate::fuse_adapter::{FleetFUSE, MountConfig};
+use crate::handlers::authorization::AllowAll;
+use crate::logging::{to_log_level, LogControl};
+use crate::mount_supervisor::supervise_mount;
+use crate::request_stats::{NodeStats, RequestCounts};
+use crate::storage::block_cache::{BlockCacheConfig, CacheAdmission};
+use crate::storage::checksum::parse_checksum_algorithm;
+use crate::storage_node::{
+    ChecksumConfig, MandatoryLocking, Node, NodeConfig, StartupCheck, WriteCoalescing,
+};
+use crate::tcp_client::Keepalive;
 use log::debug;
 use log::warn;
 use log::LevelFilter;
 use std::ffi::OsStr;
+use std::io::Write;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
-use crate::generated::ErrorCode;
-use crate::utils::fuse_allow_other_enabled;
+use crate::generated::{
+    AtimeMode, BackgroundAction, ErrorCode, FilesystemLimits, Timestamp, UserContext,
+};
+use crate::storage::lock_manager::LockEntry;
+use crate::storage::metadata_storage::FindQuery;
+use crate::storage::ROOT_INODE;
+use crate::utils::{fuse_allow_other_enabled, into_error_code};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+pub mod access_pattern;
+pub mod bandwidth_limiter;
 pub mod client;
+pub mod client_registry;
+pub mod disk_cache;
+pub mod event_hooks;
+pub mod frame_codec;
 pub mod fuse_adapter;
 pub mod handlers;
+pub mod inflight_requests;
+pub mod logging;
+pub mod memory_budget;
+pub mod mount_supervisor;
+pub mod offline_store;
 pub mod peer_client;
+pub mod preflight;
+pub mod read_coalescer;
+pub mod request_stats;
+pub mod request_verifier;
+#[cfg(test)]
+pub mod scratch_cluster;
 pub mod storage;
 pub mod storage_node;
+pub mod systemd;
 pub mod tcp_client;
 pub mod utils;
+pub mod worker_pool;
+
+// How long the dev-cluster subcommand waits for its nodes to elect a leader
+const DEV_CLUSTER_START_TIMEOUT: Duration = Duration::from_secs(30);
 
 include!(concat!(env!("OUT_DIR"), "/messages_generated.mod"));
 
-fn main() -> Result<(), ErrorCode> {
-    let matches = App::new("FleetFS")
-        .version(crate_version!())
-        .author("Christopher Berner")
-        .arg(
-            Arg::with_name("port")
-                .long("port")
-                .value_name("PORT")
-                .default_value("3000")
-                .help("Set server port")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("bind-ip")
-                .long("bind-ip")
-                .value_name("BIND_IP")
-                .default_value("127.0.0.1")
-                .help("Address for server to listen on")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("data-dir")
-                .long("data-dir")
-                .value_name("DIR")
-                .default_value("/tmp/fleetfs")
-                .help("Set local directory used to store data")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("peers")
-                .long("peers")
-                .value_name("PEERS")
-                .default_value("")
-                .help("Comma separated list of peer IP:PORT, or DNS-RECORD:PORT in which case DNS-RECORD must resolve to an A record containing --num-peers peers")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("num-peers")
-                .long("num-peers")
-                .value_name("NUM-PEERS")
-                .default_value("0")
-                .requires("peers")
-                .help("Number of peer records to expect in the DNS record specified in --peers")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("server-ip-port")
-                .long("server-ip-port")
-                .value_name("IP_PORT")
-                .default_value("127.0.0.1:3000")
-                .help("Act as a client, and connect to given server")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("mount-point")
-                .long("mount-point")
-                .value_name("MOUNT_POINT")
-                .default_value("")
-                .help("Act as a client, and mount FUSE at given path")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("direct-io")
-                .long("direct-io")
-                .requires("mount-point")
-                .help("Mount FUSE with direct IO"),
-        )
-        .arg(
-            Arg::with_name("fsck")
-                .long("fsck")
-                .help("Run a filesystem check on the cluster"),
-        )
-        .arg(
-            Arg::with_name("get-leader")
-                .long("get-leader")
-                .help("Print the ID of the leader node"),
-        )
-        .arg(
-            Arg::with_name("v")
-                .short("v")
-                .multiple(true)
-                .help("Sets the level of verbosity"),
-        )
-        .get_matches();
+// Starts the nodes of a local cluster, each in a child process of executable with its own address and data
+// directory below data_dir
+fn start_nodes(
+    executable: &Path,
+    addresses: &[SocketAddr],
+    data_dir: &Path,
+    verbosity: u64,
+) -> Vec<Child> {
+    let mut children = vec![];
+    for (i, address) in addresses.iter().enumerate() {
+        let peers: Vec<String> = addresses
+            .iter()
+            .filter(|x| *x != address)
+            .map(ToString::to_string)
+            .collect();
+        let node_dir = data_dir.join(format!("node-{}", i));
+        let mut command = Command::new(executable);
+        command
+            .arg("server")
+            .arg("--bind-ip")
+            .arg(address.ip().to_string())
+            .arg("--port")
+            .arg(address.port().to_string())
+            .arg("--data-dir")
+            .arg(node_dir)
+            .arg("--peers")
+            .arg(peers.join(","));
+        if verbosity > 0 {
+            command.arg(format!("-{}", "v".repeat(verbosity as usize)));
+        }
+        children.push(command.spawn().expect("Failed to start node"));
+    }
 
-    let verbosity: u64 = matches.occurrences_of("v");
-    let log_level = match verbosity {
-        0 => LevelFilter::Error,
-        1 => LevelFilter::Warn,
-        2 => LevelFilter::Info,
-        3 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
+    children
+}
+
+// Waits until the cluster of the node at address has elected a leader
+fn wait_for_leader(address: SocketAddr) -> Result<(), ErrorCode> {
+    let start = Instant::now();
+    loop {
+        match NodeClient::new(address).leader_id() {
+            Ok(_) => return Ok(()),
+            Err(error_code) => {
+                if start.elapsed() > DEV_CLUSTER_START_TIMEOUT {
+                    return Err(error_code);
+                }
+                sleep(Duration::from_millis(200));
+            }
+        }
+    }
+}
+
+// Writes a file of size bytes, and then reads it back, in transfers of chunk_size bytes, printing the throughput
+// of each
+fn bench(
+    client: &NodeClient,
+    size: u64,
+    chunk_size: u32,
+    context: UserContext,
+) -> Result<(), ErrorCode> {
+    let attributes = client.create_temporary(ROOT_INODE, context.uid(), context.gid(), 0o600)?;
+    let chunk = vec![0xA5; chunk_size as usize];
+
+    let start = Instant::now();
+    let mut written = 0;
+    while written < size {
+        let length = min(u64::from(chunk_size), size - written) as usize;
+        if let Err(error_code) = client.write(attributes.ino, &chunk[..length], written, context) {
+            client.release(attributes.ino)?;
+            return Err(error_code);
+        }
+        written += length as u64;
+    }
+    print_throughput("Wrote", written, start.elapsed());
+
+    let start = Instant::now();
+    let mut renewed = Instant::now();
+    let mut read = 0;
+    while read < size {
+        // Reads don't renew the session's lease, which keeps the temporary file
+        if renewed.elapsed() >= Duration::from_secs(10) {
+            client.renew_locks()?;
+            renewed = Instant::now();
+        }
+        match client.read_to_vec(
+            attributes.ino,
+            read,
+            chunk_size,
+            context,
+            AtimeMode::NoAtime,
+        ) {
+            Ok(ref data) if data.is_empty() => break,
+            Ok(data) => read += data.len() as u64,
+            Err(error_code) => {
+                client.release(attributes.ino)?;
+                return Err(error_code);
+            }
+        }
+    }
+    print_throughput("Read", read, start.elapsed());
+
+    // The file is temporary, so it's deleted once released
+    return client.release(attributes.ino);
+}
+
+fn print_throughput(action: &str, bytes: u64, elapsed: Duration) {
+    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let megabytes = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{} {:.1} MB in {:.2}s ({:.1} MB/s)",
+        action,
+        megabytes,
+        seconds,
+        megabytes / seconds.max(1e-9)
+    );
+}
+
+// Copies the files and directories below local_dir into the directory parent, and returns the number of files
+// and bytes copied. Symlinks and special files are skipped
+fn import_directory(
+    client: &NodeClient,
+    local_dir: &Path,
+    parent: u64,
+    context: UserContext,
+) -> Result<(u64, u64), ErrorCode> {
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(local_dir).map_err(into_error_code)? {
+        let entry = entry.map_err(into_error_code)?;
+        let file_type = entry.file_type().map_err(into_error_code)?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                eprintln!("Skipping {:?}, which isn't valid UTF-8", name);
+                continue;
+            }
+        };
+        if file_type.is_dir() {
+            let mode = entry
+                .metadata()
+                .map_err(into_error_code)?
+                .permissions()
+                .mode();
+            let attributes = client.mkdir(
+                parent,
+                &name,
+                context.uid(),
+                context.gid(),
+                mode as u16 & 0o7777,
+            )?;
+            let (dir_files, dir_bytes) =
+                import_directory(client, &entry.path(), attributes.ino, context)?;
+            files += dir_files;
+            bytes += dir_bytes;
+        } else if file_type.is_file() {
+            let data = std::fs::read(entry.path()).map_err(into_error_code)?;
+            client.upload_into(&data, parent, &name, context)?;
+            files += 1;
+            bytes += data.len() as u64;
+        } else {
+            eprintln!(
+                "Skipping {:?}, which isn't a regular file or directory",
+                entry.path()
+            );
+        }
+    }
+
+    return Ok((files, bytes));
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total > 0 {
+        part as f64 * 100.0 / total as f64
+    } else {
+        0.0
+    }
+}
+
+// Prints one line per lock, with the waiters after the holders of each inode
+fn print_locks(entries: &[LockEntry]) {
+    println!(
+        "{:>12} {:>20} {:>20} {:>8} {:>20} {:>20} {:>9} {:>20} STATE",
+        "INODE", "SESSION", "OWNER", "PID", "START", "END", "KIND", "TOKEN"
+    );
+    for entry in entries {
+        let lock = entry.lock;
+        println!(
+            "{:>12} {:>20} {:>20} {:>8} {:>20} {:>20} {:>9} {:>20} {}",
+            entry.inode,
+            lock.owner.session_id,
+            lock.owner.owner,
+            lock.pid,
+            lock.start,
+            lock.end,
+            if lock.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            },
+            lock.fencing_token,
+            if entry.waiting { "waiting" } else { "held" }
+        );
+    }
+}
+
+// Prints one line per node. Rates are over the time since the previous refresh, or since the node started
+fn print_cluster_stats(nodes: &[NodeStats], previous: &HashMap<u64, NodeStats>, elapsed: Duration) {
+    println!(
+        "{:>20} {:>10} {:>10} {:>8} {:>9} {:>9} {:>9} {:>11} {:>11} {:>6} {:>6}",
+        "NODE",
+        "ROLE",
+        "ZONE",
+        "LAG",
+        "OPS/s",
+        "READS/s",
+        "WRITES/s",
+        "READ B/s",
+        "WRITE B/s",
+        "DISK%",
+        "HIT%"
+    );
+    for node in nodes.iter() {
+        if !node.reachable {
+            println!("{:>20} {:>10}", node.node_id, "unreachable");
+            continue;
+        }
+        let (base, seconds) = match previous.get(&node.node_id) {
+            Some(previous) if previous.reachable => {
+                (previous.counts, elapsed.as_millis() as f64 / 1000.0)
+            }
+            _ => (RequestCounts::default(), node.uptime_seconds as f64),
+        };
+        let rate = |current: u64, base: u64| current.saturating_sub(base) as f64 / seconds.max(1.0);
+        let counts = node.counts;
+        println!(
+            "{:>20} {:>10} {:>10} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>11.0} {:>11.0} {:>6.1} {:>6.1}",
+            node.node_id,
+            node.role,
+            node.zone,
+            node.raft_lag,
+            rate(counts.requests, base.requests),
+            rate(counts.reads, base.reads),
+            rate(counts.writes, base.writes),
+            rate(counts.read_bytes, base.read_bytes),
+            rate(counts.written_bytes, base.written_bytes),
+            percent(
+                node.total_bytes.saturating_sub(node.available_bytes),
+                node.total_bytes
+            ),
+            percent(node.cache_hits, node.cache_hits + node.cache_misses)
+        );
+    }
+}
+
+// Administrative commands, which the admin subcommand runs one of
+const ADMIN_COMMANDS: &[&str] = &[
+    "set-log-level",
+    "dump-requests",
+    "get-leader",
+    "force-new-cluster",
+    "set-replication-bandwidth",
+    "set-session-limits",
+    "set-limits",
+    "freeze",
+    "thaw",
+    "block-cache-stats",
+    "top",
+    "heatmap",
+    "background",
+    "raft-debug",
+    "inflight",
+    "list-clients",
+    "evict-client",
+    "locks",
+    "break-locks",
+    "du",
+    "prefetch",
+    "verify",
+    "archive",
+    "upload",
+    "create-log",
+    "follow-log",
+    "find",
+    "glob",
+    "search",
+];
+
+fn common_options() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("v")
+        .short("v")
+        .multiple(true)
+        .help("Sets the level of verbosity")]
+}
+
+// Options of the subcommands which connect to a node
+fn client_options() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("server-ip-port")
+        .long("server-ip-port")
+        .value_name("IP_PORT")
+        .default_value("127.0.0.1:3000")
+        .help("Act as a client, and connect to given server")
+        .takes_value(true)]
+}
+
+// Options of the subcommands which run nodes
+fn listen_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("port")
+            .long("port")
+            .value_name("PORT")
+            .default_value("3000")
+            .help("Set server port")
+            .takes_value(true),
+        Arg::with_name("bind-ip")
+            .long("bind-ip")
+            .value_name("BIND_IP")
+            .default_value("127.0.0.1")
+            .help("Address for server to listen on")
+            .takes_value(true),
+        Arg::with_name("data-dir")
+            .long("data-dir")
+            .value_name("DIR")
+            .default_value("/tmp/fleetfs")
+            .help("Set local directory used to store data")
+            .takes_value(true),
+    ]
+}
+
+fn server_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("peers")
+            .long("peers")
+            .value_name("PEERS")
+            .default_value("")
+            .help("Comma separated list of peer IP:PORT, or DNS-RECORD:PORT in which case DNS-RECORD must resolve to an A record containing --num-peers peers")
+            .takes_value(true),
+        Arg::with_name("cluster-name")
+            .long("cluster-name")
+            .value_name("NAME")
+            .default_value("fleetfs")
+            .help("Name of the cluster. A node refuses to start if its data dir, or one of its peers, belongs to a cluster with another name")
+            .takes_value(true),
+        Arg::with_name("num-peers")
+            .long("num-peers")
+            .value_name("NUM-PEERS")
+            .default_value("0")
+            .requires("peers")
+            .help("Number of peer records to expect in the DNS record specified in --peers")
+            .takes_value(true),
+        Arg::with_name("write-coalescing-window")
+            .long("write-coalescing-window")
+            .value_name("MICROSECONDS")
+            .default_value("0")
+            .help("As the leader, hold writes for up to MICROSECONDS to combine consecutive writes to the same file. 0 disables coalescing")
+            .takes_value(true),
+        Arg::with_name("write-coalescing-max-bytes")
+            .long("write-coalescing-max-bytes")
+            .value_name("BYTES")
+            .default_value("1048576")
+            .help("Maximum size of a combined write")
+            .takes_value(true),
+        Arg::with_name("join")
+            .long("join")
+            .help("Fetch a snapshot of the filesystem from a peer, and catch up with the cluster before voting or serving clients. Used to replace a node whose data was lost. The data dir must be empty. Implied for a node which ran before, if its data dir is now empty"),
+        Arg::with_name("observers")
+            .long("observers")
+            .value_name("OBSERVERS")
+            .default_value("")
+            .help("Comma separated list of IP:PORT of read-only observer nodes. A node whose --bind-ip:--port is in the list runs as an observer. Must be the same on every node")
+            .takes_value(true),
+        Arg::with_name("snapshot-bandwidth")
+            .long("snapshot-bandwidth")
+            .value_name("BYTES_PER_SEC")
+            .default_value("0")
+            .help("Limit the transfer of the snapshot fetched to join the cluster. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("replication-bandwidth")
+            .long("replication-bandwidth")
+            .value_name("BYTES_PER_SEC")
+            .default_value("0")
+            .help("Limit the total rate at which snapshots are sent to other nodes. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("peer-replication-bandwidth")
+            .long("peer-replication-bandwidth")
+            .value_name("BYTES_PER_SEC")
+            .default_value("0")
+            .help("Limit the rate at which snapshots are sent to each node. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("session-iops")
+            .long("session-iops")
+            .value_name("IOPS")
+            .default_value("0")
+            .help("Limit the requests per second of each client session. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("session-bandwidth")
+            .long("session-bandwidth")
+            .value_name("BYTES_PER_SEC")
+            .default_value("0")
+            .help("Limit the bytes read and written per second by each client session. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("block-cache-size")
+            .long("block-cache-size")
+            .value_name("BYTES")
+            .default_value("67108864")
+            .help("Memory used to cache data read from disk. 0 disables the cache")
+            .takes_value(true),
+        Arg::with_name("block-cache-admission")
+            .long("block-cache-admission")
+            .value_name("POLICY")
+            .possible_values(&["tinylfu", "lru"])
+            .default_value("tinylfu")
+            .help("Which data read from disk is cached. tinylfu only caches data read more often than what it would evict")
+            .takes_value(true),
+        Arg::with_name("hook-url")
+            .long("hook-url")
+            .value_name("URL")
+            .multiple(true)
+            .number_of_values(1)
+            .help("POST an event as JSON to this http:// URL when a file is written, a directory is created, or a write fails because the filesystem is full. May be given several times")
+            .takes_value(true),
+        Arg::with_name("hook-command")
+            .long("hook-command")
+            .value_name("PATH")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Run this command with the event as JSON on stdin, for the same events as --hook-url. May be given several times")
+            .takes_value(true),
+        Arg::with_name("hook-debounce")
+            .long("hook-debounce")
+            .value_name("MILLISECONDS")
+            .default_value("1000")
+            .help("Deliver repeated events for the same file once, after they stop for this long")
+            .takes_value(true),
+        Arg::with_name("background-bandwidth")
+            .long("background-bandwidth")
+            .value_name("BYTES_PER_SEC")
+            .default_value("0")
+            .help("Limit the IO of background maintenance, such as packing and defragmentation. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("content-index")
+            .long("content-index")
+            .help("Maintain an index of file contents on this node, to serve --search"),
+        Arg::with_name("case-insensitive")
+            .long("case-insensitive")
+            .help("Compare file names case-insensitively, while preserving their case. Must be the same on every node"),
+        Arg::with_name("fsck-checksum")
+            .long("fsck-checksum")
+            .value_name("ALGORITHM")
+            .possible_values(&["sha256", "blake3", "xxh3"])
+            .default_value("xxh3")
+            .help("Checksum algorithm used to compare the data on each node during fsck")
+            .takes_value(true),
+        Arg::with_name("wire-checksum")
+            .long("wire-checksum")
+            .value_name("ALGORITHM")
+            .possible_values(&["sha256", "blake3", "xxh3"])
+            .default_value("sha256")
+            .help("Checksum algorithm used to verify snapshots transferred between nodes")
+            .takes_value(true),
+        Arg::with_name("reserved-space")
+            .long("reserved-space")
+            .value_name("PERCENT")
+            .default_value("5")
+            .help("Percentage of the disk reserved for root. Writes from other users fail once only this much space is left")
+            .takes_value(true),
+        Arg::with_name("startup-check")
+            .long("startup-check")
+            .value_name("MODE")
+            .possible_values(&["repair", "refuse", "off"])
+            .default_value("repair")
+            .help("What to do if the data stored on the node is inconsistent with the metadata, when it starts")
+            .takes_value(true),
+        Arg::with_name("metadata-reads")
+            .long("metadata-reads")
+            .value_name("CONSISTENCY")
+            .possible_values(&["strict", "relaxed"])
+            .default_value("relaxed")
+            .help("Whether getattr, getxattr and listxattr wait for the node to catch up with the leader. Relaxed reads may miss changes made through other nodes, but not those made through the same node. Lookups always wait")
+            .takes_value(true),
+        Arg::with_name("mandatory-locking")
+            .long("mandatory-locking")
+            .value_name("MODE")
+            .possible_values(&["off", "fail", "block"])
+            .default_value("off")
+            .help("Whether reads and writes respect the byte range locks of other clients, which are otherwise advisory. fail rejects conflicting reads and writes with EAGAIN, and block waits until the locks are released. Every node of the volume must use the same mode")
+            .takes_value(true),
+        Arg::with_name("max-client-connections")
+            .long("max-client-connections")
+            .value_name("CONNECTIONS")
+            .default_value("0")
+            .help("Limit the connections of each client session to this node. Requests on further connections fail with EAGAIN. 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("max-client-open-handles")
+            .long("max-client-open-handles")
+            .value_name("HANDLES")
+            .default_value("0")
+            .help("Limit the files and directories each mount connected to this node may have open at once. Further opens fail with EMFILE. 0 is unlimited")
+            .takes_value(true),
+    ]
+}
+
+// Options of both nodes and mounts
+fn shared_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("atime")
+            .long("atime")
+            .value_name("MODE")
+            .possible_values(&["strictatime", "relatime", "noatime"])
+            .help("How reads update access times. As a server, sets the default for the volume. When mounting, overrides it")
+            .takes_value(true),
+        Arg::with_name("max-frame-size")
+            .long("max-frame-size")
+            .value_name("BYTES")
+            .default_value("67108864")
+            .help("Largest request a node accepts, and largest message a client sends or receives. Larger reads and writes are split into several requests")
+            .takes_value(true),
+        Arg::with_name("zone")
+            .long("zone")
+            .value_name("LABEL")
+            .help("Topology label, such as an availability zone or rack. As a server, it's reported in the cluster stats. When mounting with --read-from=nearest, nodes with the same label are preferred")
+            .takes_value(true),
+    ]
+}
+
+fn mount_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("mount-point")
+            .long("mount-point")
+            .value_name("MOUNT_POINT")
+            .default_value("")
+            .help("Act as a client, and mount FUSE at given path")
+            .takes_value(true),
+        Arg::with_name("direct-io")
+            .long("direct-io")
+            .requires("mount-point")
+            .help("Mount FUSE with direct IO"),
+        Arg::with_name("supervise")
+            .long("supervise")
+            .requires("mount-point")
+            .help("Mount FUSE again whenever the session ends, or the server is unreachable for --remount-after. Runs until terminated"),
+        Arg::with_name("remount-after")
+            .long("remount-after")
+            .value_name("SECONDS")
+            .default_value("60")
+            .requires("supervise")
+            .help("Remount if the server has been unreachable for SECONDS")
+            .takes_value(true),
+        Arg::with_name("keepalive-interval")
+            .long("keepalive-interval")
+            .value_name("MILLISECONDS")
+            .default_value("5000")
+            .requires("mount-point")
+            .help("Ping the server when the connection has been idle for MILLISECONDS, to detect dead connections. 0 disables keepalive")
+            .takes_value(true),
+        Arg::with_name("keepalive-timeout")
+            .long("keepalive-timeout")
+            .value_name("MILLISECONDS")
+            .default_value("2000")
+            .requires("mount-point")
+            .help("Reconnect if a keepalive ping isn't answered within MILLISECONDS")
+            .takes_value(true),
+        Arg::with_name("unreachable")
+            .long("unreachable")
+            .value_name("POLICY")
+            .possible_values(&["hang", "fail", "stale"])
+            .default_value("hang")
+            .requires("mount-point")
+            .help("What requests do while the server is unreachable: wait for it, fail with EIO after --unreachable-timeout, or answer from the metadata cached by the mount, read-only")
+            .takes_value(true),
+        Arg::with_name("unreachable-timeout")
+            .long("unreachable-timeout")
+            .value_name("SECONDS")
+            .default_value("30")
+            .help("With --unreachable=fail, fail requests once the server has been unreachable for SECONDS")
+            .takes_value(true),
+        Arg::with_name("offline-dir")
+            .long("offline-dir")
+            .value_name("DIR")
+            .requires("mount-point")
+            .help("Keep a copy of the files opened in DIR, to read and write them while the server is unreachable. Writes are replayed once it's reachable again, and files which were also changed on the server are saved in DIR/conflicts instead. The writes not yet replayed are in the fleetfs.sync_status xattr of any file. Implies --unreachable=stale")
+            .takes_value(true),
+        Arg::with_name("disk-cache-dir")
+            .long("disk-cache-dir")
+            .value_name("DIR")
+            .requires("mount-point")
+            .help("Cache the data and directory listings read through the mount in DIR, so that they survive remounts. Files are checked for changes on the server when they're opened, and listings when they're read")
+            .takes_value(true),
+        Arg::with_name("disk-cache-size")
+            .long("disk-cache-size")
+            .value_name("MB")
+            .default_value("1024")
+            .help("Maximum size of --disk-cache-dir")
+            .takes_value(true),
+        Arg::with_name("client-memory")
+            .long("client-memory")
+            .value_name("MB")
+            .default_value("256")
+            .requires("mount-point")
+            .help("Memory used by the read-ahead and directory listing caches of the mount. The least recently used entries are evicted once it's exceeded. Their usage is in the fleetfs.client_memory xattr of any file")
+            .takes_value(true),
+        Arg::with_name("read-coalescing-window")
+            .long("read-coalescing-window")
+            .value_name("MICROSECONDS")
+            .default_value("0")
+            .requires("mount-point")
+            .help("Hold small reads for up to MICROSECONDS to merge them with other reads of nearby data in the same file into one request. Files whose reads are never merged are read directly for a while. 0 disables coalescing")
+            .takes_value(true),
+        Arg::with_name("fuse-workers")
+            .long("fuse-workers")
+            .value_name("THREADS")
+            .default_value("16")
+            .requires("mount-point")
+            .help("Number of FUSE requests which are processed concurrently")
+            .takes_value(true),
+        Arg::with_name("read-from")
+            .long("read-from")
+            .value_name("NODE")
+            .possible_values(&["server", "nearest"])
+            .default_value("server")
+            .requires("mount-point")
+            .help("Where the mount sends getattr and xattr reads. nearest sends them to the node with the lowest round trip time, preferring those in --zone, which may not have applied the latest writes yet")
+            .takes_value(true),
+    ]
+}
+
+fn admin_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("set-replication-bandwidth")
+            .long("set-replication-bandwidth")
+            .value_names(&["GLOBAL", "PER_PEER"])
+            .help("Change the snapshot bandwidth limits, in bytes/sec, of the node at --server-ip-port")
+            .number_of_values(2),
+        Arg::with_name("set-session-limits")
+            .long("set-session-limits")
+            .value_names(&["SESSION", "IOPS", "BYTES_PER_SEC"])
+            .help(
+                "Change the rate limits of a client session on the node at --server-ip-port. \
+                 Session 0 changes the default limits",
+            )
+            .number_of_values(3),
+        Arg::with_name("set-limits")
+            .long("set-limits")
+            .value_names(&[
+                "MAX_FILE_SIZE",
+                "MAX_NAME_LENGTH",
+                "MAX_XATTR_SIZE",
+                "MAX_INODES",
+            ])
+            .help(
+                "Change the limits of the filesystem, which are enforced by every node and client. \
+                 Existing files which exceed them are kept",
+            )
+            .number_of_values(4),
+        Arg::with_name("freeze")
+            .long("freeze")
+            .value_names(&["PATH", "SECONDS"])
+            .help(
+                "Make PATH, and everything below it, read-only on every node, so that it can be backed up in a \
+                 consistent state. It's thawed after SECONDS, or with --thaw",
+            )
+            .number_of_values(2),
+        Arg::with_name("thaw")
+            .long("thaw")
+            .value_name("PATH")
+            .help("Make PATH writable again, after --freeze")
+            .takes_value(true),
+        Arg::with_name("block-cache-stats")
+            .long("block-cache-stats")
+            .help("Print the block cache statistics of the node at --server-ip-port"),
+        Arg::with_name("top")
+            .long("top")
+            .help("Continuously print the utilization, request rates, and Raft lag of every node in the cluster"),
+        Arg::with_name("top-interval")
+            .long("top-interval")
+            .value_name("SECONDS")
+            .default_value("2")
+            .help("Refresh interval of --top")
+            .takes_value(true),
+        Arg::with_name("heatmap")
+            .long("heatmap")
+            .help("Print the files which have been read and written the most recently"),
+        Arg::with_name("heatmap-limit")
+            .long("heatmap-limit")
+            .value_name("FILES")
+            .default_value("20")
+            .help("Number of files printed by --heatmap")
+            .takes_value(true),
+        Arg::with_name("background")
+            .long("background")
+            .value_name("ACTION")
+            .possible_values(&["status", "pause", "resume"])
+            .help("Pause or resume the background maintenance of the node at --server-ip-port, and print its status")
+            .takes_value(true),
+        Arg::with_name("search")
+            .long("search")
+            .value_name("TEXT")
+            .help("Search the contents of files for TEXT. Server must be started with --content-index")
+            .takes_value(true),
+        Arg::with_name("set-log-level")
+            .long("set-log-level")
+            .value_name("[MODULE=]LEVEL")
+            .help("Set the log level of the server, or only of MODULE (like fleetfs::storage) and its submodules. LEVEL is one of off, error, warn, info, debug, trace")
+            .takes_value(true),
+        Arg::with_name("dump-requests")
+            .long("dump-requests")
+            .value_name("on|off")
+            .possible_values(&["on", "off"])
+            .help("Toggle logging of every request received by the server, at the info level")
+            .takes_value(true),
+        Arg::with_name("get-leader")
+            .long("get-leader")
+            .help("Print the ID of the leader node"),
+        Arg::with_name("force-new-cluster")
+            .long("force-new-cluster")
+            .help(
+                "Disaster recovery, when quorum is permanently lost. Removes every other node from the cluster \
+                 of the node at --server-ip-port, which must be run on the same host. Blocks of striped files \
+                 stored on the other nodes are lost",
+            ),
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .requires("force-new-cluster")
+            .help("Only print what --force-new-cluster would do"),
+        Arg::with_name("raft-debug")
+            .long("raft-debug")
+            .help("Print the Raft state of the node at --server-ip-port"),
+        Arg::with_name("log-tail")
+            .long("log-tail")
+            .value_name("ENTRIES")
+            .requires("raft-debug")
+            .default_value("10")
+            .help("Number of entries at the end of the Raft log to print")
+            .takes_value(true),
+        Arg::with_name("inflight")
+            .long("inflight")
+            .help("Print the requests which the node at --server-ip-port is executing, and what each is waiting for"),
+        Arg::with_name("list-clients")
+            .long("list-clients")
+            .help("Print the client sessions connected to each node, with the mount they belong to"),
+        Arg::with_name("evict-client")
+            .long("evict-client")
+            .value_name("SESSION")
+            .help(
+                "Evict a client session, as listed by --list-clients: its locks are released, its connections \
+                 are closed, and its later requests fail with ESTALE until it's remounted",
+            )
+            .takes_value(true),
+        Arg::with_name("locks")
+            .long("locks")
+            .help("Print the holders and waiters of the byte range locks, by inode"),
+        Arg::with_name("break-locks")
+            .long("break-locks")
+            .value_name("INODE")
+            .help(
+                "Forcibly release the byte range locks of INODE, or of every inode if it's 0 and \
+                 --break-locks-session is given. Writes made under them are rejected",
+            )
+            .takes_value(true),
+        Arg::with_name("break-locks-session")
+            .long("break-locks-session")
+            .value_name("SESSION")
+            .requires("break-locks")
+            .help("Only release the locks of SESSION, such as a crashed client")
+            .takes_value(true),
+        Arg::with_name("du")
+            .long("du")
+            .value_name("PATH")
+            .help("Print the total size and number of inodes below PATH")
+            .takes_value(true),
+        Arg::with_name("find")
+            .long("find")
+            .value_name("PATH")
+            .help("Search for files below PATH")
+            .takes_value(true),
+        Arg::with_name("glob")
+            .long("glob")
+            .value_name("PATTERN")
+            .help("Print the size and path of each file matching PATTERN, an absolute path which may contain wildcards")
+            .takes_value(true),
+        Arg::with_name("archive")
+            .long("archive")
+            .value_name("PATH")
+            .help("Write a tar archive of the files below PATH to stdout. Use --freeze first, to archive a consistent state")
+            .takes_value(true),
+        Arg::with_name("verify")
+            .long("verify")
+            .value_name("PATH")
+            .help("Compare the copies of PATH, or of the files below it, stored on each node")
+            .takes_value(true),
+        Arg::with_name("prefetch")
+            .long("prefetch")
+            .value_name("PATH")
+            .help("Load PATH, or the files below it, into the block caches of the nodes")
+            .takes_value(true),
+        Arg::with_name("upload")
+            .long("upload")
+            .value_names(&["LOCAL_FILE", "PATH"])
+            .help("Copy LOCAL_FILE to PATH, which only appears once the copy is complete")
+            .number_of_values(2),
+        Arg::with_name("create-log")
+            .long("create-log")
+            .value_name("PATH")
+            .help("Create a shared log at PATH, which many clients can append records to")
+            .takes_value(true),
+        Arg::with_name("follow-log")
+            .long("follow-log")
+            .value_name("PATH")
+            .help("Print the records of the shared log at PATH, and then the ones appended to it")
+            .takes_value(true),
+        Arg::with_name("name")
+            .long("name")
+            .value_name("GLOB")
+            .requires("find")
+            .help("Only find entries whose name matches GLOB")
+            .takes_value(true),
+        Arg::with_name("min-size")
+            .long("min-size")
+            .value_name("BYTES")
+            .requires("find")
+            .help("Only find entries of at least BYTES")
+            .takes_value(true),
+        Arg::with_name("max-size")
+            .long("max-size")
+            .value_name("BYTES")
+            .requires("find")
+            .help("Only find entries of at most BYTES")
+            .takes_value(true),
+        Arg::with_name("modified-after")
+            .long("modified-after")
+            .value_name("UNIX_SECONDS")
+            .requires("find")
+            .help("Only find entries modified after UNIX_SECONDS")
+            .takes_value(true),
+        Arg::with_name("modified-before")
+            .long("modified-before")
+            .value_name("UNIX_SECONDS")
+            .requires("find")
+            .help("Only find entries modified before UNIX_SECONDS")
+            .takes_value(true),
+        Arg::with_name("xattr")
+            .long("xattr")
+            .value_name("KEY[=VALUE]")
+            .requires("find")
+            .help("Only find entries with xattr KEY, and optionally the given VALUE")
+            .takes_value(true),
+    ]
+}
+
+fn fsck_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("fsck-progress")
+            .long("fsck-progress")
+            .help("Print the progress of the filesystem check running on the server"),
+        Arg::with_name("repair")
+            .long("repair")
+            .help("Repair metadata inconsistencies found by --fsck, which it implies. Unreachable files are moved to /lost+found"),
+    ]
+}
+
+fn bench_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("bench-size")
+            .long("bench-size")
+            .value_name("MB")
+            .default_value("256")
+            .help("Size of the file written and read by the bench subcommand")
+            .takes_value(true),
+        Arg::with_name("bench-chunk-size")
+            .long("bench-chunk-size")
+            .value_name("KB")
+            .default_value("1024")
+            .help("Size of each write and read made by the bench subcommand. Transfers larger than half of --max-frame-size are split into several requests")
+            .takes_value(true),
+    ]
+}
+
+fn dev_cluster_options() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("nodes")
+        .long("nodes")
+        .value_name("N")
+        .default_value("3")
+        .help("Number of nodes started by the dev-cluster subcommand")
+        .takes_value(true)]
+}
+
+// Options accepted without a subcommand, in which case they select what to run
+fn legacy_options() -> Vec<Arg<'static, 'static>> {
+    let mut options = vec![Arg::with_name("fsck")
+        .long("fsck")
+        .help("Run a filesystem check on the cluster")];
+    options.extend(common_options());
+    options.extend(client_options());
+    options.extend(listen_options());
+    options.extend(server_options());
+    options.extend(shared_options());
+    options.extend(mount_options());
+    options.extend(admin_options());
+    options.extend(fsck_options());
+
+    options
+}
+
+// Subcommand which accepts the options of each of groups
+fn with_options(
+    name: &'static str,
+    about: &'static str,
+    groups: &[fn() -> Vec<Arg<'static, 'static>>],
+) -> App<'static, 'static> {
+    let mut subcommand = SubCommand::with_name(name).about(about);
+    for group in groups {
+        subcommand = subcommand.args(&group());
+    }
 
-    env_logger::builder()
-        .default_format_timestamp_nanos(true)
-        .filter_level(log_level)
-        .init();
+    subcommand
+}
 
+// The subcommand which the options given without one select, like before there were subcommands
+fn implied_subcommand(matches: &ArgMatches) -> &'static str {
+    if matches.is_present("fsck")
+        || matches.is_present("repair")
+        || matches.is_present("fsck-progress")
+    {
+        "fsck"
+    } else if ADMIN_COMMANDS.iter().any(|x| matches.is_present(x)) {
+        "admin"
+    } else if matches
+        .value_of("mount-point")
+        .unwrap_or_default()
+        .is_empty()
+    {
+        "server"
+    } else {
+        "mount"
+    }
+}
+
+fn current_user() -> UserContext {
+    UserContext::new(unsafe { libc::getuid() }, unsafe { libc::getgid() })
+}
+
+fn server_address(matches: &ArgMatches) -> SocketAddr {
+    matches
+        .value_of("server-ip-port")
+        .unwrap_or_default()
+        .parse()
+        .unwrap()
+}
+
+fn bind_address(matches: &ArgMatches) -> SocketAddr {
     let port: u16 = matches
         .value_of("port")
         .unwrap_or_default()
         .parse()
         .unwrap();
-    let data_dir: String = matches.value_of("data-dir").unwrap_or_default().to_string();
     let bind_ip: IpAddr = matches
         .value_of("bind-ip")
         .unwrap_or_default()
         .parse()
         .unwrap();
-    let bind_address: SocketAddr = (bind_ip, port).into();
-    let server_ip_port: SocketAddr = matches
-        .value_of("server-ip-port")
+
+    (bind_ip, port).into()
+}
+
+fn max_frame_length(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("max-frame-size")
         .unwrap_or_default()
         .parse()
-        .unwrap();
-    let mount_point: String = matches
-        .value_of("mount-point")
-        .unwrap_or_default()
-        .to_string();
-    let direct_io: bool = matches.is_present("direct-io");
-    let fsck: bool = matches.is_present("fsck");
-    let get_leader: bool = matches.is_present("get-leader");
+        .unwrap()
+}
+
+fn atime_mode(matches: &ArgMatches) -> Option<AtimeMode> {
+    matches.value_of("atime").map(|mode| match mode {
+        "strictatime" => AtimeMode::StrictAtime,
+        "relatime" => AtimeMode::RelAtime,
+        "noatime" => AtimeMode::NoAtime,
+        _ => unreachable!(),
+    })
+}
+
+// Resolves --peers, waiting for the DNS record to contain --num-peers addresses if it's given
+fn resolve_peers(matches: &ArgMatches, bind_address: SocketAddr) -> Vec<SocketAddr> {
     let num_peers: usize = matches
         .value_of("num-peers")
         .unwrap_or_default()
         .parse()
         .unwrap();
-    let peers: Vec<SocketAddr> = if num_peers > 0 {
-        let record = format!("{}:{}", matches.value_of("peers").unwrap_or_default(), port);
-        let mut found_peers: Vec<SocketAddr> = match record.to_socket_addrs() {
+    if num_peers == 0 {
+        return matches
+            .value_of("peers")
+            .unwrap_or_default()
+            .split(',')
+            .map(ToString::to_string)
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse().unwrap())
+            .collect();
+    }
+
+    let record = format!(
+        "{}:{}",
+        matches.value_of("peers").unwrap_or_default(),
+        bind_address.port()
+    );
+    let mut found_peers: Vec<SocketAddr> = match record.to_socket_addrs() {
+        Ok(addresses) => addresses.collect(),
+        Err(error) => {
+            warn!("Encountered error in DNS lookup of {}: {:?}", record, error);
+            vec![]
+        }
+    };
+    while found_peers.len() < num_peers {
+        found_peers = match record.to_socket_addrs() {
             Ok(addresses) => addresses.collect(),
             Err(error) => {
                 warn!("Encountered error in DNS lookup of {}: {:?}", record, error);
                 vec![]
             }
         };
-        while found_peers.len() < num_peers {
-            found_peers = match record.to_socket_addrs() {
-                Ok(addresses) => addresses.collect(),
-                Err(error) => {
-                    warn!("Encountered error in DNS lookup of {}: {:?}", record, error);
-                    vec![]
-                }
-            };
-            debug!(
-                "Found {:?} peers. Waiting for {} peers",
-                found_peers, num_peers
-            );
-            sleep(Duration::from_secs(1));
-        }
+        debug!(
+            "Found {:?} peers. Waiting for {} peers",
+            found_peers, num_peers
+        );
+        sleep(Duration::from_secs(1));
+    }
+
+    found_peers.retain(|x| *x != bind_address);
+    return found_peers;
+}
+
+fn node_config(matches: &ArgMatches) -> NodeConfig {
+    let bind_address = bind_address(matches);
+    let mut peers = resolve_peers(matches, bind_address);
+    let mut observers: Vec<SocketAddr> = matches
+        .value_of("observers")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().unwrap())
+        .collect();
+    let observer = observers.contains(&bind_address);
+    peers.retain(|x| !observers.contains(x));
+    observers.retain(|x| *x != bind_address);
+
+    let reserved_space_percent: u8 = matches
+        .value_of("reserved-space")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+    assert!(reserved_space_percent <= 100);
+
+    NodeConfig {
+        node_dir: matches.value_of("data-dir").unwrap_or_default().to_string(),
+        cluster_name: matches
+            .value_of("cluster-name")
+            .unwrap_or_default()
+            .to_string(),
+        bind_address,
+        peers,
+        observers,
+        observer,
+        content_index: matches.is_present("content-index"),
+        atime_mode: atime_mode(matches).unwrap_or(AtimeMode::RelAtime),
+        write_coalescing: WriteCoalescing {
+            window: Duration::from_micros(
+                matches
+                    .value_of("write-coalescing-window")
+                    .unwrap_or_default()
+                    .parse()
+                    .unwrap(),
+            ),
+            max_batch_bytes: matches
+                .value_of("write-coalescing-max-bytes")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        },
+        authorizer: Arc::new(AllowAll),
+        case_insensitive: matches.is_present("case-insensitive"),
+        checksums: ChecksumConfig {
+            fsck: parse_checksum_algorithm(matches.value_of("fsck-checksum").unwrap_or_default())
+                .unwrap(),
+            wire: parse_checksum_algorithm(matches.value_of("wire-checksum").unwrap_or_default())
+                .unwrap(),
+        },
+        reserved_space_percent,
+        replication_limiter: BandwidthLimiter::new(
+            matches
+                .value_of("replication-bandwidth")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+            matches
+                .value_of("peer-replication-bandwidth")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        ),
+        session_limiter: SessionLimiter::new(SessionLimits {
+            iops: matches
+                .value_of("session-iops")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+            bytes_per_second: matches
+                .value_of("session-bandwidth")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        }),
+        startup_check: match matches.value_of("startup-check").unwrap_or_default() {
+            "refuse" => StartupCheck::Refuse,
+            "off" => StartupCheck::Off,
+            _ => StartupCheck::Repair,
+        },
+        background_bytes_per_second: matches
+            .value_of("background-bandwidth")
+            .unwrap_or_default()
+            .parse()
+            .unwrap(),
+        block_cache: BlockCacheConfig {
+            capacity_bytes: matches
+                .value_of("block-cache-size")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+            admission: match matches
+                .value_of("block-cache-admission")
+                .unwrap_or_default()
+            {
+                "lru" => CacheAdmission::Lru,
+                _ => CacheAdmission::TinyLfu,
+            },
+        },
+        max_frame_length: max_frame_length(matches),
+        hooks: HookConfig {
+            urls: matches
+                .values_of("hook-url")
+                .map(|values| values.map(ToString::to_string).collect())
+                .unwrap_or_default(),
+            commands: matches
+                .values_of("hook-command")
+                .map(|values| values.map(ToString::to_string).collect())
+                .unwrap_or_default(),
+            debounce: Duration::from_millis(
+                matches
+                    .value_of("hook-debounce")
+                    .unwrap_or_default()
+                    .parse()
+                    .unwrap(),
+            ),
+        },
+        relaxed_metadata_reads: matches.value_of("metadata-reads") == Some("relaxed"),
+        zone: matches.value_of("zone").unwrap_or_default().to_string(),
+        mandatory_locking: match matches.value_of("mandatory-locking").unwrap_or_default() {
+            "fail" => MandatoryLocking::Fail,
+            "block" => MandatoryLocking::Block,
+            _ => MandatoryLocking::Off,
+        },
+        client_limits: ClientLimits {
+            max_connections: matches
+                .value_of("max-client-connections")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+            max_open_handles: matches
+                .value_of("max-client-open-handles")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        },
+        join: matches.is_present("join"),
+        snapshot_bandwidth: matches
+            .value_of("snapshot-bandwidth")
+            .unwrap_or_default()
+            .parse()
+            .unwrap(),
+    }
+}
 
-        found_peers.retain(|x| *x != bind_address);
-        found_peers
+fn mount_config(matches: &ArgMatches, server_ip_port: SocketAddr) -> MountConfig {
+    let keepalive_interval: u64 = matches
+        .value_of("keepalive-interval")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+    let keepalive: Option<Keepalive> = if keepalive_interval > 0 {
+        Some(Keepalive {
+            interval: Duration::from_millis(keepalive_interval),
+            timeout: Duration::from_millis(
+                matches
+                    .value_of("keepalive-timeout")
+                    .unwrap_or_default()
+                    .parse()
+                    .unwrap(),
+            ),
+        })
     } else {
-        matches
-            .value_of("peers")
+        None
+    };
+    let offline_dir: Option<String> = matches.value_of("offline-dir").map(ToString::to_string);
+    let unreachable_policy = match matches.value_of("unreachable").unwrap_or_default() {
+        _ if offline_dir.is_some() => UnreachablePolicy::ServeStale,
+        "fail" => UnreachablePolicy::FailAfter(Duration::from_secs(
+            matches
+                .value_of("unreachable-timeout")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        )),
+        "stale" => UnreachablePolicy::ServeStale,
+        _ => UnreachablePolicy::Hang,
+    };
+    let disk_cache: Option<(String, u64)> = matches.value_of("disk-cache-dir").map(|dir| {
+        let size: u64 = matches
+            .value_of("disk-cache-size")
             .unwrap_or_default()
-            .split(',')
-            .map(ToString::to_string)
-            .filter(|x| !x.is_empty())
-            .map(|x| x.parse().unwrap())
-            .collect()
+            .parse()
+            .unwrap();
+        (dir.to_string(), size * 1024 * 1024)
+    });
+    let client_memory: u64 = matches
+        .value_of("client-memory")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+    let nearest_reads: Option<Option<String>> = if matches.value_of("read-from") == Some("nearest")
+    {
+        Some(matches.value_of("zone").map(ToString::to_string))
+    } else {
+        None
     };
 
-    if fsck {
-        let client = NodeClient::new(server_ip_port);
+    MountConfig {
+        server_ip_port,
+        mount_point: matches
+            .value_of("mount-point")
+            .unwrap_or_default()
+            .to_string(),
+        atime_mode: atime_mode(matches).unwrap_or(AtimeMode::VolumeDefault),
+        keepalive,
+        workers: matches
+            .value_of("fuse-workers")
+            .unwrap_or_default()
+            .parse()
+            .unwrap(),
+        unreachable_policy,
+        offline_dir,
+        disk_cache,
+        max_frame_length: max_frame_length(matches),
+        memory_budget_bytes: client_memory * 1024 * 1024,
+        nearest_reads,
+        read_coalescing_window: Duration::from_micros(
+            matches
+                .value_of("read-coalescing-window")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        ),
+    }
+}
+
+fn run_server(matches: &ArgMatches, log_control: LogControl) -> Result<(), ErrorCode> {
+    let config = node_config(matches);
+    println!("Starting with peers: {:?}", &config.peers);
+    Node::new(config, log_control).run();
+
+    Ok(())
+}
+
+fn run_mount(matches: &ArgMatches, server_ip_port: SocketAddr) -> Result<(), ErrorCode> {
+    let config = mount_config(matches, server_ip_port);
+    if config.mount_point.is_empty() {
+        eprintln!("--mount-point is required");
+        return Err(ErrorCode::InvalidArgument);
+    }
+    println!(
+        "Connecting to server {} and mounting FUSE at {}",
+        &server_ip_port, &config.mount_point
+    );
+    let mut fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o")];
+    let mut options = "fsname=fleetfs,auto_unmount".to_string();
+    if matches.is_present("direct-io") {
+        println!("Using Direct IO");
+        options.push_str(",direct_io");
+    }
+    if let Ok(enabled) = fuse_allow_other_enabled() {
+        if enabled {
+            options.push_str(",allow_other");
+        }
+    } else {
+        eprintln!("Unable to read /etc/fuse.conf");
+    }
+
+    if matches.is_present("supervise") {
+        let remount_after = Duration::from_secs(
+            matches
+                .value_of("remount-after")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+        );
+        supervise_mount(&config, &options, remount_after);
+    }
+
+    fuse_args.push(&OsStr::new(&options));
+    let fs = FleetFUSE::new(&config);
+    println!("Session ID: {}", fs.session_id());
+    fuse::mount(fs, &config.mount_point, &fuse_args).unwrap();
+
+    Ok(())
+}
+
+fn run_dev_cluster(matches: &ArgMatches) -> Result<(), ErrorCode> {
+    let nodes: u16 = matches
+        .value_of("nodes")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+    if nodes == 0 {
+        eprintln!("--nodes must be at least 1");
+        return Err(ErrorCode::InvalidArgument);
+    }
+    let bind_address = bind_address(matches);
+    let data_dir = matches.value_of("data-dir").unwrap_or_default();
+    let verbosity = matches.occurrences_of("v");
+    let executable = std::env::current_exe().expect("Failed to find the fleetfs executable");
+    let addresses: Vec<SocketAddr> = (0..nodes)
+        .map(|i| (bind_address.ip(), bind_address.port() + i).into())
+        .collect();
+    let mut dev_nodes = start_nodes(&executable, &addresses, Path::new(data_dir), verbosity);
+    wait_for_leader(bind_address)?;
+    println!("Started {} nodes. Connect to {}", nodes, bind_address);
+
+    let result = if matches
+        .value_of("mount-point")
+        .unwrap_or_default()
+        .is_empty()
+    {
+        // The nodes run until they're interrupted
+        for node in dev_nodes.iter_mut() {
+            node.wait().ok();
+        }
+        Ok(())
+    } else {
+        run_mount(matches, bind_address)
+    };
+
+    for mut node in dev_nodes {
+        node.kill().ok();
+        node.wait().ok();
+    }
+
+    return result;
+}
+
+fn run_bench(matches: &ArgMatches) -> Result<(), ErrorCode> {
+    let client = NodeClient::new(server_address(matches));
+    let size: u64 = matches
+        .value_of("bench-size")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+    let chunk_size: u32 = matches
+        .value_of("bench-chunk-size")
+        .unwrap_or_default()
+        .parse()
+        .unwrap();
+
+    return bench(
+        &client,
+        size * 1024 * 1024,
+        chunk_size * 1024,
+        current_user(),
+    );
+}
+
+fn run_import(matches: &ArgMatches) -> Result<(), ErrorCode> {
+    let client = NodeClient::new(server_address(matches));
+    let context = current_user();
+    let local_dir = matches.value_of("LOCAL_DIR").unwrap_or_default();
+    let path = matches.value_of("PATH").unwrap_or_default();
+    let inode = client.lookup_path(path, context)?;
+    let (files, bytes) = import_directory(&client, Path::new(local_dir), inode, context)?;
+    println!("Imported {} files ({} bytes)", files, bytes);
+
+    Ok(())
+}
+
+fn run_fsck(matches: &ArgMatches) -> Result<(), ErrorCode> {
+    let client = NodeClient::new(server_address(matches));
+    if matches.is_present("repair") {
+        let problems = client.fsck_repair()?;
+        for problem in problems.iter() {
+            println!("Repaired: {}", problem);
+        }
+        println!("Repaired {} problems", problems.len());
+    } else if matches.is_present("fsck-progress") {
+        let (running, total_files, hashed_files, hashed_bytes) = client.checksum_progress()?;
+        println!(
+            "{}: hashed {}/{} files ({} bytes)",
+            if running { "Running" } else { "Finished" },
+            hashed_files,
+            total_files,
+            hashed_bytes
+        );
+    } else {
         match client.fsck() {
             Ok(_) => println!("Filesystem is ok"),
             Err(e) => {
@@ -206,35 +1493,570 @@ fn main() -> Result<(), ErrorCode> {
                 return Err(e);
             }
         }
-    } else if get_leader {
-        let client = NodeClient::new(server_ip_port);
+    }
+
+    Ok(())
+}
+
+fn run_admin(matches: &ArgMatches) -> Result<(), ErrorCode> {
+    let client = NodeClient::new(server_address(matches));
+    let context = current_user();
+    if matches.is_present("set-log-level") || matches.is_present("dump-requests") {
+        if let Some(setting) = matches.value_of("set-log-level") {
+            let mut parts = setting.rsplitn(2, '=');
+            let level: LevelFilter = match parts.next().unwrap_or_default().parse() {
+                Ok(level) => level,
+                Err(_) => {
+                    eprintln!("Invalid log level: {}", setting);
+                    return Err(ErrorCode::BadRequest);
+                }
+            };
+            client.set_log_level(parts.next(), to_log_level(level))?;
+        }
+        if let Some(enabled) = matches.value_of("dump-requests").map(|x| x == "on") {
+            client.set_request_dumping(enabled)?;
+        }
+    } else if matches.is_present("get-leader") {
         println!("Leader: {}", client.leader_id()?);
-    } else if mount_point.is_empty() {
-        println!("Starting with peers: {:?}", &peers);
-        Node::new(&data_dir, bind_address, peers).run();
+    } else if matches.is_present("force-new-cluster") {
+        force_new_cluster(&client, matches.is_present("dry-run"))?;
+    } else if let Some(limits) = matches.values_of("set-replication-bandwidth") {
+        let limits: Vec<u64> = limits.map(|x| x.parse().unwrap()).collect();
+        client.set_replication_bandwidth(limits[0], limits[1])?;
+    } else if let Some(limits) = matches.values_of("set-session-limits") {
+        let limits: Vec<u64> = limits.map(|x| x.parse().unwrap()).collect();
+        client.set_session_limits(limits[0], limits[1], limits[2])?;
+    } else if let Some(limits) = matches.values_of("set-limits") {
+        let limits: Vec<u64> = limits.map(|x| x.parse().unwrap()).collect();
+        client.set_limits(FilesystemLimits::new(
+            limits[0],
+            limits[3],
+            limits[1] as u32,
+            limits[2] as u32,
+        ))?;
+    } else if let Some(arguments) = matches.values_of("freeze") {
+        let arguments: Vec<&str> = arguments.collect();
+        let inode = client.lookup_path(arguments[0], context)?;
+        let timeout_seconds = arguments[1]
+            .parse()
+            .map_err(|_| ErrorCode::InvalidArgument)?;
+        client.freeze(inode, timeout_seconds)?;
+    } else if let Some(path) = matches.value_of("thaw") {
+        let inode = client.lookup_path(path, context)?;
+        client.thaw(inode)?;
+    } else if matches.is_present("block-cache-stats") {
+        print_block_cache_stats(&client)?;
+    } else if matches.is_present("top") {
+        let top_interval: u64 = matches
+            .value_of("top-interval")
+            .unwrap_or_default()
+            .parse()
+            .unwrap();
+        run_top(&client, Duration::from_secs(top_interval))?;
+    } else if matches.is_present("heatmap") {
+        let heatmap_limit: u32 = matches
+            .value_of("heatmap-limit")
+            .unwrap_or_default()
+            .parse()
+            .unwrap();
+        print_heatmap(&client, heatmap_limit)?;
+    } else if let Some(action) = matches.value_of("background") {
+        let action = match action {
+            "status" => BackgroundAction::Status,
+            "pause" => BackgroundAction::Pause,
+            "resume" => BackgroundAction::Resume,
+            _ => unreachable!(),
+        };
+        print_background_status(&client, action)?;
+    } else if matches.is_present("raft-debug") {
+        let log_tail: u32 = matches
+            .value_of("log-tail")
+            .unwrap_or_default()
+            .parse()
+            .unwrap();
+        print_raft_debug(&client, log_tail)?;
+    } else if matches.is_present("inflight") {
+        print_inflight(&client)?;
+    } else if matches.is_present("list-clients") {
+        print_clients(&client)?;
+    } else if let Some(session_id) = matches.value_of("evict-client") {
+        evict_client(&client, session_id.parse().unwrap())?;
+    } else if matches.is_present("locks") {
+        print_locks(&client.lock_status(0)?);
+    } else if let Some(inode) = matches.value_of("break-locks") {
+        let session_id: u64 = matches
+            .value_of("break-locks-session")
+            .unwrap_or("0")
+            .parse()
+            .unwrap();
+        let broken = client.break_locks(inode.parse().unwrap(), session_id)?;
+        println!("Released {} locks:", broken.len());
+        print_locks(&broken);
+    } else if let Some(path) = matches.value_of("du") {
+        let inode = client.lookup_path(path, context)?;
+        let (bytes, inodes) = client.get_tree_usage(inode)?;
+        println!("{}\t{} inodes\t{}", bytes, inodes, path);
+    } else if let Some(path) = matches.value_of("prefetch") {
+        let inode = client.lookup_path(path, context)?;
+        let (files, bytes) = client.prefetch(inode, context)?;
+        println!("Prefetched {} files ({} bytes)", files, bytes);
+    } else if let Some(path) = matches.value_of("verify") {
+        verify(&client, path, context)?;
+    } else if let Some(path) = matches.value_of("archive") {
+        let inode = client.lookup_path(path, context)?;
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        client.export_archive(inode, context, &mut out)?;
+        out.flush().map_err(into_error_code)?;
+    } else if let Some(paths) = matches.values_of("upload") {
+        let paths: Vec<&str> = paths.collect();
+        let data = std::fs::read(paths[0]).map_err(into_error_code)?;
+        client.upload(&data, paths[1], context)?;
+    } else if let Some(path) = matches.value_of("create-log") {
+        client.create_log(path, context)?;
+    } else if let Some(path) = matches.value_of("follow-log") {
+        follow_log(&client, path, context)?;
+    } else if let Some(path) = matches.value_of("find") {
+        find(&client, path, &find_query(matches), context)?;
+    } else if let Some(pattern) = matches.value_of("glob") {
+        glob(&client, pattern, context)?;
+    } else if let Some(text) = matches.value_of("search") {
+        search(&client, text, context)?;
     } else {
+        eprintln!("No administrative command given");
+        return Err(ErrorCode::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+fn force_new_cluster(client: &NodeClient, dry_run: bool) -> Result<(), ErrorCode> {
+    let (removed_nodes, striped_files) = client.force_new_cluster(true)?;
+    println!(
+        "WARNING: nodes {:?} will be removed from the cluster. Blocks of {} striped files stored on them will be lost",
+        removed_nodes, striped_files
+    );
+    if dry_run {
+        return Ok(());
+    }
+    println!("Type \"force\" to continue");
+    let mut confirmation = String::new();
+    std::io::stdin()
+        .read_line(&mut confirmation)
+        .map_err(into_error_code)?;
+    if confirmation.trim() != "force" {
+        return Err(ErrorCode::Interrupted);
+    }
+    let (removed_nodes, _) = client.force_new_cluster(false)?;
+    println!("Removed nodes {:?}", removed_nodes);
+
+    Ok(())
+}
+
+fn print_block_cache_stats(client: &NodeClient) -> Result<(), ErrorCode> {
+    let stats = client.block_cache_stats()?;
+    let lookups = stats.hits + stats.misses;
+    println!(
+        "Cached: {} of {} bytes",
+        stats.cached_bytes, stats.capacity_bytes
+    );
+    println!(
+        "Hits: {} ({:.1}%)",
+        stats.hits,
+        if lookups > 0 {
+            stats.hits as f64 * 100.0 / lookups as f64
+        } else {
+            0.0
+        }
+    );
+    println!("Misses: {}", stats.misses);
+    println!("Rejected: {}", stats.rejected);
+    println!("Evictions: {}", stats.evictions);
+
+    Ok(())
+}
+
+// Refreshes the cluster stats every interval, until they can't be fetched
+fn run_top(client: &NodeClient, interval: Duration) -> Result<(), ErrorCode> {
+    let mut previous = HashMap::new();
+    let mut refreshed_at = Instant::now();
+    loop {
+        let nodes = client.cluster_stats()?;
+        let elapsed = refreshed_at.elapsed();
+        refreshed_at = Instant::now();
+        // Clear the terminal
+        print!("\x1B[2J\x1B[H");
+        print_cluster_stats(&nodes, &previous, elapsed);
+        previous = nodes.into_iter().map(|x| (x.node_id, x)).collect();
+        sleep(interval);
+    }
+}
+
+fn print_heatmap(client: &NodeClient, limit: u32) -> Result<(), ErrorCode> {
+    for (inode, path, access) in client.heatmap(limit)? {
+        let name = if path.is_empty() {
+            format!("inode {}", inode)
+        } else {
+            path
+        };
+        println!(
+            "{}: reads={} ({} bytes) writes={} ({} bytes) last_access={}",
+            name,
+            access.reads,
+            access.read_bytes,
+            access.writes,
+            access.written_bytes,
+            access.last_access
+        );
+    }
+
+    Ok(())
+}
+
+fn print_background_status(client: &NodeClient, action: BackgroundAction) -> Result<(), ErrorCode> {
+    let status = client.background_control(action)?;
+    println!("Paused: {}", status.paused);
+    println!("Budget: {} bytes/sec", status.bytes_per_second);
+    for task in status.tasks.iter() {
         println!(
-            "Connecting to server {} and mounting FUSE at {}",
-            &server_ip_port, &mount_point
+            "{}: runs={} deferred={} bytes={}",
+            task.name, task.runs, task.deferred, task.bytes
         );
-        let mut fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o")];
-        let mut options = "fsname=fleetfs,auto_unmount".to_string();
-        if direct_io {
-            println!("Using Direct IO");
-            options.push_str(",direct_io");
-        }
-        if let Ok(enabled) = fuse_allow_other_enabled() {
-            if enabled {
-                options.push_str(",allow_other");
+    }
+
+    Ok(())
+}
+
+fn print_raft_debug(client: &NodeClient, log_tail: u32) -> Result<(), ErrorCode> {
+    let info = client.raft_debug(log_tail)?;
+    println!("Node: {}", info.node_id);
+    println!("Role: {}", info.role);
+    println!("Term: {}", info.term);
+    println!("Leader: {}", info.leader_id);
+    println!("Committed index: {}", info.commit_index);
+    println!("Applied index: {}", info.applied_index);
+    println!("Log: {} to {}", info.first_index, info.last_index);
+    for entry in info.log_tail.iter() {
+        println!(
+            "  index={} term={} bytes={} {}",
+            entry.index, entry.term, entry.size, entry.description
+        );
+    }
+    for progress in info.progress.iter() {
+        println!(
+            "Peer {}: matched={} next={} state={} active={}",
+            progress.node_id,
+            progress.matched,
+            progress.next_index,
+            progress.state,
+            progress.recent_active
+        );
+    }
+
+    Ok(())
+}
+
+fn print_inflight(client: &NodeClient) -> Result<(), ErrorCode> {
+    println!(
+        "{:>10} {:>28} {:>12} {:>20} {:>21} STATE",
+        "AGE(ms)", "REQUEST", "INODE", "SESSION", "CLIENT"
+    );
+    for request in client.inflight_requests()? {
+        println!(
+            "{:>10} {:>28} {:>12} {:>20} {:>21} {}",
+            request.age.as_millis(),
+            request.request_type,
+            request.inode,
+            request.session_id,
+            request.client,
+            request.state
+        );
+    }
+
+    Ok(())
+}
+
+fn print_clients(client: &NodeClient) -> Result<(), ErrorCode> {
+    println!(
+        "{:>8} {:>20} {:>20} {:<16} {:<24} {:>21} {:>5} {:>7} {:>10} {:>8}",
+        "CLIENT",
+        "SESSION",
+        "NODE",
+        "HOST",
+        "MOUNT",
+        "ADDRESS",
+        "CONNS",
+        "HANDLES",
+        "REQUESTS",
+        "IDLE(s)"
+    );
+    for entry in client.list_clients()? {
+        println!(
+            "{:>8} {:>20} {:>20} {:<16} {:<24} {:>21} {:>5} {:>7} {:>10} {:>8}",
+            entry.client_id,
+            entry.session_id,
+            entry.node_id,
+            entry.hostname,
+            entry.mount_point,
+            entry.address,
+            entry.connections,
+            entry.open_handles,
+            entry.requests,
+            entry.idle_seconds
+        );
+    }
+
+    Ok(())
+}
+
+fn evict_client(client: &NodeClient, session_id: u64) -> Result<(), ErrorCode> {
+    let evicted = client.evict_client(session_id)?;
+    println!(
+        "Evicted session {} from {} nodes",
+        session_id,
+        evicted.len()
+    );
+    for entry in evicted {
+        println!(
+            "node {}: client {} {}:{} from {}, {} connections",
+            entry.node_id,
+            entry.client_id,
+            entry.hostname,
+            entry.mount_point,
+            entry.address,
+            entry.connections
+        );
+    }
+
+    Ok(())
+}
+
+// Compares the copies of the file at path, or of the files below it, and fails with Corrupted if any diverge
+fn verify(client: &NodeClient, path: &str, context: UserContext) -> Result<(), ErrorCode> {
+    let inode = client.lookup_path(path, context)?;
+    let mut files = vec![];
+    if client.getattr(inode)?.kind == fuse::FileType::Directory {
+        let mut start_after: Option<String> = None;
+        loop {
+            let (entries, truncated) = client.find(
+                inode,
+                &FindQuery::default(),
+                start_after.as_ref().map(String::as_str),
+                context,
+            )?;
+            for (entry_inode, entry_path) in entries.iter() {
+                files.push((
+                    *entry_inode,
+                    format!("{}{}", path.trim_end_matches('/'), entry_path),
+                ));
             }
-        } else {
-            eprintln!("Unable to read /etc/fuse.conf");
+            if !truncated || entries.is_empty() {
+                break;
+            }
+            start_after = entries.last().map(|(_, entry_path)| entry_path.clone());
+        }
+    } else {
+        files.push((inode, path.to_string()));
+    }
+
+    let mut diverged = false;
+    for (file_inode, file_path) in files {
+        match client.verify_file(file_inode, context) {
+            Ok(verification) => {
+                for node_id in verification.divergent_nodes() {
+                    println!("{}: copy on node {} diverges", file_path, node_id);
+                    diverged = true;
+                }
+            }
+            // Only files have copies to compare
+            Err(ErrorCode::IsADirectory) => {}
+            Err(error_code) => {
+                println!("{}: failed to verify: {:?}", file_path, error_code);
+                diverged = true;
+            }
+        }
+    }
+    if diverged {
+        return Err(ErrorCode::Corrupted);
+    }
+
+    Ok(())
+}
+
+fn follow_log(client: &NodeClient, path: &str, context: UserContext) -> Result<(), ErrorCode> {
+    let inode = client.lookup_path(path, context)?;
+    let stdout = std::io::stdout();
+    client.follow_log(inode, 0, context, Duration::from_secs(1), |_, record| {
+        let mut out = stdout.lock();
+        // Stop once stdout is closed
+        out.write_all(record)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush())
+            .is_ok()
+    })?;
+
+    Ok(())
+}
+
+fn find_query(matches: &ArgMatches) -> FindQuery {
+    let (xattr_key, xattr_value) = if let Some(xattr) = matches.value_of("xattr") {
+        let mut parts = xattr.splitn(2, '=');
+        (
+            parts.next().map(ToString::to_string),
+            parts.next().map(|x| x.as_bytes().to_vec()),
+        )
+    } else {
+        (None, None)
+    };
+
+    FindQuery {
+        name_glob: matches.value_of("name").map(ToString::to_string),
+        min_size: matches.value_of("min-size").map(|x| x.parse().unwrap()),
+        max_size: matches.value_of("max-size").map(|x| x.parse().unwrap()),
+        modified_after: matches
+            .value_of("modified-after")
+            .map(|x| Timestamp::new(x.parse().unwrap(), 0)),
+        modified_before: matches
+            .value_of("modified-before")
+            .map(|x| Timestamp::new(x.parse().unwrap(), 0)),
+        xattr_key,
+        xattr_value,
+    }
+}
+
+fn find(
+    client: &NodeClient,
+    path: &str,
+    query: &FindQuery,
+    context: UserContext,
+) -> Result<(), ErrorCode> {
+    let inode = client.lookup_path(path, context)?;
+    let mut start_after: Option<String> = None;
+    loop {
+        let (entries, truncated) = client.find(
+            inode,
+            query,
+            start_after.as_ref().map(String::as_str),
+            context,
+        )?;
+        for (_, entry_path) in entries.iter() {
+            println!("{}{}", path.trim_end_matches('/'), entry_path);
+        }
+        if !truncated || entries.is_empty() {
+            break;
+        }
+        start_after = entries.last().map(|(_, entry_path)| entry_path.clone());
+    }
+
+    Ok(())
+}
+
+fn glob(client: &NodeClient, pattern: &str, context: UserContext) -> Result<(), ErrorCode> {
+    let mut start_after: Option<String> = None;
+    loop {
+        let (entries, truncated) =
+            client.glob(pattern, start_after.as_ref().map(String::as_str), context)?;
+        for (path, attributes) in entries.iter() {
+            println!("{}\t{}", attributes.size, path);
         }
+        if !truncated || entries.is_empty() {
+            break;
+        }
+        start_after = entries.last().map(|(path, _)| path.clone());
+    }
 
-        fuse_args.push(&OsStr::new(&options));
-        let fs = FleetFUSE::new(server_ip_port);
-        fuse::mount(fs, &mount_point, &fuse_args).unwrap();
+    Ok(())
+}
+
+fn search(client: &NodeClient, text: &str, context: UserContext) -> Result<(), ErrorCode> {
+    let (entries, truncated) = client.search(text, context)?;
+    for (_, path) in entries.iter() {
+        println!("{}", path);
+    }
+    if truncated {
+        eprintln!(
+            "Too many results. Only the first {} are shown",
+            entries.len()
+        );
     }
 
-    return Ok(());
+    Ok(())
+}
+
+fn main() -> Result<(), ErrorCode> {
+    // Each subcommand only accepts its own options
+    let app_matches = App::new("FleetFS")
+        .version(crate_version!())
+        .author("Christopher Berner")
+        .args(&legacy_options())
+        .subcommand(with_options(
+            "server",
+            "Run a storage node",
+            &[common_options, listen_options, server_options, shared_options],
+        ))
+        .subcommand(with_options(
+            "mount",
+            "Mount the filesystem at --mount-point",
+            &[common_options, client_options, mount_options, shared_options],
+        ))
+        .subcommand(with_options(
+            "admin",
+            "Run one of the administrative commands",
+            &[common_options, client_options, admin_options],
+        ))
+        .subcommand(with_options(
+            "fsck",
+            "Check the filesystem, or repair it with --repair",
+            &[common_options, client_options, fsck_options],
+        ))
+        .subcommand(with_options(
+            "bench",
+            "Measure the write and read throughput of the cluster",
+            &[common_options, client_options, bench_options],
+        ))
+        .subcommand(with_options(
+            "dev-cluster",
+            "Run --nodes nodes on consecutive ports from --port, and mount them if --mount-point is given",
+            &[
+                common_options,
+                listen_options,
+                dev_cluster_options,
+                mount_options,
+                shared_options,
+            ],
+        ))
+        .subcommand(
+            with_options(
+                "import",
+                "Copy LOCAL_DIR, and everything below it, to PATH",
+                &[common_options, client_options],
+            )
+            .arg(Arg::with_name("LOCAL_DIR").required(true).index(1))
+            .arg(Arg::with_name("PATH").required(true).index(2)),
+        )
+        .get_matches();
+    let (subcommand, matches) = match app_matches.subcommand() {
+        (name, Some(subcommand_matches)) => (name, subcommand_matches),
+        _ => (implied_subcommand(&app_matches), &app_matches),
+    };
+
+    let log_level = match matches.occurrences_of("v") {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let log_control = LogControl::init(log_level);
+
+    match subcommand {
+        "server" => run_server(matches, log_control),
+        "mount" => run_mount(matches, server_address(matches)),
+        "admin" => run_admin(matches),
+        "fsck" => run_fsck(matches),
+        "bench" => run_bench(matches),
+        "dev-cluster" => run_dev_cluster(matches),
+        "import" => run_import(matches),
+        _ => unreachable!(),
+    }
 }
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Answered by the mount itself, with the memory used by each of its caches
+pub const CLIENT_MEMORY_XATTR: &str = "fleetfs.client_memory";
+
+// Caches of a mount which share its memory budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    // Keyed by file handle
+    ReadAhead,
+    // Keyed by directory inode
+    DirectoryListing,
+}
+
+impl CacheKind {
+    fn name(self) -> &'static str {
+        match self {
+            CacheKind::ReadAhead => "read_ahead",
+            CacheKind::DirectoryListing => "directory_listings",
+        }
+    }
+}
+
+const CACHE_KINDS: [CacheKind; 2] = [CacheKind::ReadAhead, CacheKind::DirectoryListing];
+
+struct ChargedEntry {
+    bytes: u64,
+    last_used: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct CacheUsage {
+    pub bytes: u64,
+    pub entries: u64,
+    // Entries evicted to keep the mount within its budget
+    pub evictions: u64,
+}
+
+struct BudgetState {
+    entries: HashMap<(CacheKind, u64), ChargedEntry>,
+    usage: HashMap<CacheKind, CacheUsage>,
+    total_bytes: u64,
+    // Incremented on every use, to find the least recently used entries
+    clock: u64,
+}
+
+// Accounts for the memory used by the caches of a mount, so that together they stay within one budget. The
+// caches keep their own entries: they charge each entry here when it's inserted, and remove the entries which are
+// returned for eviction, which are the least recently used entries of any cache
+pub struct MemoryBudget {
+    max_bytes: u64,
+    state: Mutex<BudgetState>,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: u64) -> MemoryBudget {
+        MemoryBudget {
+            max_bytes,
+            state: Mutex::new(BudgetState {
+                entries: HashMap::new(),
+                usage: HashMap::new(),
+                total_bytes: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    fn remove(state: &mut BudgetState, kind: CacheKind, key: u64) -> Option<ChargedEntry> {
+        let entry = state.entries.remove(&(kind, key))?;
+        state.total_bytes -= entry.bytes;
+        let usage = state.usage.entry(kind).or_default();
+        usage.bytes -= entry.bytes;
+        usage.entries -= 1;
+        Some(entry)
+    }
+
+    // Charges an entry of bytes, replacing any earlier charge for the same entry, and returns the entries which
+    // must be evicted to stay within the budget. An entry larger than the whole budget is returned itself
+    pub fn charge(&self, kind: CacheKind, key: u64, bytes: u64) -> Vec<(CacheKind, u64)> {
+        let mut state = self.state.lock().unwrap();
+        MemoryBudget::remove(&mut state, kind, key);
+        state.clock += 1;
+        let last_used = state.clock;
+        state
+            .entries
+            .insert((kind, key), ChargedEntry { bytes, last_used });
+        state.total_bytes += bytes;
+        let usage = state.usage.entry(kind).or_default();
+        usage.bytes += bytes;
+        usage.entries += 1;
+        if state.total_bytes <= self.max_bytes {
+            return vec![];
+        }
+
+        let mut candidates: Vec<(u64, (CacheKind, u64))> = state
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, *key))
+            .collect();
+        candidates.sort_by_key(|(last_used, _)| *last_used);
+        let mut evicted = vec![];
+        for (_, (kind, key)) in candidates {
+            if state.total_bytes <= self.max_bytes {
+                break;
+            }
+            MemoryBudget::remove(&mut state, kind, key);
+            state.usage.entry(kind).or_default().evictions += 1;
+            evicted.push((kind, key));
+        }
+
+        evicted
+    }
+
+    // Marks the entry as used, so that it's evicted after the entries which weren't
+    pub fn touch(&self, kind: CacheKind, key: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(&(kind, key)) {
+            entry.last_used = clock;
+        }
+    }
+
+    // Called when a cache drops an entry by itself
+    pub fn release(&self, kind: CacheKind, key: u64) {
+        MemoryBudget::remove(&mut self.state.lock().unwrap(), kind, key);
+    }
+
+    pub fn usage(&self, kind: CacheKind) -> CacheUsage {
+        self.state
+            .lock()
+            .unwrap()
+            .usage
+            .get(&kind)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // One line per cache, with its bytes, entries and evictions, followed by the total and the budget
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for kind in CACHE_KINDS.iter() {
+            let usage = self.usage(*kind);
+            report.push_str(&format!(
+                "{} {} {} {}\n",
+                kind.name(),
+                usage.bytes,
+                usage.entries,
+                usage.evictions
+            ));
+        }
+        let total_bytes = self.state.lock().unwrap().total_bytes;
+        report.push_str(&format!("total {} {}\n", total_bytes, self.max_bytes));
+        report
+    }
+}
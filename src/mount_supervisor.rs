@@ -0,0 +1,86 @@
+use std::ffi::OsStr;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::client::NodeClient;
+use crate::fuse_adapter::{FleetFUSE, MountConfig};
+
+// How often the supervisor checks that the server is reachable
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Detaches the mount, even if it's busy or its session was aborted, so that the mount point can be reused
+fn lazy_unmount(mount_point: &str) {
+    let result = Command::new("fusermount")
+        .args(&["-u", "-z", mount_point])
+        .stderr(Stdio::null())
+        .status();
+    if let Err(error) = result {
+        warn!("Failed to run fusermount: {:?}", error);
+    }
+}
+
+fn wait_for_server(client: &NodeClient, server_ip_port: SocketAddr) {
+    while client.ping().is_err() {
+        info!("Waiting for server {}", server_ip_port);
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+// Mounts FUSE at the mount point of config, and mounts it again whenever the session ends, or the server has been
+// unreachable for longer than unreachable_timeout. Runs until the process is terminated
+pub fn supervise_mount(config: &MountConfig, options: &str, unreachable_timeout: Duration) -> ! {
+    let server_ip_port = config.server_ip_port;
+    let mount_point = config.mount_point.as_str();
+    let client = NodeClient::new(server_ip_port);
+    loop {
+        // Clean up the mount of the previous session, if it's still attached
+        lazy_unmount(mount_point);
+        wait_for_server(&client, server_ip_port);
+
+        info!("Mounting FUSE at {}", mount_point);
+        let fs = FleetFUSE::new(config);
+        let session_mount_point = mount_point.to_string();
+        let session_options = options.to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let fuse_args = [OsStr::new("-o"), OsStr::new(&session_options)];
+            let result = fuse::mount(fs, &session_mount_point, &fuse_args);
+            sender.send(result).ok();
+        });
+
+        let mut last_reachable = Instant::now();
+        loop {
+            match receiver.recv_timeout(CHECK_INTERVAL) {
+                Ok(result) => {
+                    warn!("FUSE session at {} ended: {:?}", mount_point, result);
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("FUSE session at {} panicked", mount_point);
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            if client.ping().is_ok() {
+                last_reachable = Instant::now();
+            } else if last_reachable.elapsed() > unreachable_timeout {
+                warn!(
+                    "Server {} unreachable for {:?}. Remounting",
+                    server_ip_port,
+                    last_reachable.elapsed()
+                );
+                lazy_unmount(mount_point);
+                // Unmounting ends the session
+                receiver.recv().ok();
+                break;
+            }
+        }
+    }
+}
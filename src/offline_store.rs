@@ -0,0 +1,362 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client::NodeClient;
+use crate::generated::{ChecksumAlgorithm, ErrorCode, UserContext};
+use crate::storage::checksum::digest;
+
+// Larger files aren't kept for offline use
+pub const MAX_OFFLINE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// inode, offset, uid, gid, data version of the cached copy, length
+const HEADER_SIZE: usize = 40;
+// Replayed writes are sent in chunks of this size
+const REPLAY_CHUNK_SIZE: usize = 1024 * 1024;
+// Reading it from a file reports the bytes written to it, and to the whole mount, which aren't yet on the cluster,
+// and the age of the oldest such write. Other writes are only acknowledged once the cluster committed them, so only
+// writes journaled while disconnected are reported. The mount can be powered off safely once the mount's count is 0
+pub const SYNC_STATUS_XATTR: &str = "fleetfs.sync_status";
+
+// Journaled writes which weren't replayed yet, of a file or of the whole store
+#[derive(Clone, Copy, Default)]
+pub struct PendingWrites {
+    pub bytes: u64,
+    pub oldest: Option<SystemTime>,
+}
+
+impl PendingWrites {
+    fn add(&mut self, bytes: u64, written: SystemTime) {
+        self.bytes += bytes;
+        self.oldest = Some(self.oldest.map_or(written, |x| x.min(written)));
+    }
+
+    // Age of the oldest write. 0 if there are none
+    pub fn oldest_age(&self) -> Duration {
+        self.oldest
+            .and_then(|x| x.elapsed().ok())
+            .unwrap_or_default()
+    }
+}
+
+struct JournaledWrite {
+    inode: u64,
+    offset: u64,
+    context: UserContext,
+    // Data version of the cached copy the write was made to. If the file on the server has a different one, it was
+    // changed while this client was disconnected
+    base_version: u64,
+    data: Vec<u8>,
+}
+
+fn encode_record(write: &JournaledWrite) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + write.data.len() + 8);
+    record.write_u64::<LittleEndian>(write.inode).unwrap();
+    record.write_u64::<LittleEndian>(write.offset).unwrap();
+    record
+        .write_u32::<LittleEndian>(write.context.uid())
+        .unwrap();
+    record
+        .write_u32::<LittleEndian>(write.context.gid())
+        .unwrap();
+    record
+        .write_u64::<LittleEndian>(write.base_version)
+        .unwrap();
+    record
+        .write_u64::<LittleEndian>(write.data.len() as u64)
+        .unwrap();
+    record.extend_from_slice(&write.data);
+    let checksum = digest(ChecksumAlgorithm::Xxh3, &record);
+    record.extend_from_slice(&checksum);
+
+    record
+}
+
+// Returns the records in data. A torn record at the end, from a crash while it was appended, is dropped
+fn decode_records(data: &[u8]) -> Vec<JournaledWrite> {
+    let mut records = vec![];
+    let mut remaining = data;
+    while remaining.len() >= HEADER_SIZE {
+        let length = LittleEndian::read_u64(&remaining[32..40]) as usize;
+        let end = match HEADER_SIZE.checked_add(length) {
+            Some(end) => end,
+            None => break,
+        };
+        let checksum_length = digest(ChecksumAlgorithm::Xxh3, &[]).len();
+        let record_end = match end.checked_add(checksum_length) {
+            Some(record_end) => record_end,
+            None => break,
+        };
+        if remaining.len() < record_end
+            || remaining[end..record_end] != *digest(ChecksumAlgorithm::Xxh3, &remaining[..end])
+        {
+            break;
+        }
+        records.push(JournaledWrite {
+            inode: LittleEndian::read_u64(&remaining[0..8]),
+            offset: LittleEndian::read_u64(&remaining[8..16]),
+            context: UserContext::new(
+                LittleEndian::read_u32(&remaining[16..20]),
+                LittleEndian::read_u32(&remaining[20..24]),
+            ),
+            base_version: LittleEndian::read_u64(&remaining[24..32]),
+            data: remaining[HEADER_SIZE..end].to_vec(),
+        });
+        remaining = &remaining[record_end..];
+    }
+
+    records
+}
+
+// Local copies of files, so that they can be read while the cluster is unreachable, and a journal of the writes
+// made to them meanwhile, which are replayed once it's reachable again
+pub struct OfflineStore {
+    data_dir: PathBuf,
+    conflicts_dir: PathBuf,
+    journal_path: PathBuf,
+    journal: Mutex<File>,
+    // Data version of the cached copy of each file, as of when it was fetched from the server
+    cached: Mutex<HashMap<u64, u64>>,
+    // Journaled writes of each file. Only changed while the journal is locked
+    pending: Mutex<HashMap<u64, PendingWrites>>,
+}
+
+impl OfflineStore {
+    pub fn new(directory: &str) -> io::Result<OfflineStore> {
+        let directory = Path::new(directory);
+        let data_dir = directory.join("data");
+        let conflicts_dir = directory.join("conflicts");
+        fs::create_dir_all(&data_dir)?;
+        fs::create_dir_all(&conflicts_dir)?;
+        let journal_path = directory.join("journal");
+        let journal = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&journal_path)?;
+
+        let mut cached = HashMap::new();
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |x| x != "version") {
+                continue;
+            }
+            let inode = path.file_stem().and_then(|x| x.to_str()?.parse().ok());
+            let version = fs::read(&path)?;
+            if let (Some(inode), 8) = (inode, version.len()) {
+                cached.insert(inode, LittleEndian::read_u64(&version));
+            }
+        }
+
+        // Records don't store when they were written, so writes left in the journal by a previous mount are counted
+        // as made when the journal was last modified
+        let mut pending: HashMap<u64, PendingWrites> = HashMap::new();
+        let journal_modified = journal.metadata()?.modified()?;
+        for record in decode_records(&fs::read(&journal_path)?) {
+            pending
+                .entry(record.inode)
+                .or_default()
+                .add(record.data.len() as u64, journal_modified);
+        }
+
+        Ok(OfflineStore {
+            data_dir,
+            conflicts_dir,
+            journal_path,
+            journal: Mutex::new(journal),
+            cached: Mutex::new(cached),
+            pending: Mutex::new(pending),
+        })
+    }
+
+    fn data_path(&self, inode: u64) -> PathBuf {
+        self.data_dir.join(inode.to_string())
+    }
+
+    fn version_path(&self, inode: u64) -> PathBuf {
+        self.data_dir.join(format!("{}.version", inode))
+    }
+
+    pub fn is_cached(&self, inode: u64) -> bool {
+        self.cached.lock().unwrap().contains_key(&inode)
+    }
+
+    // Whether the cached copy of inode is of data_version
+    pub fn is_current(&self, inode: u64, data_version: u64) -> bool {
+        self.cached.lock().unwrap().get(&inode) == Some(&data_version)
+    }
+
+    // Drops the cached copy of inode, once it's been changed through the server
+    pub fn evict(&self, inode: u64) {
+        if self.cached.lock().unwrap().remove(&inode).is_some() {
+            fs::remove_file(self.version_path(inode)).ok();
+            fs::remove_file(self.data_path(inode)).ok();
+        }
+    }
+
+    pub fn store(&self, inode: u64, data_version: u64, data: &[u8]) -> io::Result<()> {
+        let temp_path = self.data_dir.join(format!("{}.tmp", inode));
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, self.data_path(inode))?;
+        let mut encoded = vec![];
+        encoded.write_u64::<LittleEndian>(data_version)?;
+        fs::write(self.version_path(inode), encoded)?;
+        self.cached.lock().unwrap().insert(inode, data_version);
+
+        Ok(())
+    }
+
+    // Returns None if inode isn't cached
+    pub fn read(&self, inode: u64, offset: u64, size: u32) -> io::Result<Option<Vec<u8>>> {
+        if !self.is_cached(inode) {
+            return Ok(None);
+        }
+        let file = File::open(self.data_path(inode))?;
+        let length = file.metadata()?.len();
+        let end = length.min(offset + u64::from(size));
+        let mut data = vec![0; end.saturating_sub(offset) as usize];
+        file.read_exact_at(&mut data, offset)?;
+
+        Ok(Some(data))
+    }
+
+    // Journals a write to the cached copy of inode, and applies it to the copy
+    pub fn write(
+        &self,
+        inode: u64,
+        offset: u64,
+        data: &[u8],
+        context: UserContext,
+    ) -> io::Result<()> {
+        let base_version = match self.cached.lock().unwrap().get(&inode) {
+            Some(version) => *version,
+            None => return Err(io::Error::from(ErrorKind::NotFound)),
+        };
+        let record = encode_record(&JournaledWrite {
+            inode,
+            offset,
+            context,
+            base_version,
+            data: data.to_vec(),
+        });
+        let mut journal = self.journal.lock().unwrap();
+        journal.write_all(&record)?;
+        journal.sync_data()?;
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(inode)
+            .or_default()
+            .add(data.len() as u64, SystemTime::now());
+
+        let file = OpenOptions::new().write(true).open(self.data_path(inode))?;
+        file.write_all_at(data, offset)
+    }
+
+    pub fn has_journaled_writes(&self) -> bool {
+        self.journal
+            .lock()
+            .unwrap()
+            .metadata()
+            .map(|x| x.len() > 0)
+            .unwrap_or(false)
+    }
+
+    // Journaled writes of inode, or of every file if None, which weren't replayed yet
+    pub fn pending_writes(&self, inode: Option<u64>) -> PendingWrites {
+        let pending = self.pending.lock().unwrap();
+        match inode {
+            Some(inode) => pending.get(&inode).cloned().unwrap_or_default(),
+            None => {
+                let mut total = PendingWrites::default();
+                for writes in pending.values() {
+                    if let Some(oldest) = writes.oldest {
+                        total.add(writes.bytes, oldest);
+                    }
+                }
+                total
+            }
+        }
+    }
+
+    // Saves the local copy of inode in the conflicts directory, since its journaled writes couldn't be replayed
+    fn save_conflict(&self, inode: u64) -> io::Result<PathBuf> {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        let path = self.conflicts_dir.join(format!("{}-{}", inode, seconds));
+        fs::copy(self.data_path(inode), &path)?;
+        Ok(path)
+    }
+
+    // Sends the journaled writes to the server. Writes to files which were modified on the server since they were
+    // cached aren't applied, and the local copy is saved in the conflicts directory instead. If a replay is
+    // interrupted, the files it had partially replayed are saved as conflicts by the next one
+    pub fn replay(&self, client: &NodeClient) -> Result<(), ErrorCode> {
+        let journal = self.journal.lock().unwrap();
+        let records =
+            decode_records(&fs::read(&self.journal_path).map_err(|_| ErrorCode::Uncategorized)?);
+        if records.is_empty() {
+            return Ok(());
+        }
+        info!("Replaying {} writes made while disconnected", records.len());
+
+        let mut checked = HashSet::new();
+        let mut conflicts = HashSet::new();
+        for record in records.iter() {
+            if conflicts.contains(&record.inode) {
+                continue;
+            }
+            if checked.insert(record.inode) {
+                let conflict = match client.getattr_details(record.inode) {
+                    Ok((_, details)) => details.data_version != record.base_version,
+                    Err(ErrorCode::DoesNotExist) | Err(ErrorCode::InodeDoesNotExist) => true,
+                    Err(error_code) => return Err(error_code),
+                };
+                if conflict {
+                    conflicts.insert(record.inode);
+                    match self.save_conflict(record.inode) {
+                        Ok(path) => warn!(
+                            "Inode {} was modified while disconnected. Saved the local copy to {:?}",
+                            record.inode, path
+                        ),
+                        Err(error) => warn!(
+                            "Inode {} was modified while disconnected. Failed to save the local copy: {:?}",
+                            record.inode, error
+                        ),
+                    }
+                    continue;
+                }
+            }
+            for (i, chunk) in record.data.chunks(REPLAY_CHUNK_SIZE).enumerate() {
+                let offset = record.offset + (i * REPLAY_CHUNK_SIZE) as u64;
+                client.write(record.inode, chunk, offset, record.context)?;
+            }
+        }
+
+        // The local copies now have the same data as the server, so refresh their data versions
+        for inode in checked.iter() {
+            if conflicts.contains(inode) {
+                self.cached.lock().unwrap().remove(inode);
+                fs::remove_file(self.version_path(*inode)).ok();
+            } else if let Ok((_, details)) = client.getattr_details(*inode) {
+                let data =
+                    fs::read(self.data_path(*inode)).map_err(|_| ErrorCode::Uncategorized)?;
+                self.store(*inode, details.data_version, &data)
+                    .map_err(|_| ErrorCode::Uncategorized)?;
+            }
+        }
+        journal.set_len(0).map_err(|_| ErrorCode::Uncategorized)?;
+        journal.sync_all().map_err(|_| ErrorCode::Uncategorized)?;
+        self.pending.lock().unwrap().clear();
+
+        Ok(())
+    }
+}
@@ -2,8 +2,14 @@ use std::net::SocketAddr;
 
 use flatbuffers::FlatBufferBuilder;
 
+use crate::client_registry::ClientStatus;
 use crate::generated::*;
-use crate::utils::{finalize_request, response_or_error, FlatBufferWithResponse};
+use crate::request_stats::NodeStats;
+use crate::storage::access_stats::FileAccess;
+use crate::utils::{
+    decode_fast_read_response_inplace, finalize_request, into_error_code, response_or_error,
+    FlatBufferWithResponse,
+};
 use byteorder::{ByteOrder, LittleEndian};
 use futures::future::ok;
 use futures::Future;
@@ -90,6 +96,27 @@ impl PeerClient {
             .map_err(|e| error!("Error sending Raft message: {:?}", e))
     }
 
+    pub fn stage_data(
+        &self,
+        staged_id: u64,
+        data: &[u8],
+    ) -> impl Future<Item = (), Error = std::io::Error> {
+        let mut builder = FlatBufferBuilder::new();
+        let data_offset = builder.create_vector_direct(data);
+        let mut request_builder = StageDataRequestBuilder::new(&mut builder);
+        request_builder.add_staged_id(staged_id);
+        request_builder.add_data(data_offset);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::StageDataRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(FlatBufferWithResponse::new(builder))
+            .and_then(|response| {
+                response_or_error(&response)
+                    .map(|_| ())
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))
+            })
+    }
+
     pub fn get_latest_commit(&self) -> impl Future<Item = u64, Error = ()> {
         let mut builder = FlatBufferBuilder::new();
         let request_builder = LatestCommitRequestBuilder::new(&mut builder);
@@ -111,9 +138,13 @@ impl PeerClient {
             .map_err(|e| error!("Error reading latest commit: {:?}", e))
     }
 
-    pub fn filesystem_checksum(&self) -> impl Future<Item = Vec<u8>, Error = std::io::Error> {
+    pub fn filesystem_checksum(
+        &self,
+        algorithm: ChecksumAlgorithm,
+    ) -> impl Future<Item = Vec<u8>, Error = std::io::Error> {
         let mut builder = FlatBufferBuilder::new();
-        let request_builder = FilesystemChecksumRequestBuilder::new(&mut builder);
+        let mut request_builder = FilesystemChecksumRequestBuilder::new(&mut builder);
+        request_builder.add_algorithm(algorithm);
         let finish_offset = request_builder.finish().as_union_value();
         finalize_request(
             &mut builder,
@@ -132,6 +163,165 @@ impl PeerClient {
             })
     }
 
+    pub fn local_file_checksum(
+        &self,
+        inode: u64,
+        algorithm: ChecksumAlgorithm,
+    ) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = LocalFileChecksumRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_algorithm(algorithm);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(
+            &mut builder,
+            RequestType::LocalFileChecksumRequest,
+            finish_offset,
+        );
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let data = response_or_error(&response)?
+                    .response_as_read_response()
+                    .ok_or(ErrorCode::BadResponse)?
+                    .data()
+                    .to_vec();
+                Ok(data)
+            })
+    }
+
+    // Prefetches into the block cache of the peer only. Returns the number of files and bytes it loaded
+    pub fn prefetch(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> impl Future<Item = (u64, u64), Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = PrefetchRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_context(&context);
+        request_builder.add_local(true);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::PrefetchRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let prefetch_response = response_or_error(&response)?
+                    .response_as_prefetch_response()
+                    .ok_or(ErrorCode::BadResponse)?;
+                Ok((prefetch_response.files(), prefetch_response.bytes()))
+            })
+    }
+
+    // Returns the limit files which the peer has accessed the most, without their paths
+    pub fn heatmap(
+        &self,
+        limit: u32,
+    ) -> impl Future<Item = Vec<(u64, FileAccess)>, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = HeatmapRequestBuilder::new(&mut builder);
+        request_builder.add_limit(limit);
+        request_builder.add_local(true);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::HeatmapRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let entries = response_or_error(&response)?
+                    .response_as_heatmap_response()
+                    .ok_or(ErrorCode::BadResponse)?
+                    .files();
+                let files = (0..entries.len())
+                    .map(|i| {
+                        let entry = entries.get(i);
+                        let access = FileAccess {
+                            reads: entry.reads(),
+                            writes: entry.writes(),
+                            read_bytes: entry.read_bytes(),
+                            written_bytes: entry.written_bytes(),
+                            last_access: entry.last_access(),
+                        };
+                        (entry.inode(), access)
+                    })
+                    .collect();
+                Ok(files)
+            })
+    }
+
+    pub fn node_stats(&self) -> impl Future<Item = NodeStats, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = ClusterStatsRequestBuilder::new(&mut builder);
+        request_builder.add_local(true);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(
+            &mut builder,
+            RequestType::ClusterStatsRequest,
+            finish_offset,
+        );
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let nodes = response_or_error(&response)?
+                    .response_as_cluster_stats_response()
+                    .ok_or(ErrorCode::BadResponse)?
+                    .nodes();
+                if nodes.len() != 1 {
+                    return Err(ErrorCode::BadResponse);
+                }
+                Ok(NodeStats::from_status(&nodes.get(0)))
+            })
+    }
+
+    pub fn list_clients(&self) -> impl Future<Item = Vec<ClientStatus>, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = ListClientsRequestBuilder::new(&mut builder);
+        request_builder.add_local(true);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::ListClientsRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let entries = response_or_error(&response)?
+                    .response_as_clients_response()
+                    .ok_or(ErrorCode::BadResponse)?
+                    .clients();
+                let clients = (0..entries.len())
+                    .map(|i| ClientStatus::from_entry(&entries.get(i)))
+                    .collect();
+                Ok(clients)
+            })
+    }
+
+    pub fn evict_client(
+        &self,
+        session_id: u64,
+    ) -> impl Future<Item = Vec<ClientStatus>, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = EvictClientRequestBuilder::new(&mut builder);
+        request_builder.add_session_id(session_id);
+        request_builder.add_local(true);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::EvictClientRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(builder.finished_data().to_vec())
+            .map_err(into_error_code)
+            .and_then(|response| {
+                let entries = response_or_error(&response)?
+                    .response_as_clients_response()
+                    .ok_or(ErrorCode::BadResponse)?
+                    .clients();
+                let clients = (0..entries.len())
+                    .map(|i| ClientStatus::from_entry(&entries.get(i)))
+                    .collect();
+                Ok(clients)
+            })
+    }
+
     pub fn read_raw(
         &self,
         inode: u64,
@@ -158,4 +348,31 @@ impl PeerClient {
                 response
             })
     }
+
+    // Reads the complete data of inode, as root, without updating its access time. Used by observers,
+    // which don't store any data
+    pub fn read(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: u32,
+    ) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+        let mut builder = FlatBufferBuilder::new();
+        let context = UserContext::new(0, 0);
+        let mut request_builder = ReadRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(offset);
+        request_builder.add_read_size(size);
+        request_builder.add_context(&context);
+        request_builder.add_atime_mode(AtimeMode::NoAtime);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::ReadRequest, finish_offset);
+
+        self.send_and_receive_length_prefixed(FlatBufferWithResponse::new(builder))
+            .map_err(into_error_code)
+            .and_then(|mut response| {
+                decode_fast_read_response_inplace(&mut response)?;
+                Ok(response)
+            })
+    }
 }
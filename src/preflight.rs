@@ -0,0 +1,219 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flatbuffers::FlatBufferBuilder;
+use log::{error, info, warn};
+
+use crate::client::NodeClient;
+use crate::generated::*;
+use crate::storage::metadata_storage::now;
+use crate::storage_node::LocalContext;
+use crate::utils::ResultResponse;
+
+// Incremented whenever the messages exchanged between nodes change incompatibly
+pub const PROTOCOL_VERSION: u32 = 1;
+// A node won't start with less space than this available for its data
+const MIN_AVAILABLE_BYTES: u64 = 64 * 1024 * 1024;
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(1);
+
+// Stored next to the data dir, so that a node can't be restarted as a member of a different cluster
+fn cluster_name_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).with_file_name("cluster_name")
+}
+
+fn available_bytes(directory: &Path) -> io::Result<u64> {
+    let directory = File::open(directory)?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(directory.as_raw_fd(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// Checks that the data dir can be written, and has enough space. Returns the problems found
+fn check_data_dir(data_dir: &str) -> Vec<String> {
+    if let Err(error) = fs::create_dir_all(data_dir) {
+        return vec![format!(
+            "Couldn't create data dir {}: {}. Check the permissions of its parent directory",
+            data_dir, error
+        )];
+    }
+
+    let mut problems = vec![];
+    let test_path = Path::new(data_dir).join("preflight.tmp");
+    let written = File::create(&test_path).and_then(|mut file| {
+        file.write_all(b"preflight")?;
+        file.sync_all()
+    });
+    if let Err(error) = written {
+        problems.push(format!(
+            "Data dir {} isn't writable: {}. Check its owner and permissions",
+            data_dir, error
+        ));
+    }
+    // Only used as a hint, since the data is written through the page cache
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&test_path)
+    {
+        Ok(_) => {}
+        Err(ref error) if error.raw_os_error() == Some(libc::EINVAL) => warn!(
+            "The filesystem of data dir {} doesn't support O_DIRECT. It may not be a local disk",
+            data_dir
+        ),
+        Err(_) => {}
+    }
+    fs::remove_file(&test_path).ok();
+
+    match available_bytes(Path::new(data_dir)) {
+        Ok(available) if available < MIN_AVAILABLE_BYTES => problems.push(format!(
+            "Only {} bytes are available for data dir {}. At least {} are needed. Free some space, or use another disk",
+            available, data_dir, MIN_AVAILABLE_BYTES
+        )),
+        Ok(_) => {}
+        Err(error) => problems.push(format!(
+            "Couldn't read the free space of data dir {}: {}",
+            data_dir, error
+        )),
+    }
+
+    problems
+}
+
+// Records the cluster name on the first start, and checks that it hasn't changed on later ones
+fn check_cluster_name(data_dir: &str, cluster_name: &str) -> Vec<String> {
+    let path = cluster_name_path(data_dir);
+    match fs::read_to_string(&path) {
+        Ok(stored) => {
+            if stored != cluster_name {
+                return vec![format!(
+                    "Data dir {} belongs to cluster {:?}, but this node was started with --cluster-name={:?}. Use the \
+                     same name, or an empty data dir",
+                    data_dir, stored, cluster_name
+                )];
+            }
+        }
+        Err(ref error) if error.kind() == ErrorKind::NotFound => {
+            if let Err(error) = fs::write(&path, cluster_name) {
+                return vec![format!(
+                    "Couldn't write {:?}: {}. Check the permissions of the data dir",
+                    path, error
+                )];
+            }
+        }
+        Err(error) => {
+            return vec![format!("Couldn't read {:?}: {}", path, error)];
+        }
+    }
+
+    vec![]
+}
+
+// Whether the node ran before, since it recorded its cluster name, but its data dir is now missing or empty, such as
+// after its disk was replaced
+pub fn lost_data_dir(data_dir: &str) -> bool {
+    let empty = match fs::read_dir(data_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(ref error) if error.kind() == ErrorKind::NotFound => true,
+        Err(_) => false,
+    };
+
+    empty && cluster_name_path(data_dir).exists()
+}
+
+// Checks that each reachable peer is a member of the same cluster, speaks the same protocol, and has a clock
+// which agrees with this node's. Peers which aren't running yet are skipped, since the nodes of a new cluster
+// are started one at a time
+fn check_peers(context: &LocalContext) -> Vec<String> {
+    let mut problems = vec![];
+    for peer in context.peers.iter().chain(context.observers.iter()) {
+        let client = NodeClient::new(*peer);
+        let sent = SystemTime::now();
+        let info = match client.node_info() {
+            Ok(info) => info,
+            Err(error_code) => {
+                info!("Skipping preflight checks of {}: {:?}", peer, error_code);
+                continue;
+            }
+        };
+        let received = SystemTime::now();
+
+        if info.cluster_name != context.cluster_name {
+            problems.push(format!(
+                "{} is a member of cluster {:?}, but this node was started with --cluster-name={:?}. Check the \
+                 --peers and --cluster-name options",
+                peer, info.cluster_name, context.cluster_name
+            ));
+        }
+        if info.protocol_version != PROTOCOL_VERSION {
+            problems.push(format!(
+                "{} uses protocol version {}, but this node uses {}. Upgrade the nodes to the same version",
+                peer, info.protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        // The peer's clock was read about half way through the round trip
+        let round_trip = received.duration_since(sent).unwrap_or_default();
+        let local_time = sent + round_trip / 2;
+        let skew = match info.time.duration_since(local_time) {
+            Ok(ahead) => ahead,
+            Err(behind) => behind.duration(),
+        };
+        if skew > MAX_CLOCK_SKEW + round_trip / 2 {
+            problems.push(format!(
+                "The clock of {} differs from this node's by {:?}. Synchronize the clocks of the nodes, for example \
+                 with NTP",
+                peer, skew
+            ));
+        }
+    }
+
+    problems
+}
+
+fn refuse_if_problems(problems: &[String]) {
+    for problem in problems.iter() {
+        error!("Preflight check failed: {}", problem);
+    }
+    if !problems.is_empty() {
+        panic!(
+            "{} preflight checks failed. Refusing to start",
+            problems.len()
+        );
+    }
+}
+
+// Run before the node opens its storage
+pub fn preflight_storage(data_dir: &str, cluster_name: &str) {
+    let mut problems = check_data_dir(data_dir);
+    if problems.is_empty() {
+        problems.extend(check_cluster_name(data_dir, cluster_name));
+    }
+    refuse_if_problems(&problems);
+}
+
+// Run before the node starts serving requests
+pub fn preflight_peers(context: &LocalContext) {
+    refuse_if_problems(&check_peers(context));
+}
+
+pub fn node_info<'a>(
+    context: &LocalContext,
+    mut builder: FlatBufferBuilder<'a>,
+) -> ResultResponse<'a> {
+    let cluster_name = builder.create_string(&context.cluster_name);
+    let mut response_builder = NodeInfoResponseBuilder::new(&mut builder);
+    response_builder.add_cluster_name(cluster_name);
+    response_builder.add_protocol_version(PROTOCOL_VERSION);
+    response_builder.add_time(&now());
+    let response_offset = response_builder.finish().as_union_value();
+
+    return Ok((builder, ResponseType::NodeInfoResponse, response_offset));
+}
@@ -0,0 +1,156 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::generated::ErrorCode;
+
+// Reads up to this size are coalesced. Larger reads already amortize their round trip
+pub const MAX_COALESCED_READ: u32 = 64 * 1024;
+// A merged read never spans more than this, so that the gaps between the reads it merges waste little
+const MAX_BATCH_SPAN: u64 = 512 * 1024;
+// Coalescing is disabled for a file after this many consecutive batches which only had one read, since waiting for
+// the window then only delays its reads
+const MAX_UNMERGED_BATCHES: u32 = 8;
+// Coalescing is tried again after this long, in case the access pattern changed
+const DISABLED_PERIOD: Duration = Duration::from_secs(5);
+
+// (offset, data) of a merged read
+type MergedRead = Result<(u64, Arc<Vec<u8>>), ErrorCode>;
+
+struct Batch {
+    start: u64,
+    end: u64,
+    // The reads which joined the batch, after the one which opened it
+    waiters: Vec<Sender<MergedRead>>,
+}
+
+#[derive(Default)]
+struct FileState {
+    open: Option<Batch>,
+    unmerged_batches: u32,
+    disabled_until: Option<Instant>,
+}
+
+// (inode, uid, gid). Reads are only merged with reads of the same user, since the merged read is sent with the
+// credentials of the read which opened the batch
+type FileKey = (u64, u32, u32);
+
+enum Role {
+    Direct,
+    Opener,
+    Joiner(Receiver<MergedRead>),
+}
+
+// Merges small reads of a file which arrive within a short window, and are close together, into one read. The first
+// read opens a batch and waits for the window, the reads which arrive meanwhile join it, and the opener then reads
+// the whole range and hands each read its slice. Files whose reads are never merged, because they're read by a
+// single thread or at random, are read directly until DISABLED_PERIOD has passed
+pub struct ReadCoalescer {
+    window: Duration,
+    files: Mutex<HashMap<FileKey, FileState>>,
+}
+
+impl ReadCoalescer {
+    pub fn new(window: Duration) -> ReadCoalescer {
+        ReadCoalescer {
+            window,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Reads size bytes at offset. read_range(offset, size) is called for a direct read, or for the merged read if
+    // this read opened the batch, and isn't called if it joined another read's batch
+    pub fn read<F: FnOnce(u64, u32) -> Result<Vec<u8>, ErrorCode>>(
+        &self,
+        key: FileKey,
+        offset: u64,
+        size: u32,
+        read_range: F,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let end = offset + u64::from(size);
+        let role = {
+            let mut files = self.files.lock().unwrap();
+            let file = files.entry(key).or_default();
+            if file.disabled_until.map_or(false, |x| x > Instant::now()) {
+                Role::Direct
+            } else if let Some(ref mut batch) = file.open {
+                let start = min(batch.start, offset);
+                let batch_end = batch.end.max(end);
+                if batch_end - start <= MAX_BATCH_SPAN {
+                    batch.start = start;
+                    batch.end = batch_end;
+                    let (sender, receiver) = channel();
+                    batch.waiters.push(sender);
+                    Role::Joiner(receiver)
+                } else {
+                    Role::Direct
+                }
+            } else {
+                file.disabled_until = None;
+                file.open = Some(Batch {
+                    start: offset,
+                    end,
+                    waiters: vec![],
+                });
+                Role::Opener
+            }
+        };
+
+        match role {
+            Role::Direct => read_range(offset, size),
+            Role::Joiner(receiver) => {
+                let (start, data) = receiver.recv().map_err(|_| ErrorCode::Uncategorized)??;
+                Ok(slice(&data, start, offset, size))
+            }
+            Role::Opener => {
+                thread::sleep(self.window);
+                let batch = self.close_batch(key);
+                let merged =
+                    read_range(batch.start, (batch.end - batch.start) as u32).map(Arc::new);
+                for waiter in batch.waiters {
+                    // The joined read may have been abandoned
+                    waiter
+                        .send(merged.clone().map(|data| (batch.start, data)))
+                        .ok();
+                }
+                merged.map(|data| slice(&data, batch.start, offset, size))
+            }
+        }
+    }
+
+    fn close_batch(&self, key: FileKey) -> Batch {
+        let mut files = self.files.lock().unwrap();
+        let file = files.entry(key).or_default();
+        let batch = file.open.take().expect("Batch was closed by another read");
+        if batch.waiters.is_empty() {
+            file.unmerged_batches += 1;
+            if file.unmerged_batches >= MAX_UNMERGED_BATCHES {
+                file.unmerged_batches = 0;
+                file.disabled_until = Some(Instant::now() + DISABLED_PERIOD);
+            }
+        } else {
+            file.unmerged_batches = 0;
+        }
+
+        batch
+    }
+
+    // Called when the file is closed, to drop what was learned about how it's read
+    pub fn forget(&self, inode: u64) {
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|(file_inode, _, _), file| *file_inode != inode || file.open.is_some());
+    }
+}
+
+// The part of data, which was read at start, that a read of size bytes at offset returns. Shorter if data ends
+// before it
+fn slice(data: &[u8], start: u64, offset: u64, size: u32) -> Vec<u8> {
+    let from = min((offset - start) as usize, data.len());
+    let to = min(from + size as usize, data.len());
+    data[from..to].to_vec()
+}
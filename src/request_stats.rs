@@ -0,0 +1,147 @@
+use crate::generated::*;
+use flatbuffers::{FlatBufferBuilder, WIPOffset};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Default)]
+pub struct RequestCounts {
+    pub requests: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+// Statistics of one node, as reported by a ClusterStatsRequest
+#[derive(Clone, Default)]
+pub struct NodeStats {
+    pub node_id: u64,
+    pub reachable: bool,
+    pub role: String,
+    pub leader_id: u64,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub raft_lag: u64,
+    pub uptime_seconds: u64,
+    pub counts: RequestCounts,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cached_bytes: u64,
+    pub address: String,
+    pub zone: String,
+}
+
+impl NodeStats {
+    pub fn from_status(status: &NodeStatus) -> NodeStats {
+        NodeStats {
+            node_id: status.node_id(),
+            reachable: status.reachable(),
+            role: status.role().to_string(),
+            leader_id: status.leader_id(),
+            commit_index: status.commit_index(),
+            applied_index: status.applied_index(),
+            raft_lag: status.raft_lag(),
+            uptime_seconds: status.uptime_seconds(),
+            counts: RequestCounts {
+                requests: status.requests(),
+                reads: status.reads(),
+                writes: status.writes(),
+                read_bytes: status.read_bytes(),
+                written_bytes: status.written_bytes(),
+            },
+            total_bytes: status.total_bytes(),
+            available_bytes: status.available_bytes(),
+            cache_hits: status.cache_hits(),
+            cache_misses: status.cache_misses(),
+            cached_bytes: status.cached_bytes(),
+            address: status.address().unwrap_or_default().to_string(),
+            zone: status.zone().unwrap_or_default().to_string(),
+        }
+    }
+
+    pub fn to_status<'a>(&self, builder: &mut FlatBufferBuilder<'a>) -> WIPOffset<NodeStatus<'a>> {
+        let role = builder.create_string(&self.role);
+        let address = builder.create_string(&self.address);
+        let zone = builder.create_string(&self.zone);
+        let mut status_builder = NodeStatusBuilder::new(builder);
+        status_builder.add_node_id(self.node_id);
+        status_builder.add_reachable(self.reachable);
+        status_builder.add_role(role);
+        status_builder.add_leader_id(self.leader_id);
+        status_builder.add_commit_index(self.commit_index);
+        status_builder.add_applied_index(self.applied_index);
+        status_builder.add_raft_lag(self.raft_lag);
+        status_builder.add_uptime_seconds(self.uptime_seconds);
+        status_builder.add_requests(self.counts.requests);
+        status_builder.add_reads(self.counts.reads);
+        status_builder.add_writes(self.counts.writes);
+        status_builder.add_read_bytes(self.counts.read_bytes);
+        status_builder.add_written_bytes(self.counts.written_bytes);
+        status_builder.add_total_bytes(self.total_bytes);
+        status_builder.add_available_bytes(self.available_bytes);
+        status_builder.add_cache_hits(self.cache_hits);
+        status_builder.add_cache_misses(self.cache_misses);
+        status_builder.add_cached_bytes(self.cached_bytes);
+        status_builder.add_address(address);
+        status_builder.add_zone(zone);
+        status_builder.finish()
+    }
+}
+
+// Counts the client requests a node has received since it started, so that their rates can be computed
+pub struct RequestStats {
+    started: Instant,
+    requests: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+}
+
+impl RequestStats {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> RequestStats {
+        RequestStats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            written_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, request_type: RequestType, bytes: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        match request_type {
+            RequestType::ReadRequest | RequestType::ReadRawRequest => {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                self.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            RequestType::WriteRequest
+            | RequestType::WritePatchRequest
+            | RequestType::WriteConditionalRequest
+            | RequestType::AppendRequest => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                self.written_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn uptime_seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn counts(&self) -> RequestCounts {
+        RequestCounts {
+            requests: self.requests.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            written_bytes: self.written_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
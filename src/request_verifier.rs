@@ -0,0 +1,360 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::generated::ErrorCode;
+
+// What's needed to check that reading a field of a table stays within the buffer, and returns a valid value.
+// The flatbuffers accessors don't check either, and panic, or worse, on malformed input
+enum FieldLayout {
+    // Scalar or struct, of the given size in bytes
+    Inline {
+        size: usize,
+        required: bool,
+    },
+    Bool,
+    // ubyte enum, whose values are 0 to max
+    Enum {
+        max: u8,
+    },
+    String {
+        required: bool,
+    },
+    Vector {
+        element_size: usize,
+        required: bool,
+    },
+    TableVector {
+        fields: &'static [FieldLayout],
+        required: bool,
+    },
+}
+
+impl FieldLayout {
+    fn required(&self) -> bool {
+        match *self {
+            FieldLayout::Inline { required, .. }
+            | FieldLayout::String { required }
+            | FieldLayout::Vector { required, .. }
+            | FieldLayout::TableVector { required, .. } => required,
+            FieldLayout::Bool | FieldLayout::Enum { .. } => false,
+        }
+    }
+
+    // Size of the field within its table
+    fn inline_size(&self) -> usize {
+        match *self {
+            FieldLayout::Inline { size, .. } => size,
+            FieldLayout::Bool | FieldLayout::Enum { .. } => 1,
+            // Offset to the data
+            _ => 4,
+        }
+    }
+}
+
+// Defines REQUEST_LAYOUTS, the fields of each request table, indexed by RequestType
+include!(concat!(env!("OUT_DIR"), "/request_layouts.rs"));
+
+// The request union is verified separately, since its layout depends on its type
+const GENERIC_REQUEST: &[FieldLayout] = &[
+    FieldLayout::Inline {
+        size: 1,
+        required: true,
+    },
+    FieldLayout::Inline {
+        size: 4,
+        required: true,
+    },
+    FieldLayout::Inline {
+        size: 8,
+        required: false,
+    },
+    FieldLayout::Inline {
+        size: 8,
+        required: false,
+    },
+];
+const REQUEST_TYPE_FIELD: usize = 0;
+const REQUEST_FIELD: usize = 1;
+
+// Returns the bytes at position, if they're all in data
+fn bytes_at(data: &[u8], position: usize, length: usize) -> Result<&[u8], ErrorCode> {
+    let end = position.checked_add(length).ok_or(ErrorCode::BadRequest)?;
+    data.get(position..end).ok_or(ErrorCode::BadRequest)
+}
+
+fn read_u16(data: &[u8], position: usize) -> Result<usize, ErrorCode> {
+    Ok(usize::from(LittleEndian::read_u16(bytes_at(
+        data, position, 2,
+    )?)))
+}
+
+fn read_u32(data: &[u8], position: usize) -> Result<usize, ErrorCode> {
+    Ok(LittleEndian::read_u32(bytes_at(data, position, 4)?) as usize)
+}
+
+// Returns the position of the object which the offset at position refers to
+fn follow(data: &[u8], position: usize) -> Result<usize, ErrorCode> {
+    position
+        .checked_add(read_u32(data, position)?)
+        .ok_or(ErrorCode::BadRequest)
+}
+
+// Returns the position of the elements of the vector at position, and the number of them
+fn vector_at(
+    data: &[u8],
+    position: usize,
+    element_size: usize,
+) -> Result<(usize, usize), ErrorCode> {
+    let length = read_u32(data, position)?;
+    let size = length
+        .checked_mul(element_size)
+        .ok_or(ErrorCode::BadRequest)?;
+    bytes_at(data, position + 4, size)?;
+
+    Ok((position + 4, length))
+}
+
+// Returns the position of every field of the table at position. Fields which aren't present are None
+fn field_positions(
+    data: &[u8],
+    position: usize,
+    fields: &[FieldLayout],
+) -> Result<Vec<Option<usize>>, ErrorCode> {
+    let vtable_offset = LittleEndian::read_i32(bytes_at(data, position, 4)?);
+    let vtable = position as i64 - i64::from(vtable_offset);
+    if vtable < 0 {
+        return Err(ErrorCode::BadRequest);
+    }
+    let vtable = vtable as usize;
+    let vtable_length = read_u16(data, vtable)?;
+    let table_length = read_u16(data, vtable + 2)?;
+    if vtable_length < 4 || vtable_length % 2 != 0 || table_length < 4 {
+        return Err(ErrorCode::BadRequest);
+    }
+    bytes_at(data, vtable, vtable_length)?;
+    bytes_at(data, position, table_length)?;
+
+    let mut positions = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let slot = 4 + 2 * i;
+        let offset = if slot < vtable_length {
+            read_u16(data, vtable + slot)?
+        } else {
+            0
+        };
+        if offset == 0 {
+            if field.required() {
+                return Err(ErrorCode::BadRequest);
+            }
+            positions.push(None);
+            continue;
+        }
+        if offset < 4 || offset + field.inline_size() > table_length {
+            return Err(ErrorCode::BadRequest);
+        }
+        positions.push(Some(position + offset));
+    }
+
+    Ok(positions)
+}
+
+fn verify_table(data: &[u8], position: usize, fields: &[FieldLayout]) -> Result<(), ErrorCode> {
+    let positions = field_positions(data, position, fields)?;
+    for (field, position) in fields.iter().zip(positions) {
+        if let Some(position) = position {
+            verify_field(data, position, field)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_field(data: &[u8], position: usize, field: &FieldLayout) -> Result<(), ErrorCode> {
+    match *field {
+        FieldLayout::Inline { .. } => {}
+        FieldLayout::Bool => {
+            if data[position] > 1 {
+                return Err(ErrorCode::BadRequest);
+            }
+        }
+        FieldLayout::Enum { max } => {
+            if data[position] > max {
+                return Err(ErrorCode::BadRequest);
+            }
+        }
+        FieldLayout::String { .. } => {
+            let (start, length) = vector_at(data, follow(data, position)?, 1)?;
+            // Strings are null terminated, and are read without checking that they're UTF-8
+            let terminated = bytes_at(data, start, length + 1)?;
+            if terminated[length] != 0 || std::str::from_utf8(&terminated[..length]).is_err() {
+                return Err(ErrorCode::BadRequest);
+            }
+        }
+        FieldLayout::Vector { element_size, .. } => {
+            vector_at(data, follow(data, position)?, element_size)?;
+        }
+        FieldLayout::TableVector { fields, .. } => {
+            let (start, length) = vector_at(data, follow(data, position)?, 4)?;
+            for i in 0..length {
+                verify_table(data, follow(data, start + 4 * i)?, fields)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Checks that data, a frame received from a client or peer, is a GenericRequest which can be read without
+// going out of bounds, and that every required field of its request is present
+pub fn verify_request(data: &[u8]) -> Result<(), ErrorCode> {
+    let root = follow(data, 0)?;
+    let positions = field_positions(data, root, GENERIC_REQUEST)?;
+    let request_type = match positions[REQUEST_TYPE_FIELD] {
+        Some(position) => usize::from(data[position]),
+        None => return Err(ErrorCode::BadRequest),
+    };
+    // RequestType::NONE, or a type this node doesn't know
+    if request_type == 0 || request_type >= REQUEST_LAYOUTS.len() {
+        return Err(ErrorCode::BadRequest);
+    }
+    let request_position = positions[REQUEST_FIELD].ok_or(ErrorCode::BadRequest)?;
+
+    verify_table(
+        data,
+        follow(data, request_position)?,
+        REQUEST_LAYOUTS[request_type],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::*;
+    use crate::handlers::authorization::authorization_target;
+    use crate::handlers::transferred_bytes;
+    use crate::request_verifier::{verify_request, REQUEST_LAYOUTS};
+    use crate::storage::operation::Operation;
+    use crate::utils::finalize_request;
+    use flatbuffers::FlatBufferBuilder;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    // Skips the size prefix, like the frames received from clients
+    fn frame(builder: &FlatBufferBuilder) -> Vec<u8> {
+        builder.finished_data()[4..].to_vec()
+    }
+
+    fn rename_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("name");
+        let new_name = builder.create_string("new_name");
+        let mut request_builder = RenameRequestBuilder::new(&mut builder);
+        request_builder.add_parent(1);
+        request_builder.add_name(name);
+        request_builder.add_new_parent(2);
+        request_builder.add_new_name(new_name);
+        request_builder.add_context(&UserContext::new(1000, 1000));
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::RenameRequest, finish_offset);
+        frame(&builder)
+    }
+
+    fn write_patch_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let mut patches = vec![];
+        for (offset, data) in vec![(0, &b"first"[..]), (100, &b"second"[..])] {
+            let data = builder.create_vector(data);
+            patches.push(Patch::create(
+                &mut builder,
+                &PatchArgs {
+                    offset,
+                    data: Some(data),
+                },
+            ));
+        }
+        let patches = builder.create_vector(&patches);
+        let mut request_builder = WritePatchRequestBuilder::new(&mut builder);
+        request_builder.add_inode(3);
+        request_builder.add_patches(patches);
+        request_builder.add_context(&UserContext::new(0, 0));
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::WritePatchRequest, finish_offset);
+        frame(&builder)
+    }
+
+    fn create_request() -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("file");
+        let mut request_builder = CreateRequestBuilder::new(&mut builder);
+        request_builder.add_parent(1);
+        request_builder.add_name(name);
+        request_builder.add_uid(0);
+        request_builder.add_gid(0);
+        request_builder.add_mode(0o644);
+        request_builder.add_kind(FileKind::File);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::CreateRequest, finish_offset);
+        frame(&builder)
+    }
+
+    // Reads the request the way a node does, before it's routed
+    fn decode(data: &[u8]) {
+        let request = get_root_as_generic_request(data);
+        transferred_bytes(&request, data.len());
+        authorization_target(&request);
+        if let Ok(operation) = Operation::from_request(&request) {
+            operation.validate().ok();
+        }
+    }
+
+    #[test]
+    fn layouts_cover_every_request_type() {
+        assert_eq!(REQUEST_LAYOUTS.len(), ENUM_MAX_REQUEST_TYPE as usize + 1);
+    }
+
+    #[test]
+    fn valid_requests() {
+        for request in [rename_request(), write_patch_request(), create_request()].iter() {
+            assert_eq!(verify_request(request), Ok(()));
+            decode(request);
+        }
+    }
+
+    #[test]
+    fn truncated_requests() {
+        for request in [rename_request(), write_patch_request(), create_request()].iter() {
+            for length in 0..request.len() {
+                if verify_request(&request[..length]).is_ok() {
+                    decode(&request[..length]);
+                }
+            }
+        }
+    }
+
+    // Corrupts valid requests at random, and checks that every one which is accepted can be decoded
+    #[test]
+    fn fuzz_requests() {
+        let requests = [rename_request(), write_patch_request(), create_request()];
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100_000 {
+            let mut data = requests.choose(&mut rng).unwrap().clone();
+            match rng.gen_range(0, 3) {
+                0 => {
+                    for _ in 0..rng.gen_range(1, 4) {
+                        let index = rng.gen_range(0, data.len());
+                        data[index] = rng.gen();
+                    }
+                }
+                1 => {
+                    let index = rng.gen_range(0, data.len());
+                    data[index] ^= 1 << rng.gen_range(0, 8);
+                }
+                _ => {
+                    data = (0..rng.gen_range(0, 64)).map(|_| rng.gen()).collect();
+                }
+            }
+            if verify_request(&data).is_ok() {
+                decode(&data);
+            }
+        }
+    }
+}
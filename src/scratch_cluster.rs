@@ -0,0 +1,146 @@
+use crate::client::{NodeClient, UnreachablePolicy};
+use crate::frame_codec::DEFAULT_MAX_FRAME_LENGTH;
+use crate::fuse_adapter::{FleetFUSE, MountConfig};
+use crate::generated::AtimeMode;
+use crate::{start_nodes, wait_for_leader};
+use std::ffi::OsStr;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+static NEXT_CLUSTER_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The nodes run the fleetfs binary, which is next to the directory of the test binary
+fn fleetfs_executable() -> PathBuf {
+    let test_executable = std::env::current_exe().expect("Failed to find the test executable");
+    let executable = test_executable
+        .parent()
+        .and_then(Path::parent)
+        .expect("Test executable isn't in a target directory")
+        .join("fleetfs");
+    assert!(
+        executable.exists(),
+        "{:?} doesn't exist. Run cargo build first",
+        executable
+    );
+
+    executable
+}
+
+// Returns a port which nothing is listening on
+fn unused_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to find an unused port");
+    listener.local_addr().unwrap().port()
+}
+
+// A temporary cluster, with the filesystem mounted in a temporary directory, for end-to-end tests. Everything is
+// torn down when it's dropped, including when the test panics
+pub struct ScratchCluster {
+    dir: PathBuf,
+    address: SocketAddr,
+    nodes: Vec<Child>,
+    session: Option<fuse::BackgroundSession<'static>>,
+}
+
+impl ScratchCluster {
+    pub fn start(nodes: usize) -> ScratchCluster {
+        let dir = std::env::temp_dir().join(format!(
+            "fleetfs-scratch-{}-{}",
+            std::process::id(),
+            NEXT_CLUSTER_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(dir.join("mount")).expect("Failed to create scratch directory");
+
+        let addresses: Vec<SocketAddr> = (0..nodes)
+            .map(|_| ([127, 0, 0, 1], unused_port()).into())
+            .collect();
+        let mut cluster = ScratchCluster {
+            address: addresses[0],
+            nodes: start_nodes(&fleetfs_executable(), &addresses, &dir, 0),
+            dir,
+            session: None,
+        };
+        wait_for_leader(cluster.address).expect("Scratch cluster didn't elect a leader");
+
+        let fs = FleetFUSE::new(&MountConfig {
+            server_ip_port: cluster.address,
+            mount_point: cluster.mount_point().to_string_lossy().to_string(),
+            atime_mode: AtimeMode::VolumeDefault,
+            keepalive: None,
+            workers: 1,
+            unreachable_policy: UnreachablePolicy::Hang,
+            offline_dir: None,
+            disk_cache: None,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            // Default of --client-memory
+            memory_budget_bytes: 256 * 1024 * 1024,
+            nearest_reads: None,
+            read_coalescing_window: Duration::from_secs(0),
+        });
+        let options = [OsStr::new("-o"), OsStr::new("fsname=fleetfs,auto_unmount")];
+        let session = unsafe { fuse::spawn_mount(fs, &cluster.mount_point(), &options) }
+            .expect("Failed to mount scratch cluster");
+        cluster.session = Some(session);
+
+        cluster
+    }
+
+    pub fn mount_point(&self) -> PathBuf {
+        self.dir.join("mount")
+    }
+
+    pub fn client(&self) -> NodeClient {
+        NodeClient::new(self.address)
+    }
+}
+
+impl Drop for ScratchCluster {
+    fn drop(&mut self) {
+        // Unmounts the filesystem
+        self.session.take();
+        for node in self.nodes.iter_mut() {
+            node.kill().ok();
+            node.wait().ok();
+        }
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+// Runs test against the mount point of a new scratch cluster of nodes, which is torn down afterwards
+pub fn with_scratch_cluster<F: FnOnce(&Path)>(nodes: usize, test: F) {
+    let cluster = ScratchCluster::start(nodes);
+    test(&cluster.mount_point());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::UserContext;
+    use crate::scratch_cluster::{with_scratch_cluster, ScratchCluster};
+    use std::fs;
+
+    // Needs FUSE, and the fleetfs binary, so it only runs with --ignored. test.sh runs these
+    #[test]
+    #[ignore]
+    fn write_and_read_through_mount() {
+        with_scratch_cluster(2, |mount_point| {
+            let path = mount_point.join("file");
+            fs::write(&path, b"hello").unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"hello");
+            fs::rename(&path, mount_point.join("renamed")).unwrap();
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn mount_and_client_agree() {
+        let cluster = ScratchCluster::start(2);
+        fs::write(cluster.mount_point().join("file"), vec![0; 1000]).unwrap();
+        let client = cluster.client();
+        let inode = client.lookup_path("/file", UserContext::new(0, 0)).unwrap();
+        assert_eq!(client.getattr(inode).unwrap().size, 1000);
+    }
+}
@@ -0,0 +1,169 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use log::warn;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// inode, reads, writes, read bytes, written bytes, last access
+const RECORD_SIZE: usize = 48;
+// Once more files than this are tracked, the coldest are dropped
+const MAX_TRACKED_FILES: usize = 100_000;
+// Counters are halved this often, so that they reflect recent activity
+const DECAY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+// The statistics are stored next to the data directory, so that they don't show up as a file in it
+pub fn access_stats_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).with_file_name("access-stats")
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct FileAccess {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    // Seconds since the epoch
+    pub last_access: i64,
+}
+
+impl FileAccess {
+    pub fn heat(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+fn now_seconds() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn decode(data: &[u8]) -> HashMap<u64, FileAccess> {
+    let mut files = HashMap::new();
+    for record in data.chunks_exact(RECORD_SIZE) {
+        let access = FileAccess {
+            reads: LittleEndian::read_u64(&record[8..16]),
+            writes: LittleEndian::read_u64(&record[16..24]),
+            read_bytes: LittleEndian::read_u64(&record[24..32]),
+            written_bytes: LittleEndian::read_u64(&record[32..40]),
+            last_access: LittleEndian::read_i64(&record[40..48]),
+        };
+        files.insert(LittleEndian::read_u64(&record[0..8]), access);
+    }
+
+    files
+}
+
+struct AccessState {
+    files: HashMap<u64, FileAccess>,
+    decayed_at: Instant,
+    persisted_at: Instant,
+}
+
+// Per file read and write counters of this node, which are persisted periodically, so that hot files are still
+// known after a restart
+pub struct AccessStats {
+    path: PathBuf,
+    state: Mutex<AccessState>,
+}
+
+impl AccessStats {
+    pub fn new(data_dir: &str) -> AccessStats {
+        let path = access_stats_path(data_dir);
+        let files = match fs::read(&path) {
+            Ok(data) => decode(&data),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                warn!("Failed to load access statistics: {:?}", error);
+                HashMap::new()
+            }
+        };
+
+        AccessStats {
+            path,
+            state: Mutex::new(AccessState {
+                files,
+                decayed_at: Instant::now(),
+                persisted_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn record_read(&self, inode: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let access = state.files.entry(inode).or_insert_with(FileAccess::default);
+        access.reads += 1;
+        access.read_bytes += bytes;
+        access.last_access = now_seconds();
+    }
+
+    pub fn record_write(&self, inode: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let access = state.files.entry(inode).or_insert_with(FileAccess::default);
+        access.writes += 1;
+        access.written_bytes += bytes;
+        access.last_access = now_seconds();
+    }
+
+    pub fn remove(&self, inode: u64) {
+        self.state.lock().unwrap().files.remove(&inode);
+    }
+
+    // The limit hottest files, hottest first
+    pub fn hottest(&self, limit: usize) -> Vec<(u64, FileAccess)> {
+        let state = self.state.lock().unwrap();
+        let mut files: Vec<(u64, FileAccess)> =
+            state.files.iter().map(|(inode, x)| (*inode, *x)).collect();
+        files.sort_by_key(|(inode, access)| {
+            (Reverse(access.heat()), Reverse(access.last_access), *inode)
+        });
+        files.truncate(limit);
+
+        files
+    }
+
+    // Decays the counters and writes them to disk, if it's due
+    pub fn persist(&self) -> io::Result<()> {
+        let mut data = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.persisted_at.elapsed() < PERSIST_INTERVAL {
+                return Ok(());
+            }
+            state.persisted_at = Instant::now();
+            if state.decayed_at.elapsed() >= DECAY_INTERVAL {
+                state.decayed_at = Instant::now();
+                for access in state.files.values_mut() {
+                    access.reads /= 2;
+                    access.writes /= 2;
+                }
+                state.files.retain(|_, access| access.heat() > 0);
+            }
+            if state.files.len() > MAX_TRACKED_FILES {
+                let mut heats: Vec<u64> = state.files.values().map(FileAccess::heat).collect();
+                heats.sort_by_key(|heat| Reverse(*heat));
+                let threshold = heats[MAX_TRACKED_FILES];
+                state.files.retain(|_, access| access.heat() > threshold);
+            }
+            for (inode, access) in state.files.iter() {
+                data.write_u64::<LittleEndian>(*inode)?;
+                data.write_u64::<LittleEndian>(access.reads)?;
+                data.write_u64::<LittleEndian>(access.writes)?;
+                data.write_u64::<LittleEndian>(access.read_bytes)?;
+                data.write_u64::<LittleEndian>(access.written_bytes)?;
+                data.write_i64::<LittleEndian>(access.last_access)?;
+            }
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, &data)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
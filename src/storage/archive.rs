@@ -0,0 +1,290 @@
+use crate::generated::FileKind;
+use crate::storage::metadata_storage::InodeAttributes;
+
+// Writes tar archives in the pax format, which GNU tar and bsdtar extract. Names, link targets, sizes and ids
+// which don't fit in a ustar header, and xattrs, are stored in a pax extended header before the entry's header
+
+const BLOCK_SIZE: u64 = 512;
+const NAME_LENGTH: usize = 100;
+// Largest values of the 12 and 8 byte octal fields
+const MAX_OCTAL_12: u64 = 0o77_777_777_777;
+const MAX_OCTAL_8: u64 = 0o7_777_777;
+
+pub enum ArchiveRecord {
+    Entry {
+        // Relative to the exported directory
+        path: String,
+        attributes: InodeAttributes,
+        // Path of an earlier entry for the same file, if it has several links
+        hardlink_target: Option<String>,
+    },
+    // Two zero blocks, which mark the end of the archive
+    End,
+}
+
+impl ArchiveRecord {
+    // The headers of the record. symlink_target is only used by symlinks
+    pub fn header(&self, symlink_target: &[u8]) -> Vec<u8> {
+        match *self {
+            ArchiveRecord::Entry {
+                ref path,
+                ref attributes,
+                ref hardlink_target,
+            } => entry_header(
+                path,
+                attributes,
+                hardlink_target.as_ref().map(String::as_str),
+                symlink_target,
+            ),
+            ArchiveRecord::End => vec![0; 2 * BLOCK_SIZE as usize],
+        }
+    }
+
+    // Bytes of file data which follow the header
+    pub fn data_length(&self) -> u64 {
+        match *self {
+            ArchiveRecord::Entry {
+                ref attributes,
+                hardlink_target: None,
+                ..
+            } if is_regular_file(attributes.kind) => attributes.size,
+            _ => 0,
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        // Only the length of the target affects the length of the header
+        let symlink_target = match *self {
+            ArchiveRecord::Entry { ref attributes, .. } if attributes.kind == FileKind::Symlink => {
+                vec![0; attributes.size as usize]
+            }
+            _ => vec![],
+        };
+        let data_length = self.data_length();
+        return self.header(&symlink_target).len() as u64
+            + data_length
+            + padding_length(data_length);
+    }
+}
+
+// Shared logs are exported with their records, like they're stored
+fn is_regular_file(kind: FileKind) -> bool {
+    kind == FileKind::File || kind == FileKind::SharedLog
+}
+
+// Zeros which pad data of the given length to a whole number of blocks
+pub fn padding_length(length: u64) -> u64 {
+    (BLOCK_SIZE - length % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// Names which don't fit are truncated. Their full value is in the pax header
+fn write_truncated(field: &mut [u8], value: &[u8]) {
+    let length = value.len().min(field.len());
+    field[..length].copy_from_slice(&value[..length]);
+}
+
+fn ustar_header(
+    name: &[u8],
+    typeflag: u8,
+    linkname: &[u8],
+    size: u64,
+    attributes: &InodeAttributes,
+) -> Vec<u8> {
+    let mut header = vec![0; BLOCK_SIZE as usize];
+    write_truncated(&mut header[0..100], name);
+    write_octal(&mut header[100..108], u64::from(attributes.mode & 0o7777));
+    write_octal(
+        &mut header[108..116],
+        u64::from(attributes.uid).min(MAX_OCTAL_8),
+    );
+    write_octal(
+        &mut header[116..124],
+        u64::from(attributes.gid).min(MAX_OCTAL_8),
+    );
+    write_octal(&mut header[124..136], size.min(MAX_OCTAL_12));
+    write_octal(
+        &mut header[136..148],
+        (attributes.last_modified.seconds().max(0) as u64).min(MAX_OCTAL_12),
+    );
+    header[156] = typeflag;
+    write_truncated(&mut header[157..257], linkname);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|x| u64::from(*x)).sum();
+    write_octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+
+    header
+}
+
+// A record of a pax extended header, which is prefixed with its own length in decimal
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let unprefixed = key.len() + value.len() + 3;
+    let mut length = unprefixed + 1;
+    while length != unprefixed + length.to_string().len() {
+        length = unprefixed + length.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+fn entry_header(
+    path: &str,
+    attributes: &InodeAttributes,
+    hardlink_target: Option<&str>,
+    symlink_target: &[u8],
+) -> Vec<u8> {
+    let (typeflag, linkname, size) = match hardlink_target {
+        Some(target) => (b'1', target.as_bytes(), 0),
+        None => match attributes.kind {
+            FileKind::Directory => (b'5', &b""[..], 0),
+            FileKind::Symlink => (b'2', symlink_target, 0),
+            _ => (b'0', &b""[..], attributes.size),
+        },
+    };
+    let name = if attributes.kind == FileKind::Directory {
+        format!("{}/", path)
+    } else {
+        path.to_string()
+    };
+
+    let mut records = vec![];
+    if name.len() > NAME_LENGTH {
+        records.extend(pax_record("path", name.as_bytes()));
+    }
+    if linkname.len() > NAME_LENGTH {
+        records.extend(pax_record("linkpath", linkname));
+    }
+    if size > MAX_OCTAL_12 {
+        records.extend(pax_record("size", size.to_string().as_bytes()));
+    }
+    if u64::from(attributes.uid) > MAX_OCTAL_8 {
+        records.extend(pax_record("uid", attributes.uid.to_string().as_bytes()));
+    }
+    if u64::from(attributes.gid) > MAX_OCTAL_8 {
+        records.extend(pax_record("gid", attributes.gid.to_string().as_bytes()));
+    }
+    let mtime = attributes.last_modified.seconds();
+    if mtime < 0 || mtime as u64 > MAX_OCTAL_12 {
+        records.extend(pax_record("mtime", mtime.to_string().as_bytes()));
+    }
+    // Sorted, so that the header is the same every time the archive is exported
+    let mut xattrs: Vec<(&String, &Vec<u8>)> = attributes.xattrs.iter().collect();
+    xattrs.sort();
+    for (key, value) in xattrs {
+        records.extend(pax_record(&format!("SCHILY.xattr.{}", key), value));
+    }
+
+    let mut header = vec![];
+    if !records.is_empty() {
+        let pax_name = format!("PaxHeaders/{}", name);
+        header.extend(ustar_header(
+            pax_name.as_bytes(),
+            b'x',
+            b"",
+            records.len() as u64,
+            attributes,
+        ));
+        let padding = padding_length(records.len() as u64) as usize;
+        header.extend(records);
+        header.extend(vec![0; padding]);
+    }
+    header.extend(ustar_header(
+        name.as_bytes(),
+        typeflag,
+        linkname,
+        size,
+        attributes,
+    ));
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::{FileKind, Timestamp};
+    use crate::storage::archive::{pax_record, ArchiveRecord};
+    use crate::storage::metadata_storage::InodeAttributes;
+
+    fn attributes(kind: FileKind, size: u64) -> InodeAttributes {
+        InodeAttributes {
+            inode: 2,
+            size,
+            last_accessed: Timestamp::new(0, 0),
+            last_modified: Timestamp::new(1_000_000, 0),
+            last_metadata_changed: Timestamp::new(0, 0),
+            kind,
+            mode: 0o644,
+            hardlinks: 1,
+            redundancy: 1,
+            retention: 0,
+            retained_until: 0,
+            uid: 1000,
+            gid: 1000,
+            xattrs: Default::default(),
+            dos_attributes: 0,
+            created: Timestamp::new(0, 0),
+            data_version: 0,
+            fencing_token: 0,
+        }
+    }
+
+    #[test]
+    fn pax_record_lengths() {
+        assert_eq!(pax_record("path", b"a"), b"9 path=a\n".to_vec());
+        // Nine bytes without the prefix, so that a one digit prefix would be too short
+        assert_eq!(pax_record("k", b"12345"), b"11 k=12345\n".to_vec());
+    }
+
+    #[test]
+    fn headers() {
+        let record = ArchiveRecord::Entry {
+            path: "dir/file".to_string(),
+            attributes: attributes(FileKind::File, 1000),
+            hardlink_target: None,
+        };
+        let header = record.header(b"");
+        assert_eq!(header.len(), 512);
+        assert_eq!(&header[..9], b"dir/file\0");
+        assert_eq!(&header[124..136], b"00000001750\0");
+        assert_eq!(header[156], b'0');
+        let checksum: u64 = header[..148]
+            .iter()
+            .chain(header[156..].iter())
+            .map(|x| u64::from(*x))
+            .sum::<u64>()
+            + 8 * u64::from(b' ');
+        let stored = std::str::from_utf8(&header[148..154]).unwrap();
+        assert_eq!(u64::from_str_radix(stored, 8).unwrap(), checksum);
+        // Padded to a whole number of blocks
+        assert_eq!(record.length(), 512 + 1024);
+
+        let mut long_attributes = attributes(FileKind::Symlink, 200);
+        long_attributes
+            .xattrs
+            .insert("user.key".to_string(), b"value".to_vec());
+        let record = ArchiveRecord::Entry {
+            path: "link".to_string(),
+            attributes: long_attributes,
+            hardlink_target: None,
+        };
+        let header = record.header(&[b'x'; 200]);
+        // A pax header, its records, and the symlink's header
+        assert_eq!(header.len(), 3 * 512);
+        assert_eq!(header[156], b'x');
+        assert_eq!(header[1024 + 156], b'2');
+        assert_eq!(record.length(), header.len() as u64);
+    }
+}
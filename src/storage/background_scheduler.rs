@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Maintenance work which runs in the background, from highest priority to lowest
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BackgroundTask {
+    // Stale index entries make search results wrong, so reindexing goes first
+    Reindex,
+    Defragment,
+    Pack,
+    Compact,
+}
+
+pub const BACKGROUND_TASKS: [BackgroundTask; 4] = [
+    BackgroundTask::Reindex,
+    BackgroundTask::Defragment,
+    BackgroundTask::Pack,
+    BackgroundTask::Compact,
+];
+
+impl BackgroundTask {
+    pub fn name(self) -> &'static str {
+        match self {
+            BackgroundTask::Reindex => "reindex",
+            BackgroundTask::Defragment => "defragment",
+            BackgroundTask::Pack => "pack",
+            BackgroundTask::Compact => "compact",
+        }
+    }
+
+    fn priority(self) -> usize {
+        BACKGROUND_TASKS.iter().position(|x| *x == self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct TaskStats {
+    pub runs: u64,
+    pub bytes: u64,
+    // Number of times the task was held back, because background work was paused or over its budget
+    pub deferred: u64,
+}
+
+struct SchedulerState {
+    paused: bool,
+    // Bytes the background tasks may still use. Negative once they've used more than their budget
+    available: i64,
+    last_refill: Instant,
+    stats: HashMap<BackgroundTask, TaskStats>,
+}
+
+// Shares an IO budget between the background tasks of a node, so that they don't starve client requests.
+// Tasks ask to be admitted before each unit of work, and are charged for the bytes they read or wrote afterwards
+pub struct BackgroundScheduler {
+    // Zero is unlimited
+    bytes_per_second: u64,
+    state: Mutex<SchedulerState>,
+}
+
+impl BackgroundScheduler {
+    pub fn new(bytes_per_second: u64) -> BackgroundScheduler {
+        BackgroundScheduler {
+            bytes_per_second,
+            state: Mutex::new(SchedulerState {
+                paused: false,
+                available: bytes_per_second as i64,
+                last_refill: Instant::now(),
+                stats: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    fn refill(&self, state: &mut SchedulerState) {
+        let elapsed = state.last_refill.elapsed();
+        state.last_refill = Instant::now();
+        let refilled = elapsed.as_micros() * u128::from(self.bytes_per_second) / 1_000_000;
+        // At most one second of budget is saved up
+        state.available = (state.available as i128 + refilled as i128)
+            .min(i128::from(self.bytes_per_second)) as i64;
+    }
+
+    // Whether task may start a unit of work now. Lower priority tasks are only admitted while more of the budget
+    // is left, so that when the budget runs short it goes to the higher priority ones
+    pub fn admit(&self, task: BackgroundTask) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let admitted = if state.paused {
+            false
+        } else if self.bytes_per_second == 0 {
+            true
+        } else {
+            self.refill(&mut state);
+            let reserved = self.bytes_per_second as i64 * task.priority() as i64
+                / BACKGROUND_TASKS.len() as i64;
+            state.available > reserved
+        };
+
+        let stats = state.stats.entry(task).or_insert_with(TaskStats::default);
+        if admitted {
+            stats.runs += 1;
+        } else {
+            stats.deferred += 1;
+        }
+
+        admitted
+    }
+
+    pub fn charge(&self, task: BackgroundTask, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.available = state.available.saturating_sub(bytes as i64);
+        state
+            .stats
+            .entry(task)
+            .or_insert_with(TaskStats::default)
+            .bytes += bytes;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    pub fn stats(&self) -> Vec<(BackgroundTask, TaskStats)> {
+        let state = self.state.lock().unwrap();
+        BACKGROUND_TASKS
+            .iter()
+            .map(|task| (*task, state.stats.get(task).cloned().unwrap_or_default()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::background_scheduler::{BackgroundScheduler, BackgroundTask};
+
+    #[test]
+    fn low_priority_deferred_first() {
+        let scheduler = BackgroundScheduler::new(1_000_000);
+        scheduler.charge(BackgroundTask::Reindex, 600_000);
+        assert!(scheduler.admit(BackgroundTask::Reindex));
+        assert!(scheduler.admit(BackgroundTask::Defragment));
+        assert!(!scheduler.admit(BackgroundTask::Compact));
+
+        scheduler.set_paused(true);
+        assert!(!scheduler.admit(BackgroundTask::Reindex));
+        let stats = scheduler.stats();
+        assert_eq!(stats[0].1.bytes, 600_000);
+        assert_eq!(stats[0].1.deferred, 1);
+        assert_eq!(stats[3].1.deferred, 1);
+    }
+}
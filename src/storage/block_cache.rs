@@ -0,0 +1,262 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+type Inode = u64;
+type ChunkKey = (Inode, u64);
+
+// Local data is cached in aligned chunks of this size
+pub const BLOCK_CACHE_CHUNK_SIZE: u64 = 64 * 1024;
+// Rows of the frequency sketch, each with its own hash of the key
+const SKETCH_DEPTH: u64 = 4;
+const MAX_FREQUENCY: u8 = 15;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CacheAdmission {
+    // Every chunk read from disk is cached
+    Lru,
+    // A chunk is only cached if it has been read more often than the chunk it would evict, so that large scans
+    // don't flush out hot data
+    TinyLfu,
+}
+
+#[derive(Clone, Copy)]
+pub struct BlockCacheConfig {
+    // Zero disables the cache
+    pub capacity_bytes: u64,
+    pub admission: CacheAdmission,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BlockCacheStats {
+    pub capacity_bytes: u64,
+    pub cached_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    // Chunks which weren't cached, because of the admission policy
+    pub rejected: u64,
+    pub evictions: u64,
+}
+
+// Approximate access counts, in a count-min sketch. The counts are halved periodically, so that they favor recent
+// accesses
+struct FrequencySketch {
+    counters: Vec<u8>,
+    width: u64,
+    additions: u64,
+    reset_after: u64,
+}
+
+impl FrequencySketch {
+    fn new(capacity_chunks: u64) -> FrequencySketch {
+        let width = capacity_chunks.max(16).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; (width * SKETCH_DEPTH) as usize],
+            width,
+            additions: 0,
+            reset_after: width * 10,
+        }
+    }
+
+    fn index(&self, key: ChunkKey, row: u64) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        (row * self.width + (hasher.finish() & (self.width - 1))) as usize
+    }
+
+    fn increment(&mut self, key: ChunkKey) {
+        for row in 0..SKETCH_DEPTH {
+            let index = self.index(key, row);
+            if self.counters[index] < MAX_FREQUENCY {
+                self.counters[index] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_after {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, key: ChunkKey) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[self.index(key, row)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+struct CacheState {
+    // Chunks of each inode, and the tick they were last used
+    chunks: HashMap<Inode, HashMap<u64, (Arc<Vec<u8>>, u64)>>,
+    // Chunks ordered by when they were last used, least recent first
+    recency: BTreeMap<u64, ChunkKey>,
+    next_tick: u64,
+    // Incremented whenever an inode is modified, so that reads which raced with the modification aren't cached
+    generations: HashMap<Inode, u64>,
+    sketch: FrequencySketch,
+    stats: BlockCacheStats,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: ChunkKey) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let (inode, chunk) = key;
+        if let Some((_, last_used)) = self
+            .chunks
+            .get_mut(&inode)
+            .and_then(|chunks| chunks.get_mut(&chunk))
+        {
+            self.recency.remove(last_used);
+            *last_used = tick;
+            self.recency.insert(tick, key);
+        }
+    }
+
+    fn get(&self, key: ChunkKey) -> Option<Arc<Vec<u8>>> {
+        let (inode, chunk) = key;
+        self.chunks
+            .get(&inode)
+            .and_then(|chunks| chunks.get(&chunk))
+            .map(|(data, _)| data.clone())
+    }
+
+    fn remove(&mut self, key: ChunkKey) {
+        let (inode, chunk) = key;
+        let mut now_empty = false;
+        if let Some(chunks) = self.chunks.get_mut(&inode) {
+            if let Some((data, last_used)) = chunks.remove(&chunk) {
+                self.recency.remove(&last_used);
+                self.stats.cached_bytes -= data.len() as u64;
+            }
+            now_empty = chunks.is_empty();
+        }
+        if now_empty {
+            self.chunks.remove(&inode);
+        }
+    }
+}
+
+// Caches data read from the local disk of a storage node, so that hot files are served from memory
+pub struct BlockCache {
+    admission: CacheAdmission,
+    state: Mutex<CacheState>,
+}
+
+impl BlockCache {
+    pub fn new(config: BlockCacheConfig) -> BlockCache {
+        BlockCache {
+            admission: config.admission,
+            state: Mutex::new(CacheState {
+                chunks: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_tick: 0,
+                generations: HashMap::new(),
+                sketch: FrequencySketch::new(config.capacity_bytes / BLOCK_CACHE_CHUNK_SIZE),
+                stats: BlockCacheStats {
+                    capacity_bytes: config.capacity_bytes,
+                    ..BlockCacheStats::default()
+                },
+            }),
+        }
+    }
+
+    pub fn generation(&self, inode: Inode) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.generations.get(&inode).cloned().unwrap_or(0)
+    }
+
+    pub fn get(&self, inode: Inode, chunk: u64) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let key = (inode, chunk);
+        state.sketch.increment(key);
+        let data = state.get(key);
+        if data.is_some() {
+            state.stats.hits += 1;
+            state.touch(key);
+        } else {
+            state.stats.misses += 1;
+        }
+
+        data
+    }
+
+    // Whether the chunk is cached. Unlike get(), doesn't count as an access
+    pub fn contains(&self, inode: Inode, chunk: u64) -> bool {
+        self.state.lock().unwrap().get((inode, chunk)).is_some()
+    }
+
+    // generation must be the value of generation() from before the chunk was read
+    pub fn insert(&self, inode: Inode, chunk: u64, generation: u64, data: Arc<Vec<u8>>) {
+        self.insert_chunk(inode, chunk, generation, data, false);
+    }
+
+    // Caches a chunk which was explicitly requested, so it bypasses the admission policy
+    pub fn prefetch(&self, inode: Inode, chunk: u64, generation: u64, data: Arc<Vec<u8>>) {
+        self.insert_chunk(inode, chunk, generation, data, true);
+    }
+
+    fn insert_chunk(
+        &self,
+        inode: Inode,
+        chunk: u64,
+        generation: u64,
+        data: Arc<Vec<u8>>,
+        admit_always: bool,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.generations.get(&inode).cloned().unwrap_or(0) != generation {
+            return;
+        }
+        let key = (inode, chunk);
+        let length = data.len() as u64;
+        if state.get(key).is_some() || length > state.stats.capacity_bytes {
+            return;
+        }
+
+        while state.stats.cached_bytes + length > state.stats.capacity_bytes {
+            let victim = match state.recency.values().next() {
+                Some(victim) => *victim,
+                None => break,
+            };
+            if !admit_always
+                && self.admission == CacheAdmission::TinyLfu
+                && state.sketch.estimate(key) <= state.sketch.estimate(victim)
+            {
+                state.stats.rejected += 1;
+                return;
+            }
+            state.remove(victim);
+            state.stats.evictions += 1;
+        }
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state
+            .chunks
+            .entry(inode)
+            .or_insert_with(HashMap::new)
+            .insert(chunk, (data, tick));
+        state.recency.insert(tick, key);
+        state.stats.cached_bytes += length;
+    }
+
+    pub fn invalidate(&self, inode: Inode) {
+        let mut state = self.state.lock().unwrap();
+        *state.generations.entry(inode).or_insert(0) += 1;
+        if let Some(chunks) = state.chunks.remove(&inode) {
+            for (data, last_used) in chunks.values() {
+                state.recency.remove(last_used);
+                state.stats.cached_bytes -= data.len() as u64;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        self.state.lock().unwrap().stats
+    }
+}
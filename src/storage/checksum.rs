@@ -0,0 +1,311 @@
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::generated::ChecksumAlgorithm;
+use crate::storage::data_storage::DataStorage;
+use crate::storage::packed_storage::{packed_directory, packed_inodes};
+use sha2::{Digest, Sha256};
+use twox_hash::xxh3::Hash64;
+use walkdir::WalkDir;
+
+// Incremental checksum, using any of the supported algorithms. SHA-256 is the default for data sent over the network,
+// since it's collision resistant, and the faster algorithms are useful for routine checks of local data
+pub enum Checksum {
+    Sha256(Sha256),
+    // Boxed, since its state is much larger than the others
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Hash64),
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Checksum {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Checksum::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Checksum::Blake3(Box::new(blake3::Hasher::new())),
+            ChecksumAlgorithm::Xxh3 => Checksum::Xxh3(Hash64::with_seed(0)),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Sha256(hasher) => hasher.input(data),
+            Checksum::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Checksum::Xxh3(hasher) => hasher.write(data),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Checksum::Sha256(hasher) => hasher.result().to_vec(),
+            Checksum::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Checksum::Xxh3(hasher) => hasher.finish().to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl io::Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn digest(algorithm: ChecksumAlgorithm, data: &[u8]) -> Vec<u8> {
+    let mut checksum = Checksum::new(algorithm);
+    checksum.update(data);
+    checksum.finish()
+}
+
+pub fn parse_checksum_algorithm(name: &str) -> Option<ChecksumAlgorithm> {
+    match name {
+        "sha256" => Some(ChecksumAlgorithm::Sha256),
+        "blake3" => Some(ChecksumAlgorithm::Blake3),
+        "xxh3" => Some(ChecksumAlgorithm::Xxh3),
+        _ => None,
+    }
+}
+
+struct CachedDigest {
+    algorithm: ChecksumAlgorithm,
+    modified: SystemTime,
+    size: u64,
+    digest: Vec<u8>,
+}
+
+// Progress of the checksum of the data dir that is running, or of the last one if none are running
+#[derive(Default)]
+pub struct ChecksumProgress {
+    pub running: AtomicUsize,
+    pub total_files: AtomicU64,
+    pub hashed_files: AtomicU64,
+    pub hashed_bytes: AtomicU64,
+}
+
+// Digests of the files in the data dir, so that only files which changed since the last checksum are read again
+#[derive(Default)]
+pub struct ChecksumCache {
+    digests: Mutex<HashMap<u64, CachedDigest>>,
+    pub progress: ChecksumProgress,
+}
+
+impl ChecksumCache {
+    pub fn new() -> ChecksumCache {
+        ChecksumCache::default()
+    }
+
+    fn get(
+        &self,
+        inode: u64,
+        algorithm: ChecksumAlgorithm,
+        modified: SystemTime,
+        size: u64,
+    ) -> Option<Vec<u8>> {
+        let digests = self.digests.lock().unwrap();
+        let cached = digests.get(&inode)?;
+        if cached.algorithm == algorithm && cached.modified == modified && cached.size == size {
+            Some(cached.digest.clone())
+        } else {
+            None
+        }
+    }
+
+    fn insert(&self, inode: u64, cached: CachedDigest) {
+        self.digests.lock().unwrap().insert(inode, cached);
+    }
+
+    // Drops the digests of files which no longer exist
+    fn retain(&self, inodes: &HashSet<u64>) {
+        self.digests
+            .lock()
+            .unwrap()
+            .retain(|inode, _| inodes.contains(inode));
+    }
+}
+
+// Returns the paths of the files in data_dir, relative to it. data_dir is mostly flat, so its entries are split
+// between one thread per core
+fn list_files(data_dir: &str) -> io::Result<Vec<String>> {
+    let mut top_level = vec![];
+    for entry in fs::read_dir(data_dir)? {
+        top_level.push(entry?.path());
+    }
+    let chunk_size = max(1, (top_level.len() + num_cpus::get() - 1) / num_cpus::get());
+    let mut walkers = vec![];
+    for chunk in top_level.chunks(chunk_size) {
+        let chunk = chunk.to_vec();
+        let data_dir = data_dir.to_string();
+        walkers.push(thread::spawn(move || -> io::Result<Vec<String>> {
+            let mut paths = vec![];
+            for top_level_path in chunk {
+                for entry in WalkDir::new(top_level_path) {
+                    let entry = entry?;
+                    if entry.file_type().is_file() {
+                        let path = entry
+                            .path()
+                            .to_str()
+                            .unwrap()
+                            .trim_start_matches(&data_dir)
+                            .to_string();
+                        paths.push(path);
+                    }
+                    // TODO handle other file types
+                }
+            }
+            Ok(paths)
+        }));
+    }
+
+    let mut paths = vec![];
+    for walker in walkers {
+        paths.extend(walker.join().expect("Checksum thread panicked")?);
+    }
+    Ok(paths)
+}
+
+// Returns the digest of the contents of a file, or an empty digest if only its name should be compared
+fn file_digest(
+    data_dir: &str,
+    path: &str,
+    packed: bool,
+    algorithm: ChecksumAlgorithm,
+    mirrored: &HashSet<u64>,
+    data_storage: &DataStorage,
+    cache: &ChecksumCache,
+) -> io::Result<Vec<u8>> {
+    let inode = match path.trim_start_matches('/').parse() {
+        Ok(inode) if mirrored.contains(&inode) => inode,
+        _ => return Ok(vec![]),
+    };
+    // Packed files are small, and are moved out of their segment before they're modified, so they aren't cached
+    let cache_key = if packed {
+        None
+    } else {
+        let metadata = fs::metadata(Path::new(data_dir).join(path.trim_start_matches('/')))?;
+        let key = (metadata.modified()?, metadata.len());
+        if let Some(digest) = cache.get(inode, algorithm, key.0, key.1) {
+            return Ok(digest);
+        }
+        Some(key)
+    };
+
+    let mut checksum = Checksum::new(algorithm);
+    if let Some((_, mut contents)) = data_storage.open_local(inode)? {
+        let bytes = io::copy(&mut contents, &mut checksum)?;
+        cache
+            .progress
+            .hashed_bytes
+            .fetch_add(bytes, Ordering::SeqCst);
+    }
+    let digest = checksum.finish();
+    if let Some((modified, size)) = cache_key {
+        cache.insert(
+            inode,
+            CachedDigest {
+                algorithm,
+                modified,
+                size,
+                digest: digest.clone(),
+            },
+        );
+    }
+
+    Ok(digest)
+}
+
+// Checksum of the names of all the files stored by this node, and the contents of mirrored files. Striped files
+// only have some of their blocks on each node, so only their names are comparable between nodes.
+// Files are hashed in parallel by a pool of one worker per core
+pub fn checksum_data_dir(
+    data_dir: &str,
+    algorithm: ChecksumAlgorithm,
+    mirrored: HashSet<u64>,
+    data_storage: Arc<DataStorage>,
+    cache: Arc<ChecksumCache>,
+) -> io::Result<Vec<u8>> {
+    let mut files: Vec<(String, bool)> = list_files(data_dir)?
+        .into_iter()
+        .map(|path| (path, false))
+        .collect();
+    // Packed files are stored outside of data_dir, but should hash the same as unpacked ones,
+    // since nodes pack independently
+    for inode in packed_inodes(&packed_directory(data_dir))? {
+        files.push((format!("/{}", inode), true));
+    }
+    files.sort();
+
+    let progress = &cache.progress;
+    progress
+        .total_files
+        .store(files.len() as u64, Ordering::SeqCst);
+    progress.hashed_files.store(0, Ordering::SeqCst);
+    progress.hashed_bytes.store(0, Ordering::SeqCst);
+
+    let files = Arc::new(files);
+    let mirrored = Arc::new(mirrored);
+    let next_file = Arc::new(AtomicUsize::new(0));
+    let mut workers = vec![];
+    for _ in 0..num_cpus::get() {
+        let files = files.clone();
+        let mirrored = mirrored.clone();
+        let next_file = next_file.clone();
+        let data_storage = data_storage.clone();
+        let cache = cache.clone();
+        let data_dir = data_dir.to_string();
+        workers.push(thread::spawn(
+            move || -> io::Result<Vec<(usize, Vec<u8>)>> {
+                let mut digests = vec![];
+                loop {
+                    let index = next_file.fetch_add(1, Ordering::SeqCst);
+                    if index >= files.len() {
+                        return Ok(digests);
+                    }
+                    let (ref path, packed) = files[index];
+                    let digest = file_digest(
+                        &data_dir,
+                        path,
+                        packed,
+                        algorithm,
+                        &mirrored,
+                        &data_storage,
+                        &cache,
+                    )?;
+                    cache.progress.hashed_files.fetch_add(1, Ordering::SeqCst);
+                    digests.push((index, digest));
+                }
+            },
+        ));
+    }
+    let mut digests = vec![vec![]; files.len()];
+    for worker in workers {
+        for (index, digest) in worker.join().expect("Checksum thread panicked")? {
+            digests[index] = digest;
+        }
+    }
+
+    let mut inodes = HashSet::new();
+    let mut hasher = Checksum::new(algorithm);
+    for ((path, _), digest) in files.iter().zip(digests.iter()) {
+        if let Ok(inode) = path.trim_start_matches('/').parse() {
+            inodes.insert(inode);
+        }
+        hasher.update(path.as_bytes());
+        hasher.update(digest);
+    }
+    cache.retain(&inodes);
+
+    Ok(hasher.finish())
+}
@@ -0,0 +1,138 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+type Inode = u64;
+type Trigram = [u8; 3];
+
+// Files larger than this are not indexed
+pub const MAX_INDEXED_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct Trigrams {
+    by_inode: HashMap<Inode, HashSet<Trigram>>,
+    by_trigram: HashMap<Trigram, HashSet<Inode>>,
+}
+
+// Trigram index of file contents, used to answer substring searches without reading every file.
+// Files are indexed asynchronously: changes only mark them dirty, and they are re-read in the background.
+// Search results are candidates, which contain every trigram of the query, but not necessarily the query itself
+// When acquiring locks on multiple fields, they must be in alphabetical order
+pub struct ContentIndex {
+    // Inodes which have changed since they were last indexed
+    dirty: Mutex<HashSet<Inode>>,
+    trigrams: Mutex<Trigrams>,
+}
+
+impl ContentIndex {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> ContentIndex {
+        ContentIndex {
+            dirty: Mutex::new(HashSet::new()),
+            trigrams: Mutex::new(Trigrams::default()),
+        }
+    }
+
+    pub fn mark_dirty(&self, inode: Inode) {
+        self.dirty.lock().unwrap().insert(inode);
+    }
+
+    // Returns all the dirty inodes, and marks them clean
+    pub fn take_dirty(&self) -> Vec<Inode> {
+        self.dirty.lock().unwrap().drain().collect()
+    }
+
+    pub fn update(&self, inode: Inode, contents: &[u8]) {
+        let new_trigrams: HashSet<Trigram> =
+            contents.windows(3).map(|x| [x[0], x[1], x[2]]).collect();
+        let mut trigrams = self.trigrams.lock().unwrap();
+        remove_postings(&mut trigrams, inode);
+        for trigram in new_trigrams.iter() {
+            trigrams
+                .by_trigram
+                .entry(*trigram)
+                .or_insert_with(HashSet::new)
+                .insert(inode);
+        }
+        trigrams.by_inode.insert(inode, new_trigrams);
+    }
+
+    pub fn remove(&self, inode: Inode) {
+        let mut dirty = self.dirty.lock().unwrap();
+        let mut trigrams = self.trigrams.lock().unwrap();
+        dirty.remove(&inode);
+        remove_postings(&mut trigrams, inode);
+    }
+
+    // Returns candidate inodes, in ascending order. query must be at least 3 bytes
+    pub fn search(&self, query: &[u8]) -> Vec<Inode> {
+        assert!(query.len() >= 3);
+        let trigrams = self.trigrams.lock().unwrap();
+        let mut candidates: Option<HashSet<Inode>> = None;
+        for window in query.windows(3) {
+            let trigram = [window[0], window[1], window[2]];
+            if let Some(inodes) = trigrams.by_trigram.get(&trigram) {
+                candidates = Some(match candidates {
+                    None => inodes.clone(),
+                    Some(previous) => previous.intersection(inodes).cloned().collect(),
+                });
+            } else {
+                return vec![];
+            }
+        }
+
+        let mut result: Vec<Inode> = candidates.unwrap_or_default().into_iter().collect();
+        result.sort();
+        return result;
+    }
+}
+
+fn remove_postings(trigrams: &mut Trigrams, inode: Inode) {
+    if let Some(old_trigrams) = trigrams.by_inode.remove(&inode) {
+        for trigram in old_trigrams {
+            if let Some(inodes) = trigrams.by_trigram.get_mut(&trigram) {
+                inodes.remove(&inode);
+                if inodes.is_empty() {
+                    trigrams.by_trigram.remove(&trigram);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::content_index::ContentIndex;
+
+    #[test]
+    fn search() {
+        let index = ContentIndex::new();
+        index.update(1, b"hello world");
+        index.update(2, b"world peace");
+        index.update(3, b"worl");
+
+        assert_eq!(index.search(b"world"), vec![1, 2]);
+        assert_eq!(index.search(b"hello"), vec![1]);
+        assert_eq!(index.search(b"orl"), vec![1, 2, 3]);
+        assert!(index.search(b"xyz").is_empty());
+        // Candidates contain every trigram of the query, even if not the query itself
+        index.update(4, b"abcd bcde");
+        assert_eq!(index.search(b"abcde"), vec![4]);
+    }
+
+    #[test]
+    fn update_and_remove() {
+        let index = ContentIndex::new();
+        index.update(1, b"old contents");
+        index.update(1, b"new contents");
+        assert!(index.search(b"old").is_empty());
+        assert_eq!(index.search(b"new"), vec![1]);
+
+        index.mark_dirty(1);
+        index.mark_dirty(2);
+        index.remove(1);
+        assert!(index.search(b"new").is_empty());
+        assert!(index.search(b"contents").is_empty());
+        assert_eq!(index.take_dirty(), vec![2]);
+        assert!(index.take_dirty().is_empty());
+    }
+}
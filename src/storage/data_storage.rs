@@ -2,28 +2,94 @@ use futures::Future;
 
 use crate::generated::ErrorCode;
 use crate::peer_client::PeerClient;
+use crate::storage::background_scheduler::{BackgroundScheduler, BackgroundTask};
+use crate::storage::block_cache::{BlockCache, BlockCacheStats, BLOCK_CACHE_CHUNK_SIZE};
+use crate::storage::observer_cache::{ObserverCache, CHUNK_SIZE};
+use crate::storage::packed_storage::{
+    packed_directory, packed_inodes, PackedStorage, MAX_PACKED_FILE_SIZE,
+};
+use crate::storage::write_journal::WriteJournal;
 use crate::storage::ROOT_INODE;
 use crate::storage_node::LocalContext;
 use crate::utils::{into_error_code, node_id_from_address, LengthPrefixedVec};
-use futures::future::{err, join_all, Either};
+use futures::future::{err, join_all, ok, result, Either};
 use log::info;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{fs, io};
 
 pub const BLOCK_SIZE: u64 = 512;
+// Files are only packed once they haven't been modified for this long
+const PACK_MIN_AGE: Duration = Duration::from_secs(60);
+// Files which have been written this many times are rewritten, since they're likely fragmented
+const DEFRAG_WRITE_THRESHOLD: u32 = 1024;
 
-pub struct DataStorage {
+// Space on the disk of the data dir, in blocks of block_size
+pub struct DiskSpace {
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    // Free blocks which can be written by users other than root
+    pub available_blocks: u64,
+    pub reserved_blocks: u64,
+    pub free_files: u64,
+}
+
+// Space of a peer's disk, as last reported by it
+#[derive(Clone, Copy)]
+struct NodeSpace {
+    total_bytes: u64,
+    available_bytes: u64,
+}
+
+// Nodes which the blocks of striped files are spread across, in the order of their rank
+struct StripeLayout {
     node_ids: Vec<u64>,
     local_rank: u64,
+}
+
+impl StripeLayout {
+    fn total_nodes(&self) -> u64 {
+        self.node_ids.len() as u64
+    }
+
+    // Files with redundancy equal to the number of nodes are mirrored, instead of striped,
+    // so every node stores all of their data
+    fn is_mirrored(&self, redundancy: u8) -> bool {
+        u64::from(redundancy) >= self.total_nodes()
+    }
+
+    // Files are either striped without redundancy, or mirrored on every node. Any other number of copies would be
+    // stored as a single one
+    fn is_supported_redundancy(&self, redundancy: u8) -> bool {
+        redundancy == 1 || u64::from(redundancy) == self.total_nodes()
+    }
+}
+
+pub struct DataStorage {
+    // Only changes when the other nodes are removed from the cluster
+    layout: RwLock<StripeLayout>,
     local_node_id: u64,
     local_data_dir: String,
+    reserved_space_percent: u8,
     peers: HashMap<u64, PeerClient>,
+    packed_storage: PackedStorage,
+    journal: WriteJournal,
+    writes_since_defrag: Mutex<HashMap<u64, u32>>,
+    scheduler: Arc<BackgroundScheduler>,
+    // Not set on observers, or if the cache is disabled
+    block_cache: Option<BlockCache>,
+    // Only set on observers, which don't store any data locally
+    observer_cache: Option<Arc<ObserverCache>>,
+    // Refreshed periodically by refresh_node_space(). Peers which haven't reported their space aren't included
+    node_space: Arc<Mutex<HashMap<u64, NodeSpace>>>,
 }
 
 // Convert to local index, or the nearest lesser index on this (local_rank) node, if this index lives on another node
@@ -73,6 +139,13 @@ fn stores_index(global_index: u64, local_rank: u64, total_nodes: u64) -> bool {
     local_rank == remainder_blocks
 }
 
+// Number of the global_length bytes at global_index which local_rank node stores
+fn stored_length(global_index: u64, global_length: u64, local_rank: u64, total_nodes: u64) -> u64 {
+    let global_end = global_index.saturating_add(global_length);
+    to_local_index_ceiling(global_end, local_rank, total_nodes)
+        - to_local_index_ceiling(global_index, local_rank, total_nodes)
+}
+
 fn to_global_index(local_index: u64, local_rank: u64, total_nodes: u64) -> u64 {
     let stripes = local_index / BLOCK_SIZE;
     let remainder = local_index % BLOCK_SIZE;
@@ -80,25 +153,89 @@ fn to_global_index(local_index: u64, local_rank: u64, total_nodes: u64) -> u64 {
     stripes * BLOCK_SIZE * total_nodes + local_rank * BLOCK_SIZE + remainder
 }
 
+// Reassembles the global bytes at global_offset from the local data of each rank, which starts at the first of them
+// that the rank stores. Blocks which a rank doesn't have, because they were never written, read as zeros up to the
+// end of the data of the other ranks
+fn merge_stripes(stripes: &[&[u8]], global_offset: u64) -> LengthPrefixedVec {
+    let total_nodes = stripes.len() as u64;
+    let mut length = 0;
+    for (rank, stripe) in stripes.iter().enumerate() {
+        if stripe.is_empty() {
+            continue;
+        }
+        let local_start = to_local_index_ceiling(global_offset, rank as u64, total_nodes);
+        let last = to_global_index(
+            local_start + stripe.len() as u64 - 1,
+            rank as u64,
+            total_nodes,
+        );
+        length = max(length, last + 1 - global_offset);
+    }
+
+    let mut result = LengthPrefixedVec::zeros(length as usize);
+    for (rank, stripe) in stripes.iter().enumerate() {
+        let mut local_index = to_local_index_ceiling(global_offset, rank as u64, total_nodes);
+        let mut copied = 0;
+        while copied < stripe.len() {
+            let start =
+                (to_global_index(local_index, rank as u64, total_nodes) - global_offset) as usize;
+            let block_read = min(
+                (BLOCK_SIZE - local_index % BLOCK_SIZE) as usize,
+                stripe.len() - copied,
+            );
+            result.bytes_mut()[start..start + block_read]
+                .copy_from_slice(&stripe[copied..copied + block_read]);
+            copied += block_read;
+            local_index += block_read as u64;
+        }
+    }
+
+    result
+}
+
 // Abstraction of file storage. Files are split into blocks of BLOCK_SIZE, and stored in RAID0 across
-// multiple nodes
+// all of the nodes
 impl DataStorage {
     pub fn new(local_node_id: u64, node_ids: &[u64], context: &LocalContext) -> DataStorage {
-        // TODO: support sharding strategies besides RAID0 with 2 nodes
-        assert_eq!(node_ids.len(), 2);
+        assert!(!node_ids.is_empty());
         let mut sorted = node_ids.to_vec();
         sorted.sort();
-        let local_rank = sorted.iter().position(|x| *x == local_node_id).unwrap() as u64;
+        // Observers aren't one of node_ids
+        let local_rank = if context.observer {
+            0
+        } else {
+            sorted.iter().position(|x| *x == local_node_id).unwrap() as u64
+        };
         DataStorage {
-            node_ids: sorted,
+            layout: RwLock::new(StripeLayout {
+                node_ids: sorted,
+                local_rank,
+            }),
             local_node_id,
-            local_rank,
             local_data_dir: context.data_dir.clone(),
+            reserved_space_percent: context.reserved_space_percent,
+            packed_storage: PackedStorage::new(&context.data_dir),
+            journal: WriteJournal::new(&context.data_dir).expect("Failed to recover write journal"),
+            writes_since_defrag: Mutex::new(HashMap::new()),
+            scheduler: Arc::new(BackgroundScheduler::new(
+                context.background_bytes_per_second,
+            )),
+            block_cache: if context.observer || context.block_cache.capacity_bytes == 0 {
+                None
+            } else {
+                Some(BlockCache::new(context.block_cache))
+            },
             peers: context
                 .peers
                 .iter()
                 .map(|peer| (node_id_from_address(peer), PeerClient::new(*peer)))
                 .collect(),
+            observer_cache: if context.observer {
+                Some(Arc::new(ObserverCache::new()))
+            } else {
+                None
+            },
+            node_space: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -106,45 +243,97 @@ impl DataStorage {
         Path::new(&self.local_data_dir).join(path.trim_start_matches('/'))
     }
 
+    pub fn scheduler(&self) -> Arc<BackgroundScheduler> {
+        self.scheduler.clone()
+    }
+
+    pub fn block_cache_stats(&self) -> Option<BlockCacheStats> {
+        self.block_cache.as_ref().map(BlockCache::stats)
+    }
+
+    pub fn node_ids(&self) -> Vec<u64> {
+        self.layout.read().unwrap().node_ids.clone()
+    }
+
+    pub fn is_supported_redundancy(&self, redundancy: u8) -> bool {
+        self.layout
+            .read()
+            .unwrap()
+            .is_supported_redundancy(redundancy)
+    }
+
+    pub fn is_mirrored(&self, redundancy: u8) -> bool {
+        self.layout.read().unwrap().is_mirrored(redundancy)
+    }
+
     // Writes the portions of data that should be stored locally to local storage
     pub fn write_local_blocks(
         &self,
         inode: u64,
         global_offset: u64,
         global_data: &[u8],
+        redundancy: u8,
     ) -> io::Result<u32> {
-        let local_index =
-            to_local_index_ceiling(global_offset, self.local_rank, self.node_ids.len() as u64);
+        if let Some(ref cache) = self.observer_cache {
+            cache.invalidate(inode);
+            return Ok(global_data.len() as u32);
+        }
+        if let Some(ref cache) = self.block_cache {
+            cache.invalidate(inode);
+        }
+        let _unpacked = self.packed_storage.unpack(inode)?;
+        *self
+            .writes_since_defrag
+            .lock()
+            .unwrap()
+            .entry(inode)
+            .or_insert(0) += 1;
+
+        let layout = self.layout.read().unwrap();
+        if layout.is_mirrored(redundancy) {
+            self.write_local(inode, global_offset, global_data)?;
+            return Ok(global_data.len() as u32);
+        }
+
+        let total_nodes = layout.total_nodes();
+        let local_index = to_local_index_ceiling(global_offset, layout.local_rank, total_nodes);
         let mut local_data = vec![];
-        let mut start = if stores_index(global_offset, self.local_rank, self.node_ids.len() as u64)
-        {
+        let mut start = if stores_index(global_offset, layout.local_rank, total_nodes) {
             let partial_first_block = BLOCK_SIZE - global_offset % BLOCK_SIZE;
             local_data.extend_from_slice(
                 &global_data[0..min(partial_first_block as usize, global_data.len())],
             );
-            (partial_first_block + (self.node_ids.len() - 1) as u64 * BLOCK_SIZE) as usize
+            (partial_first_block + (total_nodes - 1) * BLOCK_SIZE) as usize
         } else {
-            (to_global_index(local_index, self.local_rank, self.node_ids.len() as u64)
-                - global_offset) as usize
+            (to_global_index(local_index, layout.local_rank, total_nodes) - global_offset) as usize
         };
         while start < global_data.len() {
             let end = min(start + BLOCK_SIZE as usize, global_data.len());
             local_data.extend_from_slice(&global_data[start..end]);
-            start += self.node_ids.len() * BLOCK_SIZE as usize;
+            start += (total_nodes * BLOCK_SIZE) as usize;
+        }
+
+        self.write_local(inode, local_index, &local_data)?;
+        return Ok(local_data.len() as u32);
+    }
+
+    // Writes which span multiple blocks are journaled, so that they can't be torn by a crash
+    fn write_local(&self, inode: u64, local_offset: u64, local_data: &[u8]) -> io::Result<()> {
+        if local_data.is_empty() {
+            return Ok(());
+        }
+        let last_byte = local_offset + local_data.len() as u64 - 1;
+        if local_offset / BLOCK_SIZE != last_byte / BLOCK_SIZE {
+            return self.journal.write(inode, local_offset, local_data);
         }
 
-        // TODO: hack
-        let path = inode.to_string();
-        let local_path = self.to_local_path(&path);
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&local_path)?;
-
-        file.seek(SeekFrom::Start(local_index))?;
-
-        file.write_all(&local_data)?;
-        return Ok(local_data.len() as u32);
+            .open(self.to_local_path(&inode.to_string()))?;
+        file.seek(SeekFrom::Start(local_offset))?;
+        file.write_all(local_data)?;
+        return Ok(());
     }
 
     pub fn read_raw(
@@ -155,21 +344,124 @@ impl DataStorage {
     ) -> io::Result<LengthPrefixedVec> {
         assert_ne!(inode, ROOT_INODE);
 
-        let local_start =
-            to_local_index_ceiling(global_offset, self.local_rank, self.node_ids.len() as u64);
-        let local_end = to_local_index_floor(
-            global_offset + u64::from(global_size),
-            self.local_rank,
-            self.node_ids.len() as u64,
-        )
-        .unwrap_or(0);
-        assert!(local_end >= local_start);
+        let (local_rank, total_nodes) = {
+            let layout = self.layout.read().unwrap();
+            (layout.local_rank, layout.total_nodes())
+        };
+        let local_start = to_local_index_ceiling(global_offset, local_rank, total_nodes);
+        // Just past the last byte of the read which is stored locally
+        let local_end = if global_size == 0 {
+            local_start
+        } else {
+            to_local_index_floor(
+                global_offset + u64::from(global_size) - 1,
+                local_rank,
+                total_nodes,
+            )
+            .map_or(local_start, |x| max(x + 1, local_start))
+        };
 
         let size = local_end - local_start;
-        let file = File::open(self.to_local_path(&inode.to_string()))?;
-
         let mut contents = LengthPrefixedVec::zeros(size as usize);
-        let bytes_read = file.read_at(contents.bytes_mut(), local_start)?;
+        let bytes_read = self.read_local(inode, contents.bytes_mut(), local_start)?;
+        contents.truncate(bytes_read);
+
+        Ok(contents)
+    }
+
+    // Reads local data through the block cache, and returns the number of bytes read. Fewer than buffer's length
+    // are read, if the local data ends first
+    fn read_local(&self, inode: u64, buffer: &mut [u8], local_offset: u64) -> io::Result<usize> {
+        let cache = match self.block_cache {
+            Some(ref cache) => cache,
+            None => return self.packed_storage.read_at(inode, buffer, local_offset),
+        };
+
+        let mut read = 0;
+        while read < buffer.len() {
+            let offset = local_offset + read as u64;
+            let chunk = offset / BLOCK_CACHE_CHUNK_SIZE;
+            let chunk_data = match cache.get(inode, chunk) {
+                Some(data) => data,
+                None => {
+                    let generation = cache.generation(inode);
+                    let mut data = vec![0; BLOCK_CACHE_CHUNK_SIZE as usize];
+                    let length = self.packed_storage.read_at(
+                        inode,
+                        &mut data,
+                        chunk * BLOCK_CACHE_CHUNK_SIZE,
+                    )?;
+                    data.truncate(length);
+                    let data = Arc::new(data);
+                    cache.insert(inode, chunk, generation, data.clone());
+                    data
+                }
+            };
+
+            let start = (offset - chunk * BLOCK_CACHE_CHUNK_SIZE) as usize;
+            if start >= chunk_data.len() {
+                break;
+            }
+            let length = min(chunk_data.len() - start, buffer.len() - read);
+            buffer[read..read + length].copy_from_slice(&chunk_data[start..start + length]);
+            read += length;
+            // The local data ends in this chunk
+            if chunk_data.len() < BLOCK_CACHE_CHUNK_SIZE as usize {
+                break;
+            }
+        }
+
+        Ok(read)
+    }
+
+    // Loads the local data of inode into the block cache, and returns the number of bytes loaded
+    pub fn prefetch(&self, inode: u64, global_length: u64, redundancy: u8) -> io::Result<u64> {
+        let cache = match self.block_cache {
+            Some(ref cache) => cache,
+            None => return Ok(0),
+        };
+
+        let local_length = self.local_length(global_length, redundancy);
+        let chunks = (local_length + BLOCK_CACHE_CHUNK_SIZE - 1) / BLOCK_CACHE_CHUNK_SIZE;
+        let mut loaded = 0;
+        for chunk in 0..chunks {
+            if cache.contains(inode, chunk) {
+                continue;
+            }
+            let generation = cache.generation(inode);
+            let mut data = vec![0; BLOCK_CACHE_CHUNK_SIZE as usize];
+            let length =
+                match self
+                    .packed_storage
+                    .read_at(inode, &mut data, chunk * BLOCK_CACHE_CHUNK_SIZE)
+                {
+                    Ok(length) => length,
+                    // The file was never written, so there's nothing to load
+                    Err(ref error) if error.kind() == ErrorKind::NotFound => break,
+                    Err(error) => return Err(error),
+                };
+            // Blocks at the end of the file which weren't written aren't stored
+            if length == 0 {
+                break;
+            }
+            data.truncate(length);
+            loaded += length as u64;
+            cache.prefetch(inode, chunk, generation, Arc::new(data));
+        }
+
+        Ok(loaded)
+    }
+
+    fn read_mirrored(
+        &self,
+        inode: u64,
+        global_offset: u64,
+        global_size: u32,
+    ) -> io::Result<LengthPrefixedVec> {
+        assert_ne!(inode, ROOT_INODE);
+
+        let mut contents = LengthPrefixedVec::zeros(global_size as usize);
+        let bytes_read = self.read_local(inode, contents.bytes_mut(), global_offset)?;
         contents.truncate(bytes_read);
 
         Ok(contents)
@@ -180,7 +472,31 @@ impl DataStorage {
         inode: u64,
         global_offset: u64,
         global_size: u32,
+        redundancy: u8,
     ) -> impl Future<Item = LengthPrefixedVec, Error = ErrorCode> {
+        if let Some(ref cache) = self.observer_cache {
+            return Either::B(Either::B(self.read_through_cache(
+                cache.clone(),
+                inode,
+                global_offset,
+                global_size,
+            )));
+        }
+        let (node_ids, local_rank, mirrored) = {
+            let layout = self.layout.read().unwrap();
+            (
+                layout.node_ids.clone(),
+                layout.local_rank,
+                layout.is_mirrored(redundancy),
+            )
+        };
+        if mirrored {
+            return Either::A(result(
+                self.read_mirrored(inode, global_offset, global_size)
+                    .map_err(into_error_code),
+            ));
+        }
+
         let local_data = match self.read_raw(inode, global_offset, global_size) {
             Ok(value) => value,
             Err(error) => {
@@ -189,7 +505,7 @@ impl DataStorage {
         };
 
         let mut remote_data_blocks = vec![];
-        for node_id in self.node_ids.iter() {
+        for node_id in node_ids.iter() {
             if *node_id == self.local_node_id {
                 continue;
             }
@@ -200,45 +516,277 @@ impl DataStorage {
             ));
         }
 
-        let local_rank = self.local_rank;
         let result = join_all(remote_data_blocks)
             .map(move |fetched_data_blocks| {
                 let mut data_blocks: Vec<&[u8]> =
                     fetched_data_blocks.iter().map(AsRef::as_ref).collect();
                 data_blocks.insert(local_rank as usize, local_data.bytes());
 
-                let mut result = LengthPrefixedVec::with_capacity(global_size as usize);
-                let partial_first_block = BLOCK_SIZE - global_offset % BLOCK_SIZE;
-                let first_block_size = data_blocks[0].len();
-                let mut indices = vec![0; data_blocks.len()];
-                let first_block_read = min(partial_first_block as usize, first_block_size);
-                result.extend(&data_blocks[0][0..first_block_read]);
-                indices[0] = first_block_read;
-
-                let mut next_block = min(1, data_blocks.len() - 1);
-                while indices[next_block] < data_blocks[next_block].len() {
-                    let index = indices[next_block];
-                    let remaining = data_blocks[next_block].len() - index;
-                    let block_read = min(BLOCK_SIZE as usize, remaining);
-                    result.extend(&data_blocks[next_block][index..(index + block_read)]);
-                    indices[next_block] += block_read;
-                    next_block += 1;
-                    next_block %= data_blocks.len();
+                merge_stripes(&data_blocks, global_offset)
+            })
+            .map_err(into_error_code);
+
+        Either::B(Either::A(result))
+    }
+
+    // Serves a read on an observer from the cache, fetching any missing chunks from one of the peers
+    fn read_through_cache(
+        &self,
+        cache: Arc<ObserverCache>,
+        inode: u64,
+        global_offset: u64,
+        global_size: u32,
+    ) -> impl Future<Item = LengthPrefixedVec, Error = ErrorCode> {
+        let end = global_offset + u64::from(global_size);
+        let first_chunk = global_offset / CHUNK_SIZE;
+        let last_chunk = (end + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let generation = cache.generation(inode);
+        // Spread the load across the peers
+        let node_ids = self.node_ids();
+        let node_id = node_ids[(inode % node_ids.len() as u64) as usize];
+        let peer = &self.peers[&node_id];
+
+        let mut chunks = vec![];
+        for chunk in first_chunk..last_chunk {
+            if let Some(data) = cache.get(inode, chunk) {
+                chunks.push(Either::A(ok(data)));
+            } else {
+                chunks.push(Either::B(peer.read(
+                    inode,
+                    chunk * CHUNK_SIZE,
+                    CHUNK_SIZE as u32,
+                )));
+            }
+        }
+
+        join_all(chunks).map(move |fetched_chunks| {
+            let mut result = LengthPrefixedVec::with_capacity(global_size as usize);
+            for (chunk, data) in (first_chunk..last_chunk).zip(fetched_chunks.into_iter()) {
+                let chunk_start = chunk * CHUNK_SIZE;
+                let start = min(global_offset.saturating_sub(chunk_start), data.len() as u64);
+                let chunk_end = min(end - chunk_start, data.len() as u64);
+                result.extend(&data[start as usize..chunk_end as usize]);
+                let end_of_file = (data.len() as u64) < CHUNK_SIZE;
+                cache.insert(inode, chunk, generation, data);
+                if end_of_file {
+                    break;
                 }
+            }
+            result
+        })
+    }
+
+    // Number of local bytes which store global bytes before global_length
+    pub fn local_length(&self, global_length: u64, redundancy: u8) -> u64 {
+        let layout = self.layout.read().unwrap();
+        if layout.is_mirrored(redundancy) {
+            global_length
+        } else {
+            to_local_index_ceiling(global_length, layout.local_rank, layout.total_nodes())
+        }
+    }
+
+    // Number of bytes which each node stores, of the global_length bytes at global_offset
+    pub fn placement(
+        &self,
+        global_offset: u64,
+        global_length: u64,
+        redundancy: u8,
+    ) -> Vec<(u64, u64)> {
+        let layout = self.layout.read().unwrap();
+        let total_nodes = layout.total_nodes();
+        layout
+            .node_ids
+            .iter()
+            .enumerate()
+            .map(|(rank, node_id)| {
+                let bytes = if layout.is_mirrored(redundancy) {
+                    global_length
+                } else {
+                    stored_length(global_offset, global_length, rank as u64, total_nodes)
+                };
+                (*node_id, bytes)
+            })
+            .collect()
+    }
 
-                result
+    // Bytes which users other than root can write on the disk of node_id, or None if it isn't known
+    pub fn available_bytes(&self, node_id: u64) -> Option<u64> {
+        if node_id == self.local_node_id && self.observer_cache.is_none() {
+            return self
+                .disk_space()
+                .ok()
+                .map(|space| space.available_blocks * space.block_size);
+        }
+        self.node_space
+            .lock()
+            .unwrap()
+            .get(&node_id)
+            .map(|space| space.available_bytes)
+    }
+
+    // Asks each peer for the space of its disk, so that writes can be checked against the space of every node
+    // which stores a part of them
+    pub fn refresh_node_space(&self) -> impl Future<Item = (), Error = ()> {
+        let requests: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(node_id, client)| {
+                let node_id = *node_id;
+                let node_space = self.node_space.clone();
+                client.node_stats().then(move |stats| {
+                    match stats {
+                        Ok(stats) => {
+                            node_space.lock().unwrap().insert(
+                                node_id,
+                                NodeSpace {
+                                    total_bytes: stats.total_bytes,
+                                    available_bytes: stats.available_bytes,
+                                },
+                            );
+                        }
+                        Err(error_code) => {
+                            info!("Failed to read space of node {}: {:?}", node_id, error_code)
+                        }
+                    }
+                    Ok(())
+                })
             })
-            .map_err(into_error_code);
+            .collect();
+
+        join_all(requests).map(|_| ())
+    }
+
+    // Space of the whole cluster. Files are striped across every node, so it can hold as much more data as fits
+    // on its fullest node, times the number of nodes. Peers which haven't reported their space are assumed to
+    // have the same as this node
+    pub fn cluster_space(&self) -> io::Result<DiskSpace> {
+        let local = self.disk_space()?;
+        let block_size = local.block_size;
+        let local_space = NodeSpace {
+            total_bytes: local.total_blocks * block_size,
+            available_bytes: local.available_blocks * block_size,
+        };
+        let node_space = self.node_space.lock().unwrap();
+        let spaces: Vec<NodeSpace> = self
+            .layout
+            .read()
+            .unwrap()
+            .node_ids
+            .iter()
+            .map(|node_id| {
+                if *node_id == self.local_node_id {
+                    local_space
+                } else {
+                    *node_space.get(node_id).unwrap_or(&local_space)
+                }
+            })
+            .collect();
+
+        let total_nodes = spaces.len() as u64;
+        let total_blocks = spaces.iter().map(|x| x.total_bytes).sum::<u64>() / block_size;
+        let min_available_bytes = spaces.iter().map(|x| x.available_bytes).min().unwrap_or(0);
+        let available_blocks = min_available_bytes * total_nodes / block_size;
+        let reserved_blocks = local.reserved_blocks * total_nodes;
+        return Ok(DiskSpace {
+            block_size,
+            total_blocks,
+            free_blocks: min(available_blocks + reserved_blocks, total_blocks),
+            available_blocks,
+            reserved_blocks,
+            free_files: local.free_files,
+        });
+    }
+
+    // Inodes which have data stored on this node
+    pub fn local_inodes(&self) -> io::Result<Vec<u64>> {
+        if self.observer_cache.is_some() {
+            return Ok(vec![]);
+        }
+        let mut inodes = packed_inodes(&packed_directory(&self.local_data_dir))?;
+        for entry in fs::read_dir(&self.local_data_dir)? {
+            if let Some(inode) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+                inodes.push(inode);
+            }
+        }
+        inodes.sort();
+        inodes.dedup();
+
+        Ok(inodes)
+    }
 
-        Either::B(result)
+    // Makes this the only node, once the others were removed from the cluster. The local blocks of each striped file,
+    // given as its inode, length, and redundancy, are moved to their global offsets, so that every file is mirrored.
+    // The blocks which were stored on the other nodes read as zeros
+    pub fn make_standalone(&self, files: &[(u64, u64, u8)]) -> io::Result<()> {
+        let mut layout = self.layout.write().unwrap();
+        if self.observer_cache.is_some() || layout.total_nodes() == 1 {
+            return Ok(());
+        }
+        let total_nodes = layout.total_nodes();
+        for &(inode, global_length, redundancy) in files.iter() {
+            if layout.is_mirrored(redundancy) {
+                continue;
+            }
+            let _unpacked = self.packed_storage.unpack(inode)?;
+            let local_path = self.to_local_path(&inode.to_string());
+            let local_file = match File::open(&local_path) {
+                Ok(file) => file,
+                Err(ref error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            let local_length = local_file.metadata()?.len();
+            let mirrored_path = self.to_local_path(&format!("{}.standalone", inode));
+            let mirrored_file = File::create(&mirrored_path)?;
+            mirrored_file.set_len(global_length)?;
+            let mut block = vec![0; BLOCK_SIZE as usize];
+            let mut local_index = 0;
+            while local_index < local_length {
+                let length = min(BLOCK_SIZE, local_length - local_index) as usize;
+                local_file.read_exact_at(&mut block[..length], local_index)?;
+                let global_index = to_global_index(local_index, layout.local_rank, total_nodes);
+                if global_index < global_length {
+                    let length = min(length as u64, global_length - global_index) as usize;
+                    mirrored_file.write_all_at(&block[..length], global_index)?;
+                }
+                local_index += BLOCK_SIZE;
+            }
+            mirrored_file.sync_all()?;
+            fs::rename(&mirrored_path, &local_path)?;
+            if let Some(ref cache) = self.block_cache {
+                cache.invalidate(inode);
+            }
+        }
+        info!(
+            "Removed nodes {:?}. This node stores every file",
+            layout
+                .node_ids
+                .iter()
+                .filter(|x| **x != self.local_node_id)
+                .collect::<Vec<_>>()
+        );
+        layout.node_ids = vec![self.local_node_id];
+        layout.local_rank = 0;
+
+        Ok(())
     }
 
-    pub fn truncate(&self, inode: u64, global_length: u64) -> io::Result<()> {
-        let local_bytes =
-            to_local_index_floor(global_length, self.local_rank, self.node_ids.len() as u64)
-                .unwrap_or(0);
+    pub fn truncate(&self, inode: u64, global_length: u64, redundancy: u8) -> io::Result<()> {
+        if let Some(ref cache) = self.observer_cache {
+            cache.invalidate(inode);
+            return Ok(());
+        }
+        if let Some(ref cache) = self.block_cache {
+            cache.invalidate(inode);
+        }
+        let _unpacked = self.packed_storage.unpack(inode)?;
+        let local_bytes = self.local_length(global_length, redundancy);
         let local_path = self.to_local_path(&inode.to_string());
-        let file = File::create(&local_path).expect("Couldn't create file");
+        // Data beyond the new length is freed, and reads as zeros if the file is extended again
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&local_path)?;
         file.set_len(local_bytes)?;
 
         Ok(())
@@ -248,6 +796,13 @@ impl DataStorage {
         assert_ne!(inode, ROOT_INODE);
 
         info!("Fsync'ing {}", inode);
+        if self.observer_cache.is_some() {
+            return Ok(());
+        }
+        if self.packed_storage.is_packed(inode) {
+            // Segments are synced when they're written
+            return Ok(());
+        }
         let local_path = self.to_local_path(&inode.to_string());
         let file = File::open(local_path).map_err(into_error_code)?;
         unsafe {
@@ -256,11 +811,155 @@ impl DataStorage {
         Ok(())
     }
 
+    // Flushes all the data stored on this node to disk
+    pub fn sync_all(&self) -> Result<(), ErrorCode> {
+        info!("Syncing all data");
+        if self.observer_cache.is_some() {
+            return Ok(());
+        }
+        let directory = File::open(&self.local_data_dir).map_err(into_error_code)?;
+        if unsafe { libc::syncfs(directory.as_raw_fd()) } != 0 {
+            return Err(into_error_code(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    pub fn disk_space(&self) -> io::Result<DiskSpace> {
+        let directory = File::open(&self.local_data_dir)?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatvfs(directory.as_raw_fd(), &mut stats) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let total_blocks = stats.f_blocks as u64;
+        let reserved_blocks = total_blocks * u64::from(self.reserved_space_percent) / 100;
+        return Ok(DiskSpace {
+            block_size: stats.f_frsize as u64,
+            total_blocks,
+            free_blocks: stats.f_bfree as u64,
+            available_blocks: (stats.f_bavail as u64).saturating_sub(reserved_blocks),
+            reserved_blocks,
+            free_files: stats.f_favail as u64,
+        });
+    }
+
+    // Returns the length and contents of the local data of inode, or None if there isn't any
+    pub fn open_local(&self, inode: u64) -> io::Result<Option<(u64, Box<Read>)>> {
+        if self.observer_cache.is_some() {
+            return Ok(None);
+        }
+        if self.packed_storage.is_packed(inode) {
+            let mut contents = vec![0; MAX_PACKED_FILE_SIZE as usize];
+            let length = self.packed_storage.read_at(inode, &mut contents, 0)?;
+            contents.truncate(length);
+            return Ok(Some((length as u64, Box::new(Cursor::new(contents)))));
+        }
+
+        match File::open(self.to_local_path(&inode.to_string())) {
+            Ok(file) => Ok(Some((file.metadata()?.len(), Box::new(file)))),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
     pub fn delete(&self, inode: u64) -> Result<(), ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
 
-        let local_path = self.to_local_path(&inode.to_string());
-        fs::remove_file(local_path).map_err(into_error_code)?;
+        if let Some(ref cache) = self.observer_cache {
+            cache.invalidate(inode);
+            return Ok(());
+        }
+        if let Some(ref cache) = self.block_cache {
+            cache.invalidate(inode);
+        }
+        self.writes_since_defrag.lock().unwrap().remove(&inode);
+        self.packed_storage.delete(inode).map_err(into_error_code)?;
+        Ok(())
+    }
+
+    // Rewrites fragmented files, packs small files, and compacts packed segments, as far as the scheduler allows
+    pub fn run_maintenance(&self) -> io::Result<()> {
+        if self.observer_cache.is_some() {
+            return Ok(());
+        }
+        self.defragment()?;
+        self.pack_small_files()?;
+        self.packed_storage.compact(&self.scheduler)?;
+
+        Ok(())
+    }
+
+    fn pack_small_files(&self) -> io::Result<()> {
+        if !self.scheduler.admit(BackgroundTask::Pack) {
+            return Ok(());
+        }
+        let mut candidates = vec![];
+        let mut candidate_bytes = 0;
+        for entry in fs::read_dir(&self.local_data_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || metadata.len() > MAX_PACKED_FILE_SIZE {
+                continue;
+            }
+            let recently_modified = metadata
+                .modified()?
+                .elapsed()
+                .map(|age| age < PACK_MIN_AGE)
+                .unwrap_or(true);
+            if recently_modified {
+                continue;
+            }
+            if let Some(inode) = entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+                candidates.push(inode);
+                candidate_bytes += metadata.len();
+            }
+        }
+
+        let packed = self.packed_storage.pack(&candidates)?;
+        self.scheduler.charge(BackgroundTask::Pack, candidate_bytes);
+        if packed > 0 {
+            info!("Packed {} small files", packed);
+        }
+
+        Ok(())
+    }
+
+    // Rewrites files which have received many writes, so that the local filesystem can lay them out contiguously
+    fn defragment(&self) -> io::Result<()> {
+        let inodes: Vec<u64> = self
+            .writes_since_defrag
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, count)| **count >= DEFRAG_WRITE_THRESHOLD)
+            .map(|(inode, _)| *inode)
+            .collect();
+
+        let temp_directory = packed_directory(&self.local_data_dir);
+        fs::create_dir_all(&temp_directory)?;
+        let temp_path = temp_directory.join("defrag.tmp");
+        for inode in inodes {
+            // The rest are left for the next run
+            if !self.scheduler.admit(BackgroundTask::Defragment) {
+                break;
+            }
+            self.writes_since_defrag.lock().unwrap().remove(&inode);
+            let _unpacked = self.packed_storage.unpack(inode)?;
+            let path = self.to_local_path(&inode.to_string());
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                // Deleted since it was written
+                Err(ref error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            let mut rewritten = File::create(&temp_path)?;
+            let copied = io::copy(&mut file, &mut rewritten)?;
+            rewritten.sync_all()?;
+            self.scheduler.charge(BackgroundTask::Defragment, copied);
+            fs::rename(&temp_path, &path)?;
+            info!("Defragmented {}", inode);
+        }
+
         Ok(())
     }
 }
@@ -268,9 +967,48 @@ impl DataStorage {
 #[cfg(test)]
 mod tests {
     use crate::storage::data_storage::{
-        stores_index, to_global_index, to_local_index_ceiling, to_local_index_floor, BLOCK_SIZE,
+        merge_stripes, stored_length, stores_index, to_global_index, to_local_index_ceiling,
+        to_local_index_floor, StripeLayout, BLOCK_SIZE,
     };
 
+    #[test]
+    fn supported_redundancy() {
+        let layout = StripeLayout {
+            node_ids: vec![1, 2, 3, 4],
+            local_rank: 0,
+        };
+        assert!(!layout.is_supported_redundancy(0));
+        assert!(layout.is_supported_redundancy(1));
+        // Would be stored as a single copy
+        assert!(!layout.is_supported_redundancy(2));
+        assert!(!layout.is_supported_redundancy(3));
+        assert!(layout.is_supported_redundancy(4));
+        assert!(layout.is_mirrored(4));
+        assert!(!layout.is_supported_redundancy(5));
+
+        let layout = StripeLayout {
+            node_ids: vec![1],
+            local_rank: 0,
+        };
+        assert!(layout.is_supported_redundancy(1));
+        assert!(layout.is_mirrored(1));
+    }
+
+    #[test]
+    fn stored_lengths() {
+        assert_eq!(stored_length(0, 1, 0, 2), 1);
+        assert_eq!(stored_length(0, 1, 1, 2), 0);
+        assert_eq!(stored_length(BLOCK_SIZE - 1, 2, 0, 2), 1);
+        assert_eq!(stored_length(BLOCK_SIZE - 1, 2, 1, 2), 1);
+        // Every node stores an equal share of a large write
+        for rank in 0..3 {
+            assert_eq!(
+                stored_length(BLOCK_SIZE / 2, BLOCK_SIZE * 300, rank, 3),
+                BLOCK_SIZE * 100
+            );
+        }
+    }
+
     #[test]
     fn local_index_floor() {
         assert_eq!(to_local_index_floor(0, 0, 2), Some(0));
@@ -339,4 +1077,43 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn merged_reads() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 7).map(|x| (x % 251) as u8).collect();
+        for total_nodes in 1..=3 {
+            for offset in &[0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE * 2 + 7] {
+                let stripes: Vec<Vec<u8>> = (0..total_nodes)
+                    .map(|rank| {
+                        (*offset..data.len() as u64)
+                            .filter(|x| stores_index(*x, rank, total_nodes))
+                            .map(|x| data[x as usize])
+                            .collect()
+                    })
+                    .collect();
+                let stripes: Vec<&[u8]> = stripes.iter().map(Vec::as_slice).collect();
+                let merged = merge_stripes(&stripes, *offset);
+                assert_eq!(merged.bytes(), &data[*offset as usize..]);
+            }
+        }
+
+        // The first block was never written
+        let written = vec![1; BLOCK_SIZE as usize];
+        let merged = merge_stripes(&[&[], &written], 0);
+        assert_eq!(merged.bytes().len(), BLOCK_SIZE as usize * 2);
+        assert!(merged.bytes()[..BLOCK_SIZE as usize]
+            .iter()
+            .all(|x| *x == 0));
+        assert_eq!(&merged.bytes()[BLOCK_SIZE as usize..], &written[..]);
+    }
+
+    #[test]
+    fn truncated_length() {
+        for length in 0..(BLOCK_SIZE * 3) {
+            assert_eq!(
+                to_local_index_ceiling(length, 0, 2) + to_local_index_ceiling(length, 1, 2),
+                length
+            );
+        }
+    }
 }
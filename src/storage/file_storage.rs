@@ -1,30 +1,198 @@
 use flatbuffers::{FlatBufferBuilder, WIPOffset};
-use log::info;
+use log::{error, info, warn};
 
+use crate::client::NodeClient;
 use crate::generated::*;
-use crate::storage::data_storage::DataStorage;
-use crate::storage::metadata_storage::MetadataStorage;
+use crate::storage::access_stats::{AccessStats, FileAccess};
+use crate::storage::archive::ArchiveRecord;
+use crate::storage::background_scheduler::BackgroundTask;
+use crate::storage::block_cache::BlockCacheStats;
+use crate::storage::checksum::{
+    checksum_data_dir, digest, Checksum, ChecksumCache, ChecksumProgress,
+};
+use crate::storage::content_index::{ContentIndex, MAX_INDEXED_FILE_SIZE};
+use crate::storage::data_storage::{DataStorage, DiskSpace};
+use crate::storage::metadata_storage::{
+    FindQuery, MetadataStorage, CHECKSUM_XATTR, DOS_ATTRIBUTES_XATTR, DOS_CREATED_XATTR,
+    LINKS_XATTR, MAX_PATH_LENGTH, REDUNDANCY_XATTR, REPLICAS_XATTR, RETAINED_UNTIL_XATTR,
+    RETENTION_XATTR, USAGE_XATTR,
+};
+use crate::storage::operation::Operation;
+use crate::storage::raft_manager::AppliedSession;
+use crate::storage::snapshot::{snapshot_directory, SnapshotInfo, SnapshotWriter};
+use crate::storage::staged_data::{StagedData, MAX_STAGED_BYTES};
 use crate::storage::ROOT_INODE;
 use crate::storage_node::LocalContext;
 use crate::utils::{
-    empty_response, into_error_code, to_fast_read_response, to_fileattr_response,
-    to_inode_response, to_read_response, to_write_response, to_xattrs_response,
-    FlatBufferWithResponse, ResultResponse,
+    create_fileattr, empty_response, frame_log_record, into_error_code, rolling_checksum,
+    to_fast_read_response, to_fileattr_response, to_inode_response, to_read_response,
+    to_write_response, to_xattrs_response, FlatBufferResponse, FlatBufferWithResponse,
+    ResultResponse,
 };
-use futures::future::{ok, Either};
-use futures::Future;
+use futures::future::{err, join_all, loop_fn, ok, result, Either, Loop};
+use futures::stream::iter_ok;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Limit on the amount of data hashed by a single FileBlockHashesRequest
+const MAX_HASHED_BYTES: u64 = 64 * 1024 * 1024;
+// Number of the hottest files which are loaded into the block cache on startup, if they fit
+const WARM_UP_FILES: usize = 1000;
+// Size of the reads which copy a file from a peer
+const RESTORE_CHUNK_SIZE: u64 = 1024 * 1024;
+// Files are read in chunks of this size, to compute CHECKSUM_XATTR
+const CHECKSUM_XATTR_CHUNK_SIZE: u64 = 1024 * 1024;
+// Limit on the number of inodes in a BatchGetattrRequest, so that its response fits in a frame
+pub const MAX_BATCH_GETATTR_INODES: usize = 4096;
+// Limit on the size of a page of an exported archive
+pub const MAX_ARCHIVE_PAGE_BYTES: u32 = 16 * 1024 * 1024;
+
+// Reads length bytes of an archive record, starting at offset start in it
+fn read_archive_part(
+    data_storage: &Arc<DataStorage>,
+    record: ArchiveRecord,
+    start: u64,
+    length: u64,
+) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+    let symlink_target = match record {
+        ArchiveRecord::Entry { ref attributes, .. } if attributes.kind == FileKind::Symlink => {
+            Either::A(
+                data_storage
+                    .read(
+                        attributes.inode,
+                        0,
+                        attributes.size as u32,
+                        attributes.redundancy,
+                    )
+                    .map(|data| data.bytes().to_vec()),
+            )
+        }
+        _ => Either::B(ok(vec![])),
+    };
+    let data_storage = data_storage.clone();
+    symlink_target.and_then(move |symlink_target| {
+        let header = record.header(&symlink_target);
+        let header_length = header.len() as u64;
+        let end = start + length;
+        let mut part = vec![];
+        if start < header_length {
+            part.extend_from_slice(&header[start as usize..min(end, header_length) as usize]);
+        }
+        let data_start = max(start, header_length) - header_length;
+        let data_end = min(end.saturating_sub(header_length), record.data_length());
+        let data = match record {
+            ArchiveRecord::Entry { ref attributes, .. } if data_end > data_start => Either::A(
+                data_storage
+                    .read(
+                        attributes.inode,
+                        data_start,
+                        (data_end - data_start) as u32,
+                        attributes.redundancy,
+                    )
+                    .map(|data| data.bytes().to_vec()),
+            ),
+            _ => Either::B(ok(vec![])),
+        };
+        data.map(move |data| {
+            part.extend(data);
+            // The padding at the end of the record, and files which were truncated since the page was planned,
+            // are filled with zeros
+            part.resize(length as usize, 0);
+            part
+        })
+    })
+}
+
+fn to_find_response(
+    mut builder: FlatBufferBuilder,
+    matches: Vec<(String, u64)>,
+    truncated: bool,
+) -> ResultResponse {
+    let mut entries = vec![];
+    for (path, inode) in matches {
+        let path = builder.create_string(&path);
+        let entry = FindEntry::create(
+            &mut builder,
+            &FindEntryArgs {
+                inode,
+                path: Some(path),
+                attributes: None,
+            },
+        );
+        entries.push(entry);
+    }
+    let entries = builder.create_vector(&entries);
+    let mut response_builder = FindResponseBuilder::new(&mut builder);
+    response_builder.add_entries(entries);
+    response_builder.add_truncated(truncated);
+
+    let offset = response_builder.finish().as_union_value();
+    return Ok((builder, ResponseType::FindResponse, offset));
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
 
 pub struct FileStorage {
-    data_storage: DataStorage,
+    content_index: Option<Arc<ContentIndex>>,
+    // Shared with the threads which checksum the data dir
+    data_storage: Arc<DataStorage>,
     metadata_storage: MetadataStorage,
+    checksum_cache: Arc<ChecksumCache>,
+    // Digests of whole files, for CHECKSUM_XATTR, and the modification time and size of the file they're of
+    file_checksums: Arc<Mutex<HashMap<u64, (Timestamp, u64, Vec<u8>)>>>,
+    staged_data: StagedData,
+    access_stats: AccessStats,
 }
 
 impl FileStorage {
-    pub fn new(node_id: u64, all_node_ids: &[u64], context: &LocalContext) -> FileStorage {
+    // metadata_snapshot is restored if provided, otherwise the filesystem starts empty
+    pub fn new(
+        node_id: u64,
+        all_node_ids: &[u64],
+        context: &LocalContext,
+        metadata_snapshot: Option<&[u8]>,
+    ) -> FileStorage {
         FileStorage {
-            data_storage: DataStorage::new(node_id, all_node_ids, context),
-            metadata_storage: MetadataStorage::new(),
+            content_index: if context.content_index {
+                Some(Arc::new(ContentIndex::new()))
+            } else {
+                None
+            },
+            data_storage: Arc::new(DataStorage::new(node_id, all_node_ids, context)),
+            metadata_storage: metadata_snapshot
+                .map(|snapshot| MetadataStorage::from_snapshot(snapshot, context.case_insensitive))
+                .unwrap_or_else(|| MetadataStorage::new(context.case_insensitive)),
+            staged_data: StagedData::new(MAX_STAGED_BYTES),
+            checksum_cache: Arc::new(ChecksumCache::new()),
+            file_checksums: Arc::new(Mutex::new(HashMap::new())),
+            access_stats: AccessStats::new(&context.data_dir),
+        }
+    }
+
+    // Called whenever the contents of inode change
+    fn contents_changed(&self, inode: u64) {
+        if let Some(ref index) = self.content_index {
+            index.mark_dirty(inode);
+        }
+        self.file_checksums.lock().unwrap().remove(&inode);
+    }
+
+    fn contents_deleted(&self, inode: u64) {
+        self.data_storage.delete(inode).unwrap();
+        self.access_stats.remove(inode);
+        if let Some(ref index) = self.content_index {
+            index.remove(inode);
         }
+        self.file_checksums.lock().unwrap().remove(&inode);
     }
 
     pub fn lookup<'a>(
@@ -43,6 +211,41 @@ impl FileStorage {
         }
     }
 
+    pub fn resolve(
+        &self,
+        directory: u64,
+        name: &str,
+        context: UserContext,
+    ) -> Result<(u64, FileKind), ErrorCode> {
+        self.metadata_storage.resolve(directory, name, context)
+    }
+
+    // Returns the target of a symlink
+    pub fn read_symlink(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+        let attributes = self
+            .metadata_storage
+            .read(inode, context)
+            .and_then(|_| self.metadata_storage.get_attributes(inode));
+        match attributes {
+            Err(error_code) => Either::A(err(error_code)),
+            Ok(ref attributes) if attributes.kind != FileKind::Symlink => {
+                Either::A(err(ErrorCode::InvalidArgument))
+            }
+            Ok(ref attributes) if attributes.size > MAX_PATH_LENGTH as u64 => {
+                Either::A(err(ErrorCode::NameTooLong))
+            }
+            Ok(attributes) => Either::B(
+                self.data_storage
+                    .read(inode, 0, attributes.size as u32, attributes.redundancy)
+                    .map(|data| data.bytes().to_vec()),
+            ),
+        }
+    }
+
     pub fn truncate<'a>(
         &self,
         inode: u64,
@@ -51,7 +254,11 @@ impl FileStorage {
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
         self.metadata_storage.truncate(inode, new_length, context)?;
-        self.data_storage.truncate(inode, new_length).unwrap();
+        let redundancy = self.metadata_storage.get_redundancy(inode)?;
+        self.data_storage
+            .truncate(inode, new_length, redundancy)
+            .map_err(into_error_code)?;
+        self.contents_changed(inode);
 
         return empty_response(builder);
     }
@@ -77,10 +284,27 @@ impl FileStorage {
     pub fn readdir<'a>(
         &self,
         inode: u64,
+        since_version: Option<u64>,
         mut builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
+        let delta = match since_version {
+            Some(version) => self.metadata_storage.readdir_since(inode, version)?,
+            None => None,
+        };
+        let (version, listing, removed) = match delta {
+            Some(delta) => (delta.version, delta.added, Some(delta.removed)),
+            None => {
+                let (version, listing) = self.metadata_storage.readdir(inode)?;
+                (version, listing, None)
+            }
+        };
+        let removed = removed.map(|names| {
+            let names: Vec<_> = names.iter().map(|x| builder.create_string(x)).collect();
+            builder.create_vector(&names)
+        });
+
         let mut entries = vec![];
-        for (inode, filename, file_type) in self.metadata_storage.readdir(inode)? {
+        for (inode, filename, file_type) in listing {
             let name = builder.create_string(&filename);
             let directory_entry = DirectoryEntry::create(
                 &mut builder,
@@ -99,14 +323,797 @@ impl FileStorage {
         let entries = builder.end_vector(entries.len());
         let mut response_builder = DirectoryListingResponseBuilder::new(&mut builder);
         response_builder.add_entries(entries);
+        response_builder.add_version(version);
+        if let Some(removed) = removed {
+            response_builder.add_delta(true);
+            response_builder.add_removed(removed);
+        }
 
         let offset = response_builder.finish().as_union_value();
         return Ok((builder, ResponseType::DirectoryListingResponse, offset));
     }
 
+    pub fn find<'a>(
+        &self,
+        inode: u64,
+        query: &FindQuery,
+        start_after: Option<&str>,
+        max_results: usize,
+        context: UserContext,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let mut matches = self.metadata_storage.find(inode, query, context)?;
+        if let Some(start_after) = start_after {
+            matches.retain(|(path, _)| path.as_str() > start_after);
+        }
+        let truncated = matches.len() > max_results;
+        matches.truncate(max_results);
+
+        return to_find_response(builder, matches, truncated);
+    }
+
+    pub fn glob<'a>(
+        &self,
+        pattern: &str,
+        start_after: Option<&str>,
+        max_results: usize,
+        context: UserContext,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let mut matches = self.metadata_storage.glob(pattern, context)?;
+        if let Some(start_after) = start_after {
+            matches.retain(|(path, _)| path.as_str() > start_after);
+        }
+        let truncated = matches.len() > max_results;
+        matches.truncate(max_results);
+
+        let mut entries = vec![];
+        for (path, inode) in matches {
+            let attributes = self.metadata_storage.get_attributes(inode)?;
+            let directory_version = self.metadata_storage.directory_version(inode)?;
+            let attributes = create_fileattr(&mut builder, &attributes, directory_version);
+            let path = builder.create_string(&path);
+            entries.push(FindEntry::create(
+                &mut builder,
+                &FindEntryArgs {
+                    inode,
+                    path: Some(path),
+                    attributes: Some(attributes),
+                },
+            ));
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = FindResponseBuilder::new(&mut builder);
+        response_builder.add_entries(entries);
+        response_builder.add_truncated(truncated);
+        let offset = response_builder.finish().as_union_value();
+        return Ok((builder, ResponseType::FindResponse, offset));
+    }
+
+    // Returns a page of a tar archive of the entries below inode, which starts resume_offset bytes after the
+    // start of the entry at resume_path, or of the archive. Each page is assembled from the current state of the
+    // subtree, so it should be frozen while it's exported
+    #[allow(clippy::too_many_arguments)]
+    pub fn export_archive(
+        &self,
+        inode: u64,
+        resume_path: Option<&str>,
+        resume_offset: u64,
+        max_bytes: u32,
+        context: UserContext,
+        mut builder: FlatBufferBuilder<'static>,
+    ) -> impl Future<Item = FlatBufferResponse<'static>, Error = ErrorCode> {
+        let max_bytes = if max_bytes == 0 {
+            MAX_ARCHIVE_PAGE_BYTES
+        } else {
+            min(max_bytes, MAX_ARCHIVE_PAGE_BYTES)
+        };
+        let (parts, resume) = match self.plan_archive_page(
+            inode,
+            resume_path,
+            resume_offset,
+            u64::from(max_bytes),
+            context,
+        ) {
+            Ok(page) => page,
+            Err(error_code) => return Either::A(err(error_code)),
+        };
+
+        let data_storage = self.data_storage.clone();
+        let data = loop_fn(
+            (parts.into_iter(), vec![]),
+            move |(mut parts, mut data)| match parts.next() {
+                None => Either::A(ok(Loop::Break(data))),
+                Some((record, start, length)) => Either::B(
+                    read_archive_part(&data_storage, record, start, length).map(move |part| {
+                        data.extend(part);
+                        Loop::Continue((parts, data))
+                    }),
+                ),
+            },
+        );
+        Either::B(data.map(move |data| {
+            let data = builder.create_vector_direct(&data);
+            let resume_path = resume
+                .as_ref()
+                .and_then(|(path, _)| path.as_ref())
+                .map(|path| builder.create_string(path));
+            let mut response_builder = ExportArchiveResponseBuilder::new(&mut builder);
+            response_builder.add_data(data);
+            if let Some(resume_path) = resume_path {
+                response_builder.add_resume_path(resume_path);
+            }
+            response_builder.add_resume_offset(resume.as_ref().map_or(0, |(_, offset)| *offset));
+            response_builder.add_done(resume.is_none());
+            let offset = response_builder.finish().as_union_value();
+            (builder, ResponseType::ExportArchiveResponse, offset)
+        }))
+    }
+
+    // Returns the parts of the records in a page of an archive, as (record, offset in the record, length), and
+    // where the next page starts, or None if it's the last page
+    #[allow(clippy::type_complexity)]
+    fn plan_archive_page(
+        &self,
+        inode: u64,
+        resume_path: Option<&str>,
+        resume_offset: u64,
+        max_bytes: u64,
+        context: UserContext,
+    ) -> Result<
+        (
+            Vec<(ArchiveRecord, u64, u64)>,
+            Option<(Option<String>, u64)>,
+        ),
+        ErrorCode,
+    > {
+        let entries = self
+            .metadata_storage
+            .find(inode, &FindQuery::default(), context)?;
+        let start = match resume_path {
+            // The entry was removed, so the offset can't be resumed from
+            Some(resume_path) => entries
+                .iter()
+                .position(|(path, _)| path.trim_start_matches('/') == resume_path)
+                .ok_or(ErrorCode::VersionMismatch)?,
+            None => 0,
+        };
+
+        let mut records = vec![];
+        // The first entry of each file with several links. The others are archived as hard links to it
+        let mut first_paths: HashMap<u64, String> = HashMap::new();
+        for (i, (path, entry_inode)) in entries.into_iter().enumerate() {
+            let path = path.trim_start_matches('/').to_string();
+            let attributes = self.metadata_storage.get_attributes(entry_inode)?;
+            let mut hardlink_target = None;
+            if attributes.kind != FileKind::Directory && attributes.hardlinks > 1 {
+                match first_paths.get(&entry_inode) {
+                    Some(first_path) => hardlink_target = Some(first_path.clone()),
+                    None => {
+                        first_paths.insert(entry_inode, path.clone());
+                    }
+                }
+            }
+            if i >= start {
+                records.push(ArchiveRecord::Entry {
+                    path,
+                    attributes,
+                    hardlink_target,
+                });
+            }
+        }
+        records.push(ArchiveRecord::End);
+
+        let mut parts = vec![];
+        let mut budget = max_bytes;
+        // Offset of the page in the current record
+        let mut skip = resume_offset;
+        // Offset of the current record from the start of the latest entry, which the next page is resumed from
+        let mut anchor = resume_path.map(ToString::to_string);
+        let mut anchor_offset = 0;
+        for record in records {
+            let length = record.length();
+            if let ArchiveRecord::Entry { ref path, .. } = record {
+                anchor = Some(path.clone());
+                anchor_offset = 0;
+            }
+            if skip >= length {
+                skip -= length;
+                anchor_offset += length;
+                continue;
+            }
+            if budget == 0 {
+                return Ok((parts, Some((anchor, anchor_offset + skip))));
+            }
+            if let ArchiveRecord::Entry { ref attributes, .. } = record {
+                if record.data_length() > 0 {
+                    self.metadata_storage.read(attributes.inode, context)?;
+                }
+            }
+
+            let part_length = min(length - skip, budget);
+            let end = skip + part_length;
+            budget -= part_length;
+            parts.push((record, skip, part_length));
+            if end < length {
+                return Ok((parts, Some((anchor, anchor_offset + end))));
+            }
+            skip = 0;
+            anchor_offset += length;
+        }
+
+        Ok((parts, None))
+    }
+
+    // The index only returns candidates, so each one is read to check that it really contains the query
+    pub fn search<'a>(
+        &self,
+        query: &str,
+        max_results: usize,
+        context: UserContext,
+        builder: FlatBufferBuilder<'a>,
+    ) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+        let index = match self.content_index {
+            Some(ref index) => index,
+            None => return Either::A(err(ErrorCode::NotSupported)),
+        };
+        if query.len() < 3 {
+            return Either::A(err(ErrorCode::InvalidArgument));
+        }
+
+        let mut candidates = vec![];
+        for inode in index.search(query.as_bytes()) {
+            if self.metadata_storage.read(inode, context).is_err() {
+                continue;
+            }
+            let attributes = match self.metadata_storage.get_attributes(inode) {
+                Ok(attributes) => attributes,
+                Err(_) => continue,
+            };
+            if attributes.kind != FileKind::File || attributes.size > MAX_INDEXED_FILE_SIZE {
+                continue;
+            }
+            // Paths under directories which the caller can't search must not be revealed
+            let path = match self.metadata_storage.searchable_paths_of(inode, context) {
+                Ok(paths) if !paths.is_empty() => paths[0].clone(),
+                _ => continue,
+            };
+            candidates.push((path, inode, attributes.size, attributes.redundancy));
+        }
+
+        let data_storage = self.data_storage.clone();
+        let query = query.as_bytes().to_vec();
+        let matches = iter_ok(candidates)
+            .and_then(move |(path, inode, size, redundancy)| {
+                let query = query.clone();
+                data_storage
+                    .read(inode, 0, size as u32, redundancy)
+                    .then(move |result| {
+                        let found = match result {
+                            Ok(contents) => contents
+                                .bytes()
+                                .windows(query.len())
+                                .any(|x| x == &query[..]),
+                            Err(error_code) => {
+                                warn!(
+                                    "Failed to read search candidate {}: {:?}",
+                                    inode, error_code
+                                );
+                                false
+                            }
+                        };
+                        Ok(if found { Some((path, inode)) } else { None })
+                    })
+            })
+            .filter_map(|x| x)
+            // One extra match, to detect truncation
+            .take(max_results as u64 + 1)
+            .collect();
+
+        Either::B(matches.and_then(move |mut matches| {
+            let truncated = matches.len() > max_results;
+            matches.truncate(max_results);
+            return to_find_response(builder, matches, truncated);
+        }))
+    }
+
+    // The caller must ensure that no requests are applied while the snapshot is written
+    pub fn write_snapshot(
+        &self,
+        data_dir: &str,
+        index: u64,
+        term: u64,
+        sessions: &[(u64, AppliedSession)],
+        checksum_algorithm: ChecksumAlgorithm,
+    ) -> Result<SnapshotInfo, ErrorCode> {
+        let metadata = self.metadata_storage.snapshot()?;
+        let mut writer = SnapshotWriter::new(
+            &snapshot_directory(data_dir),
+            index,
+            term,
+            &metadata,
+            sessions,
+            checksum_algorithm,
+        )
+        .map_err(into_error_code)?;
+        // Striped files are stored without redundancy, so the blocks of the joining node are only on that node, and
+        // only mirrored files can be shipped
+        for (inode, redundancy) in self.metadata_storage.file_redundancies()? {
+            if !self.data_storage.is_mirrored(redundancy) {
+                continue;
+            }
+            if let Some((length, mut contents)) = self
+                .data_storage
+                .open_local(inode)
+                .map_err(into_error_code)?
+            {
+                writer
+                    .add_file(inode, length, &mut contents)
+                    .map_err(into_error_code)?;
+            }
+        }
+
+        return writer.finish().map_err(into_error_code);
+    }
+
+    // Called on a node which joined from a snapshot, before it serves clients. Copies the mirrored files which the
+    // snapshot didn't include from the peers, and reports the striped files whose blocks on this node were lost.
+    // Returns the number of files whose local data couldn't be restored
+    pub fn restore_local_data(&self, peers: &[SocketAddr]) -> Result<usize, ErrorCode> {
+        let mut lost = 0;
+        for (inode, redundancy) in self.metadata_storage.file_redundancies()? {
+            let size = self.metadata_storage.get_attributes(inode)?.size;
+            let expected = self.data_storage.local_length(size, redundancy);
+            let stored = self
+                .data_storage
+                .open_local(inode)
+                .map_err(into_error_code)?
+                .map_or(0, |(length, _)| length);
+            if stored >= expected {
+                continue;
+            }
+            if self.data_storage.is_mirrored(redundancy)
+                && peers
+                    .iter()
+                    .any(|peer| self.copy_from_peer(*peer, inode, size, redundancy).is_ok())
+            {
+                continue;
+            }
+            error!(
+                "Lost the local data of inode {}. {} of {} bytes are stored",
+                inode, stored, expected
+            );
+            lost += 1;
+        }
+
+        return Ok(lost);
+    }
+
+    fn copy_from_peer(
+        &self,
+        peer: SocketAddr,
+        inode: u64,
+        size: u64,
+        redundancy: u8,
+    ) -> Result<(), ErrorCode> {
+        let client = NodeClient::new(peer);
+        let mut offset = 0;
+        while offset < size {
+            let mut chunk = Err(ErrorCode::Uncategorized);
+            client.read(
+                inode,
+                offset,
+                min(RESTORE_CHUNK_SIZE, size - offset) as u32,
+                UserContext::new(0, 0),
+                AtimeMode::NoAtime,
+                |data| chunk = data.map(<[u8]>::to_vec),
+            );
+            let chunk = chunk?;
+            // The rest of the file is a hole
+            if chunk.is_empty() {
+                break;
+            }
+            self.data_storage
+                .write_local_blocks(inode, offset, &chunk, redundancy)
+                .map_err(into_error_code)?;
+            offset += chunk.len() as u64;
+        }
+
+        return Ok(());
+    }
+
+    pub fn run_maintenance(&self) {
+        self.staged_data.expire();
+        if let Err(error) = self.data_storage.run_maintenance() {
+            warn!("Local storage maintenance failed: {:?}", error);
+        }
+        if let Err(error) = self.access_stats.persist() {
+            warn!("Failed to persist access statistics: {:?}", error);
+        }
+    }
+
+    // Re-reads and indexes the contents of every file which changed since it was last indexed
+    pub fn reindex_content(&self) -> impl Future<Item = (), Error = ()> {
+        let index = if let Some(ref index) = self.content_index {
+            index.clone()
+        } else {
+            return Either::A(ok(()));
+        };
+        let scheduler = self.data_storage.scheduler();
+        if !scheduler.admit(BackgroundTask::Reindex) {
+            return Either::A(ok(()));
+        }
+
+        let mut reads = vec![];
+        let mut read_bytes = 0;
+        for inode in index.take_dirty() {
+            let attributes = match self.metadata_storage.get_attributes(inode) {
+                Ok(attributes) => attributes,
+                Err(_) => {
+                    // Deleted since it was marked dirty
+                    index.remove(inode);
+                    continue;
+                }
+            };
+            if attributes.kind != FileKind::File || attributes.size > MAX_INDEXED_FILE_SIZE {
+                index.remove(inode);
+                continue;
+            }
+
+            read_bytes += attributes.size;
+            let index = index.clone();
+            let read = self
+                .data_storage
+                .read(inode, 0, attributes.size as u32, attributes.redundancy)
+                .then(move |result| {
+                    match result {
+                        Ok(contents) => index.update(inode, contents.bytes()),
+                        Err(error_code) => {
+                            warn!("Failed to index {}: {:?}", inode, error_code);
+                        }
+                    }
+                    Ok(())
+                });
+            reads.push(read);
+        }
+
+        scheduler.charge(BackgroundTask::Reindex, read_bytes);
+
+        Either::B(join_all(reads).map(|_| ()))
+    }
+
+    pub fn block_cache_stats<'a>(
+        &self,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> FlatBufferResponse<'a> {
+        let stats = self.data_storage.block_cache_stats().unwrap_or_default();
+        let mut response_builder = BlockCacheStatsResponseBuilder::new(&mut builder);
+        response_builder.add_capacity_bytes(stats.capacity_bytes);
+        response_builder.add_cached_bytes(stats.cached_bytes);
+        response_builder.add_hits(stats.hits);
+        response_builder.add_misses(stats.misses);
+        response_builder.add_rejected(stats.rejected);
+        response_builder.add_evictions(stats.evictions);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (
+            builder,
+            ResponseType::BlockCacheStatsResponse,
+            response_offset,
+        );
+    }
+
+    // Pauses or resumes the background tasks of this node, and returns their status
+    pub fn background_control<'a>(
+        &self,
+        action: BackgroundAction,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> FlatBufferResponse<'a> {
+        let scheduler = self.data_storage.scheduler();
+        match action {
+            BackgroundAction::Pause => scheduler.set_paused(true),
+            BackgroundAction::Resume => scheduler.set_paused(false),
+            BackgroundAction::Status => {}
+        }
+
+        let mut tasks = vec![];
+        for (task, stats) in scheduler.stats() {
+            let name = builder.create_string(task.name());
+            let mut task_builder = BackgroundTaskStatusBuilder::new(&mut builder);
+            task_builder.add_name(name);
+            task_builder.add_runs(stats.runs);
+            task_builder.add_bytes(stats.bytes);
+            task_builder.add_deferred(stats.deferred);
+            tasks.push(task_builder.finish());
+        }
+        let tasks = builder.create_vector(&tasks);
+        let mut response_builder = BackgroundStatusResponseBuilder::new(&mut builder);
+        response_builder.add_paused(scheduler.is_paused());
+        response_builder.add_bytes_per_second(scheduler.bytes_per_second());
+        response_builder.add_tasks(tasks);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return (
+            builder,
+            ResponseType::BackgroundStatusResponse,
+            response_offset,
+        );
+    }
+
+    pub fn get_tree_usage<'a>(
+        &self,
+        inode: u64,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let usage = self.metadata_storage.get_tree_usage(inode)?;
+        let mut response_builder = TreeUsageResponseBuilder::new(&mut builder);
+        response_builder.add_bytes(usage.bytes);
+        response_builder.add_inodes(usage.inodes);
+        let offset = response_builder.finish().as_union_value();
+        return Ok((builder, ResponseType::TreeUsageResponse, offset));
+    }
+
+    pub fn filesystem_information<'a>(
+        &self,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let space = self.data_storage.cluster_space().map_err(into_error_code)?;
+        let usage = self.metadata_storage.get_tree_usage(ROOT_INODE)?;
+        let mut response_builder = FilesystemInformationResponseBuilder::new(&mut builder);
+        response_builder.add_block_size(space.block_size as u32);
+        response_builder.add_total_blocks(space.total_blocks);
+        response_builder.add_free_blocks(space.free_blocks);
+        response_builder.add_available_blocks(space.available_blocks);
+        response_builder.add_reserved_blocks(space.reserved_blocks);
+        // The root directory isn't counted in its own usage
+        response_builder.add_files(usage.inodes + 1);
+        let limits = self.metadata_storage.limits();
+        let free_inodes = limits.max_inodes().saturating_sub(usage.inodes + 1);
+        response_builder.add_free_files(min(space.free_files, free_inodes));
+        response_builder.add_limits(&limits);
+        let offset = response_builder.finish().as_union_value();
+        return Ok((builder, ResponseType::FilesystemInformationResponse, offset));
+    }
+
+    pub fn disk_space(&self) -> io::Result<DiskSpace> {
+        self.data_storage.disk_space()
+    }
+
+    // All zero, if the block cache is disabled
+    pub fn local_cache_stats(&self) -> BlockCacheStats {
+        self.data_storage.block_cache_stats().unwrap_or_default()
+    }
+
+    pub fn refresh_node_space(&self) -> impl Future<Item = (), Error = ()> {
+        self.data_storage.refresh_node_space()
+    }
+
+    // Whether writes by uid to inode, of (offset, length), fit in the space which isn't reserved for root, on
+    // every node which stores a part of them. Appends have no offset, and are written at the end of the file.
+    // Root may use the reserved space, and its writes only fail once the disk is full
+    pub fn has_space_for_write(&self, uid: u32, inode: u64, writes: &[(Option<u64>, u64)]) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        // Let the write report the error, if there is one
+        let attributes = match self.metadata_storage.get_attributes(inode) {
+            Ok(attributes) => attributes,
+            Err(_) => return true,
+        };
+
+        let mut required: HashMap<u64, u64> = HashMap::new();
+        for (offset, length) in writes.iter() {
+            let offset = offset.unwrap_or(attributes.size);
+            for (node_id, bytes) in
+                self.data_storage
+                    .placement(offset, *length, attributes.redundancy)
+            {
+                *required.entry(node_id).or_insert(0) += bytes;
+            }
+        }
+        // Nodes whose space isn't known yet are assumed to have enough
+        required.iter().all(|(node_id, bytes)| {
+            self.data_storage
+                .available_bytes(*node_id)
+                .map_or(true, |available| available >= *bytes)
+        })
+    }
+
     pub fn getattr<'a>(&self, inode: u64, builder: FlatBufferBuilder<'a>) -> ResultResponse<'a> {
         let attributes = self.metadata_storage.get_attributes(inode)?;
-        return to_fileattr_response(builder, attributes);
+        let directory_version = self.metadata_storage.directory_version(inode)?;
+        return to_fileattr_response(builder, attributes, directory_version);
+    }
+
+    pub fn batch_getattr<'a>(
+        &self,
+        inodes: &[u64],
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        if inodes.len() > MAX_BATCH_GETATTR_INODES {
+            return Err(ErrorCode::BadRequest);
+        }
+
+        let mut entries = vec![];
+        for inode in inodes.iter() {
+            let attributes = self.metadata_storage.get_attributes(*inode).and_then(|x| {
+                let directory_version = self.metadata_storage.directory_version(*inode)?;
+                Ok((x, directory_version))
+            });
+            let entry = match attributes {
+                Ok((attributes, directory_version)) => {
+                    let attributes = create_fileattr(&mut builder, &attributes, directory_version);
+                    BatchGetattrEntry::create(
+                        &mut builder,
+                        &BatchGetattrEntryArgs {
+                            attributes: Some(attributes),
+                            error_code: ErrorCode::DefaultValueNotAnError,
+                        },
+                    )
+                }
+                Err(error_code) => BatchGetattrEntry::create(
+                    &mut builder,
+                    &BatchGetattrEntryArgs {
+                        attributes: None,
+                        error_code,
+                    },
+                ),
+            };
+            entries.push(entry);
+        }
+        let entries = builder.create_vector(&entries);
+        let mut response_builder = BatchGetattrResponseBuilder::new(&mut builder);
+        response_builder.add_entries(entries);
+        let offset = response_builder.finish().as_union_value();
+        return Ok((builder, ResponseType::BatchGetattrResponse, offset));
+    }
+
+    // Applies a committed operation. This is the only way the filesystem is changed, and it's deterministic, so
+    // that every node ends up in the same state. time is the time assigned to it by the node which proposed it
+    pub fn apply<'a>(
+        &self,
+        operation: &Operation,
+        time: Timestamp,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        operation.validate()?;
+        self.metadata_storage.set_operation_time(time);
+        self.metadata_storage
+            .check_not_frozen(&operation.changed_inodes())?;
+
+        match *operation {
+            Operation::Hardlink {
+                inode,
+                new_parent,
+                new_name,
+                context,
+            } => self.hardlink(inode, new_parent, new_name, context, builder),
+            Operation::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                context,
+            } => self.rename(parent, name, new_parent, new_name, context, builder),
+            Operation::Chmod {
+                inode,
+                mode,
+                context,
+            } => self.chmod(inode, mode, context, builder),
+            Operation::Chown {
+                inode,
+                uid,
+                gid,
+                context,
+            } => self.chown(inode, uid, gid, context, builder),
+            Operation::Truncate {
+                inode,
+                new_length,
+                context,
+            } => self.truncate(inode, new_length, context, builder),
+            Operation::Fsync { inode } => self.fsync(inode, builder),
+            Operation::Create {
+                parent,
+                name,
+                uid,
+                gid,
+                mode,
+                kind,
+            } => self.create(parent, name, uid, gid, mode, kind, builder),
+            Operation::CreateTemporary {
+                parent,
+                uid,
+                gid,
+                mode,
+                session_id,
+            } => self.create_temporary(parent, uid, gid, mode, session_id, builder),
+            Operation::OpenTemporary { inode, session_id } => {
+                self.open_temporary(inode, session_id, builder)
+            }
+            Operation::SetXattr {
+                inode,
+                key,
+                value,
+                context,
+            } => self.set_xattr(inode, key, value, context, builder),
+            Operation::RemoveXattr { inode, key } => self.remove_xattr(inode, key, builder),
+            Operation::Unlink {
+                parent,
+                name,
+                context,
+            } => self.unlink(parent, name, context, builder),
+            Operation::Rmdir {
+                parent,
+                name,
+                context,
+            } => self.rmdir(parent, name, context, builder),
+            Operation::Write {
+                inode,
+                offset,
+                data,
+                context,
+                fencing_token,
+            } => {
+                self.metadata_storage
+                    .advance_fencing_token(inode, fencing_token)?;
+                self.write(inode, offset, data, context, builder)
+            }
+            Operation::WriteConditional {
+                inode,
+                offset,
+                data,
+                expected_version,
+                context,
+            } => self.write_conditional(inode, offset, data, expected_version, context, builder),
+            Operation::Append {
+                inode,
+                data,
+                context,
+            } => self.append(inode, data, context, builder),
+            Operation::WritePatch {
+                inode,
+                ref patches,
+                context,
+            } => self.write_patch(inode, patches, context, builder),
+            Operation::Utimens {
+                inode,
+                atime,
+                mtime,
+                context,
+            } => self.utimens(inode, atime, mtime, context, builder),
+            Operation::Mkdir {
+                parent,
+                name,
+                uid,
+                gid,
+                mode,
+            } => self.mkdir(parent, name, uid, gid, mode, builder),
+            Operation::StagedWrite {
+                inode,
+                offset,
+                staged_id,
+                context,
+                fencing_token,
+                ..
+            } => {
+                self.metadata_storage
+                    .advance_fencing_token(inode, fencing_token)?;
+                self.write_staged(inode, offset, staged_id, context, builder)
+            }
+            Operation::UpdateAtime { ref inodes, atime } => {
+                self.update_atime(inodes, atime, builder)
+            }
+            Operation::Release { inode, session_id } => self.release(inode, session_id, builder),
+            Operation::FilesystemRepair => self.repair_metadata(builder),
+            Operation::SetLimits { limits } => self.set_limits(limits, builder),
+            Operation::Freeze {
+                inode,
+                timeout_seconds,
+            } => self.freeze(inode, timeout_seconds, builder),
+            Operation::Thaw { inode } => self.thaw(inode, builder),
+            Operation::AllocateClientId => self.allocate_client_id(builder),
+            Operation::EndSession { session_id } => self.end_session(session_id, builder),
+        }
     }
 
     pub fn utimens<'a>(
@@ -117,7 +1124,6 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
-        assert_ne!(inode, ROOT_INODE);
         self.metadata_storage
             .utimens(inode, atime, mtime, context)?;
         return empty_response(builder);
@@ -130,7 +1136,6 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
-        assert_ne!(inode, ROOT_INODE);
         if let Err(error_code) = self.metadata_storage.chmod(inode, mode, context) {
             return Err(error_code);
         } else {
@@ -146,7 +1151,6 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
-        assert_ne!(inode, ROOT_INODE);
         if let Err(error_code) = self.metadata_storage.chown(inode, uid, gid, context) {
             return Err(error_code);
         } else {
@@ -154,6 +1158,10 @@ impl FileStorage {
         }
     }
 
+    pub fn sync_all(&self) -> Result<(), ErrorCode> {
+        self.data_storage.sync_all()
+    }
+
     pub fn fsync<'a>(&self, inode: u64, builder: FlatBufferBuilder<'a>) -> ResultResponse<'a> {
         if let Err(error_code) = self.data_storage.fsync(inode) {
             return Err(error_code);
@@ -170,16 +1178,97 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'static>,
     ) -> impl Future<Item = FlatBufferWithResponse<'static>, Error = ErrorCode> {
-        if let Err(error_code) = self.metadata_storage.read(inode, context) {
-            Either::A(ok(to_fast_read_response(builder, Err(error_code))))
-        } else {
-            let read_result = self.data_storage.read(inode, offset, read_size);
-            Either::B(
-                read_result.then(move |response| Ok(to_fast_read_response(builder, response))),
-            )
+        let redundancy = self
+            .metadata_storage
+            .read(inode, context)
+            .and_then(|_| self.metadata_storage.get_redundancy(inode));
+        match redundancy {
+            Err(error_code) => Either::A(ok(to_fast_read_response(builder, Err(error_code)))),
+            Ok(redundancy) => {
+                self.access_stats.record_read(inode, u64::from(read_size));
+                let read_result = self.data_storage.read(inode, offset, read_size, redundancy);
+                Either::B(
+                    read_result.then(move |response| Ok(to_fast_read_response(builder, response))),
+                )
+            }
         }
     }
 
+    pub fn block_hashes(
+        &self,
+        inode: u64,
+        block_size: u32,
+        start_block: u64,
+        algorithm: ChecksumAlgorithm,
+        context: UserContext,
+        mut builder: FlatBufferBuilder<'static>,
+    ) -> impl Future<Item = FlatBufferResponse<'static>, Error = ErrorCode> {
+        let block_size = u64::from(block_size);
+        if block_size == 0 || block_size > MAX_HASHED_BYTES {
+            return Either::A(err(ErrorCode::BadRequest));
+        }
+        let attributes = match self
+            .metadata_storage
+            .read(inode, context)
+            .and_then(|_| self.metadata_storage.get_attributes(inode))
+        {
+            Ok(attributes) => attributes,
+            Err(error_code) => return Either::A(err(error_code)),
+        };
+
+        let offset = start_block.saturating_mul(block_size);
+        let length = min(
+            attributes.size.saturating_sub(offset),
+            MAX_HASHED_BYTES / block_size * block_size,
+        );
+        let file_size = attributes.size;
+        let read_result =
+            self.data_storage
+                .read(inode, offset, length as u32, attributes.redundancy);
+        Either::B(read_result.map(move |data| {
+            let mut hashes = vec![];
+            for block in data.bytes().chunks(block_size as usize) {
+                let strong = builder.create_vector_direct(&digest(algorithm, block));
+                hashes.push(BlockHash::create(
+                    &mut builder,
+                    &BlockHashArgs {
+                        rolling: rolling_checksum(block),
+                        strong: Some(strong),
+                    },
+                ));
+            }
+            let hashes = builder.create_vector(&hashes);
+            let mut response_builder = FileBlockHashesResponseBuilder::new(&mut builder);
+            response_builder.add_file_size(file_size);
+            response_builder.add_hashes(hashes);
+            let offset = response_builder.finish().as_union_value();
+            (builder, ResponseType::FileBlockHashesResponse, offset)
+        }))
+    }
+
+    pub fn needs_atime_update(&self, inode: u64) -> bool {
+        return self
+            .metadata_storage
+            .needs_atime_update(inode)
+            .unwrap_or(false);
+    }
+
+    pub fn update_atime<'a>(
+        &self,
+        inodes: &[u64],
+        atime: Timestamp,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        for inode in inodes {
+            match self.metadata_storage.update_atime(*inode, atime) {
+                // The inode may have been deleted after it was read
+                Ok(_) | Err(ErrorCode::InodeDoesNotExist) => {}
+                Err(error_code) => return Err(error_code),
+            }
+        }
+        return empty_response(builder);
+    }
+
     pub fn read_raw<'a>(
         &self,
         inode: u64,
@@ -208,7 +1297,12 @@ impl FileStorage {
         {
             return Err(error_code);
         } else {
-            let write_result = self.data_storage.write_local_blocks(inode, offset, data);
+            let redundancy = self.metadata_storage.get_redundancy(inode)?;
+            let write_result = self
+                .data_storage
+                .write_local_blocks(inode, offset, data, redundancy);
+            self.contents_changed(inode);
+            self.access_stats.record_write(inode, data.len() as u64);
             // Reply with the total requested write size, since that's what the FUSE client is expecting, even though this node only wrote some of the bytes
             let total_bytes = data.len() as u32;
             return write_result
@@ -217,6 +1311,105 @@ impl FileStorage {
         }
     }
 
+    // The version is checked when the write is applied, so every node makes the same decision
+    pub fn write_conditional<'a>(
+        &self,
+        inode: u64,
+        offset: u64,
+        data: &[u8],
+        expected_version: u64,
+        context: UserContext,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let attributes = self.metadata_storage.get_attributes(inode)?;
+        if attributes.data_version != expected_version {
+            return Err(ErrorCode::VersionMismatch);
+        }
+        return self.write(inode, offset, data, context, builder);
+    }
+
+    // The offset is the size of the file when the append is applied, so it's the same on every node. Appends to
+    // shared logs are stored as records
+    pub fn append<'a>(
+        &self,
+        inode: u64,
+        data: &[u8],
+        context: UserContext,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let record;
+        let data = if self.metadata_storage.get_attributes(inode)?.kind == FileKind::SharedLog {
+            record = frame_log_record(data);
+            &record
+        } else {
+            data
+        };
+        let offset = self
+            .metadata_storage
+            .append(inode, data.len() as u32, context)?;
+        let redundancy = self.metadata_storage.get_redundancy(inode)?;
+        self.data_storage
+            .write_local_blocks(inode, offset, data, redundancy)
+            .map_err(into_error_code)?;
+        self.contents_changed(inode);
+        self.access_stats.record_write(inode, data.len() as u64);
+
+        let mut response_builder = WrittenResponseBuilder::new(&mut builder);
+        response_builder.add_bytes_written(data.len() as u32);
+        response_builder.add_offset(offset);
+        let response_offset = response_builder.finish().as_union_value();
+        return Ok((builder, ResponseType::WrittenResponse, response_offset));
+    }
+
+    // Stops at the first patch which fails. Since patches are applied through Raft, every node stops at the same one
+    pub fn write_patch<'a>(
+        &self,
+        inode: u64,
+        patches: &[(u64, &[u8])],
+        context: UserContext,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let redundancy = self.metadata_storage.get_redundancy(inode)?;
+        let mut total_bytes = 0;
+        for (offset, data) in patches {
+            self.metadata_storage
+                .write(inode, *offset, data.len() as u32, context)?;
+            self.data_storage
+                .write_local_blocks(inode, *offset, data, redundancy)
+                .map_err(into_error_code)?;
+            total_bytes += data.len() as u32;
+        }
+        self.contents_changed(inode);
+        self.access_stats
+            .record_write(inode, u64::from(total_bytes));
+
+        return to_write_response(builder, total_bytes);
+    }
+
+    pub fn staged_data(&self) -> &StagedData {
+        &self.staged_data
+    }
+
+    pub fn write_staged<'a>(
+        &self,
+        inode: u64,
+        offset: u64,
+        staged_id: u64,
+        context: UserContext,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        if let Some(data) = self.staged_data.take_for_apply(staged_id) {
+            return self.write(inode, offset, &data, context, builder);
+        }
+
+        // The data is fetched before the write is applied, and applying it without the data would make this node
+        // diverge from the others
+        panic!(
+            "Data for staged write {} to inode {} is missing",
+            staged_id, inode
+        );
+    }
+
     pub fn hardlink<'a>(
         &self,
         inode: u64,
@@ -225,7 +1418,6 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
-        assert_ne!(inode, ROOT_INODE);
         info!("Hardlinking file: {} to {} {}", inode, new_parent, new_name);
 
         self.metadata_storage
@@ -239,10 +1431,119 @@ impl FileStorage {
         key: &str,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
+        if key == REDUNDANCY_XATTR {
+            let redundancy = self.metadata_storage.get_redundancy(inode)?;
+            return to_read_response(builder, redundancy.to_string().as_bytes());
+        }
+        if key == RETENTION_XATTR {
+            let (retention, _) = self.metadata_storage.get_retention(inode)?;
+            return to_read_response(builder, retention.to_string().as_bytes());
+        }
+        if key == RETAINED_UNTIL_XATTR {
+            let (_, retained_until) = self.metadata_storage.get_retention(inode)?;
+            return to_read_response(builder, retained_until.to_string().as_bytes());
+        }
+        if key == LINKS_XATTR {
+            let paths = self.metadata_storage.paths_of(inode)?;
+            return to_read_response(builder, paths.join("\n").as_bytes());
+        }
+        if key == DOS_ATTRIBUTES_XATTR {
+            let (dos_attributes, _) = self.metadata_storage.get_dos_attributes(inode)?;
+            return to_read_response(builder, format!("0x{:x}", dos_attributes).as_bytes());
+        }
+        if key == DOS_CREATED_XATTR {
+            let (_, created) = self.metadata_storage.get_dos_attributes(inode)?;
+            return to_read_response(builder, created.seconds().to_string().as_bytes());
+        }
+        if key == REPLICAS_XATTR {
+            let attributes = self.metadata_storage.get_attributes(inode)?;
+            let node_ids: Vec<String> = if attributes.kind == FileKind::Directory {
+                self.data_storage
+                    .node_ids()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect()
+            } else {
+                self.data_storage
+                    .placement(0, attributes.size, attributes.redundancy)
+                    .iter()
+                    .filter(|(_, bytes)| *bytes > 0)
+                    .map(|(node_id, _)| node_id.to_string())
+                    .collect()
+            };
+            return to_read_response(builder, node_ids.join("\n").as_bytes());
+        }
+        if key == USAGE_XATTR {
+            let usage = self.metadata_storage.get_tree_usage(inode)?;
+            return to_read_response(
+                builder,
+                format!("{} {}", usage.bytes, usage.inodes).as_bytes(),
+            );
+        }
         let attr = self.metadata_storage.get_xattr(inode, key)?;
         return to_read_response(builder, &attr);
     }
 
+    // Returns CHECKSUM_XATTR of inode. The digest is cached until the file is modified, so only the first request
+    // after a modification reads the file
+    pub fn get_checksum_xattr<'a>(
+        &self,
+        inode: u64,
+        builder: FlatBufferBuilder<'a>,
+    ) -> impl Future<Item = FlatBufferResponse<'a>, Error = ErrorCode> {
+        let attributes = match self.metadata_storage.get_attributes(inode) {
+            Ok(attributes) => attributes,
+            Err(error_code) => return Either::A(err(error_code)),
+        };
+        if attributes.kind != FileKind::File {
+            return Either::A(err(ErrorCode::MissingXattrKey));
+        }
+        let modified = attributes.last_modified;
+        let size = attributes.size;
+        let cached = self
+            .file_checksums
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .filter(|(cached_modified, cached_size, _)| {
+                *cached_modified == modified && *cached_size == size
+            })
+            .map(|(_, _, digest)| digest.clone());
+        if let Some(digest) = cached {
+            return Either::A(result(to_read_response(
+                builder,
+                to_hex(&digest).as_bytes(),
+            )));
+        }
+
+        let data_storage = self.data_storage.clone();
+        let redundancy = attributes.redundancy;
+        let start = (0, Checksum::new(ChecksumAlgorithm::Sha256));
+        let digest = loop_fn(start, move |(offset, mut checksum)| {
+            if offset >= size {
+                return Either::A(ok(Loop::Break(checksum.finish())));
+            }
+            let length = min(size - offset, CHECKSUM_XATTR_CHUNK_SIZE);
+            Either::B(
+                data_storage
+                    .read(inode, offset, length as u32, redundancy)
+                    .map(move |data| {
+                        checksum.update(data.bytes());
+                        Loop::Continue((offset + length, checksum))
+                    }),
+            )
+        });
+        let file_checksums = self.file_checksums.clone();
+        Either::B(digest.and_then(move |digest| {
+            let hex = to_hex(&digest);
+            file_checksums
+                .lock()
+                .unwrap()
+                .insert(inode, (modified, size, digest));
+            to_read_response(builder, hex.as_bytes())
+        }))
+    }
+
     pub fn list_xattrs<'a>(
         &self,
         inode: u64,
@@ -257,12 +1558,439 @@ impl FileStorage {
         inode: u64,
         key: &str,
         value: &[u8],
+        context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
+        if key == REDUNDANCY_XATTR {
+            let redundancy: u8 = std::str::from_utf8(value)
+                .ok()
+                .and_then(|x| x.trim().parse().ok())
+                .ok_or(ErrorCode::InvalidArgument)?;
+            if !self.data_storage.is_supported_redundancy(redundancy) {
+                return Err(ErrorCode::InvalidXattr);
+            }
+            self.metadata_storage
+                .set_redundancy(inode, redundancy, context)?;
+            return empty_response(builder);
+        }
+        if key == RETENTION_XATTR {
+            let retention: u64 = std::str::from_utf8(value)
+                .ok()
+                .and_then(|x| x.trim().parse().ok())
+                .ok_or(ErrorCode::InvalidArgument)?;
+            self.metadata_storage
+                .set_retention(inode, retention, context)?;
+            return empty_response(builder);
+        }
+        if key == RETAINED_UNTIL_XATTR
+            || key == LINKS_XATTR
+            || key == CHECKSUM_XATTR
+            || key == REPLICAS_XATTR
+            || key == USAGE_XATTR
+        {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
+        if key == DOS_ATTRIBUTES_XATTR {
+            // Accept the value with or without the "0x" prefix
+            let dos_attributes = std::str::from_utf8(value)
+                .ok()
+                .map(|x| x.trim().trim_start_matches("0x"))
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or(ErrorCode::InvalidArgument)?;
+            self.metadata_storage
+                .set_dos_attributes(inode, dos_attributes)?;
+            return empty_response(builder);
+        }
+        if key == DOS_CREATED_XATTR {
+            let created: i64 = std::str::from_utf8(value)
+                .ok()
+                .and_then(|x| x.trim().parse().ok())
+                .ok_or(ErrorCode::InvalidArgument)?;
+            self.metadata_storage
+                .set_created(inode, Timestamp::new(created, 0))?;
+            return empty_response(builder);
+        }
         self.metadata_storage.set_xattr(inode, key, value)?;
         return empty_response(builder);
     }
 
+    // Checksums the data stored on this node in a separate thread, since it reads every file which changed since
+    // the last checksum
+    pub fn data_checksum(
+        &self,
+        data_dir: &str,
+        algorithm: ChecksumAlgorithm,
+    ) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+        let mut mirrored = HashSet::new();
+        match self.metadata_storage.file_redundancies() {
+            Ok(redundancies) => {
+                for (inode, redundancy) in redundancies {
+                    if self.data_storage.is_mirrored(redundancy) {
+                        mirrored.insert(inode);
+                    }
+                }
+            }
+            Err(error_code) => return Either::A(err(error_code)),
+        }
+
+        let data_dir = data_dir.to_string();
+        let data_storage = self.data_storage.clone();
+        let cache = self.checksum_cache.clone();
+        let (sender, receiver) = oneshot::channel();
+        cache.progress.running.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            let checksum =
+                checksum_data_dir(&data_dir, algorithm, mirrored, data_storage, cache.clone());
+            cache.progress.running.fetch_sub(1, Ordering::SeqCst);
+            // The receiver is dropped if the request was cancelled
+            sender.send(checksum.map_err(into_error_code)).ok();
+        });
+
+        Either::B(
+            receiver
+                .map_err(|_| ErrorCode::Uncategorized)
+                .and_then(|checksum| checksum),
+        )
+    }
+
+    // Returns whether inode is mirrored, if it's a file that context can read
+    pub fn verify_target(&self, inode: u64, context: UserContext) -> Result<bool, ErrorCode> {
+        self.metadata_storage.read(inode, context)?;
+        let redundancy = self.metadata_storage.get_redundancy(inode)?;
+        return Ok(self.data_storage.is_mirrored(redundancy));
+    }
+
+    // Checksums the data of inode which is stored on this node, in a separate thread since the file may be large
+    pub fn local_file_checksum(
+        &self,
+        inode: u64,
+        algorithm: ChecksumAlgorithm,
+    ) -> impl Future<Item = Vec<u8>, Error = ErrorCode> {
+        let data_storage = self.data_storage.clone();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let checksum = data_storage.open_local(inode).and_then(|contents| {
+                let mut checksum = Checksum::new(algorithm);
+                if let Some((_, mut contents)) = contents {
+                    io::copy(&mut contents, &mut checksum)?;
+                }
+                Ok(checksum.finish())
+            });
+            // The receiver is dropped if the request was cancelled
+            sender.send(checksum.map_err(into_error_code)).ok();
+        });
+
+        receiver
+            .map_err(|_| ErrorCode::Uncategorized)
+            .and_then(|checksum| checksum)
+    }
+
+    // The limit files which this node has accessed the most, hottest first
+    pub fn hottest_files(&self, limit: usize) -> Vec<(u64, FileAccess)> {
+        self.access_stats.hottest(limit)
+    }
+
+    // Fails if inode has no path, such as a temporary file
+    pub fn path_of(&self, inode: u64) -> Result<String, ErrorCode> {
+        self.metadata_storage.path_of(inode)
+    }
+
+    // Loads the files which were hottest before the node restarted into the block cache, in a separate thread
+    pub fn warm_block_cache(&self) {
+        let capacity = match self.data_storage.block_cache_stats() {
+            Some(stats) => stats.capacity_bytes,
+            None => return,
+        };
+        let mut targets = vec![];
+        for (inode, _) in self.access_stats.hottest(WARM_UP_FILES) {
+            if let Ok(attributes) = self.metadata_storage.get_attributes(inode) {
+                if attributes.kind == FileKind::File {
+                    targets.push((inode, attributes.size, attributes.redundancy));
+                }
+            }
+        }
+        if targets.is_empty() {
+            return;
+        }
+
+        let data_storage = self.data_storage.clone();
+        thread::spawn(move || {
+            let mut files = 0;
+            let mut bytes = 0;
+            for (inode, size, redundancy) in targets {
+                if bytes >= capacity {
+                    break;
+                }
+                match data_storage.prefetch(inode, size, redundancy) {
+                    Ok(inode_bytes) => bytes += inode_bytes,
+                    Err(error) => {
+                        warn!("Failed to warm up block cache: {:?}", error);
+                        break;
+                    }
+                }
+                files += 1;
+            }
+            info!(
+                "Warmed up block cache with {} hot files ({} bytes)",
+                files, bytes
+            );
+        });
+    }
+
+    // Files which a prefetch of inode loads: inode itself, or the files below it which user can read
+    fn prefetch_targets(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> Result<Vec<(u64, u64, u8)>, ErrorCode> {
+        let inodes = if self.metadata_storage.get_attributes(inode)?.kind == FileKind::Directory {
+            self.metadata_storage
+                .find(inode, &FindQuery::default(), context)?
+                .into_iter()
+                .map(|(_, entry_inode)| entry_inode)
+                .collect()
+        } else {
+            self.metadata_storage.read(inode, context)?;
+            vec![inode]
+        };
+
+        let mut targets = vec![];
+        for target in inodes {
+            let attributes = self.metadata_storage.get_attributes(target)?;
+            if attributes.kind == FileKind::File
+                && self.metadata_storage.read(target, context).is_ok()
+            {
+                targets.push((target, attributes.size, attributes.redundancy));
+            }
+        }
+
+        return Ok(targets);
+    }
+
+    // Loads the data stored on this node of inode, or of the files below it, into the block cache, in a separate
+    // thread. Stops once the cache is full. Returns the number of files and bytes loaded
+    pub fn prefetch_local(
+        &self,
+        inode: u64,
+        context: UserContext,
+    ) -> impl Future<Item = (u64, u64), Error = ErrorCode> {
+        let targets = match self.prefetch_targets(inode, context) {
+            Ok(targets) => targets,
+            Err(error_code) => return Either::A(err(error_code)),
+        };
+        let data_storage = self.data_storage.clone();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let capacity = data_storage
+                .block_cache_stats()
+                .map_or(0, |stats| stats.capacity_bytes);
+            let mut files = 0;
+            let mut bytes = 0;
+            let mut loaded = Ok(());
+            for (target, size, redundancy) in targets {
+                if bytes >= capacity {
+                    break;
+                }
+                match data_storage.prefetch(target, size, redundancy) {
+                    Ok(target_bytes) => bytes += target_bytes,
+                    Err(error) => {
+                        loaded = Err(into_error_code(error));
+                        break;
+                    }
+                }
+                files += 1;
+            }
+            info!("Prefetched {} files ({} bytes)", files, bytes);
+            // The receiver is dropped if the request was cancelled
+            sender.send(loaded.map(|_| (files, bytes))).ok();
+        });
+
+        Either::B(
+            receiver
+                .map_err(|_| ErrorCode::Uncategorized)
+                .and_then(|loaded| loaded),
+        )
+    }
+
+    pub fn checksum_progress(&self) -> &ChecksumProgress {
+        return &self.checksum_cache.progress;
+    }
+
+    // Checks that the data stored on this node is consistent with the metadata, and returns a description of each
+    // problem found. If repair is set, data of inodes which don't exist is deleted, and data beyond the end of
+    // files is truncated
+    pub fn check_local_data(&self, repair: bool) -> Result<Vec<String>, ErrorCode> {
+        let mut problems = vec![];
+        for inode in self.data_storage.local_inodes().map_err(into_error_code)? {
+            let attributes = match self.metadata_storage.get_attributes(inode) {
+                Ok(attributes) => attributes,
+                Err(ErrorCode::InodeDoesNotExist) => {
+                    problems.push(format!(
+                        "Data stored for inode {}, which doesn't exist",
+                        inode
+                    ));
+                    if repair {
+                        self.data_storage.delete(inode)?;
+                    }
+                    continue;
+                }
+                Err(error_code) => return Err(error_code),
+            };
+            let local_length = match self.data_storage.open_local(inode) {
+                Ok(Some((length, _))) => length,
+                Ok(None) => continue,
+                Err(error) => return Err(into_error_code(error)),
+            };
+            let expected_length = self
+                .data_storage
+                .local_length(attributes.size, attributes.redundancy);
+            // Local data may be shorter, since blocks which were never written aren't stored
+            if local_length > expected_length {
+                problems.push(format!(
+                    "Data stored for inode {} is {} bytes, but its size only needs {}",
+                    inode, local_length, expected_length
+                ));
+                if repair {
+                    self.data_storage
+                        .truncate(inode, attributes.size, attributes.redundancy)
+                        .map_err(into_error_code)?;
+                }
+            }
+        }
+
+        return Ok(problems);
+    }
+
+    // Number of files whose blocks are split between the nodes, instead of mirrored
+    pub fn striped_file_count(&self) -> Result<u64, ErrorCode> {
+        let redundancies = self.metadata_storage.file_redundancies()?;
+        return Ok(redundancies
+            .iter()
+            .filter(|(_, redundancy)| !self.data_storage.is_mirrored(*redundancy))
+            .count() as u64);
+    }
+
+    // Called once every other node was removed from the cluster, so that this node stores all of every file
+    pub fn make_standalone(&self) -> Result<(), ErrorCode> {
+        let mut files = vec![];
+        for (inode, redundancy) in self.metadata_storage.file_redundancies()? {
+            let length = self.metadata_storage.get_attributes(inode)?.size;
+            files.push((inode, length, redundancy));
+        }
+        return self
+            .data_storage
+            .make_standalone(&files)
+            .map_err(into_error_code);
+    }
+
+    // Returns a description of each inconsistency in the metadata
+    pub fn check_metadata(&self) -> Result<Vec<String>, ErrorCode> {
+        return self.metadata_storage.check();
+    }
+
+    pub fn repair_metadata<'a>(&self, mut builder: FlatBufferBuilder<'a>) -> ResultResponse<'a> {
+        let problems = self.metadata_storage.repair()?;
+        for problem in problems.iter() {
+            warn!("Repaired: {}", problem);
+        }
+        let refs: Vec<&str> = problems.iter().map(String::as_str).collect();
+        let offset = builder.create_vector_of_strings(&refs);
+        let mut response_builder = FilesystemRepairResponseBuilder::new(&mut builder);
+        response_builder.add_problems(offset);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return Ok((
+            builder,
+            ResponseType::FilesystemRepairResponse,
+            response_offset,
+        ));
+    }
+
+    // Returns true if releasing the inode would start its retention period
+    pub fn has_pending_retention(&self, inode: u64) -> Result<bool, ErrorCode> {
+        let (retention, retained_until) = self.metadata_storage.get_retention(inode)?;
+        return Ok(retention > 0 && retained_until == 0);
+    }
+
+    pub fn is_temporary(&self, inode: u64) -> Result<bool, ErrorCode> {
+        self.metadata_storage.is_temporary(inode)
+    }
+
+    pub fn set_limits<'a>(
+        &self,
+        limits: FilesystemLimits,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        self.metadata_storage.set_limits(limits);
+        return empty_response(builder);
+    }
+
+    pub fn freeze<'a>(
+        &self,
+        inode: u64,
+        timeout_seconds: u32,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        self.metadata_storage.freeze(inode, timeout_seconds)?;
+        return empty_response(builder);
+    }
+
+    pub fn thaw<'a>(&self, inode: u64, builder: FlatBufferBuilder<'a>) -> ResultResponse<'a> {
+        self.metadata_storage.thaw(inode)?;
+        return empty_response(builder);
+    }
+
+    pub fn allocate_client_id<'a>(&self, mut builder: FlatBufferBuilder<'a>) -> ResultResponse<'a> {
+        let client_id = self.metadata_storage.allocate_client_id();
+        let mut response_builder = ClientRegisteredResponseBuilder::new(&mut builder);
+        response_builder.add_client_id(client_id);
+        let response_offset = response_builder.finish().as_union_value();
+
+        return Ok((
+            builder,
+            ResponseType::ClientRegisteredResponse,
+            response_offset,
+        ));
+    }
+
+    pub fn release<'a>(
+        &self,
+        inode: u64,
+        session_id: u64,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        if let Some(deleted_inode) = self.metadata_storage.release(inode, session_id)? {
+            self.contents_deleted(deleted_inode);
+        }
+        return empty_response(builder);
+    }
+
+    pub fn open_temporary<'a>(
+        &self,
+        inode: u64,
+        session_id: u64,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        self.metadata_storage.open_temporary(inode, session_id)?;
+        return empty_response(builder);
+    }
+
+    pub fn end_session<'a>(
+        &self,
+        session_id: u64,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        for inode in self.metadata_storage.end_session(session_id)? {
+            self.contents_deleted(inode);
+        }
+        return empty_response(builder);
+    }
+
+    // Sessions which hold a handle of a temporary file
+    pub fn temporary_sessions(&self) -> Vec<u64> {
+        self.metadata_storage.temporary_sessions()
+    }
+
     pub fn remove_xattr<'a>(
         &self,
         inode: u64,
@@ -282,8 +2010,12 @@ impl FileStorage {
         context: UserContext,
         builder: FlatBufferBuilder<'a>,
     ) -> ResultResponse<'a> {
-        self.metadata_storage
-            .rename(parent, name, new_parent, new_name, context)?;
+        if let Some(deleted_inode) = self
+            .metadata_storage
+            .rename(parent, name, new_parent, new_name, context)?
+        {
+            self.contents_deleted(deleted_inode);
+        }
         return empty_response(builder);
     }
 
@@ -309,7 +2041,7 @@ impl FileStorage {
     ) -> ResultResponse<'a> {
         info!("Deleting file");
         if let Some(deleted_inode) = self.metadata_storage.unlink(parent, name, context)? {
-            self.data_storage.delete(deleted_inode).unwrap();
+            self.contents_deleted(deleted_inode);
         }
 
         return empty_response(builder);
@@ -330,8 +2062,30 @@ impl FileStorage {
             .metadata_storage
             .create(parent, name, uid, gid, mode, kind)?;
 
-        self.data_storage.truncate(attributes.inode, 0).unwrap();
+        self.data_storage
+            .truncate(attributes.inode, 0, attributes.redundancy)
+            .unwrap();
+
+        return to_fileattr_response(builder, attributes, 0);
+    }
+
+    pub fn create_temporary<'a>(
+        &self,
+        parent: u64,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        session_id: u64,
+        builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        let attributes = self
+            .metadata_storage
+            .create_temporary(parent, uid, gid, mode, session_id)?;
+
+        self.data_storage
+            .truncate(attributes.inode, 0, attributes.redundancy)
+            .unwrap();
 
-        return to_fileattr_response(builder, attributes);
+        return to_fileattr_response(builder, attributes, 0);
     }
 }
@@ -0,0 +1,135 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+
+use crate::generated::Timestamp;
+use crate::preflight::MAX_CLOCK_SKEW;
+use crate::storage::metadata_storage::now;
+
+// Size of a Timestamp encoded by encode_timestamp()
+pub const ENCODED_TIMESTAMP_SIZE: usize = 12;
+
+fn as_tuple(timestamp: Timestamp) -> (i64, i32) {
+    (timestamp.seconds(), timestamp.nanos())
+}
+
+// The later of two timestamps
+pub fn later(first: Timestamp, second: Timestamp) -> Timestamp {
+    if as_tuple(first) >= as_tuple(second) {
+        first
+    } else {
+        second
+    }
+}
+
+// The smallest timestamp after timestamp
+pub fn successor(timestamp: Timestamp) -> Timestamp {
+    if timestamp.nanos() >= 999_999_999 {
+        Timestamp::new(timestamp.seconds() + 1, 0)
+    } else {
+        Timestamp::new(timestamp.seconds(), timestamp.nanos() + 1)
+    }
+}
+
+pub fn encode_timestamp(timestamp: Timestamp, buffer: &mut Vec<u8>) {
+    let mut encoded = [0; ENCODED_TIMESTAMP_SIZE];
+    LittleEndian::write_i64(&mut encoded[..8], timestamp.seconds());
+    LittleEndian::write_i32(&mut encoded[8..], timestamp.nanos());
+    buffer.extend_from_slice(&encoded);
+}
+
+pub fn decode_timestamp(encoded: &[u8]) -> Option<Timestamp> {
+    if encoded.len() < ENCODED_TIMESTAMP_SIZE {
+        return None;
+    }
+    Some(Timestamp::new(
+        LittleEndian::read_i64(&encoded[..8]),
+        LittleEndian::read_i32(&encoded[8..ENCODED_TIMESTAMP_SIZE]),
+    ))
+}
+
+// Hybrid logical clock. Its timestamps follow the wall clock, but never go backwards, even if the wall clock does,
+// and are always later than every timestamp observed from other nodes. So a change which causally follows
+// another always gets a later timestamp, whichever node assigned them
+pub struct HybridClock {
+    latest: Mutex<Timestamp>,
+}
+
+impl HybridClock {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> HybridClock {
+        HybridClock {
+            latest: Mutex::new(Timestamp::new(0, 0)),
+        }
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.tick(now())
+    }
+
+    fn tick(&self, wall_clock: Timestamp) -> Timestamp {
+        let mut latest = self.latest.lock().unwrap();
+        *latest = later(wall_clock, successor(*latest));
+        return *latest;
+    }
+
+    // Called with the timestamps assigned by other nodes, so that later local timestamps follow them
+    pub fn observe(&self, timestamp: Timestamp) {
+        let wall_clock = now();
+        let ahead = Duration::new(
+            (timestamp.seconds() - wall_clock.seconds()).max(0) as u64,
+            0,
+        );
+        if ahead > MAX_CLOCK_SKEW {
+            warn!(
+                "Observed a timestamp {:?} ahead of the local clock. The clocks of the nodes are skewed",
+                ahead
+            );
+        }
+        let mut latest = self.latest.lock().unwrap();
+        *latest = later(*latest, timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::Timestamp;
+    use crate::storage::hybrid_clock::{decode_timestamp, encode_timestamp, HybridClock};
+
+    #[test]
+    fn never_goes_backwards() {
+        let clock = HybridClock::new();
+        let first = clock.tick(Timestamp::new(100, 999_999_999));
+        // The wall clock jumped back
+        let second = clock.tick(Timestamp::new(90, 0));
+        let third = clock.tick(Timestamp::new(90, 0));
+        assert_eq!(first, Timestamp::new(100, 999_999_999));
+        assert_eq!(second, Timestamp::new(101, 0));
+        assert_eq!(third, Timestamp::new(101, 1));
+        assert_eq!(clock.tick(Timestamp::new(200, 0)), Timestamp::new(200, 0));
+    }
+
+    #[test]
+    fn follows_observed_timestamps() {
+        let clock = HybridClock::new();
+        clock.tick(Timestamp::new(100, 0));
+        clock.observe(Timestamp::new(150, 5));
+        assert_eq!(clock.tick(Timestamp::new(101, 0)), Timestamp::new(150, 6));
+        // Older timestamps don't move it back
+        clock.observe(Timestamp::new(120, 0));
+        assert_eq!(clock.tick(Timestamp::new(101, 0)), Timestamp::new(150, 7));
+    }
+
+    #[test]
+    fn encoding() {
+        let mut buffer = vec![1, 2];
+        encode_timestamp(Timestamp::new(-5, 123), &mut buffer);
+        assert_eq!(
+            decode_timestamp(&buffer[2..]),
+            Some(Timestamp::new(-5, 123))
+        );
+        assert_eq!(decode_timestamp(&buffer[3..]), None);
+    }
+}
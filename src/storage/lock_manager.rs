@@ -0,0 +1,522 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::generated::{ErrorCode, LockKind};
+
+// Waiting requests which aren't retried within this long are assumed to have been abandoned
+const WAITER_TIMEOUT: Duration = Duration::from_secs(5);
+// Locks of a client session which doesn't renew them, or make another lock request, within this long are released,
+// so that a crashed client can't hold them forever. The session is then ended, which releases its temporary files
+// and forgets the responses to its requests
+pub const LOCK_LEASE: Duration = Duration::from_secs(30);
+
+// Lock owner of the kernel, which identifies the open file description or process, within the client session
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    pub session_id: u64,
+    pub owner: u64,
+}
+
+// A POSIX byte range lock. end is inclusive, and u64::MAX extends the lock to the end of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRangeLock {
+    pub owner: LockOwner,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub exclusive: bool,
+    // Issued with each exclusive lock, and larger than any issued before it, so that writes made under a lock which
+    // was since broken can be rejected. 0 for shared locks
+    pub fencing_token: u64,
+}
+
+impl ByteRangeLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &ByteRangeLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.exclusive || other.exclusive)
+    }
+}
+
+pub struct LockEntry {
+    pub inode: u64,
+    pub lock: ByteRangeLock,
+    // Whether the lock is waited for, rather than held
+    pub waiting: bool,
+}
+
+struct Waiter {
+    inode: u64,
+    lock: ByteRangeLock,
+    last_retried: Instant,
+}
+
+struct LockState {
+    // Term in which the locks were granted
+    term: u64,
+    held: HashMap<u64, Vec<ByteRangeLock>>,
+    // Each owner waits for at most one lock, since the kernel blocks it until the lock is granted
+    waiting: HashMap<LockOwner, Waiter>,
+    // When the lease of each session with locks, waiters, or other state in the cluster was last renewed
+    leases: HashMap<u64, Instant>,
+    // Sessions whose lease expired, which haven't been returned by expire_sessions() yet
+    expired: Vec<u64>,
+    // Sequence of the last fencing token issued in this term
+    sequence: u64,
+}
+
+// Byte range locks, which are held in the memory of the leader. Locks are dropped when the leadership changes.
+// Conflicting requests which wait are registered as waiters, and fail until the client retries them after the
+// conflicting locks are released. Waiters form a wait-for graph with the holders of the locks they wait for, which
+// is checked for cycles, so that a request which would deadlock fails instead
+pub struct LockManager {
+    state: Mutex<LockState>,
+}
+
+impl LockManager {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> LockManager {
+        LockManager {
+            state: Mutex::new(LockState {
+                term: 0,
+                held: HashMap::new(),
+                waiting: HashMap::new(),
+                leases: HashMap::new(),
+                expired: vec![],
+                sequence: 0,
+            }),
+        }
+    }
+
+    fn current_state(&self, term: u64) -> std::sync::MutexGuard<LockState> {
+        let mut state = self.state.lock().unwrap();
+        if state.term != term {
+            state.term = term;
+            state.held.clear();
+            state.waiting.clear();
+            state.leases.clear();
+            state.expired.clear();
+            state.sequence = 0;
+        }
+        state
+            .waiting
+            .retain(|_, waiter| waiter.last_retried.elapsed() < WAITER_TIMEOUT);
+        let expired: Vec<u64> = state
+            .leases
+            .iter()
+            .filter(|(_, renewed)| renewed.elapsed() >= LOCK_LEASE)
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        for session_id in expired {
+            let released = release_session(&mut state, None, session_id);
+            state.leases.remove(&session_id);
+            state.expired.push(session_id);
+            if released.is_empty() {
+                continue;
+            }
+            warn!(
+                "Lease of session {} expired. Released {} of its locks",
+                session_id,
+                released.len()
+            );
+        }
+
+        state
+    }
+
+    // Renews the lease of the session, or starts it
+    pub fn renew(&self, term: u64, session_id: u64) {
+        let mut state = self.current_state(term);
+        state.leases.insert(session_id, Instant::now());
+    }
+
+    // Returns the sessions whose lease expired since the last call, so that they can be ended. Each of the held
+    // sessions which has no lease is given one, since a new leader doesn't know when they were last active
+    pub fn expire_sessions(&self, term: u64, held: &[u64]) -> Vec<u64> {
+        let mut state = self.current_state(term);
+        for session_id in held {
+            state.leases.entry(*session_id).or_insert_with(Instant::now);
+        }
+
+        state.expired.drain(..).collect()
+    }
+
+    // Forcibly releases the held and waited for locks of the inode and/or session (None matches any), and returns
+    // them. Holders find out when their next write is rejected for its stale fencing token
+    pub fn break_locks(
+        &self,
+        term: u64,
+        inode: Option<u64>,
+        session_id: Option<u64>,
+    ) -> Vec<LockEntry> {
+        let mut state = self.current_state(term);
+        let sessions: Vec<u64> = match session_id {
+            Some(session_id) => vec![session_id],
+            None => state.leases.keys().cloned().collect(),
+        };
+        let mut broken = vec![];
+        for session_id in sessions {
+            broken.extend(release_session(&mut state, inode, session_id));
+        }
+        broken.sort_by_key(|x| (x.inode, x.waiting, x.lock.start));
+
+        broken
+    }
+
+    // Acquires, converts, or releases (with LockKind::Unlock) the range for its owner, and renews the lease of its
+    // session. Returns the fencing token of the lock, which is 0 unless it's exclusive. Fails with LockConflict if
+    // it conflicts with a lock of another owner, and with Deadlock if waiting for that lock would deadlock
+    pub fn lock(
+        &self,
+        term: u64,
+        inode: u64,
+        mut lock: ByteRangeLock,
+        kind: LockKind,
+        wait: bool,
+    ) -> Result<u64, ErrorCode> {
+        let mut state = self.current_state(term);
+        state.leases.insert(lock.owner.session_id, Instant::now());
+        if kind == LockKind::Unlock {
+            state.waiting.remove(&lock.owner);
+            unlock_range(&mut state, inode, lock.owner, lock.start, lock.end);
+            return Ok(0);
+        }
+
+        let blockers = blocking_owners(&state, inode, &lock);
+        if blockers.is_empty() {
+            state.waiting.remove(&lock.owner);
+            unlock_range(&mut state, inode, lock.owner, lock.start, lock.end);
+            lock.fencing_token = 0;
+            if lock.exclusive {
+                // Tokens of later terms are larger, since the term is in the high bits
+                state.sequence += 1;
+                lock.fencing_token = (term << 32) | state.sequence;
+            }
+            state.held.entry(inode).or_default().push(lock);
+            return Ok(lock.fencing_token);
+        }
+        if !wait {
+            return Err(ErrorCode::LockConflict);
+        }
+        if waits_for(&state, blockers, lock.owner) {
+            state.waiting.remove(&lock.owner);
+            return Err(ErrorCode::Deadlock);
+        }
+        state.waiting.insert(
+            lock.owner,
+            Waiter {
+                inode,
+                lock,
+                last_retried: Instant::now(),
+            },
+        );
+
+        return Err(ErrorCode::LockConflict);
+    }
+
+    // A lock which conflicts with the given one, if there is any
+    pub fn test(&self, term: u64, inode: u64, lock: &ByteRangeLock) -> Option<ByteRangeLock> {
+        let state = self.current_state(term);
+        state
+            .held
+            .get(&inode)
+            .and_then(|locks| locks.iter().find(|x| x.conflicts(lock)).cloned())
+    }
+
+    // A lock of another session which conflicts with reading, or writing, the range. Used for mandatory locking,
+    // which can only tell sessions apart, since reads and writes don't carry a lock owner
+    pub fn conflicting_access(
+        &self,
+        term: u64,
+        inode: u64,
+        session_id: u64,
+        start: u64,
+        end: u64,
+        write: bool,
+    ) -> Option<ByteRangeLock> {
+        let state = self.current_state(term);
+        state.held.get(&inode).and_then(|locks| {
+            locks
+                .iter()
+                .find(|x| {
+                    x.owner.session_id != session_id
+                        && x.overlaps(start, end)
+                        && (x.exclusive || write)
+                })
+                .cloned()
+        })
+    }
+
+    // Held and waited for locks of the inode, or of every inode if it's None, sorted by inode
+    pub fn list(&self, term: u64, inode: Option<u64>) -> Vec<LockEntry> {
+        let state = self.current_state(term);
+        let mut entries = vec![];
+        for (held_inode, locks) in state.held.iter() {
+            if inode.map_or(true, |x| x == *held_inode) {
+                entries.extend(locks.iter().map(|lock| LockEntry {
+                    inode: *held_inode,
+                    lock: *lock,
+                    waiting: false,
+                }));
+            }
+        }
+        for waiter in state.waiting.values() {
+            if inode.map_or(true, |x| x == waiter.inode) {
+                entries.push(LockEntry {
+                    inode: waiter.inode,
+                    lock: waiter.lock,
+                    waiting: true,
+                });
+            }
+        }
+        entries.sort_by_key(|x| (x.inode, x.waiting, x.lock.start));
+
+        entries
+    }
+}
+
+// Removes the range from the owner's locks of the inode, splitting the locks which extend past it
+fn unlock_range(state: &mut LockState, inode: u64, owner: LockOwner, start: u64, end: u64) {
+    let locks = match state.held.get_mut(&inode) {
+        Some(locks) => locks,
+        None => return,
+    };
+    let mut remaining = vec![];
+    for lock in locks.drain(..) {
+        if lock.owner != owner || !lock.overlaps(start, end) {
+            remaining.push(lock);
+            continue;
+        }
+        if lock.start < start {
+            remaining.push(ByteRangeLock {
+                end: start - 1,
+                ..lock
+            });
+        }
+        if lock.end > end {
+            remaining.push(ByteRangeLock {
+                start: end + 1,
+                ..lock
+            });
+        }
+    }
+    if remaining.is_empty() {
+        state.held.remove(&inode);
+    } else {
+        *locks = remaining;
+    }
+}
+
+// Removes the session's held and waited for locks of the inode, or of every inode if it's None, and returns them
+fn release_session(state: &mut LockState, inode: Option<u64>, session_id: u64) -> Vec<LockEntry> {
+    let mut released = vec![];
+    let matches = |x: u64| inode.map_or(true, |inode| inode == x);
+    for (held_inode, locks) in state.held.iter_mut() {
+        if !matches(*held_inode) {
+            continue;
+        }
+        locks.retain(|lock| {
+            if lock.owner.session_id != session_id {
+                return true;
+            }
+            released.push(LockEntry {
+                inode: *held_inode,
+                lock: *lock,
+                waiting: false,
+            });
+            false
+        });
+    }
+    state.held.retain(|_, locks| !locks.is_empty());
+    state.waiting.retain(|owner, waiter| {
+        if owner.session_id != session_id || !matches(waiter.inode) {
+            return true;
+        }
+        released.push(LockEntry {
+            inode: waiter.inode,
+            lock: waiter.lock,
+            waiting: true,
+        });
+        false
+    });
+    let remaining = state
+        .held
+        .values()
+        .flatten()
+        .any(|x| x.owner.session_id == session_id)
+        || state.waiting.keys().any(|x| x.session_id == session_id);
+    if !remaining {
+        state.leases.remove(&session_id);
+    }
+
+    released
+}
+
+// Owners of the locks which conflict with the given lock
+fn blocking_owners(state: &LockState, inode: u64, lock: &ByteRangeLock) -> Vec<LockOwner> {
+    let mut owners: Vec<LockOwner> = state
+        .held
+        .get(&inode)
+        .map(|locks| {
+            locks
+                .iter()
+                .filter(|x| x.conflicts(lock))
+                .map(|x| x.owner)
+                .collect()
+        })
+        .unwrap_or_default();
+    owners.dedup();
+    owners
+}
+
+// Whether any of the owners waits, directly or through other owners, for target
+fn waits_for(state: &LockState, owners: Vec<LockOwner>, target: LockOwner) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = owners;
+    while let Some(owner) = pending.pop() {
+        if owner == target {
+            return true;
+        }
+        if !visited.insert(owner) {
+            continue;
+        }
+        if let Some(waiter) = state.waiting.get(&owner) {
+            pending.extend(blocking_owners(state, waiter.inode, &waiter.lock));
+        }
+    }
+
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::{ErrorCode, LockKind};
+    use crate::storage::lock_manager::{ByteRangeLock, LockManager, LockOwner};
+
+    fn lock(session_id: u64, start: u64, end: u64, exclusive: bool) -> ByteRangeLock {
+        ByteRangeLock {
+            owner: LockOwner {
+                session_id,
+                owner: 1,
+            },
+            pid: 100,
+            start,
+            end,
+            exclusive,
+            fencing_token: 0,
+        }
+    }
+
+    #[test]
+    fn conflicts_and_splits() {
+        let locks = LockManager::new();
+        assert!(locks
+            .lock(1, 5, lock(1, 0, 99, true), LockKind::Exclusive, false)
+            .is_ok());
+        // Shared locks of other owners conflict with it, but not outside its range
+        assert_eq!(
+            locks.lock(1, 5, lock(2, 50, 50, false), LockKind::Shared, false),
+            Err(ErrorCode::LockConflict)
+        );
+        assert!(locks
+            .lock(1, 5, lock(2, 100, 199, false), LockKind::Shared, false)
+            .is_ok());
+
+        // Unlocking the middle leaves the ends
+        locks
+            .lock(1, 5, lock(1, 10, 19, true), LockKind::Unlock, false)
+            .unwrap();
+        assert!(locks.test(1, 5, &lock(2, 10, 19, true)).is_none());
+        assert_eq!(
+            locks
+                .test(1, 5, &lock(2, 0, 10, true))
+                .map(|x| (x.start, x.end)),
+            Some((0, 9))
+        );
+        assert_eq!(locks.list(1, Some(5)).len(), 3);
+
+        // Locks are dropped when the term changes
+        assert!(locks.list(2, None).is_empty());
+    }
+
+    #[test]
+    fn deadlock() {
+        let locks = LockManager::new();
+        locks
+            .lock(1, 5, lock(1, 0, 0, true), LockKind::Exclusive, false)
+            .unwrap();
+        locks
+            .lock(1, 6, lock(2, 0, 0, true), LockKind::Exclusive, false)
+            .unwrap();
+        // 1 waits for 2
+        assert_eq!(
+            locks.lock(1, 6, lock(1, 0, 0, true), LockKind::Exclusive, true),
+            Err(ErrorCode::LockConflict)
+        );
+        assert!(locks.list(1, Some(6)).iter().any(|x| x.waiting));
+        // So 2 can't wait for 1
+        assert_eq!(
+            locks.lock(1, 5, lock(2, 0, 0, true), LockKind::Exclusive, true),
+            Err(ErrorCode::Deadlock)
+        );
+
+        // Once 2 releases its lock, 1 gets it
+        locks
+            .lock(1, 6, lock(2, 0, 0, true), LockKind::Unlock, false)
+            .unwrap();
+        assert!(locks
+            .lock(1, 6, lock(1, 0, 0, true), LockKind::Exclusive, true)
+            .is_ok());
+        assert!(!locks.list(1, None).iter().any(|x| x.waiting));
+    }
+
+    #[test]
+    fn conflicting_access() {
+        let locks = LockManager::new();
+        locks
+            .lock(1, 5, lock(1, 0, 9, false), LockKind::Shared, false)
+            .unwrap();
+        // Shared locks only conflict with writes, and never with the session's own access
+        assert!(locks.conflicting_access(1, 5, 2, 5, 20, false).is_none());
+        assert!(locks.conflicting_access(1, 5, 2, 5, 20, true).is_some());
+        assert!(locks.conflicting_access(1, 5, 2, 10, 20, true).is_none());
+        assert!(locks.conflicting_access(1, 5, 1, 5, 20, true).is_none());
+    }
+
+    #[test]
+    fn fencing_tokens_and_breaking() {
+        let locks = LockManager::new();
+        let first = locks
+            .lock(1, 5, lock(1, 0, 9, true), LockKind::Exclusive, false)
+            .unwrap();
+        assert_eq!(
+            locks
+                .lock(1, 6, lock(1, 0, 9, false), LockKind::Shared, false)
+                .unwrap(),
+            0
+        );
+
+        // Breaking the locks of the session on one inode leaves its other locks
+        let broken = locks.break_locks(1, Some(5), Some(1));
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].lock.fencing_token, first);
+        assert_eq!(locks.list(1, None).len(), 1);
+
+        // Another owner's lock gets a larger token, and so does a lock granted in a later term
+        let second = locks
+            .lock(1, 5, lock(2, 0, 9, true), LockKind::Exclusive, false)
+            .unwrap();
+        assert!(second > first);
+        let third = locks
+            .lock(2, 5, lock(1, 0, 9, true), LockKind::Exclusive, false)
+            .unwrap();
+        assert!(third > second);
+    }
+}
@@ -1,20 +1,163 @@
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 
-use crate::generated::{ErrorCode, FileKind, Timestamp, UserContext};
+use crate::generated::{
+    DirectoryEntrySnapshot, DirectoryEntrySnapshotArgs, DirectorySnapshot, DirectorySnapshotArgs,
+    ErrorCode, FileKind, FilesystemLimits, FindRequest, FrozenSubtreeSnapshot,
+    FrozenSubtreeSnapshotArgs, InodeSnapshot, InodeSnapshotArgs, MetadataSnapshot,
+    MetadataSnapshotArgs, TemporaryHandlesSnapshot, TemporaryHandlesSnapshotArgs, Timestamp,
+    UserContext, XattrSnapshot, XattrSnapshotArgs,
+};
 use crate::storage::data_storage::BLOCK_SIZE;
-use crate::utils::check_access;
+use crate::storage::hybrid_clock::{later, successor};
+use crate::utils::{check_access, glob_matches};
+use flatbuffers::FlatBufferBuilder;
 use fuse::FUSE_ROOT_ID;
 use std::time::SystemTime;
 
 pub const ROOT_INODE: u64 = FUSE_ROOT_ID;
+// Upper bounds of the configurable limits
 pub const MAX_NAME_LENGTH: u32 = 255;
-pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+pub const MAX_XATTR_SIZE: u32 = 64 * 1024;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+// Longest time a subtree can be frozen for with a single FreezeRequest
+pub const MAX_FREEZE_SECONDS: u32 = 24 * 60 * 60;
+// Maximum number of hard links to a file, like ext4
+pub const MAX_HARDLINKS: u32 = 65_000;
+// Equivalent of PATH_MAX, for requests which take a path
+pub const MAX_PATH_LENGTH: usize = 4096;
+// Directories can't be nested deeper than this, so that their paths can be resolved
+pub const MAX_DIRECTORY_DEPTH: u32 = 1024;
+// Files are striped across nodes, without redundancy, unless configured otherwise
+pub const DEFAULT_REDUNDANCY: u8 = 1;
+// Virtual xattr used to get and set the redundancy of an inode
+pub const REDUNDANCY_XATTR: &str = "fleetfs.redundancy";
+// relatime updates the access time at least this often, even if the file hasn't been modified
+const RELATIME_INTERVAL_SECS: i64 = 24 * 60 * 60;
+// Virtual xattrs used to get and set the retention of an inode, and get the time until which it's retained
+pub const RETENTION_XATTR: &str = "fleetfs.retention";
+pub const RETAINED_UNTIL_XATTR: &str = "fleetfs.retained_until";
+// Directory in the root, where fsck reattaches unreachable inodes
+pub const LOST_AND_FOUND: &str = "lost+found";
+// Virtual xattr used to get the paths of all the links to an inode, separated by newlines
+pub const LINKS_XATTR: &str = "fleetfs.links";
+// Virtual xattr used to get the SHA-256 digest of the contents of a file, as a hex string
+pub const CHECKSUM_XATTR: &str = "fleetfs.checksum";
+// Virtual xattr used to get the IDs of the nodes which store the data of an inode, one per line. Directories
+// are stored by every node
+pub const REPLICAS_XATTR: &str = "fleetfs.replicas";
+// Virtual xattr used to get the total bytes and number of inodes below a directory, separated by a space
+pub const USAGE_XATTR: &str = "fleetfs.usage";
+// Virtual xattrs used by Samba to store DOS attributes, as a hex string like "0x21", and the creation time,
+// in Unix seconds
+pub const DOS_ATTRIBUTES_XATTR: &str = "fleetfs.dos.attributes";
+pub const DOS_CREATED_XATTR: &str = "fleetfs.dos.created";
+// DOS attribute bits, with the same values as FILE_ATTRIBUTE_*
+pub const DOS_READONLY: u8 = 0x01;
+pub const DOS_HIDDEN: u8 = 0x02;
+pub const DOS_SYSTEM: u8 = 0x04;
+pub const DOS_ARCHIVE: u8 = 0x20;
+const DOS_ATTRIBUTES_MASK: u8 = DOS_READONLY | DOS_HIDDEN | DOS_SYSTEM | DOS_ARCHIVE;
+// Number of recent changes remembered for each directory, to answer delta listings
+const MAX_DIRECTORY_CHANGES: usize = 1024;
 
 type Inode = u64;
-type DirectoryDescriptor = HashMap<String, (Inode, FileKind)>;
+
+// Entries of a directory, by name. In case-insensitive volumes, names are also indexed by their case folded form,
+// so that they can be looked up in any case, while listings preserve the case they were created with
+struct DirectoryDescriptor {
+    entries: HashMap<String, (Inode, FileKind)>,
+    // Folded name -> name in entries. Only maintained in case-insensitive volumes
+    folded: Option<HashMap<String, String>>,
+}
+
+// Unicode lowercasing, which is equivalent to case folding for all but a few special cases
+fn fold_case(name: &str) -> String {
+    name.to_lowercase()
+}
+
+impl DirectoryDescriptor {
+    fn new(case_insensitive: bool) -> DirectoryDescriptor {
+        DirectoryDescriptor {
+            entries: HashMap::new(),
+            folded: if case_insensitive {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    // Returns the name that the entry matching name was stored with
+    fn stored_name<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        match self.folded {
+            Some(ref folded) => folded.get(&fold_case(name)).map(String::as_str),
+            None if self.entries.contains_key(name) => Some(name),
+            None => None,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&(Inode, FileKind)> {
+        self.stored_name(name).and_then(|x| self.entries.get(x))
+    }
+
+    // Returns the name and entry which were replaced, if any
+    fn insert(
+        &mut self,
+        name: String,
+        entry: (Inode, FileKind),
+    ) -> Option<(String, (Inode, FileKind))> {
+        let replaced = self.remove(&name);
+        if let Some(ref mut folded) = self.folded {
+            folded.insert(fold_case(&name), name.clone());
+        }
+        self.entries.insert(name, entry);
+
+        replaced
+    }
+
+    // Returns the name the entry was stored with, and the entry
+    fn remove(&mut self, name: &str) -> Option<(String, (Inode, FileKind))> {
+        let stored_name = self.stored_name(name)?.to_string();
+        if let Some(ref mut folded) = self.folded {
+            folded.remove(&fold_case(name));
+        }
+        self.entries
+            .remove(&stored_name)
+            .map(|entry| (stored_name, entry))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &(Inode, FileKind))> {
+        self.entries.iter()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &(Inode, FileKind)> {
+        self.entries.values()
+    }
+}
+
+// Recent changes to the entries of a directory
+#[derive(Default)]
+struct DirectoryChanges {
+    // Incremented on every change
+    version: u64,
+    // Version after each change, the name changed, and its new entry, or None if it was removed. Oldest first
+    log: VecDeque<(u64, String, Option<(Inode, FileKind)>)>,
+}
+
+// Changes to a directory since an earlier version
+pub struct DirectoryDelta {
+    pub version: u64,
+    // Entries which were added or replaced
+    pub added: Vec<(Inode, String, FileKind)>,
+    pub removed: Vec<String>,
+}
 
 #[derive(Clone, Debug)]
 pub struct InodeAttributes {
@@ -27,9 +170,101 @@ pub struct InodeAttributes {
     // Permissions and special mode bits
     pub mode: u16,
     pub hardlinks: u32,
+    // Number of nodes storing each block. Inherited from the parent directory, when created
+    pub redundancy: u8,
+    // Seconds that files are retained for, after they are released. Inherited from the parent directory
+    pub retention: u64,
+    // Unix time in seconds, until which the file can't be modified, renamed, or deleted. 0 if not retained
+    pub retained_until: i64,
     pub uid: u32,
     pub gid: u32,
     pub xattrs: HashMap<String, Vec<u8>>,
+    // DOS_* bits. Writes are denied while DOS_READONLY is set
+    pub dos_attributes: u8,
+    pub created: Timestamp,
+    // Incremented by every write and truncate. Always 0 for directories
+    pub data_version: u64,
+    // Largest fencing token of the writes applied to the file
+    pub fencing_token: u64,
+}
+
+// Usage of all the files and directories below a directory, not including the directory itself
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TreeUsage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+// Predicates for a search. An entry matches if it satisfies all the predicates which are set
+#[derive(Clone, Debug, Default)]
+pub struct FindQuery {
+    pub name_glob: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<Timestamp>,
+    pub modified_before: Option<Timestamp>,
+    pub xattr_key: Option<String>,
+    pub xattr_value: Option<Vec<u8>>,
+}
+
+impl FindQuery {
+    pub fn from_request(request: &FindRequest) -> FindQuery {
+        FindQuery {
+            name_glob: request.name_glob().map(ToString::to_string),
+            min_size: request.min_size().map(|x| x.value()),
+            max_size: request.max_size().map(|x| x.value()),
+            modified_after: request.modified_after().cloned(),
+            modified_before: request.modified_before().cloned(),
+            xattr_key: request.xattr_key().map(ToString::to_string),
+            xattr_value: request.xattr_value().map(<[u8]>::to_vec),
+        }
+    }
+
+    fn matches(&self, name: &str, attributes: &InodeAttributes) -> bool {
+        if let Some(ref glob) = self.name_glob {
+            if !glob_matches(glob, name) {
+                return false;
+            }
+        }
+        if let Some(min_size) = self.min_size {
+            if attributes.size < min_size {
+                return false;
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if attributes.size > max_size {
+                return false;
+            }
+        }
+        let modified = (
+            attributes.last_modified.seconds(),
+            attributes.last_modified.nanos(),
+        );
+        if let Some(after) = self.modified_after {
+            if modified <= (after.seconds(), after.nanos()) {
+                return false;
+            }
+        }
+        if let Some(before) = self.modified_before {
+            if modified >= (before.seconds(), before.nanos()) {
+                return false;
+            }
+        }
+        if let Some(ref key) = self.xattr_key {
+            match attributes.xattrs.get(key) {
+                None => return false,
+                Some(value) => {
+                    if let Some(ref expected) = self.xattr_value {
+                        if value != expected {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        return true;
+    }
 }
 
 // TODO: add persistence
@@ -40,19 +275,48 @@ pub struct MetadataStorage {
     // Maybe we should revisit that design?
     // Maps directory inodes to maps of name -> inode
     directories: Mutex<HashMap<Inode, DirectoryDescriptor>>,
+    // Version of each directory, and its recent changes. Directories which have never changed are at version 0
+    directory_changes: Mutex<HashMap<Inode, DirectoryChanges>>,
     // Stores mapping of directory inodes to their parent
     directory_parents: Mutex<HashMap<Inode, Inode>>,
+    // Stores mapping of non-directory inodes to the (directory, name) of each of their links
+    file_parents: Mutex<HashMap<Inode, Vec<(Inode, String)>>>,
     metadata: Mutex<HashMap<Inode, InodeAttributes>>,
+    // Recursive usage of each directory. Maintained on every change, so that it can be queried in O(1)
+    tree_usage: Mutex<HashMap<Inode, TreeUsage>>,
     // Raft guarantees that operations are performed in the same order across all nodes
     // which means that all nodes have the same value for this counter
     next_inode: AtomicU64,
+    // Whether names are compared case-insensitively. Must be the same on every node
+    case_insensitive: bool,
+    // Time of the latest operation applied from the Raft log, which is the time of the one being applied while
+    // it's applied. None if no operation has been applied, in which case the local clock is used
+    operation_time: Mutex<Option<Timestamp>>,
+    // Changed with SetLimitsRequest, so that they're the same on every node
+    limits: Mutex<FilesystemLimits>,
+    // Roots of the subtrees frozen with FreezeRequest, mapped to the operation time at which they're thawed
+    frozen: Mutex<HashMap<Inode, Timestamp>>,
+    // Assigned by AllocateClientIdRequest. The same on every node, like next_inode
+    next_client_id: AtomicU64,
+    // Open handles of each temporary file, by the client session which holds them. The file is deleted once its
+    // last handle is released, or the sessions holding them end
+    temporary_handles: Mutex<HashMap<Inode, HashMap<u64, u32>>>,
+}
+
+// Limits of a new filesystem. The number of inodes is only limited by the available memory
+pub fn default_limits() -> FilesystemLimits {
+    FilesystemLimits::new(
+        DEFAULT_MAX_FILE_SIZE,
+        u64::MAX,
+        MAX_NAME_LENGTH,
+        MAX_XATTR_SIZE,
+    )
 }
 
 impl MetadataStorage {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> MetadataStorage {
+    pub fn new(case_insensitive: bool) -> MetadataStorage {
         let mut directories = HashMap::new();
-        directories.insert(ROOT_INODE, HashMap::new());
+        directories.insert(ROOT_INODE, DirectoryDescriptor::new(case_insensitive));
 
         let mut parents = HashMap::new();
         parents.insert(ROOT_INODE, ROOT_INODE);
@@ -69,18 +333,284 @@ impl MetadataStorage {
                 kind: FileKind::Directory,
                 mode: 0o777,
                 hardlinks: 2,
+                redundancy: DEFAULT_REDUNDANCY,
+                retention: 0,
+                retained_until: 0,
                 uid: 0,
                 gid: 0,
                 xattrs: Default::default(),
+                dos_attributes: 0,
+                created: now(),
+                data_version: 0,
+                fencing_token: 0,
             },
         );
 
+        let mut tree_usage = HashMap::new();
+        tree_usage.insert(ROOT_INODE, TreeUsage::default());
+
         MetadataStorage {
             metadata: Mutex::new(metadata),
             directories: Mutex::new(directories),
+            directory_changes: Mutex::new(HashMap::new()),
             directory_parents: Mutex::new(parents),
+            file_parents: Mutex::new(HashMap::new()),
+            tree_usage: Mutex::new(tree_usage),
             next_inode: AtomicU64::new(ROOT_INODE + 1),
+            case_insensitive,
+            operation_time: Mutex::new(None),
+            limits: Mutex::new(default_limits()),
+            frozen: Mutex::new(HashMap::new()),
+            next_client_id: AtomicU64::new(1),
+            temporary_handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Restores the metadata serialized by snapshot(). The indices which are derived from it are rebuilt
+    pub fn from_snapshot(data: &[u8], case_insensitive: bool) -> MetadataStorage {
+        let snapshot = flatbuffers::get_root::<MetadataSnapshot>(data);
+
+        let mut metadata = HashMap::new();
+        let inodes = snapshot.inodes();
+        for i in 0..inodes.len() {
+            let entry = inodes.get(i);
+            let mut xattrs = HashMap::new();
+            let entry_xattrs = entry.xattrs();
+            for j in 0..entry_xattrs.len() {
+                let xattr = entry_xattrs.get(j);
+                xattrs.insert(xattr.key().to_string(), xattr.value().to_vec());
+            }
+            metadata.insert(
+                entry.inode(),
+                InodeAttributes {
+                    inode: entry.inode(),
+                    size: entry.size(),
+                    last_accessed: *entry.last_accessed(),
+                    last_modified: *entry.last_modified(),
+                    last_metadata_changed: *entry.last_metadata_changed(),
+                    kind: entry.kind(),
+                    mode: entry.mode(),
+                    hardlinks: entry.hardlinks(),
+                    redundancy: entry.redundancy(),
+                    retention: entry.retention(),
+                    retained_until: entry.retained_until(),
+                    uid: entry.uid(),
+                    gid: entry.gid(),
+                    xattrs,
+                    dos_attributes: entry.dos_attributes(),
+                    created: *entry.created(),
+                    data_version: entry.data_version(),
+                    fencing_token: entry.fencing_token(),
+                },
+            );
+        }
+
+        let mut directories = HashMap::new();
+        let mut directory_changes = HashMap::new();
+        let mut directory_parents = HashMap::new();
+        directory_parents.insert(ROOT_INODE, ROOT_INODE);
+        let mut file_parents: HashMap<Inode, Vec<(Inode, String)>> = HashMap::new();
+        let snapshot_directories = snapshot.directories();
+        for i in 0..snapshot_directories.len() {
+            let directory = snapshot_directories.get(i);
+            let mut descriptor = DirectoryDescriptor::new(case_insensitive);
+            let entries = directory.entries();
+            for j in 0..entries.len() {
+                let entry = entries.get(j);
+                descriptor.insert(entry.name().to_string(), (entry.inode(), entry.kind()));
+                if entry.kind() == FileKind::Directory {
+                    directory_parents.insert(entry.inode(), directory.inode());
+                } else {
+                    file_parents
+                        .entry(entry.inode())
+                        .or_insert_with(Vec::new)
+                        .push((directory.inode(), entry.name().to_string()));
+                }
+            }
+            directories.insert(directory.inode(), descriptor);
+            // Older changes are not included, so listings since an earlier version won't be deltas
+            if directory.version() > 0 {
+                directory_changes.insert(
+                    directory.inode(),
+                    DirectoryChanges {
+                        version: directory.version(),
+                        log: VecDeque::new(),
+                    },
+                );
+            }
+        }
+        let tree_usage =
+            compute_tree_usage(&directories, &directory_parents, &file_parents, &metadata);
+        let mut frozen = HashMap::new();
+        let snapshot_frozen = snapshot.frozen();
+        for i in 0..snapshot_frozen.len() {
+            let subtree = snapshot_frozen.get(i);
+            frozen.insert(subtree.inode(), *subtree.thawed_at());
+        }
+        let mut temporary_handles: HashMap<Inode, HashMap<u64, u32>> = HashMap::new();
+        let snapshot_handles = snapshot.temporary_handles();
+        for i in 0..snapshot_handles.len() {
+            let entry = snapshot_handles.get(i);
+            temporary_handles
+                .entry(entry.inode())
+                .or_insert_with(HashMap::new)
+                .insert(entry.session_id(), entry.handles());
+        }
+
+        MetadataStorage {
+            metadata: Mutex::new(metadata),
+            directories: Mutex::new(directories),
+            directory_changes: Mutex::new(directory_changes),
+            directory_parents: Mutex::new(directory_parents),
+            file_parents: Mutex::new(file_parents),
+            tree_usage: Mutex::new(tree_usage),
+            next_inode: AtomicU64::new(snapshot.next_inode()),
+            case_insensitive,
+            operation_time: Mutex::new(snapshot.operation_time().cloned()),
+            limits: Mutex::new(*snapshot.limits()),
+            frozen: Mutex::new(frozen),
+            next_client_id: AtomicU64::new(snapshot.next_client_id()),
+            temporary_handles: Mutex::new(temporary_handles),
+        }
+    }
+
+    pub fn snapshot(&self) -> Result<Vec<u8>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut builder = FlatBufferBuilder::new();
+
+        let mut inodes = vec![];
+        for attributes in metadata.values() {
+            let mut xattrs = vec![];
+            for (key, value) in attributes.xattrs.iter() {
+                let key = builder.create_string(key);
+                let value = builder.create_vector_direct(value);
+                xattrs.push(XattrSnapshot::create(
+                    &mut builder,
+                    &XattrSnapshotArgs {
+                        key: Some(key),
+                        value: Some(value),
+                    },
+                ));
+            }
+            let xattrs = builder.create_vector(&xattrs);
+            inodes.push(InodeSnapshot::create(
+                &mut builder,
+                &InodeSnapshotArgs {
+                    inode: attributes.inode,
+                    size: attributes.size,
+                    last_accessed: Some(&attributes.last_accessed),
+                    last_modified: Some(&attributes.last_modified),
+                    last_metadata_changed: Some(&attributes.last_metadata_changed),
+                    kind: attributes.kind,
+                    mode: attributes.mode,
+                    hardlinks: attributes.hardlinks,
+                    redundancy: attributes.redundancy,
+                    retention: attributes.retention,
+                    retained_until: attributes.retained_until,
+                    uid: attributes.uid,
+                    gid: attributes.gid,
+                    xattrs: Some(xattrs),
+                    dos_attributes: attributes.dos_attributes,
+                    created: Some(&attributes.created),
+                    data_version: attributes.data_version,
+                    fencing_token: attributes.fencing_token,
+                },
+            ));
+        }
+        let inodes = builder.create_vector(&inodes);
+
+        let mut snapshot_directories = vec![];
+        for (inode, descriptor) in directories.iter() {
+            let mut entries = vec![];
+            for (name, (entry_inode, kind)) in descriptor.iter() {
+                let name = builder.create_string(name);
+                entries.push(DirectoryEntrySnapshot::create(
+                    &mut builder,
+                    &DirectoryEntrySnapshotArgs {
+                        name: Some(name),
+                        inode: *entry_inode,
+                        kind: *kind,
+                    },
+                ));
+            }
+            let entries = builder.create_vector(&entries);
+            snapshot_directories.push(DirectorySnapshot::create(
+                &mut builder,
+                &DirectorySnapshotArgs {
+                    inode: *inode,
+                    entries: Some(entries),
+                    version: directory_changes.get(inode).map_or(0, |x| x.version),
+                },
+            ));
+        }
+        let snapshot_directories = builder.create_vector(&snapshot_directories);
+
+        let operation_time = *self
+            .operation_time
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let limits = self.limits();
+        let mut frozen = vec![];
+        for (inode, thawed_at) in self.frozen.lock().map_err(|_| ErrorCode::Corrupted)?.iter() {
+            frozen.push(FrozenSubtreeSnapshot::create(
+                &mut builder,
+                &FrozenSubtreeSnapshotArgs {
+                    inode: *inode,
+                    thawed_at: Some(thawed_at),
+                },
+            ));
         }
+        let frozen = builder.create_vector(&frozen);
+        let mut temporary_handles = vec![];
+        for (inode, sessions) in self
+            .temporary_handles
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?
+            .iter()
+        {
+            for (session_id, handles) in sessions.iter() {
+                temporary_handles.push(TemporaryHandlesSnapshot::create(
+                    &mut builder,
+                    &TemporaryHandlesSnapshotArgs {
+                        inode: *inode,
+                        session_id: *session_id,
+                        handles: *handles,
+                    },
+                ));
+            }
+        }
+        let temporary_handles = builder.create_vector(&temporary_handles);
+        let root = MetadataSnapshot::create(
+            &mut builder,
+            &MetadataSnapshotArgs {
+                next_inode: self.next_inode.load(Ordering::SeqCst),
+                inodes: Some(inodes),
+                directories: Some(snapshot_directories),
+                operation_time: operation_time.as_ref(),
+                limits: Some(&limits),
+                frozen: Some(frozen),
+                next_client_id: self.next_client_id.load(Ordering::SeqCst),
+                temporary_handles: Some(temporary_handles),
+            },
+        );
+        builder.finish(root, None);
+
+        Ok(builder.finished_data().to_vec())
+    }
+
+    // Returns the redundancy of every inode that isn't a directory
+    pub fn file_redundancies(&self) -> Result<Vec<(Inode, u8)>, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        Ok(metadata
+            .values()
+            .filter(|x| x.kind != FileKind::Directory)
+            .map(|x| (x.inode, x.redundancy))
+            .collect())
     }
 
     pub fn lookup(
@@ -89,33 +619,70 @@ impl MetadataStorage {
         name: &str,
         context: UserContext,
     ) -> Result<Option<Inode>, ErrorCode> {
+        // Names which exceed the configured limit may have been created before it was lowered
         if name.len() > MAX_NAME_LENGTH as usize {
             return Err(ErrorCode::NameTooLong);
         }
 
         let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
         let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
-        let parent_attrs = metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?;
-        if !check_access(
-            parent_attrs.uid,
-            parent_attrs.gid,
-            parent_attrs.mode,
-            context.uid(),
-            context.gid(),
-            libc::X_OK as u32,
-        ) {
-            return Err(ErrorCode::AccessDenied);
-        }
+        check_searchable(
+            metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?,
+            context,
+        )?;
 
         let maybe_inode = directories[&parent].get(name).map(|(inode, _)| *inode);
         Ok(maybe_inode)
     }
 
+    // Resolves one component of a path, in directory. Unlike lookup(), "." and ".." are handled,
+    // and the kind of the entry is returned
+    pub fn resolve(
+        &self,
+        directory: Inode,
+        name: &str,
+        context: UserContext,
+    ) -> Result<(Inode, FileKind), ErrorCode> {
+        if name.len() > MAX_NAME_LENGTH as usize {
+            return Err(ErrorCode::NameTooLong);
+        }
+
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        check_searchable(
+            metadata
+                .get(&directory)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+            context,
+        )?;
+
+        match name {
+            "." => Ok((directory, FileKind::Directory)),
+            // The root is its own parent
+            ".." if directory == ROOT_INODE => Ok((ROOT_INODE, FileKind::Directory)),
+            ".." => {
+                let parent = *parents.get(&directory).ok_or(ErrorCode::Corrupted)?;
+                Ok((parent, FileKind::Directory))
+            }
+            _ => directories
+                .get(&directory)
+                .ok_or(ErrorCode::InodeDoesNotExist)?
+                .get(name)
+                .cloned()
+                .ok_or(ErrorCode::DoesNotExist),
+        }
+    }
+
     pub fn read(&self, inode: Inode, context: UserContext) -> Result<(), ErrorCode> {
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_not_directory(inode_attrs)?;
         if !check_access(
             inode_attrs.uid,
             inode_attrs.gid,
@@ -130,6 +697,46 @@ impl MetadataStorage {
         Ok(())
     }
 
+    // Whether a read should update the access time, in relatime mode
+    pub fn needs_atime_update(&self, inode: Inode) -> Result<bool, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        let accessed = (
+            inode_attrs.last_accessed.seconds(),
+            inode_attrs.last_accessed.nanos(),
+        );
+        let modified = (
+            inode_attrs.last_modified.seconds(),
+            inode_attrs.last_modified.nanos(),
+        );
+        let changed = (
+            inode_attrs.last_metadata_changed.seconds(),
+            inode_attrs.last_metadata_changed.nanos(),
+        );
+
+        Ok(accessed <= modified
+            || accessed <= changed
+            || now().seconds() - accessed.0 >= RELATIME_INTERVAL_SECS)
+    }
+
+    // Access time updates don't change ctime, and never move the access time backwards
+    pub fn update_atime(&self, inode: Inode, atime: Timestamp) -> Result<(), ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        if (atime.seconds(), atime.nanos())
+            > (
+                inode_attrs.last_accessed.seconds(),
+                inode_attrs.last_accessed.nanos(),
+            )
+        {
+            inode_attrs.last_accessed = atime;
+        }
+
+        Ok(())
+    }
+
     pub fn get_xattr(&self, inode: Inode, key: &str) -> Result<Vec<u8>, ErrorCode> {
         let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
         if let Some(value) = metadata
@@ -154,12 +761,15 @@ impl MetadataStorage {
     }
 
     pub fn set_xattr(&self, inode: Inode, key: &str, value: &[u8]) -> Result<(), ErrorCode> {
+        if value.len() > self.limits().max_xattr_size() as usize {
+            return Err(ErrorCode::XattrTooLarge);
+        }
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
         inode_attrs.xattrs.insert(key.to_string(), value.to_vec());
-        inode_attrs.last_metadata_changed = now();
+        mark_changed(inode_attrs, self.time());
 
         Ok(())
     }
@@ -170,130 +780,398 @@ impl MetadataStorage {
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
         inode_attrs.xattrs.remove(key);
-        inode_attrs.last_metadata_changed = now();
+        mark_changed(inode_attrs, self.time());
 
         Ok(())
     }
 
-    pub fn readdir(&self, inode: Inode) -> Result<Vec<(Inode, String, FileKind)>, ErrorCode> {
-        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
-        let parents = self
-            .directory_parents
-            .lock()
-            .map_err(|_| ErrorCode::Corrupted)?;
-
-        if let Some(entries) = directories.get(&inode) {
-            let parent_inode = *parents.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
-            let mut result: Vec<(Inode, String, FileKind)> = entries
-                .iter()
-                .map(|(name, (inode, kind))| (*inode, name.clone(), *kind))
-                .collect();
-            // TODO: kind of a hack
-            result.insert(0, (parent_inode, "..".to_string(), FileKind::Directory));
-            result.insert(0, (inode, ".".to_string(), FileKind::Directory));
-            Ok(result)
-        } else {
-            Err(ErrorCode::DoesNotExist)
-        }
-    }
-
-    pub fn utimens(
+    // Files can only be changed while they're empty, since their existing data isn't re-laid out.
+    // Changing it on a directory only affects entries created in it afterwards. Only the owner and root can set it
+    pub fn set_redundancy(
         &self,
         inode: Inode,
-        atime: Option<&Timestamp>,
-        mtime: Option<&Timestamp>,
+        redundancy: u8,
         context: UserContext,
     ) -> Result<(), ErrorCode> {
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
-
-        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
-        if inode_attrs.uid != context.uid()
-            && !check_access(
-                inode_attrs.uid,
-                inode_attrs.gid,
-                inode_attrs.mode,
-                context.uid(),
-                context.gid(),
-                libc::W_OK as u32,
-            )
-        {
-            return Err(ErrorCode::AccessDenied);
+        let inode_attrs = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        if context.uid() != 0 && inode_attrs.uid != context.uid() {
+            return Err(ErrorCode::OperationNotPermitted);
         }
-
-        if let Some(inode_metadata) = metadata.get_mut(&inode) {
-            // Non-owners are only allowed to change atime & mtime to current:
-            // http://man7.org/linux/man-pages/man2/utimensat.2.html
-            if inode_metadata.uid != context.uid()
-                && context.uid() != 0
-                && (atime.map_or(libc::UTIME_NOW as i32, Timestamp::nanos)
-                    != libc::UTIME_NOW as i32
-                    || mtime.map_or(libc::UTIME_NOW as i32, Timestamp::nanos)
-                        != libc::UTIME_NOW as i32)
-            {
-                return Err(ErrorCode::OperationNotPermitted);
-            }
-
-            if let Some(atime) = atime {
-                if atime.nanos() == libc::UTIME_NOW as i32 {
-                    // TODO: this should be set during proposal. Currently each node set its own timestamp
-                    inode_metadata.last_accessed = now();
-                } else {
-                    inode_metadata.last_accessed = *atime;
-                }
-            }
-            if let Some(mtime) = mtime {
-                if mtime.nanos() == libc::UTIME_NOW as i32 {
-                    // TODO: this should be set during proposal. Currently each node set its own timestamp
-                    inode_metadata.last_modified = now();
-                } else {
-                    inode_metadata.last_modified = *mtime;
-                }
-            }
-
-            Ok(())
-        } else {
-            Err(ErrorCode::DoesNotExist)
+        if inode_attrs.kind != FileKind::Directory && inode_attrs.size > 0 {
+            return Err(ErrorCode::NotSupported);
         }
+        inode_attrs.redundancy = redundancy;
+        mark_changed(inode_attrs, self.time());
+
+        Ok(())
     }
 
-    pub fn chmod(
+    // Only affects files released afterwards, and entries created afterwards in directories. Only the owner and root
+    // can set it, and only root can lower it, since otherwise the owner could delete the files early
+    pub fn set_retention(
         &self,
         inode: Inode,
-        mut mode: u32,
+        retention: u64,
         context: UserContext,
     ) -> Result<(), ErrorCode> {
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
-        if context.uid() != 0 && inode_attrs.uid != context.uid() {
+        if context.uid() != 0
+            && (inode_attrs.uid != context.uid() || retention < inode_attrs.retention)
+        {
             return Err(ErrorCode::OperationNotPermitted);
         }
-
-        // TODO: suid/sgid not supported
-        mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
-        inode_attrs.mode = mode as u16;
-        inode_attrs.last_metadata_changed = now();
+        inode_attrs.retention = retention;
+        mark_changed(inode_attrs, self.time());
 
         Ok(())
     }
 
-    pub fn chown(
-        &self,
-        inode: Inode,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        context: UserContext,
-    ) -> Result<(), ErrorCode> {
+    // Returns (retention, retained_until)
+    pub fn get_retention(&self, inode: Inode) -> Result<(u64, i64), ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        Ok((inode_attrs.retention, inode_attrs.retained_until))
+    }
+
+    // Bits outside of the supported DOS attributes are ignored
+    pub fn set_dos_attributes(&self, inode: Inode, dos_attributes: u8) -> Result<(), ErrorCode> {
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
-        let inode_metadata = metadata
+        let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        inode_attrs.dos_attributes = dos_attributes & DOS_ATTRIBUTES_MASK;
+        mark_changed(inode_attrs, self.time());
 
-        // Only root can change uid
-        if let Some(uid) = uid {
-            if context.uid() != 0
-                // but no-op changes by the owner are not an error
+        Ok(())
+    }
+
+    pub fn set_created(&self, inode: Inode, created: Timestamp) -> Result<(), ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        inode_attrs.created = created;
+        mark_changed(inode_attrs, self.time());
+
+        Ok(())
+    }
+
+    // Returns (dos_attributes, created)
+    pub fn get_dos_attributes(&self, inode: Inode) -> Result<(u8, Timestamp), ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        Ok((inode_attrs.dos_attributes, inode_attrs.created))
+    }
+
+    // Called when a file that was opened for writing, or a temporary file, is closed. Returns the inode, if it was
+    // the last handle of a temporary file which was never linked into a directory, and so it was deleted
+    pub fn release(&self, inode: Inode, session_id: u64) -> Result<Option<Inode>, ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        if is_temporary(inode_attrs) {
+            let mut temporary_handles = self
+                .temporary_handles
+                .lock()
+                .map_err(|_| ErrorCode::Corrupted)?;
+            let sessions = temporary_handles
+                .get_mut(&inode)
+                .ok_or(ErrorCode::Corrupted)?;
+            if let Some(handles) = sessions.get_mut(&session_id) {
+                *handles -= 1;
+                if *handles == 0 {
+                    sessions.remove(&session_id);
+                }
+            }
+            if !sessions.is_empty() {
+                return Ok(None);
+            }
+            temporary_handles.remove(&inode);
+            metadata.remove(&inode);
+            return Ok(Some(inode));
+        }
+        if inode_attrs.kind != FileKind::Directory
+            && inode_attrs.retention > 0
+            && inode_attrs.retained_until == 0
+        {
+            inode_attrs.retained_until = self.time().seconds() + inode_attrs.retention as i64;
+            mark_changed(inode_attrs, self.time());
+        }
+
+        Ok(None)
+    }
+
+    // Registers another handle of the session on a temporary file, which keeps it until that handle is released too.
+    // Does nothing if the file was linked into a directory
+    pub fn open_temporary(&self, inode: Inode, session_id: u64) -> Result<(), ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        if is_temporary(inode_attrs) {
+            *self
+                .temporary_handles
+                .lock()
+                .map_err(|_| ErrorCode::Corrupted)?
+                .entry(inode)
+                .or_insert_with(HashMap::new)
+                .entry(session_id)
+                .or_insert(0) += 1;
+        }
+
+        Ok(())
+    }
+
+    // Releases every handle of the session, which was evicted or whose lease expired. Returns the temporary files
+    // which were deleted, because no other session holds them
+    pub fn end_session(&self, session_id: u64) -> Result<Vec<Inode>, ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut temporary_handles = self
+            .temporary_handles
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let mut deleted = vec![];
+        for (inode, sessions) in temporary_handles.iter_mut() {
+            if sessions.remove(&session_id).is_some() && sessions.is_empty() {
+                deleted.push(*inode);
+            }
+        }
+        deleted.sort();
+        for inode in deleted.iter() {
+            temporary_handles.remove(inode);
+            metadata.remove(inode);
+        }
+
+        Ok(deleted)
+    }
+
+    // Sessions which hold a handle of a temporary file
+    pub fn temporary_sessions(&self) -> Vec<u64> {
+        let mut sessions: Vec<u64> = self
+            .temporary_handles
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|x| x.keys().cloned())
+            .collect();
+        sessions.sort();
+        sessions.dedup();
+
+        sessions
+    }
+
+    pub fn is_temporary(&self, inode: Inode) -> Result<bool, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        Ok(is_temporary(inode_attrs))
+    }
+
+    pub fn get_redundancy(&self, inode: Inode) -> Result<u8, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        metadata
+            .get(&inode)
+            .map(|x| x.redundancy)
+            .ok_or(ErrorCode::InodeDoesNotExist)
+    }
+
+    // Returns the version of the directory, and its entries
+    pub fn readdir(
+        &self,
+        inode: Inode,
+    ) -> Result<(u64, Vec<(Inode, String, FileKind)>), ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+
+        if let Some(entries) = directories.get(&inode) {
+            let parent_inode = *parents.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+            let mut result: Vec<(Inode, String, FileKind)> = entries
+                .iter()
+                .map(|(name, (inode, kind))| (*inode, name.clone(), *kind))
+                .collect();
+            // TODO: kind of a hack
+            result.insert(0, (parent_inode, "..".to_string(), FileKind::Directory));
+            result.insert(0, (inode, ".".to_string(), FileKind::Directory));
+            let version = directory_changes.get(&inode).map_or(0, |x| x.version);
+            Ok((version, result))
+        } else {
+            Err(self.missing_directory_error(inode)?)
+        }
+    }
+
+    pub fn directory_version(&self, inode: Inode) -> Result<u64, ErrorCode> {
+        let directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        Ok(directory_changes.get(&inode).map_or(0, |x| x.version))
+    }
+
+    // Returns the changes to the directory since version, or None if they're no longer known
+    pub fn readdir_since(
+        &self,
+        inode: Inode,
+        version: u64,
+    ) -> Result<Option<DirectoryDelta>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        if !directories.contains_key(&inode) {
+            return Err(self.missing_directory_error(inode)?);
+        }
+
+        let changes = directory_changes.get(&inode);
+        let current_version = changes.map_or(0, |x| x.version);
+        if version == current_version {
+            return Ok(Some(DirectoryDelta {
+                version,
+                added: vec![],
+                removed: vec![],
+            }));
+        }
+        let changes = match changes {
+            Some(changes) => changes,
+            None => return Ok(None),
+        };
+        let oldest_known = changes.log.front().map_or(current_version + 1, |x| x.0);
+        if version > current_version || oldest_known > version + 1 {
+            return Ok(None);
+        }
+
+        // Only the latest change to each name matters
+        let mut latest: HashMap<&str, Option<(Inode, FileKind)>> = HashMap::new();
+        for (change_version, name, entry) in changes.log.iter() {
+            if *change_version > version {
+                latest.insert(name, *entry);
+            }
+        }
+        let mut delta = DirectoryDelta {
+            version: current_version,
+            added: vec![],
+            removed: vec![],
+        };
+        for (name, entry) in latest {
+            match entry {
+                Some((entry_inode, kind)) => {
+                    delta.added.push((entry_inode, name.to_string(), kind))
+                }
+                None => delta.removed.push(name.to_string()),
+            }
+        }
+
+        Ok(Some(delta))
+    }
+
+    pub fn utimens(
+        &self,
+        inode: Inode,
+        atime: Option<&Timestamp>,
+        mtime: Option<&Timestamp>,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        if inode_attrs.uid != context.uid()
+            && !check_access(
+                inode_attrs.uid,
+                inode_attrs.gid,
+                inode_attrs.mode,
+                context.uid(),
+                context.gid(),
+                libc::W_OK as u32,
+            )
+        {
+            return Err(ErrorCode::AccessDenied);
+        }
+
+        if let Some(inode_metadata) = metadata.get_mut(&inode) {
+            // Non-owners are only allowed to change atime & mtime to current:
+            // http://man7.org/linux/man-pages/man2/utimensat.2.html
+            if inode_metadata.uid != context.uid()
+                && context.uid() != 0
+                && (atime.map_or(libc::UTIME_NOW as i32, Timestamp::nanos)
+                    != libc::UTIME_NOW as i32
+                    || mtime.map_or(libc::UTIME_NOW as i32, Timestamp::nanos)
+                        != libc::UTIME_NOW as i32)
+            {
+                return Err(ErrorCode::OperationNotPermitted);
+            }
+
+            let time = self.time();
+            if let Some(atime) = atime {
+                if atime.nanos() == libc::UTIME_NOW as i32 {
+                    inode_metadata.last_accessed = time;
+                } else {
+                    inode_metadata.last_accessed = *atime;
+                }
+            }
+            if let Some(mtime) = mtime {
+                if mtime.nanos() == libc::UTIME_NOW as i32 {
+                    inode_metadata.last_modified = time;
+                } else {
+                    inode_metadata.last_modified = *mtime;
+                }
+            }
+            mark_changed(inode_metadata, time);
+
+            Ok(())
+        } else {
+            Err(ErrorCode::DoesNotExist)
+        }
+    }
+
+    pub fn chmod(
+        &self,
+        inode: Inode,
+        mut mode: u32,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        if context.uid() != 0 && inode_attrs.uid != context.uid() {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
+
+        // TODO: suid/sgid not supported
+        mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+        inode_attrs.mode = mode as u16;
+        mark_changed(inode_attrs, self.time());
+
+        Ok(())
+    }
+
+    pub fn chown(
+        &self,
+        inode: Inode,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        context: UserContext,
+    ) -> Result<(), ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_metadata = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+
+        // Only root can change uid
+        if let Some(uid) = uid {
+            if context.uid() != 0
+                // but no-op changes by the owner are not an error
                 && !(uid == inode_metadata.uid && context.uid() == inode_metadata.uid)
             {
                 return Err(ErrorCode::OperationNotPermitted);
@@ -311,7 +1189,7 @@ impl MetadataStorage {
             inode_metadata.gid = gid;
         }
         if uid.is_some() || gid.is_some() {
-            inode_metadata.last_metadata_changed = now();
+            mark_changed(inode_metadata, self.time());
         }
 
         Ok(())
@@ -324,34 +1202,84 @@ impl MetadataStorage {
         new_name: &str,
         context: UserContext,
     ) -> Result<(), ErrorCode> {
+        self.check_name(new_name)?;
+
         let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let mut file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let inode_attrs = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        // Directories can't have more than one link, like on Linux
+        if inode_attrs.kind == FileKind::Directory {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
+        if inode_attrs.hardlinks >= MAX_HARDLINKS {
+            return Err(ErrorCode::TooManyLinks);
+        }
         let new_parent_attrs = metadata
             .get_mut(&new_parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_directory(new_parent_attrs)?;
         if !check_access(
             new_parent_attrs.uid,
             new_parent_attrs.gid,
             new_parent_attrs.mode,
             context.uid(),
             context.gid(),
-            libc::W_OK as u32,
+            (libc::W_OK | libc::X_OK) as u32,
         ) {
             return Err(ErrorCode::AccessDenied);
         }
-        new_parent_attrs.last_modified = now();
-        new_parent_attrs.last_metadata_changed = now();
+        if directories
+            .get(&new_parent)
+            .ok_or(ErrorCode::InodeDoesNotExist)?
+            .get(new_name)
+            .is_some()
+        {
+            return Err(ErrorCode::AlreadyExists);
+        }
+        let time = self.time();
+        mark_modified(new_parent_attrs, time);
 
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        // Once it's linked, a temporary file is kept when its handles are released
+        if is_temporary(inode_attrs) {
+            self.temporary_handles
+                .lock()
+                .map_err(|_| ErrorCode::Corrupted)?
+                .remove(&inode);
+        }
         inode_attrs.hardlinks += 1;
-        inode_attrs.last_metadata_changed = now();
+        mark_changed(inode_attrs, time);
 
         directories
             .get_mut(&new_parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .insert(new_name.to_string(), (inode, inode_attrs.kind));
+        record_change(
+            &mut directory_changes,
+            new_parent,
+            new_name,
+            Some((inode, inode_attrs.kind)),
+        );
+
+        let before = containing_directories(&parents, &file_parents, inode);
+        file_parents
+            .entry(inode)
+            .or_insert_with(Vec::new)
+            .push((new_parent, new_name.to_string()));
+        let after = containing_directories(&parents, &file_parents, inode);
+        update_file_usage(&mut tree_usage, &before, &after, inode_attrs.size);
 
         Ok(())
     }
@@ -364,56 +1292,93 @@ impl MetadataStorage {
         gid: u32,
         mode: u16,
     ) -> Result<(), ErrorCode> {
+        self.check_name(name)?;
+
         let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
         let mut parents = self
             .directory_parents
             .lock()
             .map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let parent_attrs = metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_directory(parent_attrs)?;
         if !check_access(
             parent_attrs.uid,
             parent_attrs.gid,
             parent_attrs.mode,
             uid,
             gid,
-            libc::W_OK as u32,
+            (libc::W_OK | libc::X_OK) as u32,
         ) {
             return Err(ErrorCode::AccessDenied);
         }
+        self.check_inode_available(&metadata)?;
+        if directory_depth(&parents, parent)? >= MAX_DIRECTORY_DEPTH {
+            return Err(ErrorCode::NameTooLong);
+        }
+        if directories
+            .get(&parent)
+            .ok_or(ErrorCode::InodeDoesNotExist)?
+            .get(name)
+            .is_some()
+        {
+            return Err(ErrorCode::AlreadyExists);
+        }
+        let redundancy = parent_attrs.redundancy;
+        let retention = parent_attrs.retention;
 
         let inode = self.allocate_inode();
         directories
             .get_mut(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .insert(name.to_string(), (inode, FileKind::Directory));
-        directories.insert(inode, HashMap::new());
+        directories.insert(inode, DirectoryDescriptor::new(self.case_insensitive));
+        record_change(
+            &mut directory_changes,
+            parent,
+            name,
+            Some((inode, FileKind::Directory)),
+        );
 
         parents.insert(inode, parent);
 
+        tree_usage.insert(inode, TreeUsage::default());
+        update_tree_usage(&mut tree_usage, &parents, parent, 0, 1);
+
+        let time = self.time();
         let inode_metadata = InodeAttributes {
             inode,
             size: BLOCK_SIZE,
-            last_accessed: now(),
-            last_modified: now(),
-            last_metadata_changed: now(),
+            last_accessed: time,
+            last_modified: time,
+            last_metadata_changed: time,
             kind: FileKind::Directory,
             // TODO: suid/sgid not supported
             mode: mode & !(libc::S_ISUID | libc::S_ISGID) as u16,
             hardlinks: 2,
+            redundancy,
+            retention,
+            retained_until: 0,
             uid,
             gid,
             xattrs: Default::default(),
+            dos_attributes: 0,
+            created: time,
+            data_version: 0,
+            fencing_token: 0,
         };
         metadata.insert(inode, inode_metadata);
-        metadata
-            .get_mut(&parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_metadata_changed = now();
-        metadata
-            .get_mut(&parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_modified = now();
+        mark_modified(
+            metadata
+                .get_mut(&parent)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+            time,
+        );
 
         Ok(())
     }
@@ -425,13 +1390,27 @@ impl MetadataStorage {
         new_parent: u64,
         new_name: &str,
         context: UserContext,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<Option<Inode>, ErrorCode> {
+        self.check_name(new_name)?;
+
         let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
         let mut parents = self
             .directory_parents
             .lock()
             .map_err(|_| ErrorCode::Corrupted)?;
+        let mut file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        check_directory(metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?)?;
+        check_directory(
+            metadata
+                .get(&new_parent)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+        )?;
         if let Some((inode, _)) = directories
             .get(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
@@ -444,7 +1423,7 @@ impl MetadataStorage {
                 parent_attrs.mode,
                 context.uid(),
                 context.gid(),
-                libc::W_OK as u32,
+                (libc::W_OK | libc::X_OK) as u32,
             ) {
                 return Err(ErrorCode::AccessDenied);
             }
@@ -469,7 +1448,7 @@ impl MetadataStorage {
                 new_parent_attrs.mode,
                 context.uid(),
                 context.gid(),
-                libc::W_OK as u32,
+                (libc::W_OK | libc::X_OK) as u32,
             ) {
                 return Err(ErrorCode::AccessDenied);
             }
@@ -493,15 +1472,41 @@ impl MetadataStorage {
                 }
             }
 
-            // Only overwrite an existing directory if it's empty
+            // Renaming a link onto another link of the same file does nothing, like on Linux
+            let new_parent_directory = directories
+                .get(&new_parent)
+                .ok_or(ErrorCode::InodeDoesNotExist)?;
+            if let Some((new_inode, _)) = new_parent_directory.get(new_name) {
+                let same_entry = parent == new_parent
+                    && new_parent_directory.stored_name(new_name)
+                        == new_parent_directory.stored_name(name);
+                if new_inode == inode && !same_entry {
+                    return Ok(None);
+                }
+            }
+
+            // Only overwrite an existing directory if it's empty. In case-insensitive volumes, the existing
+            // entry may be this one, if only the case of its name is changing
             if let Some((new_inode, _)) = directories
                 .get(&new_parent)
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .get(new_name)
+                .filter(|(new_inode, _)| new_inode != inode)
             {
                 let new_inode_attrs = metadata
                     .get(new_inode)
                     .ok_or(ErrorCode::InodeDoesNotExist)?;
+                // Directories can only replace directories, and files only non-directories
+                let kind = metadata
+                    .get(inode)
+                    .ok_or(ErrorCode::InodeDoesNotExist)?
+                    .kind;
+                if kind == FileKind::Directory && new_inode_attrs.kind != FileKind::Directory {
+                    return Err(ErrorCode::NotADirectory);
+                }
+                if kind != FileKind::Directory && new_inode_attrs.kind == FileKind::Directory {
+                    return Err(ErrorCode::IsADirectory);
+                }
                 if new_inode_attrs.kind == FileKind::Directory
                     && !directories
                         .get(&new_inode)
@@ -510,7 +1515,12 @@ impl MetadataStorage {
                 {
                     return Err(ErrorCode::NotEmpty);
                 }
+                check_retention(new_inode_attrs, self.time())?;
             }
+            check_retention(
+                metadata.get(inode).ok_or(ErrorCode::InodeDoesNotExist)?,
+                self.time(),
+            )?;
 
             // Only move an existing directory to a new parent, if we have write access to it,
             // because that will change the ".." link in it
@@ -528,45 +1538,166 @@ impl MetadataStorage {
             {
                 return Err(ErrorCode::AccessDenied);
             }
+
+            // A directory can't be moved into itself, or one of its descendants, since that would
+            // disconnect it from the tree
+            if inode_attrs.kind == FileKind::Directory
+                && parent != new_parent
+                && is_ancestor(&parents, *inode, new_parent)?
+            {
+                return Err(ErrorCode::InvalidArgument);
+            }
+
+            if inode_attrs.kind == FileKind::Directory
+                && parent != new_parent
+                && directory_depth(&parents, new_parent)?
+                    + 1
+                    + subtree_height(&directories, *inode)?
+                    > MAX_DIRECTORY_DEPTH
+            {
+                return Err(ErrorCode::NameTooLong);
+            }
         }
 
-        let entry = directories
+        let (stored_name, entry) = directories
             .get_mut(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .remove(name)
             .ok_or(ErrorCode::DoesNotExist)?;
-        directories
+        let replaced = directories
             .get_mut(&new_parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .insert(new_name.to_string(), entry);
+        record_change(&mut directory_changes, parent, &stored_name, None);
+        if let Some((ref replaced_name, _)) = replaced {
+            if replaced_name != new_name {
+                record_change(&mut directory_changes, new_parent, replaced_name, None);
+            }
+        }
+        record_change(&mut directory_changes, new_parent, new_name, Some(entry));
 
         let (inode, kind) = entry;
+        // Files are counted once in each directory containing any of their links. So the usage of the renamed or
+        // replaced file, and of files below a renamed directory which have other links, is updated by comparing those
+        // directories before and after. The rest of a directory's usage moves with it
+        let mut linked_files: Vec<Inode> = vec![];
+        if kind == FileKind::Directory {
+            linked_files.extend(
+                files_below(&directories, inode)
+                    .into_iter()
+                    .filter(|x| metadata.get(x).map_or(false, |x| x.hardlinks > 1)),
+            );
+            // Once for each of their links below it
+            linked_files.sort();
+            linked_files.dedup();
+        } else {
+            linked_files.push(inode);
+        }
+        if let Some((_, (replaced_inode, replaced_kind))) = replaced {
+            if replaced_kind != FileKind::Directory && !linked_files.contains(&replaced_inode) {
+                linked_files.push(replaced_inode);
+            }
+        }
+        let linked_usage: Vec<(Inode, u64, HashSet<Inode>)> = linked_files
+            .into_iter()
+            .map(|file| {
+                let size = metadata.get(&file).map_or(0, |x| x.size);
+                (
+                    file,
+                    size,
+                    containing_directories(&parents, &file_parents, file),
+                )
+            })
+            .collect();
+        let (bytes, inodes) = if kind == FileKind::Directory {
+            let (bytes, inodes) = entry_usage(&metadata, &tree_usage, inode, kind);
+            (
+                bytes - linked_usage.iter().map(|x| x.1 as i64).sum::<i64>(),
+                inodes - linked_usage.len() as i64,
+            )
+        } else {
+            (0, 0)
+        };
+        if kind == FileKind::Directory {
+            update_tree_usage(&mut tree_usage, &parents, parent, -bytes, -inodes);
+        }
+        let time = self.time();
+        let mut deleted = None;
+        if let Some((replaced_name, (replaced_inode, replaced_kind))) = replaced {
+            if replaced_kind == FileKind::Directory {
+                let (bytes, inodes) =
+                    entry_usage(&metadata, &tree_usage, replaced_inode, replaced_kind);
+                update_tree_usage(&mut tree_usage, &parents, new_parent, -bytes, -inodes);
+            } else {
+                remove_link(
+                    &mut file_parents,
+                    replaced_inode,
+                    new_parent,
+                    &replaced_name,
+                );
+            }
+            // The replaced entry loses a link, like in unlink() and rmdir()
+            if replaced_kind == FileKind::Directory {
+                directories.remove(&replaced_inode);
+                directory_changes.remove(&replaced_inode);
+                parents.remove(&replaced_inode);
+                tree_usage.remove(&replaced_inode);
+                metadata.remove(&replaced_inode);
+            } else {
+                let replaced_attrs = metadata
+                    .get_mut(&replaced_inode)
+                    .ok_or(ErrorCode::InodeDoesNotExist)?;
+                replaced_attrs.hardlinks -= 1;
+                mark_changed(replaced_attrs, time);
+                if replaced_attrs.hardlinks == 0 {
+                    metadata.remove(&replaced_inode);
+                    deleted = Some(replaced_inode);
+                }
+            }
+        }
         if kind == FileKind::Directory {
             parents.insert(inode, new_parent);
+            if parent != new_parent {
+                record_change(
+                    &mut directory_changes,
+                    inode,
+                    "..",
+                    Some((new_parent, FileKind::Directory)),
+                );
+            }
+            update_tree_usage(&mut tree_usage, &parents, new_parent, bytes, inodes);
+        } else {
+            remove_link(&mut file_parents, inode, parent, &stored_name);
+            file_parents
+                .entry(inode)
+                .or_insert_with(Vec::new)
+                .push((new_parent, new_name.to_string()));
+        }
+        for (file, size, before) in linked_usage {
+            let after = containing_directories(&parents, &file_parents, file);
+            update_file_usage(&mut tree_usage, &before, &after, size);
         }
 
-        metadata
-            .get_mut(&parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_metadata_changed = now();
-        metadata
-            .get_mut(&parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_modified = now();
-        metadata
-            .get_mut(&new_parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_metadata_changed = now();
-        metadata
-            .get_mut(&new_parent)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_modified = now();
-        metadata
-            .get_mut(&inode)
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .last_metadata_changed = now();
+        mark_modified(
+            metadata
+                .get_mut(&parent)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+            time,
+        );
+        mark_modified(
+            metadata
+                .get_mut(&new_parent)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+            time,
+        );
+        mark_changed(
+            metadata
+                .get_mut(&inode)
+                .ok_or(ErrorCode::InodeDoesNotExist)?,
+            time,
+        );
 
-        Ok(())
+        Ok(deleted)
     }
 
     pub fn truncate(
@@ -575,14 +1706,21 @@ impl MetadataStorage {
         new_length: u64,
         context: UserContext,
     ) -> Result<(), ErrorCode> {
-        if new_length > MAX_FILE_SIZE {
+        if new_length > self.limits().max_file_size() {
             return Err(ErrorCode::FileTooLarge);
         }
 
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_not_directory(inode_attrs)?;
         if !check_access(
             inode_attrs.uid,
             inode_attrs.gid,
@@ -593,10 +1731,20 @@ impl MetadataStorage {
         ) {
             return Err(ErrorCode::AccessDenied);
         }
+        check_retention(inode_attrs, self.time())?;
+        check_dos_readonly(inode_attrs)?;
 
+        let delta = new_length as i64 - inode_attrs.size as i64;
         inode_attrs.size = new_length;
-        inode_attrs.last_metadata_changed = now();
-        inode_attrs.last_modified = now();
+        inode_attrs.dos_attributes |= DOS_ARCHIVE;
+        mark_modified(inode_attrs, self.time());
+        inode_attrs.data_version += 1;
+
+        resize_file_usage(
+            &mut tree_usage,
+            &containing_directories(&parents, &file_parents, inode),
+            delta,
+        );
 
         Ok(())
     }
@@ -609,11 +1757,25 @@ impl MetadataStorage {
         context: UserContext,
     ) -> Result<Option<Inode>, ErrorCode> {
         let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let mut file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        check_directory(metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?)?;
         let parent_directory = directories
             .get_mut(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
-        let (inode, _) = parent_directory.get(name).ok_or(ErrorCode::DoesNotExist)?;
+        let (inode, kind) = parent_directory.get(name).ok_or(ErrorCode::DoesNotExist)?;
+        if *kind == FileKind::Directory {
+            return Err(ErrorCode::IsADirectory);
+        }
 
         let parent_attrs = metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?;
         if !check_access(
@@ -622,7 +1784,7 @@ impl MetadataStorage {
             parent_attrs.mode,
             context.uid(),
             context.gid(),
-            libc::W_OK as u32,
+            (libc::W_OK | libc::X_OK) as u32,
         ) {
             return Err(ErrorCode::AccessDenied);
         }
@@ -635,20 +1797,29 @@ impl MetadataStorage {
                 return Err(ErrorCode::AccessDenied);
             }
         }
+        check_retention(
+            metadata.get(inode).ok_or(ErrorCode::InodeDoesNotExist)?,
+            self.time(),
+        )?;
 
         let parent_attrs = metadata
             .get_mut(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
-        parent_attrs.last_metadata_changed = now();
-        parent_attrs.last_modified = now();
-        let (inode, _) = parent_directory
+        let time = self.time();
+        mark_modified(parent_attrs, time);
+        let (stored_name, (inode, _)) = parent_directory
             .remove(name)
             .ok_or(ErrorCode::DoesNotExist)?;
+        record_change(&mut directory_changes, parent, &stored_name, None);
         let inode_attrs = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
         inode_attrs.hardlinks -= 1;
-        inode_attrs.last_metadata_changed = now();
+        mark_changed(inode_attrs, time);
+        let before = containing_directories(&parents, &file_parents, inode);
+        remove_link(&mut file_parents, inode, parent, &stored_name);
+        let after = containing_directories(&parents, &file_parents, inode);
+        update_file_usage(&mut tree_usage, &before, &after, inode_attrs.size);
         if inode_attrs.hardlinks == 0 {
             metadata.remove(&inode);
             return Ok(Some(inode));
@@ -659,19 +1830,28 @@ impl MetadataStorage {
 
     pub fn rmdir(&self, parent: u64, name: &str, context: UserContext) -> Result<(), ErrorCode> {
         let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
         let mut parents = self
             .directory_parents
             .lock()
             .map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
-        if let Some((inode, _)) = directories
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        check_directory(metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?)?;
+        if let Some((inode, kind)) = directories
             .get(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .get(name)
         {
+            if *kind != FileKind::Directory {
+                return Err(ErrorCode::NotADirectory);
+            }
             if !directories
                 .get(&inode)
-                .map(HashMap::is_empty)
+                .map(DirectoryDescriptor::is_empty)
                 .unwrap_or(true)
             {
                 return Err(ErrorCode::NotEmpty);
@@ -683,7 +1863,7 @@ impl MetadataStorage {
                 parent_attrs.mode,
                 context.uid(),
                 context.gid(),
-                libc::W_OK as u32,
+                (libc::W_OK | libc::X_OK) as u32,
             ) {
                 return Err(ErrorCode::AccessDenied);
             }
@@ -698,24 +1878,28 @@ impl MetadataStorage {
                     return Err(ErrorCode::AccessDenied);
                 }
             }
+        } else {
+            return Err(ErrorCode::DoesNotExist);
         }
 
-        if let Some((inode, _)) = directories
+        if let Some((stored_name, (inode, _))) = directories
             .get_mut(&parent)
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .remove(name)
         {
             directories.remove(&inode);
+            directory_changes.remove(&inode);
+            record_change(&mut directory_changes, parent, &stored_name, None);
             metadata.remove(&inode);
-            metadata
-                .get_mut(&parent)
-                .ok_or(ErrorCode::InodeDoesNotExist)?
-                .last_metadata_changed = now();
-            metadata
-                .get_mut(&parent)
-                .ok_or(ErrorCode::InodeDoesNotExist)?
-                .last_modified = now();
+            mark_modified(
+                metadata
+                    .get_mut(&parent)
+                    .ok_or(ErrorCode::InodeDoesNotExist)?,
+                self.time(),
+            );
             parents.remove(&inode);
+            tree_usage.remove(&inode);
+            update_tree_usage(&mut tree_usage, &parents, parent, 0, -1);
         }
 
         Ok(())
@@ -728,10 +1912,48 @@ impl MetadataStorage {
         length: u32,
         context: UserContext,
     ) -> Result<(), ErrorCode> {
+        self.write_at(inode, offset, length, context, false)
+    }
+
+    // Returns the offset the data is appended at
+    pub fn append(
+        &self,
+        inode: Inode,
+        length: u32,
+        context: UserContext,
+    ) -> Result<u64, ErrorCode> {
+        let offset = self.get_attributes(inode)?.size;
+        self.write_at(inode, offset, length, context, true)?;
+        Ok(offset)
+    }
+
+    // Shared logs can only be appended to
+    fn write_at(
+        &self,
+        inode: Inode,
+        offset: u64,
+        length: u32,
+        context: UserContext,
+        append: bool,
+    ) -> Result<(), ErrorCode> {
+        if offset.saturating_add(u64::from(length)) > self.limits().max_file_size() {
+            return Err(ErrorCode::FileTooLarge);
+        }
+
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let inode_metadata = metadata
             .get_mut(&inode)
             .ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_not_directory(inode_metadata)?;
+        if inode_metadata.kind == FileKind::SharedLog && !append {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
         if !check_access(
             inode_metadata.uid,
             inode_metadata.gid,
@@ -742,11 +1964,21 @@ impl MetadataStorage {
         ) {
             return Err(ErrorCode::AccessDenied);
         }
+        check_retention(inode_metadata, self.time())?;
+        check_dos_readonly(inode_metadata)?;
 
         let current_length = inode_metadata.size;
         inode_metadata.size = max(current_length, u64::from(length) + offset);
-        inode_metadata.last_metadata_changed = now();
-        inode_metadata.last_modified = now();
+        inode_metadata.dos_attributes |= DOS_ARCHIVE;
+        mark_modified(inode_metadata, self.time());
+        inode_metadata.data_version += 1;
+
+        let delta = inode_metadata.size as i64 - current_length as i64;
+        resize_file_usage(
+            &mut tree_usage,
+            &containing_directories(&parents, &file_parents, inode),
+            delta,
+        );
 
         Ok(())
     }
@@ -760,12 +1992,23 @@ impl MetadataStorage {
         mode: u16,
         kind: FileKind,
     ) -> Result<(Inode, InodeAttributes), ErrorCode> {
+        self.check_name(name)?;
         if self
             .lookup(parent, name, UserContext::new(uid, gid))?
             .is_none()
         {
             let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+            let mut directory_changes = self
+                .directory_changes
+                .lock()
+                .map_err(|_| ErrorCode::Corrupted)?;
+            let parents = self
+                .directory_parents
+                .lock()
+                .map_err(|_| ErrorCode::Corrupted)?;
+            let mut file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
             let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+            let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
             let parent_attrs = metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?;
             if !check_access(
                 parent_attrs.uid,
@@ -777,42 +2020,118 @@ impl MetadataStorage {
             ) {
                 return Err(ErrorCode::AccessDenied);
             }
+            self.check_inode_available(&metadata)?;
+            let redundancy = parent_attrs.redundancy;
+            let retention = parent_attrs.retention;
 
             let inode = self.allocate_inode();
             directories
                 .get_mut(&parent)
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .insert(name.to_string(), (inode, FileKind::File));
+            record_change(
+                &mut directory_changes,
+                parent,
+                name,
+                Some((inode, FileKind::File)),
+            );
+            file_parents.insert(inode, vec![(parent, name.to_string())]);
+            update_tree_usage(&mut tree_usage, &parents, parent, 0, 1);
 
+            let time = self.time();
             let inode_metadata = InodeAttributes {
                 inode,
                 size: 0,
-                last_accessed: now(),
-                last_modified: now(),
-                last_metadata_changed: now(),
+                last_accessed: time,
+                last_modified: time,
+                last_metadata_changed: time,
                 kind,
                 // TODO: suid/sgid not supported
                 mode: mode & !(libc::S_ISUID | libc::S_ISGID) as u16,
                 hardlinks: 1,
+                redundancy,
+                retention,
+                retained_until: 0,
                 uid,
                 gid,
                 xattrs: Default::default(),
+                dos_attributes: 0,
+                created: time,
+                data_version: 0,
+                fencing_token: 0,
             };
             metadata.insert(inode, inode_metadata.clone());
-            metadata
-                .get_mut(&parent)
-                .ok_or(ErrorCode::InodeDoesNotExist)?
-                .last_metadata_changed = now();
-            metadata
-                .get_mut(&parent)
-                .ok_or(ErrorCode::InodeDoesNotExist)?
-                .last_modified = now();
+            mark_modified(
+                metadata
+                    .get_mut(&parent)
+                    .ok_or(ErrorCode::InodeDoesNotExist)?,
+                time,
+            );
             Ok((inode, inode_metadata))
         } else {
             Err(ErrorCode::AlreadyExists)
         }
     }
 
+    // Creates a file which isn't linked into any directory, like O_TMPFILE, and is open by the session. It's deleted
+    // when its last handle is released, unless it's hardlinked into a directory first. parent is only used for
+    // permissions and inherited attributes
+    pub fn create_temporary(
+        &self,
+        parent: Inode,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        session_id: u64,
+    ) -> Result<InodeAttributes, ErrorCode> {
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let parent_attrs = metadata.get(&parent).ok_or(ErrorCode::InodeDoesNotExist)?;
+        check_directory(parent_attrs)?;
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            uid,
+            gid,
+            libc::W_OK as u32,
+        ) {
+            return Err(ErrorCode::AccessDenied);
+        }
+        self.check_inode_available(&metadata)?;
+
+        let time = self.time();
+        let inode_metadata = InodeAttributes {
+            inode: self.allocate_inode(),
+            size: 0,
+            last_accessed: time,
+            last_modified: time,
+            last_metadata_changed: time,
+            kind: FileKind::File,
+            // TODO: suid/sgid not supported
+            mode: mode & !(libc::S_ISUID | libc::S_ISGID) as u16,
+            hardlinks: 0,
+            redundancy: parent_attrs.redundancy,
+            retention: parent_attrs.retention,
+            retained_until: 0,
+            uid,
+            gid,
+            xattrs: Default::default(),
+            dos_attributes: 0,
+            created: time,
+            data_version: 0,
+            fencing_token: 0,
+        };
+        metadata.insert(inode_metadata.inode, inode_metadata.clone());
+        let mut sessions = HashMap::new();
+        sessions.insert(session_id, 1);
+        self.temporary_handles
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?
+            .insert(inode_metadata.inode, sessions);
+
+        Ok(inode_metadata)
+    }
+
     pub fn get_attributes(&self, inode: Inode) -> Result<InodeAttributes, ErrorCode> {
         // TODO: find a way to avoid this clone()
         self.metadata
@@ -823,14 +2142,2231 @@ impl MetadataStorage {
             .ok_or(ErrorCode::DoesNotExist)
     }
 
-    fn allocate_inode(&self) -> u64 {
-        self.next_inode.fetch_add(1, Ordering::SeqCst)
+    // Returns the paths, relative to inode, and inodes of every entry below it which match the query,
+    // sorted by path. Directories that the user isn't allowed to search are skipped
+    pub fn find(
+        &self,
+        inode: Inode,
+        query: &FindQuery,
+        context: UserContext,
+    ) -> Result<Vec<(String, Inode)>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        let mut results = vec![];
+        let mut pending = vec![(inode, String::new())];
+        while let Some((directory, prefix)) = pending.pop() {
+            let directory_attrs = metadata
+                .get(&directory)
+                .ok_or(ErrorCode::InodeDoesNotExist)?;
+            if !check_access(
+                directory_attrs.uid,
+                directory_attrs.gid,
+                directory_attrs.mode,
+                context.uid(),
+                context.gid(),
+                (libc::R_OK | libc::X_OK) as u32,
+            ) {
+                continue;
+            }
+
+            let entries = directories
+                .get(&directory)
+                .ok_or(ErrorCode::InodeDoesNotExist)?;
+            for (name, (entry_inode, kind)) in entries.iter() {
+                let path = format!("{}/{}", prefix, name);
+                let entry_attrs = metadata.get(entry_inode).ok_or(ErrorCode::Corrupted)?;
+                if query.matches(name, entry_attrs) {
+                    results.push((path.clone(), *entry_inode));
+                }
+                if *kind == FileKind::Directory {
+                    pending.push((*entry_inode, path));
+                }
+            }
+        }
+        results.sort();
+
+        Ok(results)
     }
-}
 
-fn now() -> Timestamp {
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("System time before unix epoch");
-    Timestamp::new(now.as_secs() as i64, now.subsec_nanos() as i32)
+    // Expands an absolute path, whose components may contain the wildcards '*' and '?', like a shell does.
+    // Returns the paths which exist and their inodes, sorted. Like in a shell, wildcards don't match names
+    // which start with '.', and directories which the user can't search are skipped
+    pub fn glob(
+        &self,
+        pattern: &str,
+        context: UserContext,
+    ) -> Result<Vec<(String, Inode)>, ErrorCode> {
+        if !pattern.starts_with('/') || pattern.len() > MAX_PATH_LENGTH {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        let components: Vec<&str> = pattern
+            .split('/')
+            .filter(|x| !x.is_empty() && *x != ".")
+            .collect();
+        let mut matched = vec![(String::new(), ROOT_INODE)];
+        for (i, component) in components.iter().enumerate() {
+            let last = i == components.len() - 1;
+            let wildcard = component.contains(|x| x == '*' || x == '?');
+            let mut next = vec![];
+            for (prefix, directory) in matched {
+                let entries = match directories.get(&directory) {
+                    Some(entries) => entries,
+                    None => continue,
+                };
+                let directory_attrs = metadata.get(&directory).ok_or(ErrorCode::Corrupted)?;
+                let access_mask = if wildcard {
+                    libc::R_OK | libc::X_OK
+                } else {
+                    libc::X_OK
+                };
+                if !check_access(
+                    directory_attrs.uid,
+                    directory_attrs.gid,
+                    directory_attrs.mode,
+                    context.uid(),
+                    context.gid(),
+                    access_mask as u32,
+                ) {
+                    continue;
+                }
+
+                if !wildcard {
+                    if let Some((inode, kind)) = entries.get(component) {
+                        if last || *kind == FileKind::Directory {
+                            next.push((format!("{}/{}", prefix, component), *inode));
+                        }
+                    }
+                    continue;
+                }
+                for (name, (inode, kind)) in entries.iter() {
+                    if name.starts_with('.') && !component.starts_with('.') {
+                        continue;
+                    }
+                    let name_matches = if self.case_insensitive {
+                        glob_matches(&component.to_lowercase(), &name.to_lowercase())
+                    } else {
+                        glob_matches(component, name)
+                    };
+                    if name_matches && (last || *kind == FileKind::Directory) {
+                        next.push((format!("{}/{}", prefix, name), *inode));
+                    }
+                }
+            }
+            matched = next;
+        }
+        if components.is_empty() {
+            matched = vec![("/".to_string(), ROOT_INODE)];
+        }
+        matched.sort();
+
+        Ok(matched)
+    }
+
+    // Returns the path of one of the links to inode
+    pub fn path_of(&self, inode: Inode) -> Result<String, ErrorCode> {
+        self.paths_of(inode)?
+            .into_iter()
+            .next()
+            .ok_or(ErrorCode::DoesNotExist)
+    }
+
+    // Returns the paths of all the links to inode, sorted
+    pub fn paths_of(&self, inode: Inode) -> Result<Vec<String>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        if parents.contains_key(&inode) {
+            return Ok(vec![directory_path(&directories, &parents, inode)?]);
+        }
+        let links = file_parents
+            .get(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        let mut paths = vec![];
+        for (parent, name) in links.iter() {
+            let parent_path = directory_path(&directories, &parents, *parent)?;
+            paths.push(format!("{}/{}", parent_path.trim_end_matches('/'), name));
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    // Returns the paths of the links to inode which context may resolve: every directory on the path must grant it
+    // search permission. Sorted
+    pub fn searchable_paths_of(
+        &self,
+        inode: Inode,
+        context: UserContext,
+    ) -> Result<Vec<String>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        let links = if let Some(parent) = parents.get(&inode) {
+            // A directory's own permissions don't hide its path, only those of its ancestors
+            if inode == ROOT_INODE {
+                return Ok(vec!["/".to_string()]);
+            }
+            vec![(*parent, None)]
+        } else {
+            file_parents
+                .get(&inode)
+                .ok_or(ErrorCode::InodeDoesNotExist)?
+                .iter()
+                .map(|(parent, name)| (*parent, Some(name)))
+                .collect()
+        };
+        let mut paths = vec![];
+        for (parent, name) in links {
+            if !ancestors_searchable(&metadata, &parents, parent, context)? {
+                continue;
+            }
+            match name {
+                Some(name) => {
+                    let parent_path = directory_path(&directories, &parents, parent)?;
+                    paths.push(format!("{}/{}", parent_path.trim_end_matches('/'), name));
+                }
+                None => paths.push(directory_path(&directories, &parents, inode)?),
+            }
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    // Returns a description of each inconsistency found: entries which refer to missing inodes, inodes which
+    // aren't reachable from the root, and directories whose parent pointer doesn't match the entry linking to them
+    pub fn check(&self) -> Result<Vec<String>, ErrorCode> {
+        let directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+
+        let mut problems = vec![];
+        for (directory, descriptor) in directories.iter() {
+            for (name, (inode, _)) in descriptor.iter() {
+                if !metadata.contains_key(inode) {
+                    problems.push(format!(
+                        "Entry {} in directory {} refers to missing inode {}",
+                        name, directory, inode
+                    ));
+                }
+            }
+        }
+        let reachable = reachable_inodes(&directories, &metadata);
+        for (inode, attributes) in metadata.iter() {
+            match reachable.get(inode) {
+                None if is_temporary(attributes) => {}
+                None => problems.push(format!("Inode {} is not reachable from the root", inode)),
+                Some(parent) if attributes.kind == FileKind::Directory => {
+                    if parents.get(inode) != Some(parent) {
+                        problems.push(format!(
+                            "Directory {} has parent {:?}, but is linked from {}",
+                            inode,
+                            parents.get(inode),
+                            parent
+                        ));
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        problems.sort();
+
+        Ok(problems)
+    }
+
+    // Removes entries which refer to missing inodes, and reattaches unreachable inodes in lost+found,
+    // named after their inode number. Returns the problems that were found, like check()
+    pub fn repair(&self) -> Result<Vec<String>, ErrorCode> {
+        let problems = self.check()?;
+        if problems.is_empty() {
+            return Ok(problems);
+        }
+        let lost_and_found =
+            match self.lookup(ROOT_INODE, LOST_AND_FOUND, UserContext::new(0, 0))? {
+                Some(inode) => inode,
+                None => {
+                    self.mkdir(ROOT_INODE, LOST_AND_FOUND, 0, 0, 0o700)?;
+                    self.lookup(ROOT_INODE, LOST_AND_FOUND, UserContext::new(0, 0))?
+                        .ok_or(ErrorCode::Corrupted)?
+                }
+            };
+
+        let mut directories = self.directories.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut directory_changes = self
+            .directory_changes
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let mut parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let mut file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        if metadata.get(&lost_and_found).map(|x| x.kind) != Some(FileKind::Directory) {
+            return Err(ErrorCode::AlreadyExists);
+        }
+
+        // Iterate in a fixed order, so that every node makes the same repairs
+        let mut directory_inodes: Vec<Inode> = directories.keys().cloned().collect();
+        directory_inodes.sort();
+        for directory in directory_inodes.iter() {
+            let descriptor = directories.get_mut(directory).ok_or(ErrorCode::Corrupted)?;
+            let mut dangling: Vec<String> = descriptor
+                .iter()
+                .filter(|(_, (inode, _))| !metadata.contains_key(inode))
+                .map(|(name, _)| name.clone())
+                .collect();
+            dangling.sort();
+            for name in dangling {
+                descriptor.remove(&name);
+                record_change(&mut directory_changes, *directory, &name, None);
+            }
+        }
+
+        // Reattach one orphan at a time, preferring ones which aren't linked from another orphaned directory,
+        // since those will be reattached along with their directory
+        loop {
+            let reachable = reachable_inodes(&directories, &metadata);
+            let mut orphans: Vec<Inode> = metadata
+                .values()
+                .filter(|x| !reachable.contains_key(&x.inode) && !is_temporary(x))
+                .map(|x| x.inode)
+                .collect();
+            if orphans.is_empty() {
+                break;
+            }
+            orphans.sort();
+            let linked_from_orphans: Vec<Inode> = orphans
+                .iter()
+                .filter_map(|inode| directories.get(inode))
+                .flat_map(|descriptor| descriptor.values().map(|(inode, _)| *inode))
+                .collect();
+            let orphan = *orphans
+                .iter()
+                .find(|inode| !linked_from_orphans.contains(inode))
+                .unwrap_or(&orphans[0]);
+            // The orphan is part of a cycle of directories, so break the cycle by unlinking it
+            for directory in orphans.iter() {
+                if let Some(descriptor) = directories.get_mut(directory) {
+                    let names: Vec<String> = descriptor
+                        .iter()
+                        .filter(|(_, (inode, _))| *inode == orphan)
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    for name in names {
+                        descriptor.remove(&name);
+                        record_change(&mut directory_changes, *directory, &name, None);
+                    }
+                }
+            }
+
+            let kind = metadata.get(&orphan).ok_or(ErrorCode::Corrupted)?.kind;
+            if kind == FileKind::Directory && !directories.contains_key(&orphan) {
+                directories.insert(orphan, DirectoryDescriptor::new(self.case_insensitive));
+            }
+            let name = format!("#{}", orphan);
+            directories
+                .get_mut(&lost_and_found)
+                .ok_or(ErrorCode::Corrupted)?
+                .insert(name.clone(), (orphan, kind));
+            record_change(
+                &mut directory_changes,
+                lost_and_found,
+                &name,
+                Some((orphan, kind)),
+            );
+            if kind == FileKind::Directory {
+                record_change(
+                    &mut directory_changes,
+                    orphan,
+                    "..",
+                    Some((lost_and_found, FileKind::Directory)),
+                );
+            }
+        }
+
+        // Rebuild the indices, now that every inode is linked from exactly the entries in directories
+        parents.clear();
+        parents.insert(ROOT_INODE, ROOT_INODE);
+        file_parents.clear();
+        let mut directory_inodes: Vec<Inode> = directories.keys().cloned().collect();
+        directory_inodes.sort();
+        for directory in directory_inodes.iter() {
+            let descriptor = directories.get(directory).ok_or(ErrorCode::Corrupted)?;
+            let mut entries: Vec<(&String, &(Inode, FileKind))> = descriptor.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (name, (inode, kind)) in entries {
+                if *kind == FileKind::Directory {
+                    parents.entry(*inode).or_insert(*directory);
+                } else {
+                    file_parents
+                        .entry(*inode)
+                        .or_insert_with(Vec::new)
+                        .push((*directory, name.clone()));
+                }
+            }
+        }
+        for (inode, links) in file_parents.iter() {
+            if let Some(attributes) = metadata.get_mut(inode) {
+                attributes.hardlinks = links.len() as u32;
+            }
+        }
+        *tree_usage = compute_tree_usage(&directories, &parents, &file_parents, &metadata);
+
+        Ok(problems)
+    }
+
+    // Error for an inode which isn't in directories, depending on whether it exists
+    fn missing_directory_error(&self, inode: Inode) -> Result<ErrorCode, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        if metadata.contains_key(&inode) {
+            Ok(ErrorCode::NotADirectory)
+        } else {
+            Ok(ErrorCode::DoesNotExist)
+        }
+    }
+
+    pub fn get_tree_usage(&self, inode: Inode) -> Result<TreeUsage, ErrorCode> {
+        let metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let tree_usage = self.tree_usage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let attributes = metadata.get(&inode).ok_or(ErrorCode::InodeDoesNotExist)?;
+        if attributes.kind == FileKind::Directory {
+            tree_usage.get(&inode).cloned().ok_or(ErrorCode::Corrupted)
+        } else {
+            Ok(TreeUsage {
+                bytes: attributes.size,
+                inodes: 0,
+            })
+        }
+    }
+
+    // Sets the time of the operation which is about to be applied. Operation times only increase, so if time
+    // isn't after the previous one, the operation is applied just after the previous one instead
+    pub fn set_operation_time(&self, time: Timestamp) {
+        let mut operation_time = self.operation_time.lock().unwrap();
+        *operation_time = Some(match *operation_time {
+            Some(previous) => later(time, successor(previous)),
+            None => time,
+        });
+    }
+
+    // Time used for the changes made by the current operation
+    fn time(&self) -> Timestamp {
+        self.operation_time.lock().unwrap().unwrap_or_else(now)
+    }
+
+    pub fn limits(&self) -> FilesystemLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    pub fn allocate_client_id(&self) -> u64 {
+        self.next_client_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Existing files, names and xattrs which exceed new limits are kept. The limits only apply to later changes
+    pub fn set_limits(&self, limits: FilesystemLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    // Freezes the subtree of inode until timeout_seconds after the current operation. Freezing it again replaces
+    // the timeout
+    pub fn freeze(&self, inode: Inode, timeout_seconds: u32) -> Result<(), ErrorCode> {
+        if !self
+            .metadata
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?
+            .contains_key(&inode)
+        {
+            return Err(ErrorCode::InodeDoesNotExist);
+        }
+        let time = self.time();
+        let thawed_at = Timestamp::new(time.seconds() + i64::from(timeout_seconds), time.nanos());
+        self.frozen
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?
+            .insert(inode, thawed_at);
+
+        Ok(())
+    }
+
+    // Fails with StaleFencingToken if a write with a later token than fencing_token was applied to the file, since
+    // the lock the write was made under has been broken, and otherwise records it. 0 means the write isn't fenced
+    pub fn advance_fencing_token(&self, inode: Inode, fencing_token: u64) -> Result<(), ErrorCode> {
+        if fencing_token == 0 {
+            return Ok(());
+        }
+        let mut metadata = self.metadata.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let attributes = metadata
+            .get_mut(&inode)
+            .ok_or(ErrorCode::InodeDoesNotExist)?;
+        if fencing_token < attributes.fencing_token {
+            return Err(ErrorCode::StaleFencingToken);
+        }
+        attributes.fencing_token = fencing_token;
+
+        Ok(())
+    }
+
+    // Thawing a subtree which isn't frozen does nothing, so that it can be retried
+    pub fn thaw(&self, inode: Inode) -> Result<(), ErrorCode> {
+        self.frozen
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?
+            .remove(&inode);
+
+        Ok(())
+    }
+
+    // Fails with ReadOnly if any of inodes is in a frozen subtree. Files are in the subtrees of all their links
+    pub fn check_not_frozen(&self, inodes: &[Inode]) -> Result<(), ErrorCode> {
+        let time = self.time();
+        let mut frozen = self.frozen.lock().map_err(|_| ErrorCode::Corrupted)?;
+        frozen.retain(|_, thawed_at| {
+            (thawed_at.seconds(), thawed_at.nanos()) > (time.seconds(), time.nanos())
+        });
+        if frozen.is_empty() {
+            return Ok(());
+        }
+
+        let directory_parents = self
+            .directory_parents
+            .lock()
+            .map_err(|_| ErrorCode::Corrupted)?;
+        let file_parents = self.file_parents.lock().map_err(|_| ErrorCode::Corrupted)?;
+        for inode in inodes.iter() {
+            if frozen.contains_key(inode) {
+                return Err(ErrorCode::ReadOnly);
+            }
+            let mut directories: Vec<Inode> = if directory_parents.contains_key(inode) {
+                vec![*inode]
+            } else {
+                file_parents
+                    .get(inode)
+                    .map(|links| links.iter().map(|(parent, _)| *parent).collect())
+                    .unwrap_or_default()
+            };
+            while let Some(directory) = directories.pop() {
+                if frozen.contains_key(&directory) {
+                    return Err(ErrorCode::ReadOnly);
+                }
+                match directory_parents.get(&directory) {
+                    Some(parent) if *parent != directory => directories.push(*parent),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), ErrorCode> {
+        if name.len() > self.limits().max_name_length() as usize {
+            return Err(ErrorCode::NameTooLong);
+        }
+
+        Ok(())
+    }
+
+    fn check_inode_available(
+        &self,
+        metadata: &HashMap<Inode, InodeAttributes>,
+    ) -> Result<(), ErrorCode> {
+        if metadata.len() as u64 >= self.limits().max_inodes() {
+            return Err(ErrorCode::NoSpace);
+        }
+
+        Ok(())
+    }
+
+    fn allocate_inode(&self) -> u64 {
+        self.next_inode.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+// Retained files are immutable until their retention expires
+fn check_retention(attributes: &InodeAttributes, time: Timestamp) -> Result<(), ErrorCode> {
+    if attributes.retained_until > time.seconds() {
+        return Err(ErrorCode::OperationNotPermitted);
+    }
+
+    Ok(())
+}
+
+// Temporary files have no links, until they're linked into a directory
+fn is_temporary(attributes: &InodeAttributes) -> bool {
+    attributes.kind != FileKind::Directory && attributes.hardlinks == 0
+}
+
+// Directories must be searchable to look up their entries
+fn check_searchable(attributes: &InodeAttributes, context: UserContext) -> Result<(), ErrorCode> {
+    check_directory(attributes)?;
+    if !check_access(
+        attributes.uid,
+        attributes.gid,
+        attributes.mode,
+        context.uid(),
+        context.gid(),
+        libc::X_OK as u32,
+    ) {
+        return Err(ErrorCode::AccessDenied);
+    }
+
+    Ok(())
+}
+
+fn check_directory(attributes: &InodeAttributes) -> Result<(), ErrorCode> {
+    if attributes.kind != FileKind::Directory {
+        return Err(ErrorCode::NotADirectory);
+    }
+
+    Ok(())
+}
+
+fn check_not_directory(attributes: &InodeAttributes) -> Result<(), ErrorCode> {
+    if attributes.kind == FileKind::Directory {
+        return Err(ErrorCode::IsADirectory);
+    }
+
+    Ok(())
+}
+
+// The DOS readonly bit is honored even for users who have write permission, as it is on Windows
+fn check_dos_readonly(attributes: &InodeAttributes) -> Result<(), ErrorCode> {
+    if attributes.dos_attributes & DOS_READONLY != 0 {
+        return Err(ErrorCode::AccessDenied);
+    }
+
+    Ok(())
+}
+
+// Adds the given change in usage to directory, and all of its ancestors
+fn update_tree_usage(
+    tree_usage: &mut HashMap<Inode, TreeUsage>,
+    parents: &HashMap<Inode, Inode>,
+    mut directory: Inode,
+    bytes: i64,
+    inodes: i64,
+) {
+    loop {
+        if let Some(usage) = tree_usage.get_mut(&directory) {
+            usage.bytes = (usage.bytes as i64 + bytes) as u64;
+            usage.inodes = (usage.inodes as i64 + inodes) as u64;
+        }
+        if directory == ROOT_INODE {
+            return;
+        }
+        if let Some(parent) = parents.get(&directory) {
+            directory = *parent;
+        } else {
+            return;
+        }
+    }
+}
+
+// Directories which contain any of the links to file: the directories of its links, and all of their ancestors
+fn containing_directories(
+    parents: &HashMap<Inode, Inode>,
+    file_parents: &HashMap<Inode, Vec<(Inode, String)>>,
+    file: Inode,
+) -> HashSet<Inode> {
+    let mut containing = HashSet::new();
+    for (parent, _) in file_parents.get(&file).into_iter().flatten() {
+        let mut directory = *parent;
+        // The ancestors of a directory which was already reached through another link were too
+        while containing.insert(directory) && directory != ROOT_INODE {
+            match parents.get(&directory) {
+                Some(parent) => directory = *parent,
+                None => break,
+            }
+        }
+    }
+
+    containing
+}
+
+// Updates the usage of a file of the given size, whose links were in the directories before, and now are in after.
+// A file is counted once in each directory containing any of its links, however many links it has
+fn update_file_usage(
+    tree_usage: &mut HashMap<Inode, TreeUsage>,
+    before: &HashSet<Inode>,
+    after: &HashSet<Inode>,
+    size: u64,
+) {
+    for directory in before.difference(after) {
+        if let Some(usage) = tree_usage.get_mut(directory) {
+            usage.bytes -= size;
+            usage.inodes -= 1;
+        }
+    }
+    for directory in after.difference(before) {
+        if let Some(usage) = tree_usage.get_mut(directory) {
+            usage.bytes += size;
+            usage.inodes += 1;
+        }
+    }
+}
+
+// Adds the change in size of a file to each of the directories containing it
+fn resize_file_usage(
+    tree_usage: &mut HashMap<Inode, TreeUsage>,
+    containing: &HashSet<Inode>,
+    delta: i64,
+) {
+    for directory in containing.iter() {
+        if let Some(usage) = tree_usage.get_mut(directory) {
+            usage.bytes = (usage.bytes as i64 + delta) as u64;
+        }
+    }
+}
+
+// Files anywhere below directory
+fn files_below(directories: &HashMap<Inode, DirectoryDescriptor>, directory: Inode) -> Vec<Inode> {
+    let mut files = vec![];
+    let mut pending = vec![directory];
+    while let Some(directory) = pending.pop() {
+        for (inode, kind) in directories
+            .get(&directory)
+            .into_iter()
+            .flat_map(|x| x.values())
+        {
+            if *kind == FileKind::Directory {
+                pending.push(*inode);
+            } else {
+                files.push(*inode);
+            }
+        }
+    }
+
+    files
+}
+
+// Usage that an entry contributes to the directory containing it
+fn entry_usage(
+    metadata: &HashMap<Inode, InodeAttributes>,
+    tree_usage: &HashMap<Inode, TreeUsage>,
+    inode: Inode,
+    kind: FileKind,
+) -> (i64, i64) {
+    if kind == FileKind::Directory {
+        let usage = tree_usage.get(&inode).cloned().unwrap_or_default();
+        (usage.bytes as i64, usage.inodes as i64 + 1)
+    } else {
+        let size = metadata.get(&inode).map(|x| x.size).unwrap_or(0);
+        (size as i64, 1)
+    }
+}
+
+fn record_change(
+    directory_changes: &mut HashMap<Inode, DirectoryChanges>,
+    directory: Inode,
+    name: &str,
+    entry: Option<(Inode, FileKind)>,
+) {
+    let changes = directory_changes
+        .entry(directory)
+        .or_insert_with(DirectoryChanges::default);
+    changes.version += 1;
+    if changes.log.len() >= MAX_DIRECTORY_CHANGES {
+        changes.log.pop_front();
+    }
+    changes
+        .log
+        .push_back((changes.version, name.to_string(), entry));
+}
+
+// Returns the inodes reachable from the root, and the directory each was first reached from
+fn reachable_inodes(
+    directories: &HashMap<Inode, DirectoryDescriptor>,
+    metadata: &HashMap<Inode, InodeAttributes>,
+) -> HashMap<Inode, Inode> {
+    let mut reachable = HashMap::new();
+    reachable.insert(ROOT_INODE, ROOT_INODE);
+    let mut pending = VecDeque::new();
+    pending.push_back(ROOT_INODE);
+    while let Some(directory) = pending.pop_front() {
+        let mut entries: Vec<(&String, &(Inode, FileKind))> = directories
+            .get(&directory)
+            .into_iter()
+            .flat_map(DirectoryDescriptor::iter)
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, (inode, kind)) in entries {
+            if !metadata.contains_key(inode) || reachable.contains_key(inode) {
+                continue;
+            }
+            reachable.insert(*inode, directory);
+            if *kind == FileKind::Directory {
+                pending.push_back(*inode);
+            }
+        }
+    }
+
+    reachable
+}
+
+fn compute_tree_usage(
+    directories: &HashMap<Inode, DirectoryDescriptor>,
+    parents: &HashMap<Inode, Inode>,
+    file_parents: &HashMap<Inode, Vec<(Inode, String)>>,
+    metadata: &HashMap<Inode, InodeAttributes>,
+) -> HashMap<Inode, TreeUsage> {
+    let mut tree_usage = HashMap::new();
+    for directory in directories.keys() {
+        tree_usage.insert(*directory, TreeUsage::default());
+    }
+    // Add the usage of each entry to all of its ancestors, now that they're all known
+    for (directory, entries) in directories.iter() {
+        for (_, kind) in entries.values() {
+            if *kind == FileKind::Directory {
+                update_tree_usage(&mut tree_usage, parents, *directory, 0, 1);
+            }
+        }
+    }
+    // Files are counted once, however many links they have
+    for file in file_parents.keys() {
+        let size = metadata.get(file).map_or(0, |x| x.size);
+        let containing = containing_directories(parents, file_parents, *file);
+        update_file_usage(&mut tree_usage, &HashSet::new(), &containing, size);
+    }
+
+    tree_usage
+}
+
+// Whether ancestor is directory, or one of the directories above it
+fn is_ancestor(
+    parents: &HashMap<Inode, Inode>,
+    ancestor: Inode,
+    directory: Inode,
+) -> Result<bool, ErrorCode> {
+    let mut current = directory;
+    loop {
+        if current == ancestor {
+            return Ok(true);
+        }
+        if current == ROOT_INODE {
+            return Ok(false);
+        }
+        current = *parents.get(&current).ok_or(ErrorCode::Corrupted)?;
+    }
+}
+
+// Number of directories above directory. The root has depth 0
+fn directory_depth(parents: &HashMap<Inode, Inode>, directory: Inode) -> Result<u32, ErrorCode> {
+    let mut depth = 0;
+    let mut current = directory;
+    while current != ROOT_INODE {
+        current = *parents.get(&current).ok_or(ErrorCode::Corrupted)?;
+        depth += 1;
+    }
+
+    Ok(depth)
+}
+
+// Number of levels of directories below directory
+fn subtree_height(
+    directories: &HashMap<Inode, DirectoryDescriptor>,
+    directory: Inode,
+) -> Result<u32, ErrorCode> {
+    let mut height = 0;
+    let mut level = vec![directory];
+    loop {
+        let mut next_level = vec![];
+        for inode in level {
+            let descriptor = directories.get(&inode).ok_or(ErrorCode::Corrupted)?;
+            for (child, kind) in descriptor.values() {
+                if *kind == FileKind::Directory {
+                    next_level.push(*child);
+                }
+            }
+        }
+        if next_level.is_empty() {
+            return Ok(height);
+        }
+        height += 1;
+        level = next_level;
+    }
+}
+
+fn directory_path(
+    directories: &HashMap<Inode, DirectoryDescriptor>,
+    parents: &HashMap<Inode, Inode>,
+    inode: Inode,
+) -> Result<String, ErrorCode> {
+    let mut child = inode;
+    let mut names = vec![];
+    while child != ROOT_INODE {
+        let parent = *parents.get(&child).ok_or(ErrorCode::Corrupted)?;
+        let name = directories
+            .get(&parent)
+            .ok_or(ErrorCode::Corrupted)?
+            .iter()
+            .find(|(_, (entry_inode, _))| *entry_inode == child)
+            .map(|(name, _)| name.clone())
+            .ok_or(ErrorCode::Corrupted)?;
+        names.push(name);
+        child = parent;
+    }
+    names.reverse();
+
+    Ok(format!("/{}", names.join("/")))
+}
+
+// Whether context has search permission on directory and all of its ancestors
+fn ancestors_searchable(
+    metadata: &HashMap<Inode, InodeAttributes>,
+    parents: &HashMap<Inode, Inode>,
+    directory: Inode,
+    context: UserContext,
+) -> Result<bool, ErrorCode> {
+    let mut current = directory;
+    loop {
+        let attributes = metadata.get(&current).ok_or(ErrorCode::Corrupted)?;
+        if check_searchable(attributes, context).is_err() {
+            return Ok(false);
+        }
+        if current == ROOT_INODE {
+            return Ok(true);
+        }
+        current = *parents.get(&current).ok_or(ErrorCode::Corrupted)?;
+    }
+}
+
+fn remove_link(
+    file_parents: &mut HashMap<Inode, Vec<(Inode, String)>>,
+    inode: Inode,
+    parent: Inode,
+    name: &str,
+) {
+    if let Some(links) = file_parents.get_mut(&inode) {
+        if let Some(index) = links
+            .iter()
+            .position(|(link_parent, link_name)| *link_parent == parent && link_name == name)
+        {
+            links.remove(index);
+        }
+        if links.is_empty() {
+            file_parents.remove(&inode);
+        }
+    }
+}
+
+// Timestamps are updated as specified by POSIX, which is also what ext4 does:
+//
+// | Operation                     | Inode                           | Parent directories         |
+// |-------------------------------|---------------------------------|----------------------------|
+// | create, mkdir, mknod, symlink | all (new inode)                 | mtime, ctime               |
+// | link                          | ctime                           | mtime, ctime of new parent |
+// | unlink, rmdir                 | ctime                           | mtime, ctime               |
+// | rename                        | ctime, also of a replaced inode | mtime, ctime of both       |
+// | write, truncate               | mtime, ctime                    | none                       |
+// | chmod, chown, xattr changes   | ctime                           | none                       |
+// | utimens                       | ctime, and the requested times  | none                       |
+//
+// In particular, writing to a file doesn't change its parent's timestamps. The timestamps set by one operation
+// are all equal
+fn mark_modified(attributes: &mut InodeAttributes, time: Timestamp) {
+    attributes.last_modified = time;
+    attributes.last_metadata_changed = time;
+}
+
+fn mark_changed(attributes: &mut InodeAttributes, time: Timestamp) {
+    attributes.last_metadata_changed = time;
+}
+
+pub fn now() -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time before unix epoch");
+    Timestamp::new(now.as_secs() as i64, now.subsec_nanos() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::{ErrorCode, FileKind, FilesystemLimits, Timestamp, UserContext};
+    use crate::storage::metadata_storage::{MetadataStorage, TreeUsage, MAX_HARDLINKS, ROOT_INODE};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // (mtime, ctime) of inode
+    fn times(storage: &MetadataStorage, inode: u64) -> ((i64, i32), (i64, i32)) {
+        let attributes = storage.get_attributes(inode).unwrap();
+        (
+            (
+                attributes.last_modified.seconds(),
+                attributes.last_modified.nanos(),
+            ),
+            (
+                attributes.last_metadata_changed.seconds(),
+                attributes.last_metadata_changed.nanos(),
+            ),
+        )
+    }
+
+    // Runs operation, and returns whether it changed the (mtime, ctime) of each of inodes
+    fn changed<F: FnOnce()>(
+        storage: &MetadataStorage,
+        inodes: &[u64],
+        operation: F,
+    ) -> Vec<(bool, bool)> {
+        let before: Vec<_> = inodes.iter().map(|x| times(storage, *x)).collect();
+        sleep(Duration::from_millis(2));
+        operation();
+        inodes
+            .iter()
+            .zip(before.iter())
+            .map(|(inode, (mtime, ctime))| {
+                let (new_mtime, new_ctime) = times(storage, *inode);
+                (new_mtime != *mtime, new_ctime != *ctime)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn operation_times_only_increase() {
+        let storage = MetadataStorage::new(false);
+        storage.set_operation_time(Timestamp::new(100, 0));
+        storage.mkdir(ROOT_INODE, "a", 0, 0, 0o755).unwrap();
+        assert_eq!(times(&storage, ROOT_INODE), ((100, 0), (100, 0)));
+
+        // Proposed by a node whose clock is behind
+        storage.set_operation_time(Timestamp::new(50, 0));
+        storage.mkdir(ROOT_INODE, "b", 0, 0, 0o755).unwrap();
+        assert_eq!(times(&storage, ROOT_INODE), ((100, 1), (100, 1)));
+
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+        restored.set_operation_time(Timestamp::new(50, 0));
+        restored.mkdir(ROOT_INODE, "c", 0, 0, 0o755).unwrap();
+        assert_eq!(times(&restored, ROOT_INODE), ((100, 2), (100, 2)));
+    }
+
+    #[test]
+    fn glob() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "logs", 0, 0, 0o755).unwrap();
+        let logs = storage
+            .lookup(ROOT_INODE, "logs", context)
+            .unwrap()
+            .unwrap();
+        for day in ["2024-01", "2024-02", "2023-12"].iter() {
+            storage.mkdir(logs, day, 0, 0, 0o755).unwrap();
+            let directory = storage.lookup(logs, day, context).unwrap().unwrap();
+            for name in ["a.gz", "b.txt", ".hidden.gz"].iter() {
+                storage
+                    .create(directory, name, 0, 0, 0o644, FileKind::File)
+                    .unwrap();
+            }
+        }
+        storage
+            .create(logs, "2024-03", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+
+        let paths = |pattern: &str| -> Vec<String> {
+            storage
+                .glob(pattern, context)
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect()
+        };
+        assert_eq!(
+            paths("/logs/2024-*/*.gz"),
+            vec!["/logs/2024-01/a.gz", "/logs/2024-02/a.gz"]
+        );
+        assert_eq!(
+            paths("/logs/202?-0*"),
+            vec!["/logs/2024-01", "/logs/2024-02", "/logs/2024-03"]
+        );
+        assert_eq!(
+            paths("/logs/2023-12/.*.gz"),
+            vec!["/logs/2023-12/.hidden.gz"]
+        );
+        assert_eq!(paths("/logs/2024-03/*"), Vec::<String>::new());
+        assert_eq!(paths("/"), vec!["/"]);
+        assert_eq!(
+            storage.glob("logs/*", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn configured_limits() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "long_name", 0, 0, 0o755).unwrap();
+        let (inode, _) = storage
+            .create(ROOT_INODE, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        // The root directory, and the two above
+        storage.set_limits(FilesystemLimits::new(100, 4, 8, 4));
+
+        assert_eq!(
+            storage.mkdir(ROOT_INODE, "too_long_", 0, 0, 0o755),
+            Err(ErrorCode::NameTooLong)
+        );
+        assert_eq!(
+            storage.rename(ROOT_INODE, "file", ROOT_INODE, "too_long_", context),
+            Err(ErrorCode::NameTooLong)
+        );
+        // Existing names which exceed the limit can still be looked up
+        assert!(storage
+            .lookup(ROOT_INODE, "long_name", context)
+            .unwrap()
+            .is_some());
+        assert_eq!(storage.write(inode, 90, 10, context), Ok(()));
+        assert_eq!(
+            storage.write(inode, 90, 11, context),
+            Err(ErrorCode::FileTooLarge)
+        );
+        assert_eq!(
+            storage.truncate(inode, 101, context),
+            Err(ErrorCode::FileTooLarge)
+        );
+        assert_eq!(
+            storage.set_xattr(inode, "user.key", b"value"),
+            Err(ErrorCode::XattrTooLarge)
+        );
+        assert!(storage
+            .create(ROOT_INODE, "other", 0, 0, 0o644, FileKind::File)
+            .is_ok());
+        assert_eq!(
+            storage.mkdir(ROOT_INODE, "dir", 0, 0, 0o755),
+            Err(ErrorCode::NoSpace)
+        );
+
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+        assert_eq!(
+            restored.limits().max_name_length(),
+            storage.limits().max_name_length()
+        );
+    }
+
+    #[test]
+    fn retention_permissions() {
+        let storage = MetadataStorage::new(false);
+        let (inode, _) = storage
+            .create(ROOT_INODE, "file", 1000, 1000, 0o666, FileKind::File)
+            .unwrap();
+
+        assert_eq!(
+            storage.set_retention(inode, 60, UserContext::new(1001, 1000)),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        storage
+            .set_retention(inode, 60, UserContext::new(1000, 1000))
+            .unwrap();
+        storage
+            .set_retention(inode, 120, UserContext::new(1000, 1000))
+            .unwrap();
+        assert_eq!(
+            storage.set_retention(inode, 60, UserContext::new(1000, 1000)),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        storage
+            .set_retention(inode, 0, UserContext::new(0, 0))
+            .unwrap();
+        assert_eq!(storage.get_retention(inode).unwrap().0, 0);
+    }
+
+    #[test]
+    fn redundancy_permissions() {
+        let storage = MetadataStorage::new(false);
+        let (inode, _) = storage
+            .create(ROOT_INODE, "file", 1000, 1000, 0o666, FileKind::File)
+            .unwrap();
+
+        assert_eq!(
+            storage.set_redundancy(inode, 3, UserContext::new(1001, 1000)),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        assert_eq!(storage.get_redundancy(inode).unwrap(), 1);
+        storage
+            .set_redundancy(inode, 3, UserContext::new(1000, 1000))
+            .unwrap();
+        assert_eq!(storage.get_redundancy(inode).unwrap(), 3);
+        storage
+            .set_redundancy(inode, 1, UserContext::new(0, 0))
+            .unwrap();
+        assert_eq!(storage.get_redundancy(inode).unwrap(), 1);
+    }
+
+    #[test]
+    fn tree_usage_of_links() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "a", 0, 0, 0o755).unwrap();
+        storage.mkdir(ROOT_INODE, "b", 0, 0, 0o755).unwrap();
+        let a = storage.lookup(ROOT_INODE, "a", context).unwrap().unwrap();
+        let b = storage.lookup(ROOT_INODE, "b", context).unwrap().unwrap();
+        let (file, _) = storage
+            .create(a, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        storage.write(file, 0, 100, context).unwrap();
+        // (root, a, b), checked against the usage recomputed from a snapshot too
+        let usage = |storage: &MetadataStorage| -> Vec<(u64, u64)> {
+            let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+            let usage: Vec<(u64, u64)> = [ROOT_INODE, a, b]
+                .iter()
+                .map(|inode| storage.get_tree_usage(*inode).unwrap_or_default())
+                .map(|x: TreeUsage| (x.bytes, x.inodes))
+                .collect();
+            let recomputed: Vec<(u64, u64)> = [ROOT_INODE, a, b]
+                .iter()
+                .map(|inode| restored.get_tree_usage(*inode).unwrap_or_default())
+                .map(|x: TreeUsage| (x.bytes, x.inodes))
+                .collect();
+            assert_eq!(usage, recomputed);
+            usage
+        };
+        assert_eq!(usage(&storage), vec![(100, 3), (100, 1), (0, 0)]);
+
+        storage.truncate(file, 40, context).unwrap();
+        assert_eq!(usage(&storage), vec![(40, 3), (40, 1), (0, 0)]);
+
+        // Counted once in each directory which contains any of its links
+        storage.hardlink(file, a, "again", context).unwrap();
+        storage.hardlink(file, b, "other", context).unwrap();
+        assert_eq!(usage(&storage), vec![(40, 3), (40, 1), (40, 1)]);
+        storage.write(file, 0, 60, context).unwrap();
+        assert_eq!(usage(&storage), vec![(60, 3), (60, 1), (60, 1)]);
+        storage.truncate(file, 10, context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (10, 1), (10, 1)]);
+
+        // Moving a directory next to another link of the file
+        storage.rename(ROOT_INODE, "a", b, "a", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (10, 1), (10, 2)]);
+        storage.rename(b, "a", ROOT_INODE, "a", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (10, 1), (10, 1)]);
+
+        // Moving a link out of a directory which still has another one
+        storage.rename(a, "again", b, "moved", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (10, 1), (10, 1)]);
+        let (replaced, _) = storage
+            .create(b, "replaced", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        storage.write(replaced, 0, 5, context).unwrap();
+        assert_eq!(usage(&storage), vec![(15, 4), (10, 1), (15, 2)]);
+        storage.rename(b, "moved", b, "replaced", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (10, 1), (10, 1)]);
+
+        storage.unlink(a, "file", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (0, 0), (10, 1)]);
+        storage.unlink(b, "other", context).unwrap();
+        assert_eq!(usage(&storage), vec![(10, 3), (0, 0), (10, 1)]);
+        storage.unlink(b, "replaced", context).unwrap();
+        assert_eq!(usage(&storage), vec![(0, 2), (0, 0), (0, 0)]);
+    }
+
+    // Names in directory, excluding "." and ".."
+    fn names(storage: &MetadataStorage, directory: u64) -> Vec<String> {
+        let (_, entries) = storage.readdir(directory).unwrap();
+        let mut names: Vec<String> = entries
+            .into_iter()
+            .map(|(_, name, _)| name)
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn case_insensitive_names() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(true);
+        let (file, _) = storage
+            .create(ROOT_INODE, "Readme.TXT", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        storage.mkdir(ROOT_INODE, "Ärger", 0, 0, 0o755).unwrap();
+        let directory = storage
+            .lookup(ROOT_INODE, "ärger", context)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "readme.txt", context),
+            Ok(Some(file))
+        );
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "ÄRGER", context),
+            Ok(Some(directory))
+        );
+
+        // Names which only differ in case collide
+        assert_eq!(
+            storage
+                .create(ROOT_INODE, "README.txt", 0, 0, 0o644, FileKind::File)
+                .err(),
+            Some(ErrorCode::AlreadyExists)
+        );
+        assert_eq!(
+            storage.mkdir(ROOT_INODE, "äRGER", 0, 0, 0o755),
+            Err(ErrorCode::AlreadyExists)
+        );
+        assert_eq!(
+            storage.hardlink(file, ROOT_INODE, "readme.txt", context),
+            Err(ErrorCode::AlreadyExists)
+        );
+        // The case they were created with is preserved
+        assert_eq!(names(&storage, ROOT_INODE), vec!["Readme.TXT", "Ärger"]);
+
+        // Renaming to a name which only differs in case changes the case, instead of replacing the entry
+        assert_eq!(
+            storage.rename(ROOT_INODE, "readme.txt", ROOT_INODE, "README.TXT", context),
+            Ok(None)
+        );
+        assert_eq!(names(&storage, ROOT_INODE), vec!["README.TXT", "Ärger"]);
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "Readme.TXT", context),
+            Ok(Some(file))
+        );
+        assert_eq!(storage.get_attributes(file).unwrap().hardlinks, 1);
+        assert_eq!(
+            storage.rename(ROOT_INODE, "ÄRGER", ROOT_INODE, "ärger", context),
+            Ok(None)
+        );
+        assert_eq!(names(&storage, ROOT_INODE), vec!["README.TXT", "ärger"]);
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "ÄRGER", context),
+            Ok(Some(directory))
+        );
+
+        // Renaming onto a name in a different case replaces the existing entry, and takes the new case
+        let (other, _) = storage
+            .create(directory, "other", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        assert_eq!(
+            storage.rename(directory, "OTHER", ROOT_INODE, "readme.txt", context),
+            Ok(Some(file))
+        );
+        assert_eq!(names(&storage, ROOT_INODE), vec!["readme.txt", "ärger"]);
+        assert!(names(&storage, directory).is_empty());
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "README.TXT", context),
+            Ok(Some(other))
+        );
+        assert_eq!(storage.paths_of(other).unwrap(), vec!["/readme.txt"]);
+
+        // The index is rebuilt from snapshots
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), true);
+        assert_eq!(
+            restored.lookup(ROOT_INODE, "Readme.Txt", context),
+            Ok(Some(other))
+        );
+        assert_eq!(names(&restored, ROOT_INODE), vec!["readme.txt", "ärger"]);
+
+        assert_eq!(
+            storage.unlink(ROOT_INODE, "README.txt", context),
+            Ok(Some(other))
+        );
+        assert_eq!(names(&storage, ROOT_INODE), vec!["ärger"]);
+        assert_eq!(storage.lookup(ROOT_INODE, "readme.txt", context), Ok(None));
+        storage.rmdir(ROOT_INODE, "ÄRGER", context).unwrap();
+        assert!(names(&storage, ROOT_INODE).is_empty());
+    }
+
+    #[test]
+    fn case_sensitive_names() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        let (lower, _) = storage
+            .create(ROOT_INODE, "readme", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        let (upper, _) = storage
+            .create(ROOT_INODE, "README", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        assert_ne!(lower, upper);
+        assert_eq!(storage.lookup(ROOT_INODE, "Readme", context), Ok(None));
+        // Renaming across case replaces the other file
+        assert_eq!(
+            storage.rename(ROOT_INODE, "readme", ROOT_INODE, "README", context),
+            Ok(Some(upper))
+        );
+        assert_eq!(names(&storage, ROOT_INODE), vec!["README"]);
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "README", context),
+            Ok(Some(lower))
+        );
+    }
+
+    #[test]
+    fn file_kinds() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "dir", 0, 0, 0o755).unwrap();
+        let dir = storage.lookup(ROOT_INODE, "dir", context).unwrap().unwrap();
+        let (file, _) = storage
+            .create(ROOT_INODE, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        storage.write(file, 0, 10, context).unwrap();
+
+        // Files used as directories
+        assert_eq!(
+            storage.lookup(file, "x", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(storage.readdir(file).err(), Some(ErrorCode::NotADirectory));
+        assert_eq!(
+            storage.readdir_since(file, 0).err(),
+            Some(ErrorCode::NotADirectory)
+        );
+        assert_eq!(storage.readdir(12345).err(), Some(ErrorCode::DoesNotExist));
+        assert_eq!(
+            storage.create(file, "x", 0, 0, 0o644, FileKind::File).err(),
+            Some(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.mkdir(file, "x", 0, 0, 0o755),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.unlink(file, "x", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.rmdir(file, "x", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.hardlink(file, file, "x", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.rename(ROOT_INODE, "file", file, "x", context),
+            Err(ErrorCode::NotADirectory)
+        );
+
+        // Operations on the wrong kind of entry
+        assert_eq!(
+            storage.unlink(ROOT_INODE, "dir", context),
+            Err(ErrorCode::IsADirectory)
+        );
+        assert_eq!(
+            storage.rmdir(ROOT_INODE, "file", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.hardlink(dir, ROOT_INODE, "link", context),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        assert_eq!(storage.read(dir, context), Err(ErrorCode::IsADirectory));
+        assert_eq!(
+            storage.write(dir, 0, 10, context),
+            Err(ErrorCode::IsADirectory)
+        );
+        assert_eq!(
+            storage.truncate(dir, 0, context),
+            Err(ErrorCode::IsADirectory)
+        );
+
+        // A directory can only replace a directory, and a file only a file
+        assert_eq!(
+            storage.rename(ROOT_INODE, "dir", ROOT_INODE, "file", context),
+            Err(ErrorCode::NotADirectory)
+        );
+        assert_eq!(
+            storage.rename(ROOT_INODE, "file", ROOT_INODE, "dir", context),
+            Err(ErrorCode::IsADirectory)
+        );
+
+        // None of them changed anything
+        assert_eq!(names(&storage, ROOT_INODE), vec!["dir", "file"]);
+        assert!(names(&storage, dir).is_empty());
+        let attributes = storage.get_attributes(file).unwrap();
+        assert_eq!((attributes.size, attributes.hardlinks), (10, 1));
+        assert_eq!(
+            storage.get_attributes(dir).unwrap().kind,
+            FileKind::Directory
+        );
+    }
+
+    #[test]
+    fn hardlink_limit() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        let (file, _) = storage
+            .create(ROOT_INODE, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        // Creating that many links would make the test slow, so start just below the limit
+        storage
+            .metadata
+            .lock()
+            .unwrap()
+            .get_mut(&file)
+            .unwrap()
+            .hardlinks = MAX_HARDLINKS - 1;
+
+        storage.hardlink(file, ROOT_INODE, "last", context).unwrap();
+        assert_eq!(
+            storage.get_attributes(file).unwrap().hardlinks,
+            MAX_HARDLINKS
+        );
+        assert_eq!(
+            storage.hardlink(file, ROOT_INODE, "one_too_many", context),
+            Err(ErrorCode::TooManyLinks)
+        );
+        assert_eq!(
+            storage.lookup(ROOT_INODE, "one_too_many", context),
+            Ok(None)
+        );
+        assert_eq!(
+            storage.get_attributes(file).unwrap().hardlinks,
+            MAX_HARDLINKS
+        );
+
+        // Removing a link makes room for another
+        assert_eq!(storage.unlink(ROOT_INODE, "last", context), Ok(None));
+        storage
+            .hardlink(file, ROOT_INODE, "one_too_many", context)
+            .unwrap();
+    }
+
+    #[test]
+    fn rename_into_own_subtree() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "a", 0, 0, 0o755).unwrap();
+        let a = storage.lookup(ROOT_INODE, "a", context).unwrap().unwrap();
+        storage.mkdir(a, "b", 0, 0, 0o755).unwrap();
+        let b = storage.lookup(a, "b", context).unwrap().unwrap();
+        storage.mkdir(b, "c", 0, 0, 0o755).unwrap();
+        let c = storage.lookup(b, "c", context).unwrap().unwrap();
+
+        // Into itself, its child, and a deeper descendant
+        assert_eq!(
+            storage.rename(ROOT_INODE, "a", a, "a", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(
+            storage.rename(ROOT_INODE, "a", b, "a", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(
+            storage.rename(ROOT_INODE, "a", c, "a", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(
+            storage.rename(a, "b", c, "b", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(storage.paths_of(c).unwrap(), vec!["/a/b/c"]);
+        assert!(names(&storage, c).is_empty());
+
+        // Moving a directory up, or to a sibling's subtree, is fine
+        assert_eq!(storage.rename(a, "b", ROOT_INODE, "b", context), Ok(None));
+        assert_eq!(storage.rename(ROOT_INODE, "a", c, "a", context), Ok(None));
+        assert_eq!(storage.paths_of(a).unwrap(), vec!["/b/c/a"]);
+        assert_eq!(names(&storage, ROOT_INODE), vec!["b"]);
+        // Which makes the old ancestor a descendant
+        assert_eq!(
+            storage.rename(ROOT_INODE, "b", a, "b", context),
+            Err(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(storage.check().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn temporary_file_handles() {
+        let storage = MetadataStorage::new(false);
+        let first = storage
+            .create_temporary(ROOT_INODE, 0, 0, 0o600, 1)
+            .unwrap()
+            .inode;
+        storage.open_temporary(first, 1).unwrap();
+        storage.open_temporary(first, 2).unwrap();
+
+        // Deleted once every handle is released
+        assert_eq!(storage.release(first, 1), Ok(None));
+        assert_eq!(storage.release(first, 2), Ok(None));
+        assert_eq!(storage.release(first, 1), Ok(Some(first)));
+        assert_eq!(
+            storage.open_temporary(first, 1),
+            Err(ErrorCode::InodeDoesNotExist)
+        );
+
+        // Or when the sessions which hold it end, which survives snapshots
+        let second = storage
+            .create_temporary(ROOT_INODE, 0, 0, 0o600, 1)
+            .unwrap()
+            .inode;
+        storage.open_temporary(second, 2).unwrap();
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+        assert_eq!(restored.temporary_sessions(), vec![1, 2]);
+        assert_eq!(restored.end_session(1), Ok(vec![]));
+        assert_eq!(restored.end_session(2), Ok(vec![second]));
+        assert!(restored.temporary_sessions().is_empty());
+
+        // Linked files are kept
+        let third = storage
+            .create_temporary(ROOT_INODE, 0, 0, 0o600, 1)
+            .unwrap()
+            .inode;
+        storage
+            .hardlink(third, ROOT_INODE, "linked", UserContext::new(0, 0))
+            .unwrap();
+        assert_eq!(storage.release(third, 1), Ok(None));
+        assert_eq!(storage.end_session(1), Ok(vec![]));
+        assert!(storage.get_attributes(third).is_ok());
+    }
+
+    #[test]
+    fn client_ids_survive_snapshots() {
+        let storage = MetadataStorage::new(false);
+        assert_eq!(storage.allocate_client_id(), 1);
+        assert_eq!(storage.allocate_client_id(), 2);
+
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+        assert_eq!(restored.allocate_client_id(), 3);
+    }
+
+    #[test]
+    fn frozen_subtrees() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "frozen", 0, 0, 0o755).unwrap();
+        let frozen = storage
+            .lookup(ROOT_INODE, "frozen", context)
+            .unwrap()
+            .unwrap();
+        storage.mkdir(frozen, "nested", 0, 0, 0o755).unwrap();
+        let nested = storage.lookup(frozen, "nested", context).unwrap().unwrap();
+        let (file, _) = storage
+            .create(nested, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        let (other, _) = storage
+            .create(ROOT_INODE, "other", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+        storage.set_operation_time(Timestamp::new(100, 0));
+        storage.freeze(frozen, 10).unwrap();
+
+        assert_eq!(
+            storage.check_not_frozen(&[nested]),
+            Err(ErrorCode::ReadOnly)
+        );
+        assert_eq!(storage.check_not_frozen(&[file]), Err(ErrorCode::ReadOnly));
+        assert_eq!(storage.check_not_frozen(&[other]), Ok(()));
+        assert_eq!(storage.check_not_frozen(&[ROOT_INODE]), Ok(()));
+        // Files are frozen if any of their links is
+        storage.hardlink(other, nested, "other", context).unwrap();
+        assert_eq!(storage.check_not_frozen(&[other]), Err(ErrorCode::ReadOnly));
+
+        let restored = MetadataStorage::from_snapshot(&storage.snapshot().unwrap(), false);
+        assert_eq!(restored.check_not_frozen(&[file]), Err(ErrorCode::ReadOnly));
+        restored.thaw(frozen).unwrap();
+        assert_eq!(restored.check_not_frozen(&[file]), Ok(()));
+
+        // Thawed automatically once the timeout has passed
+        storage.set_operation_time(Timestamp::new(110, 0));
+        assert_eq!(storage.check_not_frozen(&[file]), Ok(()));
+    }
+
+    #[test]
+    fn searchable_paths() {
+        let root = UserContext::new(0, 0);
+        let other = UserContext::new(1000, 1000);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "private", 0, 0, 0o700).unwrap();
+        let private = storage
+            .lookup(ROOT_INODE, "private", root)
+            .unwrap()
+            .unwrap();
+        storage.mkdir(private, "nested", 0, 0, 0o755).unwrap();
+        let nested = storage.lookup(private, "nested", root).unwrap().unwrap();
+        let (file, _) = storage
+            .create(nested, "file", 0, 0, 0o644, FileKind::File)
+            .unwrap();
+
+        assert_eq!(
+            storage.searchable_paths_of(file, root),
+            Ok(vec!["/private/nested/file".to_string()])
+        );
+        assert_eq!(storage.searchable_paths_of(file, other), Ok(vec![]));
+        assert_eq!(storage.searchable_paths_of(nested, other), Ok(vec![]));
+        assert_eq!(
+            storage.searchable_paths_of(private, other),
+            Ok(vec!["/private".to_string()])
+        );
+
+        // Only the links under searchable directories are returned
+        storage.hardlink(file, ROOT_INODE, "public", root).unwrap();
+        assert_eq!(
+            storage.searchable_paths_of(file, other),
+            Ok(vec!["/public".to_string()])
+        );
+    }
+
+    // The expected changes are what ext4 does for the same operations
+    #[test]
+    fn timestamp_matrix() {
+        let context = UserContext::new(0, 0);
+        let storage = MetadataStorage::new(false);
+        storage.mkdir(ROOT_INODE, "a", 0, 0, 0o755).unwrap();
+        storage.mkdir(ROOT_INODE, "b", 0, 0, 0o755).unwrap();
+        let a = storage.lookup(ROOT_INODE, "a", context).unwrap().unwrap();
+        let b = storage.lookup(ROOT_INODE, "b", context).unwrap().unwrap();
+
+        let mut file = 0;
+        assert_eq!(
+            changed(&storage, &[a, b], || {
+                file = storage
+                    .create(a, "file", 0, 0, 0o644, FileKind::File)
+                    .unwrap()
+                    .0;
+            }),
+            vec![(true, true), (false, false)]
+        );
+        let created = times(&storage, file);
+        assert_eq!(created.0, created.1);
+        assert_eq!(created.1, times(&storage, a).1);
+
+        // Data changes don't affect the parent
+        assert_eq!(
+            changed(&storage, &[file, a], || {
+                storage.write(file, 0, 10, context).unwrap();
+            }),
+            vec![(true, true), (false, false)]
+        );
+        assert_eq!(
+            changed(&storage, &[file, a], || {
+                storage.truncate(file, 5, context).unwrap();
+            }),
+            vec![(true, true), (false, false)]
+        );
+
+        assert_eq!(
+            changed(&storage, &[file, a], || {
+                storage.chmod(file, 0o600, context).unwrap();
+            }),
+            vec![(false, true), (false, false)]
+        );
+        assert_eq!(
+            changed(&storage, &[file, a], || {
+                storage.set_xattr(file, "user.key", b"value").unwrap();
+            }),
+            vec![(false, true), (false, false)]
+        );
+        assert_eq!(
+            changed(&storage, &[file, a], || {
+                storage.utimens(file, None, None, context).unwrap();
+            }),
+            vec![(false, true), (false, false)]
+        );
+
+        assert_eq!(
+            changed(&storage, &[file, a, b], || {
+                storage.hardlink(file, b, "link", context).unwrap();
+            }),
+            vec![(false, true), (false, false), (true, true)]
+        );
+        assert_eq!(
+            changed(&storage, &[file, a, b], || {
+                storage.unlink(b, "link", context).unwrap();
+            }),
+            vec![(false, true), (false, false), (true, true)]
+        );
+
+        let replaced = storage
+            .create(b, "replaced", 0, 0, 0o644, FileKind::File)
+            .unwrap()
+            .0;
+        storage
+            .hardlink(replaced, ROOT_INODE, "kept", context)
+            .unwrap();
+        assert_eq!(
+            changed(&storage, &[file, a, b, replaced], || {
+                storage.rename(a, "file", b, "replaced", context).unwrap();
+            }),
+            vec![(false, true), (true, true), (true, true), (false, true)]
+        );
+        let renamed = times(&storage, file);
+        assert_eq!(renamed.1, times(&storage, a).1);
+        assert_eq!(renamed.1, times(&storage, b).0);
+
+        assert_eq!(
+            changed(&storage, &[ROOT_INODE, a], || {
+                storage.mkdir(a, "c", 0, 0, 0o755).unwrap();
+            }),
+            vec![(false, false), (true, true)]
+        );
+        assert_eq!(
+            changed(&storage, &[ROOT_INODE, a], || {
+                storage.rmdir(a, "c", context).unwrap();
+            }),
+            vec![(false, false), (true, true)]
+        );
+    }
+
+    const NAMES: [&str; 3] = ["a", "b", "c"];
+    const USERS: [u32; 2] = [0, 1000];
+    const MODES: [u16; 4] = [0o755, 0o700, 0o777, 0o500];
+
+    #[derive(Debug)]
+    enum ModelOperation {
+        Create {
+            parent: u64,
+            name: &'static str,
+            uid: u32,
+            mode: u16,
+        },
+        Mkdir {
+            parent: u64,
+            name: &'static str,
+            uid: u32,
+            mode: u16,
+        },
+        Unlink {
+            parent: u64,
+            name: &'static str,
+            uid: u32,
+        },
+        Rmdir {
+            parent: u64,
+            name: &'static str,
+            uid: u32,
+        },
+        Rename {
+            parent: u64,
+            name: &'static str,
+            new_parent: u64,
+            new_name: &'static str,
+            uid: u32,
+        },
+        Hardlink {
+            inode: u64,
+            new_parent: u64,
+            new_name: &'static str,
+            uid: u32,
+        },
+        Chmod {
+            inode: u64,
+            mode: u16,
+            uid: u32,
+        },
+    }
+
+    struct ModelInode {
+        directory: bool,
+        uid: u32,
+        mode: u16,
+        hardlinks: u32,
+        // Only used for directories
+        parent: u64,
+        entries: BTreeMap<String, u64>,
+    }
+
+    // Reference model of the POSIX semantics of each operation, which MetadataStorage is compared against. Every
+    // user's gid is equal to their uid
+    struct Model {
+        inodes: BTreeMap<u64, ModelInode>,
+    }
+
+    impl Model {
+        fn new() -> Model {
+            let mut inodes = BTreeMap::new();
+            inodes.insert(
+                ROOT_INODE,
+                ModelInode {
+                    directory: true,
+                    uid: 0,
+                    mode: 0o755,
+                    hardlinks: 2,
+                    parent: ROOT_INODE,
+                    entries: BTreeMap::new(),
+                },
+            );
+            Model { inodes }
+        }
+
+        fn has_access(&self, inode: u64, uid: u32, mask: u16) -> bool {
+            let attributes = &self.inodes[&inode];
+            if uid == 0 {
+                return true;
+            }
+            let bits = if uid == attributes.uid {
+                attributes.mode >> 6
+            } else {
+                attributes.mode
+            };
+            bits & mask == mask
+        }
+
+        // Whether entries can be added to, and removed from, directory
+        fn can_modify(&self, directory: u64, uid: u32) -> bool {
+            self.has_access(directory, uid, (libc::W_OK | libc::X_OK) as u16)
+        }
+
+        fn entry(&self, directory: u64, name: &str) -> Option<u64> {
+            self.inodes[&directory].entries.get(name).cloned()
+        }
+
+        fn is_ancestor(&self, ancestor: u64, mut directory: u64) -> bool {
+            loop {
+                if directory == ancestor {
+                    return true;
+                }
+                if directory == ROOT_INODE {
+                    return false;
+                }
+                directory = self.inodes[&directory].parent;
+            }
+        }
+
+        fn inodes_where<F: Fn(&ModelInode) -> bool>(&self, filter: F) -> Vec<u64> {
+            self.inodes
+                .iter()
+                .filter(|(_, attributes)| filter(attributes))
+                .map(|(inode, _)| *inode)
+                .collect()
+        }
+
+        fn random_operation(&self, rng: &mut StdRng) -> ModelOperation {
+            let directories = self.inodes_where(|x| x.directory);
+            let files = self.inodes_where(|x| !x.directory);
+            // The root can't be chmod'ed through the filesystem, so it's never chosen
+            let others: Vec<u64> = self
+                .inodes
+                .keys()
+                .filter(|x| **x != ROOT_INODE)
+                .cloned()
+                .collect();
+            let name = *NAMES.choose(rng).unwrap();
+            let uid = *USERS.choose(rng).unwrap();
+            let mode = *MODES.choose(rng).unwrap();
+            let parent = *directories.choose(rng).unwrap();
+            match rng.gen_range(0, 7) {
+                0 => ModelOperation::Mkdir {
+                    parent,
+                    name,
+                    uid,
+                    mode,
+                },
+                1 => ModelOperation::Unlink { parent, name, uid },
+                2 => ModelOperation::Rmdir { parent, name, uid },
+                3 => ModelOperation::Rename {
+                    parent,
+                    name,
+                    new_parent: *directories.choose(rng).unwrap(),
+                    new_name: *NAMES.choose(rng).unwrap(),
+                    uid,
+                },
+                4 if !files.is_empty() => ModelOperation::Hardlink {
+                    inode: *files.choose(rng).unwrap(),
+                    new_parent: parent,
+                    new_name: name,
+                    uid,
+                },
+                5 if !others.is_empty() => ModelOperation::Chmod {
+                    inode: *others.choose(rng).unwrap(),
+                    mode,
+                    uid,
+                },
+                _ => ModelOperation::Create {
+                    parent,
+                    name,
+                    uid,
+                    mode,
+                },
+            }
+        }
+
+        fn allowed(&self, operation: &ModelOperation) -> bool {
+            match *operation {
+                ModelOperation::Create {
+                    parent, name, uid, ..
+                }
+                | ModelOperation::Mkdir {
+                    parent, name, uid, ..
+                } => self.can_modify(parent, uid) && self.entry(parent, name).is_none(),
+                ModelOperation::Unlink { parent, name, uid } => match self.entry(parent, name) {
+                    Some(inode) => !self.inodes[&inode].directory && self.can_modify(parent, uid),
+                    None => false,
+                },
+                ModelOperation::Rmdir { parent, name, uid } => match self.entry(parent, name) {
+                    Some(inode) => {
+                        self.inodes[&inode].directory
+                            && self.inodes[&inode].entries.is_empty()
+                            && self.can_modify(parent, uid)
+                    }
+                    None => false,
+                },
+                ModelOperation::Rename {
+                    parent,
+                    name,
+                    new_parent,
+                    new_name,
+                    uid,
+                } => {
+                    let inode = match self.entry(parent, name) {
+                        Some(inode) => inode,
+                        None => return false,
+                    };
+                    if !self.can_modify(parent, uid) || !self.can_modify(new_parent, uid) {
+                        return false;
+                    }
+                    let directory = self.inodes[&inode].directory;
+                    if let Some(replaced) = self.entry(new_parent, new_name) {
+                        if replaced == inode {
+                            return true;
+                        }
+                        let replaced = &self.inodes[&replaced];
+                        if replaced.directory != directory || !replaced.entries.is_empty() {
+                            return false;
+                        }
+                    }
+                    if directory && parent != new_parent {
+                        // The ".." entry of the directory changes
+                        return self.has_access(inode, uid, libc::W_OK as u16)
+                            && !self.is_ancestor(inode, new_parent);
+                    }
+                    true
+                }
+                ModelOperation::Hardlink {
+                    new_parent,
+                    new_name,
+                    uid,
+                    ..
+                } => self.can_modify(new_parent, uid) && self.entry(new_parent, new_name).is_none(),
+                ModelOperation::Chmod { inode, uid, .. } => {
+                    uid == 0 || uid == self.inodes[&inode].uid
+                }
+            }
+        }
+
+        fn remove_link(&mut self, inode: u64) {
+            let attributes = self.inodes.get_mut(&inode).unwrap();
+            attributes.hardlinks -= 1;
+            if attributes.directory || attributes.hardlinks == 0 {
+                self.inodes.remove(&inode);
+            }
+        }
+
+        // Applies an operation which was allowed. created is the inode MetadataStorage allocated, if any
+        fn apply(&mut self, operation: &ModelOperation, created: Option<u64>) {
+            match *operation {
+                ModelOperation::Create {
+                    parent,
+                    name,
+                    uid,
+                    mode,
+                }
+                | ModelOperation::Mkdir {
+                    parent,
+                    name,
+                    uid,
+                    mode,
+                } => {
+                    let inode = created.unwrap();
+                    let directory = match operation {
+                        ModelOperation::Mkdir { .. } => true,
+                        _ => false,
+                    };
+                    self.inodes.insert(
+                        inode,
+                        ModelInode {
+                            directory,
+                            uid,
+                            mode,
+                            hardlinks: 1,
+                            parent,
+                            entries: BTreeMap::new(),
+                        },
+                    );
+                    self.inodes
+                        .get_mut(&parent)
+                        .unwrap()
+                        .entries
+                        .insert(name.to_string(), inode);
+                }
+                ModelOperation::Unlink { parent, name, .. }
+                | ModelOperation::Rmdir { parent, name, .. } => {
+                    let inode = self
+                        .inodes
+                        .get_mut(&parent)
+                        .unwrap()
+                        .entries
+                        .remove(name)
+                        .unwrap();
+                    self.remove_link(inode);
+                }
+                ModelOperation::Rename {
+                    parent,
+                    name,
+                    new_parent,
+                    new_name,
+                    ..
+                } => {
+                    let inode = self.entry(parent, name).unwrap();
+                    if self.entry(new_parent, new_name) == Some(inode) {
+                        return;
+                    }
+                    self.inodes.get_mut(&parent).unwrap().entries.remove(name);
+                    let replaced = self
+                        .inodes
+                        .get_mut(&new_parent)
+                        .unwrap()
+                        .entries
+                        .insert(new_name.to_string(), inode);
+                    if let Some(replaced) = replaced {
+                        self.remove_link(replaced);
+                    }
+                    self.inodes.get_mut(&inode).unwrap().parent = new_parent;
+                }
+                ModelOperation::Hardlink {
+                    inode,
+                    new_parent,
+                    new_name,
+                    ..
+                } => {
+                    self.inodes.get_mut(&inode).unwrap().hardlinks += 1;
+                    self.inodes
+                        .get_mut(&new_parent)
+                        .unwrap()
+                        .entries
+                        .insert(new_name.to_string(), inode);
+                }
+                ModelOperation::Chmod { inode, mode, .. } => {
+                    self.inodes.get_mut(&inode).unwrap().mode = mode;
+                }
+            }
+        }
+    }
+
+    // Returns the inode created by the operation, if any
+    fn apply_to_storage(
+        storage: &MetadataStorage,
+        operation: &ModelOperation,
+    ) -> Result<Option<u64>, ErrorCode> {
+        match *operation {
+            ModelOperation::Create {
+                parent,
+                name,
+                uid,
+                mode,
+            } => {
+                let (inode, _) = storage.create(parent, name, uid, uid, mode, FileKind::File)?;
+                Ok(Some(inode))
+            }
+            ModelOperation::Mkdir {
+                parent,
+                name,
+                uid,
+                mode,
+            } => {
+                storage.mkdir(parent, name, uid, uid, mode)?;
+                Ok(storage.lookup(parent, name, UserContext::new(0, 0))?)
+            }
+            ModelOperation::Unlink { parent, name, uid } => storage
+                .unlink(parent, name, UserContext::new(uid, uid))
+                .map(|_| None),
+            ModelOperation::Rmdir { parent, name, uid } => storage
+                .rmdir(parent, name, UserContext::new(uid, uid))
+                .map(|_| None),
+            ModelOperation::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                uid,
+            } => storage
+                .rename(
+                    parent,
+                    name,
+                    new_parent,
+                    new_name,
+                    UserContext::new(uid, uid),
+                )
+                .map(|_| None),
+            ModelOperation::Hardlink {
+                inode,
+                new_parent,
+                new_name,
+                uid,
+            } => storage
+                .hardlink(inode, new_parent, new_name, UserContext::new(uid, uid))
+                .map(|_| None),
+            ModelOperation::Chmod { inode, mode, uid } => storage
+                .chmod(inode, u32::from(mode), UserContext::new(uid, uid))
+                .map(|_| None),
+        }
+    }
+
+    fn assert_matches(storage: &MetadataStorage, model: &Model, created: &[u64]) {
+        for (inode, expected) in model.inodes.iter() {
+            let attributes = storage.get_attributes(*inode).unwrap();
+            assert_eq!(attributes.mode, expected.mode, "mode of {}", inode);
+            assert_eq!(attributes.uid, expected.uid, "uid of {}", inode);
+            if expected.directory {
+                assert_eq!(attributes.kind, FileKind::Directory);
+                let (_, entries) = storage.readdir(*inode).unwrap();
+                let entries: BTreeMap<String, u64> = entries
+                    .into_iter()
+                    .filter(|(_, name, _)| name != "." && name != "..")
+                    .map(|(entry, name, _)| (name, entry))
+                    .collect();
+                assert_eq!(entries, expected.entries, "entries of {}", inode);
+            } else {
+                assert_eq!(attributes.kind, FileKind::File);
+                assert_eq!(
+                    attributes.hardlinks, expected.hardlinks,
+                    "links of {}",
+                    inode
+                );
+            }
+        }
+        for inode in created.iter() {
+            if !model.inodes.contains_key(inode) {
+                assert!(storage.get_attributes(*inode).is_err(), "{} exists", inode);
+            }
+        }
+    }
+
+    // Applies random sequences of operations, by random users, to MetadataStorage and the model, and checks that
+    // they accept the same operations, and end up with the same entries, permissions and link counts
+    #[test]
+    fn random_operations_match_model() {
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let storage = MetadataStorage::new(false);
+            let mut model = Model::new();
+            let mut created = vec![];
+            for step in 0..300 {
+                let operation = model.random_operation(&mut rng);
+                let allowed = model.allowed(&operation);
+                let result = apply_to_storage(&storage, &operation);
+                assert_eq!(
+                    result.is_ok(),
+                    allowed,
+                    "seed {}, step {}: {:?} returned {:?}",
+                    seed,
+                    step,
+                    operation,
+                    result
+                );
+                if let Ok(inode) = result {
+                    model.apply(&operation, inode);
+                    created.extend(inode);
+                }
+                assert_matches(&storage, &model, &created);
+            }
+        }
+    }
 }
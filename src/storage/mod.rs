@@ -1,6 +1,20 @@
+pub mod access_stats;
+pub mod archive;
+pub mod background_scheduler;
+pub mod block_cache;
+pub mod checksum;
+pub mod content_index;
 pub mod data_storage;
 pub mod file_storage;
+pub mod hybrid_clock;
+pub mod lock_manager;
 pub mod metadata_storage;
+pub mod observer_cache;
+pub mod operation;
+pub mod packed_storage;
 pub mod raft_manager;
+pub mod snapshot;
+pub mod staged_data;
+pub mod write_journal;
 
 pub use metadata_storage::ROOT_INODE;
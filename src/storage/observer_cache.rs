@@ -0,0 +1,93 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+type Inode = u64;
+
+// Data is cached in aligned chunks of this size
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+const MAX_CACHED_CHUNKS: usize = 4096;
+
+#[derive(Default)]
+struct CacheState {
+    chunks: HashMap<Inode, HashMap<u64, Vec<u8>>>,
+    // Order in which chunks were cached, for eviction. May contain chunks which were already invalidated
+    order: VecDeque<(Inode, u64)>,
+    cached_chunks: usize,
+    // Incremented whenever an inode is modified, so that fetches which raced with the modification aren't cached
+    generations: HashMap<Inode, u64>,
+}
+
+// Data cached by observer nodes, which don't store any data themselves, and instead fetch it on demand
+// from the voting nodes. Cached data is dropped when its file is modified
+pub struct ObserverCache {
+    state: Mutex<CacheState>,
+}
+
+impl ObserverCache {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> ObserverCache {
+        ObserverCache {
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn generation(&self, inode: Inode) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.generations.get(&inode).cloned().unwrap_or(0)
+    }
+
+    pub fn get(&self, inode: Inode, chunk: u64) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .chunks
+            .get(&inode)
+            .and_then(|chunks| chunks.get(&chunk))
+            .cloned()
+    }
+
+    // generation must be the value of generation() from before the chunk was fetched
+    pub fn insert(&self, inode: Inode, chunk: u64, generation: u64, data: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        if state.generations.get(&inode).cloned().unwrap_or(0) != generation {
+            return;
+        }
+
+        while state.cached_chunks >= MAX_CACHED_CHUNKS {
+            if let Some((evicted_inode, evicted_chunk)) = state.order.pop_front() {
+                remove_chunk(&mut state, evicted_inode, evicted_chunk);
+            } else {
+                break;
+            }
+        }
+        let previous = state
+            .chunks
+            .entry(inode)
+            .or_insert_with(HashMap::new)
+            .insert(chunk, data);
+        if previous.is_none() {
+            state.cached_chunks += 1;
+            state.order.push_back((inode, chunk));
+        }
+    }
+
+    pub fn invalidate(&self, inode: Inode) {
+        let mut state = self.state.lock().unwrap();
+        *state.generations.entry(inode).or_insert(0) += 1;
+        if let Some(chunks) = state.chunks.remove(&inode) {
+            state.cached_chunks -= chunks.len();
+        }
+    }
+}
+
+fn remove_chunk(state: &mut CacheState, inode: Inode, chunk: u64) {
+    let mut now_empty = false;
+    if let Some(chunks) = state.chunks.get_mut(&inode) {
+        if chunks.remove(&chunk).is_some() {
+            state.cached_chunks -= 1;
+        }
+        now_empty = chunks.is_empty();
+    }
+    if now_empty {
+        state.chunks.remove(&inode);
+    }
+}
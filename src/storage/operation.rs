@@ -0,0 +1,520 @@
+use crate::generated::*;
+use crate::storage::metadata_storage::{MAX_FREEZE_SECONDS, MAX_NAME_LENGTH, MAX_XATTR_SIZE};
+use crate::storage::ROOT_INODE;
+
+// A change to the filesystem, decoded from a request in the Raft log. The leader validates it before proposing
+// it, and every node validates it again before applying it. Validation only depends on the operation itself,
+// so an operation which the leader rejected can never be applied by another node
+pub enum Operation<'a> {
+    Hardlink {
+        inode: u64,
+        new_parent: u64,
+        new_name: &'a str,
+        context: UserContext,
+    },
+    Rename {
+        parent: u64,
+        name: &'a str,
+        new_parent: u64,
+        new_name: &'a str,
+        context: UserContext,
+    },
+    Chmod {
+        inode: u64,
+        mode: u32,
+        context: UserContext,
+    },
+    Chown {
+        inode: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        context: UserContext,
+    },
+    Truncate {
+        inode: u64,
+        new_length: u64,
+        context: UserContext,
+    },
+    Fsync {
+        inode: u64,
+    },
+    Create {
+        parent: u64,
+        name: &'a str,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        kind: FileKind,
+    },
+    CreateTemporary {
+        parent: u64,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        session_id: u64,
+    },
+    OpenTemporary {
+        inode: u64,
+        session_id: u64,
+    },
+    SetXattr {
+        inode: u64,
+        key: &'a str,
+        value: &'a [u8],
+        context: UserContext,
+    },
+    RemoveXattr {
+        inode: u64,
+        key: &'a str,
+    },
+    Unlink {
+        parent: u64,
+        name: &'a str,
+        context: UserContext,
+    },
+    Rmdir {
+        parent: u64,
+        name: &'a str,
+        context: UserContext,
+    },
+    Write {
+        inode: u64,
+        offset: u64,
+        data: &'a [u8],
+        context: UserContext,
+        fencing_token: u64,
+    },
+    WriteConditional {
+        inode: u64,
+        offset: u64,
+        data: &'a [u8],
+        expected_version: u64,
+        context: UserContext,
+    },
+    Append {
+        inode: u64,
+        data: &'a [u8],
+        context: UserContext,
+    },
+    WritePatch {
+        inode: u64,
+        patches: Vec<(u64, &'a [u8])>,
+        context: UserContext,
+    },
+    Utimens {
+        inode: u64,
+        atime: Option<&'a Timestamp>,
+        mtime: Option<&'a Timestamp>,
+        context: UserContext,
+    },
+    Mkdir {
+        parent: u64,
+        name: &'a str,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+    },
+    StagedWrite {
+        inode: u64,
+        offset: u64,
+        staged_id: u64,
+        context: UserContext,
+        fencing_token: u64,
+        holders: Vec<u64>,
+    },
+    UpdateAtime {
+        inodes: Vec<u64>,
+        atime: Timestamp,
+    },
+    Release {
+        inode: u64,
+        session_id: u64,
+    },
+    FilesystemRepair,
+    SetLimits {
+        limits: FilesystemLimits,
+    },
+    Freeze {
+        inode: u64,
+        timeout_seconds: u32,
+    },
+    Thaw {
+        inode: u64,
+    },
+    AllocateClientId,
+    EndSession {
+        session_id: u64,
+    },
+}
+
+impl<'a> Operation<'a> {
+    // Requests which don't change the filesystem aren't operations, and are rejected with BadRequest
+    pub fn from_request(request: &GenericRequest<'a>) -> Result<Operation<'a>, ErrorCode> {
+        let operation = match request.request_type() {
+            RequestType::HardlinkRequest => {
+                let hardlink_request = request
+                    .request_as_hardlink_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Hardlink {
+                    inode: hardlink_request.inode(),
+                    new_parent: hardlink_request.new_parent(),
+                    new_name: hardlink_request.new_name(),
+                    context: *hardlink_request.context(),
+                }
+            }
+            RequestType::RenameRequest => {
+                let rename_request = request
+                    .request_as_rename_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Rename {
+                    parent: rename_request.parent(),
+                    name: rename_request.name(),
+                    new_parent: rename_request.new_parent(),
+                    new_name: rename_request.new_name(),
+                    context: *rename_request.context(),
+                }
+            }
+            RequestType::ChmodRequest => {
+                let chmod_request = request
+                    .request_as_chmod_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Chmod {
+                    inode: chmod_request.inode(),
+                    mode: chmod_request.mode(),
+                    context: *chmod_request.context(),
+                }
+            }
+            RequestType::ChownRequest => {
+                let chown_request = request
+                    .request_as_chown_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Chown {
+                    inode: chown_request.inode(),
+                    uid: chown_request.uid().map(OptionalUInt::value),
+                    gid: chown_request.gid().map(OptionalUInt::value),
+                    context: *chown_request.context(),
+                }
+            }
+            RequestType::TruncateRequest => {
+                let truncate_request = request
+                    .request_as_truncate_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Truncate {
+                    inode: truncate_request.inode(),
+                    new_length: truncate_request.new_length(),
+                    context: *truncate_request.context(),
+                }
+            }
+            RequestType::FsyncRequest => {
+                let fsync_request = request
+                    .request_as_fsync_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Fsync {
+                    inode: fsync_request.inode(),
+                }
+            }
+            RequestType::CreateRequest => {
+                let create_request = request
+                    .request_as_create_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Create {
+                    parent: create_request.parent(),
+                    name: create_request.name(),
+                    uid: create_request.uid(),
+                    gid: create_request.gid(),
+                    mode: create_request.mode(),
+                    kind: create_request.kind(),
+                }
+            }
+            RequestType::CreateTemporaryRequest => {
+                let create_request = request
+                    .request_as_create_temporary_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::CreateTemporary {
+                    parent: create_request.parent(),
+                    uid: create_request.uid(),
+                    gid: create_request.gid(),
+                    mode: create_request.mode(),
+                    session_id: request.session_id(),
+                }
+            }
+            RequestType::OpenTemporaryRequest => {
+                let open_request = request
+                    .request_as_open_temporary_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::OpenTemporary {
+                    inode: open_request.inode(),
+                    session_id: request.session_id(),
+                }
+            }
+            RequestType::SetXattrRequest => {
+                let set_xattr_request = request
+                    .request_as_set_xattr_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::SetXattr {
+                    inode: set_xattr_request.inode(),
+                    key: set_xattr_request.key(),
+                    value: set_xattr_request.value(),
+                    context: *set_xattr_request.context(),
+                }
+            }
+            RequestType::RemoveXattrRequest => {
+                let remove_xattr_request = request
+                    .request_as_remove_xattr_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::RemoveXattr {
+                    inode: remove_xattr_request.inode(),
+                    key: remove_xattr_request.key(),
+                }
+            }
+            RequestType::UnlinkRequest => {
+                let unlink_request = request
+                    .request_as_unlink_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Unlink {
+                    parent: unlink_request.parent(),
+                    name: unlink_request.name(),
+                    context: *unlink_request.context(),
+                }
+            }
+            RequestType::RmdirRequest => {
+                let rmdir_request = request
+                    .request_as_rmdir_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Rmdir {
+                    parent: rmdir_request.parent(),
+                    name: rmdir_request.name(),
+                    context: *rmdir_request.context(),
+                }
+            }
+            RequestType::WriteRequest => {
+                let write_request = request
+                    .request_as_write_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Write {
+                    inode: write_request.inode(),
+                    offset: write_request.offset(),
+                    data: write_request.data(),
+                    context: *write_request.context(),
+                    fencing_token: write_request.fencing_token(),
+                }
+            }
+            RequestType::WriteConditionalRequest => {
+                let write_conditional_request = request
+                    .request_as_write_conditional_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::WriteConditional {
+                    inode: write_conditional_request.inode(),
+                    offset: write_conditional_request.offset(),
+                    data: write_conditional_request.data(),
+                    expected_version: write_conditional_request.expected_version(),
+                    context: *write_conditional_request.context(),
+                }
+            }
+            RequestType::AppendRequest => {
+                let append_request = request
+                    .request_as_append_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Append {
+                    inode: append_request.inode(),
+                    data: append_request.data(),
+                    context: *append_request.context(),
+                }
+            }
+            RequestType::WritePatchRequest => {
+                let write_patch_request = request
+                    .request_as_write_patch_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                let patches = write_patch_request.patches();
+                Operation::WritePatch {
+                    inode: write_patch_request.inode(),
+                    patches: (0..patches.len())
+                        .map(|i| (patches.get(i).offset(), patches.get(i).data()))
+                        .collect(),
+                    context: *write_patch_request.context(),
+                }
+            }
+            RequestType::UtimensRequest => {
+                let utimens_request = request
+                    .request_as_utimens_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Utimens {
+                    inode: utimens_request.inode(),
+                    atime: utimens_request.atime(),
+                    mtime: utimens_request.mtime(),
+                    context: *utimens_request.context(),
+                }
+            }
+            RequestType::MkdirRequest => {
+                let mkdir_request = request
+                    .request_as_mkdir_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Mkdir {
+                    parent: mkdir_request.parent(),
+                    name: mkdir_request.name(),
+                    uid: mkdir_request.uid(),
+                    gid: mkdir_request.gid(),
+                    mode: mkdir_request.mode(),
+                }
+            }
+            RequestType::StagedWriteRequest => {
+                let staged_write_request = request
+                    .request_as_staged_write_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::StagedWrite {
+                    inode: staged_write_request.inode(),
+                    offset: staged_write_request.offset(),
+                    staged_id: staged_write_request.staged_id(),
+                    context: *staged_write_request.context(),
+                    fencing_token: staged_write_request.fencing_token(),
+                    holders: staged_write_request
+                        .holders()
+                        .map(|holders| (0..holders.len()).map(|i| holders.get(i)).collect())
+                        .unwrap_or_default(),
+                }
+            }
+            RequestType::UpdateAtimeRequest => {
+                let update_atime_request = request
+                    .request_as_update_atime_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                let inodes = update_atime_request.inodes();
+                Operation::UpdateAtime {
+                    inodes: (0..inodes.len()).map(|i| inodes.get(i)).collect(),
+                    atime: *update_atime_request.atime(),
+                }
+            }
+            RequestType::ReleaseRequest => {
+                let release_request = request
+                    .request_as_release_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Release {
+                    inode: release_request.inode(),
+                    session_id: request.session_id(),
+                }
+            }
+            RequestType::FilesystemRepairRequest => Operation::FilesystemRepair,
+            RequestType::SetLimitsRequest => {
+                let set_limits_request = request
+                    .request_as_set_limits_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::SetLimits {
+                    limits: *set_limits_request.limits(),
+                }
+            }
+            RequestType::FreezeRequest => {
+                let freeze_request = request
+                    .request_as_freeze_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Freeze {
+                    inode: freeze_request.inode(),
+                    timeout_seconds: freeze_request.timeout_seconds(),
+                }
+            }
+            RequestType::ThawRequest => {
+                let thaw_request = request
+                    .request_as_thaw_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::Thaw {
+                    inode: thaw_request.inode(),
+                }
+            }
+            RequestType::AllocateClientIdRequest => Operation::AllocateClientId,
+            RequestType::EndSessionRequest => {
+                let end_request = request
+                    .request_as_end_session_request()
+                    .ok_or(ErrorCode::BadRequest)?;
+                Operation::EndSession {
+                    session_id: end_request.session_id(),
+                }
+            }
+            _ => return Err(ErrorCode::BadRequest),
+        };
+
+        return Ok(operation);
+    }
+
+    // Checks everything about the operation which doesn't depend on the state of the filesystem. The checks which
+    // do are made when it's applied, and give the same result on every node, since they all apply the same log
+    pub fn validate(&self) -> Result<(), ErrorCode> {
+        match *self {
+            // The root directory's attributes can't be changed, and it can't be linked elsewhere
+            Operation::Hardlink { inode, .. }
+            | Operation::Chmod { inode, .. }
+            | Operation::Chown { inode, .. }
+            | Operation::Utimens { inode, .. } => {
+                if inode == ROOT_INODE {
+                    return Err(ErrorCode::OperationNotPermitted);
+                }
+            }
+            // Directories are only created by mkdir, which also creates their entries
+            Operation::Create { kind, .. } => {
+                if kind == FileKind::Directory {
+                    return Err(ErrorCode::BadRequest);
+                }
+            }
+            // File sizes are signed in stat
+            Operation::SetLimits { limits } => {
+                if limits.max_file_size() == 0
+                    || limits.max_file_size() > i64::MAX as u64
+                    || limits.max_inodes() == 0
+                    || limits.max_name_length() == 0
+                    || limits.max_name_length() > MAX_NAME_LENGTH
+                    || limits.max_xattr_size() > MAX_XATTR_SIZE
+                {
+                    return Err(ErrorCode::InvalidArgument);
+                }
+            }
+            // Subtrees are only frozen for a limited time, so that a backup tool which dies can't leave them frozen
+            Operation::Freeze {
+                timeout_seconds, ..
+            } => {
+                if timeout_seconds == 0 || timeout_seconds > MAX_FREEZE_SECONDS {
+                    return Err(ErrorCode::InvalidArgument);
+                }
+            }
+            _ => {}
+        }
+
+        return Ok(());
+    }
+
+    // The inodes which the operation changes, or whose entries it changes. It fails if any of them is in a frozen
+    // subtree
+    pub fn changed_inodes(&self) -> Vec<u64> {
+        match *self {
+            Operation::Hardlink {
+                inode, new_parent, ..
+            } => vec![inode, new_parent],
+            Operation::Rename {
+                parent, new_parent, ..
+            } => vec![parent, new_parent],
+            Operation::Chmod { inode, .. }
+            | Operation::Chown { inode, .. }
+            | Operation::Truncate { inode, .. }
+            | Operation::SetXattr { inode, .. }
+            | Operation::RemoveXattr { inode, .. }
+            | Operation::Write { inode, .. }
+            | Operation::WriteConditional { inode, .. }
+            | Operation::Append { inode, .. }
+            | Operation::WritePatch { inode, .. }
+            | Operation::Utimens { inode, .. }
+            | Operation::StagedWrite { inode, .. } => vec![inode],
+            Operation::Create { parent, .. }
+            | Operation::CreateTemporary { parent, .. }
+            | Operation::Unlink { parent, .. }
+            | Operation::Rmdir { parent, .. }
+            | Operation::Mkdir { parent, .. } => vec![parent],
+            // Access times and syncs don't change the contents which are backed up
+            Operation::Fsync { .. }
+            | Operation::UpdateAtime { .. }
+            | Operation::Release { .. }
+            | Operation::OpenTemporary { .. }
+            | Operation::EndSession { .. }
+            | Operation::FilesystemRepair
+            | Operation::SetLimits { .. }
+            | Operation::Freeze { .. }
+            | Operation::Thaw { .. }
+            | Operation::AllocateClientId => vec![],
+        }
+    }
+}
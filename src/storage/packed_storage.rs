@@ -0,0 +1,496 @@
+use crate::storage::background_scheduler::{BackgroundScheduler, BackgroundTask};
+use byteorder::{ByteOrder, LittleEndian};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+// Files up to this size are packed
+pub const MAX_PACKED_FILE_SIZE: u64 = 4096;
+// A new segment is started once the current one reaches this size
+const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// inode, segment, offset, length
+const INDEX_ENTRY_SIZE: usize = 32;
+const SEGMENT_PREFIX: &str = "segment-";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct PackedLocation {
+    segment: u64,
+    offset: u64,
+    length: u64,
+}
+
+// Inodes whose own files are being modified, or packed
+struct InodeLocks {
+    locked: Mutex<HashSet<u64>>,
+    released: Condvar,
+}
+
+impl InodeLocks {
+    fn lock(&self, inode: u64) -> InodeGuard {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(&inode) {
+            locked = self.released.wait(locked).unwrap();
+        }
+        locked.insert(inode);
+
+        InodeGuard { locks: self, inode }
+    }
+
+    fn try_lock(&self, inode: u64) -> Option<InodeGuard> {
+        if self.locked.lock().unwrap().insert(inode) {
+            Some(InodeGuard { locks: self, inode })
+        } else {
+            None
+        }
+    }
+}
+
+// Held while an inode's own file is modified, so that it isn't packed concurrently
+pub struct InodeGuard<'a> {
+    locks: &'a InodeLocks,
+    inode: u64,
+}
+
+impl<'a> Drop for InodeGuard<'a> {
+    fn drop(&mut self) {
+        self.locks.locked.lock().unwrap().remove(&self.inode);
+        self.locks.released.notify_all();
+    }
+}
+
+// Packed segments are stored next to the data directory, so that they don't show up as files in it
+pub fn packed_directory(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).with_file_name("packed")
+}
+
+// Inodes which are stored in packed segments
+pub fn packed_inodes(directory: &Path) -> io::Result<Vec<u64>> {
+    Ok(read_index(directory)?.keys().cloned().collect())
+}
+
+fn segment_path(directory: &Path, segment: u64) -> PathBuf {
+    directory.join(format!("{}{}", SEGMENT_PREFIX, segment))
+}
+
+fn list_segments(directory: &Path) -> io::Result<Vec<u64>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(error),
+    };
+
+    let mut segments = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str() {
+            if name.starts_with(SEGMENT_PREFIX) {
+                if let Ok(segment) = name.trim_start_matches(SEGMENT_PREFIX).parse() {
+                    segments.push(segment);
+                }
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+fn read_index(directory: &Path) -> io::Result<HashMap<u64, PackedLocation>> {
+    let data = match fs::read(directory.join("index")) {
+        Ok(data) => data,
+        Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut index = HashMap::new();
+    for entry in data.chunks_exact(INDEX_ENTRY_SIZE) {
+        let location = PackedLocation {
+            segment: LittleEndian::read_u64(&entry[8..16]),
+            offset: LittleEndian::read_u64(&entry[16..24]),
+            length: LittleEndian::read_u64(&entry[24..32]),
+        };
+        index.insert(LittleEndian::read_u64(&entry[0..8]), location);
+    }
+
+    Ok(index)
+}
+
+// The index is written to a temporary file, and then renamed over the old one, so that it's replaced atomically
+fn write_index(directory: &Path, index: &HashMap<u64, PackedLocation>) -> io::Result<()> {
+    let mut data = vec![0; index.len() * INDEX_ENTRY_SIZE];
+    for (entry, (inode, location)) in data.chunks_exact_mut(INDEX_ENTRY_SIZE).zip(index.iter()) {
+        LittleEndian::write_u64(&mut entry[0..8], *inode);
+        LittleEndian::write_u64(&mut entry[8..16], location.segment);
+        LittleEndian::write_u64(&mut entry[16..24], location.offset);
+        LittleEndian::write_u64(&mut entry[24..32], location.length);
+    }
+
+    let temp_path = directory.join("index.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(temp_path, directory.join("index"))
+}
+
+// Small files are packed together into append only segments, to save space and IOPS.
+// A file is moved back to its own file in the data directory before it's modified, and the space
+// it used in its segment is reclaimed by compact(). The index lock is only held to look up or update
+// locations, so reads may find that a file moved since it was looked up, and look it up again
+pub struct PackedStorage {
+    data_dir: PathBuf,
+    directory: PathBuf,
+    index: Mutex<HashMap<u64, PackedLocation>>,
+    // Held while appending to the current segment
+    current_segment: Mutex<u64>,
+    inode_locks: InodeLocks,
+}
+
+impl PackedStorage {
+    pub fn new(data_dir: &str) -> PackedStorage {
+        let directory = packed_directory(data_dir);
+        let data_dir = Path::new(data_dir).to_path_buf();
+        let mut index = read_index(&directory).expect("Failed to read packed index");
+        // If we crashed while unpacking a file, its own file is the latest version
+        index.retain(|inode, _| !data_dir.join(inode.to_string()).exists());
+        // Always start a new segment, in case the last one has a partially written tail
+        let last_segment = list_segments(&directory)
+            .expect("Failed to list packed segments")
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+
+        PackedStorage {
+            data_dir,
+            directory,
+            index: Mutex::new(index),
+            current_segment: Mutex::new(last_segment + 1),
+            inode_locks: InodeLocks {
+                locked: Mutex::new(HashSet::new()),
+                released: Condvar::new(),
+            },
+        }
+    }
+
+    fn loose_path(&self, inode: u64) -> PathBuf {
+        self.data_dir.join(inode.to_string())
+    }
+
+    fn location(&self, inode: u64) -> Option<PackedLocation> {
+        self.index.lock().unwrap().get(&inode).cloned()
+    }
+
+    pub fn is_packed(&self, inode: u64) -> bool {
+        self.location(inode).is_some()
+    }
+
+    // Reads inode from its segment, or returns None if it isn't packed. If compaction moved it while it was read,
+    // its old segment is gone, and it's read from the new one
+    fn read_packed(&self, inode: u64, buffer: &mut [u8], offset: u64) -> io::Result<Option<usize>> {
+        loop {
+            let location = match self.location(inode) {
+                Some(location) => location,
+                None => return Ok(None),
+            };
+            if offset >= location.length {
+                return Ok(Some(0));
+            }
+            let length = (location.length - offset).min(buffer.len() as u64) as usize;
+            let read =
+                File::open(segment_path(&self.directory, location.segment)).and_then(|file| {
+                    file.read_exact_at(&mut buffer[..length], location.offset + offset)
+                });
+            match read {
+                Ok(_) => return Ok(Some(length)),
+                Err(ref error)
+                    if error.kind() == ErrorKind::NotFound
+                        && self.location(inode) != Some(location) =>
+                {
+                    continue
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn read_at(&self, inode: u64, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        loop {
+            if let Some(length) = self.read_packed(inode, buffer, offset)? {
+                return Ok(length);
+            }
+            match File::open(self.loose_path(inode)) {
+                Ok(file) => return file.read_at(buffer, offset),
+                // Packed since it was looked up
+                Err(ref error) if error.kind() == ErrorKind::NotFound && self.is_packed(inode) => {
+                    continue
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    // Moves inode back into its own file, if it's packed. The returned guard must be held while
+    // modifying the file, so that it isn't packed concurrently
+    pub fn unpack(&self, inode: u64) -> io::Result<InodeGuard> {
+        let guard = self.inode_locks.lock(inode);
+        // Nothing else packs, unpacks, or deletes inode while the guard is held
+        if let Some(location) = self.location(inode) {
+            let mut contents = vec![0; location.length as usize];
+            let length = self.read_packed(inode, &mut contents, 0)?.unwrap_or(0);
+            contents.truncate(length);
+            let mut file = File::create(self.loose_path(inode))?;
+            file.write_all(&contents)?;
+            file.sync_all()?;
+
+            let mut index = self.index.lock().unwrap();
+            index.remove(&inode);
+            write_index(&self.directory, &index)?;
+        }
+
+        Ok(guard)
+    }
+
+    pub fn delete(&self, inode: u64) -> io::Result<()> {
+        let _guard = self.inode_locks.lock(inode);
+        let packed = {
+            let mut index = self.index.lock().unwrap();
+            let packed = index.remove(&inode).is_some();
+            if packed {
+                write_index(&self.directory, &index)?;
+            }
+            packed
+        };
+        match fs::remove_file(self.loose_path(inode)) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound && packed => Ok(()),
+            other => other,
+        }
+    }
+
+    // Packs the given inodes, and deletes their own files. Inodes which are being modified are skipped. Returns
+    // the number packed
+    pub fn pack(&self, inodes: &[u64]) -> io::Result<usize> {
+        let mut files = vec![];
+        let mut guards = vec![];
+        for inode in inodes {
+            let guard = match self.inode_locks.try_lock(*inode) {
+                Some(guard) => guard,
+                None => continue,
+            };
+            if self.is_packed(*inode) {
+                continue;
+            }
+            let contents = match fs::read(self.loose_path(*inode)) {
+                Ok(contents) => contents,
+                // Deleted since it was selected
+                Err(ref error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            // It may have grown since it was selected
+            if contents.len() as u64 <= MAX_PACKED_FILE_SIZE {
+                files.push((*inode, contents));
+                guards.push(guard);
+            }
+        }
+        if files.is_empty() {
+            return Ok(0);
+        }
+
+        let locations = self.append(files)?;
+        {
+            let mut index = self.index.lock().unwrap();
+            for (inode, location) in locations.iter() {
+                index.insert(*inode, *location);
+            }
+            write_index(&self.directory, &index)?;
+        }
+        for (inode, _) in locations.iter() {
+            fs::remove_file(self.loose_path(*inode))?;
+        }
+
+        Ok(locations.len())
+    }
+
+    // Rewrites segments which are mostly free space, because their files were deleted or unpacked. Each segment is
+    // only rewritten if the scheduler admits it
+    pub fn compact(&self, scheduler: &BackgroundScheduler) -> io::Result<()> {
+        let mut segment_files: HashMap<u64, Vec<(u64, PackedLocation)>> = HashMap::new();
+        for (inode, location) in self.index.lock().unwrap().iter() {
+            segment_files
+                .entry(location.segment)
+                .or_insert_with(Vec::new)
+                .push((*inode, *location));
+        }
+        let current_segment = *self.current_segment.lock().unwrap();
+
+        for segment in list_segments(&self.directory)? {
+            if segment >= current_segment {
+                continue;
+            }
+            let path = segment_path(&self.directory, segment);
+            let size = fs::metadata(&path)?.len();
+            let old_locations = segment_files.remove(&segment).unwrap_or_default();
+            let segment_live_bytes: u64 = old_locations.iter().map(|(_, x)| x.length).sum();
+            if segment_live_bytes * 2 > size {
+                continue;
+            }
+            if !scheduler.admit(BackgroundTask::Compact) {
+                break;
+            }
+
+            let old_segment = File::open(&path)?;
+            let mut files = vec![];
+            for (inode, location) in old_locations.iter() {
+                let mut contents = vec![0; location.length as usize];
+                old_segment.read_exact_at(&mut contents, location.offset)?;
+                files.push((*inode, contents));
+            }
+
+            let locations = self.append(files)?;
+            let mut moved = 0;
+            {
+                let mut index = self.index.lock().unwrap();
+                for ((inode, location), (_, old_location)) in
+                    locations.iter().zip(old_locations.iter())
+                {
+                    // Files which were unpacked or deleted meanwhile stay that way
+                    if index.get(inode) == Some(old_location) {
+                        index.insert(*inode, *location);
+                        moved += 1;
+                    }
+                }
+                write_index(&self.directory, &index)?;
+            }
+            fs::remove_file(&path)?;
+            scheduler.charge(BackgroundTask::Compact, segment_live_bytes);
+            info!("Compacted segment {}. Moved {} files", segment, moved);
+        }
+
+        Ok(())
+    }
+
+    // Appends the files to the current segment, and returns their locations once they're durable
+    fn append(&self, files: Vec<(u64, Vec<u8>)>) -> io::Result<Vec<(u64, PackedLocation)>> {
+        let mut current_segment = self.current_segment.lock().unwrap();
+        fs::create_dir_all(&self.directory)?;
+        let mut segment = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.directory, *current_segment))?;
+        let mut offset = segment.metadata()?.len();
+
+        let mut locations = vec![];
+        for (inode, contents) in files {
+            segment.write_all(&contents)?;
+            let location = PackedLocation {
+                segment: *current_segment,
+                offset,
+                length: contents.len() as u64,
+            };
+            locations.push((inode, location));
+            offset += contents.len() as u64;
+        }
+        segment.sync_all()?;
+
+        if offset >= MAX_SEGMENT_SIZE {
+            *current_segment += 1;
+        }
+
+        Ok(locations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::background_scheduler::BackgroundScheduler;
+    use crate::storage::packed_storage::PackedStorage;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    fn test_data_dir(name: &str) -> String {
+        let directory = env::temp_dir().join(format!("fleetfs-packed-{}-{}", name, process::id()));
+        fs::remove_dir_all(&directory).ok();
+        let data_dir = directory.join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        data_dir.to_str().unwrap().to_string()
+    }
+
+    fn read_all(storage: &PackedStorage, inode: u64) -> Vec<u8> {
+        let mut buffer = vec![0; 8192];
+        let length = storage.read_at(inode, &mut buffer, 0).unwrap();
+        buffer.truncate(length);
+        buffer
+    }
+
+    #[test]
+    fn round_trip() {
+        let data_dir = test_data_dir("round-trip");
+        fs::write(Path::new(&data_dir).join("2"), vec![2; 100]).unwrap();
+        fs::write(Path::new(&data_dir).join("3"), vec![3; 10]).unwrap();
+        let storage = PackedStorage::new(&data_dir);
+
+        assert_eq!(storage.pack(&[2, 3, 4]).unwrap(), 2);
+        assert!(storage.is_packed(2));
+        assert!(!Path::new(&data_dir).join("2").exists());
+        assert_eq!(read_all(&storage, 2), vec![2; 100]);
+        assert_eq!(read_all(&storage, 3), vec![3; 10]);
+        let mut buffer = vec![0; 5];
+        assert_eq!(storage.read_at(2, &mut buffer, 98).unwrap(), 2);
+        assert_eq!(storage.read_at(2, &mut buffer, 100).unwrap(), 0);
+
+        // Reopening reads the index back
+        let storage = PackedStorage::new(&data_dir);
+        assert_eq!(read_all(&storage, 3), vec![3; 10]);
+
+        let guard = storage.unpack(2).unwrap();
+        assert!(!storage.is_packed(2));
+        assert_eq!(
+            fs::read(Path::new(&data_dir).join("2")).unwrap(),
+            vec![2; 100]
+        );
+        // Files which are being modified aren't packed
+        assert_eq!(storage.pack(&[2]).unwrap(), 0);
+        drop(guard);
+        assert_eq!(read_all(&storage, 2), vec![2; 100]);
+
+        storage.delete(3).unwrap();
+        assert!(!storage.is_packed(3));
+        assert!(storage.read_at(3, &mut buffer, 0).is_err());
+        storage.delete(2).unwrap();
+        assert!(!Path::new(&data_dir).join("2").exists());
+
+        fs::remove_dir_all(Path::new(&data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_live_files() {
+        let data_dir = test_data_dir("compact");
+        for inode in 2..6 {
+            fs::write(
+                Path::new(&data_dir).join(inode.to_string()),
+                vec![inode as u8; 100],
+            )
+            .unwrap();
+        }
+        let storage = PackedStorage::new(&data_dir);
+        assert_eq!(storage.pack(&[2, 3, 4, 5]).unwrap(), 4);
+        storage.delete(2).unwrap();
+        storage.delete(3).unwrap();
+        drop(storage.unpack(4).unwrap());
+
+        // The segment is only compacted once it's no longer the current one
+        let storage = PackedStorage::new(&data_dir);
+        storage.compact(&BackgroundScheduler::new(0)).unwrap();
+        assert!(storage.is_packed(5));
+        assert!(!storage.is_packed(4));
+        assert_eq!(read_all(&storage, 5), vec![5; 100]);
+        assert_eq!(read_all(&storage, 4), vec![4; 100]);
+
+        fs::remove_dir_all(Path::new(&data_dir).parent().unwrap()).unwrap();
+    }
+}
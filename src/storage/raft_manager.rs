@@ -1,82 +1,240 @@
-use log::{error, info};
-use raft::eraftpb::Message;
+use log::{error, info, warn};
+use raft::eraftpb::{ConfChange, ConfChangeType, Entry, Message, MessageType, Snapshot};
 use raft::prelude::EntryType;
 use raft::storage::MemStorage;
-use raft::{Config, RawNode};
+use raft::{Config, RawNode, Storage};
 use std::sync::Mutex;
 
+use crate::client::NodeClient;
+use crate::event_hooks::EventKind;
 use crate::generated::*;
 use crate::peer_client::PeerClient;
 use crate::storage::file_storage::FileStorage;
+use crate::storage::hybrid_clock::{decode_timestamp, encode_timestamp, HybridClock};
+use crate::storage::lock_manager::LockManager;
+use crate::storage::operation::Operation;
+use crate::storage::snapshot::{InstalledSnapshot, SnapshotInfo};
+use crate::storage::staged_data::STAGED_DATA_TIMEOUT;
 use crate::storage_node::LocalContext;
-use crate::utils::{node_id_from_address, FlatBufferResponse};
+use crate::utils::{
+    finalize_request, finalize_response, node_id_from_address, to_error_response,
+    to_write_response, FlatBufferResponse, FlatBufferWithResponse, LengthPrefixedVec,
+    ResultResponse,
+};
 use flatbuffers::FlatBufferBuilder;
-use futures::future::{ok, Either};
-use futures::sync::oneshot;
+use futures::future::{err, ok, Either};
 use futures::sync::oneshot::Sender;
-use futures::Future;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Stream};
 use rand::Rng;
-use std::cmp::max;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A new snapshot is created for new nodes, if the latest one is older than this. Otherwise it's reused,
+// so that interrupted transfers can be resumed
+const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(600);
+// How long to wait before asking the other nodes for the data of a staged write again, if none of them had it
+const STAGED_FETCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Requests applied from a client session, whose responses the client may not have received yet
+#[derive(Clone, Default)]
+pub struct AppliedSession {
+    // Every request with a lower sequence number was answered, or given up on, by the client
+    pub acknowledged: u64,
+    // Response to each request applied since, by sequence number
+    pub responses: BTreeMap<u64, Vec<u8>>,
+}
+
+impl AppliedSession {
+    // Forgets the responses to the requests before acknowledged. It never moves backwards, since requests which
+    // aren't sent by the client itself don't acknowledge anything
+    fn acknowledge(&mut self, acknowledged: u64) {
+        if acknowledged > self.acknowledged {
+            self.responses = self.responses.split_off(&acknowledged);
+            self.acknowledged = acknowledged;
+        }
+    }
+
+    // Whether the request was applied, or its client already stopped waiting for it. In that case it's a retry, or
+    // a copy which was delayed, and mustn't be applied again
+    fn is_applied(&self, sequence_number: u64) -> bool {
+        sequence_number < self.acknowledged || self.responses.contains_key(&sequence_number)
+    }
+
+    fn record(&mut self, sequence_number: u64, response: Vec<u8>) {
+        self.responses.insert(sequence_number, response);
+    }
+}
 
 type PendingResponse = (
     FlatBufferBuilder<'static>,
-    Sender<Result<FlatBufferResponse<'static>, ErrorCode>>,
+    Sender<Result<FlatBufferWithResponse<'static>, ErrorCode>>,
 );
 
+// Consecutive writes to an inode, which will be proposed as a single write
+struct CoalescedWrite {
+    offset: u64,
+    data: Vec<u8>,
+    context: UserContext,
+    started: Instant,
+    // Length of each of the original writes, and where to send its response
+    waiters: Vec<(u32, PendingResponse)>,
+    // Session, sequence number, and length of each of the original writes
+    sources: Vec<CoalescedSource>,
+}
+
 pub struct RaftManager {
     raft_node: Mutex<RawNode<MemStorage>>,
     pending_responses: Mutex<HashMap<u128, PendingResponse>>,
+    // Writes waiting for more writes to coalesce with, by inode
+    coalescing_writes: Mutex<HashMap<u64, CoalescedWrite>>,
+    // Original writes of the coalesced writes that have been proposed
+    coalesced_responses: Mutex<HashMap<u128, Vec<(u32, PendingResponse)>>>,
     sync_requests: Mutex<Vec<(u64, Sender<()>)>>,
     leader_requests: Mutex<Vec<Sender<u64>>>,
     applied_index: AtomicU64,
+    // Committed entries which haven't been applied yet, in order
+    unapplied_entries: Mutex<VecDeque<Entry>>,
+    // Staged writes whose data is being fetched from other nodes. Receives None if none of them has it
+    staged_fetches: Mutex<HashMap<u64, std_mpsc::Receiver<Option<Vec<u8>>>>>,
+    // Requests applied from each client session, and the responses they produced. Updated during apply, so that
+    // it's identical on every node
+    applied_sessions: Mutex<HashMap<u64, AppliedSession>>,
+    // Inodes read in relatime mode, whose access time will be updated in the next batch
+    pending_atime_updates: Mutex<HashSet<u64>>,
+    // Latest snapshot, and when it was created
+    latest_snapshot: Mutex<Option<(Instant, SnapshotInfo)>>,
     peers: HashMap<u64, PeerClient>,
     node_id: u64,
     context: LocalContext,
     file_storage: FileStorage,
+    // Assigns the time of the operations proposed by this node
+    clock: HybridClock,
+    // Only used while this node is the leader
+    locks: LockManager,
+    // Set while a node which joined from a snapshot catches up with the log. It neither campaigns nor votes until
+    // then, so that it can't disrupt the cluster, and doesn't serve clients
+    recovering: AtomicBool,
+    // Applied index of the leader when this node started recovering, which it must reach. 0 until it's known
+    recovery_target: AtomicU64,
+    // Nodes removed by force_new_cluster, whose removal hasn't been proposed yet
+    pending_removals: Mutex<Vec<u64>>,
+}
+
+fn remove_node_change(node_id: u64) -> ConfChange {
+    let mut change = ConfChange::new();
+    change.set_change_type(ConfChangeType::RemoveNode);
+    change.set_node_id(node_id);
+    change
 }
 
 impl RaftManager {
-    pub fn new(context: LocalContext) -> RaftManager {
+    // If a snapshot was installed, the Raft log continues from it
+    pub fn new(context: LocalContext, snapshot: Option<InstalledSnapshot>) -> RaftManager {
         let node_id = context.node_id;
         let mut peer_ids: Vec<u64> = context
             .peers
             .iter()
             .map(|peer| node_id_from_address(peer))
             .collect();
-        peer_ids.push(node_id);
+        // Observers follow the log as Raft learners
+        let mut learner_ids: Vec<u64> = context
+            .observers
+            .iter()
+            .map(|peer| node_id_from_address(peer))
+            .collect();
+        if context.observer {
+            learner_ids.push(node_id);
+        } else {
+            peer_ids.push(node_id);
+        }
+
+        let raft_storage = MemStorage::new();
+        let mut applied = 0;
+        let mut applied_sessions = HashMap::new();
+        if let Some(ref installed) = snapshot {
+            let mut raft_snapshot = Snapshot::new();
+            raft_snapshot.mut_metadata().set_index(installed.index);
+            raft_snapshot.mut_metadata().set_term(installed.term);
+            raft_snapshot
+                .mut_metadata()
+                .mut_conf_state()
+                .set_nodes(peer_ids.clone());
+            raft_snapshot
+                .mut_metadata()
+                .mut_conf_state()
+                .set_learners(learner_ids.clone());
+            raft_storage.wl().apply_snapshot(raft_snapshot).unwrap();
+            applied = installed.index;
+            for (session_id, session) in installed.sessions.iter() {
+                applied_sessions.insert(*session_id, session.clone());
+            }
+        }
 
         let raft_config = Config {
             id: node_id,
-            peers: peer_ids.clone(),
-            learners: vec![],
+            // The peers are stored in the snapshot, if there is one
+            peers: if snapshot.is_some() {
+                vec![]
+            } else {
+                peer_ids.clone()
+            },
+            learners: if snapshot.is_some() {
+                vec![]
+            } else {
+                learner_ids
+            },
             election_tick: 10 * 3,
             heartbeat_tick: 3,
             // TODO: need to restore this from storage
-            applied: 0,
-            max_size_per_msg: 1024 * 1024 * 1024,
+            applied,
+            // Leave room for the rest of the RaftRequest, so that every message fits in a frame
+            max_size_per_msg: (context.max_frame_length / 2) as u64,
             max_inflight_msgs: 256,
             tag: format!("peer_{}", node_id).to_string(),
             ..Default::default()
         };
-        let raft_storage = MemStorage::new();
         let raft_node = RawNode::new(&raft_config, raft_storage, vec![]).unwrap();
 
         RaftManager {
             raft_node: Mutex::new(raft_node),
             pending_responses: Mutex::new(HashMap::new()),
+            coalescing_writes: Mutex::new(HashMap::new()),
+            coalesced_responses: Mutex::new(HashMap::new()),
             leader_requests: Mutex::new(vec![]),
             sync_requests: Mutex::new(vec![]),
-            applied_index: AtomicU64::new(0),
+            applied_index: AtomicU64::new(applied),
+            unapplied_entries: Mutex::new(VecDeque::new()),
+            staged_fetches: Mutex::new(HashMap::new()),
+            applied_sessions: Mutex::new(applied_sessions),
+            pending_atime_updates: Mutex::new(HashSet::new()),
+            latest_snapshot: Mutex::new(None),
             peers: context
                 .peers
                 .iter()
+                .chain(context.observers.iter())
                 .map(|peer| (node_id_from_address(peer), PeerClient::new(*peer)))
                 .collect(),
             node_id,
             context: context.clone(),
-            file_storage: FileStorage::new(node_id, &peer_ids, &context),
+            file_storage: FileStorage::new(
+                node_id,
+                &peer_ids,
+                &context,
+                snapshot.as_ref().map(|x| x.metadata.as_slice()),
+            ),
+            clock: HybridClock::new(),
+            locks: LockManager::new(),
+            recovering: AtomicBool::new(snapshot.is_some()),
+            recovery_target: AtomicU64::new(0),
+            pending_removals: Mutex::new(vec![]),
         }
     }
 
@@ -93,9 +251,17 @@ impl RaftManager {
     pub fn apply_messages(&self, messages: &[Message]) -> raft::Result<()> {
         {
             let mut raft_node = self.raft_node.lock().unwrap();
+            let recovering = self.is_recovering();
 
             for message in messages {
-                assert_eq!(message.to, self.node_id);
+                // Messages come from the network, so one for another node is an error rather than a bug
+                if message.to != self.node_id {
+                    return Err(raft::Error::StepPeerNotFound);
+                }
+                // Candidates treat a recovering node like an unreachable one
+                if recovering && is_election_message(message) {
+                    continue;
+                }
                 raft_node.step(message.clone())?;
             }
         }
@@ -142,6 +308,239 @@ impl RaftManager {
         commit
     }
 
+    pub fn is_leader(&self) -> bool {
+        self.raft_node.lock().unwrap().raft.leader_id == self.node_id
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        self.recovering.load(Ordering::SeqCst)
+    }
+
+    // Called periodically while recovering, with the applied index of the leader, or 0 if there's no leader.
+    // Recovery ends once this node applied as much of the log as the leader had the first time it was asked.
+    // Returns whether it has ended
+    pub fn finish_recovery(&self, leader_applied: u64) -> bool {
+        if !self.is_recovering() {
+            return true;
+        }
+        if leader_applied == 0 {
+            return false;
+        }
+        let target = match self.recovery_target.compare_exchange(
+            0,
+            leader_applied,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => leader_applied,
+            Err(target) => target,
+        };
+        let applied = self.applied_index.load(Ordering::SeqCst);
+        if applied < target {
+            info!("Recovering: applied index {} of {}", applied, target);
+            return false;
+        }
+        self.recovering.store(false, Ordering::SeqCst);
+        info!(
+            "Caught up with the cluster at index {}. Voting and serving clients",
+            applied
+        );
+
+        return true;
+    }
+
+    pub fn locks(&self) -> &LockManager {
+        &self.locks
+    }
+
+    // Sends a request, which only the leader serves, to the leader and returns its response
+    pub fn forward_to_leader(
+        &self,
+        request: &[u8],
+    ) -> impl Future<Item = LengthPrefixedVec, Error = ErrorCode> {
+        let leader_id = self.raft_node.lock().unwrap().raft.leader_id;
+        let leader = match self.peers.get(&leader_id) {
+            Some(leader) => leader,
+            // There's no leader, or it's this node
+            None => return Either::A(err(ErrorCode::RaftFailure)),
+        };
+
+        Either::B(send_to_peer(leader, request))
+    }
+
+    // Sends a request to each of the voting peers, and returns their responses
+    pub fn send_to_voters(
+        &self,
+        request: &[u8],
+    ) -> Vec<impl Future<Item = LengthPrefixedVec, Error = ErrorCode>> {
+        self.context
+            .peers
+            .iter()
+            .map(|peer| send_to_peer(&self.peers[&node_id_from_address(peer)], request))
+            .collect()
+    }
+
+    // Summarizes the Raft state of this node, including the last log_tail entries of its log
+    pub fn debug_info<'a>(
+        &self,
+        log_tail: u32,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> FlatBufferResponse<'a> {
+        let raft_node = self.raft_node.lock().unwrap();
+        let raft = &raft_node.raft;
+        let first_index = raft.raft_log.first_index();
+        let last_index = raft.raft_log.last_index();
+
+        let tail_start = max(
+            first_index,
+            (last_index + 1).saturating_sub(u64::from(log_tail)),
+        );
+        // Entries which haven't been persisted yet aren't in the store
+        let stored_last_index = min(last_index, raft_node.get_store().last_index().unwrap_or(0));
+        let entries = if tail_start <= stored_last_index {
+            raft_node
+                .get_store()
+                .entries(tail_start, stored_last_index + 1, u64::max_value())
+                .unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let mut log_entries = vec![];
+        for entry in entries.iter() {
+            let description = if entry.data.is_empty() {
+                "Empty".to_string()
+            } else if entry.entry_type == EntryType::EntryNormal {
+                format!(
+                    "{:?}",
+                    get_root_as_generic_request(&entry.data).request_type()
+                )
+            } else {
+                format!("{:?}", entry.entry_type)
+            };
+            let description = builder.create_string(&description);
+            log_entries.push(RaftLogEntry::create(
+                &mut builder,
+                &RaftLogEntryArgs {
+                    index: entry.index,
+                    term: entry.term,
+                    size: entry.data.len() as u32,
+                    description: Some(description),
+                },
+            ));
+        }
+        let log_entries = builder.create_vector(&log_entries);
+
+        let mut progress_entries = vec![];
+        if raft.leader_id == self.node_id {
+            let mut progress: Vec<_> = raft.prs().iter().collect();
+            progress.sort_by_key(|(node_id, _)| **node_id);
+            for (node_id, node_progress) in progress {
+                let state = builder.create_string(&format!("{:?}", node_progress.state));
+                progress_entries.push(RaftPeerProgress::create(
+                    &mut builder,
+                    &RaftPeerProgressArgs {
+                        node_id: *node_id,
+                        matched: node_progress.matched,
+                        next_index: node_progress.next_idx,
+                        state: Some(state),
+                        recent_active: node_progress.recent_active,
+                    },
+                ));
+            }
+        }
+        let progress_entries = builder.create_vector(&progress_entries);
+
+        let role = builder.create_string(&self.role(raft));
+        let mut response_builder = RaftDebugResponseBuilder::new(&mut builder);
+        response_builder.add_node_id(self.node_id);
+        response_builder.add_term(raft.term);
+        response_builder.add_role(role);
+        response_builder.add_leader_id(raft.leader_id);
+        response_builder.add_commit_index(raft.raft_log.committed);
+        response_builder.add_applied_index(self.applied_index.load(Ordering::SeqCst));
+        response_builder.add_first_index(first_index);
+        response_builder.add_last_index(last_index);
+        response_builder.add_log_tail(log_entries);
+        response_builder.add_progress(progress_entries);
+        let response_offset = response_builder.finish().as_union_value();
+
+        (builder, ResponseType::RaftDebugResponse, response_offset)
+    }
+
+    fn role(&self, raft: &raft::Raft<MemStorage>) -> String {
+        if self.is_recovering() {
+            "Recovering".to_string()
+        } else {
+            format!("{:?}", raft.state)
+        }
+    }
+
+    // Returns the role, leader id, commit index, and applied index of this node
+    pub fn raft_status(&self) -> (String, u64, u64, u64) {
+        let raft_node = self.raft_node.lock().unwrap();
+        let raft = &raft_node.raft;
+        (
+            self.role(raft),
+            raft.leader_id,
+            raft.raft_log.committed,
+            self.applied_index.load(Ordering::SeqCst),
+        )
+    }
+
+    // Removes every other node from the Raft configuration, and makes this node the leader of a cluster of one.
+    // Only for when quorum is permanently lost
+    pub fn force_new_cluster<'a>(
+        &self,
+        dry_run: bool,
+        mut builder: FlatBufferBuilder<'a>,
+    ) -> ResultResponse<'a> {
+        if self.context.observer {
+            return Err(ErrorCode::NotSupported);
+        }
+        let striped_files = self.file_storage.striped_file_count()?;
+        let mut raft_node = self.raft_node.lock().unwrap();
+        let mut removed_nodes: Vec<u64> = raft_node
+            .raft
+            .prs()
+            .iter()
+            .map(|(node_id, _)| *node_id)
+            .filter(|node_id| *node_id != self.node_id)
+            .collect();
+        removed_nodes.sort();
+        if !dry_run {
+            warn!(
+                "Forcing a new cluster. Removing nodes {:?}. Blocks of {} striped files stored on them are lost",
+                removed_nodes, striped_files
+            );
+            // The other nodes can't vote, so they're removed from the local configuration first. Their removals are
+            // then proposed, once this node is the leader, so that they're in the log and applied like any other
+            for node_id in removed_nodes.iter() {
+                let conf_state = raft_node
+                    .apply_conf_change(&remove_node_change(*node_id))
+                    .map_err(|_| ErrorCode::RaftFailure)?;
+                raft_node.mut_store().wl().set_conf_state(conf_state, None);
+            }
+            *self.pending_removals.lock().unwrap() = removed_nodes.clone();
+            raft_node.campaign().map_err(|_| ErrorCode::RaftFailure)?;
+        }
+
+        let removed = builder.create_vector(&removed_nodes);
+        let mut response_builder = ForceNewClusterResponseBuilder::new(&mut builder);
+        response_builder.add_removed_nodes(removed);
+        response_builder.add_striped_files(striped_files);
+        let response_offset = response_builder.finish().as_union_value();
+
+        Ok((
+            builder,
+            ResponseType::ForceNewClusterResponse,
+            response_offset,
+        ))
+    }
+
+    pub fn current_term(&self) -> u64 {
+        self.raft_node.lock().unwrap().raft.term
+    }
+
     pub fn get_leader(&self) -> impl Future<Item = u64, Error = ()> {
         let raft_node = self.raft_node.lock().unwrap();
 
@@ -175,7 +574,10 @@ impl RaftManager {
     pub fn background_tick(&self) {
         {
             let mut raft_node = self.raft_node.lock().unwrap();
-            raft_node.tick();
+            // Only elections are driven by ticks on a follower, and a recovering node mustn't start one
+            if !self.is_recovering() {
+                raft_node.tick();
+            }
 
             let leader_id = raft_node.raft.leader_id;
             if leader_id > 0 {
@@ -221,63 +623,26 @@ impl RaftManager {
         }
 
         let mut applied_index = self.applied_index.load(Ordering::SeqCst);
+        let leader = raft_node.raft.leader_id == self.node_id;
+        // Set once the entries of the previous term, or the previous configuration change, were applied
+        let mut propose_removal = false;
+        let mut unapplied_entries = self.unapplied_entries.lock().unwrap();
         if let Some(committed_entries) = ready.committed_entries.take() {
-            for entry in committed_entries {
-                // TODO: probably need to save the term too
-                applied_index = max(applied_index, entry.index);
-
-                if entry.data.is_empty() {
-                    // New leaders send empty entries
-                    continue;
-                }
-
-                assert_eq!(entry.entry_type, EntryType::EntryNormal);
-
-                let mut pending_responses = self.pending_responses.lock().unwrap();
-
-                let request = get_root_as_generic_request(&entry.data);
-                let mut uuid = [0; 16];
-                uuid.copy_from_slice(&entry.context[0..16]);
-                if let Some((builder, sender)) =
-                    pending_responses.remove(&u128::from_le_bytes(uuid))
-                {
-                    match commit_write(request, &self.file_storage, builder) {
-                        Ok(response) => sender.send(Ok(response)).ok().unwrap(),
-                        // TODO: handle this somehow. If not all nodes failed, then the filesystem
-                        // is probably corrupted, since some will have applied the write, but not all
-                        // There should only be a few types of messages that can fail here. truncate is one,
-                        // since you can call it with LONG_MAX or some other value that balloons
-                        // the message into a huge write. Probably most other messages can't fail
-                        Err(error_code) => {
-                            error!(
-                                "Commit failed {:?} {:?}",
-                                error_code,
-                                request.request_type()
-                            );
-                            sender.send(Err(error_code)).ok().unwrap()
-                        }
-                    }
-                } else {
-                    let builder = FlatBufferBuilder::new();
-                    // TODO: pass None for builder to avoid this useless allocation
-                    if let Err(err) = commit_write(request, &self.file_storage, builder) {
-                        // TODO: handle this somehow. If not all nodes failed, then the filesystem
-                        // is probably corrupted, since some will have applied the write, but not all.
-                        // There should only be a few types of messages that can fail here. truncate is one,
-                        // since you can call it with LONG_MAX or some other value that balloons
-                        // the message into a huge write. Probably most other messages can't fail
-                        error!("Commit failed {:?} {:?}", err, request.request_type());
-                    }
-                }
-
-                info!(
-                    "Committed write index {} (leader={}): {:?}",
-                    entry.index,
-                    raft_node.raft.leader_id,
-                    request.request_type()
-                );
+            unapplied_entries.extend(committed_entries);
+        }
+        while let Some(entry) = unapplied_entries.pop_front() {
+            if !self.staged_data_ready(&entry) {
+                // Entries are applied in order, so the later ones wait too
+                unapplied_entries.push_front(entry);
+                break;
+            }
+            // TODO: probably need to save the term too
+            applied_index = max(applied_index, entry.index);
+            if self.apply_entry(&mut raft_node, entry, leader)? {
+                propose_removal = leader;
             }
         }
+        drop(unapplied_entries);
 
         self.applied_index.store(applied_index, Ordering::SeqCst);
 
@@ -295,219 +660,837 @@ impl RaftManager {
         let messages = ready.messages.drain(..).collect();
         raft_node.advance(ready);
 
+        // Raft only accepts one configuration change at a time, so each is proposed once the previous one was applied
+        if propose_removal {
+            if let Some(node_id) = self.pending_removals.lock().unwrap().pop() {
+                raft_node.propose_conf_change(vec![], remove_node_change(node_id))?;
+            }
+        }
+
         Ok(messages)
     }
 
-    fn _propose(&self, uuid: u128, data: Vec<u8>) {
-        let mut raft_node = self.raft_node.lock().unwrap();
-        raft_node
-            .propose(uuid.to_le_bytes().to_vec(), data)
-            .unwrap();
+    // Applies a committed entry. Returns whether it was an empty entry or a configuration change, after which the next
+    // pending removal may be proposed
+    fn apply_entry(
+        &self,
+        raft_node: &mut RawNode<MemStorage>,
+        entry: Entry,
+        leader: bool,
+    ) -> raft::Result<bool> {
+        if entry.data.is_empty() {
+            // New leaders send empty entries
+            return Ok(true);
+        }
+
+        if entry.entry_type == EntryType::EntryConfChange {
+            let change: ConfChange =
+                protobuf::parse_from_bytes(&entry.data).expect("Invalid configuration change");
+            let conf_state = raft_node.apply_conf_change(&change)?;
+            let standalone = *conf_state.get_nodes() == [self.node_id];
+            raft_node.mut_store().wl().set_conf_state(conf_state, None);
+            // Only force_new_cluster removes nodes, and it removes every other one
+            if standalone {
+                self.file_storage
+                    .make_standalone()
+                    .expect("Failed to store every file on this node");
+            }
+            info!(
+                "Applied configuration change {:?} of node {}",
+                change.get_change_type(),
+                change.get_node_id()
+            );
+            return Ok(true);
+        }
+        assert_eq!(entry.entry_type, EntryType::EntryNormal);
+
+        let mut pending_responses = self.pending_responses.lock().unwrap();
+
+        let request = get_root_as_generic_request(&entry.data);
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&entry.context[0..16]);
+        // Entries without a time are applied at the time of the previous one, on every node
+        let time = decode_timestamp(&entry.context[16..]).unwrap_or_else(|| Timestamp::new(0, 0));
+        self.clock.observe(time);
+        // Sessions which change the filesystem hold a lease, so that they're ended once they stop
+        if leader && request.session_id() != 0 {
+            self.locks.renew(raft_node.raft.term, request.session_id());
+        }
+        if let Some((builder, sender)) = pending_responses.remove(&u128::from_le_bytes(uuid)) {
+            let response = self.apply_request(request, entry.term, time, leader, builder);
+            sender.send(Ok(response)).ok().unwrap();
+        } else if let Some(waiters) = self
+            .coalesced_responses
+            .lock()
+            .unwrap()
+            .remove(&u128::from_le_bytes(uuid))
+        {
+            self.apply_coalesced_write(request, entry.term, time, waiters);
+        } else if is_coalesced(&request) {
+            // Proposed by a previous leader, whose waiters are gone
+            self.apply_coalesced_write(request, entry.term, time, vec![]);
+        } else {
+            // TODO: pass None for builder to avoid this useless allocation
+            self.apply_request(request, entry.term, time, leader, FlatBufferBuilder::new());
+        }
+
+        info!(
+            "Committed write index {} (leader={}): {:?}",
+            entry.index,
+            raft_node.raft.leader_id,
+            request.request_type()
+        );
+
+        Ok(false)
     }
 
-    pub fn propose(
+    // Whether entry can be applied. The data of a staged write which wasn't pushed to this node is fetched in the
+    // background, without holding up Raft, and the entry waits until it arrives. If no node has it any more, applying
+    // the write without it would make this node diverge from the others, so it panics instead
+    fn staged_data_ready(&self, entry: &Entry) -> bool {
+        if entry.entry_type != EntryType::EntryNormal || entry.data.is_empty() {
+            return true;
+        }
+        let request = get_root_as_generic_request(&entry.data);
+        let staged_write_request = match request.request_as_staged_write_request() {
+            Some(staged_write_request) => staged_write_request,
+            None => return true,
+        };
+        let staged_id = staged_write_request.staged_id();
+        let staged_data = self.file_storage.staged_data();
+        if staged_data.contains(staged_id) {
+            return true;
+        }
+
+        let mut staged_fetches = self.staged_fetches.lock().unwrap();
+        if let Some(fetch) = staged_fetches.get(&staged_id) {
+            return match fetch.try_recv() {
+                Ok(Some(data)) => {
+                    staged_fetches.remove(&staged_id);
+                    staged_data.restore(staged_id, data);
+                    true
+                }
+                Ok(None) | Err(TryRecvError::Disconnected) => panic!(
+                    "Data of staged write {} at index {} is lost. Replace this node's data with --join",
+                    staged_id, entry.index
+                ),
+                Err(TryRecvError::Empty) => false,
+            };
+        }
+
+        let holders: Vec<u64> = staged_write_request
+            .holders()
+            .map(|holders| (0..holders.len()).map(|i| holders.get(i)).collect())
+            .unwrap_or_default();
+        let addresses: HashMap<u64, SocketAddr> = self
+            .context
+            .peers
+            .iter()
+            .chain(self.context.observers.iter())
+            .map(|peer| (node_id_from_address(peer), *peer))
+            .collect();
+        let mut others: Vec<u64> = addresses
+            .keys()
+            .filter(|x| !holders.contains(x))
+            .cloned()
+            .collect();
+        others.sort();
+        let sources: Vec<SocketAddr> = holders
+            .iter()
+            .chain(others.iter())
+            .filter_map(|node_id| addresses.get(node_id).cloned())
+            .collect();
+        let (sender, receiver) = std_mpsc::channel();
+        thread::spawn(move || {
+            sender.send(fetch_staged_data(staged_id, &sources)).ok();
+        });
+        staged_fetches.insert(staged_id, receiver);
+
+        false
+    }
+
+    // Applies a committed request and returns the finalized response. If the request is a retry of one already
+    // applied from its session, it is not applied again, and the original response is returned. The leader also
+    // delivers the events of the change to the hooks
+    fn apply_request(
         &self,
         request: GenericRequest,
+        term: u64,
+        time: Timestamp,
+        leader: bool,
         builder: FlatBufferBuilder<'static>,
-    ) -> impl Future<Item = FlatBufferResponse<'static>, Error = ErrorCode> {
-        let uuid: u128 = rand::thread_rng().gen();
+    ) -> FlatBufferWithResponse<'static> {
+        let session_id = request.session_id();
+        let sequence_number = request.sequence_number();
+        let mut applied_sessions = self.applied_sessions.lock().unwrap();
+        if session_id != 0 {
+            let session = applied_sessions.entry(session_id).or_default();
+            session.acknowledge(request.acknowledged());
+            if session.is_applied(sequence_number) {
+                info!(
+                    "Skipping duplicate request {} from session {}: {:?}",
+                    sequence_number,
+                    session_id,
+                    request.request_type()
+                );
+                return match session.responses.get(&sequence_number) {
+                    Some(response) => {
+                        let response = LengthPrefixedVec::from_length_prefixed(response.clone());
+                        FlatBufferWithResponse::with_separate_response(builder, response)
+                    }
+                    // Nobody is waiting for the response any more
+                    None => {
+                        FlatBufferWithResponse::new(to_error_response(ErrorCode::BadRequest, term))
+                    }
+                };
+            }
+        }
 
-        let (sender, receiver) = oneshot::channel();
-        {
-            let mut pending_responses = self.pending_responses.lock().unwrap();
-            pending_responses.insert(uuid, (builder, sender));
+        let applied = Operation::from_request(&request).and_then(|operation| {
+            let applied = self.file_storage.apply(&operation, time, builder);
+            if leader && self.context.hooks.enabled() {
+                self.notify_hooks(&operation, applied.as_ref().err().cloned());
+            }
+            applied
+        });
+        let response = match applied {
+            Ok((mut builder, response_type, response_offset)) => {
+                // Include the term it was committed in, so that clients learn of new terms, and fence their
+                // later requests with it
+                finalize_response(&mut builder, response_type, response_offset, term);
+                builder
+            }
+            // TODO: handle this somehow. If not all nodes failed, then the filesystem
+            // is probably corrupted, since some will have applied the write, but not all
+            // There should only be a few types of messages that can fail here. truncate is one,
+            // since you can call it with LONG_MAX or some other value that balloons
+            // the message into a huge write. Probably most other messages can't fail
+            Err(error_code) => {
+                error!(
+                    "Commit failed {:?} {:?}",
+                    error_code,
+                    request.request_type()
+                );
+                to_error_response(error_code, term)
+            }
+        };
+
+        if session_id != 0 {
+            applied_sessions
+                .entry(session_id)
+                .or_default()
+                .record(sequence_number, response.finished_data().to_vec());
+        }
+        // The session's lease expired or its client was evicted, so it won't retry any more requests. Removed when
+        // the entry is applied, so that every node forgets it at the same point in the log
+        if let Some(end_session_request) = request.request_as_end_session_request() {
+            applied_sessions.remove(&end_session_request.session_id());
         }
-        self._propose(uuid, request._tab.buf.to_vec());
 
-        self.process_raft_queue();
+        return FlatBufferWithResponse::new(response);
+    }
+
+    fn notify_hooks(&self, operation: &Operation, error: Option<ErrorCode>) {
+        let hooks = &self.context.hooks;
+        match (operation, error) {
+            (Operation::Release { inode, .. }, None) => {
+                // Temporary files have no path, and aren't reported
+                if let Ok(path) = self.file_storage.path_of(*inode) {
+                    hooks.notify(EventKind::FileWritten, *inode, path);
+                }
+            }
+            (Operation::Mkdir { parent, name, .. }, None) => {
+                let created = self
+                    .file_storage
+                    .resolve(*parent, name, UserContext::new(0, 0));
+                if let Ok((inode, _)) = created {
+                    if let Ok(path) = self.file_storage.path_of(inode) {
+                        hooks.notify(EventKind::DirectoryCreated, inode, path);
+                    }
+                }
+            }
+            (_, Some(ErrorCode::NoSpace)) => {
+                if let Some(inode) = operation.changed_inodes().first() {
+                    let path = self.file_storage.path_of(*inode).unwrap_or_default();
+                    hooks.notify(EventKind::NoSpace, *inode, path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Returns the latest snapshot, for a new node to fetch, creating one if needed
+    // A new snapshot is only created if the latest one is too old, and isn't resume_index
+    pub fn latest_snapshot(&self, resume_index: u64) -> Result<SnapshotInfo, ErrorCode> {
+        let mut latest = self.latest_snapshot.lock().unwrap();
+        if let Some((created, ref info)) = *latest {
+            if created.elapsed() < SNAPSHOT_MAX_AGE || info.index == resume_index {
+                return Ok(info.clone());
+            }
+        }
+
+        // Hold the Raft lock, so that no entries are applied while the snapshot is written
+        let raft_node = self.raft_node.lock().unwrap();
+        let index = self.applied_index.load(Ordering::SeqCst);
+        let term = raft_node.raft.raft_log.term(index).unwrap_or(0);
+        let sessions: Vec<(u64, AppliedSession)> = self
+            .applied_sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(session_id, session)| (*session_id, session.clone()))
+            .collect();
+        let info = self.file_storage.write_snapshot(
+            &self.context.data_dir,
+            index,
+            term,
+            &sessions,
+            self.context.checksums.wire,
+        )?;
+        info!("Created snapshot at index {}", index);
+        *latest = Some((Instant::now(), info.clone()));
+
+        Ok(info)
+    }
+
+    // Pushes the data of a staged write, which this node already holds, to every peer. Resolves with the nodes which
+    // hold it once they include a quorum of the voters, and fails if too many of the voters couldn't store it
+    pub fn stage_on_peers(
+        &self,
+        staged_id: u64,
+        data: &[u8],
+    ) -> impl Future<Item = Vec<u64>, Error = ErrorCode> {
+        let voters: HashSet<u64> = self
+            .context
+            .peers
+            .iter()
+            .map(|peer| node_id_from_address(peer))
+            .collect();
+        let local = if self.context.observer { 0 } else { 1 };
+        let quorum = (voters.len() + local) / 2 + 1;
+        let needed = quorum - local;
+
+        // The pushes continue after the quorum is reached, so that the other nodes don't have to fetch the data
+        let (sender, receiver) = mpsc::unbounded();
+        for (peer_id, peer) in self.peers.iter() {
+            let peer_id = *peer_id;
+            let sender = sender.clone();
+            tokio::spawn(peer.stage_data(staged_id, data).then(move |pushed| {
+                match pushed {
+                    Ok(_) => {
+                        sender.unbounded_send(peer_id).ok();
+                    }
+                    Err(error) => warn!("Failed to push staged data to {}: {:?}", peer_id, error),
+                }
+                Ok(())
+            }));
+        }
 
-        return receiver
+        let node_id = self.node_id;
+        receiver
+            .filter(move |peer_id| voters.contains(peer_id))
+            .take(needed as u64)
+            .collect()
             .map_err(|_| ErrorCode::Uncategorized)
-            .and_then(|x| x);
+            .and_then(move |acknowledged| {
+                if acknowledged.len() < needed {
+                    error!(
+                        "Staged data was only acknowledged by {} of the {} voters needed",
+                        acknowledged.len(),
+                        needed
+                    );
+                    return Err(ErrorCode::Uncategorized);
+                }
+                let mut holders = vec![node_id];
+                holders.extend(acknowledged);
+                Ok(holders)
+            })
     }
-}
 
-pub fn commit_write<'a, 'b>(
-    request: GenericRequest<'a>,
-    file_storage: &FileStorage,
-    builder: FlatBufferBuilder<'b>,
-) -> Result<FlatBufferResponse<'b>, ErrorCode> {
-    let response;
-
-    match request.request_type() {
-        RequestType::HardlinkRequest => {
-            let hardlink_request = request
-                .request_as_hardlink_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.hardlink(
-                hardlink_request.inode(),
-                hardlink_request.new_parent(),
-                hardlink_request.new_name(),
-                *hardlink_request.context(),
-                builder,
-            );
+    // Should be called after a successful read of inode
+    pub fn record_access(&self, inode: u64, mode: AtimeMode) {
+        let mode = if mode == AtimeMode::VolumeDefault {
+            self.context.atime_mode
+        } else {
+            mode
+        };
+        match mode {
+            AtimeMode::NoAtime => {}
+            AtimeMode::StrictAtime => self.propose_atime_update(&[inode]),
+            AtimeMode::RelAtime => {
+                let mut pending = self.pending_atime_updates.lock().unwrap();
+                if !pending.contains(&inode) && self.file_storage.needs_atime_update(inode) {
+                    pending.insert(inode);
+                }
+            }
+            AtimeMode::VolumeDefault => unreachable!(),
         }
-        RequestType::RenameRequest => {
-            let rename_request = request
-                .request_as_rename_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.rename(
-                rename_request.parent(),
-                rename_request.name(),
-                rename_request.new_parent(),
-                rename_request.new_name(),
-                *rename_request.context(),
-                builder,
-            );
+    }
+
+    // Proposes a single update for all the inodes read in relatime mode since the last flush
+    pub fn flush_atime_updates(&self) {
+        let inodes: Vec<u64> = self.pending_atime_updates.lock().unwrap().drain().collect();
+        if !inodes.is_empty() {
+            self.propose_atime_update(&inodes);
         }
-        RequestType::ChmodRequest => {
-            let chmod_request = request
-                .request_as_chmod_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.chmod(
-                chmod_request.inode(),
-                chmod_request.mode(),
-                *chmod_request.context(),
-                builder,
-            );
+    }
+
+    // Ends the sessions whose lease expired. Must be called periodically on the leader
+    pub fn end_expired_sessions(&self) {
+        if !self.is_leader() {
+            return;
         }
-        RequestType::ChownRequest => {
-            let chown_request = request
-                .request_as_chown_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.chown(
-                chown_request.inode(),
-                chown_request.uid().map(OptionalUInt::value),
-                chown_request.gid().map(OptionalUInt::value),
-                *chown_request.context(),
-                builder,
-            );
+        // Sessions which applied requests are held too, so that they're forgotten once they stop sending them
+        let mut held = self.file_storage.temporary_sessions();
+        held.extend(self.applied_sessions.lock().unwrap().keys());
+        held.sort();
+        held.dedup();
+        for session_id in self.locks.expire_sessions(self.current_term(), &held) {
+            info!("Lease of session {} expired. Ending it", session_id);
+            self.end_session(session_id);
         }
-        RequestType::TruncateRequest => {
-            let truncate_request = request
-                .request_as_truncate_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.truncate(
-                truncate_request.inode(),
-                truncate_request.new_length(),
-                *truncate_request.context(),
-                builder,
-            );
+    }
+
+    // Releases the session's handles of temporary files, on every node
+    pub fn end_session(&self, session_id: u64) {
+        let mut builder = FlatBufferBuilder::new();
+        let mut request_builder = EndSessionRequestBuilder::new(&mut builder);
+        request_builder.add_session_id(session_id);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::EndSessionRequest, finish_offset);
+
+        // Skip the size prefix, like the frames received from clients
+        let request = get_root_as_generic_request(&builder.finished_data()[4..]);
+        let committed = self
+            .propose(request, FlatBufferBuilder::new())
+            .map(|_| ())
+            .map_err(move |error_code| {
+                error!("Failed to end session {}: {:?}", session_id, error_code)
+            });
+        tokio::spawn(committed);
+    }
+
+    fn propose_atime_update(&self, inodes: &[u64]) {
+        let mut builder = FlatBufferBuilder::new();
+        let inodes_offset = builder.create_vector(inodes);
+        let mut request_builder = UpdateAtimeRequestBuilder::new(&mut builder);
+        request_builder.add_inodes(inodes_offset);
+        request_builder.add_atime(&self.clock.now());
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::UpdateAtimeRequest, finish_offset);
+
+        // Skip the size prefix, like the frames received from clients
+        let request = get_root_as_generic_request(&builder.finished_data()[4..]);
+        let committed = self
+            .propose(request, FlatBufferBuilder::new())
+            .map(|_| ())
+            .map_err(|error_code| error!("Failed to update access times: {:?}", error_code));
+        tokio::spawn(committed);
+    }
+
+    // Applies a write that was coalesced from several writes, and responds to each of them. Each of the original
+    // writes is deduplicated against its session, like apply_request() does, since it may have been retried while it
+    // was waiting to be coalesced or committed. Those already applied are skipped, and get their original response,
+    // so that they can't overwrite later writes to the same range. The rest are applied in runs of consecutive writes
+    fn apply_coalesced_write(
+        &self,
+        request: GenericRequest,
+        term: u64,
+        time: Timestamp,
+        waiters: Vec<(u32, PendingResponse)>,
+    ) {
+        let write_request = request.request_as_write_request().unwrap();
+        let sources = write_request.coalesced().unwrap_or_default();
+        let mut applied_sessions = self.applied_sessions.lock().unwrap();
+        let (runs, source_runs) = plan_coalesced_write(sources, &applied_sessions);
+
+        let data = write_request.data();
+        let mut results = vec![];
+        for run in runs {
+            let operation = Operation::Write {
+                inode: write_request.inode(),
+                offset: write_request.offset() + run.start as u64,
+                data: &data[run.clone()],
+                context: *write_request.context(),
+                fencing_token: 0,
+            };
+            let result = self
+                .file_storage
+                .apply(&operation, time, FlatBufferBuilder::new())
+                .map(|_| ());
+            if let Err(error_code) = result {
+                error!("Commit failed {:?} for coalesced write", error_code);
+            }
+            results.push(result);
         }
-        RequestType::FsyncRequest => {
-            let fsync_request = request
-                .request_as_fsync_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.fsync(fsync_request.inode(), builder);
-        }
-        RequestType::CreateRequest => {
-            let create_request = request
-                .request_as_create_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.create(
-                create_request.parent(),
-                create_request.name(),
-                create_request.uid(),
-                create_request.gid(),
-                create_request.mode(),
-                create_request.kind(),
-                builder,
-            );
+
+        let mut waiters = waiters.into_iter();
+        for (source, run) in sources.iter().zip(source_runs) {
+            let session_id = source.session_id();
+            let sequence_number = source.sequence_number();
+            let response = if let Some(run) = run {
+                let response = match results[run]
+                    .and_then(|_| to_write_response(FlatBufferBuilder::new(), source.length()))
+                {
+                    Ok((mut builder, response_type, response_offset)) => {
+                        finalize_response(&mut builder, response_type, response_offset, term);
+                        builder
+                    }
+                    Err(error_code) => to_error_response(error_code, term),
+                };
+                let response = response.finished_data().to_vec();
+                if session_id != 0 {
+                    applied_sessions
+                        .entry(session_id)
+                        .or_default()
+                        .record(sequence_number, response.clone());
+                }
+                response
+            } else {
+                info!(
+                    "Skipping duplicate write {} from session {}",
+                    sequence_number, session_id
+                );
+                match applied_sessions
+                    .get(&session_id)
+                    .and_then(|session| session.responses.get(&sequence_number))
+                {
+                    Some(response) => response.clone(),
+                    // Nobody is waiting for the response any more
+                    None => to_error_response(ErrorCode::BadRequest, term)
+                        .finished_data()
+                        .to_vec(),
+                }
+            };
+
+            // This node proposed the write, and is waiting to respond to each of the original writes, in order
+            if let Some((_, (builder, sender))) = waiters.next() {
+                let response = LengthPrefixedVec::from_length_prefixed(response);
+                sender
+                    .send(Ok(FlatBufferWithResponse::with_separate_response(
+                        builder, response,
+                    )))
+                    .ok()
+                    .unwrap();
+            }
         }
-        RequestType::SetXattrRequest => {
-            let set_xattr_request = request
-                .request_as_set_xattr_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.set_xattr(
-                set_xattr_request.inode(),
-                set_xattr_request.key(),
-                set_xattr_request.value(),
-                builder,
-            );
+    }
+
+    fn coalesce_write(
+        &self,
+        write_request: WriteRequest,
+        source: CoalescedSource,
+        waiter: PendingResponse,
+    ) {
+        let inode = write_request.inode();
+        let offset = write_request.offset();
+        let data = write_request.data();
+        let context = *write_request.context();
+        let max_batch_bytes = self.context.write_coalescing.max_batch_bytes;
+
+        let mut coalescing = self.coalescing_writes.lock().unwrap();
+        if let Some(batch) = coalescing.get_mut(&inode) {
+            if batch.context.uid() == context.uid()
+                && batch.context.gid() == context.gid()
+                && batch.offset + batch.data.len() as u64 == offset
+                && batch.data.len() + data.len() <= max_batch_bytes
+            {
+                batch.data.extend_from_slice(data);
+                batch.waiters.push((data.len() as u32, waiter));
+                batch.sources.push(source);
+                return;
+            }
         }
-        RequestType::RemoveXattrRequest => {
-            let remove_xattr_request = request
-                .request_as_remove_xattr_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.remove_xattr(
-                remove_xattr_request.inode(),
-                remove_xattr_request.key(),
-                builder,
-            );
+
+        // Can't be coalesced with the pending writes, so send them first
+        if let Some(batch) = coalescing.remove(&inode) {
+            self.propose_coalesced_write(inode, batch);
         }
-        RequestType::UnlinkRequest => {
-            let unlink_request = request
-                .request_as_unlink_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.unlink(
-                unlink_request.parent(),
-                unlink_request.name(),
-                *unlink_request.context(),
-                builder,
-            );
+        coalescing.insert(
+            inode,
+            CoalescedWrite {
+                offset,
+                data: data.to_vec(),
+                context,
+                started: Instant::now(),
+                waiters: vec![(data.len() as u32, waiter)],
+                sources: vec![source],
+            },
+        );
+    }
+
+    fn propose_coalesced_write(&self, inode: u64, batch: CoalescedWrite) {
+        let mut builder = FlatBufferBuilder::new();
+        let data_offset = builder.create_vector_direct(&batch.data);
+        let sources_offset = builder.create_vector(&batch.sources);
+        let mut request_builder = WriteRequestBuilder::new(&mut builder);
+        request_builder.add_inode(inode);
+        request_builder.add_offset(batch.offset);
+        request_builder.add_data(data_offset);
+        request_builder.add_context(&batch.context);
+        request_builder.add_coalesced(sources_offset);
+        let finish_offset = request_builder.finish().as_union_value();
+        finalize_request(&mut builder, RequestType::WriteRequest, finish_offset);
+
+        let uuid: u128 = rand::thread_rng().gen();
+        self.coalesced_responses
+            .lock()
+            .unwrap()
+            .insert(uuid, batch.waiters);
+        // Skip the size prefix, like the frames received from clients
+        self._propose(uuid, builder.finished_data()[4..].to_vec());
+    }
+
+    // Proposes the coalesced writes which have waited for the coalescing window, or all of them if force is true
+    pub fn flush_coalesced_writes(&self, force: bool) {
+        let window = self.context.write_coalescing.window;
+        let mut coalescing = self.coalescing_writes.lock().unwrap();
+        let ready: Vec<u64> = coalescing
+            .iter()
+            .filter(|(_, batch)| force || batch.started.elapsed() >= window)
+            .map(|(inode, _)| *inode)
+            .collect();
+        for inode in ready.iter() {
+            let batch = coalescing.remove(inode).unwrap();
+            self.propose_coalesced_write(*inode, batch);
         }
-        RequestType::RmdirRequest => {
-            let rmdir_request = request
-                .request_as_rmdir_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.rmdir(
-                rmdir_request.parent(),
-                rmdir_request.name(),
-                *rmdir_request.context(),
-                builder,
-            );
+
+        if !ready.is_empty() {
+            self.process_raft_queue();
         }
-        RequestType::WriteRequest => {
-            let write_request = request
-                .request_as_write_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.write(
-                write_request.inode(),
-                write_request.offset(),
-                write_request.data(),
-                *write_request.context(),
-                builder,
-            );
+    }
+
+    // The operation's time is assigned here, and stored in the entry with its uuid, so that every node applies it
+    // with the same time
+    fn _propose(&self, uuid: u128, data: Vec<u8>) {
+        let mut context = uuid.to_le_bytes().to_vec();
+        encode_timestamp(self.clock.now(), &mut context);
+        let mut raft_node = self.raft_node.lock().unwrap();
+        raft_node.propose(context, data).unwrap();
+    }
+
+    // Whether the request was already applied from its session
+    fn is_applied(&self, session_id: u64, sequence_number: u64) -> bool {
+        session_id != 0
+            && self
+                .applied_sessions
+                .lock()
+                .unwrap()
+                .get(&session_id)
+                .map_or(false, |session| session.is_applied(sequence_number))
+    }
+
+    // Returns the finalized response, once the request has been committed
+    pub fn propose(
+        &self,
+        request: GenericRequest,
+        builder: FlatBufferBuilder<'static>,
+    ) -> impl Future<Item = FlatBufferWithResponse<'static>, Error = ErrorCode> {
+        // Rejected requests never enter the log, and every node would reject them the same way when applying them
+        if let Err(error_code) = Operation::from_request(&request).and_then(|x| x.validate()) {
+            return Either::A(err(error_code));
         }
-        RequestType::UtimensRequest => {
-            let utimens_request = request
-                .request_as_utimens_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.utimens(
-                utimens_request.inode(),
-                utimens_request.atime(),
-                utimens_request.mtime(),
-                *utimens_request.context(),
-                builder,
+
+        let (sender, receiver) = oneshot::channel();
+        let coalescing = self.context.write_coalescing.window > Duration::from_secs(0)
+            && self.raft_node.lock().unwrap().raft.leader_id == self.node_id;
+        // Fenced writes aren't coalesced, since each must be checked against its own token
+        // Nor are retries of writes which were already applied, so that they're deduplicated
+        let coalesced_write = request
+            .request_as_write_request()
+            .filter(|x| x.fencing_token() == 0)
+            .filter(|_| !self.is_applied(request.session_id(), request.sequence_number()));
+        if let (true, Some(write_request)) = (coalescing, coalesced_write) {
+            let source = CoalescedSource::new(
+                request.session_id(),
+                request.sequence_number(),
+                write_request.data().len() as u32,
             );
+            // Proposed in the background by flush_coalesced_writes()
+            self.coalesce_write(write_request, source, (builder, sender));
+        } else {
+            // Preserve the order of this request with writes that are waiting to be coalesced
+            self.flush_coalesced_writes(true);
+
+            let uuid: u128 = rand::thread_rng().gen();
+            {
+                let mut pending_responses = self.pending_responses.lock().unwrap();
+                pending_responses.insert(uuid, (builder, sender));
+            }
+            self._propose(uuid, request._tab.buf.to_vec());
+
+            self.process_raft_queue();
         }
-        RequestType::MkdirRequest => {
-            let mkdir_request = request
-                .request_as_mkdir_request()
-                .ok_or(ErrorCode::BadRequest)?;
-            response = file_storage.mkdir(
-                mkdir_request.parent(),
-                mkdir_request.name(),
-                mkdir_request.uid(),
-                mkdir_request.gid(),
-                mkdir_request.mode(),
-                builder,
-            );
+
+        return Either::B(
+            receiver
+                .map_err(|_| ErrorCode::Uncategorized)
+                .and_then(|x| x),
+        );
+    }
+}
+
+// Fetches the data of a staged write which wasn't pushed to this node, because it was down, was restarted or joined
+// since, from the nodes which acknowledged it, and then from the others. Retries until every node has dropped it
+fn fetch_staged_data(staged_id: u64, sources: &[SocketAddr]) -> Option<Vec<u8>> {
+    let start = Instant::now();
+    while start.elapsed() < STAGED_DATA_TIMEOUT {
+        for address in sources.iter() {
+            match NodeClient::new(*address).fetch_staged_data(staged_id) {
+                Ok(data) => {
+                    info!(
+                        "Fetched data of staged write {} from {}",
+                        staged_id, address
+                    );
+                    return Some(data);
+                }
+                Err(error_code) => warn!(
+                    "Failed to fetch data of staged write {} from {}: {:?}",
+                    staged_id, address, error_code
+                ),
+            }
+        }
+        thread::sleep(STAGED_FETCH_RETRY_INTERVAL);
+    }
+
+    None
+}
+
+// Splits a coalesced write into runs of consecutive original writes which haven't been applied yet. Returns the range
+// of the coalesced data of each run, and the run of each original write, or None if it's a duplicate. A write is a
+// duplicate if its session already applied it, or if it's a retry of an earlier write in the same batch
+fn plan_coalesced_write(
+    sources: &[CoalescedSource],
+    applied_sessions: &HashMap<u64, AppliedSession>,
+) -> (Vec<Range<usize>>, Vec<Option<usize>>) {
+    let mut runs: Vec<Range<usize>> = vec![];
+    let mut source_runs = vec![];
+    let mut seen = HashSet::new();
+    let mut position = 0;
+    for source in sources.iter() {
+        let session_id = source.session_id();
+        let sequence_number = source.sequence_number();
+        let duplicate = session_id != 0
+            && (applied_sessions
+                .get(&session_id)
+                .map_or(false, |session| session.is_applied(sequence_number))
+                || !seen.insert((session_id, sequence_number)));
+        let end = position + source.length() as usize;
+        if duplicate {
+            source_runs.push(None);
+        } else {
+            // Continues the previous run, unless a duplicate was skipped since
+            match (runs.last_mut(), source_runs.last()) {
+                (Some(run), Some(Some(_))) => run.end = end,
+                _ => runs.push(position..end),
+            }
+            source_runs.push(Some(runs.len() - 1));
         }
-        RequestType::FilesystemCheckRequest => unreachable!(),
-        RequestType::FilesystemChecksumRequest => unreachable!(),
-        RequestType::LookupRequest => unreachable!(),
-        RequestType::ReadRequest => unreachable!(),
-        RequestType::ReadRawRequest => unreachable!(),
-        RequestType::ReaddirRequest => unreachable!(),
-        RequestType::GetattrRequest => unreachable!(),
-        RequestType::GetXattrRequest => unreachable!(),
-        RequestType::ListXattrsRequest => unreachable!(),
-        RequestType::RaftRequest => unreachable!(),
-        RequestType::LatestCommitRequest => unreachable!(),
-        RequestType::GetLeaderRequest => unreachable!(),
-        RequestType::NONE => unreachable!(),
-    }
-
-    response
+        position = end;
+    }
+
+    (runs, source_runs)
+}
+
+fn is_coalesced(request: &GenericRequest) -> bool {
+    request
+        .request_as_write_request()
+        .and_then(|x| x.coalesced())
+        .is_some()
+}
+
+fn send_to_peer(
+    peer: &PeerClient,
+    request: &[u8],
+) -> impl Future<Item = LengthPrefixedVec, Error = ErrorCode> {
+    let mut prefixed = LengthPrefixedVec::with_capacity(request.len());
+    prefixed.extend(request);
+
+    peer.send_and_receive_length_prefixed(prefixed.length_prefixed_bytes().to_vec())
+        .map(|response| {
+            let mut prefixed = LengthPrefixedVec::with_capacity(response.len());
+            prefixed.extend(&response);
+            prefixed
+        })
+        .map_err(|_| ErrorCode::RaftFailure)
+}
+
+// Messages which would make the receiver vote, or start an election
+fn is_election_message(message: &Message) -> bool {
+    match message.get_msg_type() {
+        MessageType::MsgRequestVote
+        | MessageType::MsgRequestPreVote
+        | MessageType::MsgTimeoutNow => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::CoalescedSource;
+    use crate::storage::raft_manager::{plan_coalesced_write, AppliedSession};
+    use std::collections::HashMap;
+
+    #[test]
+    fn retry_after_later_request() {
+        let mut session = AppliedSession::default();
+        // Requests 1 and 2 were sent concurrently, and both were applied before the retry of 1 arrived
+        session.record(1, b"first".to_vec());
+        session.record(2, b"second".to_vec());
+        assert!(session.is_applied(1));
+        assert_eq!(session.responses[&1], b"first");
+        assert!(session.is_applied(2));
+        assert!(!session.is_applied(3));
+    }
+
+    #[test]
+    fn acknowledge() {
+        let mut session = AppliedSession::default();
+        session.record(1, b"first".to_vec());
+        session.record(2, b"second".to_vec());
+        session.acknowledge(2);
+        assert_eq!(
+            session.responses.keys().cloned().collect::<Vec<u64>>(),
+            vec![2]
+        );
+        // A delayed copy of an acknowledged request still isn't applied again
+        assert!(session.is_applied(1));
+        assert!(session.is_applied(2));
+
+        // Requests which weren't sent by the client acknowledge nothing
+        session.acknowledge(0);
+        assert_eq!(session.acknowledged, 2);
+        assert!(session.is_applied(1));
+    }
+
+    #[test]
+    fn coalesced_duplicates() {
+        let mut applied_sessions = HashMap::new();
+        let mut session = AppliedSession::default();
+        session.record(7, b"applied".to_vec());
+        applied_sessions.insert(2, session);
+
+        let sources = [
+            CoalescedSource::new(1, 1, 10),
+            CoalescedSource::new(1, 2, 10),
+            // Retried after it was applied
+            CoalescedSource::new(2, 7, 5),
+            CoalescedSource::new(2, 8, 5),
+            // Retried while the original was waiting to be coalesced
+            CoalescedSource::new(1, 2, 10),
+            CoalescedSource::new(3, 1, 1),
+        ];
+        let (runs, source_runs) = plan_coalesced_write(&sources, &applied_sessions);
+        assert_eq!(runs, vec![0..20, 25..30, 40..41]);
+        assert_eq!(
+            source_runs,
+            vec![Some(0), Some(0), None, Some(1), None, Some(2)]
+        );
+
+        // Writes which aren't part of a session are never duplicates
+        let sources = [CoalescedSource::new(0, 0, 3), CoalescedSource::new(0, 0, 3)];
+        let (runs, source_runs) = plan_coalesced_write(&sources, &applied_sessions);
+        assert_eq!(runs, vec![0..6]);
+        assert_eq!(source_runs, vec![Some(0), Some(0)]);
+    }
 }
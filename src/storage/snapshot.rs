@@ -0,0 +1,290 @@
+use crate::client::NodeClient;
+use crate::generated::{ChecksumAlgorithm, ErrorCode};
+use crate::storage::checksum::Checksum;
+use crate::storage::raft_manager::AppliedSession;
+use crate::utils::into_error_code;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Size of each chunk requested while fetching a snapshot
+const CHUNK_SIZE: u32 = 1024 * 1024;
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const INCOMING_PREFIX: &str = "incoming-";
+// Marks the end of the files in a snapshot. Never a valid inode
+const END_OF_FILES: u64 = 0;
+
+#[derive(Clone, Debug)]
+pub struct SnapshotInfo {
+    // Raft index and term of the last entry included in the snapshot
+    pub index: u64,
+    pub term: u64,
+    pub size: u64,
+    pub checksum: Vec<u8>,
+    pub checksum_algorithm: ChecksumAlgorithm,
+}
+
+// State restored from a snapshot. The Raft log continues from index
+pub struct InstalledSnapshot {
+    pub index: u64,
+    pub term: u64,
+    pub metadata: Vec<u8>,
+    pub sessions: Vec<(u64, AppliedSession)>,
+}
+
+// Snapshots are stored next to the data directory, so that they don't show up as files in it
+pub fn snapshot_directory(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).with_file_name("snapshots")
+}
+
+fn snapshot_path(directory: &Path, index: u64) -> PathBuf {
+    directory.join(format!("{}{}", SNAPSHOT_PREFIX, index))
+}
+
+fn checksum(path: &Path, algorithm: ChecksumAlgorithm) -> io::Result<Vec<u8>> {
+    let mut hasher = Checksum::new(algorithm);
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+// Removes all files in directory with the given prefix, except keep
+fn remove_with_prefix(directory: &Path, prefix: &str, keep: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let matches = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map_or(false, |x| x.starts_with(prefix));
+        if matches && path != keep {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Snapshots are a single file, so that they can be transferred in chunks. They contain the Raft index and term,
+// the serialized metadata, the client sessions, and then the contents of each file which is shipped
+pub struct SnapshotWriter {
+    directory: PathBuf,
+    index: u64,
+    term: u64,
+    checksum_algorithm: ChecksumAlgorithm,
+    file: BufWriter<File>,
+}
+
+impl SnapshotWriter {
+    pub fn new(
+        directory: &Path,
+        index: u64,
+        term: u64,
+        metadata: &[u8],
+        sessions: &[(u64, AppliedSession)],
+        checksum_algorithm: ChecksumAlgorithm,
+    ) -> io::Result<SnapshotWriter> {
+        fs::create_dir_all(directory)?;
+        let mut file = BufWriter::new(File::create(directory.join("snapshot.tmp"))?);
+        file.write_u64::<LittleEndian>(index)?;
+        file.write_u64::<LittleEndian>(term)?;
+        file.write_u64::<LittleEndian>(metadata.len() as u64)?;
+        file.write_all(metadata)?;
+        file.write_u64::<LittleEndian>(sessions.len() as u64)?;
+        for (session_id, session) in sessions {
+            file.write_u64::<LittleEndian>(*session_id)?;
+            file.write_u64::<LittleEndian>(session.acknowledged)?;
+            file.write_u64::<LittleEndian>(session.responses.len() as u64)?;
+            for (sequence_number, response) in session.responses.iter() {
+                file.write_u64::<LittleEndian>(*sequence_number)?;
+                file.write_u64::<LittleEndian>(response.len() as u64)?;
+                file.write_all(response)?;
+            }
+        }
+
+        Ok(SnapshotWriter {
+            directory: directory.to_path_buf(),
+            index,
+            term,
+            checksum_algorithm,
+            file,
+        })
+    }
+
+    pub fn add_file(&mut self, inode: u64, length: u64, contents: &mut Read) -> io::Result<()> {
+        assert_ne!(inode, END_OF_FILES);
+        self.file.write_u64::<LittleEndian>(inode)?;
+        self.file.write_u64::<LittleEndian>(length)?;
+        let copied = io::copy(&mut contents.take(length), &mut self.file)?;
+        if copied != length {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+
+        Ok(())
+    }
+
+    // Makes this the latest snapshot, and removes older ones
+    pub fn finish(mut self) -> io::Result<SnapshotInfo> {
+        self.file.write_u64::<LittleEndian>(END_OF_FILES)?;
+        let file = self.file.into_inner()?;
+        file.sync_all()?;
+        let path = snapshot_path(&self.directory, self.index);
+        fs::rename(self.directory.join("snapshot.tmp"), &path)?;
+        remove_with_prefix(&self.directory, SNAPSHOT_PREFIX, &path)?;
+
+        Ok(SnapshotInfo {
+            index: self.index,
+            term: self.term,
+            size: fs::metadata(&path)?.len(),
+            checksum: checksum(&path, self.checksum_algorithm)?,
+            checksum_algorithm: self.checksum_algorithm,
+        })
+    }
+}
+
+pub fn read_snapshot_chunk(
+    directory: &Path,
+    index: u64,
+    offset: u64,
+    size: u32,
+) -> io::Result<Vec<u8>> {
+    let file = File::open(snapshot_path(directory, index))?;
+    let mut buffer = vec![0; size as usize];
+    let mut read = 0;
+    while read < buffer.len() {
+        let bytes = file.read_at(&mut buffer[read..], offset + read as u64)?;
+        if bytes == 0 {
+            break;
+        }
+        read += bytes;
+    }
+    buffer.truncate(read);
+
+    Ok(buffer)
+}
+
+// Index of the snapshot which is partially downloaded to directory, or 0
+fn incoming_index(directory: &Path) -> u64 {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        if let Some(name) = name.to_str() {
+            if name.starts_with(INCOMING_PREFIX) {
+                if let Ok(index) = name[INCOMING_PREFIX.len()..].parse() {
+                    return index;
+                }
+            }
+        }
+    }
+
+    0
+}
+
+// Downloads the latest snapshot from peer, for the node node_id. A partial download is resumed, as long as the peer
+// still has that snapshot, and the transfer is limited to bytes_per_second, unless it's zero
+pub fn fetch_snapshot(
+    peer: SocketAddr,
+    directory: &Path,
+    bytes_per_second: u64,
+    node_id: u64,
+) -> Result<PathBuf, ErrorCode> {
+    let client = NodeClient::new(peer);
+    let info = client.create_snapshot(incoming_index(directory))?;
+    fs::create_dir_all(directory).map_err(into_error_code)?;
+    let path = directory.join(format!("{}{}", INCOMING_PREFIX, info.index));
+    remove_with_prefix(directory, INCOMING_PREFIX, &path).map_err(into_error_code)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(into_error_code)?;
+    let mut offset = file.metadata().map_err(into_error_code)?.len();
+    if offset > 0 {
+        info!("Resuming snapshot {} at {} bytes", info.index, offset);
+    }
+    while offset < info.size {
+        let start = Instant::now();
+        let chunk = client.read_snapshot(info.index, offset, CHUNK_SIZE, node_id)?;
+        if chunk.is_empty() {
+            return Err(ErrorCode::BadResponse);
+        }
+        file.write_all(&chunk).map_err(into_error_code)?;
+        offset += chunk.len() as u64;
+
+        if bytes_per_second > 0 {
+            let target = Duration::from_micros(chunk.len() as u64 * 1_000_000 / bytes_per_second);
+            if let Some(remaining) = target.checked_sub(start.elapsed()) {
+                sleep(remaining);
+            }
+        }
+    }
+    file.sync_all().map_err(into_error_code)?;
+
+    // Verified with the algorithm chosen by the peer which created the snapshot
+    let fetched_checksum = checksum(&path, info.checksum_algorithm).map_err(into_error_code)?;
+    if fetched_checksum != info.checksum {
+        fs::remove_file(&path).map_err(into_error_code)?;
+        return Err(ErrorCode::Corrupted);
+    }
+    info!("Fetched snapshot {} ({} bytes)", info.index, info.size);
+
+    Ok(path)
+}
+
+// Writes the files in the snapshot to data_dir, and returns the rest of its state
+pub fn install_snapshot(path: &Path, data_dir: &str) -> io::Result<InstalledSnapshot> {
+    let mut file = BufReader::new(File::open(path)?);
+    let index = file.read_u64::<LittleEndian>()?;
+    let term = file.read_u64::<LittleEndian>()?;
+    let mut metadata = vec![0; file.read_u64::<LittleEndian>()? as usize];
+    file.read_exact(&mut metadata)?;
+    let mut sessions = vec![];
+    for _ in 0..file.read_u64::<LittleEndian>()? {
+        let session_id = file.read_u64::<LittleEndian>()?;
+        let mut session = AppliedSession {
+            acknowledged: file.read_u64::<LittleEndian>()?,
+            responses: BTreeMap::new(),
+        };
+        for _ in 0..file.read_u64::<LittleEndian>()? {
+            let sequence_number = file.read_u64::<LittleEndian>()?;
+            let mut response = vec![0; file.read_u64::<LittleEndian>()? as usize];
+            file.read_exact(&mut response)?;
+            session.responses.insert(sequence_number, response);
+        }
+        sessions.push((session_id, session));
+    }
+
+    fs::create_dir_all(data_dir)?;
+    let mut files = 0;
+    loop {
+        let inode = file.read_u64::<LittleEndian>()?;
+        if inode == END_OF_FILES {
+            break;
+        }
+        let length = file.read_u64::<LittleEndian>()?;
+        // Same layout as DataStorage uses for files which aren't packed
+        let mut local_file = File::create(Path::new(data_dir).join(inode.to_string()))?;
+        io::copy(&mut (&mut file).take(length), &mut local_file)?;
+        local_file.sync_all()?;
+        files += 1;
+    }
+    info!("Installed snapshot {} with {} files", index, files);
+
+    Ok(InstalledSnapshot {
+        index,
+        term,
+        metadata,
+        sessions,
+    })
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::generated::ErrorCode;
+
+// Staged data is dropped if its write isn't committed within this time
+pub const STAGED_DATA_TIMEOUT: Duration = Duration::from_secs(300);
+// Limit on the staged data held by a node. Writes whose data doesn't fit are proposed with it through Raft instead
+pub const MAX_STAGED_BYTES: usize = 256 * 1024 * 1024;
+
+struct StagedEntry {
+    staged: Instant,
+    data: Arc<Vec<u8>>,
+    // Applied data is only kept for nodes which fetch it because they didn't receive it, and is dropped first when
+    // space is needed
+    applied: bool,
+}
+
+// Data of large writes, which was pushed to this node ahead of their StagedWriteRequest
+pub struct StagedData {
+    limit: usize,
+    entries: Mutex<HashMap<u64, StagedEntry>>,
+}
+
+impl StagedData {
+    pub fn new(limit: usize) -> StagedData {
+        StagedData {
+            limit,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Fails with NoSpace if the data of the writes which haven't been applied yet would exceed the limit
+    pub fn stage(&self, staged_id: u64, data: &[u8]) -> Result<(), ErrorCode> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: usize = entries.values().map(|x| x.data.len()).sum();
+        if total + data.len() > self.limit {
+            let mut applied: Vec<(Instant, u64, usize)> = entries
+                .iter()
+                .filter(|(_, entry)| entry.applied)
+                .map(|(id, entry)| (entry.staged, *id, entry.data.len()))
+                .collect();
+            applied.sort();
+            for (_, id, length) in applied {
+                if total + data.len() <= self.limit {
+                    break;
+                }
+                entries.remove(&id);
+                total -= length;
+            }
+            if total + data.len() > self.limit {
+                return Err(ErrorCode::NoSpace);
+            }
+        }
+        entries.insert(
+            staged_id,
+            StagedEntry {
+                staged: Instant::now(),
+                data: Arc::new(data.to_vec()),
+                applied: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Stores data fetched from another node, for the write which is about to be applied. Not limited, since the
+    // write is applied right after
+    pub fn restore(&self, staged_id: u64, data: Vec<u8>) {
+        self.entries.lock().unwrap().insert(
+            staged_id,
+            StagedEntry {
+                staged: Instant::now(),
+                data: Arc::new(data),
+                applied: false,
+            },
+        );
+    }
+
+    pub fn contains(&self, staged_id: u64) -> bool {
+        self.entries.lock().unwrap().contains_key(&staged_id)
+    }
+
+    pub fn get(&self, staged_id: u64) -> Option<Arc<Vec<u8>>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&staged_id)
+            .map(|x| x.data.clone())
+    }
+
+    // Returns the data of a write which is being applied. It's kept afterwards, for other nodes to fetch
+    pub fn take_for_apply(&self, staged_id: u64) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&staged_id)?;
+        entry.applied = true;
+
+        Some(entry.data.clone())
+    }
+
+    pub fn expire(&self) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.staged.elapsed() < STAGED_DATA_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generated::ErrorCode;
+    use crate::storage::staged_data::StagedData;
+
+    #[test]
+    fn limit() {
+        let staged = StagedData::new(10);
+        staged.stage(1, &[1; 6]).unwrap();
+        assert_eq!(staged.stage(2, &[2; 6]), Err(ErrorCode::NoSpace));
+
+        // Applied data makes room for new writes
+        assert_eq!(*staged.take_for_apply(1).unwrap(), vec![1; 6]);
+        staged.stage(2, &[2; 6]).unwrap();
+        assert!(!staged.contains(1));
+        assert_eq!(*staged.get(2).unwrap(), vec![2; 6]);
+
+        staged.restore(3, vec![3; 20]);
+        assert_eq!(staged.take_for_apply(3).unwrap().len(), 20);
+        assert!(staged.take_for_apply(4).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use log::info;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::generated::ChecksumAlgorithm;
+use crate::storage::checksum::digest;
+
+// inode, offset, length
+const HEADER_SIZE: usize = 24;
+
+// The journal is stored next to the data directory, so that it doesn't show up as a file in it
+pub fn journal_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).with_file_name("write-journal")
+}
+
+fn encode_record(inode: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + data.len() + 8);
+    record.write_u64::<LittleEndian>(inode).unwrap();
+    record.write_u64::<LittleEndian>(offset).unwrap();
+    record.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+    record.extend_from_slice(data);
+    let checksum = digest(ChecksumAlgorithm::Xxh3, &record);
+    record.extend_from_slice(&checksum);
+
+    record
+}
+
+// Returns None if the record is incomplete, because the node crashed while writing it
+fn decode_record(record: &[u8]) -> Option<(u64, u64, &[u8])> {
+    if record.len() < HEADER_SIZE {
+        return None;
+    }
+    let inode = LittleEndian::read_u64(&record[0..8]);
+    let offset = LittleEndian::read_u64(&record[8..16]);
+    let length = LittleEndian::read_u64(&record[16..24]) as usize;
+    let end = HEADER_SIZE.checked_add(length)?;
+    let checksum = record.get(end..)?;
+    if checksum != digest(ChecksumAlgorithm::Xxh3, &record[..end]).as_slice() {
+        return None;
+    }
+
+    Some((inode, offset, &record[HEADER_SIZE..end]))
+}
+
+// Writes which span multiple blocks are recorded here before they're applied, so that if the node crashes part way
+// through one, it's completed on restart instead of leaving the file with only some of its blocks written
+pub struct WriteJournal {
+    data_dir: PathBuf,
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl WriteJournal {
+    // Completes the write in the journal, if the node crashed while applying it
+    pub fn new(data_dir: &str) -> io::Result<WriteJournal> {
+        let path = journal_path(data_dir);
+        let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+        fs::create_dir_all(parent)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        File::open(parent)?.sync_all()?;
+
+        let journal = WriteJournal {
+            data_dir: Path::new(data_dir).to_path_buf(),
+            path,
+            file: Mutex::new(file),
+        };
+        journal.recover()?;
+
+        Ok(journal)
+    }
+
+    fn recover(&self) -> io::Result<()> {
+        let record = fs::read(&self.path)?;
+        if let Some((inode, offset, data)) = decode_record(&record) {
+            info!("Completing journaled write of inode {}", inode);
+            self.write_file(inode, offset, data)?;
+        }
+        let file = self.file.lock().unwrap();
+        file.set_len(0)?;
+        file.sync_all()
+    }
+
+    fn write_file(&self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(self.data_dir.join(inode.to_string()))?;
+        file.write_all_at(data, offset)?;
+        file.sync_data()
+    }
+
+    // Writes data to the local file of inode at offset, such that after a crash either all of it was written,
+    // or none of it
+    pub fn write(&self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = self.file.lock().unwrap();
+        file.set_len(0)?;
+        file.write_all_at(&encode_record(inode, offset, data), 0)?;
+        file.sync_all()?;
+
+        self.write_file(inode, offset, data)?;
+
+        // The record must be cleared durably, or it could overwrite later writes to the same range, if it's replayed
+        file.set_len(0)?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::write_journal::{encode_record, journal_path, WriteJournal};
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    fn test_data_dir(name: &str) -> String {
+        let directory = env::temp_dir().join(format!("fleetfs-journal-{}-{}", name, process::id()));
+        fs::remove_dir_all(&directory).ok();
+        let data_dir = directory.join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        data_dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn completes_interrupted_write() {
+        let data_dir = test_data_dir("interrupted");
+        let local_path = Path::new(&data_dir).join("5");
+        fs::write(&local_path, vec![1; 100]).unwrap();
+        // Crash after the record was journaled, but before any of the data was written
+        fs::write(journal_path(&data_dir), encode_record(5, 10, &[2; 20])).unwrap();
+
+        WriteJournal::new(&data_dir).unwrap();
+        let contents = fs::read(&local_path).unwrap();
+        assert_eq!(&contents[..10], &[1; 10][..]);
+        assert_eq!(&contents[10..30], &[2; 20][..]);
+        assert_eq!(&contents[30..], &[1; 70][..]);
+        assert!(fs::read(journal_path(&data_dir)).unwrap().is_empty());
+
+        fs::remove_dir_all(Path::new(&data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn discards_torn_record() {
+        let data_dir = test_data_dir("torn");
+        let local_path = Path::new(&data_dir).join("5");
+        fs::write(&local_path, vec![1; 100]).unwrap();
+        // Crash while the record was being journaled, so none of the data was written
+        let record = encode_record(5, 10, &[2; 20]);
+        fs::write(journal_path(&data_dir), &record[..record.len() - 1]).unwrap();
+
+        WriteJournal::new(&data_dir).unwrap();
+        assert_eq!(fs::read(&local_path).unwrap(), vec![1; 100]);
+
+        fs::remove_dir_all(Path::new(&data_dir).parent().unwrap()).unwrap();
+    }
+}
@@ -2,39 +2,293 @@ use std::error::Error;
 use std::fs;
 
 use flatbuffers::FlatBufferBuilder;
-use futures::future::Future;
+use futures::future::{lazy, ok, Either, Future};
 use futures::Stream;
-use tokio::codec::length_delimited;
+use tokio::codec::FramedRead;
 use tokio::net::TcpListener;
 use tokio::prelude::*;
+use tokio::reactor::Handle;
 
-use crate::generated::get_root_as_generic_request;
-use crate::handlers::request_router;
+use crate::bandwidth_limiter::{BandwidthLimiter, SessionLimiter};
+use crate::client_registry::{ClientConnection, ClientLimits, ClientRegistry};
+use crate::event_hooks::{EventHooks, HookConfig};
+use crate::frame_codec::{Frame, RequestFrameCodec};
+use crate::generated::{
+    get_root_as_generic_request, AtimeMode, ChecksumAlgorithm, ErrorCode, RequestType,
+};
+use crate::handlers::authorization::Authorizer;
+use crate::handlers::{check_mandatory_locks, request_inode, request_router, transferred_bytes};
+use crate::inflight_requests::{InflightRequests, RequestState};
+use crate::logging::LogControl;
+use crate::preflight::{lost_data_dir, preflight_peers, preflight_storage};
+use crate::request_stats::RequestStats;
+use crate::request_verifier::verify_request;
+use crate::storage::block_cache::BlockCacheConfig;
 use crate::storage::raft_manager::RaftManager;
-use crate::utils::node_id_from_address;
+use crate::storage::snapshot::{
+    fetch_snapshot, install_snapshot, snapshot_directory, InstalledSnapshot,
+};
+use crate::systemd::{activated_listener, notify_or_warn, watchdog_interval};
+use crate::utils::{node_id_from_address, to_error_response, FlatBufferWithResponse};
+use log::{error, info, warn};
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::thread::sleep;
 use std::time::{Duration, Instant};
-use tokio::timer::Interval;
+use tokio::timer::{Delay, Interval};
+
+#[derive(Clone, Copy)]
+pub struct WriteCoalescing {
+    // Writes are held on the leader for up to this long, waiting for more writes to the same inode.
+    // Zero disables coalescing
+    pub window: Duration,
+    // Maximum size of the data in a coalesced write
+    pub max_batch_bytes: usize,
+}
+
+// Checksum algorithm used for each purpose
+#[derive(Clone, Copy)]
+pub struct ChecksumConfig {
+    // Comparing the data stored on each node
+    pub fsck: ChecksumAlgorithm,
+    // Verifying data transferred between nodes, such as snapshots
+    pub wire: ChecksumAlgorithm,
+}
+
+// What a node does if the data it stores is inconsistent with the metadata, when it starts
+#[derive(Clone, Copy, PartialEq)]
+pub enum StartupCheck {
+    Off,
+    Repair,
+    // Refuse to start, so that the data can be inspected
+    Refuse,
+}
+
+// Whether reads and writes respect the byte range locks of other client sessions, which are otherwise advisory
+#[derive(Clone, Copy, PartialEq)]
+pub enum MandatoryLocking {
+    Off,
+    // Conflicting reads and writes fail with LockConflict
+    Fail,
+    // Conflicting reads and writes wait until the locks are released
+    Block,
+}
 
 #[derive(Clone)]
 pub struct LocalContext {
     pub data_dir: String,
+    pub cluster_name: String,
+    // Voting nodes, excluding this one
     pub peers: Vec<SocketAddr>,
+    // Observer nodes, excluding this one
+    pub observers: Vec<SocketAddr>,
+    // Observers don't vote or store data. They follow the Raft log, and cache data fetched from the peers
+    pub observer: bool,
     pub node_id: u64,
+    // Whether this node maintains an index of file contents
+    pub content_index: bool,
+    // Access time mode used for reads which don't specify one
+    pub atime_mode: AtimeMode,
+    pub write_coalescing: WriteCoalescing,
+    pub authorizer: Arc<Authorizer>,
+    // Whether names are compared case-insensitively, while preserving their case
+    pub case_insensitive: bool,
+    pub checksums: ChecksumConfig,
+    // Percentage of the disk which is reserved for root, so that metadata operations and deletions still work
+    // when it's full
+    pub reserved_space_percent: u8,
+    pub log_control: LogControl,
+    // Limits the snapshots sent to other nodes
+    pub replication_limiter: BandwidthLimiter,
+    // Limits the requests of each client session
+    pub session_limiter: SessionLimiter,
+    // IO budget of the background maintenance tasks. Zero is unlimited
+    pub background_bytes_per_second: u64,
+    pub block_cache: BlockCacheConfig,
+    // Longest request frame which is accepted, and longest read which is served
+    pub max_frame_length: usize,
+    // Client requests received by this node
+    pub request_stats: Arc<RequestStats>,
+    // Requests this node is currently executing
+    pub inflight_requests: Arc<InflightRequests>,
+    pub hooks: EventHooks,
+    // Whether getattr, getxattr and listxattr are served without syncing with the leader
+    pub relaxed_metadata_reads: bool,
+    // Topology label of the node, reported to clients so that they can prefer nearby nodes
+    pub zone: String,
+    pub mandatory_locking: MandatoryLocking,
+    // Client sessions connected to this node
+    pub clients: Arc<ClientRegistry>,
 }
 
 impl LocalContext {
-    pub fn new(data_dir: &str, peers: Vec<SocketAddr>, node_id: u64) -> LocalContext {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        data_dir: &str,
+        cluster_name: &str,
+        peers: Vec<SocketAddr>,
+        observers: Vec<SocketAddr>,
+        observer: bool,
+        node_id: u64,
+        content_index: bool,
+        atime_mode: AtimeMode,
+        write_coalescing: WriteCoalescing,
+        authorizer: Arc<Authorizer>,
+        case_insensitive: bool,
+        checksums: ChecksumConfig,
+        reserved_space_percent: u8,
+        log_control: LogControl,
+        replication_limiter: BandwidthLimiter,
+        session_limiter: SessionLimiter,
+        background_bytes_per_second: u64,
+        block_cache: BlockCacheConfig,
+        max_frame_length: usize,
+        hooks: HookConfig,
+        relaxed_metadata_reads: bool,
+        zone: &str,
+        mandatory_locking: MandatoryLocking,
+        client_limits: ClientLimits,
+    ) -> LocalContext {
         LocalContext {
             data_dir: data_dir.to_string(),
+            cluster_name: cluster_name.to_string(),
             peers,
+            observers,
+            observer,
             node_id,
+            content_index,
+            atime_mode,
+            write_coalescing,
+            authorizer,
+            case_insensitive,
+            checksums,
+            reserved_space_percent,
+            log_control,
+            replication_limiter,
+            session_limiter,
+            background_bytes_per_second,
+            block_cache,
+            max_frame_length,
+            request_stats: Arc::new(RequestStats::new()),
+            inflight_requests: Arc::new(InflightRequests::default()),
+            hooks: EventHooks::new(hooks),
+            relaxed_metadata_reads,
+            zone: zone.to_string(),
+            mandatory_locking,
+            clients: Arc::new(ClientRegistry::new(client_limits)),
+        }
+    }
+}
+
+// Fetches and installs a snapshot from one of the peers, retrying until it succeeds
+fn bootstrap_from_peers(context: &LocalContext, bytes_per_second: u64) -> InstalledSnapshot {
+    let not_empty = fs::read_dir(&context.data_dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if not_empty {
+        panic!("Data dir must be empty to join: {}", context.data_dir);
+    }
+
+    let directory = snapshot_directory(&context.data_dir);
+    loop {
+        for peer in context.peers.iter() {
+            match fetch_snapshot(*peer, &directory, bytes_per_second, context.node_id) {
+                Ok(path) => {
+                    let installed = install_snapshot(&path, &context.data_dir)
+                        .expect("Failed to install snapshot");
+                    fs::remove_file(path).expect("Failed to remove snapshot");
+                    return installed;
+                }
+                Err(error_code) => {
+                    warn!("Failed to fetch snapshot from {}: {:?}", peer, error_code)
+                }
+            }
         }
+        sleep(Duration::from_secs(1));
+    }
+}
+
+// Client requests which a recovering node serves, since they only inspect or configure the node itself
+fn served_while_recovering(request_type: RequestType) -> bool {
+    match request_type {
+        RequestType::PingRequest
+        | RequestType::NodeInfoRequest
+        | RequestType::RaftDebugRequest
+        | RequestType::InflightRequestsRequest
+        | RequestType::SetLogLevelRequest
+        | RequestType::SetRequestDumpingRequest
+        | RequestType::BackgroundControlRequest
+        | RequestType::SetReplicationBandwidthRequest
+        | RequestType::SetSessionLimitsRequest
+        | RequestType::BlockCacheStatsRequest => true,
+        _ => false,
+    }
+}
+
+// Requests which are only accepted from the node's own host, because they can destroy data
+fn admin_only(request_type: RequestType) -> bool {
+    match request_type {
+        RequestType::ForceNewClusterRequest => true,
+        _ => false,
     }
 }
 
+fn check_local_data(raft_manager: &RaftManager, repair: bool) {
+    let problems = raft_manager
+        .file_storage()
+        .check_local_data(repair)
+        .expect("Failed to check local data");
+    for problem in problems.iter() {
+        warn!("Local data inconsistency: {}", problem);
+    }
+    if !problems.is_empty() {
+        if repair {
+            warn!("Repaired {} local data inconsistencies", problems.len());
+        } else {
+            panic!(
+                "Found {} local data inconsistencies. Start with --startup-check=repair to fix them",
+                problems.len()
+            );
+        }
+    }
+}
+
+pub struct NodeConfig {
+    // The node's data is stored in the data subdirectory
+    pub node_dir: String,
+    pub cluster_name: String,
+    pub bind_address: SocketAddr,
+    // Voting nodes, excluding this one
+    pub peers: Vec<SocketAddr>,
+    // Observer nodes, excluding this one
+    pub observers: Vec<SocketAddr>,
+    pub observer: bool,
+    pub content_index: bool,
+    pub atime_mode: AtimeMode,
+    pub write_coalescing: WriteCoalescing,
+    pub authorizer: Arc<Authorizer>,
+    pub case_insensitive: bool,
+    pub checksums: ChecksumConfig,
+    pub reserved_space_percent: u8,
+    pub replication_limiter: BandwidthLimiter,
+    pub session_limiter: SessionLimiter,
+    pub startup_check: StartupCheck,
+    pub background_bytes_per_second: u64,
+    pub block_cache: BlockCacheConfig,
+    pub max_frame_length: usize,
+    pub hooks: HookConfig,
+    pub relaxed_metadata_reads: bool,
+    pub zone: String,
+    pub mandatory_locking: MandatoryLocking,
+    pub client_limits: ClientLimits,
+    // If set, the node fetches a snapshot from its peers before it starts, and catches up with the log before it
+    // votes or serves clients. Used to replace a node whose data was lost
+    pub join: bool,
+    // Limits the transfer of the snapshot. 0 is unlimited
+    pub snapshot_bandwidth: u64,
+}
+
 pub struct Node {
     context: LocalContext,
     raft_manager: RaftManager,
@@ -42,14 +296,102 @@ pub struct Node {
 }
 
 impl Node {
-    pub fn new(node_dir: &str, bind_address: SocketAddr, peers: Vec<SocketAddr>) -> Node {
-        let data_dir = Path::new(node_dir).join("data");
+    pub fn new(config: NodeConfig, log_control: LogControl) -> Node {
+        let NodeConfig {
+            node_dir,
+            cluster_name,
+            bind_address,
+            peers,
+            observers,
+            observer,
+            content_index,
+            atime_mode,
+            write_coalescing,
+            authorizer,
+            case_insensitive,
+            checksums,
+            reserved_space_percent,
+            replication_limiter,
+            session_limiter,
+            startup_check,
+            background_bytes_per_second,
+            block_cache,
+            max_frame_length,
+            hooks,
+            relaxed_metadata_reads,
+            zone,
+            mandatory_locking,
+            client_limits,
+            join,
+            snapshot_bandwidth,
+        } = config;
+        let data_dir = Path::new(&node_dir).join("data");
         // Unique ID of node within the cluster. Never 0.
         let node_id = node_id_from_address(&bind_address);
-        let context = LocalContext::new(data_dir.to_str().unwrap(), peers, node_id);
+        // A node which lost its data rejoins, instead of starting an empty copy of the filesystem. Without peers,
+        // there's nothing to rejoin
+        let join = if !join && !peers.is_empty() && lost_data_dir(data_dir.to_str().unwrap()) {
+            warn!(
+                "Data dir {:?} is empty, but this node was a member of cluster {:?}. Rejoining from a snapshot",
+                data_dir, cluster_name
+            );
+            true
+        } else {
+            join
+        };
+        preflight_storage(data_dir.to_str().unwrap(), &cluster_name);
+        let context = LocalContext::new(
+            data_dir.to_str().unwrap(),
+            &cluster_name,
+            peers,
+            observers,
+            observer,
+            node_id,
+            content_index,
+            atime_mode,
+            write_coalescing,
+            authorizer,
+            case_insensitive,
+            checksums,
+            reserved_space_percent,
+            log_control,
+            replication_limiter,
+            session_limiter,
+            background_bytes_per_second,
+            block_cache,
+            max_frame_length,
+            hooks,
+            relaxed_metadata_reads,
+            &zone,
+            mandatory_locking,
+            client_limits,
+        );
+        let raft_manager = RaftManager::new(
+            context.clone(),
+            if join {
+                Some(bootstrap_from_peers(&context, snapshot_bandwidth))
+            } else {
+                None
+            },
+        );
+        // Before this node serves clients, or stops recovering. Observers don't store data
+        if join && !context.observer {
+            let lost = raft_manager
+                .file_storage()
+                .restore_local_data(&context.peers)
+                .expect("Failed to restore local data");
+            if lost > 0 {
+                error!("Rejoined without the local data of {} files", lost);
+            }
+        }
+        // Interrupted writes were already completed from the write journal, when the storage was opened
+        if startup_check != StartupCheck::Off {
+            check_local_data(&raft_manager, startup_check == StartupCheck::Repair);
+        }
+        raft_manager.file_storage().warm_block_cache();
         Node {
-            context: context.clone(),
-            raft_manager: RaftManager::new(context.clone()),
+            context,
+            raft_manager,
             bind_address,
         }
     }
@@ -58,36 +400,169 @@ impl Node {
         if let Err(why) = fs::create_dir_all(&self.context.data_dir) {
             panic!("Couldn't create storage dir: {}", why.description());
         };
+        preflight_peers(&self.context);
 
-        let listener = TcpListener::bind(&self.bind_address).expect("unable to bind API listener");
+        // systemd may have already bound the socket, if it started the node by socket activation
+        let listener = match activated_listener() {
+            Some(listener) => TcpListener::from_std(listener, &Handle::default())
+                .expect("unable to use activated API listener"),
+            None => TcpListener::bind(&self.bind_address).expect("unable to bind API listener"),
+        };
 
         let raft_manager = Arc::new(self.raft_manager);
         let raft_manager_cloned = raft_manager.clone();
+        let indexing_raft_manager = raft_manager.clone();
+        let maintenance_raft_manager = raft_manager.clone();
+        let space_raft_manager = raft_manager.clone();
+        let atime_raft_manager = raft_manager.clone();
+        let sessions_raft_manager = raft_manager.clone();
+        let coalescing_raft_manager = raft_manager.clone();
         let server = listener
             .incoming()
             .map_err(|e| eprintln!("accept connection failed = {:?}", e))
             .for_each(move |socket| {
+                let client = socket
+                    .peer_addr()
+                    .map(|x| x.to_string())
+                    .unwrap_or_default();
+                let same_host = match (socket.peer_addr(), socket.local_addr()) {
+                    (Ok(peer), Ok(local)) => peer.ip().is_loopback() || peer.ip() == local.ip(),
+                    _ => false,
+                };
                 let (reader, writer) = socket.split();
-                let reader = length_delimited::Builder::new()
-                    .little_endian()
-                    .new_read(reader);
+                let max_frame_length = raft_manager.local_context().max_frame_length;
+                let reader = FramedRead::new(reader, RequestFrameCodec::new(max_frame_length));
 
                 let cloned_raft = raft_manager.clone();
+                let (mut connection, evicted) =
+                    ClientConnection::new(&raft_manager.local_context().clients, client.clone());
                 let builder = FlatBufferBuilder::new();
                 let conn = reader.fold((writer, builder), move |(writer, mut builder), frame| {
+                    // Malformed and oversized requests are answered with an error, instead of being read
+                    let checked = match frame {
+                        Frame::Request(frame) => verify_request(&frame)
+                            .and_then(|_| {
+                                let request = get_root_as_generic_request(&frame);
+                                // Reads are limited too, so that a request can't make the node buffer
+                                // an arbitrarily large response
+                                if transferred_bytes(&request, frame.len()) as usize
+                                    > max_frame_length
+                                {
+                                    return Err(ErrorCode::RequestTooLarge);
+                                }
+                                // Requests from other nodes, such as Raft messages, are how it recovers
+                                if request.session_id() != 0
+                                    && cloned_raft.is_recovering()
+                                    && !served_while_recovering(request.request_type())
+                                {
+                                    return Err(ErrorCode::Recovering);
+                                }
+                                if admin_only(request.request_type()) && !same_host {
+                                    return Err(ErrorCode::OperationNotPermitted);
+                                }
+                                connection.record_request(request.session_id())
+                            })
+                            .map(|_| frame),
+                        Frame::Oversized(_) => Err(ErrorCode::RequestTooLarge),
+                    };
+                    let frame = match checked {
+                        Ok(frame) => frame,
+                        Err(error_code) => {
+                            let response =
+                                to_error_response(error_code, cloned_raft.current_term());
+                            return Either::A(
+                                tokio::io::write_all(writer, FlatBufferWithResponse::new(response))
+                                    .map(|(writer, written)| (writer, written.into_buffer())),
+                            );
+                        }
+                    };
                     let request = get_root_as_generic_request(&frame);
                     builder.reset();
+                    let bytes = transferred_bytes(&request, frame.len());
+                    let context = cloned_raft.local_context();
+                    // Requests without a session are from other nodes
+                    if request.session_id() != 0 {
+                        context.request_stats.record(request.request_type(), bytes);
+                    }
+                    // Requests over their session's rate limit are delayed, which also holds back the later
+                    // requests on the connection
+                    let delay = context.session_limiter.reserve(request.session_id(), bytes);
+                    let inflight = InflightRequests::start(
+                        &context.inflight_requests,
+                        request.request_type(),
+                        request_inode(&request),
+                        request.session_id(),
+                        client.clone(),
+                    );
+                    if delay > Duration::from_secs(0) {
+                        inflight.set_state(RequestState::RateLimited);
+                    }
+                    let routed_inflight = inflight.clone();
 
-                    let response = request_router(request, cloned_raft.clone(), builder);
-                    response
-                        .map(|response| tokio::io::write_all(writer, response))
-                        .flatten()
-                        .map(|(writer, written)| (writer, written.into_buffer()))
+                    let raft = cloned_raft.clone();
+                    Either::B(
+                        Delay::new(Instant::now() + delay)
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                            .and_then(move |_| {
+                                let request = get_root_as_generic_request(&frame);
+                                routed_inflight.set_state(RequestState::Executing);
+                                check_mandatory_locks(&request, &raft, &routed_inflight).then(
+                                    move |locked| {
+                                        routed_inflight.set_state(RequestState::Executing);
+                                        match locked {
+                                            Ok(()) => {
+                                                let request = get_root_as_generic_request(&frame);
+                                                Either::A(request_router(
+                                                    request,
+                                                    raft,
+                                                    builder,
+                                                    routed_inflight,
+                                                ))
+                                            }
+                                            Err(error_code) => Either::B(ok(
+                                                FlatBufferWithResponse::new(to_error_response(
+                                                    error_code,
+                                                    raft.current_term(),
+                                                )),
+                                            )),
+                                        }
+                                    },
+                                )
+                            })
+                            .map(move |response| {
+                                // Listed until its response is ready
+                                drop(inflight);
+                                tokio::io::write_all(writer, response)
+                            })
+                            .flatten()
+                            .map(|(writer, written)| (writer, written.into_buffer())),
+                    )
                 });
 
+                // Dropping the connection closes it, if its session is evicted
+                let conn = conn
+                    .map(|_| ())
+                    .map_err(|_| ())
+                    .select(evicted.then(|_| Ok::<(), ()>(())));
                 tokio::spawn(conn.map(|_| ()).map_err(|_| ()))
             });
 
+        // Asks the leader how far it has applied the log, until this node has caught up with it
+        let recovery_raft_manager = raft_manager.clone();
+        let recovery = Interval::new(Instant::now(), Duration::from_secs(1))
+            .map_err(|e| panic!("Recovery thread failed error: {:?}", e))
+            .take_while(move |_| Ok(recovery_raft_manager.is_recovering()));
+        let recovery_raft_manager = raft_manager.clone();
+        let recovery = recovery.for_each(move |_| {
+            let raft = recovery_raft_manager.clone();
+            recovery_raft_manager
+                .get_latest_commit_from_leader()
+                .then(move |leader_applied| {
+                    raft.finish_recovery(leader_applied.unwrap_or(0));
+                    Ok(())
+                })
+        });
+
         let background_raft = Interval::new(Instant::now(), Duration::from_millis(100))
             .for_each(move |_| {
                 raft_manager_cloned.background_tick();
@@ -101,6 +576,73 @@ impl Node {
             .build()
             .unwrap();
         runtime.spawn(server);
+        runtime.spawn(recovery);
+        runtime.spawn(lazy(|| {
+            notify_or_warn("READY=1");
+            Ok(())
+        }));
+        // The watchdog is notified from the runtime, so that systemd restarts the node if the runtime hangs
+        if let Some(watchdog_interval) = watchdog_interval() {
+            let watchdog = Interval::new(Instant::now(), watchdog_interval)
+                .for_each(|_| {
+                    notify_or_warn("WATCHDOG=1");
+                    Ok(())
+                })
+                .map_err(|e| panic!("Watchdog thread failed error: {:?}", e));
+            runtime.spawn(watchdog);
+        }
+        let background_maintenance = Interval::new(Instant::now(), Duration::from_secs(10))
+            .for_each(move |_| {
+                maintenance_raft_manager.file_storage().run_maintenance();
+                Ok(())
+            })
+            .map_err(|e| panic!("Background maintenance thread failed error: {:?}", e));
+        runtime.spawn(background_maintenance);
+        let background_space = Interval::new(Instant::now(), Duration::from_secs(10))
+            .for_each(move |_| {
+                space_raft_manager
+                    .file_storage()
+                    .refresh_node_space()
+                    .then(|_| Ok(()))
+            })
+            .map_err(|e| panic!("Background space thread failed error: {:?}", e));
+        runtime.spawn(background_space);
+        // relatime updates are batched, so that reads don't each require a Raft proposal
+        let background_atime = Interval::new(Instant::now(), Duration::from_secs(1))
+            .for_each(move |_| {
+                atime_raft_manager.flush_atime_updates();
+                Ok(())
+            })
+            .map_err(|e| panic!("Background atime thread failed error: {:?}", e));
+        runtime.spawn(background_atime);
+        let background_sessions = Interval::new(Instant::now(), Duration::from_secs(1))
+            .for_each(move |_| {
+                sessions_raft_manager.end_expired_sessions();
+                Ok(())
+            })
+            .map_err(|e| panic!("Background sessions thread failed error: {:?}", e));
+        runtime.spawn(background_sessions);
+        let coalescing_window = self.context.write_coalescing.window;
+        if coalescing_window > Duration::from_secs(0) {
+            let background_coalescing = Interval::new(Instant::now(), coalescing_window)
+                .for_each(move |_| {
+                    coalescing_raft_manager.flush_coalesced_writes(false);
+                    Ok(())
+                })
+                .map_err(|e| panic!("Background coalescing thread failed error: {:?}", e));
+            runtime.spawn(background_coalescing);
+        }
+        if self.context.content_index {
+            let background_index = Interval::new(Instant::now(), Duration::from_secs(1))
+                .for_each(move |_| {
+                    indexing_raft_manager
+                        .file_storage()
+                        .reindex_content()
+                        .then(|_| Ok(()))
+                })
+                .map_err(|e| panic!("Background indexing thread failed error: {:?}", e));
+            runtime.spawn(background_index);
+        }
         runtime.block_on_all(background_raft.map(|_| ())).unwrap();
     }
 }
@@ -0,0 +1,99 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+use log::warn;
+
+// First file descriptor passed by socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+// Sends state to systemd, like sd_notify(). Returns false if the process isn't supervised by systemd
+pub fn notify(state: &str) -> io::Result<bool> {
+    let socket_path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(false),
+    };
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = socket_path.as_bytes();
+    if path.is_empty() || path.len() >= address.sun_path.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    for (i, byte) in path.iter().enumerate() {
+        address.sun_path[i] = *byte as libc::c_char;
+    }
+    // Sockets in the abstract namespace start with a null byte, instead of @
+    if path[0] == b'@' {
+        address.sun_path[0] = 0;
+    }
+    let address_length = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    unsafe {
+        let socket = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sent = libc::sendto(
+            socket,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            address_length as libc::socklen_t,
+        );
+        let error = io::Error::last_os_error();
+        libc::close(socket);
+        if sent < 0 {
+            return Err(error);
+        }
+    }
+
+    return Ok(true);
+}
+
+pub fn notify_or_warn(state: &str) {
+    if let Err(error) = notify(state) {
+        warn!("Failed to notify systemd of {}: {:?}", state, error);
+    }
+}
+
+// Returns the interval at which the watchdog must be notified, if systemd's watchdog is enabled for this process.
+// It's half the watchdog timeout, as recommended by sd_watchdog_enabled()
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(unsafe { libc::getpid() }) {
+            return None;
+        }
+    }
+    let timeout: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if timeout == 0 {
+        return None;
+    }
+    return Some(Duration::from_micros(timeout / 2));
+}
+
+// Returns the listening socket passed by systemd, if the process was started by socket activation
+pub fn activated_listener() -> Option<TcpListener> {
+    let pid: i32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != unsafe { libc::getpid() } {
+        return None;
+    }
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    // The variables only apply to this process, so don't pass them on to children
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("Only the first of {} activated sockets is used", fds);
+    }
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        return Some(TcpListener::from_raw_fd(LISTEN_FDS_START));
+    }
+}
@@ -1,25 +1,163 @@
-use std::io::{Read, Write};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Instant;
 
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use core::time::Duration;
+use log::{info, warn};
+
+use crate::frame_codec::DEFAULT_MAX_FRAME_LENGTH;
 
 const TIMEOUT: u64 = 10;
 
+// Application level keepalive. Idle connections are pinged, so that connections which were silently dropped,
+// by a NAT timeout or a crashed node, are detected and replaced before a request is sent on them
+#[derive(Clone, Copy)]
+pub struct Keepalive {
+    // Connections which have been idle this long are pinged
+    pub interval: Duration,
+    // The connection is considered dead if the ping isn't answered within this long
+    pub timeout: Duration,
+}
+
+struct Connection {
+    stream: Option<TcpStream>,
+    last_used: Instant,
+}
+
+fn connect(server: &SocketAddr, timeout: Duration) -> Result<TcpStream, std::io::Error> {
+    let stream = TcpStream::connect_timeout(server, timeout)?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(TIMEOUT)))
+        .expect("Timeout cannot be zero");
+    stream
+        .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
+        .expect("Timeout cannot be zero");
+    return Ok(stream);
+}
+
+fn ping(
+    mut stream: TcpStream,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<TcpStream, std::io::Error> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(request)?;
+    let data_size = stream.read_u32::<LittleEndian>()?;
+    let mut response = vec![0; data_size as usize];
+    stream.read_exact(&mut response)?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    return Ok(stream);
+}
+
+// Runs until the client is dropped
+fn keepalive_loop(
+    server: SocketAddr,
+    connection: Weak<Mutex<Connection>>,
+    keepalive: Keepalive,
+    ping_request: Vec<u8>,
+) {
+    loop {
+        thread::sleep(keepalive.interval);
+        let connection = match connection.upgrade() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut locked = connection.lock().expect("lock acquisition failed");
+        if locked.last_used.elapsed() < keepalive.interval {
+            continue;
+        }
+        if let Some(stream) = locked.stream.take() {
+            match ping(stream, &ping_request, keepalive.timeout) {
+                Ok(stream) => locked.stream = Some(stream),
+                Err(error) => {
+                    warn!("Connection to {} is dead: {:?}", server, error);
+                    // Reconnect now, so that the next request doesn't have to wait for it
+                    match connect(&server, keepalive.timeout) {
+                        Ok(stream) => {
+                            info!("Reconnected to {}", server);
+                            locked.stream = Some(stream);
+                        }
+                        Err(error) => warn!("Failed to reconnect to {}: {:?}", server, error),
+                    }
+                }
+            }
+        }
+        locked.last_used = Instant::now();
+    }
+}
+
 pub struct TcpClient {
     server: SocketAddr,
-    // TODO: should probably have a connection pool here
-    connection: Mutex<Option<TcpStream>>,
+    // Each connection carries one request at a time, so concurrent requests use separate connections
+    connections: Vec<Arc<Mutex<Connection>>>,
+    next_connection: AtomicUsize,
+    // Longer responses are discarded, instead of being buffered
+    max_frame_length: usize,
 }
 
 impl TcpClient {
     pub fn new(server: SocketAddr) -> TcpClient {
+        TcpClient::with_connections(server, 1)
+    }
+
+    pub fn with_connections(server: SocketAddr, connections: usize) -> TcpClient {
+        assert!(connections > 0);
         TcpClient {
             server,
-            connection: Mutex::new(None),
+            connections: (0..connections)
+                .map(|_| {
+                    Arc::new(Mutex::new(Connection {
+                        stream: None,
+                        last_used: Instant::now(),
+                    }))
+                })
+                .collect(),
+            next_connection: AtomicUsize::new(0),
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    // ping_request is a length prefixed request, which the server answers without side effects
+    pub fn with_keepalive(
+        server: SocketAddr,
+        connections: usize,
+        keepalive: Keepalive,
+        ping_request: Vec<u8>,
+    ) -> TcpClient {
+        let client = TcpClient::with_connections(server, connections);
+        for connection in client.connections.iter() {
+            let connection = Arc::downgrade(connection);
+            let ping_request = ping_request.clone();
+            thread::spawn(move || keepalive_loop(server, connection, keepalive, ping_request));
         }
+        return client;
+    }
+
+    // Returns an idle connection, or waits for one if they're all busy
+    fn acquire(&self) -> MutexGuard<Connection> {
+        for connection in self.connections.iter() {
+            if let Ok(locked) = connection.try_lock() {
+                return locked;
+            }
+        }
+        let next = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        return self.connections[next % self.connections.len()]
+            .lock()
+            .expect("lock acquisition failed");
     }
 
     pub fn send_and_receive_length_prefixed(
@@ -27,41 +165,67 @@ impl TcpClient {
         data: &[u8],
         response: &mut Vec<u8>,
     ) -> Result<(), std::io::Error> {
-        let mut locked = self.connection.lock().expect("lock acquisition failed");
-        if locked.is_none() {
-            let stream = TcpStream::connect_timeout(&self.server, Duration::from_secs(TIMEOUT))?;
-            stream
-                .set_read_timeout(Some(Duration::from_secs(TIMEOUT)))
-                .expect("Timeout cannot be zero");
-            stream
-                .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
-                .expect("Timeout cannot be zero");
-            locked.replace(stream);
+        response.clear();
+        return self.send_and_receive_appended(data, response);
+    }
+
+    // Like send_and_receive_length_prefixed, except that the response is appended to response. Large reads are
+    // assembled by reading each chunk straight into the result, instead of into a buffer which is then copied.
+    // If it fails, response is left as it was
+    pub fn send_and_receive_appended(
+        &self,
+        data: &[u8],
+        response: &mut Vec<u8>,
+    ) -> Result<(), std::io::Error> {
+        let start = response.len();
+        let result = self.exchange(data, response, start);
+        if result.is_err() {
+            response.truncate(start);
         }
+        return result;
+    }
 
-        let mut stream = locked.take().expect("connected stream is None");
+    fn exchange(
+        &self,
+        data: &[u8],
+        response: &mut Vec<u8>,
+        start: usize,
+    ) -> Result<(), std::io::Error> {
+        let mut locked = self.acquire();
+        locked.last_used = Instant::now();
+        if locked.stream.is_none() {
+            let stream = connect(&self.server, Duration::from_secs(TIMEOUT))?;
+            locked.stream.replace(stream);
+        }
+
+        let mut stream = locked.stream.take().expect("connected stream is None");
 
         match stream.write_all(data) {
             Ok(_) => {}
             Err(_) => {
                 // Retry once
-                stream = TcpStream::connect_timeout(&self.server, Duration::from_secs(TIMEOUT))?;
-                stream
-                    .set_read_timeout(Some(Duration::from_secs(TIMEOUT)))
-                    .expect("Timeout cannot be zero");
-                stream
-                    .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
-                    .expect("Timeout cannot be zero");
+                stream = connect(&self.server, Duration::from_secs(TIMEOUT))?;
                 stream.write_all(data)?;
             }
         }
 
-        let data_size = stream.read_u32::<LittleEndian>()?;
-        response.resize(data_size as usize, 0);
-        stream.read_exact(response)?;
+        let data_size = stream.read_u32::<LittleEndian>()? as usize;
+        if data_size > self.max_frame_length {
+            // Skip the response, so that the connection can still be used
+            let skipped = io::copy(&mut (&stream).take(data_size as u64), &mut io::sink())?;
+            if skipped == data_size as u64 {
+                locked.stream.replace(stream);
+            }
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Response of {} bytes is too large", data_size),
+            ));
+        }
+        response.resize(start + data_size, 0);
+        stream.read_exact(&mut response[start..])?;
 
         // If the connection is still working, store it back
-        locked.replace(stream);
+        locked.stream.replace(stream);
 
         return Ok(());
     }
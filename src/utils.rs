@@ -32,10 +32,22 @@ pub fn empty_response(mut buffer: FlatBufferBuilder) -> ResultResponse {
 pub fn into_error_code(error: std::io::Error) -> ErrorCode {
     match error.kind() {
         ErrorKind::NotFound => ErrorCode::DoesNotExist,
+        ErrorKind::PermissionDenied => ErrorCode::AccessDenied,
+        ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+        ErrorKind::InvalidInput => ErrorCode::InvalidArgument,
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => ErrorCode::TimedOut,
+        ErrorKind::Interrupted => ErrorCode::Interrupted,
         ErrorKind::Other => {
             if let Some(code) = error.raw_os_error() {
-                if code == libc::EFBIG {
-                    return ErrorCode::FileTooLarge;
+                match code {
+                    libc::EFBIG => return ErrorCode::FileTooLarge,
+                    libc::ENOSPC => return ErrorCode::NoSpace,
+                    libc::ENOTDIR => return ErrorCode::NotADirectory,
+                    libc::EISDIR => return ErrorCode::IsADirectory,
+                    libc::EMLINK => return ErrorCode::TooManyLinks,
+                    libc::EROFS => return ErrorCode::ReadOnly,
+                    libc::ELOOP => return ErrorCode::TooManySymlinks,
+                    _ => {}
                 }
             }
             return ErrorCode::Uncategorized;
@@ -48,10 +60,46 @@ pub fn finalize_request(
     builder: &mut FlatBufferBuilder,
     request_type: RequestType,
     finish_offset: WIPOffset<UnionWIPOffset>,
+) {
+    finalize_session_request(builder, request_type, finish_offset, 0, 0);
+}
+
+pub fn finalize_session_request(
+    builder: &mut FlatBufferBuilder,
+    request_type: RequestType,
+    finish_offset: WIPOffset<UnionWIPOffset>,
+    session_id: u64,
+    sequence_number: u64,
+) {
+    finalize_client_request(
+        builder,
+        request_type,
+        finish_offset,
+        session_id,
+        sequence_number,
+        0,
+        0,
+    );
+}
+
+// Like finalize_session_request(), except that the requests of the session before acknowledged were answered, and
+// nodes whose Raft term is older than term reject the request
+pub fn finalize_client_request(
+    builder: &mut FlatBufferBuilder,
+    request_type: RequestType,
+    finish_offset: WIPOffset<UnionWIPOffset>,
+    session_id: u64,
+    sequence_number: u64,
+    acknowledged: u64,
+    term: u64,
 ) {
     let mut generic_request_builder = GenericRequestBuilder::new(builder);
     generic_request_builder.add_request_type(request_type);
     generic_request_builder.add_request(finish_offset);
+    generic_request_builder.add_session_id(session_id);
+    generic_request_builder.add_sequence_number(sequence_number);
+    generic_request_builder.add_term(term);
+    generic_request_builder.add_acknowledged(acknowledged);
     let finish_offset = generic_request_builder.finish();
     builder.finish_size_prefixed(finish_offset, None);
 }
@@ -60,14 +108,30 @@ pub fn finalize_response(
     builder: &mut FlatBufferBuilder,
     response_type: ResponseType,
     finish_offset: WIPOffset<UnionWIPOffset>,
+    term: u64,
 ) {
     let mut generic_response_builder = GenericResponseBuilder::new(builder);
     generic_response_builder.add_response_type(response_type);
     generic_response_builder.add_response(finish_offset);
+    generic_response_builder.add_term(term);
     let finish_offset = generic_response_builder.finish();
     builder.finish_size_prefixed(finish_offset, None);
 }
 
+pub fn to_error_response(error_code: ErrorCode, term: u64) -> FlatBufferBuilder<'static> {
+    let mut builder = FlatBufferBuilder::new();
+    let args = ErrorResponseArgs { error_code };
+    let response_offset = ErrorResponse::create(&mut builder, &args).as_union_value();
+    finalize_response(
+        &mut builder,
+        ResponseType::ErrorResponse,
+        response_offset,
+        term,
+    );
+
+    builder
+}
+
 pub fn response_or_error(buffer: &[u8]) -> Result<GenericResponse, ErrorCode> {
     let response = flatbuffers::get_root::<GenericResponse>(buffer);
     if response.response_type() == ResponseType::ErrorResponse {
@@ -158,6 +222,11 @@ pub fn to_fast_read_response(
     }
 }
 
+// Whether a response created by to_fast_read_response contains data, rather than an error
+pub fn fast_read_succeeded(response: &FlatBufferWithResponse) -> bool {
+    response.as_ref().last() == Some(&(ErrorCode::DefaultValueNotAnError as u8))
+}
+
 pub fn decode_fast_read_response_inplace(response: &mut Vec<u8>) -> Result<&Vec<u8>, ErrorCode> {
     let value = response.pop().unwrap().from_little_endian() as i8;
     let p = &value as *const i8 as *const ErrorCode;
@@ -196,23 +265,82 @@ pub fn to_write_response(mut builder: FlatBufferBuilder, length: u32) -> ResultR
 pub fn to_fileattr_response(
     mut builder: FlatBufferBuilder,
     attributes: InodeAttributes,
+    directory_version: u64,
 ) -> ResultResponse {
-    let mut response_builder = FileMetadataResponseBuilder::new(&mut builder);
+    let offset = create_fileattr(&mut builder, &attributes, directory_version).as_union_value();
+    return Ok((builder, ResponseType::FileMetadataResponse, offset));
+}
+
+pub fn create_fileattr<'a>(
+    builder: &mut FlatBufferBuilder<'a>,
+    attributes: &InodeAttributes,
+    directory_version: u64,
+) -> WIPOffset<FileMetadataResponse<'a>> {
+    let mut response_builder = FileMetadataResponseBuilder::new(builder);
     response_builder.add_inode(attributes.inode);
     response_builder.add_size_bytes(attributes.size);
     response_builder.add_size_blocks(attributes.size / BLOCK_SIZE);
     response_builder.add_last_access_time(&attributes.last_accessed);
     response_builder.add_last_modified_time(&attributes.last_modified);
     response_builder.add_last_metadata_modified_time(&attributes.last_metadata_changed);
+    response_builder.add_creation_time(&attributes.created);
     response_builder.add_kind(attributes.kind);
     response_builder.add_mode(attributes.mode);
     response_builder.add_hard_links(attributes.hardlinks);
     response_builder.add_user_id(attributes.uid);
     response_builder.add_group_id(attributes.gid);
     response_builder.add_device_id(0); // TODO
+    response_builder.add_redundancy(attributes.redundancy);
+    response_builder.add_directory_version(directory_version);
+    response_builder.add_data_version(attributes.data_version);
 
-    let offset = response_builder.finish().as_union_value();
-    return Ok((builder, ResponseType::FileMetadataResponse, offset));
+    return response_builder.finish();
+}
+
+// Matches value against a shell style glob pattern, which may contain '*' and '?'
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let mut pattern_index = 0;
+    let mut value_index = 0;
+    // Position of the last '*' in pattern, and the position in value which it has matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while value_index < value.len() {
+        if pattern_index < pattern.len()
+            && (pattern[pattern_index] == '?' || pattern[pattern_index] == value[value_index])
+        {
+            pattern_index += 1;
+            value_index += 1;
+        } else if pattern_index < pattern.len() && pattern[pattern_index] == '*' {
+            backtrack = Some((pattern_index, value_index));
+            pattern_index += 1;
+        } else if let Some((star_index, matched_index)) = backtrack {
+            // Let the '*' consume one more character, and try again
+            backtrack = Some((star_index, matched_index + 1));
+            pattern_index = star_index + 1;
+            value_index = matched_index + 1;
+        } else {
+            return false;
+        }
+    }
+
+    while pattern_index < pattern.len() && pattern[pattern_index] == '*' {
+        pattern_index += 1;
+    }
+
+    return pattern_index == pattern.len();
+}
+
+// The weak checksum used by rsync. Two 16-bit sums, so that it can be updated in O(1) as a window slides over data
+pub fn rolling_checksum(data: &[u8]) -> u32 {
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        a = a.wrapping_add(u32::from(*byte));
+        b = b.wrapping_add((data.len() - i) as u32 * u32::from(*byte));
+    }
+
+    return (a & 0xFFFF) | (b << 16);
 }
 
 pub fn check_access(
@@ -256,6 +384,12 @@ impl LengthPrefixedVec {
         LengthPrefixedVec { data }
     }
 
+    // data must already start with its little endian length prefix, such as a size prefixed flatbuffer
+    pub fn from_length_prefixed(data: Vec<u8>) -> LengthPrefixedVec {
+        assert_eq!(LittleEndian::read_u32(&data) as usize, data.len() - 4);
+        LengthPrefixedVec { data }
+    }
+
     pub fn zeros(length: usize) -> LengthPrefixedVec {
         let mut data = vec![0; length + 4];
         LittleEndian::write_u32(&mut data, length as u32);
@@ -293,6 +427,37 @@ impl LengthPrefixedVec {
     }
 }
 
+// Records of a FileKind::SharedLog file are prefixed with their length
+pub const LOG_RECORD_HEADER_SIZE: usize = 4;
+
+pub fn frame_log_record(data: &[u8]) -> Vec<u8> {
+    let mut record = vec![0; LOG_RECORD_HEADER_SIZE];
+    LittleEndian::write_u32(&mut record, data.len() as u32);
+    record.extend_from_slice(data);
+    record
+}
+
+// Splits data, which was read at offset from a FileKind::SharedLog file, into records. Returns them with their
+// offsets, and the offset of the first record which data doesn't completely contain
+pub fn parse_log_records(data: &[u8], offset: u64) -> (Vec<(u64, Vec<u8>)>, u64) {
+    let mut records = vec![];
+    let mut position = 0;
+    while data.len() - position >= LOG_RECORD_HEADER_SIZE {
+        let length = LittleEndian::read_u32(&data[position..]) as usize;
+        let start = position + LOG_RECORD_HEADER_SIZE;
+        if data.len() - start < length {
+            break;
+        }
+        records.push((
+            offset + position as u64,
+            data[start..(start + length)].to_vec(),
+        ));
+        position = start + length;
+    }
+
+    (records, offset + position as u64)
+}
+
 pub fn node_id_from_address(address: &SocketAddr) -> u64 {
     let port = address.port();
     match address.ip() {
@@ -313,3 +478,51 @@ pub fn node_id_from_address(address: &SocketAddr) -> u64 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::{frame_log_record, glob_matches, parse_log_records, rolling_checksum};
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "a"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("*.txt", "notes.txt"));
+        assert!(!glob_matches("*.txt", "notes.txt.bak"));
+        assert!(glob_matches("a?c", "abc"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(glob_matches("a*b*c", "aXXbYYbZc"));
+        assert!(!glob_matches("a*b*c", "aXXbYY"));
+        assert!(glob_matches("**x", "x"));
+    }
+
+    #[test]
+    fn log_records() {
+        let mut data = frame_log_record(b"first");
+        data.extend(frame_log_record(b""));
+        data.extend(frame_log_record(b"third"));
+        let (records, next_offset) = parse_log_records(&data, 100);
+        assert_eq!(
+            records,
+            vec![
+                (100, b"first".to_vec()),
+                (109, vec![]),
+                (113, b"third".to_vec())
+            ]
+        );
+        assert_eq!(next_offset, 100 + data.len() as u64);
+
+        // An incomplete record is left for the next read
+        let (records, next_offset) = parse_log_records(&data[..(data.len() - 1)], 100);
+        assert_eq!(records.len(), 2);
+        assert_eq!(next_offset, 113);
+    }
+
+    #[test]
+    fn rsync_checksum() {
+        assert_eq!(rolling_checksum(b""), 0);
+        assert_eq!(rolling_checksum(b"abc"), 294 | (586 << 16));
+    }
+}
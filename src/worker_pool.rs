@@ -0,0 +1,50 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<FnOnce() + Send>;
+
+fn worker_loop(jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // Only hold the lock while waiting for a job, so that the other workers can take the next one
+        let job = jobs.lock().expect("jobs lock is poisoned").recv();
+        match job {
+            Ok(job) => job(),
+            // The pool was dropped
+            Err(_) => return,
+        }
+    }
+}
+
+// Fixed size pool of threads, which run jobs in the order they were submitted. The threads exit once the pool is
+// dropped and the jobs already submitted have run
+pub struct WorkerPool {
+    sender: Mutex<Sender<Job>>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, threads: usize) -> WorkerPool {
+        assert!(threads > 0);
+        let (sender, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let jobs = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || worker_loop(jobs))
+                .expect("Failed to spawn worker thread");
+        }
+
+        WorkerPool {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.sender
+            .lock()
+            .expect("sender lock is poisoned")
+            .send(Box::new(job))
+            .expect("Worker threads exited");
+    }
+}
@@ -282,4 +282,13 @@ else
     exit
 fi
 
+# The scratch cluster tests need FUSE, so they're ignored by cargo test
+if cargo test scratch_cluster -- --ignored; then
+    echo -e "$GREEN OK scratch cluster $NC"
+else
+    echo -e "$RED FAILED on scratch cluster tests $NC"
+    export TEST_EXIT_STATUS=1
+    exit
+fi
+
 export TEST_EXIT_STATUS=0